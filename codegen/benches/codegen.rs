@@ -0,0 +1,216 @@
+//! Benchmarks of instruction encoding, assembling and linking, run with
+//! `cargo bench`, to catch performance regressions in the generator.
+
+use alpha_codegen::{
+    elf64::program::{PF_R, PF_W, PF_X},
+    link::{ElfLinker, Label, ReferenceFormat, Segment},
+    x86::{
+        address::*,
+        control::ControlFlow,
+        function::{Arg, Function},
+        instruction::*,
+        register::R64::*,
+        Assembler, Emitter,
+    },
+};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::hint::black_box;
+
+/// Program sizes, in instructions or functions.
+const SIZES: [usize; 3] = [100, 1_000, 10_000];
+
+/// Emit `count` instructions of a mix typical of generated code: moves,
+/// arithmetic, memory operands, calls and branches to nearby labels.
+fn emit_program(asm: &mut impl Emitter, count: usize) {
+    for i in 0..count / 8 {
+        let label = format!("block_{}", i);
+        asm.label(&label);
+        asm.push(MOV(RAX, Index(RBP, -8i8)));
+        asm.push(ADD(RAX, RCX));
+        asm.push(IMUL(RAX, RDX));
+        asm.push(MOV(Indirect(RDI), RAX));
+        asm.push(CMP(RAX, 16i8));
+        asm.push(JCC(Condition::Less, Label(&label)));
+        asm.push(LEA(RSI, Index(RSP, 32i8)));
+        asm.push(CALL(Label("callee")));
+    }
+}
+
+/// A kernel-like program of `functions` functions, each with a loop, a
+/// switch through a jump table, calls to other functions and string
+/// constants, plus an interrupt stub per vector, linked as a kernel would
+/// be.
+fn kernel_like(functions: usize) -> ElfLinker {
+    let mut asm = Assembler::new();
+    for i in 0..functions {
+        let mut f = Function::new(&format!("fn_{}", i))
+            .params(2)
+            .locals(2)
+            .begin(&mut asm);
+        let (count, selector) = (f.param(0), f.param(1));
+        f.while_(
+            |f| {
+                f.push(TEST(count, count));
+                Condition::NotZero
+            },
+            |f, _| {
+                f.push(MOV(RAX, f.local(0)));
+                f.push(ADD(RAX, count));
+                f.push(MOV(f.local(0), RAX));
+                f.push(DEC(count));
+            },
+        );
+        let arms: Vec<(u64, String)> = (0..6)
+            .map(|arm| (arm, format!("fn_{}.arm_{}", i, arm)))
+            .collect();
+        let end = format!("fn_{}.end", i);
+        let table: Vec<(u64, &str)> = arms
+            .iter()
+            .map(|(case, label)| (*case, label.as_str()))
+            .collect();
+        f.switch(selector, &table, &end);
+        for (case, label) in &arms {
+            f.label(label);
+            let message = f.const_bytes(format!("fn_{} arm {}\n\0", i, case).as_bytes());
+            f.call_fn(
+                &format!("fn_{}", (i * 7 + 1) % functions),
+                &[Arg::Label(message.label()), Arg::Imm(*case)],
+            );
+            f.push(JMP(Label(&end)));
+        }
+        f.label(&end);
+        f.ret();
+        f.finish();
+    }
+
+    asm.label("isr_common");
+    asm.push(IRET);
+    for vector in 0..256 {
+        asm.export_label(&format!("isr_{}", vector));
+        asm.push(PUSH(RAX));
+        asm.push(JMP(Label("isr_common")));
+    }
+
+    let mut rodata = asm.constant_pool();
+    rodata.export_label("isr_table");
+    for vector in 0..256 {
+        rodata.append_reference(&format!("isr_{}", vector), ReferenceFormat::Abs64);
+    }
+
+    let mut data = Segment::new();
+    data.label("counter");
+    data.reserve(8);
+
+    let mut code = asm.finish();
+    code.export_label("entry");
+    code.append(&[0xc3u8]);
+
+    let mut linker = ElfLinker::new();
+    linker.add_segment("rodata", PF_R, 1 << 12, rodata);
+    linker.add_segment("data", PF_R | PF_W, 1 << 12, data);
+    linker.add_segment("code", PF_R | PF_X, 1 << 12, code);
+    linker
+}
+
+fn encoding(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode");
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("mov_index", |b| {
+        b.iter(|| black_box(MOV(black_box(R12), Index(black_box(R13), -8i8))).encode())
+    });
+    group.bench_function("jcc_label", |b| {
+        b.iter(|| black_box(JCC(Condition::Less, Label("target"))).encode())
+    });
+    group.bench_function("serialize", |b| {
+        let encoded = MOV(R12, ScaledIndex(Times8, R13, R14)).encode();
+        let mut out = Vec::with_capacity(MAX_INSTRUCTION_LENGTH);
+        b.iter(|| {
+            out.clear();
+            black_box(&encoded).serialize(&mut out);
+            black_box(&out);
+        })
+    });
+    group.finish();
+}
+
+fn assembling(c: &mut Criterion) {
+    let mut group = c.benchmark_group("assemble");
+    for size in SIZES {
+        group.throughput(Throughput::Elements(size as u64));
+        group.bench_with_input(BenchmarkId::new("program", size), &size, |b, &size| {
+            b.iter(|| {
+                let mut asm = Assembler::new();
+                emit_program(&mut asm, size);
+                asm.finish()
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("relaxed", size), &size, |b, &size| {
+            b.iter(|| {
+                let mut asm = Assembler::new();
+                asm.set_relax_branches(true);
+                emit_program(&mut asm, size);
+                asm.label("callee");
+                asm.push(RET);
+                asm.finish()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn linking(c: &mut Criterion) {
+    let mut group = c.benchmark_group("link");
+    for count in [10, 100, 1_000] {
+        group.throughput(Throughput::Elements(count as u64));
+        group.bench_with_input(BenchmarkId::new("segments", count), &count, |b, &count| {
+            b.iter_with_setup(
+                || {
+                    let mut linker = ElfLinker::new();
+                    for i in 0..count {
+                        let mut segment = Segment::new();
+                        if i == 0 {
+                            segment.export_label("entry");
+                        }
+                        segment.export_label(&format!("start_{}", i));
+                        segment.append_reference(
+                            &format!("start_{}", (i + 1) % count),
+                            ReferenceFormat::Abs64,
+                        );
+                        segment.extend([0u8; 248]);
+                        linker.add_segment(
+                            &format!("segment_{}", i),
+                            PF_R | PF_X,
+                            1 << 12,
+                            segment,
+                        );
+                    }
+                    linker
+                },
+                |linker| linker.finish().to_vec().unwrap(),
+            )
+        });
+    }
+    for functions in SIZES {
+        group.bench_with_input(
+            BenchmarkId::new("kernel_like", functions),
+            &functions,
+            |b, &functions| {
+                b.iter_with_setup(
+                    || kernel_like(functions),
+                    |linker| linker.finish().to_vec().unwrap(),
+                )
+            },
+        );
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("build");
+    group.sample_size(10);
+    group.bench_function("kernel_like_10000", |b| {
+        b.iter(|| kernel_like(10_000).finish().to_vec().unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, encoding, assembling, linking);
+criterion_main!(benches);
@@ -0,0 +1,325 @@
+//! Builds the kernel, with `cargo run --example kernel`, into `kernel.elf`
+//! and its symbol, comment and debug files, and into `kernel.iso` when
+//! `LIMINE_DIR` is set.
+
+use std::{
+    env,
+    error::Error,
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use alpha_codegen::{
+    elf64::program::{PF_R, PF_W, PF_X},
+    ir::{self, BinOp, Type},
+    limine,
+    link::{ElfLinker, Label, Linked, Ptr, Segment},
+    x86,
+};
+use x86::{
+    address::*,
+    backtrace::{Backtrace, PRINT_BACKTRACE},
+    descriptor::IdtBuilder,
+    format::{Formatter, Sink},
+    function::{Arg, Function},
+    global::Global,
+    instruction::*,
+    interrupt::InterruptHandler,
+    intrinsics::{Intrinsic, Intrinsics, Variant},
+    panic::{self, Panic},
+    pic::Pic,
+    register::R64::*,
+    vga::VgaConsole,
+};
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let linked = link_kernel();
+    // Loaders ignore the sections, so the boot image carries its own symbols
+    // and line numbers, for `gdb kernel.elf`.
+    let mut file = BufWriter::new(File::create("kernel.elf")?);
+    linked.write_debuggable(&mut file)?;
+    file.flush()?;
+    // The symbol, comment and debug files are also written separately, for
+    // external tooling.
+    let mut file = BufWriter::new(File::create("kernel.sym")?);
+    linked.write_symbols(&mut file)?;
+    file.flush()?;
+    let mut file = BufWriter::new(File::create("kernel.comments")?);
+    linked.write_comments(&mut file)?;
+    file.flush()?;
+    let mut file = BufWriter::new(File::create("kernel.debug")?);
+    linked.write_debug_info(&mut file)?;
+    file.flush()?;
+
+    // Build a bootable CD image when pointed at a Limine binary release.
+    if let Some(limine_dir) = env::var_os("LIMINE_DIR") {
+        write_iso(Path::new(&limine_dir))?;
+    }
+    Ok(())
+}
+
+fn link_kernel() -> Linked {
+    let mut requests = limine::RequestsBuilder::new();
+    let terminal = requests.add_terminal("terminal_response", 0, "terminal_callback");
+    let bootloader_info = requests.add(
+        "bootloader_info_response",
+        &limine::Request::new(limine::BOOTLOADER_INFO_REQUEST, 0),
+    );
+    let hhdm = requests.add(
+        "hhdm_response",
+        &limine::Request::new(limine::HHDM_REQUEST, 0),
+    );
+    requests.response_type::<limine::TerminalResponse>(terminal);
+    requests.response_type::<limine::BootloaderInfoResponse>(bootloader_info);
+    requests.response_type::<limine::HhdmResponse>(hhdm);
+    let requests = requests.finish();
+
+    let mut rodata = Segment::new();
+    rodata.align(8);
+
+    let mut idt = IdtBuilder::new();
+
+    rodata.export_label("tohex_lut");
+    rodata.append(b"0123456789abcdef");
+
+    let mut data = Segment::new();
+
+    let tohex_buffer = Global::<[u8; 32]>::reserve(&mut data, "tohex_buffer");
+
+    let mut intrinsics = Intrinsics::new(Variant::Rep);
+    let mut asm = x86::Assembler::new();
+    asm.label("code_start");
+
+    // Entrypoint
+    asm.export_label("entry");
+
+    asm.comment("the bootloader info response must be present");
+    asm.push(MOV(RBX, bootloader_info.ptr()));
+    asm.call_fn(panic::ASSERT_NONZERO, &[Arg::Reg(RBX)]);
+
+    asm.call_fn("tohex", &[Arg::Imm(0xdeadbeef)]);
+    let greeting = asm.const_bytes(b"Hello %s %s %s\n\0");
+    asm.call_fn(
+        "kprintf",
+        &[
+            Arg::Label(greeting.label()),
+            Arg::Index(RBX, disp8(limine::BOOTLOADER_INFO_RESPONSE_NAME_OFFSET)),
+            Arg::Index(RBX, disp8(limine::BOOTLOADER_INFO_RESPONSE_VERSION_OFFSET)),
+            Arg::Reg(RAX),
+        ],
+    );
+
+    asm.comment("move the legacy PIC's IRQs away from the exception vectors, and mask them all");
+    asm.call_fn("pic_init", &[]);
+    asm.comment("set up IDTR");
+    asm.push(LIDT(Ptr("idtr")));
+    asm.push(STI);
+    asm.push(NOP);
+    asm.push(INT3);
+
+    let hello = asm.const_bytes(b"Hello \0");
+    asm.call_fn("print", &[Arg::Label(hello.label())]);
+
+    asm.push(JMP(Label("halt")));
+
+    // Segment 5, RPL 0, from the limine-provided GDT
+    InterruptHandler::new("exception").emit_exceptions(&mut asm, &mut idt, 5 << 3, |asm, frame| {
+        let message = asm.const_bytes(b"exception %d (error %x) at %x\n\0");
+        asm.push(MOV(RSI, frame.vector()));
+        asm.push(MOV(RDX, frame.error_code()));
+        asm.push(MOV(RCX, frame.rip()));
+        asm.call_fn(
+            "kprintf",
+            &[
+                Arg::Label(message.label()),
+                Arg::Reg(RSI),
+                Arg::Reg(RDX),
+                Arg::Reg(RCX),
+            ],
+        );
+        // Only breakpoints can be resumed from. Other exceptions print a
+        // backtrace, which starts at the interrupted function, since the
+        // handler pushes no frame of its own.
+        asm.push(MOV(RAX, frame.vector()));
+        asm.push(CMP(RAX, 3i8));
+        let resume = asm.fresh_label("exception_resume");
+        asm.push(JZ(resume));
+        asm.call_fn(PRINT_BACKTRACE, &[]);
+        asm.push(JMP(Label("halt")));
+        asm.label(resume);
+    });
+
+    // Print a null-terminated string to the first terminal.
+    let mut f = Function::new("print").params(1).begin(&mut asm);
+    let string = f.param(0);
+
+    let strlen = intrinsics.require(Intrinsic::Strlen);
+    f.call_fn_preserving(strlen, &[Arg::Reg(string)], &[string]);
+    f.push(MOV(RSI, string));
+    f.push(MOV(RDX, RAX));
+
+    // Terminal write. Without a terminal, failures can't be reported, so
+    // just halt.
+    f.push(MOV(RAX, terminal.ptr()));
+    f.push(TEST(RAX, RAX));
+    f.push(JZ(Label("halt")));
+
+    f.push(MOV(
+        RDI,
+        Index(RAX, disp8(limine::TERMINAL_RESPONSE_TERMINAL_COUNT_OFFSET)),
+    ));
+    f.push(TEST(RDI, RDI));
+    f.push(JZ(Label("halt")));
+    f.push(MOV(
+        RDI,
+        Index(RAX, disp8(limine::TERMINAL_RESPONSE_TERMINALS_OFFSET)),
+    ));
+    // [0]
+    f.push(MOV(RDI, Indirect(RDI)));
+
+    f.push(MOV(
+        RAX,
+        Index(RAX, disp8(limine::TERMINAL_RESPONSE_WRITE_OFFSET)),
+    ));
+    f.push(CALL(RAX));
+    f.finish();
+
+    // Format a 64-bit integer as a null-terminated hex string.
+    // The string only contains valid data until the next call.
+    let buffer_name = tohex_buffer.label().name();
+    let mut f = ir::Function::new("tohex", &[Type::I64], Some(Type::Ptr));
+    let buffer = f.address(&buffer_name);
+    let lut = f.address("tohex_lut");
+    let mut value = f.param(0);
+    // Digits are produced least significant first and shifted up through a
+    // word, so that each word of eight digits reads most significant first
+    // in memory.
+    for (loop_label, continue_label, done_label, offset) in [
+        ("tohex_low", "tohex_low_continue", "tohex_low_done", 8),
+        ("tohex_high", "tohex_high_continue", "tohex_high_done", 0),
+    ] {
+        let digits = f.const_(Type::I64, 8);
+        let word = f.const_(Type::I64, 0);
+        let top = f.block(loop_label, &[Type::I64, Type::I64, Type::I64]);
+        let continue_ = f.block(continue_label, &[]);
+        let done = f.block(done_label, &[]);
+        f.jump(top, &[value, digits, word]);
+
+        f.switch_to(top);
+        let (value_in, digits_in, word_in) = (
+            f.block_param(top, 0),
+            f.block_param(top, 1),
+            f.block_param(top, 2),
+        );
+        let mask = f.const_(Type::I64, 0x0f);
+        let digit = f.binary(BinOp::And, value_in, mask);
+        let char = f.load_byte(lut, digit);
+        let eight = f.const_(Type::I64, 8);
+        let word = f.binary(BinOp::Shl, word_in, eight);
+        let word = f.binary(BinOp::Or, word, char);
+        let four = f.const_(Type::I64, 4);
+        value = f.binary(BinOp::Shr, value_in, four);
+        let one = f.const_(Type::I64, 1);
+        let digits = f.binary(BinOp::Sub, digits_in, one);
+        f.branch(digits, continue_, done);
+
+        f.switch_to(continue_);
+        f.jump(top, &[value, digits, word]);
+
+        f.switch_to(done);
+        f.store(buffer, offset, word);
+    }
+    let terminator = f.const_(Type::I64, 0);
+    f.store(buffer, 16, terminator);
+    f.ret(Some(buffer));
+    x86::lower::emit(&f, &mut asm);
+
+    Formatter::new("kprintf", Sink::Terminal(terminal)).emit(&mut asm, &mut data);
+
+    intrinsics.emit(&mut asm);
+    Backtrace::new("print", "tohex").emit(&mut asm);
+    Panic::new("print", "tohex", "halt")
+        .backtrace(PRINT_BACKTRACE)
+        .emit(&mut asm);
+    Pic::new("pic", 0x20, 0x28).emit(&mut asm);
+    VgaConsole::new("vga", hhdm).emit(&mut asm, &mut data);
+
+    let mut f = Function::new("terminal_callback")
+        .export()
+        .naked()
+        .begin(&mut asm);
+    f.ret();
+    f.finish();
+
+    asm.label("halt");
+    asm.comment("halt procedure");
+    asm.push(HLT);
+    asm.push(JMP(Label("halt")));
+
+    idt.emit(&mut rodata, "idt", "idtr");
+    rodata.embed(asm.constant_pool());
+    let code = asm.finish();
+
+    let mut linker = ElfLinker::new();
+    linker.add_segment("limine_requests", PF_R | PF_W, 1 << 12, requests);
+    linker.add_segment("rodata", PF_R, 1 << 12, rodata);
+    linker.add_segment("data", PF_R | PF_W, 1 << 12, data);
+    linker.add_segment("code", PF_R | PF_X, 1 << 12, code);
+    for ty in limine::debug_types() {
+        linker.add_debug_type(ty);
+    }
+
+    linker.finish()
+}
+
+fn write_iso(limine_dir: &Path) -> Result<(), Box<dyn Error>> {
+    fs::write(
+        "kernel.iso",
+        limine::iso_image(fs::read("kernel.elf")?, limine_dir)?,
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alpha_codegen::x86::emulator::Machine;
+    use std::{
+        collections::hash_map::DefaultHasher,
+        hash::{Hash, Hasher},
+    };
+
+    fn kernel_hash() -> u64 {
+        let linked = link_kernel();
+        let mut elf = Vec::new();
+        linked.write_debuggable(&mut elf).unwrap();
+        let mut symbols = Vec::new();
+        linked.write_symbols(&mut symbols).unwrap();
+
+        let mut hasher = DefaultHasher::new();
+        (elf, symbols).hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn deterministic() {
+        assert_eq!(kernel_hash(), kernel_hash());
+    }
+
+    #[test]
+    fn tohex() {
+        let linked = link_kernel();
+        let mut machine = Machine::load(&linked);
+        let tohex = linked.any_label_address("tohex").unwrap();
+        for (value, expected) in [
+            (0xdeadbeef, "00000000deadbeef"),
+            (0, "0000000000000000"),
+            (u64::MAX, "ffffffffffffffff"),
+            (0x0123_4567_89ab_cdef, "0123456789abcdef"),
+        ] {
+            let string = machine.call(tohex, &[value]);
+            assert_eq!(machine.read_c_string(string), expected.as_bytes());
+        }
+    }
+}
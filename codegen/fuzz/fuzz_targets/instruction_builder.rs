@@ -0,0 +1,133 @@
+//! Builds instructions from arbitrary sequences of `InstructionBuilder`
+//! calls, and checks that each serializes to its reported length, with its
+//! reference inside it.
+
+#![no_main]
+
+use alpha_codegen::{
+    link::{Label, Ptr},
+    x86::{
+        address::{GsIndex, Index, Indirect, ScaledIndex, Times8},
+        instruction::InstructionBuilder,
+        register::{Xmm, R64},
+    },
+};
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+
+/// A builder call. Register codes are taken modulo 16 and field values are
+/// masked to their width, since the builder asserts on values that are out of
+/// range.
+#[derive(Arbitrary, Debug)]
+enum Call {
+    Prefix(u8),
+    OperandSizeOverride,
+    RexW,
+    Opcode1(u8),
+    Opcode2([u8; 2]),
+    Opcode3([u8; 3]),
+    OpReg(u8),
+    Mod(u8),
+    Reg(u8),
+    RegConst(u8),
+    RmReg(u8),
+    RmConst(u8),
+    RmXmm(u8),
+    Index(u8),
+    Base(u8),
+    BaseConst(u8),
+    NoIndex,
+    Displacement8(i8),
+    Displacement32(i32),
+    Immediate8(u8),
+    Immediate16(u16),
+    Immediate32(u32),
+    Immediate64(u64),
+    Indirect(u8),
+    IndexedIndirect(u8, u8),
+    ScaledIndexedIndirect(u8, u8),
+    IndexedDisplacement(u8, i8),
+    IndexedDisplacement32(u8, i32),
+    GsIndex(u32),
+}
+
+/// A reference, which is added after all other calls, since later calls
+/// could replace the field that it refers to.
+#[derive(Arbitrary, Debug)]
+enum Reference {
+    Rel32,
+    RipRelative,
+}
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    calls: Vec<Call>,
+    reference: Option<Reference>,
+}
+
+fn gpr(code: u8) -> R64 {
+    R64::from_code(code % 16)
+}
+
+/// A register that can be used as an index, which RSP can not.
+fn index(code: u8) -> R64 {
+    match gpr(code) {
+        R64::RSP => R64::RBP,
+        register => register,
+    }
+}
+
+fn apply(builder: InstructionBuilder, call: Call) -> InstructionBuilder {
+    match call {
+        Call::Prefix(prefix) => builder.prefix(prefix),
+        Call::OperandSizeOverride => builder.operand_size_override(),
+        Call::RexW => builder.rex_w(),
+        Call::Opcode1(opcode) => builder.opcode(opcode),
+        Call::Opcode2(opcode) => builder.opcode(opcode),
+        Call::Opcode3(opcode) => builder.opcode(opcode),
+        Call::OpReg(code) => builder.op_reg(gpr(code)),
+        Call::Mod(mod_) => builder.mod_(mod_ & 0b11),
+        Call::Reg(code) => builder.reg(gpr(code)),
+        Call::RegConst(x) => builder.reg_const(x & 0b111),
+        Call::RmReg(code) => builder.rm_reg(gpr(code)),
+        Call::RmConst(x) => builder.rm_const(x & 0b111),
+        Call::RmXmm(code) => builder.rm_xmm(Xmm::from_code(code % 16)),
+        Call::Index(code) => builder.index(index(code)),
+        Call::Base(code) => builder.base(gpr(code)),
+        Call::BaseConst(x) => builder.base_const(x & 0b111),
+        Call::NoIndex => builder.no_index(),
+        Call::Displacement8(d) => builder.displacement(d),
+        Call::Displacement32(d) => builder.displacement(d),
+        Call::Immediate8(i) => builder.immediate(i),
+        Call::Immediate16(i) => builder.immediate(i),
+        Call::Immediate32(i) => builder.immediate(i),
+        Call::Immediate64(i) => builder.immediate(i),
+        Call::Indirect(code) => builder.indirect(Indirect(gpr(code))),
+        Call::IndexedIndirect(i, b) => builder.indexed_indirect(Index(index(i), gpr(b))),
+        Call::ScaledIndexedIndirect(i, b) => {
+            builder.scaled_indexed_indirect(ScaledIndex(Times8, index(i), gpr(b)))
+        }
+        Call::IndexedDisplacement(b, d) => builder.indexed_displacement(Index(gpr(b), d)),
+        Call::IndexedDisplacement32(b, d) => builder.indexed_displacement32(Index(gpr(b), d)),
+        Call::GsIndex(offset) => builder.gs_index(GsIndex((offset & 0x7fff_ffff) as usize)),
+    }
+}
+
+fuzz_target!(|input: Input| {
+    let mut builder = input
+        .calls
+        .into_iter()
+        .fold(InstructionBuilder::new(), apply);
+    builder = match input.reference {
+        Some(Reference::Rel32) => builder.rel32(Label("target")),
+        Some(Reference::RipRelative) => builder.rip_relative(Ptr("target")),
+        None => builder,
+    };
+
+    let mut bytes = Vec::new();
+    builder.serialize(&mut bytes);
+    assert_eq!(bytes.len(), builder.len());
+    for (_, reference) in builder.references() {
+        assert!(reference.location + reference.format.len() <= bytes.len());
+    }
+});
@@ -0,0 +1,133 @@
+//! Builds a segment from arbitrary appends, labels and references, checking
+//! its reported length as it grows, then links it and checks the output.
+
+#![no_main]
+
+use alpha_codegen::{
+    elf64::program::{PF_R, PF_X},
+    link::{ElfLinker, ReferenceFormat, Segment},
+};
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+
+const LABELS: [&str; 8] = ["a", "b", "c", "d", "e", "f", "g", "h"];
+
+/// Reference formats whose targets are always in range of a segment linked
+/// at `BASE_ADDRESS`. Abs16 is left out, since the segment may be larger
+/// than 64KiB.
+#[derive(Arbitrary, Debug, Clone, Copy)]
+enum Format {
+    Rel32,
+    Abs64,
+    Abs32,
+    GateOffset,
+    Phys64,
+}
+
+#[derive(Arbitrary, Debug)]
+enum Op {
+    Append(Vec<u8>),
+    AppendU64(u64),
+    Label(u8),
+    ExportLabel(u8),
+    OffsetLabel(u8, u8),
+    AppendReference(u8, Format),
+    PadToAlignment(u8),
+    Align(u8),
+    Reserve(u16),
+}
+
+const BASE_ADDRESS: u64 = 0x10_0000;
+
+fuzz_target!(|ops: Vec<Op>| {
+    let mut segment = Segment::new();
+    // The entry point must be inside the segment.
+    segment.export_label("entry");
+    segment.extend([0xf4]);
+    let mut defined = [false; LABELS.len()];
+    let mut referenced = [false; LABELS.len()];
+    let mut len = 1;
+    let mut reserved = false;
+    let label = |i: u8| i as usize % LABELS.len();
+
+    for op in ops {
+        match op {
+            // Nothing can be appended after reserved space.
+            Op::Append(_) | Op::AppendU64(_) | Op::AppendReference(..) if reserved => {}
+            Op::Append(bytes) => {
+                len += bytes.len();
+                segment.extend(bytes);
+            }
+            Op::AppendU64(value) => {
+                len += 8;
+                segment.append(&value);
+            }
+            // Labels can only be defined once.
+            Op::Label(i) | Op::ExportLabel(i) | Op::OffsetLabel(i, _) if defined[label(i)] => {}
+            Op::Label(i) => {
+                defined[label(i)] = true;
+                segment.label(LABELS[label(i)]);
+            }
+            Op::ExportLabel(i) => {
+                defined[label(i)] = true;
+                segment.export_label(LABELS[label(i)]);
+            }
+            Op::OffsetLabel(i, offset) => {
+                defined[label(i)] = true;
+                segment.offset_label(offset as usize, LABELS[label(i)]);
+            }
+            Op::AppendReference(i, format) => {
+                let format = match format {
+                    Format::Rel32 => ReferenceFormat::Rel32,
+                    Format::Abs64 => ReferenceFormat::Abs64,
+                    Format::Abs32 => ReferenceFormat::Abs32,
+                    Format::GateOffset => ReferenceFormat::GateOffset,
+                    Format::Phys64 => ReferenceFormat::Phys64,
+                };
+                referenced[label(i)] = true;
+                len += format.len();
+                segment.append_reference(LABELS[label(i)], format);
+            }
+            Op::PadToAlignment(shift) => {
+                let alignment = 1 << (shift % 13);
+                len = len.next_multiple_of(alignment);
+                segment.pad_to_alignment(alignment);
+            }
+            Op::Align(shift) => segment.align(1 << (shift % 13)),
+            Op::Reserve(size) => {
+                reserved = true;
+                len += size as usize;
+                segment.reserve(size as usize);
+            }
+        }
+        assert_eq!(segment.len(), len);
+    }
+
+    // Define every label that is referenced, so that linking succeeds.
+    for (i, label) in LABELS.iter().enumerate() {
+        if referenced[i] && !defined[i] {
+            segment.label(label);
+        }
+    }
+
+    let data_len = segment.bytes().len();
+    let mut linker = ElfLinker::new();
+    linker.set_base_address(BASE_ADDRESS);
+    linker.add_segment("fuzz", PF_R | PF_X, 1 << 12, segment);
+    let linked = linker.finish();
+    linked
+        .validate()
+        .unwrap_or_else(|error| panic!("{}", error));
+
+    let segment = &linked.segments()[0];
+    assert_eq!(segment.data().len(), data_len);
+    assert_eq!(segment.header().p_filesz as usize, data_len);
+    assert_eq!(segment.header().p_memsz as usize, len);
+
+    let mut file = Vec::new();
+    linked.write(&mut file).unwrap();
+    assert_eq!(
+        file.len() as u64,
+        segment.header().p_offset + data_len as u64
+    );
+});
@@ -0,0 +1,536 @@
+//! The `asm_block!` macro of `alpha_codegen::x86`, which re-exports it.
+
+use proc_macro::{Delimiter, Group, Ident, Literal, Punct, Spacing, Span, TokenStream, TokenTree};
+
+const REGISTER: &str = "::alpha_codegen::x86::register";
+const INSTRUCTION: &str = "::alpha_codegen::x86::instruction";
+const ADDRESS: &str = "::alpha_codegen::x86::address";
+const LINK: &str = "::alpha_codegen::link";
+
+/// Push a block of Intel-syntax instructions, at compile time:
+///
+/// ```ignore
+/// asm_block!(asm;
+///     mov rcx, [r8 + {disp8(COUNT_OFFSET)}];
+///     "loop":
+///     mov [rdi + rcx * 8], {value};
+///     dec rcx;
+///     jnz "loop";
+/// );
+/// ```
+///
+/// The first statement is the assembler, or anything else with `push` and
+/// `label` methods, like an [`Emitter`]. It is evaluated for every
+/// statement. Each other statement is an instruction, optionally after a
+/// `rep`, `repe`, `repne` or `lock` prefix, or labels, as `"name":` or
+/// `{expression}:`.
+///
+/// Operands are registers, numbers, `"label"`s (for branch targets) and
+/// Rust expressions in braces, or memory operands: `["label"]`, `[base]`,
+/// `[base + displacement]`, `[base + index]`, `[base + index * scale]`,
+/// `[gs:offset]` or `[{address}]`. Each instruction becomes a
+/// `push(MNEMONIC(operands...))` of the typed instruction of that name, so
+/// operands without an encoding are type errors, and immediates take their
+/// size from their type, like `8i8`, rather than from a `byte ptr`.
+/// Register names and the forms of memory operands are checked by the
+/// macro.
+///
+/// [`Emitter`]: ../alpha_codegen/x86/trait.Emitter.html
+#[proc_macro]
+pub fn asm_block(input: TokenStream) -> TokenStream {
+    match expand(input) {
+        Ok(tokens) => tokens,
+        Err(Error(span, message)) => compile_error(span, &message),
+    }
+}
+
+struct Error(Span, String);
+
+type Result<T> = std::result::Result<T, Error>;
+
+fn expand(input: TokenStream) -> Result<TokenStream> {
+    let mut statements = split(input.into_iter().collect(), ';').into_iter();
+    let emitter = match statements.next() {
+        Some(emitter) if !emitter.is_empty() => Group::new(Delimiter::Parenthesis, stream(emitter)),
+        _ => {
+            return Err(Error(
+                Span::call_site(),
+                "expected an assembler, like `asm_block!(asm; ...)`".into(),
+            ))
+        }
+    };
+
+    let mut output = Vec::new();
+    for statement in statements {
+        let mut rest = statement.as_slice();
+        // Labels, `"name":` or `{expression}:`.
+        while let [label @ (TokenTree::Literal(_) | TokenTree::Group(_)), TokenTree::Punct(colon), tail @ ..] =
+            rest
+        {
+            if colon.as_char() != ':' || !is_label(label) {
+                break;
+            }
+            let name = match label {
+                TokenTree::Group(group) => expression(group),
+                _ => label.clone(),
+            };
+            call(&mut output, &emitter, "label", name.into(), label.span());
+            rest = tail;
+        }
+        if !rest.is_empty() {
+            let (instruction, span) = instruction(rest)?;
+            call(&mut output, &emitter, "push", instruction, span);
+        }
+    }
+    Ok(TokenStream::from(TokenTree::Group(Group::new(
+        Delimiter::Brace,
+        stream(output),
+    ))))
+}
+
+/// Append `emitter.method(argument);`.
+fn call(
+    output: &mut Vec<TokenTree>,
+    emitter: &Group,
+    method: &str,
+    argument: TokenStream,
+    span: Span,
+) {
+    output.push(TokenTree::Group(emitter.clone()));
+    output.push(punct('.', Spacing::Alone, span));
+    output.push(TokenTree::Ident(Ident::new(method, span)));
+    output.push(parenthesized(argument, span));
+    output.push(punct(';', Spacing::Alone, span));
+}
+
+fn is_label(token: &TokenTree) -> bool {
+    match token {
+        TokenTree::Literal(literal) => literal.to_string().starts_with('"'),
+        TokenTree::Group(group) => group.delimiter() == Delimiter::Brace,
+        _ => false,
+    }
+}
+
+/// The instruction of a statement, and the span of its mnemonic.
+fn instruction(tokens: &[TokenTree]) -> Result<(TokenStream, Span)> {
+    let (mnemonic, span, operands) = mnemonic(tokens)?;
+    let prefix = match mnemonic.as_str() {
+        "rep" => Some("REP"),
+        "repe" | "repz" => Some("REPE"),
+        "repne" | "repnz" => Some("REPNE"),
+        "lock" => Some("LOCK"),
+        _ => None,
+    };
+    if let Some(prefix) = prefix {
+        let (inner, _) = instruction(operands)?;
+        let mut tokens = path(&format!("{}::{}", INSTRUCTION, prefix), span);
+        tokens.extend([parenthesized(inner, span)]);
+        return Ok((tokens, span));
+    }
+
+    let mut arguments = Vec::new();
+    if let Some(condition) = condition(&mnemonic) {
+        arguments.push(path(
+            &format!("{}::Condition::{}", INSTRUCTION, condition),
+            span,
+        ));
+    }
+    if !operands.is_empty() {
+        for operand_tokens in split(operands.to_vec(), ',') {
+            arguments.push(operand(&operand_tokens, span)?);
+        }
+    }
+    let name = match mnemonic.as_str() {
+        "iretq" => "IRET".to_owned(),
+        "sysretq" => "SYSRET".to_owned(),
+        "je" => "JZ".to_owned(),
+        "jne" => "JNZ".to_owned(),
+        _ if condition(&mnemonic).is_some() => "JCC".to_owned(),
+        _ => mnemonic.to_ascii_uppercase(),
+    };
+    let mut tokens = path(&format!("{}::{}", INSTRUCTION, name), span);
+    if !arguments.is_empty() {
+        let mut list = Vec::new();
+        for (i, argument) in arguments.into_iter().enumerate() {
+            if i > 0 {
+                list.push(punct(',', Spacing::Alone, span));
+            }
+            list.extend(argument);
+        }
+        tokens.extend([parenthesized(stream(list), span)]);
+    }
+    Ok((tokens, span))
+}
+
+fn mnemonic(tokens: &[TokenTree]) -> Result<(String, Span, &[TokenTree])> {
+    match tokens {
+        [TokenTree::Ident(ident), operands @ ..] => Ok((
+            ident.to_string().to_ascii_lowercase(),
+            ident.span(),
+            operands,
+        )),
+        [token, ..] => Err(Error(token.span(), "expected a mnemonic or a label".into())),
+        [] => Err(Error(Span::call_site(), "expected a mnemonic".into())),
+    }
+}
+
+/// The condition of a conditional jump other than `JZ` and `JNZ`, which
+/// have instructions of their own.
+fn condition(mnemonic: &str) -> Option<&'static str> {
+    Some(match mnemonic.strip_prefix('j')? {
+        "o" => "Overflow",
+        "no" => "NotOverflow",
+        "b" | "c" | "nae" => "Below",
+        "ae" | "nb" | "nc" => "AboveOrEqual",
+        "be" | "na" => "BelowOrEqual",
+        "a" | "nbe" => "Above",
+        "s" => "Sign",
+        "ns" => "NotSign",
+        "p" | "pe" => "Parity",
+        "np" | "po" => "NotParity",
+        "l" | "nge" => "Less",
+        "ge" | "nl" => "GreaterOrEqual",
+        "le" | "ng" => "LessOrEqual",
+        "g" | "nle" => "Greater",
+        _ => return None,
+    })
+}
+
+fn operand(tokens: &[TokenTree], span: Span) -> Result<TokenStream> {
+    match tokens {
+        [TokenTree::Ident(ident)] => register(ident),
+        [TokenTree::Literal(literal)] if literal.to_string().starts_with('"') => {
+            let mut tokens = path(&format!("{}::Label", LINK), literal.span());
+            tokens.extend([parenthesized(
+                TokenTree::Literal(literal.clone()).into(),
+                literal.span(),
+            )]);
+            Ok(tokens)
+        }
+        [TokenTree::Literal(_) | TokenTree::Group(_)] if value(tokens).is_some() => {
+            Ok(value(tokens).unwrap())
+        }
+        [minus @ TokenTree::Punct(punct), rest @ ..]
+            if punct.as_char() == '-' && value(rest).is_some() =>
+        {
+            let mut tokens = TokenStream::from(minus.clone());
+            tokens.extend(value(rest));
+            Ok(tokens)
+        }
+        [TokenTree::Group(group)] if group.delimiter() == Delimiter::Bracket => memory(group),
+        [token, ..] => Err(Error(
+            token.span(),
+            "expected a register, a number, a \"label\", a [memory operand] or a {Rust expression}"
+                .into(),
+        )),
+        [] => Err(Error(span, "missing operand".into())),
+    }
+}
+
+/// A number or a `{...}` expression.
+fn value(tokens: &[TokenTree]) -> Option<TokenStream> {
+    match tokens {
+        [TokenTree::Literal(literal)] if !literal.to_string().starts_with('"') => {
+            Some(stream(tokens.to_vec()))
+        }
+        [TokenTree::Group(group)] if group.delimiter() == Delimiter::Brace => {
+            Some(expression(group).into())
+        }
+        _ => None,
+    }
+}
+
+/// The expression in a `{...}` group, in an invisible group rather than a
+/// block, which would be linted as unnecessary braces.
+fn expression(group: &Group) -> TokenTree {
+    let mut expression = Group::new(Delimiter::None, group.stream());
+    expression.set_span(group.span());
+    TokenTree::Group(expression)
+}
+
+/// The register of a general-purpose size or kind, by name.
+fn register(ident: &Ident) -> Result<TokenStream> {
+    let name = ident.to_string().to_ascii_uppercase();
+    let kind = REGISTERS
+        .iter()
+        .find(|(_, names)| names.contains(&name.as_str()))
+        .map(|(kind, _)| kind)
+        .ok_or_else(|| {
+            Error(
+                ident.span(),
+                format!(
+                    "unknown register `{}`; Rust values go in braces, like `{{{}}}`",
+                    ident, ident
+                ),
+            )
+        })?;
+    Ok(path(
+        &format!("{}::{}::{}", REGISTER, kind, name),
+        ident.span(),
+    ))
+}
+
+const REGISTERS: &[(&str, &[&str])] = &[
+    (
+        "R64",
+        &[
+            "RAX", "RBX", "RCX", "RDX", "RDI", "RSI", "RBP", "RSP", "R8", "R9", "R10", "R11",
+            "R12", "R13", "R14", "R15",
+        ],
+    ),
+    (
+        "R32",
+        &[
+            "EAX", "ECX", "EDX", "EBX", "ESP", "EBP", "ESI", "EDI", "R8D", "R9D", "R10D", "R11D",
+            "R12D", "R13D", "R14D", "R15D",
+        ],
+    ),
+    (
+        "R16",
+        &[
+            "AX", "CX", "DX", "BX", "SP", "BP", "SI", "DI", "R8W", "R9W", "R10W", "R11W", "R12W",
+            "R13W", "R14W", "R15W",
+        ],
+    ),
+    (
+        "R8",
+        &[
+            "AL", "CL", "DL", "BL", "AH", "CH", "DH", "BH", "R8B", "R9B", "R10B", "R11B", "R12B",
+            "R13B", "R14B", "R15B",
+        ],
+    ),
+    (
+        "Egpr",
+        &[
+            "R16", "R17", "R18", "R19", "R20", "R21", "R22", "R23", "R24", "R25", "R26", "R27",
+            "R28", "R29", "R30", "R31",
+        ],
+    ),
+    (
+        "Xmm",
+        &[
+            "XMM0", "XMM1", "XMM2", "XMM3", "XMM4", "XMM5", "XMM6", "XMM7", "XMM8", "XMM9",
+            "XMM10", "XMM11", "XMM12", "XMM13", "XMM14", "XMM15",
+        ],
+    ),
+    ("Sreg", &["ES", "CS", "SS", "DS", "FS", "GS"]),
+];
+
+/// A term of a memory operand.
+enum Term {
+    Register(TokenStream),
+    Scaled(TokenStream, &'static str),
+    Displacement(TokenStream),
+}
+
+fn memory(group: &Group) -> Result<TokenStream> {
+    let span = group.span();
+    let tokens: Vec<TokenTree> = group.stream().into_iter().collect();
+    let address = |name: &str, arguments: Vec<TokenStream>| {
+        let mut tokens = path(&format!("{}::{}", ADDRESS, name), span);
+        let mut list = Vec::new();
+        for (i, argument) in arguments.into_iter().enumerate() {
+            if i > 0 {
+                list.push(punct(',', Spacing::Alone, span));
+            }
+            list.extend(argument);
+        }
+        tokens.extend([parenthesized(stream(list), span)]);
+        tokens
+    };
+
+    match tokens.as_slice() {
+        [TokenTree::Literal(literal)] if literal.to_string().starts_with('"') => {
+            let mut tokens = path(&format!("{}::Ptr", LINK), literal.span());
+            tokens.extend([parenthesized(
+                TokenTree::Literal(literal.clone()).into(),
+                literal.span(),
+            )]);
+            return Ok(tokens);
+        }
+        [TokenTree::Group(group)] if group.delimiter() == Delimiter::Brace => {
+            return Ok(expression(group).into());
+        }
+        [TokenTree::Ident(gs), TokenTree::Punct(colon), offset @ ..]
+            if gs.to_string().eq_ignore_ascii_case("gs") && colon.as_char() == ':' =>
+        {
+            let offset = value(offset)
+                .ok_or_else(|| Error(span, "expected an offset, like `[gs:8]`".into()))?;
+            return Ok(address("GsIndex", vec![offset]));
+        }
+        _ => {}
+    }
+
+    let mut terms = Vec::new();
+    let mut negative = false;
+    let mut start = 0;
+    for end in 0..=tokens.len() {
+        let sign = match tokens.get(end) {
+            Some(TokenTree::Punct(punct)) if matches!(punct.as_char(), '+' | '-') => {
+                Some(punct.as_char() == '-')
+            }
+            None => Some(false),
+            _ => None,
+        };
+        let Some(next_negative) = sign else {
+            continue;
+        };
+        terms.push(term(&tokens[start..end], negative, span)?);
+        negative = next_negative;
+        start = end + 1;
+    }
+
+    let mut registers = Vec::new();
+    let mut scaled = None;
+    let mut displacement = None;
+    for term in terms {
+        match term {
+            Term::Register(register) => registers.push(register),
+            Term::Scaled(..) if scaled.is_some() => {
+                return Err(Error(span, "only one register can be scaled".into()))
+            }
+            Term::Scaled(register, scale) => scaled = Some((register, scale)),
+            Term::Displacement(_) if displacement.is_some() => {
+                return Err(Error(span, "only one displacement is allowed".into()))
+            }
+            Term::Displacement(value) => displacement = Some(value),
+        }
+    }
+    let mut registers = registers.into_iter();
+    Ok(
+        match (registers.next(), registers.next(), scaled, displacement) {
+            (Some(base), None, None, None) => address("Indirect", vec![base]),
+            (Some(base), None, None, Some(displacement)) => {
+                address("Index", vec![base, displacement])
+            }
+            (Some(base), Some(index), None, None) => address("Index", vec![index, base]),
+            (Some(base), None, Some((index, scale)), None) => address(
+                "ScaledIndex",
+                vec![path(&format!("{}::{}", ADDRESS, scale), span), index, base],
+            ),
+            _ => {
+                return Err(Error(
+                    span,
+                    "expected `[base]`, `[base + displacement]`, `[base + index]` or \
+                     `[base + index * scale]`"
+                        .into(),
+                ))
+            }
+        },
+    )
+}
+
+fn term(tokens: &[TokenTree], negative: bool, span: Span) -> Result<Term> {
+    let address_register = |ident: &Ident| {
+        let register = register(ident)?;
+        if !REGISTERS[0]
+            .1
+            .contains(&ident.to_string().to_ascii_uppercase().as_str())
+        {
+            return Err(Error(
+                ident.span(),
+                "addresses are formed from 64-bit registers".into(),
+            ));
+        }
+        if negative {
+            return Err(Error(
+                ident.span(),
+                "registers can not be subtracted".into(),
+            ));
+        }
+        Ok(register)
+    };
+    match tokens {
+        [TokenTree::Ident(ident)] => Ok(Term::Register(address_register(ident)?)),
+        [TokenTree::Ident(ident), TokenTree::Punct(star), TokenTree::Literal(scale)]
+            if star.as_char() == '*' =>
+        {
+            let scale = match scale.to_string().as_str() {
+                "1" => "Times1",
+                "2" => "Times2",
+                "4" => "Times4",
+                "8" => "Times8",
+                _ => return Err(Error(scale.span(), "the scale must be 1, 2, 4 or 8".into())),
+            };
+            Ok(Term::Scaled(address_register(ident)?, scale))
+        }
+        _ => {
+            let value = value(tokens).ok_or_else(|| {
+                Error(
+                    tokens.first().map_or(span, TokenTree::span),
+                    "expected a register, a number or a {Rust expression}".into(),
+                )
+            })?;
+            let mut tokens = Vec::new();
+            if negative {
+                tokens.push(punct('-', Spacing::Alone, span));
+            }
+            tokens.extend(value);
+            Ok(Term::Displacement(stream(tokens)))
+        }
+    }
+}
+
+/// Split tokens at a punctuation character, dropping an empty last part.
+fn split(tokens: Vec<TokenTree>, separator: char) -> Vec<Vec<TokenTree>> {
+    let mut parts = vec![Vec::new()];
+    for token in tokens {
+        match &token {
+            TokenTree::Punct(punct) if punct.as_char() == separator => parts.push(Vec::new()),
+            _ => parts.last_mut().unwrap().push(token),
+        }
+    }
+    if parts.len() > 1 && parts.last().unwrap().is_empty() {
+        parts.pop();
+    }
+    parts
+}
+
+/// The tokens of a path like `::a::b`, with the given span.
+fn path(path: &str, span: Span) -> TokenStream {
+    let mut tokens = Vec::new();
+    for (i, segment) in path.split("::").enumerate() {
+        if i > 0 {
+            tokens.push(punct(':', Spacing::Joint, span));
+            tokens.push(punct(':', Spacing::Alone, span));
+        }
+        if !segment.is_empty() {
+            tokens.push(TokenTree::Ident(Ident::new(segment, span)));
+        }
+    }
+    stream(tokens)
+}
+
+fn punct(c: char, spacing: Spacing, span: Span) -> TokenTree {
+    let mut punct = Punct::new(c, spacing);
+    punct.set_span(span);
+    TokenTree::Punct(punct)
+}
+
+fn parenthesized(tokens: TokenStream, span: Span) -> TokenTree {
+    let mut group = Group::new(Delimiter::Parenthesis, tokens);
+    group.set_span(span);
+    TokenTree::Group(group)
+}
+
+fn stream(tokens: Vec<TokenTree>) -> TokenStream {
+    tokens.into_iter().collect()
+}
+
+/// `::core::compile_error!("message")`, reported at `span`.
+fn compile_error(span: Span, message: &str) -> TokenStream {
+    let mut tokens = path("::core::compile_error", span);
+    tokens.extend([
+        punct('!', Spacing::Alone, span),
+        parenthesized(
+            TokenTree::Literal({
+                let mut literal = Literal::string(message);
+                literal.set_span(span);
+                literal
+            })
+            .into(),
+            span,
+        ),
+    ]);
+    tokens
+}
@@ -0,0 +1,284 @@
+//! The architecture-independent core of the assemblers: labels, constants,
+//! alignment padding and branch relaxation, around the encoder of an
+//! [`Arch`].
+
+mod relax;
+
+use self::relax::{Branch, Padding};
+use crate::link::{Label, Ptr, Reference, ReferenceFormat, Segment};
+use std::{marker::PhantomData, ops::RangeInclusive, panic::Location};
+
+/// An instruction set, as far as the assembler core needs to know it.
+pub trait Arch {
+    /// An encoded instruction.
+    type Encoding: Encoding;
+
+    /// The byte that pads code which execution falls through, like x86's
+    /// one-byte `NOP`.
+    const NOP: u8;
+
+    /// The most bytes an instruction may have.
+    const MAX_INSTRUCTION_LENGTH: usize;
+
+    /// The alignment of every instruction.
+    const INSTRUCTION_ALIGNMENT: usize;
+
+    /// The length of the short form of a relaxable branch.
+    const SHORT_BRANCH_LEN: usize;
+
+    /// The displacements that the short form of a branch can reach,
+    /// relative to the end of the branch.
+    const SHORT_BRANCH_RANGE: RangeInclusive<i64>;
+
+    /// Recognize the encoding of a branch with a reference of the given
+    /// format, which has a short form for nearby targets, and return the
+    /// opcode of the short form.
+    fn short_branch(encoding: &[u8], format: ReferenceFormat) -> Option<u8>;
+
+    /// Append the short form of a branch, with its displacement, which is
+    /// within [`SHORT_BRANCH_RANGE`](Self::SHORT_BRANCH_RANGE).
+    fn encode_short_branch(opcode: u8, displacement: i64, out: &mut Vec<u8>);
+}
+
+/// An encoded instruction, ready to be appended to a segment.
+pub trait Encoding {
+    /// The length of the serialized instruction, in bytes.
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The references of the instruction to labels, at offsets from its
+    /// start.
+    fn references(&self) -> impl IntoIterator<Item = (Label, Reference)>;
+
+    /// Append the encoding of the instruction to `out`.
+    fn serialize(&self, out: &mut Vec<u8>);
+
+    /// Append the encoding of the instruction to the data of `segment`,
+    /// and record its references.
+    fn encode_into(&self, segment: &mut Segment) {
+        for (label, reference) in self.references() {
+            segment.label_reference(reference.location, label, reference.format);
+        }
+        self.serialize(segment.data_mut());
+    }
+}
+
+/// Read-only data requested through [`AssemblerCore::const_bytes`].
+struct Constant {
+    label: Label,
+    bytes: Vec<u8>,
+    align: usize,
+}
+
+/// The state of an assembler for `A` that doesn't depend on its instruction
+/// set, which architecture-specific assemblers wrap.
+pub struct AssemblerCore<A: Arch> {
+    segment: Segment,
+    next_label: usize,
+    constants: Vec<Constant>,
+    /// Relaxable branches to labels, and alignment padding, in order of
+    /// offset, for branch relaxation.
+    branches: Vec<Branch>,
+    paddings: Vec<Padding>,
+    relax: bool,
+    arch: PhantomData<A>,
+}
+
+impl<A: Arch> Default for AssemblerCore<A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<A: Arch> AssemblerCore<A> {
+    pub fn new() -> Self {
+        Self {
+            segment: Segment::new(),
+            next_label: 0,
+            constants: Vec::new(),
+            branches: Vec::new(),
+            paddings: Vec::new(),
+            relax: false,
+            arch: PhantomData,
+        }
+    }
+
+    /// Shorten relaxable branches to labels in the same segment in
+    /// [`finish`](Self::finish), wherever the target is in range.
+    pub fn set_relax_branches(&mut self, relax: bool) {
+        self.relax = relax;
+    }
+
+    /// Generate a unique local label, of the form `prefix.N`.
+    pub fn fresh_label(&mut self, prefix: &str) -> Label {
+        let label = Label(&format!("{}.{}", prefix, self.next_label));
+        self.next_label += 1;
+        label
+    }
+
+    pub fn label(&mut self, label: impl Into<Label>) {
+        self.segment.label(label);
+    }
+
+    pub fn export_label(&mut self, label: impl Into<Label>) {
+        self.segment.export_label(label);
+    }
+
+    /// A pointer to a 64-bit constant in the
+    /// [constant pool](Self::constant_pool).
+    pub fn const_u64(&mut self, value: u64) -> Ptr {
+        self.constant(&value.to_le_bytes(), 8)
+    }
+
+    /// A pointer to a copy of `bytes` in the
+    /// [constant pool](Self::constant_pool).
+    pub fn const_bytes(&mut self, bytes: &[u8]) -> Ptr {
+        self.constant(bytes, 1)
+    }
+
+    /// Identical constants share a label, which is aligned for the strictest
+    /// of their requests.
+    fn constant(&mut self, bytes: &[u8], align: usize) -> Ptr {
+        if let Some(constant) = self
+            .constants
+            .iter_mut()
+            .find(|constant| constant.bytes == bytes)
+        {
+            constant.align = constant.align.max(align);
+            return constant.label.ptr();
+        }
+        let label = self.fresh_label("const");
+        self.constants.push(Constant {
+            label,
+            bytes: bytes.to_vec(),
+            align,
+        });
+        label.ptr()
+    }
+
+    /// Take the constants requested so far, as a segment of exported labels
+    /// to be embedded in read-only data.
+    pub fn constant_pool(&mut self) -> Segment {
+        let mut pool = Segment::new();
+        for constant in self.constants.drain(..) {
+            pool.pad_to_alignment(constant.align);
+            pool.export_label(constant.label);
+            pool.extend(constant.bytes);
+        }
+        pool
+    }
+
+    /// Set the byte used to pad code in
+    /// [`pad_to_alignment`](Self::pad_to_alignment).
+    pub fn set_fill(&mut self, fill: u8) {
+        self.segment.set_fill(fill);
+    }
+
+    pub fn pad_to_alignment(&mut self, alignment: usize) {
+        let fill = self.segment.fill;
+        self.pad(alignment, fill);
+    }
+
+    /// Pad to `alignment` with `fill`, recording the padding to be
+    /// regenerated by relaxation.
+    fn pad(&mut self, alignment: usize, fill: u8) {
+        let offset = self.segment.len();
+        let previous_fill = self.segment.fill;
+        self.segment.set_fill(fill);
+        self.segment.pad_to_alignment(alignment);
+        self.segment.set_fill(previous_fill);
+        self.paddings.push(Padding {
+            offset,
+            len: self.segment.len() - offset,
+            alignment,
+            fill,
+        });
+    }
+
+    pub fn append_reference(&mut self, label: impl Into<Label>, format: ReferenceFormat) {
+        self.segment.append_reference(label, format);
+    }
+
+    /// See [`Segment::comment`].
+    pub fn comment(&mut self, text: &str) {
+        self.segment.comment(text);
+    }
+
+    /// Append data to the code stream. It must not be reachable by
+    /// execution.
+    pub fn append(&mut self, bytes: &[u8]) {
+        self.segment.data_mut().extend_from_slice(bytes);
+    }
+
+    /// Make room for `count` more instructions of any length.
+    pub fn reserve_instructions(&mut self, count: usize) {
+        self.segment
+            .reserve_capacity(count * A::MAX_INSTRUCTION_LENGTH);
+    }
+
+    /// Append an encoded instruction. It is attributed to the source
+    /// location of the caller in debugging information.
+    #[track_caller]
+    pub fn push(&mut self, encoding: &A::Encoding) {
+        self.segment.set_location(Location::caller());
+        let offset = self.segment.len();
+        assert!(
+            offset.is_multiple_of(A::INSTRUCTION_ALIGNMENT),
+            "instruction at offset {:#x} is not aligned to {} bytes",
+            offset,
+            A::INSTRUCTION_ALIGNMENT
+        );
+        encoding.encode_into(&mut self.segment);
+        if let Some((label, reference)) = encoding.references().into_iter().next() {
+            if let Some(short_opcode) =
+                A::short_branch(&self.segment.data[offset..], reference.format)
+            {
+                self.branches.push(Branch {
+                    offset,
+                    len: self.segment.len() - offset,
+                    reference: offset + reference.location,
+                    short_opcode,
+                    target: label,
+                });
+            }
+        }
+    }
+
+    /// The length of the code so far.
+    pub fn len(&self) -> usize {
+        self.segment.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segment.is_empty()
+    }
+
+    /// Append the code of another assembler, which execution falls through
+    /// into. If it requires alignment, the padding is [`Arch::NOP`]s.
+    pub fn embed(&mut self, other: AssemblerCore<A>) {
+        self.pad(other.segment.alignment, A::NOP);
+        self.constants.extend(other.constants);
+        let base = self.segment.embed(other.segment);
+        self.branches
+            .extend(other.branches.into_iter().map(|branch| Branch {
+                offset: base + branch.offset,
+                reference: base + branch.reference,
+                ..branch
+            }));
+        self.paddings
+            .extend(other.paddings.into_iter().map(|padding| Padding {
+                offset: base + padding.offset,
+                ..padding
+            }));
+    }
+
+    pub fn finish(mut self) -> Segment {
+        if self.relax {
+            relax::relax::<A>(&mut self.segment, &self.branches, &self.paddings);
+        }
+        self.segment
+    }
+}
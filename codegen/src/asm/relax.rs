@@ -0,0 +1,363 @@
+//! Branch relaxation: shortening branches to labels in the same segment to
+//! the short form of their architecture, like x86's 2-byte `JMP rel8`,
+//! wherever the target is in range.
+//!
+//! Every branch starts out short, and a worklist holds the branches whose
+//! range has to be checked. A branch that is out of range grows to its near
+//! form, which can only push other branches out of range if they span it,
+//! so only those are checked again. Branches only ever grow, so this reaches
+//! a fixed point after each branch has grown at most once.
+//!
+//! Alignment padding changes size when the code before it does. It is
+//! counted at its largest possible size when checking ranges, which keeps
+//! the distances between spans independent of the padding, at the cost of
+//! not shortening some branches across it.
+
+use super::Arch;
+use crate::link::{Label, Segment};
+
+/// A relaxable branch to a label, recorded by the assembler.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Branch {
+    /// Offset of the instruction in the segment.
+    pub offset: usize,
+    /// Length of the near form.
+    pub len: usize,
+    /// Location of the reference of the near form to its target.
+    pub reference: usize,
+    /// Opcode of the short form, as given by [`Arch::short_branch`].
+    pub short_opcode: u8,
+    pub target: Label,
+}
+
+/// Alignment padding recorded by the assembler, which is regenerated after
+/// relaxation.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Padding {
+    pub offset: usize,
+    pub len: usize,
+    pub alignment: usize,
+    pub fill: u8,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    Fixed,
+    /// A relaxable branch, by its index in the branch table.
+    Branch(usize),
+    Padding(Padding),
+}
+
+/// A contiguous range of the segment, before relaxation.
+#[derive(Debug, Clone, Copy)]
+struct Span {
+    offset: usize,
+    len: usize,
+    kind: Kind,
+}
+
+/// A Fenwick tree of the growth of branches, for the total growth of all
+/// branches before a given one.
+struct Growth(Vec<usize>);
+
+impl Growth {
+    fn add(&mut self, index: usize, growth: usize) {
+        let mut i = index + 1;
+        while i < self.0.len() {
+            self.0[i] += growth;
+            i += i & i.wrapping_neg();
+        }
+    }
+
+    /// The total growth of the branches before `index`.
+    fn before(&self, index: usize) -> usize {
+        let mut total = 0;
+        let mut i = index;
+        while i > 0 {
+            total += self.0[i];
+            i -= i & i.wrapping_neg();
+        }
+        total
+    }
+}
+
+/// Relax the branches of `segment`, given in order of offset, rewriting its
+/// data and moving its labels, references, source locations and comments to
+/// match. `paddings`, also in order of offset, are regenerated for their
+/// alignment.
+///
+/// Branches to labels that are not defined in the segment keep their near
+/// form, to be resolved by the linker.
+pub(crate) fn relax<A: Arch>(segment: &mut Segment, branches: &[Branch], paddings: &[Padding]) {
+    let short_len = A::SHORT_BRANCH_LEN;
+    let (min, max) = (*A::SHORT_BRANCH_RANGE.start(), *A::SHORT_BRANCH_RANGE.end());
+    // A short branch spanning another branch has at most this many branches
+    // between them, as each takes at least `short_len` bytes of its range.
+    let window = (max.max(-min) as usize) / short_len + 1;
+    assert_eq!(
+        segment.reserved, 0,
+        "cannot relax a segment with reserved space"
+    );
+    let branches: Vec<Branch> = branches
+        .iter()
+        .filter(|branch| segment.labels.contains_key(&branch.target))
+        .copied()
+        .collect();
+    let spans = spans(segment.data.len(), &branches, paddings);
+    let span_index = |offset: usize| spans.partition_point(|span| span.offset + span.len <= offset);
+
+    // Start of each span, with every branch short and padding at its
+    // largest, and the number of branches before each span.
+    let mut base = Vec::with_capacity(spans.len() + 1);
+    let mut rank = Vec::with_capacity(spans.len() + 1);
+    let (mut position, mut count) = (0, 0);
+    for span in &spans {
+        base.push(position);
+        rank.push(count);
+        position += match span.kind {
+            Kind::Fixed => span.len,
+            Kind::Branch(_) => {
+                count += 1;
+                short_len
+            }
+            Kind::Padding(padding) => padding.alignment - 1,
+        };
+    }
+    base.push(position);
+    rank.push(count);
+    let base_position = |offset: usize| {
+        let index = span_index(offset);
+        let within = match spans.get(index) {
+            Some(span) if matches!(span.kind, Kind::Fixed) => offset - span.offset,
+            _ => 0,
+        };
+        (base[index] + within, rank[index])
+    };
+
+    // The end of each short branch and its target, without growth.
+    let ranges: Vec<(usize, usize, usize)> = branches
+        .iter()
+        .map(|branch| {
+            let (start, _) = base_position(branch.offset);
+            let definition = segment.labels[&branch.target];
+            let (target, target_rank) = base_position(definition.offset);
+            (start + short_len, target, target_rank)
+        })
+        .collect();
+
+    let mut growth = Growth(vec![0; branches.len() + 1]);
+    let mut long = vec![false; branches.len()];
+    let mut queued = vec![true; branches.len()];
+    let mut worklist: Vec<usize> = (0..branches.len()).rev().collect();
+    while let Some(i) = worklist.pop() {
+        queued[i] = false;
+        let (end, target, target_rank) = ranges[i];
+        let displacement =
+            (target + growth.before(target_rank)) as i64 - (end + growth.before(i)) as i64;
+        if long[i] || A::SHORT_BRANCH_RANGE.contains(&displacement) {
+            continue;
+        }
+        long[i] = true;
+        growth.add(i, branches[i].len - short_len);
+        for j in i.saturating_sub(window)..(i + window + 1).min(branches.len()) {
+            if !long[j] && !queued[j] {
+                queued[j] = true;
+                worklist.push(j);
+            }
+        }
+    }
+
+    // Lay out the spans for real, with padding for the actual alignment.
+    let mut starts = Vec::with_capacity(spans.len() + 1);
+    let mut position: usize = 0;
+    for span in &spans {
+        starts.push(position);
+        position += match span.kind {
+            Kind::Fixed => span.len,
+            Kind::Branch(i) if long[i] => branches[i].len,
+            Kind::Branch(_) => short_len,
+            Kind::Padding(padding) => position.next_multiple_of(padding.alignment) - position,
+        };
+    }
+    starts.push(position);
+    let map = |offset: usize| {
+        let index = span_index(offset);
+        match spans.get(index) {
+            Some(span) if !matches!(span.kind, Kind::Padding(_)) => {
+                starts[index] + (offset - span.offset)
+            }
+            _ => starts[index],
+        }
+    };
+
+    let mut data = Vec::with_capacity(position);
+    for (index, span) in spans.iter().enumerate() {
+        let old = &segment.data[span.offset..][..span.len];
+        match span.kind {
+            Kind::Branch(i) if !long[i] => {
+                let target = map(segment.labels[&branches[i].target].offset);
+                let displacement = target as i64 - (starts[index] + short_len) as i64;
+                A::encode_short_branch(branches[i].short_opcode, displacement, &mut data);
+            }
+            Kind::Padding(padding) => {
+                data.resize(starts[index + 1], padding.fill);
+            }
+            _ => data.extend_from_slice(old),
+        }
+    }
+    segment.data = data;
+
+    // Short branches are resolved, so their references are dropped.
+    let resolved: Vec<usize> = branches
+        .iter()
+        .zip(&long)
+        .filter(|&(_, &long)| !long)
+        .map(|(branch, _)| branch.reference)
+        .collect();
+    for references in segment.references.values_mut() {
+        references.retain(|reference| resolved.binary_search(&reference.location).is_err());
+        for reference in references.iter_mut() {
+            reference.location = map(reference.location);
+        }
+    }
+    segment
+        .references
+        .retain(|_, references| !references.is_empty());
+    for definition in segment.labels.values_mut() {
+        definition.offset = map(definition.offset);
+    }
+    for (offset, _) in &mut segment.locations {
+        *offset = map(*offset);
+    }
+    // Padding that shrank away leaves locations at the same offset, of
+    // which the last applies.
+    segment.locations.dedup_by(|next, previous| {
+        next.0 == previous.0 && {
+            *previous = *next;
+            true
+        }
+    });
+    for (offset, _) in &mut segment.comments {
+        *offset = map(*offset);
+    }
+}
+
+/// Split `len` bytes into spans at the branches and paddings.
+fn spans(len: usize, branches: &[Branch], paddings: &[Padding]) -> Vec<Span> {
+    let mut spans = Vec::with_capacity(2 * (branches.len() + paddings.len()) + 1);
+    let mut position = 0;
+    let mut push = |offset: usize, len: usize, kind| {
+        if offset > position {
+            spans.push(Span {
+                offset: position,
+                len: offset - position,
+                kind: Kind::Fixed,
+            });
+        }
+        spans.push(Span { offset, len, kind });
+        position = offset + len;
+    };
+    let (mut i, mut j) = (0, 0);
+    while i < branches.len() || j < paddings.len() {
+        if j == paddings.len() || (i < branches.len() && branches[i].offset < paddings[j].offset) {
+            push(branches[i].offset, branches[i].len, Kind::Branch(i));
+            i += 1;
+        } else {
+            let padding = paddings[j];
+            push(padding.offset, padding.len, Kind::Padding(padding));
+            j += 1;
+        }
+    }
+    if position < len {
+        spans.push(Span {
+            offset: position,
+            len: len - position,
+            kind: Kind::Fixed,
+        });
+    }
+    spans
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        link::Label,
+        x86::{instruction::*, register::R64::*, Assembler},
+    };
+
+    fn relaxed(emit: impl FnOnce(&mut Assembler)) -> crate::link::Segment {
+        let mut asm = Assembler::new();
+        asm.set_relax_branches(true);
+        emit(&mut asm);
+        asm.finish()
+    }
+
+    #[test]
+    fn short_branches() {
+        let segment = relaxed(|asm| {
+            asm.label("top");
+            asm.push(DEC(RCX));
+            asm.push(JNZ(Label("top")));
+            asm.push(JMP(Label("end")));
+            asm.push(NOP);
+            asm.label("end");
+            asm.push(RET);
+        });
+        assert_eq!(
+            segment.bytes(),
+            [0x48, 0xff, 0xc9, 0x75, -5i8 as u8, 0xeb, 0x01, 0x90, 0xc3]
+        );
+        assert!(segment.references.is_empty());
+        assert_eq!(segment.labels[&Label("end")].offset, 8);
+    }
+
+    #[test]
+    fn external_and_distant_branches_stay_near() {
+        let segment = relaxed(|asm| {
+            asm.push(JMP(Label("elsewhere")));
+            asm.push(JZ(Label("far")));
+            asm.append(&[0xcc; 200]);
+            asm.label("far");
+            asm.push(RET);
+        });
+        let code = segment.bytes();
+        assert_eq!(code[0], 0xe9);
+        assert_eq!(&code[5..7], [0x0f, 0x84]);
+        // Both are left for the linker to resolve.
+        assert_eq!(segment.references.len(), 2);
+    }
+
+    #[test]
+    fn growth_cascades() {
+        // The first branch is in range of its target while the second is
+        // short, but the second is not, and grows past the range of the
+        // first.
+        let segment = relaxed(|asm| {
+            asm.push(JMP(Label("a")));
+            asm.push(JMP(Label("b")));
+            asm.append(&[0xcc; 124]);
+            asm.label("a");
+            asm.append(&[0xcc; 10]);
+            asm.label("b");
+            asm.push(RET);
+        });
+        let code = segment.bytes();
+        assert_eq!(code[0], 0xe9);
+        assert_eq!(code[5], 0xe9);
+        assert_eq!(segment.labels[&Label("b")].offset, 5 + 5 + 124 + 10);
+    }
+
+    #[test]
+    fn padding_is_regenerated() {
+        let segment = relaxed(|asm| {
+            asm.push(JMP(Label("aligned")));
+            asm.pad_to_alignment(16);
+            asm.label("aligned");
+            asm.push(RET);
+        });
+        let code = segment.bytes();
+        assert_eq!(code.len(), 17);
+        assert_eq!(&code[..2], [0xeb, 14]);
+        assert_eq!(segment.labels[&Label("aligned")].offset, 16);
+    }
+}
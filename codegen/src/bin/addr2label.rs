@@ -0,0 +1,55 @@
+//! Resolve addresses of a linked kernel to `label+offset`, from its symbol
+//! file.
+//!
+//! ```sh
+//! cargo run --bin addr2label -- 0xffffffff80004321 ffffffff800042a7
+//! cargo run --bin addr2label < crash.log
+//! ```
+//!
+//! Addresses are given in hexadecimal, with or without `0x`. Without any,
+//! every `0x...` number in the standard input, such as those printed by a
+//! panic or a backtrace, is annotated with its label instead. The symbol
+//! file defaults to `kernel.sym`, and is set with `--symbols <path>`.
+
+use std::{
+    env,
+    error::Error,
+    fs,
+    io::{self, BufRead, Write},
+};
+
+use alpha_codegen::symbolize::Symbolizer;
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let mut symbols = String::from("kernel.sym");
+    let mut addresses = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--symbols" => symbols = args.next().ok_or("--symbols needs a path")?,
+            _ if arg.starts_with('-') => {
+                return Err(format!("unknown option {:?}", arg).into());
+            }
+            _ => addresses.push(arg),
+        }
+    }
+    let symbolizer = Symbolizer::parse(&fs::read_to_string(&symbols)?)?;
+
+    let mut out = io::stdout().lock();
+    if addresses.is_empty() {
+        for line in io::stdin().lock().lines() {
+            writeln!(out, "{}", symbolizer.annotate(&line?))?;
+        }
+        return Ok(());
+    }
+    for arg in addresses {
+        let digits = arg.strip_prefix("0x").unwrap_or(&arg);
+        let address =
+            u64::from_str_radix(digits, 16).map_err(|_| format!("invalid address {:?}", arg))?;
+        match symbolizer.resolve(address) {
+            Some(resolved) => writeln!(out, "{:#018x} {}", address, resolved)?,
+            None => writeln!(out, "{:#018x} ??", address)?,
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,388 @@
+//! Assemble, link and package images from the command line, with the same
+//! assembler and linker that the kernel generator uses.
+//!
+//! ```sh
+//! cargo run --bin alpha -- asm boot.s --base 0x7c00 -o boot.bin
+//! cargo run --bin alpha -- link start.s lib.s --base 0xffffffff80000000 -o kernel.elf
+//! cargo run --bin alpha -- link --config image.toml
+//! cargo run --bin alpha -- inspect kernel.elf
+//! cargo run --bin alpha -- image kernel.elf --limine /usr/share/limine -o alpha.iso
+//! ```
+//!
+//! - `asm INPUT` assembles one [Intel-syntax](alpha_codegen::x86::parse)
+//!   file into a flat binary, entered at the start of its first section.
+//! - `link INPUT...` assembles and links any number of files into an ELF
+//!   image, entered at the exported label `entry`, and writes its symbol
+//!   file next to it. Sections of the same name are concatenated, in the
+//!   order of the inputs, and unless a configuration declares the segments,
+//!   each section is a page-aligned segment: executable if its name starts
+//!   with `.text`, writable if it starts with `.data` or `.bss`, and
+//!   read-only otherwise. Local labels are only visible within their own
+//!   file, and are named `FILE:LABEL` in the symbol file when there is more
+//!   than one input.
+//! - `inspect IMAGE` prints the headers of an ELF image, and its labels if
+//!   it has a symbol file.
+//! - `image KERNEL --limine DIR` builds a CD image that boots the kernel with
+//!   the Limine binaries in `DIR`.
+//!
+//! `asm` and `link` take `--base ADDR` for the address of the first segment
+//! (default 0), `--entry LABEL` and `--format elf|bin|ihex|srec|iso`, and
+//! `--config PATH` for an [image configuration](alpha_codegen::config),
+//! whose inputs are linked before those on the command line and whose
+//! settings the options override. Every command takes `-o PATH`, which
+//! defaults to the first input, and takes the extension of each output
+//! format, which is only replaced once it has been written in full.
+
+use std::{
+    env,
+    error::Error,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use alpha_codegen::{
+    config::{ImageConfig, OutputFormat, SegmentConfig},
+    elf64::{
+        file_header::{FileHeader, ET_DYN, ET_EXEC},
+        program::{Phdr, PF_R, PF_W, PF_X, PT_LOAD},
+    },
+    limine,
+    link::Segment,
+    symbolize::Symbolizer,
+    x86::parse::parse,
+};
+
+const USAGE: &str = "usage: alpha <asm|link|inspect|image> [options] <inputs>...";
+
+/// The arguments of a command.
+struct Args {
+    inputs: Vec<PathBuf>,
+    output: Option<PathBuf>,
+    base: Option<u64>,
+    entry: Option<String>,
+    format: Option<OutputFormat>,
+    limine: Option<PathBuf>,
+    config: Option<PathBuf>,
+}
+
+impl Args {
+    fn parse(args: impl Iterator<Item = String>) -> Result<Self, Box<dyn Error>> {
+        let mut parsed = Self {
+            inputs: Vec::new(),
+            output: None,
+            base: None,
+            entry: None,
+            format: None,
+            limine: None,
+            config: None,
+        };
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+            match arg.as_str() {
+                "-o" | "--output" => parsed.output = Some(value()?.into()),
+                "--base" => parsed.base = Some(parse_address(&value()?)?),
+                "--entry" => parsed.entry = Some(value()?),
+                "--format" => parsed.format = Some(OutputFormat::parse(&value()?)?),
+                "--limine" => parsed.limine = Some(value()?.into()),
+                "--config" => parsed.config = Some(value()?.into()),
+                _ if arg.starts_with('-') => {
+                    return Err(format!("unknown option {:?}", arg).into());
+                }
+                _ => parsed.inputs.push(arg.into()),
+            }
+        }
+        Ok(parsed)
+    }
+
+    /// The output path, defaulting to the first input with `extension`.
+    fn output(&self, extension: &str) -> PathBuf {
+        self.output
+            .clone()
+            .unwrap_or_else(|| self.inputs[0].with_extension(extension))
+    }
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("error: {}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run() -> Result<(), Box<dyn Error>> {
+    let mut args = env::args().skip(1);
+    let command = args.next().ok_or(USAGE)?;
+    let args = Args::parse(args)?;
+    match command.as_str() {
+        "asm" => {
+            if args.inputs.len() != 1 {
+                return Err("asm takes one input".into());
+            }
+            link(&args, OutputFormat::Bin)
+        }
+        "link" => link(&args, OutputFormat::Elf),
+        "inspect" => {
+            let [input] = args.inputs.as_slice() else {
+                return Err("inspect takes one input".into());
+            };
+            let mut out = BufWriter::new(io::stdout().lock());
+            inspect(&mut out, input)?;
+            Ok(out.flush()?)
+        }
+        "image" => {
+            let [input] = args.inputs.as_slice() else {
+                return Err("image takes one input".into());
+            };
+            let limine_dir = args.limine.as_ref().ok_or("image needs --limine DIR")?;
+            let iso = limine::iso_image(fs::read(input)?, limine_dir)?;
+            write_file(&args.output("iso"), |file| Ok(file.write_all(&iso)?))
+        }
+        _ => Err(format!("unknown command {:?}; {}", command, USAGE).into()),
+    }
+}
+
+/// Parse an address, in hexadecimal with `0x` or in decimal.
+fn parse_address(text: &str) -> Result<u64, Box<dyn Error>> {
+    let digits = text.replace('_', "");
+    let address = match digits.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => digits.parse(),
+    };
+    address.map_err(|_| format!("invalid address {:?}", text).into())
+}
+
+/// Assemble and link the inputs, with the layout of `args.config`, and write
+/// the image in `args.format`, the formats of the configuration, or
+/// `default_format`.
+fn link(args: &Args, default_format: OutputFormat) -> Result<(), Box<dyn Error>> {
+    let mut config = match &args.config {
+        Some(path) => {
+            let text = fs::read_to_string(path)?;
+            let mut config = ImageConfig::parse(&text)
+                .map_err(|error| format!("{}: {}", path.display(), error))?;
+            // Paths in the configuration are relative to it.
+            let dir = path.parent().unwrap_or(Path::new(""));
+            for input in &mut config.inputs {
+                *input = dir.join(&input);
+            }
+            config.output = config.output.map(|output| dir.join(output));
+            if let Some(limine) = &mut config.limine {
+                limine.dir = limine.dir.as_ref().map(|limine_dir| dir.join(limine_dir));
+            }
+            config
+        }
+        None => ImageConfig::parse("")?,
+    };
+    config.inputs.extend(args.inputs.iter().cloned());
+    config.base_address = args.base.unwrap_or(config.base_address);
+    config.entry = args.entry.clone().or(config.entry);
+    if let Some(format) = args.format {
+        config.formats = vec![format];
+    } else if config.formats.is_empty() {
+        config.formats = vec![default_format];
+    }
+    let output = args
+        .output
+        .clone()
+        .or(config.output.clone())
+        .or(config.inputs.first().cloned())
+        .ok_or("no inputs")?;
+
+    // Sections of the same name are concatenated, in order of first use.
+    // Local labels are scoped to their input, so that they don't clash.
+    let scoped = config.inputs.len() > 1;
+    let mut sections: Vec<(String, Segment)> = Vec::new();
+    for input in &config.inputs {
+        let text = fs::read_to_string(input)?;
+        let parsed = parse(&text).map_err(|error| format!("{}: {}", input.display(), error))?;
+        for (name, mut segment) in parsed {
+            if scoped {
+                // Symbol files separate fields with whitespace.
+                let scope = input
+                    .display()
+                    .to_string()
+                    .replace(char::is_whitespace, "_");
+                segment.scope_local_labels(&scope);
+            }
+            match sections.iter_mut().find(|(other, _)| *other == name) {
+                Some((_, existing)) => {
+                    existing.embed(segment);
+                }
+                None => sections.push((name, segment)),
+            }
+        }
+    }
+    if sections.is_empty() {
+        return Err("the inputs have no sections".into());
+    }
+
+    // Without a layout, each section is a page-aligned segment, with flags
+    // by its name.
+    if config.segments.is_empty() {
+        for (name, _) in &sections {
+            let flags = if name.starts_with(".text") {
+                PF_R | PF_X
+            } else if name.starts_with(".data") || name.starts_with(".bss") {
+                PF_R | PF_W
+            } else {
+                PF_R
+            };
+            config.segments.push(SegmentConfig {
+                name: name.clone(),
+                flags,
+                align: 1 << 12,
+                address: None,
+            });
+        }
+    }
+
+    // `asm` enters at the start of the image, which it has no other label
+    // for.
+    let flat = config.formats.iter().all(|format| {
+        matches!(
+            format,
+            OutputFormat::Bin | OutputFormat::Ihex | OutputFormat::Srec
+        )
+    });
+    let mut linker = config.linker(sections)?;
+    if config.entry.is_none() && flat {
+        let first = linker.segment_id(&config.segments[0].name).unwrap();
+        linker.export_segment_bounds(first, "__image_start", "__first_segment_end");
+        linker.set_entry("__image_start");
+    }
+    linker.check_labels()?;
+    let linked = linker.finish();
+
+    for &format in &config.formats {
+        let output = output.with_extension(format.extension());
+        match format {
+            OutputFormat::Elf => {
+                write_file(&output, |file| Ok(linked.write_debuggable(file)?))?;
+                write_file(&output.with_extension("sym"), |file| {
+                    Ok(linked.write_symbols(file)?)
+                })?;
+            }
+            OutputFormat::Bin => write_file(&output, |file| Ok(linked.write_flat(file)?))?,
+            OutputFormat::Ihex => write_file(&output, |file| Ok(linked.write_ihex(file)?))?,
+            OutputFormat::Srec => write_file(&output, |file| Ok(linked.write_srec(file)?))?,
+            OutputFormat::Iso => {
+                let limine_dir = args
+                    .limine
+                    .clone()
+                    .or(config.limine.as_ref().and_then(|limine| limine.dir.clone()))
+                    .ok_or("iso needs --limine DIR, or a Limine dir in the config")?;
+                let mut kernel = Vec::new();
+                linked.write_debuggable(&mut kernel)?;
+                let iso = limine::iso_image(kernel, &limine_dir)?;
+                write_file(&output, |file| Ok(file.write_all(&iso)?))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Write a file with `write`, through a temporary file that replaces `path`
+/// once it is complete, so that a failure doesn't leave a partial file.
+fn write_file(
+    path: &Path,
+    write: impl FnOnce(&mut BufWriter<File>) -> Result<(), Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let temporary = PathBuf::from(temporary);
+    let result = File::create(&temporary)
+        .map_err(Into::into)
+        .and_then(|file| {
+            let mut file = BufWriter::new(file);
+            write(&mut file)?;
+            Ok(file.into_inner().map_err(io::Error::from)?.sync_all()?)
+        })
+        .and_then(|()| Ok(fs::rename(&temporary, path)?));
+    if result.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+    result
+}
+
+/// Print the file header and program headers of an ELF image, and the labels
+/// from its symbol file, if it has one, in the format of
+/// `Linked::write_report`.
+fn inspect<W: Write>(out: &mut W, path: &Path) -> Result<(), Box<dyn Error>> {
+    let image = fs::read(path)?;
+    let header: FileHeader = read_pod(&image, 0)?;
+    let type_ = match header.e_type {
+        ET_EXEC => "EXEC (executable)".to_owned(),
+        ET_DYN => "DYN (position-independent executable)".to_owned(),
+        other => format!("{:#x}", other),
+    };
+    writeln!(out, "File header:")?;
+    writeln!(out, "  Type:            {}", type_)?;
+    writeln!(out, "  Entry point:     {:#x}", header.e_entry)?;
+    writeln!(
+        out,
+        "  Program headers: {} of {} bytes, at offset {:#x}",
+        header.e_phnum, header.e_phentsize, header.e_phoff
+    )?;
+
+    writeln!(out)?;
+    writeln!(out, "Program headers:")?;
+    writeln!(
+        out,
+        "  {:<12} {:<18} {:<18} {:<18} {:<10} {:<10} Flg Align",
+        "Type", "Offset", "VirtAddr", "PhysAddr", "FileSiz", "MemSiz"
+    )?;
+    for i in 0..header.e_phnum as usize {
+        let offset = header.e_phoff as usize + i * header.e_phentsize as usize;
+        let phdr: Phdr = read_pod(&image, offset)?;
+        let type_ = match phdr.p_type {
+            PT_LOAD => "LOAD".to_owned(),
+            other => format!("{:#x}", other),
+        };
+        let flags: String = [(PF_R, 'R'), (PF_W, 'W'), (PF_X, 'E')]
+            .iter()
+            .map(|&(flag, c)| if phdr.p_flags & flag != 0 { c } else { ' ' })
+            .collect();
+        writeln!(
+            out,
+            "  {:<12} {:#018x} {:#018x} {:#018x} {:#010x} {:#010x} {} {:#x}",
+            type_,
+            phdr.p_offset,
+            phdr.p_vaddr,
+            phdr.p_paddr,
+            phdr.p_filesz,
+            phdr.p_memsz,
+            flags,
+            phdr.p_align
+        )?;
+    }
+
+    let symbols = match fs::read_to_string(path.with_extension("sym")) {
+        Ok(text) => Symbolizer::parse(&text)?,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(error) => return Err(error.into()),
+    };
+    writeln!(out)?;
+    writeln!(out, "Labels:")?;
+    for symbol in symbols.symbols() {
+        let scope = if symbol.exported { 'g' } else { 'l' };
+        writeln!(
+            out,
+            "  {:#018x} {:#010x} {} {:<18} {}",
+            symbol.address, symbol.size, scope, symbol.segment, symbol.name
+        )?;
+    }
+    Ok(())
+}
+
+fn read_pod<T: bytemuck::Pod>(image: &[u8], offset: usize) -> Result<T, Box<dyn Error>> {
+    let bytes = image
+        .get(offset..offset + size_of::<T>())
+        .ok_or("ELF header is out of bounds")?;
+    Ok(bytemuck::pod_read_unaligned(bytes))
+}
@@ -0,0 +1,325 @@
+//! Disassemble the executable segments of a linked kernel with the built-in
+//! decoder, and diff the listing against a checked-in golden listing, to
+//! show how changes to the generator affect the emitted machine code.
+//!
+//! ```sh
+//! cargo run --example kernel && cargo run --bin objdump-diff
+//! cargo run --example kernel && cargo run --bin objdump-diff -- --update
+//! ```
+//!
+//! The kernel defaults to `kernel.elf`, with its labels read from the symbol
+//! file next to it, and its comments, if any, from the comment file. The golden listing is `golden/kernel.lst`. Exits with
+//! status 1 if the listings differ, and `--update` replaces the golden
+//! listing instead.
+
+use std::{
+    env,
+    error::Error,
+    fmt::Write as _,
+    fs, io,
+    ops::Range,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use alpha_codegen::{
+    elf64::{
+        file_header::FileHeader,
+        program::{Phdr, PF_X, PT_LOAD},
+    },
+    symbolize::{Symbol, Symbolizer},
+    x86::{
+        decode::{decode, Base, Decoded, Operand},
+        instruction::Immediate,
+    },
+};
+
+/// Lines of unchanged context around each hunk of the diff.
+const CONTEXT: usize = 3;
+
+/// Columns taken by the encoding of an instruction in the listing.
+const BYTES_WIDTH: usize = 30;
+
+fn main() -> Result<ExitCode, Box<dyn Error>> {
+    let mut update = false;
+    let mut kernel = PathBuf::from("kernel.elf");
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--update" => update = true,
+            _ if arg.starts_with('-') => {
+                return Err(format!("unknown option {:?}", arg).into());
+            }
+            _ => kernel = PathBuf::from(arg),
+        }
+    }
+    let golden = Path::new(env!("CARGO_MANIFEST_DIR")).join("golden/kernel.lst");
+
+    let image = fs::read(&kernel)?;
+    let symbolizer = Symbolizer::parse(&fs::read_to_string(kernel.with_extension("sym"))?)?;
+    let comments = match fs::read_to_string(kernel.with_extension("comments")) {
+        Ok(text) => read_comments(&text)?,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(error) => return Err(error.into()),
+    };
+    let listing = listing(&image, &symbolizer, &comments)?;
+
+    if update {
+        fs::create_dir_all(golden.parent().unwrap())?;
+        fs::write(&golden, listing)?;
+        eprintln!("updated {}", golden.display());
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let expected = fs::read_to_string(&golden).unwrap_or_default();
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = listing.lines().collect();
+    if expected == actual {
+        return Ok(ExitCode::SUCCESS);
+    }
+    println!("--- {}", golden.display());
+    println!("+++ {}", kernel.display());
+    print!("{}", unified_diff(&expected, &actual));
+    Ok(ExitCode::FAILURE)
+}
+
+/// Parse a comment file, as written by `Linked::write_comments`, into
+/// `(address, comment)` pairs.
+fn read_comments(text: &str) -> Result<Vec<(u64, String)>, Box<dyn Error>> {
+    let mut comments = Vec::new();
+    for line in text.lines() {
+        let mut fields = line.splitn(3, ' ');
+        let (Some(address), Some(_segment), Some(comment)) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            return Err(format!("malformed comment {:?}", line).into());
+        };
+        comments.push((u64::from_str_radix(address, 16)?, comment.to_string()));
+    }
+    Ok(comments)
+}
+
+/// List every instruction of the executable `PT_LOAD` segments of an ELF
+/// image, under the labels that precede them.
+///
+/// Instructions are given at offsets from their label, so that the listing
+/// of a routine only changes when the routine does, after the comments on
+/// them. Bytes that do not decode are listed one at a time.
+fn listing(
+    image: &[u8],
+    symbolizer: &Symbolizer,
+    comments: &[(u64, String)],
+) -> Result<String, Box<dyn Error>> {
+    let header: FileHeader = read_pod(image, 0)?;
+    let mut out = String::new();
+
+    for i in 0..header.e_phnum as usize {
+        let offset = header.e_phoff as usize + i * header.e_phentsize as usize;
+        let phdr: Phdr = read_pod(image, offset)?;
+        if phdr.p_type != PT_LOAD || phdr.p_flags & PF_X == 0 {
+            continue;
+        }
+        let code = image
+            .get(phdr.p_offset as usize..(phdr.p_offset + phdr.p_filesz) as usize)
+            .ok_or("segment data is out of bounds")?;
+        let start = phdr.p_vaddr;
+        let end = start + phdr.p_filesz;
+        let labels: Vec<&Symbol> = symbolizer
+            .symbols()
+            .iter()
+            .filter(|symbol| (start..end).contains(&symbol.address))
+            .collect();
+        let segment = labels.first().map_or("?", |symbol| &symbol.segment);
+
+        writeln!(out, "segment {}, {:#x} bytes", segment, phdr.p_filesz)?;
+
+        // Each label starts a block of instructions, which ends at the next
+        // label, so that undecodable bytes can't hide the next routine.
+        let mut boundaries = vec![start];
+        boundaries.extend(labels.iter().map(|symbol| symbol.address));
+        boundaries.push(end);
+        boundaries.dedup();
+        let blocks = boundaries.windows(2).map(|pair| pair[0]..pair[1]);
+
+        for block in blocks {
+            writeln!(out)?;
+            let names: Vec<&str> = labels
+                .iter()
+                .filter(|symbol| symbol.address == block.start)
+                .map(|symbol| symbol.name.as_str())
+                .collect();
+            match names.as_slice() {
+                [] => writeln!(out, "{:#x}:", block.start)?,
+                names => {
+                    for name in names {
+                        writeln!(out, "{}:", name)?;
+                    }
+                }
+            }
+            list_block(&mut out, code, start, block, symbolizer, comments)?;
+        }
+        writeln!(out)?;
+    }
+    Ok(out)
+}
+
+fn list_block(
+    out: &mut String,
+    code: &[u8],
+    segment_start: u64,
+    block: Range<u64>,
+    symbolizer: &Symbolizer,
+    comments: &[(u64, String)],
+) -> std::fmt::Result {
+    let bytes = &code[(block.start - segment_start) as usize..(block.end - segment_start) as usize];
+    let mut position = 0;
+    while position < bytes.len() {
+        let address = block.start + position as u64;
+        for (_, comment) in comments.iter().filter(|(at, _)| *at == address) {
+            writeln!(out, "    ; {}", comment)?;
+        }
+        let (length, text) = match decode(&bytes[position..]) {
+            Some(decoded) => {
+                let mut text = decoded.to_string();
+                let targets: Vec<String> = targets(&decoded, address)
+                    .into_iter()
+                    .filter_map(|target| symbolizer.resolve(target))
+                    .map(|resolved| resolved.to_string())
+                    .collect();
+                if !targets.is_empty() {
+                    text = format!("{:<40}; {}", text, targets.join(", "));
+                }
+                (decoded.length, text)
+            }
+            None => (1, format!(".byte {:#04x}", bytes[position])),
+        };
+        let encoding: Vec<String> = bytes[position..][..length]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        writeln!(
+            out,
+            "    +{:04x}  {:<width$}  {}",
+            position,
+            encoding.join(" "),
+            text,
+            width = BYTES_WIDTH,
+        )?;
+        position += length;
+    }
+    Ok(())
+}
+
+/// The addresses that the operands of an instruction at `address` may
+/// refer to: branch targets, RIP-relative and absolute memory operands, and
+/// immediates that could be addresses.
+fn targets(decoded: &Decoded, address: u64) -> Vec<u64> {
+    let next = address.wrapping_add(decoded.length as u64);
+    decoded
+        .operands
+        .iter()
+        .filter_map(|operand| match *operand {
+            Operand::Relative(offset) => Some(next.wrapping_add(offset as i64 as u64)),
+            Operand::Memory(memory) => {
+                let displacement = memory.displacement.map_or(0, |d| d.value()) as i64 as u64;
+                match (memory.base, memory.index) {
+                    (Base::Rip, _) => Some(next.wrapping_add(displacement)),
+                    (Base::Absolute, None) => Some(displacement),
+                    _ => None,
+                }
+            }
+            Operand::Immediate(Immediate::X64(value)) => Some(u64::from_le_bytes(value)),
+            Operand::Immediate(Immediate::X32(value)) => Some(i32::from_le_bytes(value) as u64),
+            _ => None,
+        })
+        .collect()
+}
+
+fn read_pod<T: bytemuck::Pod>(image: &[u8], offset: usize) -> Result<T, Box<dyn Error>> {
+    let bytes = image
+        .get(offset..offset + size_of::<T>())
+        .ok_or("ELF header is out of bounds")?;
+    Ok(bytemuck::pod_read_unaligned(bytes))
+}
+
+/// A line-based diff of `old` and `new`, in unified format.
+fn unified_diff(old: &[&str], new: &[&str]) -> String {
+    // Lines that are kept, removed or added, in order, from the longest
+    // common subsequence of the lines between the common prefix and suffix.
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (a, b) = (
+        &old[prefix..old.len() - suffix],
+        &new[prefix..new.len() - suffix],
+    );
+    let mut lengths = vec![vec![0u32; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lengths[i][j] = if a[i] == b[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+
+    let mut edits: Vec<(char, &str)> = old[..prefix].iter().map(|&line| (' ', line)).collect();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            edits.push((' ', a[i]));
+            i += 1;
+            j += 1;
+        } else if j == b.len() || (i < a.len() && lengths[i + 1][j] >= lengths[i][j + 1]) {
+            edits.push(('-', a[i]));
+            i += 1;
+        } else {
+            edits.push(('+', b[j]));
+            j += 1;
+        }
+    }
+    edits.extend(old[old.len() - suffix..].iter().map(|&line| (' ', line)));
+
+    // Group the changes into hunks, merging those whose context overlaps.
+    let changed: Vec<usize> = (0..edits.len()).filter(|&k| edits[k].0 != ' ').collect();
+    let mut hunks: Vec<Range<usize>> = Vec::new();
+    for &k in &changed {
+        let range = k.saturating_sub(CONTEXT)..(k + CONTEXT + 1).min(edits.len());
+        match hunks.last_mut() {
+            Some(last) if last.end >= range.start => last.end = range.end,
+            _ => hunks.push(range),
+        }
+    }
+
+    let mut out = String::new();
+    for hunk in hunks {
+        let line = |kind: char| {
+            edits[..hunk.start]
+                .iter()
+                .filter(|edit| edit.0 == ' ' || edit.0 == kind)
+                .count()
+        };
+        let count = |kind: char| {
+            edits[hunk.clone()]
+                .iter()
+                .filter(|edit| edit.0 == ' ' || edit.0 == kind)
+                .count()
+        };
+        let _ = writeln!(
+            out,
+            "@@ -{},{} +{},{} @@",
+            line('-') + 1,
+            count('-'),
+            line('+') + 1,
+            count('+'),
+        );
+        for (kind, text) in &edits[hunk] {
+            let _ = writeln!(out, "{}{}", kind, text);
+        }
+    }
+    out
+}
@@ -0,0 +1,153 @@
+//! Legacy BIOS boot sectors.
+//!
+//! The BIOS loads the first sector of the boot disk at [`ORIGIN`] and jumps
+//! to it in real mode, provided that the sector ends with [`SIGNATURE`].
+
+use bytemuck::{Pod, Zeroable};
+
+use crate::link::{collect_exports, resolve_references, Segment};
+
+/// Physical address the BIOS loads the boot sector at.
+pub const ORIGIN: u64 = 0x7c00;
+pub const SECTOR_SIZE: usize = 512;
+/// Offset of the partition table in a master boot record.
+pub const PARTITION_TABLE_OFFSET: usize = 446;
+pub const SIGNATURE_OFFSET: usize = 510;
+pub const SIGNATURE: [u8; 2] = [0x55, 0xaa];
+
+/// Partition status flag marking the boot partition.
+pub const PARTITION_ACTIVE: u8 = 0x80;
+
+/// A master boot record partition table entry.
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct PartitionEntry {
+    pub status: u8,
+    pub chs_first: [u8; 3],
+    pub partition_type: u8,
+    pub chs_last: [u8; 3],
+    pub lba_first: u32,
+    pub sector_count: u32,
+}
+
+/// Links a single segment of real-mode code into a boot sector.
+///
+/// The code is placed at [`ORIGIN`], and execution starts at its first
+/// byte. It may use up to 510 bytes, or 446 bytes if a partition table is
+/// included.
+pub struct BootSector {
+    code: Segment,
+    partition_table: Option<[PartitionEntry; 4]>,
+}
+
+impl BootSector {
+    pub fn new(code: Segment) -> Self {
+        Self {
+            code,
+            partition_table: None,
+        }
+    }
+
+    /// Include a partition table, making this a master boot record.
+    pub fn set_partition_table(&mut self, partition_table: [PartitionEntry; 4]) {
+        self.partition_table = Some(partition_table);
+    }
+
+    /// Link the boot sector.
+    pub fn finish(mut self) -> [u8; SECTOR_SIZE] {
+        let limit = match self.partition_table {
+            Some(_) => PARTITION_TABLE_OFFSET,
+            None => SIGNATURE_OFFSET,
+        };
+        assert!(
+            self.code.len() <= limit,
+            "boot sector code is {} bytes, larger than {} bytes",
+            self.code.len(),
+            limit,
+        );
+
+        // All addresses are fixed, so absolute references need no
+        // relocation.
+        let exports = collect_exports(&[("boot", ORIGIN, &self.code)], false);
+        resolve_references(
+            ORIGIN,
+            ORIGIN,
+            &mut self.code,
+            &exports,
+            &[],
+            &mut Vec::new(),
+        );
+
+        let mut sector = [self.code.fill; SECTOR_SIZE];
+        let data = self.code.bytes();
+        sector[..data.len()].copy_from_slice(data);
+        sector[data.len()..self.code.len()].fill(0);
+        if let Some(partition_table) = &self.partition_table {
+            sector[PARTITION_TABLE_OFFSET..SIGNATURE_OFFSET]
+                .copy_from_slice(bytemuck::cast_slice(partition_table));
+        }
+        sector[SIGNATURE_OFFSET..].copy_from_slice(&SIGNATURE);
+        sector
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        link::{Label, Ptr, ReferenceFormat},
+        x86::{
+            instruction::{Mode, JMP, MOV},
+            register::R16::AX,
+            Assembler,
+        },
+    };
+
+    #[test]
+    fn boot_sector() {
+        let mut code = Segment::new();
+        code.set_fill(0xf4);
+        // mov si, message
+        code.extend([0xbe]);
+        code.append_reference("message", ReferenceFormat::Abs16);
+        code.label("message");
+        code.extend(*b"hi\0");
+
+        let mut partition = PartitionEntry::zeroed();
+        partition.status = PARTITION_ACTIVE;
+        let mut boot_sector = BootSector::new(code);
+        boot_sector.set_partition_table([partition; 4]);
+        let sector = boot_sector.finish();
+
+        assert_eq!(sector[..6], [0xbe, 0x03, 0x7c, b'h', b'i', 0]);
+        assert_eq!(sector[6], 0xf4);
+        assert_eq!(sector[PARTITION_TABLE_OFFSET], PARTITION_ACTIVE);
+        assert_eq!(sector[SIGNATURE_OFFSET..], SIGNATURE);
+    }
+
+    #[test]
+    fn assembled_boot_sector() {
+        let mut asm = Assembler::new();
+        asm.set_mode(Mode::Real);
+        asm.label("start");
+        asm.push(MOV(AX, Ptr("value")));
+        asm.push(JMP(Label("start")));
+        asm.label("value");
+        asm.append(&[0x34, 0x12]);
+        let sector = BootSector::new(asm.finish()).finish();
+
+        // mov ax, [0x7c07]; jmp 0x7c00
+        assert_eq!(
+            sector[..9],
+            [0x8b, 0x06, 0x07, 0x7c, 0xe9, 0xf9, 0xff, 0x34, 0x12]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "boot sector code is 511 bytes, larger than 510 bytes")]
+    fn boot_sector_too_large() {
+        let mut code = Segment::new();
+        code.extend([0; 511]);
+        BootSector::new(code).finish();
+    }
+}
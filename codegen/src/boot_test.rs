@@ -0,0 +1,152 @@
+//! End-to-end tests that link a kernel, boot it with Limine under QEMU, and
+//! check what it writes to the serial port.
+//!
+//! They are enabled by the `qemu` feature, and ignored by default since they
+//! need QEMU and a Limine binary release:
+//!
+//! ```sh
+//! LIMINE_DIR=path/to/limine cargo test --features qemu -- --ignored
+//! ```
+//!
+//! `QEMU` overrides the emulator, which is `qemu-system-x86_64` by default.
+
+use crate::{
+    elf64::program::{PF_R, PF_W, PF_X},
+    limine::{self, iso_image},
+    link::{ElfLinker, Segment},
+    x86::{
+        address::disp8,
+        debug_exit::{self, Outcome, EXIT_SUCCESS},
+        format::{Formatter, Sink},
+        function::Arg,
+        instruction::*,
+        register::R64::*,
+        Assembler,
+    },
+};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+const COM1: u16 = 0x3f8;
+
+/// How long a kernel may run before QEMU is killed.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Link a kernel with a `kprintf` that writes to COM1, whose entry point is
+/// emitted by `entry`. The kernel passes if the entry point returns.
+fn link_kernel(requests: Segment, entry: impl FnOnce(&mut Assembler)) -> Vec<u8> {
+    let mut data = Segment::new();
+    let mut asm = Assembler::new();
+
+    asm.export_label("entry");
+    entry(&mut asm);
+    debug_exit::emit_exit(&mut asm, EXIT_SUCCESS);
+
+    Formatter::new("kprintf", Sink::Serial(COM1)).emit(&mut asm, &mut data);
+
+    let mut rodata = asm.constant_pool();
+    rodata.align(8);
+    let code = asm.finish();
+
+    let mut linker = ElfLinker::new();
+    linker.add_segment("limine_requests", PF_R | PF_W, 1 << 12, requests);
+    linker.add_segment("rodata", PF_R, 1 << 12, rodata);
+    linker.add_segment("data", PF_R | PF_W, 1 << 12, data);
+    linker.add_segment("code", PF_R | PF_X, 1 << 12, code);
+
+    linker.finish().to_vec().unwrap()
+}
+
+/// A directory for the files of one test, emptied if it already exists.
+fn work_dir(test: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("alpha-boot-{}-{}", test, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Boot `kernel` from a CD image, and return its serial output and the
+/// outcome. QEMU is killed after `TIMEOUT`.
+fn boot(test: &str, kernel: Vec<u8>) -> (String, Outcome) {
+    let limine_dir = env::var_os("LIMINE_DIR").expect("LIMINE_DIR is not set");
+    let qemu = env::var_os("QEMU").unwrap_or_else(|| "qemu-system-x86_64".into());
+
+    let dir = work_dir(test);
+    let iso = dir.join("kernel.iso");
+    let serial = dir.join("serial.log");
+    fs::write(&iso, iso_image(kernel, Path::new(&limine_dir)).unwrap()).unwrap();
+
+    let mut child = Command::new(&qemu)
+        .arg("-cdrom")
+        .arg(&iso)
+        .arg("-serial")
+        .arg(format!("file:{}", serial.display()))
+        .args([
+            "-display",
+            "none",
+            "-monitor",
+            "none",
+            "-no-reboot",
+            "-m",
+            "256M",
+        ])
+        .args(["-device", debug_exit::QEMU_DEVICE])
+        .stdin(Stdio::null())
+        .spawn()
+        .unwrap_or_else(|error| panic!("can not run {:?}: {}", qemu, error));
+
+    let start = Instant::now();
+    let code = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status.code();
+        }
+        if start.elapsed() > TIMEOUT {
+            child.kill().unwrap();
+            child.wait().unwrap();
+            break None;
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+
+    let output = fs::read(&serial).unwrap_or_default();
+    let _ = fs::remove_dir_all(&dir);
+    (
+        String::from_utf8_lossy(&output).into_owned(),
+        Outcome::from_exit_code(code),
+    )
+}
+
+#[test]
+#[ignore = "needs QEMU and LIMINE_DIR"]
+fn boots_and_prints() {
+    let mut requests = limine::RequestsBuilder::new();
+    let bootloader_info = requests.add(
+        "bootloader_info_response",
+        &limine::Request::new(limine::BOOTLOADER_INFO_REQUEST, 0),
+    );
+    let kernel = link_kernel(requests.finish(), |asm| {
+        let message = asm.const_bytes(b"alpha: booted by %s %s\n\0");
+        asm.push(MOV(RBX, bootloader_info.ptr()));
+        asm.call_fn(
+            "kprintf",
+            &[
+                Arg::Label(message.label()),
+                Arg::Index(RBX, disp8(limine::BOOTLOADER_INFO_RESPONSE_NAME_OFFSET)),
+                Arg::Index(RBX, disp8(limine::BOOTLOADER_INFO_RESPONSE_VERSION_OFFSET)),
+            ],
+        );
+    });
+
+    let (output, outcome) = boot("boots_and_prints", kernel);
+    assert!(
+        output.contains("alpha: booted by Limine "),
+        "unexpected serial output:\n{}",
+        output
+    );
+    assert_eq!(outcome, Outcome::Passed);
+}
@@ -0,0 +1,645 @@
+//! Image layout configuration, read from a TOML file, so that the layout of
+//! an image can be changed without rebuilding the tool that links it.
+//!
+//! ```toml
+//! base = 0xffffffff80000000
+//! entry = "entry"
+//! inputs = ["start.s", "lib.s"]
+//! output = "kernel"
+//! formats = ["elf", "iso"]
+//!
+//! [limine]
+//! base_revision = 2
+//! requests = ["bootloader_info", "hhdm", "memmap"]
+//! section = ".requests"
+//! dir = "/usr/share/limine"
+//!
+//! [[segment]]
+//! name = ".text"
+//! flags = "rx"
+//!
+//! [[segment]]
+//! name = ".requests"
+//! flags = "rw"
+//! align = 8
+//! ```
+//!
+//! Segments are laid out in the order they are declared, and every section
+//! that is linked must be declared. Only the subset of TOML that such a file
+//! needs is accepted: comments, `[table]` and `[[array]]` headers, and
+//! `key = value` pairs whose values are strings, integers or arrays of them,
+//! on one line.
+
+use crate::{
+    elf64::{
+        common::{Addr, Word, Xword},
+        program::{PF_R, PF_W, PF_X},
+    },
+    limine::{self, Request, RequestsBuilder, SmpRequest},
+    link::{ElfLinker, Segment},
+};
+use std::{collections::BTreeMap, io, path::PathBuf};
+
+/// The layout of an image, and what to write it as.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageConfig {
+    /// The address of the first segment.
+    pub base_address: Addr,
+    /// The load address of the first segment, if it differs from
+    /// `base_address`.
+    pub physical_base: Option<Addr>,
+    /// The exported label that execution starts at, if not `entry`.
+    pub entry: Option<String>,
+    pub page_size: Option<Xword>,
+    /// Assembly files to link, relative to the configuration file.
+    pub inputs: Vec<PathBuf>,
+    /// The path of the outputs, without an extension.
+    pub output: Option<PathBuf>,
+    pub formats: Vec<OutputFormat>,
+    pub segments: Vec<SegmentConfig>,
+    pub limine: Option<LimineConfig>,
+}
+
+/// A segment of the image, filled with the section of the same name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentConfig {
+    pub name: String,
+    /// `PF_*` flags, written as any of `r`, `w` and `x` in the file.
+    pub flags: Word,
+    pub align: Xword,
+    /// A fixed virtual address, instead of after the previous segment.
+    pub address: Option<Addr>,
+}
+
+/// The Limine requests to link into the image, as a section of its own.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LimineConfig {
+    pub base_revision: Option<u64>,
+    pub requests: Vec<LimineRequest>,
+    /// The section that the requests are placed in.
+    pub section: String,
+    /// The Limine binary release that CD images are built with.
+    pub dir: Option<PathBuf>,
+}
+
+/// A Limine request, whose response pointer is exported as
+/// `<name>_response`, e.g. `hhdm_response`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimineRequest {
+    BootloaderInfo,
+    /// A terminal, whose callback is the label `terminal_callback`.
+    Terminal,
+    Framebuffer,
+    Memmap,
+    Hhdm,
+    Smp,
+    Smbios,
+    Rsdp,
+}
+
+/// A format that a linked image can be written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// ELF, with debug information, and its symbol file.
+    Elf,
+    /// The loaded bytes, from the lowest address.
+    Bin,
+    Ihex,
+    Srec,
+    /// A CD image that boots the ELF image with Limine.
+    Iso,
+}
+
+impl OutputFormat {
+    pub fn parse(name: &str) -> io::Result<Self> {
+        match name {
+            "elf" => Ok(Self::Elf),
+            "bin" => Ok(Self::Bin),
+            "ihex" => Ok(Self::Ihex),
+            "srec" => Ok(Self::Srec),
+            "iso" => Ok(Self::Iso),
+            _ => Err(invalid(format!("unknown output format {:?}", name))),
+        }
+    }
+
+    /// The file extension of the format.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Elf => "elf",
+            Self::Bin => "bin",
+            Self::Ihex => "hex",
+            Self::Srec => "srec",
+            Self::Iso => "iso",
+        }
+    }
+}
+
+impl LimineRequest {
+    pub fn parse(name: &str) -> io::Result<Self> {
+        match name {
+            "bootloader_info" => Ok(Self::BootloaderInfo),
+            "terminal" => Ok(Self::Terminal),
+            "framebuffer" => Ok(Self::Framebuffer),
+            "memmap" => Ok(Self::Memmap),
+            "hhdm" => Ok(Self::Hhdm),
+            "smp" => Ok(Self::Smp),
+            "smbios" => Ok(Self::Smbios),
+            "rsdp" => Ok(Self::Rsdp),
+            _ => Err(invalid(format!("unknown Limine request {:?}", name))),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::BootloaderInfo => "bootloader_info",
+            Self::Terminal => "terminal",
+            Self::Framebuffer => "framebuffer",
+            Self::Memmap => "memmap",
+            Self::Hhdm => "hhdm",
+            Self::Smp => "smp",
+            Self::Smbios => "smbios",
+            Self::Rsdp => "rsdp",
+        }
+    }
+
+    /// Add the request, at revision 0, with the type of its response.
+    fn add_to(self, requests: &mut RequestsBuilder) {
+        let response = format!("{}_response", self.name());
+        let request = |id| Request::new(id, 0);
+        let handle = match self {
+            Self::BootloaderInfo => {
+                requests.add(&response, &request(limine::BOOTLOADER_INFO_REQUEST))
+            }
+            Self::Terminal => requests.add_terminal(&response, 0, "terminal_callback"),
+            Self::Framebuffer => requests.add(&response, &request(limine::FRAMEBUFFER_REQUEST)),
+            Self::Memmap => requests.add(&response, &request(limine::MEMMAP_REQUEST)),
+            Self::Hhdm => requests.add(&response, &request(limine::HHDM_REQUEST)),
+            Self::Smp => requests.add(&response, &SmpRequest::new(0, 0)),
+            Self::Smbios => requests.add(&response, &request(limine::SMBIOS_REQUEST)),
+            Self::Rsdp => requests.add(&response, &request(limine::RSDP_REQUEST)),
+        };
+        match self {
+            Self::BootloaderInfo => {
+                requests.response_type::<limine::BootloaderInfoResponse>(handle)
+            }
+            Self::Terminal => requests.response_type::<limine::TerminalResponse>(handle),
+            Self::Framebuffer => requests.response_type::<limine::FramebufferResponse>(handle),
+            Self::Memmap => requests.response_type::<limine::MemmapResponse>(handle),
+            Self::Hhdm => requests.response_type::<limine::HhdmResponse>(handle),
+            Self::Smp => requests.response_type::<limine::SmpResponse>(handle),
+            Self::Smbios => requests.response_type::<limine::SmbiosResponse>(handle),
+            Self::Rsdp => requests.response_type::<limine::RsdpResponse>(handle),
+        }
+    }
+}
+
+impl ImageConfig {
+    /// Parse a configuration file.
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut root = parse_toml(text)?;
+        let mut config = Self {
+            base_address: take(&mut root, "base", Value::integer)?.unwrap_or(0),
+            physical_base: take(&mut root, "physical_base", Value::integer)?,
+            entry: take(&mut root, "entry", Value::string)?,
+            page_size: take(&mut root, "page_size", Value::integer)?,
+            inputs: take(&mut root, "inputs", Value::strings)?
+                .unwrap_or_default()
+                .into_iter()
+                .map(PathBuf::from)
+                .collect(),
+            output: take(&mut root, "output", Value::string)?.map(PathBuf::from),
+            formats: Vec::new(),
+            segments: Vec::new(),
+            limine: None,
+        };
+        if let Some(page_size) = config.page_size.filter(|size| !size.is_power_of_two()) {
+            return Err(invalid(format!(
+                "page_size {:#x} is not a power of two",
+                page_size
+            )));
+        }
+        for format in take(&mut root, "formats", Value::strings)?.unwrap_or_default() {
+            config.formats.push(OutputFormat::parse(&format)?);
+        }
+
+        for mut table in take(&mut root, "segment", Value::tables)?.unwrap_or_default() {
+            let name = take(&mut table, "name", Value::string)?
+                .ok_or_else(|| invalid("segment has no name".into()))?;
+            let flags = take(&mut table, "flags", Value::string)?.unwrap_or_else(|| "r".into());
+            let flags = flags.chars().try_fold(0, |flags, c| match c {
+                'r' => Ok(flags | PF_R),
+                'w' => Ok(flags | PF_W),
+                'x' => Ok(flags | PF_X),
+                _ => Err(invalid(format!(
+                    "segment {:?} has unknown flag {:?}",
+                    name, c
+                ))),
+            })?;
+            if config.segments.iter().any(|segment| segment.name == name) {
+                return Err(invalid(format!("segment {:?} is declared twice", name)));
+            }
+            let align = take(&mut table, "align", Value::integer)?.unwrap_or(1 << 12);
+            if !align.is_power_of_two() {
+                return Err(invalid(format!(
+                    "segment {:?} has align {:#x}, which is not a power of two",
+                    name, align
+                )));
+            }
+            config.segments.push(SegmentConfig {
+                flags,
+                align,
+                address: take(&mut table, "address", Value::integer)?,
+                name,
+            });
+            no_more_keys(table, "segment")?;
+        }
+
+        if let Some(mut table) = take(&mut root, "limine", Value::table)? {
+            let mut requests = Vec::new();
+            for request in take(&mut table, "requests", Value::strings)?.unwrap_or_default() {
+                requests.push(LimineRequest::parse(&request)?);
+            }
+            config.limine = Some(LimineConfig {
+                base_revision: take(&mut table, "base_revision", Value::integer)?,
+                requests,
+                section: take(&mut table, "section", Value::string)?
+                    .unwrap_or_else(|| ".requests".into()),
+                dir: take(&mut table, "dir", Value::string)?.map(PathBuf::from),
+            });
+            no_more_keys(table, "limine")?;
+        }
+        no_more_keys(root, "configuration")?;
+        Ok(config)
+    }
+
+    /// A linker for the image, with the sections of the inputs, and the
+    /// Limine requests, in the declared segments.
+    ///
+    /// Sections of the same name are concatenated. It is an error for a
+    /// section to have no segment, but a segment may be empty.
+    pub fn linker(&self, sections: Vec<(String, Segment)>) -> io::Result<ElfLinker> {
+        let mut contents: BTreeMap<String, Segment> = BTreeMap::new();
+        let mut add_section = |name: String, segment: Segment| {
+            if !self.segments.iter().any(|declared| declared.name == name) {
+                return Err(invalid(format!("section {:?} has no segment", name)));
+            }
+            match contents.get_mut(&name) {
+                Some(existing) => {
+                    existing.embed(segment);
+                }
+                None => {
+                    contents.insert(name, segment);
+                }
+            }
+            Ok(())
+        };
+        if let Some(config) = &self.limine {
+            let mut requests = RequestsBuilder::new();
+            if let Some(revision) = config.base_revision {
+                requests.base_revision(revision, "limine_base_revision");
+            }
+            for request in &config.requests {
+                request.add_to(&mut requests);
+            }
+            add_section(config.section.clone(), requests.finish())?;
+        }
+        for (name, segment) in sections {
+            add_section(name, segment)?;
+        }
+
+        let mut linker = ElfLinker::new();
+        linker.set_base_address(self.base_address);
+        if let Some(physical_base) = self.physical_base {
+            linker.set_physical_base(physical_base);
+        }
+        if let Some(page_size) = self.page_size {
+            linker.set_page_size(page_size);
+        }
+        if let Some(entry) = &self.entry {
+            linker.set_entry(entry);
+        }
+        for declared in &self.segments {
+            let segment = contents.remove(&declared.name).unwrap_or_else(Segment::new);
+            let id = linker.add_segment(&declared.name, declared.flags, declared.align, segment);
+            if let Some(address) = declared.address {
+                linker.set_segment_address(id, address);
+            }
+        }
+        Ok(linker)
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Remove `key` from `table`, converted by `convert`, which names the type
+/// that it expected if the value has another.
+fn take<T>(
+    table: &mut Table,
+    key: &str,
+    convert: fn(Value) -> Result<T, &'static str>,
+) -> io::Result<Option<T>> {
+    table
+        .remove(key)
+        .map(|value| convert(value).map_err(|ty| invalid(format!("{} should be {}", key, ty))))
+        .transpose()
+}
+
+fn no_more_keys(table: Table, name: &str) -> io::Result<()> {
+    match table.keys().next() {
+        Some(key) => Err(invalid(format!("unknown key {:?} in {}", key, name))),
+        None => Ok(()),
+    }
+}
+
+type Table = BTreeMap<String, Value>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Value {
+    String(String),
+    Integer(u64),
+    Array(Vec<Value>),
+    Table(Table),
+    /// An array of tables, from `[[name]]` headers.
+    Tables(Vec<Table>),
+}
+
+impl Value {
+    fn integer(self) -> Result<u64, &'static str> {
+        match self {
+            Self::Integer(value) => Ok(value),
+            _ => Err("an integer"),
+        }
+    }
+
+    fn string(self) -> Result<String, &'static str> {
+        match self {
+            Self::String(value) => Ok(value),
+            _ => Err("a string"),
+        }
+    }
+
+    fn strings(self) -> Result<Vec<String>, &'static str> {
+        match self {
+            Self::Array(values) => values
+                .into_iter()
+                .map(|value| value.string().map_err(|_| "an array of strings"))
+                .collect(),
+            _ => Err("an array of strings"),
+        }
+    }
+
+    fn table(self) -> Result<Table, &'static str> {
+        match self {
+            Self::Table(table) => Ok(table),
+            _ => Err("a table"),
+        }
+    }
+
+    fn tables(self) -> Result<Vec<Table>, &'static str> {
+        match self {
+            Self::Tables(tables) => Ok(tables),
+            _ => Err("an array of tables"),
+        }
+    }
+}
+
+/// Parse the subset of TOML described in the module documentation.
+fn parse_toml(text: &str) -> io::Result<Table> {
+    let mut root = Table::new();
+    // The header that keys are currently added under, if any, and whether it
+    // is an array of tables.
+    let mut current: Option<(String, bool)> = None;
+    for (number, line) in text.lines().enumerate() {
+        let error = |message: String| invalid(format!("line {}: {}", number + 1, message));
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(name) = line.strip_prefix("[[").and_then(|l| l.strip_suffix("]]")) {
+            let name = name.trim().to_owned();
+            match root
+                .entry(name.clone())
+                .or_insert_with(|| Value::Tables(Vec::new()))
+            {
+                Value::Tables(tables) => tables.push(Table::new()),
+                _ => return Err(error(format!("{} is not an array of tables", name))),
+            }
+            current = Some((name, true));
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            let name = name.trim().to_owned();
+            if root.contains_key(&name) {
+                return Err(error(format!("{} is defined twice", name)));
+            }
+            root.insert(name.clone(), Value::Table(Table::new()));
+            current = Some((name, false));
+            continue;
+        }
+
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| error(format!("expected `key = value`, found {:?}", line)))?;
+        let key = key.trim();
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(error(format!("invalid key {:?}", key)));
+        }
+        let (value, rest) = parse_value(value.trim()).map_err(error)?;
+        if !rest.trim().is_empty() {
+            return Err(error(format!("unexpected {:?} after value", rest.trim())));
+        }
+        let table = match &current {
+            None => &mut root,
+            Some((name, array)) => match (root.get_mut(name), array) {
+                (Some(Value::Tables(tables)), true) => tables.last_mut().unwrap(),
+                (Some(Value::Table(table)), false) => table,
+                _ => unreachable!(),
+            },
+        };
+        if table.insert(key.to_owned(), value).is_some() {
+            return Err(error(format!("{} is defined twice", key)));
+        }
+    }
+    Ok(root)
+}
+
+/// The line up to a `#` that is not in a string.
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+/// Parse the value at the start of `text`, returning it and the rest of the
+/// text.
+fn parse_value(text: &str) -> Result<(Value, &str), String> {
+    if let Some(mut rest) = text.strip_prefix('"') {
+        let mut value = String::new();
+        loop {
+            let mut chars = rest.chars();
+            match chars.next() {
+                None => return Err("unterminated string".into()),
+                Some('"') => return Ok((Value::String(value), chars.as_str())),
+                Some('\\') => {
+                    value.push(match chars.next() {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some(c @ ('"' | '\\')) => c,
+                        other => return Err(format!("unknown escape {:?}", other)),
+                    });
+                }
+                Some(c) => value.push(c),
+            }
+            rest = chars.as_str();
+        }
+    }
+    if let Some(mut rest) = text.strip_prefix('[') {
+        let mut values = Vec::new();
+        loop {
+            rest = rest.trim_start();
+            if let Some(after) = rest.strip_prefix(']') {
+                return Ok((Value::Array(values), after));
+            }
+            let (value, after) = parse_value(rest)?;
+            values.push(value);
+            rest = after.trim_start();
+            match rest.strip_prefix(',') {
+                Some(after) => rest = after,
+                None if rest.starts_with(']') => {}
+                None => return Err("expected `,` or `]` in array".into()),
+            }
+        }
+    }
+
+    let end = text
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or(text.len());
+    let (word, rest) = text.split_at(end);
+    let digits = word.replace('_', "");
+    let number = match digits.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => digits.parse(),
+    };
+    let number = number.map_err(|_| format!("invalid value {:?}", text))?;
+    Ok((Value::Integer(number), rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        # The higher half.
+        base = 0xffff_ffff_8000_0000
+        entry = "start"
+        formats = ["elf", "bin"]
+
+        [limine]
+        base_revision = 2
+        requests = ["hhdm", "memmap"]
+
+        [[segment]]
+        name = ".text"   # code
+        flags = "rx"
+
+        [[segment]]
+        name = ".requests"
+        flags = "rw"
+        align = 8
+        address = 0xffffffff80100000
+    "#;
+
+    #[test]
+    fn parse() {
+        let config = ImageConfig::parse(CONFIG).unwrap();
+        assert_eq!(config.base_address, 0xffffffff80000000);
+        assert_eq!(config.entry.as_deref(), Some("start"));
+        assert_eq!(config.formats, [OutputFormat::Elf, OutputFormat::Bin]);
+        assert_eq!(
+            config.segments,
+            [
+                SegmentConfig {
+                    name: ".text".into(),
+                    flags: PF_R | PF_X,
+                    align: 1 << 12,
+                    address: None,
+                },
+                SegmentConfig {
+                    name: ".requests".into(),
+                    flags: PF_R | PF_W,
+                    align: 8,
+                    address: Some(0xffffffff80100000),
+                },
+            ]
+        );
+        let limine = config.limine.unwrap();
+        assert_eq!(limine.base_revision, Some(2));
+        assert_eq!(
+            limine.requests,
+            [LimineRequest::Hhdm, LimineRequest::Memmap]
+        );
+        assert_eq!(limine.section, ".requests");
+    }
+
+    #[test]
+    fn links_the_declared_layout() {
+        let config = ImageConfig::parse(CONFIG).unwrap();
+        let mut text = Segment::new();
+        text.export_label("start");
+        text.extend([0xf4]);
+
+        let linked = config
+            .linker(vec![(".text".into(), text)])
+            .unwrap()
+            .finish();
+        let start = linked.label_address("start").unwrap();
+        assert_eq!(Some(start), linked.segment_vaddr(".text"));
+        assert_eq!(start & !0xfff, 0xffffffff80000000);
+        assert_eq!(linked.segment_vaddr(".requests"), Some(0xffffffff80100000));
+        assert_eq!(linked.label_segment("hhdm_response"), Some(".requests"));
+        assert!(linked.label_address("limine_base_revision").is_some());
+    }
+
+    #[test]
+    fn errors() {
+        let error = |text: &str| ImageConfig::parse(text).unwrap_err().to_string();
+        assert_eq!(error("base = \"low\""), "base should be an integer");
+        assert_eq!(error("bass = 1"), "unknown key \"bass\" in configuration");
+        assert_eq!(error("\n\nbase = "), "line 3: invalid value \"\"");
+        assert_eq!(
+            error("[[segment]]\nname = \".text\"\nflags = \"rwz\""),
+            "segment \".text\" has unknown flag 'z'"
+        );
+        assert_eq!(
+            error("page_size = 3000"),
+            "page_size 0xbb8 is not a power of two"
+        );
+        assert_eq!(
+            error("[[segment]]\nname = \".text\"\nalign = 3"),
+            "segment \".text\" has align 0x3, which is not a power of two"
+        );
+        assert_eq!(
+            error("[[segment]]\nname = \".text\"\n[[segment]]\nname = \".text\""),
+            "segment \".text\" is declared twice"
+        );
+
+        let config = ImageConfig::parse("[[segment]]\nname = \".text\"").unwrap();
+        let error = config
+            .linker(vec![(".data".into(), Segment::new())])
+            .err()
+            .unwrap();
+        assert_eq!(error.to_string(), "section \".data\" has no segment");
+    }
+}
@@ -0,0 +1,775 @@
+//! DWARF debugging information: line numbers, mapping the addresses of
+//! generated code to the source locations of the generator code that emitted
+//! it, and the types of data structures.
+//!
+//! Only what debuggers need to find a line from an address is generated: a
+//! compilation unit per segment, with its address range, and its line number
+//! program. Types, such as those declared with `layout!`, and the labels of
+//! data of those types are described in one more compilation unit, in terms
+//! of C, so that a debugger can print e.g. `idt[3].offset_low`. See the
+//! [DWARF 4 specification](https://dwarfstd.org/doc/DWARF4.pdf).
+
+use crate::elf64::common::Addr;
+use std::panic::Location;
+
+const DW_TAG_ARRAY_TYPE: u8 = 0x01;
+const DW_TAG_MEMBER: u8 = 0x0d;
+const DW_TAG_POINTER_TYPE: u8 = 0x0f;
+const DW_TAG_COMPILE_UNIT: u8 = 0x11;
+const DW_TAG_STRUCTURE_TYPE: u8 = 0x13;
+const DW_TAG_SUBRANGE_TYPE: u8 = 0x21;
+const DW_TAG_BASE_TYPE: u8 = 0x24;
+const DW_TAG_VARIABLE: u8 = 0x34;
+const DW_CHILDREN_NO: u8 = 0;
+const DW_CHILDREN_YES: u8 = 1;
+
+const DW_AT_LOCATION: u8 = 0x02;
+const DW_AT_NAME: u8 = 0x03;
+const DW_AT_BYTE_SIZE: u8 = 0x0b;
+const DW_AT_STMT_LIST: u8 = 0x10;
+const DW_AT_LOW_PC: u8 = 0x11;
+const DW_AT_HIGH_PC: u8 = 0x12;
+const DW_AT_LANGUAGE: u8 = 0x13;
+const DW_AT_COMP_DIR: u8 = 0x1b;
+const DW_AT_PRODUCER: u8 = 0x25;
+const DW_AT_COUNT: u8 = 0x37;
+const DW_AT_DATA_MEMBER_LOCATION: u8 = 0x38;
+const DW_AT_ENCODING: u8 = 0x3e;
+const DW_AT_EXTERNAL: u8 = 0x3f;
+const DW_AT_TYPE: u8 = 0x49;
+const DW_AT_BYTE_STRIDE: u8 = 0x51;
+
+const DW_FORM_ADDR: u8 = 0x01;
+const DW_FORM_DATA2: u8 = 0x05;
+const DW_FORM_DATA8: u8 = 0x07;
+const DW_FORM_STRING: u8 = 0x08;
+const DW_FORM_DATA1: u8 = 0x0b;
+const DW_FORM_UDATA: u8 = 0x0f;
+const DW_FORM_REF4: u8 = 0x13;
+const DW_FORM_SEC_OFFSET: u8 = 0x17;
+const DW_FORM_EXPRLOC: u8 = 0x18;
+const DW_FORM_FLAG_PRESENT: u8 = 0x19;
+
+const DW_LANG_C99: u16 = 0x000c;
+const DW_LANG_MIPS_ASSEMBLER: u16 = 0x8001;
+
+const DW_ATE_UNSIGNED: u8 = 0x07;
+
+const DW_OP_ADDR: u8 = 0x03;
+
+const DW_LNS_COPY: u8 = 1;
+const DW_LNS_ADVANCE_PC: u8 = 2;
+const DW_LNS_ADVANCE_LINE: u8 = 3;
+const DW_LNS_SET_FILE: u8 = 4;
+const DW_LNS_SET_COLUMN: u8 = 5;
+
+const DW_LNE_END_SEQUENCE: u8 = 1;
+const DW_LNE_SET_ADDRESS: u8 = 2;
+
+/// The number of operands of each standard opcode, from 1.
+const STANDARD_OPCODE_LENGTHS: [u8; 12] = [0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1];
+
+/// The line number program parameters for special opcodes, which are not
+/// used, but must still be given.
+const LINE_BASE: i8 = -5;
+const LINE_RANGE: u8 = 14;
+
+const VERSION: u16 = 4;
+/// The size of the header of a compilation unit, which offsets of DIEs
+/// within the unit count from.
+const UNIT_HEADER_SIZE: usize = 4 + 2 + 4 + 1;
+
+const ABBREV_COMPILE_UNIT: u8 = 1;
+const ABBREV_TYPES_UNIT: u8 = 2;
+const ABBREV_BASE_TYPE: u8 = 3;
+const ABBREV_STRUCTURE_TYPE: u8 = 4;
+const ABBREV_MEMBER: u8 = 5;
+const ABBREV_ARRAY_TYPE: u8 = 6;
+const ABBREV_SUBRANGE_TYPE: u8 = 7;
+const ABBREV_VARIABLE: u8 = 8;
+const ABBREV_POINTER_TYPE: u8 = 9;
+
+/// The tag of an abbreviation, whether it has children, and the attribute
+/// and form of each attribute.
+type Abbreviation = (u8, u8, &'static [(u8, u8)]);
+
+/// The abbreviations, by code from 1.
+const ABBREVIATIONS: [Abbreviation; 9] = [
+    (
+        DW_TAG_COMPILE_UNIT,
+        DW_CHILDREN_NO,
+        &[
+            (DW_AT_NAME, DW_FORM_STRING),
+            (DW_AT_PRODUCER, DW_FORM_STRING),
+            (DW_AT_COMP_DIR, DW_FORM_STRING),
+            (DW_AT_LANGUAGE, DW_FORM_DATA2),
+            (DW_AT_STMT_LIST, DW_FORM_SEC_OFFSET),
+            (DW_AT_LOW_PC, DW_FORM_ADDR),
+            (DW_AT_HIGH_PC, DW_FORM_DATA8),
+        ],
+    ),
+    (
+        DW_TAG_COMPILE_UNIT,
+        DW_CHILDREN_YES,
+        &[
+            (DW_AT_NAME, DW_FORM_STRING),
+            (DW_AT_PRODUCER, DW_FORM_STRING),
+            (DW_AT_LANGUAGE, DW_FORM_DATA2),
+        ],
+    ),
+    (
+        DW_TAG_BASE_TYPE,
+        DW_CHILDREN_NO,
+        &[
+            (DW_AT_NAME, DW_FORM_STRING),
+            (DW_AT_BYTE_SIZE, DW_FORM_UDATA),
+            (DW_AT_ENCODING, DW_FORM_DATA1),
+        ],
+    ),
+    (
+        DW_TAG_STRUCTURE_TYPE,
+        DW_CHILDREN_YES,
+        &[
+            (DW_AT_NAME, DW_FORM_STRING),
+            (DW_AT_BYTE_SIZE, DW_FORM_UDATA),
+        ],
+    ),
+    (
+        DW_TAG_MEMBER,
+        DW_CHILDREN_NO,
+        &[
+            (DW_AT_NAME, DW_FORM_STRING),
+            (DW_AT_TYPE, DW_FORM_REF4),
+            (DW_AT_DATA_MEMBER_LOCATION, DW_FORM_UDATA),
+        ],
+    ),
+    (
+        DW_TAG_ARRAY_TYPE,
+        DW_CHILDREN_YES,
+        &[
+            (DW_AT_TYPE, DW_FORM_REF4),
+            (DW_AT_BYTE_STRIDE, DW_FORM_UDATA),
+        ],
+    ),
+    (
+        DW_TAG_SUBRANGE_TYPE,
+        DW_CHILDREN_NO,
+        &[(DW_AT_COUNT, DW_FORM_UDATA)],
+    ),
+    (
+        DW_TAG_VARIABLE,
+        DW_CHILDREN_NO,
+        &[
+            (DW_AT_NAME, DW_FORM_STRING),
+            (DW_AT_TYPE, DW_FORM_REF4),
+            (DW_AT_EXTERNAL, DW_FORM_FLAG_PRESENT),
+            (DW_AT_LOCATION, DW_FORM_EXPRLOC),
+        ],
+    ),
+    (
+        DW_TAG_POINTER_TYPE,
+        DW_CHILDREN_NO,
+        &[(DW_AT_BYTE_SIZE, DW_FORM_UDATA), (DW_AT_TYPE, DW_FORM_REF4)],
+    ),
+];
+
+const PRODUCER: &str = concat!("alpha-codegen ", env!("CARGO_PKG_VERSION"));
+
+/// The code of one segment, as a compilation unit.
+pub struct Unit<'a> {
+    pub name: &'a str,
+    /// The addresses of the code, `low_pc..high_pc`.
+    pub low_pc: Addr,
+    pub high_pc: Addr,
+    /// The source location of the code from each address to the next, in
+    /// order of address.
+    pub rows: Vec<(Addr, &'static Location<'static>)>,
+}
+
+/// The layout of a value, as described to debuggers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Type {
+    /// An unsigned integer of 1, 2, 4 or 8 bytes.
+    Unsigned(usize),
+    /// `count` values of the `element` type, each `stride` bytes after the
+    /// previous one.
+    Array {
+        element: Box<Type>,
+        count: usize,
+        stride: usize,
+    },
+    Struct {
+        name: &'static str,
+        size: usize,
+        fields: Vec<Field>,
+    },
+    /// A 64-bit pointer to a value of a type.
+    Pointer(Box<Type>),
+}
+
+/// A field of a [`Type::Struct`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Field {
+    pub name: &'static str,
+    pub offset: usize,
+    pub ty: Type,
+}
+
+impl Type {
+    pub fn size(&self) -> usize {
+        match self {
+            Type::Unsigned(size) => *size,
+            Type::Array { count, stride, .. } => count * stride,
+            Type::Struct { size, .. } => *size,
+            Type::Pointer(_) => 8,
+        }
+    }
+
+    /// An array of `count` consecutive values of this type.
+    pub fn array(self, count: usize) -> Type {
+        let stride = self.size();
+        Type::Array {
+            element: Box::new(self),
+            count,
+            stride,
+        }
+    }
+}
+
+/// Rust types that can be described to debuggers, implemented by the
+/// structs declared with `layout!` or `debug_type!`.
+pub trait DebugType {
+    fn debug_type() -> Type;
+}
+
+macro_rules! unsigned_debug_types {
+    ($($ty:ty),*) => {
+        $(
+            impl DebugType for $ty {
+                fn debug_type() -> Type {
+                    Type::Unsigned(std::mem::size_of::<$ty>())
+                }
+            }
+        )*
+    };
+}
+unsigned_debug_types!(u8, u16, u32, u64);
+
+impl<T: DebugType, const N: usize> DebugType for [T; N] {
+    fn debug_type() -> Type {
+        T::debug_type().array(N)
+    }
+}
+
+/// The description of the type of a field, given a function that borrows
+/// the field, e.g. `|value: &Example| &value.a`, so that macros can name
+/// the type of a field that they only know by name.
+pub fn field_type<S, T: DebugType>(_field: fn(&S) -> &T) -> Type {
+    T::debug_type()
+}
+
+/// The data at a label, described as a variable of a type.
+pub struct Variable<'a> {
+    pub name: &'a str,
+    pub address: Addr,
+    pub ty: Type,
+}
+
+/// The contents of the `.debug_abbrev`, `.debug_info` and `.debug_line`
+/// sections.
+pub struct DebugSections {
+    pub abbrev: Vec<u8>,
+    pub info: Vec<u8>,
+    pub line: Vec<u8>,
+}
+
+/// Generate the debugging information of `units`, and of `variables` and
+/// `types`, if any, which are described whether or not any variable has
+/// them. Relative source paths are relative to `comp_dir`.
+pub fn debug_sections(
+    units: &[Unit],
+    variables: &[Variable],
+    types: &[Type],
+    comp_dir: &str,
+) -> DebugSections {
+    let mut abbrev = Vec::new();
+    for (code, (tag, children, attributes)) in (1..).zip(ABBREVIATIONS) {
+        uleb128(&mut abbrev, code);
+        abbrev.extend([tag, children]);
+        for &(attribute, form) in attributes {
+            abbrev.extend([attribute, form]);
+        }
+        abbrev.extend([0, 0]);
+    }
+    abbrev.push(0);
+
+    let mut info = Vec::new();
+    let mut line = Vec::new();
+    for unit in units {
+        let stmt_list = line.len() as u32;
+        line_program(&mut line, unit);
+
+        let mut die = Vec::new();
+        uleb128(&mut die, ABBREV_COMPILE_UNIT as u64);
+        string(&mut die, unit.name);
+        string(&mut die, PRODUCER);
+        string(&mut die, comp_dir);
+        die.extend(DW_LANG_MIPS_ASSEMBLER.to_le_bytes());
+        die.extend(stmt_list.to_le_bytes());
+        die.extend(unit.low_pc.to_le_bytes());
+        die.extend((unit.high_pc - unit.low_pc).to_le_bytes());
+        compile_unit(&mut info, die);
+    }
+    if !variables.is_empty() || !types.is_empty() {
+        compile_unit(&mut info, types_unit(variables, types));
+    }
+
+    DebugSections { abbrev, info, line }
+}
+
+/// Append a compilation unit with the DIEs `dies`.
+fn compile_unit(info: &mut Vec<u8>, dies: Vec<u8>) {
+    // The header after the length: version, abbreviation table offset and
+    // address size.
+    let length = UNIT_HEADER_SIZE - 4 + dies.len();
+    info.extend((length as u32).to_le_bytes());
+    info.extend(VERSION.to_le_bytes());
+    info.extend(0u32.to_le_bytes());
+    info.push(8);
+    info.extend(dies);
+}
+
+/// The DIEs of the compilation unit of `variables` and `types`.
+fn types_unit(variables: &[Variable], types: &[Type]) -> Vec<u8> {
+    let mut unit = TypesUnit {
+        dies: Vec::new(),
+        types: Vec::new(),
+    };
+    uleb128(&mut unit.dies, ABBREV_TYPES_UNIT as u64);
+    string(&mut unit.dies, "types");
+    string(&mut unit.dies, PRODUCER);
+    unit.dies.extend(DW_LANG_C99.to_le_bytes());
+    for ty in types {
+        unit.type_ref(ty);
+    }
+    for variable in variables {
+        let ty = unit.type_ref(&variable.ty);
+        let dies = &mut unit.dies;
+        uleb128(dies, ABBREV_VARIABLE as u64);
+        string(dies, variable.name);
+        dies.extend(ty.to_le_bytes());
+        // The location: a single `DW_OP_addr`.
+        dies.extend([9, DW_OP_ADDR]);
+        dies.extend(variable.address.to_le_bytes());
+    }
+    unit.dies.push(0);
+    unit.dies
+}
+
+struct TypesUnit {
+    dies: Vec<u8>,
+    /// The offset of the DIE of each type described so far.
+    types: Vec<(Type, u32)>,
+}
+
+impl TypesUnit {
+    /// The offset of the DIE of `ty`, describing it first if it isn't yet.
+    ///
+    /// A type is only described after the types it refers to, so that DIEs
+    /// of types aren't nested in each other.
+    fn type_ref(&mut self, ty: &Type) -> u32 {
+        if let Some(&(_, offset)) = self.types.iter().find(|(other, _)| other == ty) {
+            return offset;
+        }
+        if let Type::Struct { name, .. } = ty {
+            assert!(
+                !self
+                    .types
+                    .iter()
+                    .any(|(other, _)| matches!(other, Type::Struct { name: n, .. } if n == name)),
+                "conflicting descriptions of type {}",
+                name
+            );
+        }
+
+        let offset;
+        match ty {
+            Type::Unsigned(size) => {
+                offset = self.offset();
+                uleb128(&mut self.dies, ABBREV_BASE_TYPE as u64);
+                string(&mut self.dies, &format!("u{}", size * 8));
+                uleb128(&mut self.dies, *size as u64);
+                self.dies.push(DW_ATE_UNSIGNED);
+            }
+            Type::Array {
+                element,
+                count,
+                stride,
+            } => {
+                let element = self.type_ref(element);
+                offset = self.offset();
+                uleb128(&mut self.dies, ABBREV_ARRAY_TYPE as u64);
+                self.dies.extend(element.to_le_bytes());
+                uleb128(&mut self.dies, *stride as u64);
+                uleb128(&mut self.dies, ABBREV_SUBRANGE_TYPE as u64);
+                uleb128(&mut self.dies, *count as u64);
+                self.dies.push(0);
+            }
+            Type::Struct { name, size, fields } => {
+                let field_types: Vec<u32> = fields
+                    .iter()
+                    .map(|field| self.type_ref(&field.ty))
+                    .collect();
+                offset = self.offset();
+                uleb128(&mut self.dies, ABBREV_STRUCTURE_TYPE as u64);
+                string(&mut self.dies, name);
+                uleb128(&mut self.dies, *size as u64);
+                for (field, field_type) in fields.iter().zip(field_types) {
+                    uleb128(&mut self.dies, ABBREV_MEMBER as u64);
+                    string(&mut self.dies, field.name);
+                    self.dies.extend(field_type.to_le_bytes());
+                    uleb128(&mut self.dies, field.offset as u64);
+                }
+                self.dies.push(0);
+            }
+            Type::Pointer(target) => {
+                let target = self.type_ref(target);
+                offset = self.offset();
+                uleb128(&mut self.dies, ABBREV_POINTER_TYPE as u64);
+                uleb128(&mut self.dies, 8);
+                self.dies.extend(target.to_le_bytes());
+            }
+        }
+        self.types.push((ty.clone(), offset));
+        offset
+    }
+
+    /// The offset of the next DIE from the start of the unit.
+    fn offset(&self) -> u32 {
+        (UNIT_HEADER_SIZE + self.dies.len()) as u32
+    }
+}
+
+/// Append the line number program of `unit`, with its header.
+///
+/// Each row is emitted with standard opcodes only, which is less compact
+/// than special opcodes, but simple.
+fn line_program(out: &mut Vec<u8>, unit: &Unit) {
+    let mut files: Vec<&str> = Vec::new();
+    for (_, location) in &unit.rows {
+        if !files.contains(&location.file()) {
+            files.push(location.file());
+        }
+    }
+
+    // After the header length.
+    let mut header = vec![
+        1, // minimum_instruction_length
+        1, // maximum_operations_per_instruction
+        1, // default_is_stmt
+        LINE_BASE as u8,
+        LINE_RANGE,
+        STANDARD_OPCODE_LENGTHS.len() as u8 + 1,
+    ];
+    header.extend(STANDARD_OPCODE_LENGTHS);
+    // No include directories: every file is in the compilation directory.
+    header.push(0);
+    for file in &files {
+        string(&mut header, file);
+        // Directory index, modification time and length.
+        header.extend([0, 0, 0]);
+    }
+    header.push(0);
+
+    let mut program = Vec::new();
+    program.extend([0, 9, DW_LNE_SET_ADDRESS]);
+    program.extend(unit.low_pc.to_le_bytes());
+    let (mut address, mut file, mut line, mut column) = (unit.low_pc, 1, 1, 0);
+    for &(row_address, location) in &unit.rows {
+        let row_file = files.iter().position(|&f| f == location.file()).unwrap() as u64 + 1;
+        if row_file != file {
+            program.push(DW_LNS_SET_FILE);
+            uleb128(&mut program, row_file);
+            file = row_file;
+        }
+        if location.line() != line {
+            program.push(DW_LNS_ADVANCE_LINE);
+            sleb128(&mut program, location.line() as i64 - line as i64);
+            line = location.line();
+        }
+        if location.column() != column {
+            program.push(DW_LNS_SET_COLUMN);
+            uleb128(&mut program, location.column() as u64);
+            column = location.column();
+        }
+        if row_address != address {
+            program.push(DW_LNS_ADVANCE_PC);
+            uleb128(&mut program, row_address - address);
+            address = row_address;
+        }
+        program.push(DW_LNS_COPY);
+    }
+    if unit.high_pc != address {
+        program.push(DW_LNS_ADVANCE_PC);
+        uleb128(&mut program, unit.high_pc - address);
+    }
+    program.extend([0, 1, DW_LNE_END_SEQUENCE]);
+
+    let length = 2 + 4 + header.len() + program.len();
+    out.extend((length as u32).to_le_bytes());
+    out.extend(VERSION.to_le_bytes());
+    out.extend((header.len() as u32).to_le_bytes());
+    out.extend(header);
+    out.extend(program);
+}
+
+fn string(out: &mut Vec<u8>, string: &str) {
+    out.extend(string.as_bytes());
+    out.push(0);
+}
+
+fn uleb128(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn sleb128(out: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        let done = (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0);
+        if done {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_uleb128(bytes: &mut &[u8]) -> u64 {
+        let mut value = 0;
+        for shift in (0..).step_by(7) {
+            let byte = bytes[0];
+            *bytes = &bytes[1..];
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        value
+    }
+
+    fn read_sleb128(bytes: &mut &[u8]) -> i64 {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = bytes[0];
+            *bytes = &bytes[1..];
+            value |= ((byte & 0x7f) as i64) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    value |= -1 << shift;
+                }
+                return value;
+            }
+        }
+    }
+
+    /// A row of the line number table: `(address, file, line, column)`.
+    type Row = (Addr, u64, u32, u32);
+
+    /// Run a line number program that uses only the opcodes generated here,
+    /// and return its file names and rows, with the end of the sequence
+    /// last.
+    fn run(line_section: &[u8]) -> (Vec<String>, Vec<Row>) {
+        let length = u32::from_le_bytes(line_section[..4].try_into().unwrap()) as usize;
+        assert_eq!(length, line_section.len() - 4);
+        assert_eq!(
+            u16::from_le_bytes([line_section[4], line_section[5]]),
+            VERSION
+        );
+        let header_length = u32::from_le_bytes(line_section[6..10].try_into().unwrap()) as usize;
+        let header = &line_section[10..][..header_length];
+        let mut program = &line_section[10 + header_length..];
+
+        // Skip the parameters and the empty include directories.
+        let mut tables = &header[6 + STANDARD_OPCODE_LENGTHS.len() + 1..];
+        let mut files = Vec::new();
+        while tables[0] != 0 {
+            let end = tables.iter().position(|&b| b == 0).unwrap();
+            files.push(String::from_utf8(tables[..end].to_vec()).unwrap());
+            tables = &tables[end + 4..];
+        }
+
+        let mut rows = Vec::new();
+        let (mut address, mut file, mut line, mut column) = (0, 1, 1u32, 0);
+        loop {
+            let opcode = program[0];
+            program = &program[1..];
+            match opcode {
+                0 => {
+                    let len = read_uleb128(&mut program) as usize;
+                    match program[0] {
+                        DW_LNE_SET_ADDRESS => {
+                            address = u64::from_le_bytes(program[1..9].try_into().unwrap())
+                        }
+                        DW_LNE_END_SEQUENCE => {
+                            rows.push((address, file, line, column));
+                            return (files, rows);
+                        }
+                        other => panic!("unexpected extended opcode {}", other),
+                    }
+                    program = &program[len..];
+                }
+                DW_LNS_COPY => rows.push((address, file, line, column)),
+                DW_LNS_ADVANCE_PC => address += read_uleb128(&mut program),
+                DW_LNS_ADVANCE_LINE => line = (line as i64 + read_sleb128(&mut program)) as u32,
+                DW_LNS_SET_FILE => file = read_uleb128(&mut program),
+                DW_LNS_SET_COLUMN => column = read_uleb128(&mut program) as u32,
+                other => panic!("unexpected opcode {}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn leb128() {
+        for value in [0, 1, 0x7f, 0x80, 0x3fff, 0x4000, u64::MAX] {
+            let mut bytes = Vec::new();
+            uleb128(&mut bytes, value);
+            assert_eq!(read_uleb128(&mut bytes.as_slice()), value);
+        }
+        for value in [0, 1, -1, 63, 64, -64, -65, i64::MIN, i64::MAX] {
+            let mut bytes = Vec::new();
+            sleb128(&mut bytes, value);
+            assert_eq!(read_sleb128(&mut bytes.as_slice()), value);
+        }
+        let mut bytes = Vec::new();
+        sleb128(&mut bytes, -2);
+        assert_eq!(bytes, [0x7e]);
+    }
+
+    #[test]
+    fn line_program() {
+        let here = Location::caller();
+        let first = Location::caller();
+        let second = Location::caller();
+        let unit = Unit {
+            name: "code",
+            low_pc: 0x1000,
+            high_pc: 0x1010,
+            rows: vec![(0x1000, second), (0x1004, first), (0x100a, here)],
+        };
+        let sections = debug_sections(&[unit], &[], &[], "/src");
+        let (files, rows) = run(&sections.line);
+
+        assert_eq!(files, [file!()]);
+        let row = |address, location: &Location| (address, 1, location.line(), location.column());
+        assert_eq!(
+            rows,
+            [
+                row(0x1000, second),
+                row(0x1004, first),
+                row(0x100a, here),
+                row(0x1010, here),
+            ]
+        );
+
+        // One compilation unit, whose line program is at the start of the
+        // section.
+        let info = &sections.info;
+        assert_eq!(
+            u32::from_le_bytes(info[..4].try_into().unwrap()) as usize,
+            info.len() - 4
+        );
+        let die = &info[11..];
+        assert_eq!(die[0], ABBREV_COMPILE_UNIT);
+        let high_pc = &die[die.len() - 8..];
+        assert_eq!(u64::from_le_bytes(high_pc.try_into().unwrap()), 0x10);
+    }
+
+    #[test]
+    fn types() {
+        let pair = Type::Struct {
+            name: "Pair",
+            size: 4,
+            fields: vec![
+                Field {
+                    name: "a",
+                    offset: 0,
+                    ty: Type::Unsigned(2),
+                },
+                Field {
+                    name: "b",
+                    offset: 2,
+                    ty: Type::Unsigned(1).array(2),
+                },
+            ],
+        };
+        let variables = [
+            Variable {
+                name: "pairs",
+                address: 0x2000,
+                ty: pair.clone().array(3),
+            },
+            Variable {
+                name: "pair_ptr",
+                address: 0x2010,
+                ty: Type::Pointer(Box::new(pair.clone())),
+            },
+        ];
+        let sections = debug_sections(&[], &variables, &[pair], "/src");
+        let info = &sections.info;
+        assert_eq!(info[UNIT_HEADER_SIZE], ABBREV_TYPES_UNIT);
+        // Types are described once, however often they are referred to.
+        assert_eq!(info.windows(5).filter(|w| w == b"Pair\0").count(), 1);
+
+        // The type of a variable, and the location after it.
+        let variable = |name: &str| {
+            let start = info
+                .windows(name.len() + 1)
+                .position(|w| w == format!("{}\0", name).as_bytes())
+                .unwrap();
+            let after = &info[start + name.len() + 1..];
+            let ty = u32::from_le_bytes(after[..4].try_into().unwrap()) as usize;
+            assert_eq!(after[4..6], [9, DW_OP_ADDR]);
+            (ty, u64::from_le_bytes(after[6..14].try_into().unwrap()))
+        };
+        let ref_at = |offset: usize| u32::from_le_bytes(info[offset..][..4].try_into().unwrap());
+        let (pairs, address) = variable("pairs");
+        assert_eq!(address, 0x2000);
+        assert_eq!(info[pairs], ABBREV_ARRAY_TYPE);
+        let element = ref_at(pairs + 1) as usize;
+        assert_eq!(info[element], ABBREV_STRUCTURE_TYPE);
+        assert_eq!(&info[element + 1..][..5], b"Pair\0");
+        // The stride, then the count of the subrange.
+        assert_eq!(info[pairs + 5..][..3], [4, ABBREV_SUBRANGE_TYPE, 3]);
+
+        let (pair_ptr, address) = variable("pair_ptr");
+        assert_eq!(address, 0x2010);
+        assert_eq!(info[pair_ptr], ABBREV_POINTER_TYPE);
+        assert_eq!(ref_at(pair_ptr + 2) as usize, element);
+    }
+
+    #[test]
+    #[should_panic(expected = "conflicting descriptions of type Pair")]
+    fn conflicting_types() {
+        let pair = |size| Type::Struct {
+            name: "Pair",
+            size,
+            fields: Vec::new(),
+        };
+        debug_sections(&[], &[], &[pair(4), pair(8)], "/src");
+    }
+}
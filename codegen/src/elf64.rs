@@ -41,6 +41,15 @@ pub mod file_header {
     /// 2's complement, most-significant byte first.
     pub const ELFDATA2MSB: Uchar = 2;
 
+    /// No extensions or unspecified.
+    pub const ELFOSABI_NONE: Uchar = 0;
+    /// System V ABI.
+    pub const ELFOSABI_SYSV: Uchar = 0;
+    /// GNU/Linux extensions.
+    pub const ELFOSABI_GNU: Uchar = 3;
+    /// FreeBSD extensions.
+    pub const ELFOSABI_FREEBSD: Uchar = 9;
+    /// Standalone (embedded) application.
     pub const ELFOSABI_STANDALONE: Uchar = 255;
 
     /// No file type.
@@ -58,7 +67,18 @@ pub mod file_header {
     pub const ET_LOPROC: Half = 0xff00;
     pub const ET_HIPROC: Half = 0xffff;
 
+    /// No machine.
     pub const EM_NONE: Half = 0;
+    /// Intel 80386.
+    pub const EM_386: Half = 3;
+    /// ARM 32-bit.
+    pub const EM_ARM: Half = 40;
+    /// AMD x86-64.
+    pub const EM_X86_64: Half = 62;
+    /// ARM 64-bit.
+    pub const EM_AARCH64: Half = 183;
+    /// RISC-V.
+    pub const EM_RISCV: Half = 243;
 
     pub const EV_NONE: Uchar = 0;
     pub const EV_CURRENT: Uchar = 1;
@@ -389,6 +409,18 @@ pub mod reloc {
 
     use bytemuck::{Pod, Zeroable};
 
+    /// No relocation.
+    pub const R_X86_64_NONE: Word = 0;
+    /// Direct 64-bit: `S + A`.
+    pub const R_X86_64_64: Word = 1;
+    /// PC-relative 32-bit signed: `S + A - P`.
+    pub const R_X86_64_PC32: Word = 2;
+    /// Adjust by program base: `B + A`.
+    pub const R_X86_64_RELATIVE: Word = 8;
+
+    pub const REL_SIZE: Half = 0x10;
+    pub const RELA_SIZE: Half = 0x18;
+
     #[derive(Clone, Copy, Pod, Zeroable)]
     #[repr(C)]
     pub struct Rel {
@@ -434,6 +466,43 @@ pub mod reloc {
     }
 }
 
+pub mod dynamic {
+    use super::common::*;
+
+    use bytemuck::{Pod, Zeroable};
+
+    /// Marks the end of the dynamic array.
+    pub const DT_NULL: Sxword = 0;
+    /// Address of the relocation table with explicit addends.
+    pub const DT_RELA: Sxword = 7;
+    /// Total size, in bytes, of the `DT_RELA` relocation table.
+    pub const DT_RELASZ: Sxword = 8;
+    /// Size, in bytes, of each `DT_RELA` relocation entry.
+    pub const DT_RELAENT: Sxword = 9;
+    /// Flag values specific to this object.
+    pub const DT_FLAGS: Sxword = 30;
+    /// Number of relative relocations at the start of the `DT_RELA` table.
+    pub const DT_RELACOUNT: Sxword = 0x6fff_fff9;
+    /// State flags (GNU extension).
+    pub const DT_FLAGS_1: Sxword = 0x6fff_fffb;
+
+    /// Relocations may modify a non-writable segment.
+    pub const DF_TEXTREL: Xword = 0x4;
+    /// The object is a position-independent executable.
+    pub const DF_1_PIE: Xword = 0x0800_0000;
+
+    pub const DYN_SIZE: Half = 0x10;
+
+    #[derive(Clone, Copy, Pod, Zeroable)]
+    #[repr(C)]
+    pub struct Dyn {
+        /// Type of the entry.
+        pub d_tag: Sxword,
+        /// Integer value or address, interpreted according to `d_tag`.
+        pub d_val: Xword,
+    }
+}
+
 pub mod program {
     use super::common::*;
 
@@ -447,6 +516,12 @@ pub mod program {
     pub const PT_SHLIB: Word = 5;
     pub const PT_PHDR: Word = 6;
     pub const PT_LOOS: Word = 0x6000_0000;
+    /// Location of exception handling information (GNU extension).
+    pub const PT_GNU_EH_FRAME: Word = 0x6474_e550;
+    /// Stack executability (GNU extension).
+    pub const PT_GNU_STACK: Word = 0x6474_e551;
+    /// Read-only after relocation (GNU extension).
+    pub const PT_GNU_RELRO: Word = 0x6474_e552;
     pub const PT_HIOS: Word = 0x6fff_ffff;
     pub const PT_LOPROC: Word = 0x7000_0000;
     pub const PT_HIPROC: Word = 0x7fff_ffff;
@@ -489,7 +564,9 @@ mod tests {
     use crate::elf64::program::{Phdr, PROGRAM_HEADER_SIZE};
 
     use super::{
+        dynamic::{Dyn, DYN_SIZE},
         file_header::{FileHeader, FILE_HEADER_SIZE},
+        reloc::{Rela, RELA_SIZE},
         section_header::{SectionHeader, SECTION_HEADER_SIZE},
         symbol::{Symbol, SYMBOL_SIZE},
     };
@@ -521,6 +598,16 @@ mod tests {
         );
     }
 
+    #[test]
+    fn rela_size() {
+        assert_eq!(size_of::<Rela>(), usize::from(RELA_SIZE));
+    }
+
+    #[test]
+    fn dyn_size() {
+        assert_eq!(size_of::<Dyn>(), usize::from(DYN_SIZE));
+    }
+
     #[test]
     fn symbol_size() {
         assert_eq!(size_of::<Symbol>(), usize::try_from(SYMBOL_SIZE).unwrap());
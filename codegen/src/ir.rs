@@ -0,0 +1,355 @@
+//! A small typed, SSA-style intermediate representation.
+//!
+//! A [`Function`] is a list of basic blocks. Each block has parameters,
+//! which take the place of phi nodes: a jump passes a value for each
+//! parameter of its target. Every [`Value`] is defined exactly once, by a
+//! block parameter or an instruction.
+//!
+//! Code is built by positioning the function at a block with
+//! [`switch_to`](Function::switch_to) and appending instructions, each of
+//! which returns its result value. The x86 backend lowers it in
+//! [`crate::x86::lower`].
+
+/// The type of a value. All values are 64 bits wide.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Type {
+    I64,
+    Ptr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Value(u32);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Block(u32);
+
+impl Value {
+    pub fn index(&self) -> usize {
+        self.0 as usize
+    }
+}
+
+impl Block {
+    pub fn index(&self) -> usize {
+        self.0 as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    /// `I64 + I64 -> I64` or `Ptr + I64 -> Ptr`.
+    Add,
+    /// `I64 - I64 -> I64`, `Ptr - I64 -> Ptr` or `Ptr - Ptr -> I64`.
+    Sub,
+    And,
+    Or,
+    Xor,
+    /// Shift left. The shift amount must be a constant.
+    Shl,
+    /// Logical shift right. The shift amount must be a constant.
+    Shr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op<'a> {
+    Const(u64),
+    /// The address of a label.
+    Address(&'a str),
+    Binary(BinOp, Value, Value),
+    /// Load 64 bits from `ptr + offset`.
+    Load(Value, i8),
+    /// Load a zero-extended byte from `ptr + index`.
+    LoadByte(Value, Value),
+    /// Store 64 bits to `ptr + offset`.
+    Store(Value, i8, Value),
+    /// Call a System V function.
+    Call(&'a str, Vec<Value>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Inst<'a> {
+    pub result: Option<Value>,
+    pub op: Op<'a>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Terminator {
+    Jump(Block, Vec<Value>),
+    /// Jump to the first block if the value is nonzero, otherwise to the
+    /// second. Neither block may have parameters.
+    Branch(Value, Block, Block),
+    Return(Option<Value>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockData<'a> {
+    pub label: &'a str,
+    pub params: Vec<Value>,
+    pub insts: Vec<Inst<'a>>,
+    pub terminator: Option<Terminator>,
+}
+
+pub struct Function<'a> {
+    name: &'a str,
+    exported: bool,
+    returns: Option<Type>,
+    values: Vec<Type>,
+    blocks: Vec<BlockData<'a>>,
+    current: Block,
+}
+
+impl<'a> Function<'a> {
+    /// Create a function, positioned at its entry block, whose parameters
+    /// are the function's parameters.
+    pub fn new(name: &'a str, params: &[Type], returns: Option<Type>) -> Self {
+        let mut function = Self {
+            name,
+            exported: false,
+            returns,
+            values: Vec::new(),
+            blocks: Vec::new(),
+            current: Block(0),
+        };
+        function.block(name, params);
+        function
+    }
+
+    /// Export the function's label to other segments.
+    pub fn export(&mut self) {
+        self.exported = true;
+    }
+
+    pub fn name(&self) -> &'a str {
+        self.name
+    }
+
+    pub fn is_exported(&self) -> bool {
+        self.exported
+    }
+
+    pub fn returns(&self) -> Option<Type> {
+        self.returns
+    }
+
+    pub fn param_count(&self) -> usize {
+        self.blocks[0].params.len()
+    }
+
+    pub fn param(&self, index: usize) -> Value {
+        self.blocks[0].params[index]
+    }
+
+    pub fn ty(&self, value: Value) -> Type {
+        self.values[value.index()]
+    }
+
+    pub fn value_count(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn blocks(&self) -> &[BlockData<'a>] {
+        &self.blocks
+    }
+
+    pub fn block_data(&self, block: Block) -> &BlockData<'a> {
+        &self.blocks[block.index()]
+    }
+
+    fn value(&mut self, ty: Type) -> Value {
+        let value = Value(self.values.len() as u32);
+        self.values.push(ty);
+        value
+    }
+
+    /// Create a block with the given parameter types. Blocks are emitted in
+    /// order of creation. The label must be unique within the segment.
+    pub fn block(&mut self, label: &'a str, params: &[Type]) -> Block {
+        let params = params.iter().map(|&ty| self.value(ty)).collect();
+        self.blocks.push(BlockData {
+            label,
+            params,
+            insts: Vec::new(),
+            terminator: None,
+        });
+        Block(self.blocks.len() as u32 - 1)
+    }
+
+    pub fn block_param(&self, block: Block, index: usize) -> Value {
+        self.blocks[block.index()].params[index]
+    }
+
+    /// Append subsequent instructions to `block`.
+    pub fn switch_to(&mut self, block: Block) {
+        self.current = block;
+    }
+
+    fn current_block(&mut self) -> &mut BlockData<'a> {
+        let block = &mut self.blocks[self.current.index()];
+        assert!(
+            block.terminator.is_none(),
+            "block {} is already terminated",
+            block.label
+        );
+        block
+    }
+
+    fn push(&mut self, result: Option<Type>, op: Op<'a>) -> Option<Value> {
+        let result = result.map(|ty| self.value(ty));
+        self.current_block().insts.push(Inst { result, op });
+        result
+    }
+
+    pub fn const_(&mut self, ty: Type, value: u64) -> Value {
+        self.push(Some(ty), Op::Const(value)).unwrap()
+    }
+
+    pub fn address(&mut self, label: &'a str) -> Value {
+        self.push(Some(Type::Ptr), Op::Address(label)).unwrap()
+    }
+
+    pub fn binary(&mut self, op: BinOp, lhs: Value, rhs: Value) -> Value {
+        let ty = match (op, self.ty(lhs), self.ty(rhs)) {
+            (_, Type::I64, Type::I64) => Type::I64,
+            (BinOp::Add | BinOp::Sub, Type::Ptr, Type::I64) => Type::Ptr,
+            (BinOp::Sub, Type::Ptr, Type::Ptr) => Type::I64,
+            (op, lhs, rhs) => panic!("invalid operand types for {:?}: {:?}, {:?}", op, lhs, rhs),
+        };
+        self.push(Some(ty), Op::Binary(op, lhs, rhs)).unwrap()
+    }
+
+    fn expect(&self, value: Value, ty: Type) {
+        assert_eq!(self.ty(value), ty, "expected {:?} to be {:?}", value, ty);
+    }
+
+    pub fn load(&mut self, ptr: Value, offset: i8) -> Value {
+        self.expect(ptr, Type::Ptr);
+        self.push(Some(Type::I64), Op::Load(ptr, offset)).unwrap()
+    }
+
+    pub fn load_byte(&mut self, ptr: Value, index: Value) -> Value {
+        self.expect(ptr, Type::Ptr);
+        self.expect(index, Type::I64);
+        self.push(Some(Type::I64), Op::LoadByte(ptr, index))
+            .unwrap()
+    }
+
+    pub fn store(&mut self, ptr: Value, offset: i8, value: Value) {
+        self.expect(ptr, Type::Ptr);
+        self.push(None, Op::Store(ptr, offset, value));
+    }
+
+    /// Call a function, returning its result if `returns` is given.
+    pub fn call(
+        &mut self,
+        target: &'a str,
+        args: &[Value],
+        returns: Option<Type>,
+    ) -> Option<Value> {
+        self.push(returns, Op::Call(target, args.to_vec()))
+    }
+
+    fn terminate(&mut self, terminator: Terminator) {
+        self.current_block().terminator = Some(terminator);
+    }
+
+    pub fn jump(&mut self, target: Block, args: &[Value]) {
+        let params = &self.blocks[target.index()].params;
+        assert_eq!(
+            params.len(),
+            args.len(),
+            "wrong number of arguments to block {}",
+            self.blocks[target.index()].label
+        );
+        for (&param, &arg) in params.iter().zip(args) {
+            self.expect(arg, self.ty(param));
+        }
+        self.terminate(Terminator::Jump(target, args.to_vec()));
+    }
+
+    pub fn branch(&mut self, condition: Value, nonzero: Block, zero: Block) {
+        for block in [nonzero, zero] {
+            assert!(
+                self.blocks[block.index()].params.is_empty(),
+                "branch target {} cannot have parameters",
+                self.blocks[block.index()].label
+            );
+        }
+        self.terminate(Terminator::Branch(condition, nonzero, zero));
+    }
+
+    pub fn ret(&mut self, value: Option<Value>) {
+        match (value, self.returns) {
+            (Some(value), Some(ty)) => self.expect(value, ty),
+            (None, None) => {}
+            _ => panic!("{} must return {:?}", self.name, self.returns),
+        }
+        self.terminate(Terminator::Return(value));
+    }
+
+    /// Check that every block is terminated.
+    pub fn verify(&self) {
+        for block in &self.blocks {
+            assert!(
+                block.terminator.is_some(),
+                "block {} is not terminated",
+                block.label
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_loop() {
+        // Sum the integers below the parameter.
+        let mut f = Function::new("sum", &[Type::I64], Some(Type::I64));
+        let n = f.param(0);
+        let zero = f.const_(Type::I64, 0);
+        let head = f.block("sum_head", &[Type::I64, Type::I64]);
+        let body = f.block("sum_body", &[]);
+        let done = f.block("sum_done", &[]);
+        f.jump(head, &[n, zero]);
+
+        f.switch_to(head);
+        let i = f.block_param(head, 0);
+        let total = f.block_param(head, 1);
+        f.branch(i, body, done);
+
+        f.switch_to(body);
+        let one = f.const_(Type::I64, 1);
+        let i = f.binary(BinOp::Sub, i, one);
+        let total = f.binary(BinOp::Add, total, i);
+        f.jump(head, &[i, total]);
+
+        f.switch_to(done);
+        f.ret(Some(f.block_param(head, 1)));
+
+        f.verify();
+        assert_eq!(f.blocks().len(), 4);
+        assert_eq!(f.value_count(), 7);
+    }
+
+    #[test]
+    fn pointer_arithmetic() {
+        let mut f = Function::new("f", &[], Some(Type::Ptr));
+        let base = f.address("table");
+        let offset = f.const_(Type::I64, 8);
+        let ptr = f.binary(BinOp::Add, base, offset);
+        assert_eq!(f.ty(ptr), Type::Ptr);
+        let distance = f.binary(BinOp::Sub, ptr, base);
+        assert_eq!(f.ty(distance), Type::I64);
+        f.ret(Some(ptr));
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid operand types for And: Ptr, I64")]
+    fn type_mismatch() {
+        let mut f = Function::new("f", &[], None);
+        let base = f.address("table");
+        let mask = f.const_(Type::I64, 0xff);
+        f.binary(BinOp::And, base, mask);
+    }
+}
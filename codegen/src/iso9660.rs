@@ -0,0 +1,483 @@
+//! ISO9660 CD-ROM images, bootable through El Torito.
+//!
+//! See ECMA-119 and the El Torito Bootable CD-ROM Format Specification.
+//!
+//! Only plain ISO9660 names are written (no Rock Ridge or Joliet), so file
+//! names are stored in upper case and should be limited to letters, digits,
+//! underscores and a single dot.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+pub const SECTOR_SIZE: usize = 2048;
+/// The first 16 sectors are reserved for the system area.
+pub const SYSTEM_AREA_SECTORS: usize = 16;
+pub const STANDARD_ID: &[u8; 5] = b"CD001";
+
+pub const VOLUME_DESCRIPTOR_BOOT_RECORD: u8 = 0;
+pub const VOLUME_DESCRIPTOR_PRIMARY: u8 = 1;
+pub const VOLUME_DESCRIPTOR_TERMINATOR: u8 = 255;
+
+pub const FILE_FLAG_DIRECTORY: u8 = 0x02;
+
+pub const EL_TORITO_ID: &[u8] = b"EL TORITO SPECIFICATION";
+pub const PLATFORM_X86: u8 = 0x00;
+pub const PLATFORM_EFI: u8 = 0xef;
+pub const BOOT_INDICATOR_BOOTABLE: u8 = 0x88;
+pub const SECTION_HEADER_MORE: u8 = 0x90;
+pub const SECTION_HEADER_FINAL: u8 = 0x91;
+pub const MEDIA_NO_EMULATION: u8 = 0;
+
+struct BootEntry {
+    platform: u8,
+    path: String,
+    /// Patch a boot information table into the image.
+    boot_info_table: bool,
+}
+
+/// Builds a bootable ISO9660 image from in-memory files.
+pub struct IsoBuilder {
+    volume_id: String,
+    /// File contents by upper-case path, without a leading `/`.
+    files: BTreeMap<String, Vec<u8>>,
+    boot_entries: Vec<BootEntry>,
+}
+
+impl IsoBuilder {
+    pub fn new(volume_id: &str) -> Self {
+        assert!(
+            volume_id.len() <= 32,
+            "volume ID {:?} is too long",
+            volume_id
+        );
+        Self {
+            volume_id: volume_id.to_ascii_uppercase(),
+            files: BTreeMap::new(),
+            boot_entries: Vec::new(),
+        }
+    }
+
+    /// Add a file at the given `/`-separated path. Parent directories are
+    /// created implicitly.
+    pub fn add_file(&mut self, path: &str, data: Vec<u8>) {
+        let path = normalize(path);
+        assert!(
+            self.files.insert(path.clone(), data).is_none(),
+            "duplicate file {:?}",
+            path
+        );
+    }
+
+    /// Boot the file at `path` on BIOS systems, as a no-emulation boot image
+    /// loaded at 0x7c00.
+    ///
+    /// A boot information table is patched into the image at offset 8, as
+    /// expected by e.g. `limine-bios-cd.bin` and ISOLINUX.
+    pub fn add_bios_boot(&mut self, path: &str) {
+        self.boot_entries.push(BootEntry {
+            platform: PLATFORM_X86,
+            path: normalize(path),
+            boot_info_table: true,
+        });
+    }
+
+    /// Boot UEFI systems from the FAT filesystem image at `path`.
+    pub fn add_efi_boot(&mut self, path: &str) {
+        self.boot_entries.push(BootEntry {
+            platform: PLATFORM_EFI,
+            path: normalize(path),
+            boot_info_table: false,
+        });
+    }
+
+    /// Lay out and write the image.
+    pub fn finish(mut self) -> Vec<u8> {
+        // 1. Collect directories in the order of the path table: by level,
+        // then by parent, then by name. Each is identified by its number,
+        // starting from 1 for the root.
+        let mut directory_paths = BTreeSet::new();
+        for path in self.files.keys() {
+            let mut parent = path.as_str();
+            while let Some((prefix, _)) = parent.rsplit_once('/') {
+                directory_paths.insert(prefix.to_owned());
+                parent = prefix;
+            }
+        }
+        for path in &directory_paths {
+            assert!(
+                !self.files.contains_key(path),
+                "{:?} is both a file and a directory",
+                path
+            );
+        }
+        let mut directories = vec![""];
+        let mut parents = vec![1u16];
+        let mut i = 0;
+        while i < directories.len() {
+            for path in &directory_paths {
+                if parent_of(path) == directories[i] {
+                    directories.push(path);
+                    parents.push(i as u16 + 1);
+                }
+            }
+            i += 1;
+        }
+
+        // 2. Assign sectors: volume descriptors, path tables, the boot
+        // catalog, then directory and file extents.
+        let bootable = !self.boot_entries.is_empty();
+        let primary_sector = SYSTEM_AREA_SECTORS;
+        let boot_record_sector = primary_sector + 1;
+        let terminator_sector = primary_sector + 1 + bootable as usize;
+
+        let path_table: Vec<usize> = directories
+            .iter()
+            .map(|path| 8 + padded_len(identifier(path).len()))
+            .collect();
+        let path_table_size: usize = path_table.iter().sum();
+        let l_path_table_sector = terminator_sector + 1;
+        let m_path_table_sector = l_path_table_sector + sectors(path_table_size);
+        let boot_catalog_sector = m_path_table_sector + sectors(path_table_size);
+        let mut next_sector = boot_catalog_sector + bootable as usize;
+
+        let mut directory_extents = Vec::with_capacity(directories.len());
+        for path in &directories {
+            let size =
+                self.children(path, &directory_paths)
+                    .fold(2 * record_len(1), |size, (name, _)| {
+                        let len = record_len(name.len());
+                        // Records may not cross sector boundaries.
+                        if size % SECTOR_SIZE + len > SECTOR_SIZE {
+                            size.next_multiple_of(SECTOR_SIZE) + len
+                        } else {
+                            size + len
+                        }
+                    });
+            directory_extents.push((next_sector, size));
+            next_sector += sectors(size);
+        }
+        let mut file_extents = BTreeMap::new();
+        for (path, data) in &self.files {
+            file_extents.insert(path.clone(), (next_sector, data.len()));
+            next_sector += sectors(data.len());
+        }
+        let total_sectors = next_sector;
+
+        // 3. Patch boot information tables, now that file locations are
+        // known.
+        for entry in &self.boot_entries {
+            let &(sector, len) = file_extents
+                .get(&entry.path)
+                .unwrap_or_else(|| panic!("boot image {:?} not found", entry.path));
+            if !entry.boot_info_table {
+                continue;
+            }
+            let data = self.files.get_mut(&entry.path).unwrap();
+            assert!(len >= 64, "boot image {:?} is too small", entry.path);
+            let checksum = data[64..].chunks(4).fold(0u32, |sum, chunk| {
+                let mut word = [0; 4];
+                word[..chunk.len()].copy_from_slice(chunk);
+                sum.wrapping_add(u32::from_le_bytes(word))
+            });
+            for (i, field) in [primary_sector as u32, sector as u32, len as u32, checksum]
+                .into_iter()
+                .enumerate()
+            {
+                data[8 + 4 * i..][..4].copy_from_slice(&field.to_le_bytes());
+            }
+        }
+
+        // 4. Write the image.
+        let mut image = vec![0; total_sectors * SECTOR_SIZE];
+
+        let descriptor = &mut image[primary_sector * SECTOR_SIZE..][..SECTOR_SIZE];
+        volume_descriptor(descriptor, VOLUME_DESCRIPTOR_PRIMARY);
+        pad_str(&mut descriptor[8..40], "");
+        pad_str(&mut descriptor[40..72], &self.volume_id);
+        descriptor[80..88].copy_from_slice(&both_u32(total_sectors as u32));
+        descriptor[120..124].copy_from_slice(&both_u16(1));
+        descriptor[124..128].copy_from_slice(&both_u16(1));
+        descriptor[128..132].copy_from_slice(&both_u16(SECTOR_SIZE as u16));
+        descriptor[132..140].copy_from_slice(&both_u32(path_table_size as u32));
+        descriptor[140..144].copy_from_slice(&(l_path_table_sector as u32).to_le_bytes());
+        descriptor[148..152].copy_from_slice(&(m_path_table_sector as u32).to_be_bytes());
+        let (root_sector, root_size) = directory_extents[0];
+        directory_record(
+            &mut descriptor[156..190],
+            &[0],
+            root_sector,
+            root_size,
+            FILE_FLAG_DIRECTORY,
+        );
+        // Volume set, publisher, preparer, application, copyright, abstract
+        // and bibliographic identifiers.
+        for range in [
+            190..318,
+            318..446,
+            446..574,
+            574..702,
+            702..739,
+            739..776,
+            776..813,
+        ] {
+            pad_str(&mut descriptor[range], "");
+        }
+        // Creation, modification, expiration and effective dates: unset.
+        for offset in [813, 830, 847, 864] {
+            descriptor[offset..offset + 16].fill(b'0');
+        }
+        // File structure version.
+        descriptor[881] = 1;
+
+        if bootable {
+            let descriptor = &mut image[boot_record_sector * SECTOR_SIZE..][..SECTOR_SIZE];
+            volume_descriptor(descriptor, VOLUME_DESCRIPTOR_BOOT_RECORD);
+            descriptor[7..7 + EL_TORITO_ID.len()].copy_from_slice(EL_TORITO_ID);
+            descriptor[71..75].copy_from_slice(&(boot_catalog_sector as u32).to_le_bytes());
+
+            let catalog = &mut image[boot_catalog_sector * SECTOR_SIZE..][..SECTOR_SIZE];
+            self.write_boot_catalog(catalog, &file_extents);
+        }
+
+        volume_descriptor(
+            &mut image[terminator_sector * SECTOR_SIZE..][..SECTOR_SIZE],
+            VOLUME_DESCRIPTOR_TERMINATOR,
+        );
+
+        let mut l_offset = l_path_table_sector * SECTOR_SIZE;
+        let mut m_offset = m_path_table_sector * SECTOR_SIZE;
+        for ((path, &parent), &(sector, _)) in
+            directories.iter().zip(&parents).zip(&directory_extents)
+        {
+            let identifier = identifier(path);
+            for (offset, sector, parent) in [
+                (
+                    &mut l_offset,
+                    (sector as u32).to_le_bytes(),
+                    parent.to_le_bytes(),
+                ),
+                (
+                    &mut m_offset,
+                    (sector as u32).to_be_bytes(),
+                    parent.to_be_bytes(),
+                ),
+            ] {
+                let entry = &mut image[*offset..][..8 + identifier.len()];
+                entry[0] = identifier.len() as u8;
+                entry[2..6].copy_from_slice(&sector);
+                entry[6..8].copy_from_slice(&parent);
+                entry[8..].copy_from_slice(identifier);
+                *offset += 8 + padded_len(identifier.len());
+            }
+        }
+
+        for (i, path) in directories.iter().enumerate() {
+            let (sector, size) = directory_extents[i];
+            let (parent_sector, parent_size) = directory_extents[parents[i] as usize - 1];
+            let mut offset = sector * SECTOR_SIZE;
+            let mut push = |name: &[u8], sector, size, flags| {
+                let len = record_len(name.len());
+                if offset % SECTOR_SIZE + len > SECTOR_SIZE {
+                    offset = offset.next_multiple_of(SECTOR_SIZE);
+                }
+                directory_record(&mut image[offset..][..len], name, sector, size, flags);
+                offset += len;
+            };
+
+            push(&[0], sector, size, FILE_FLAG_DIRECTORY);
+            push(&[1], parent_sector, parent_size, FILE_FLAG_DIRECTORY);
+            for (name, child) in self.children(path, &directory_paths) {
+                match file_extents.get(&child) {
+                    Some(&(sector, size)) => push(name.as_bytes(), sector, size, 0),
+                    None => {
+                        let index = directories.iter().position(|path| *path == child).unwrap();
+                        let (sector, size) = directory_extents[index];
+                        push(name.as_bytes(), sector, size, FILE_FLAG_DIRECTORY);
+                    }
+                }
+            }
+        }
+
+        for (path, data) in &self.files {
+            let (sector, _) = file_extents[path];
+            image[sector * SECTOR_SIZE..][..data.len()].copy_from_slice(data);
+        }
+
+        image
+    }
+
+    /// The entries of a directory, sorted by identifier, as
+    /// `(identifier, path)`.
+    fn children<'b>(
+        &'b self,
+        directory: &'b str,
+        directory_paths: &'b BTreeSet<String>,
+    ) -> impl Iterator<Item = (String, String)> + 'b {
+        let files = self
+            .files
+            .keys()
+            .filter(move |path| parent_of(path) == directory)
+            .map(|path| (format!("{};1", base_name(path)), path.clone()));
+        let directories = directory_paths
+            .iter()
+            .filter(move |path| parent_of(path) == directory)
+            .map(|path| (base_name(path).to_owned(), path.clone()));
+        let mut children: Vec<_> = files.chain(directories).collect();
+        children.sort();
+        children.into_iter()
+    }
+
+    fn write_boot_catalog(
+        &self,
+        catalog: &mut [u8],
+        file_extents: &BTreeMap<String, (usize, usize)>,
+    ) {
+        let entry = |entry: &mut [u8], boot_entry: &BootEntry| {
+            let (sector, len) = file_extents[&boot_entry.path];
+            // Number of 512-byte sectors loaded by the firmware.
+            let load_size = match boot_entry.platform {
+                PLATFORM_X86 => 4,
+                _ => len.div_ceil(512).min(u16::MAX as usize) as u16,
+            };
+            entry[0] = BOOT_INDICATOR_BOOTABLE;
+            entry[1] = MEDIA_NO_EMULATION;
+            entry[6..8].copy_from_slice(&load_size.to_le_bytes());
+            entry[8..12].copy_from_slice(&(sector as u32).to_le_bytes());
+        };
+
+        // Validation entry
+        catalog[0] = 1;
+        catalog[1] = self.boot_entries[0].platform;
+        catalog[30] = 0x55;
+        catalog[31] = 0xaa;
+        let checksum = catalog[..32]
+            .chunks(2)
+            .fold(0u16, |sum, word| {
+                sum.wrapping_add(u16::from_le_bytes([word[0], word[1]]))
+            })
+            .wrapping_neg();
+        catalog[28..30].copy_from_slice(&checksum.to_le_bytes());
+
+        // Initial/default entry
+        entry(&mut catalog[32..64], &self.boot_entries[0]);
+
+        // Each additional entry gets its own section.
+        let sections = &self.boot_entries[1..];
+        for (i, boot_entry) in sections.iter().enumerate() {
+            let header = &mut catalog[64 + 64 * i..][..32];
+            header[0] = match i == sections.len() - 1 {
+                true => SECTION_HEADER_FINAL,
+                false => SECTION_HEADER_MORE,
+            };
+            header[1] = boot_entry.platform;
+            header[2..4].copy_from_slice(&1u16.to_le_bytes());
+            entry(&mut catalog[96 + 64 * i..][..32], boot_entry);
+        }
+    }
+}
+
+fn normalize(path: &str) -> String {
+    path.trim_start_matches('/').to_ascii_uppercase()
+}
+
+fn parent_of(path: &str) -> &str {
+    path.rsplit_once('/').map_or("", |(parent, _)| parent)
+}
+
+fn base_name(path: &str) -> &str {
+    path.rsplit_once('/').map_or(path, |(_, name)| name)
+}
+
+/// The identifier of a directory in the path table.
+fn identifier(path: &str) -> &[u8] {
+    match path {
+        "" => &[0],
+        _ => base_name(path).as_bytes(),
+    }
+}
+
+fn padded_len(len: usize) -> usize {
+    len + len % 2
+}
+
+fn record_len(name_len: usize) -> usize {
+    33 + name_len + (name_len + 1) % 2
+}
+
+fn sectors(size: usize) -> usize {
+    size.div_ceil(SECTOR_SIZE)
+}
+
+fn both_u16(x: u16) -> [u8; 4] {
+    let mut bytes = [0; 4];
+    bytes[..2].copy_from_slice(&x.to_le_bytes());
+    bytes[2..].copy_from_slice(&x.to_be_bytes());
+    bytes
+}
+
+fn both_u32(x: u32) -> [u8; 8] {
+    let mut bytes = [0; 8];
+    bytes[..4].copy_from_slice(&x.to_le_bytes());
+    bytes[4..].copy_from_slice(&x.to_be_bytes());
+    bytes
+}
+
+fn pad_str(field: &mut [u8], s: &str) {
+    field.fill(b' ');
+    field[..s.len()].copy_from_slice(s.as_bytes());
+}
+
+fn volume_descriptor(descriptor: &mut [u8], type_: u8) {
+    descriptor[0] = type_;
+    descriptor[1..6].copy_from_slice(STANDARD_ID);
+    descriptor[6] = 1;
+}
+
+fn directory_record(record: &mut [u8], name: &[u8], sector: usize, size: usize, flags: u8) {
+    record[0] = record_len(name.len()) as u8;
+    record[2..10].copy_from_slice(&both_u32(sector as u32));
+    record[10..18].copy_from_slice(&both_u32(size as u32));
+    record[25] = flags;
+    record[28..32].copy_from_slice(&both_u16(1));
+    record[32] = name.len() as u8;
+    record[33..33 + name.len()].copy_from_slice(name);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bootable_image() {
+        let mut builder = IsoBuilder::new("alpha");
+        builder.add_file("/boot/kernel.elf", vec![0xaa; 3000]);
+        builder.add_file("/boot/limine/bios-cd.bin", vec![0xbb; 4096]);
+        builder.add_file("/limine.conf", b"timeout: 0\n".to_vec());
+        builder.add_bios_boot("/boot/limine/bios-cd.bin");
+        let image = builder.finish();
+
+        let sector = |n: usize| &image[n * SECTOR_SIZE..][..SECTOR_SIZE];
+        assert_eq!(&sector(16)[..7], b"\x01CD001\x01");
+        assert_eq!(&sector(17)[..7], b"\x00CD001\x01");
+        assert_eq!(&sector(18)[..7], b"\xffCD001\x01");
+
+        let catalog_sector = u32::from_le_bytes(sector(17)[71..75].try_into().unwrap());
+        let catalog = sector(catalog_sector as usize);
+        let checksum = catalog[..32].chunks(2).fold(0u16, |sum, word| {
+            sum.wrapping_add(u16::from_le_bytes([word[0], word[1]]))
+        });
+        assert_eq!(checksum, 0);
+        assert_eq!(catalog[32], BOOT_INDICATOR_BOOTABLE);
+
+        // The boot information table points back at the primary volume
+        // descriptor and the boot image itself.
+        let boot_sector = u32::from_le_bytes(catalog[40..44].try_into().unwrap());
+        let boot_image = &image[boot_sector as usize * SECTOR_SIZE..][..4096];
+        let table: Vec<u32> = boot_image[8..24]
+            .chunks(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+        assert_eq!(table[..3], [16, boot_sector, 4096]);
+        assert_eq!(table[3], 0xbbbbbbbb_u32.wrapping_mul(1008));
+        assert_eq!(boot_image[24], 0xbb);
+    }
+}
@@ -0,0 +1,129 @@
+//! Declarative C-layout structures, with their field offsets, size and
+//! alignment available as constants for generated code, and their layout
+//! available to debuggers.
+
+/// Defines a `#[repr(C)]`, [`Pod`](bytemuck::Pod) struct, with associated
+/// constants for its `SIZE`, `ALIGN`, and the byte offset of each field
+/// under the name given after `=>`, and an implementation of
+/// [`DebugType`](crate::dwarf::DebugType).
+///
+/// Deriving `Pod` rejects structs with padding, so every byte of the layout
+/// must be accounted for by a field.
+macro_rules! layout {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident {
+            $(
+                $(#[$field_meta:meta])*
+                $field_vis:vis $field:ident: $ty:ty => $offset:ident,
+            )*
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+        #[repr(C)]
+        $vis struct $name {
+            $(
+                $(#[$field_meta])*
+                $field_vis $field: $ty,
+            )*
+        }
+
+        impl $name {
+            pub const SIZE: usize = std::mem::size_of::<Self>();
+            pub const ALIGN: usize = std::mem::align_of::<Self>();
+            $(
+                #[doc = concat!("Byte offset of `", stringify!($field), "`.")]
+                pub const $offset: usize = std::mem::offset_of!(Self, $field);
+            )*
+        }
+
+        $crate::layout::debug_type!($name { $($field),* });
+    };
+}
+pub(crate) use layout;
+
+/// Implements [`DebugType`](crate::dwarf::DebugType) for an existing
+/// `#[repr(C)]` struct, describing the listed fields, whose types must
+/// implement it as well.
+macro_rules! debug_type {
+    ($ty:ident { $($field:ident),* $(,)? }) => {
+        impl $crate::dwarf::DebugType for $ty {
+            fn debug_type() -> $crate::dwarf::Type {
+                $crate::dwarf::Type::Struct {
+                    name: stringify!($ty),
+                    size: std::mem::size_of::<$ty>(),
+                    fields: vec![
+                        $(
+                            $crate::dwarf::Field {
+                                name: stringify!($field),
+                                offset: std::mem::offset_of!($ty, $field),
+                                ty: $crate::dwarf::field_type(|value: &$ty| &value.$field),
+                            },
+                        )*
+                    ],
+                }
+            }
+        }
+    };
+}
+pub(crate) use debug_type;
+
+/// Defines a byte offset constant for each listed field of an existing
+/// `#[repr(C)]` struct, derived from the struct definition so that generated
+/// code cannot drift out of sync with it.
+#[cfg(feature = "limine")]
+macro_rules! offsets {
+    ($ty:ty { $($field:ident => $name:ident),* $(,)? }) => {
+        $(
+            #[doc = concat!(
+                "Byte offset of `", stringify!($ty), ".", stringify!($field), "`."
+            )]
+            pub const $name: usize = std::mem::offset_of!($ty, $field);
+        )*
+    };
+}
+#[cfg(feature = "limine")]
+pub(crate) use offsets;
+
+#[cfg(test)]
+mod tests {
+    use crate::dwarf::{DebugType, Field, Type};
+
+    layout! {
+        struct Example {
+            a: u32 => A,
+            b: u16 => B,
+            c: [u8; 2] => C,
+            d: u64 => D,
+        }
+    }
+
+    #[test]
+    fn layout_constants() {
+        assert_eq!(Example::SIZE, 16);
+        assert_eq!(Example::ALIGN, 8);
+        assert_eq!(Example::A, 0);
+        assert_eq!(Example::B, 4);
+        assert_eq!(Example::C, 6);
+        assert_eq!(Example::D, 8);
+    }
+
+    #[test]
+    fn debug_type() {
+        let field = |name, offset, ty| Field { name, offset, ty };
+        assert_eq!(
+            Example::debug_type(),
+            Type::Struct {
+                name: "Example",
+                size: 16,
+                fields: vec![
+                    field("a", 0, Type::Unsigned(4)),
+                    field("b", 4, Type::Unsigned(2)),
+                    field("c", 6, Type::Unsigned(1).array(2)),
+                    field("d", 8, Type::Unsigned(8)),
+                ],
+            }
+        );
+    }
+}
@@ -0,0 +1,37 @@
+//! The code generator, linker and image formats that the kernel is built
+//! with. The `kernel` example uses them to build the kernel itself.
+//!
+//! The x86-64 encoder and assembler, and the segments they emit, are always
+//! built. The rest is behind features: `image` for the ELF linker and the
+//! image formats, `limine` for the Limine boot protocol, and `kernel`, the
+//! default, for the generator itself.
+
+// For the paths generated by `asm_block!`, which name this crate as a
+// dependency would.
+extern crate self as alpha_codegen;
+
+pub mod asm;
+#[cfg(feature = "image")]
+pub mod boot_sector;
+#[cfg(all(test, feature = "qemu"))]
+mod boot_test;
+#[cfg(feature = "limine")]
+pub mod config;
+pub mod dwarf;
+pub mod elf64;
+#[cfg(feature = "kernel")]
+pub mod ir;
+#[cfg(feature = "image")]
+pub mod iso9660;
+pub mod layout;
+#[cfg(feature = "limine")]
+pub mod limine;
+pub mod link;
+pub mod math;
+#[cfg(feature = "image")]
+pub mod multiboot2;
+#[cfg(feature = "image")]
+pub mod pe;
+#[cfg(feature = "image")]
+pub mod symbolize;
+pub mod x86;
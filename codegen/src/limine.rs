@@ -1,13 +1,32 @@
 use bytemuck::{Pod, Zeroable};
+use std::{fs, io, path::Path};
+
+use crate::{
+    dwarf::{DebugType, Type},
+    iso9660::IsoBuilder,
+    layout::{debug_type, offsets},
+    link::{Label, Ptr, ReferenceFormat, Segment},
+    x86::{address::disp8, asm_block, Assembler},
+};
+
+pub const REQUESTS_START_MARKER: [u64; 4] = [
+    0xf6b8f4b39de7d1ae,
+    0xfab91a6940fcb9cf,
+    0x785c6ed015d3e316,
+    0x181e920a7852b9d9,
+];
+pub const REQUESTS_END_MARKER: [u64; 2] = [0xadc0e0531bb10d03, 0x9572709f31764c62];
+pub const BASE_REVISION_MAGIC: [u64; 2] = [0xf9562b2d5c95a6c8, 0x6a7b384944536bdc];
 
 pub const COMMON_MAGIC: [u64; 2] = [0xc7b1dd30df4c8b88, 0x0a82e883a194f07b];
 pub const BOOTLOADER_INFO_REQUEST: [u64; 2] = [0xf55038d8e2a1202f, 0x279426fcf5f59740];
 pub const TERMINAL_REQUEST: [u64; 2] = [0xc8ac59310c2b0844, 0xa68d0c7265d38878];
-
-/// Byte offset of Request.response from the start of the struct.
-///
-/// `[u64; 2]; [u64; 2]; u64`
-pub const RESPONSE_OFFSET: usize = 40;
+pub const FRAMEBUFFER_REQUEST: [u64; 2] = [0x9d5827dcd881dd75, 0xa3148604f6fab11b];
+pub const MEMMAP_REQUEST: [u64; 2] = [0x67cf3d9d378a806f, 0xe304acdfc50c3c62];
+pub const HHDM_REQUEST: [u64; 2] = [0x48dcf1cb8ad2b852, 0x63984e959a98244b];
+pub const SMP_REQUEST: [u64; 2] = [0x95a67b819a1b857e, 0xa0b61b723b6a73e0];
+pub const SMBIOS_REQUEST: [u64; 2] = [0x9e9046f11e095391, 0xaa4a520fefbde5ee];
+pub const RSDP_REQUEST: [u64; 2] = [0xc5e77b6b397e7b43, 0x27637845accdcf3c];
 
 #[derive(Clone, Copy, Pod, Zeroable)]
 #[repr(C)]
@@ -18,6 +37,452 @@ pub struct Request {
     response: u64,
 }
 
+offsets!(Request {
+    response => RESPONSE_OFFSET,
+});
+
+debug_type!(Request {
+    common_magic,
+    request_id,
+    revision,
+    response
+});
+
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct BootloaderInfoResponse {
+    pub revision: u64,
+    /// Pointer to a null-terminated string.
+    pub name: u64,
+    /// Pointer to a null-terminated string.
+    pub version: u64,
+}
+
+offsets!(BootloaderInfoResponse {
+    name => BOOTLOADER_INFO_RESPONSE_NAME_OFFSET,
+    version => BOOTLOADER_INFO_RESPONSE_VERSION_OFFSET,
+});
+
+debug_type!(BootloaderInfoResponse {
+    revision,
+    name,
+    version
+});
+
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct TerminalResponse {
+    pub revision: u64,
+    pub terminal_count: u64,
+    /// Pointer to an array of `terminal_count` pointers to terminals.
+    pub terminals: u64,
+    /// Address of the terminal write function, called with the terminal in
+    /// RDI, the string in RSI and its length in RDX.
+    pub write: u64,
+}
+
+offsets!(TerminalResponse {
+    terminal_count => TERMINAL_RESPONSE_TERMINAL_COUNT_OFFSET,
+    terminals => TERMINAL_RESPONSE_TERMINALS_OFFSET,
+    write => TERMINAL_RESPONSE_WRITE_OFFSET,
+});
+
+debug_type!(TerminalResponse {
+    revision,
+    terminal_count,
+    terminals,
+    write
+});
+
+/// Framebuffer memory model: RGB with the channel layout given by the mask
+/// fields.
+pub const FRAMEBUFFER_RGB: u8 = 1;
+
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct FramebufferResponse {
+    pub revision: u64,
+    pub framebuffer_count: u64,
+    /// Pointer to an array of `framebuffer_count` pointers to
+    /// [`Framebuffer`]s.
+    pub framebuffers: u64,
+}
+
+offsets!(FramebufferResponse {
+    framebuffer_count => FRAMEBUFFER_RESPONSE_FRAMEBUFFER_COUNT_OFFSET,
+    framebuffers => FRAMEBUFFER_RESPONSE_FRAMEBUFFERS_OFFSET,
+});
+
+debug_type!(FramebufferResponse {
+    revision,
+    framebuffer_count,
+    framebuffers
+});
+
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct Framebuffer {
+    /// Virtual address of the framebuffer memory.
+    pub address: u64,
+    pub width: u64,
+    pub height: u64,
+    /// Bytes per row.
+    pub pitch: u64,
+    /// Bits per pixel.
+    pub bpp: u16,
+    pub memory_model: u8,
+    pub red_mask_size: u8,
+    pub red_mask_shift: u8,
+    pub green_mask_size: u8,
+    pub green_mask_shift: u8,
+    pub blue_mask_size: u8,
+    pub blue_mask_shift: u8,
+    pub unused: [u8; 7],
+    pub edid_size: u64,
+    /// Pointer to the display's EDID blob, or null.
+    pub edid: u64,
+    /// Response revision 1: number of entries in `modes`.
+    pub mode_count: u64,
+    /// Response revision 1: pointer to an array of pointers to
+    /// [`VideoMode`]s.
+    pub modes: u64,
+}
+
+offsets!(Framebuffer {
+    address => FRAMEBUFFER_ADDRESS_OFFSET,
+    width => FRAMEBUFFER_WIDTH_OFFSET,
+    height => FRAMEBUFFER_HEIGHT_OFFSET,
+    pitch => FRAMEBUFFER_PITCH_OFFSET,
+    bpp => FRAMEBUFFER_BPP_OFFSET,
+    memory_model => FRAMEBUFFER_MEMORY_MODEL_OFFSET,
+    red_mask_size => FRAMEBUFFER_RED_MASK_SIZE_OFFSET,
+    red_mask_shift => FRAMEBUFFER_RED_MASK_SHIFT_OFFSET,
+    green_mask_size => FRAMEBUFFER_GREEN_MASK_SIZE_OFFSET,
+    green_mask_shift => FRAMEBUFFER_GREEN_MASK_SHIFT_OFFSET,
+    blue_mask_size => FRAMEBUFFER_BLUE_MASK_SIZE_OFFSET,
+    blue_mask_shift => FRAMEBUFFER_BLUE_MASK_SHIFT_OFFSET,
+    edid_size => FRAMEBUFFER_EDID_SIZE_OFFSET,
+    edid => FRAMEBUFFER_EDID_OFFSET,
+    mode_count => FRAMEBUFFER_MODE_COUNT_OFFSET,
+    modes => FRAMEBUFFER_MODES_OFFSET,
+});
+
+debug_type!(Framebuffer {
+    address,
+    width,
+    height,
+    pitch,
+    bpp,
+    memory_model,
+    red_mask_size,
+    red_mask_shift,
+    green_mask_size,
+    green_mask_shift,
+    blue_mask_size,
+    blue_mask_shift,
+    unused,
+    edid_size,
+    edid,
+    mode_count,
+    modes
+});
+
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct VideoMode {
+    pub pitch: u64,
+    pub width: u64,
+    pub height: u64,
+    pub bpp: u16,
+    pub memory_model: u8,
+    pub red_mask_size: u8,
+    pub red_mask_shift: u8,
+    pub green_mask_size: u8,
+    pub green_mask_shift: u8,
+    pub blue_mask_size: u8,
+    pub blue_mask_shift: u8,
+    pub unused: [u8; 7],
+}
+
+debug_type!(VideoMode {
+    pitch,
+    width,
+    height,
+    bpp,
+    memory_model,
+    red_mask_size,
+    red_mask_shift,
+    green_mask_size,
+    green_mask_shift,
+    blue_mask_size,
+    blue_mask_shift,
+    unused
+});
+
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct MemmapResponse {
+    pub revision: u64,
+    pub entry_count: u64,
+    /// Pointer to an array of `entry_count` pointers to [`MemmapEntry`]s,
+    /// sorted by base address.
+    pub entries: u64,
+}
+
+offsets!(MemmapResponse {
+    entry_count => MEMMAP_RESPONSE_ENTRY_COUNT_OFFSET,
+    entries => MEMMAP_RESPONSE_ENTRIES_OFFSET,
+});
+
+debug_type!(MemmapResponse {
+    revision,
+    entry_count,
+    entries
+});
+
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct MemmapEntry {
+    pub base: u64,
+    pub length: u64,
+    /// One of the `MEMMAP_*` entry types.
+    pub type_: u64,
+}
+
+offsets!(MemmapEntry {
+    base => MEMMAP_ENTRY_BASE_OFFSET,
+    length => MEMMAP_ENTRY_LENGTH_OFFSET,
+    type_ => MEMMAP_ENTRY_TYPE_OFFSET,
+});
+
+debug_type!(MemmapEntry {
+    base,
+    length,
+    type_
+});
+
+pub const MEMMAP_USABLE: u64 = 0;
+pub const MEMMAP_RESERVED: u64 = 1;
+pub const MEMMAP_ACPI_RECLAIMABLE: u64 = 2;
+pub const MEMMAP_ACPI_NVS: u64 = 3;
+pub const MEMMAP_BAD_MEMORY: u64 = 4;
+/// Usable once the kernel is done with bootloader structures, including
+/// all responses.
+pub const MEMMAP_BOOTLOADER_RECLAIMABLE: u64 = 5;
+pub const MEMMAP_KERNEL_AND_MODULES: u64 = 6;
+pub const MEMMAP_FRAMEBUFFER: u64 = 7;
+
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct HhdmResponse {
+    pub revision: u64,
+    /// Virtual address at which all physical memory is mapped, i.e. the
+    /// value to add to a physical address to access it.
+    pub offset: u64,
+}
+
+offsets!(HhdmResponse {
+    offset => HHDM_RESPONSE_OFFSET_OFFSET,
+});
+
+debug_type!(HhdmResponse { revision, offset });
+
+/// Displacement of `HhdmResponse.offset` from the start of the response,
+/// typed for use in `Index` addressing.
+pub const HHDM_OFFSET_DISPLACEMENT: i8 = disp8(HHDM_RESPONSE_OFFSET_OFFSET);
+
+/// SMP request flag: enable x2APIC mode, if available.
+pub const SMP_X2APIC: u64 = 1 << 0;
+
+/// The SMP request, which has an extra flags field following the common
+/// request fields.
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct SmpRequest {
+    request: Request,
+    flags: u64,
+}
+
+debug_type!(SmpRequest { request, flags });
+
+impl SmpRequest {
+    pub fn new(revision: u64, flags: u64) -> Self {
+        Self {
+            request: Request::new(SMP_REQUEST, revision),
+            flags,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct SmpResponse {
+    pub revision: u64,
+    /// Bit 0: x2APIC mode was enabled.
+    pub flags: u32,
+    pub bsp_lapic_id: u32,
+    pub cpu_count: u64,
+    /// Pointer to an array of `cpu_count` pointers to [`SmpInfo`]s.
+    pub cpus: u64,
+}
+
+offsets!(SmpResponse {
+    flags => SMP_RESPONSE_FLAGS_OFFSET,
+    bsp_lapic_id => SMP_RESPONSE_BSP_LAPIC_ID_OFFSET,
+    cpu_count => SMP_RESPONSE_CPU_COUNT_OFFSET,
+    cpus => SMP_RESPONSE_CPUS_OFFSET,
+});
+
+debug_type!(SmpResponse {
+    revision,
+    flags,
+    bsp_lapic_id,
+    cpu_count,
+    cpus
+});
+
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct SmpInfo {
+    pub processor_id: u32,
+    pub lapic_id: u32,
+    pub reserved: u64,
+    /// Writing an address here starts the AP at that address, with a
+    /// pointer to this structure in RDI.
+    pub goto_address: u64,
+    /// Free for use by the kernel, e.g. to pass arguments to the AP.
+    pub extra_argument: u64,
+}
+
+offsets!(SmpInfo {
+    processor_id => SMP_INFO_PROCESSOR_ID_OFFSET,
+    lapic_id => SMP_INFO_LAPIC_ID_OFFSET,
+    goto_address => SMP_INFO_GOTO_ADDRESS_OFFSET,
+    extra_argument => SMP_INFO_EXTRA_ARGUMENT_OFFSET,
+});
+
+debug_type!(SmpInfo {
+    processor_id,
+    lapic_id,
+    reserved,
+    goto_address,
+    extra_argument
+});
+
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct SmbiosResponse {
+    pub revision: u64,
+    /// Address of the 32-bit SMBIOS entry point, or 0 if not present.
+    pub entry_32: u64,
+    /// Address of the 64-bit (SMBIOS 3) entry point, or 0 if not present.
+    pub entry_64: u64,
+}
+
+offsets!(SmbiosResponse {
+    entry_32 => SMBIOS_RESPONSE_ENTRY_32_OFFSET,
+    entry_64 => SMBIOS_RESPONSE_ENTRY_64_OFFSET,
+});
+
+debug_type!(SmbiosResponse {
+    revision,
+    entry_32,
+    entry_64
+});
+
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct RsdpResponse {
+    pub revision: u64,
+    /// Address of the ACPI RSDP. It is a virtual address in the HHDM up to
+    /// base revision 2, and a physical address from base revision 3.
+    pub address: u64,
+}
+
+offsets!(RsdpResponse {
+    address => RSDP_RESPONSE_ADDRESS_OFFSET,
+});
+
+debug_type!(RsdpResponse { revision, address });
+
+/// Generates the code to start the application processors (APs) reported in
+/// an SMP response, each on its own stack.
+///
+/// The generated code uses the fixed local labels `smp_start_loop`,
+/// `smp_start_done`, `smp_trampoline` and `smp_halt`, so it can only be
+/// emitted once per segment.
+pub struct SmpStartup<'a> {
+    /// Label of the `response` pointer of the SMP request.
+    pub response: &'a str,
+    /// Label of the per-CPU stack array, `max_cpus * stack_size` bytes.
+    pub stacks: &'a str,
+    /// Size of each stack. Must be a multiple of 16.
+    pub stack_size: i32,
+    /// Number of stacks in the array. CPUs beyond this are not started.
+    pub max_cpus: u64,
+    /// Function called on each AP, with a pointer to its [`SmpInfo`] in
+    /// RDI. Should not return; if it does, the AP halts.
+    pub ap_entry: &'a str,
+}
+
+impl<'a> SmpStartup<'a> {
+    /// Emit the code run by the bootstrap processor, which assigns each CPU
+    /// a stack through its `extra_argument` and then writes its
+    /// `goto_address`.
+    ///
+    /// The BSP is assigned a stack as well, but Limine ignores its
+    /// `goto_address`. Clobbers RAX, RCX, RDX, RSI, RDI, R8 and R9.
+    pub fn emit_start(&self, asm: &mut Assembler) {
+        assert!(
+            self.stack_size % 16 == 0,
+            "stack size must be a multiple of 16"
+        );
+
+        asm_block!(asm;
+            mov r8, [{Ptr(self.response)}];
+            test r8, r8;
+            jz "smp_start_done";
+            mov rcx, [r8 + {disp8(SMP_RESPONSE_CPU_COUNT_OFFSET)}];
+            mov rsi, [r8 + {disp8(SMP_RESPONSE_CPUS_OFFSET)}];
+            mov r9, {self.max_cpus};
+            lea rdx, [{Ptr(self.stacks)}];
+            lea rax, ["smp_trampoline"];
+
+            "smp_start_loop":
+            test rcx, rcx;
+            jz "smp_start_done";
+            test r9, r9;
+            jz "smp_start_done";
+            mov rdi, [rsi];
+            // Stacks grow down, so each CPU starts at the end of its stack.
+            add rdx, {self.stack_size};
+            mov [rdi + {disp8(SMP_INFO_EXTRA_ARGUMENT_OFFSET)}], rdx;
+            // The AP starts as soon as goto_address is written, so this must
+            // come last.
+            mov [rdi + {disp8(SMP_INFO_GOTO_ADDRESS_OFFSET)}], rax;
+            add rsi, 8i8;
+            dec rcx;
+            dec r9;
+            jmp "smp_start_loop";
+            "smp_start_done":
+        );
+    }
+
+    /// Emit the trampoline that each AP starts at, which switches to the
+    /// AP's stack and calls `ap_entry`.
+    pub fn emit_trampoline(&self, asm: &mut Assembler) {
+        asm_block!(asm;
+            "smp_trampoline":
+            mov rsp, [rdi + {disp8(SMP_INFO_EXTRA_ARGUMENT_OFFSET)}];
+            xor rbp, rbp;
+            call {Label(self.ap_entry)};
+            "smp_halt":
+            hlt;
+            jmp "smp_halt";
+        );
+    }
+}
+
 impl Request {
     pub fn new(request_id: [u64; 2], revision: u64) -> Self {
         Self {
@@ -29,15 +494,325 @@ impl Request {
     }
 }
 
+/// A request added to a [`RequestsBuilder`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestHandle {
+    /// Exported label of the request's `response` pointer, which is filled in
+    /// by the bootloader, or left null if the request is not supported.
+    pub response: Label,
+}
+
+impl RequestHandle {
+    /// The response pointer as an operand, e.g. for `MOV(RAX, ...)`.
+    pub fn ptr(&self) -> Ptr {
+        self.response.ptr()
+    }
+}
+
+/// Collects Limine requests into a dedicated segment, delimited by the
+/// request start and end markers.
+///
+/// The bootloader writes the response pointers, so the segment should be
+/// mapped writable, or at least not be placed in read-only memory that the
+/// kernel relies on being unmodified.
+pub struct RequestsBuilder {
+    segment: Segment,
+}
+
+impl Default for RequestsBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RequestsBuilder {
+    pub fn new() -> Self {
+        let mut segment = Segment::new();
+        segment.align(8);
+        segment.label("limine_requests_start");
+        segment.append(&REQUESTS_START_MARKER);
+        Self { segment }
+    }
+
+    /// Declare the base protocol revision that the kernel supports.
+    ///
+    /// `label` is exported at the last word of the tag, which the bootloader
+    /// sets to zero if it supports the revision.
+    pub fn base_revision(&mut self, revision: u64, label: &str) {
+        self.segment.append(&BASE_REVISION_MAGIC);
+        self.segment.export_label(label);
+        self.segment.append(&revision);
+    }
+
+    /// Add a request. `request` must begin with a [`Request`], such as
+    /// `Request` itself or [`SmpRequest`].
+    pub fn add<T: Pod>(&mut self, response: &str, request: &T) -> RequestHandle {
+        assert!(
+            size_of::<T>() >= size_of::<Request>(),
+            "request is smaller than the common request header"
+        );
+        self.segment.export_offset_label(RESPONSE_OFFSET, response);
+        self.segment.append(request);
+        RequestHandle {
+            response: Label(response),
+        }
+    }
+
+    /// Describe the response pointer of a request to debuggers, as a
+    /// pointer to a `T`, e.g. [`BootloaderInfoResponse`].
+    pub fn response_type<T: DebugType>(&mut self, handle: RequestHandle) {
+        self.segment
+            .label_type(handle.response, Type::Pointer(Box::new(T::debug_type())));
+    }
+
+    /// Add a terminal request, with a callback for terminal events.
+    pub fn add_terminal(&mut self, response: &str, revision: u64, callback: &str) -> RequestHandle {
+        let handle = self.add(response, &Request::new(TERMINAL_REQUEST, revision));
+        self.segment
+            .append_reference(callback, ReferenceFormat::Abs64);
+        handle
+    }
+
+    pub fn finish(mut self) -> Segment {
+        self.segment.append(&REQUESTS_END_MARKER);
+        self.segment.label("limine_requests_end");
+        self.segment
+    }
+}
+
+/// The descriptions of the responses, and of the structures they point to,
+/// for debuggers to cast the response pointers to.
+pub fn debug_types() -> Vec<Type> {
+    vec![
+        BootloaderInfoResponse::debug_type(),
+        TerminalResponse::debug_type(),
+        FramebufferResponse::debug_type(),
+        Framebuffer::debug_type(),
+        VideoMode::debug_type(),
+        MemmapResponse::debug_type(),
+        MemmapEntry::debug_type(),
+        HhdmResponse::debug_type(),
+        SmpResponse::debug_type(),
+        SmpInfo::debug_type(),
+        SmbiosResponse::debug_type(),
+        RsdpResponse::debug_type(),
+    ]
+}
+
+/// A CD image that boots `kernel` with the Limine binaries from `limine_dir`,
+/// by BIOS or UEFI.
+pub fn iso_image(kernel: Vec<u8>, limine_dir: &Path) -> io::Result<Vec<u8>> {
+    let mut iso = IsoBuilder::new("ALPHA");
+    iso.add_file("/boot/kernel.elf", kernel);
+    iso.add_file(
+        "/boot/limine/limine.conf",
+        b"timeout: 0\n\n/alpha\n    protocol: limine\n    path: boot():/boot/kernel.elf\n".to_vec(),
+    );
+    for file in [
+        "limine-bios.sys",
+        "limine-bios-cd.bin",
+        "limine-uefi-cd.bin",
+    ] {
+        iso.add_file(
+            &format!("/boot/limine/{}", file),
+            fs::read(limine_dir.join(file))?,
+        );
+    }
+    iso.add_bios_boot("/boot/limine/limine-bios-cd.bin");
+    iso.add_efi_boot("/boot/limine/limine-uefi-cd.bin");
+    Ok(iso.finish())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    // The offsets are derived from the struct definitions, so these check the
+    // struct definitions against the layouts given in the Limine protocol.
+
+    #[test]
+    fn requests_builder() {
+        let mut requests = RequestsBuilder::new();
+        requests.base_revision(2, "base_revision");
+        let info = requests.add("info_response", &Request::new(BOOTLOADER_INFO_REQUEST, 0));
+        requests.add("smp_response", &SmpRequest::new(0, SMP_X2APIC));
+        let segment = requests.finish();
+
+        assert_eq!(info.ptr(), Ptr("info_response"));
+        assert_eq!(segment.alignment, 8);
+        let words: Vec<u64> = segment
+            .bytes()
+            .chunks(8)
+            .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()))
+            .collect();
+        assert_eq!(words[..4], REQUESTS_START_MARKER);
+        assert_eq!(
+            words[4..7],
+            [BASE_REVISION_MAGIC[0], BASE_REVISION_MAGIC[1], 2]
+        );
+        assert_eq!(words[7..9], COMMON_MAGIC);
+        assert_eq!(words[9..11], BOOTLOADER_INFO_REQUEST);
+        assert_eq!(words[15..17], SMP_REQUEST);
+        assert_eq!(words[19], SMP_X2APIC);
+        assert_eq!(words[20..], REQUESTS_END_MARKER);
+        assert_eq!(segment.labels[&Label("base_revision")].offset, 6 * 8);
+        assert_eq!(segment.labels[&Label("info_response")].offset, 12 * 8);
+        assert_eq!(segment.labels[&Label("smp_response")].offset, 18 * 8);
+    }
+
+    #[test]
+    fn request_layout() {
+        assert_eq!(RESPONSE_OFFSET, 40);
+        assert_eq!(size_of::<Request>(), 48);
+        assert_eq!(std::mem::offset_of!(SmpRequest, flags), 48);
+    }
+
+    #[test]
+    fn response_layouts() {
+        assert_eq!(BOOTLOADER_INFO_RESPONSE_NAME_OFFSET, 8);
+        assert_eq!(BOOTLOADER_INFO_RESPONSE_VERSION_OFFSET, 16);
+        assert_eq!(TERMINAL_RESPONSE_TERMINALS_OFFSET, 16);
+        assert_eq!(TERMINAL_RESPONSE_WRITE_OFFSET, 24);
+        assert_eq!(FRAMEBUFFER_RESPONSE_FRAMEBUFFERS_OFFSET, 16);
+        assert_eq!(MEMMAP_RESPONSE_ENTRIES_OFFSET, 16);
+        assert_eq!(HHDM_OFFSET_DISPLACEMENT, 8);
+        assert_eq!(SMP_RESPONSE_BSP_LAPIC_ID_OFFSET, 12);
+        assert_eq!(SMP_RESPONSE_CPUS_OFFSET, 24);
+        assert_eq!(SMBIOS_RESPONSE_ENTRY_64_OFFSET, 16);
+    }
+
+    #[test]
+    fn structure_layouts() {
+        assert_eq!(FRAMEBUFFER_MEMORY_MODEL_OFFSET, 34);
+        assert_eq!(FRAMEBUFFER_BLUE_MASK_SHIFT_OFFSET, 40);
+        assert_eq!(FRAMEBUFFER_EDID_SIZE_OFFSET, 48);
+        assert_eq!(FRAMEBUFFER_MODES_OFFSET, 72);
+        assert_eq!(size_of::<Framebuffer>(), 80);
+        assert_eq!(size_of::<VideoMode>(), 40);
+        assert_eq!(MEMMAP_ENTRY_TYPE_OFFSET, 16);
+        assert_eq!(size_of::<MemmapEntry>(), 24);
+        assert_eq!(SMP_INFO_LAPIC_ID_OFFSET, 4);
+        assert_eq!(SMP_INFO_GOTO_ADDRESS_OFFSET, 16);
+        assert_eq!(SMP_INFO_EXTRA_ARGUMENT_OFFSET, 24);
+    }
+
+    /// Assert the size of a structure and the offset of each of its fields.
+    macro_rules! assert_layout {
+        ($ty:ty, $size:expr, { $($field:ident: $offset:expr),* $(,)? }) => {
+            assert_eq!(size_of::<$ty>(), $size, "size of {}", stringify!($ty));
+            $(
+                assert_eq!(
+                    std::mem::offset_of!($ty, $field),
+                    $offset,
+                    "offset of {}.{}",
+                    stringify!($ty),
+                    stringify!($field),
+                );
+            )*
+        };
+    }
+
+    // Transcribed from the `struct limine_*` definitions of `limine.h`, for
+    // x86-64, with every field of every structure.
     #[test]
-    fn response_offset() {
-        let request = Request::new([0, 0], 0);
-        let request_location = &request as *const _ as usize;
-        let response_location = &request.response as *const _ as usize;
-        assert_eq!(response_location - request_location, RESPONSE_OFFSET);
+    fn c_header_layouts() {
+        assert_layout!(Request, 48, {
+            common_magic: 0,
+            request_id: 16,
+            revision: 32,
+            response: 40,
+        });
+        assert_layout!(SmpRequest, 56, {
+            request: 0,
+            flags: 48,
+        });
+        assert_layout!(BootloaderInfoResponse, 24, {
+            revision: 0,
+            name: 8,
+            version: 16,
+        });
+        assert_layout!(TerminalResponse, 32, {
+            revision: 0,
+            terminal_count: 8,
+            terminals: 16,
+            write: 24,
+        });
+        assert_layout!(FramebufferResponse, 24, {
+            revision: 0,
+            framebuffer_count: 8,
+            framebuffers: 16,
+        });
+        assert_layout!(Framebuffer, 80, {
+            address: 0,
+            width: 8,
+            height: 16,
+            pitch: 24,
+            bpp: 32,
+            memory_model: 34,
+            red_mask_size: 35,
+            red_mask_shift: 36,
+            green_mask_size: 37,
+            green_mask_shift: 38,
+            blue_mask_size: 39,
+            blue_mask_shift: 40,
+            unused: 41,
+            edid_size: 48,
+            edid: 56,
+            mode_count: 64,
+            modes: 72,
+        });
+        // The C structure has no `unused` field, but the same size, since it
+        // is padded to the alignment of its `uint64_t` fields.
+        assert_layout!(VideoMode, 40, {
+            pitch: 0,
+            width: 8,
+            height: 16,
+            bpp: 24,
+            memory_model: 26,
+            red_mask_size: 27,
+            red_mask_shift: 28,
+            green_mask_size: 29,
+            green_mask_shift: 30,
+            blue_mask_size: 31,
+            blue_mask_shift: 32,
+            unused: 33,
+        });
+        assert_layout!(MemmapResponse, 24, {
+            revision: 0,
+            entry_count: 8,
+            entries: 16,
+        });
+        assert_layout!(MemmapEntry, 24, {
+            base: 0,
+            length: 8,
+            type_: 16,
+        });
+        assert_layout!(HhdmResponse, 16, {
+            revision: 0,
+            offset: 8,
+        });
+        assert_layout!(SmpResponse, 32, {
+            revision: 0,
+            flags: 8,
+            bsp_lapic_id: 12,
+            cpu_count: 16,
+            cpus: 24,
+        });
+        assert_layout!(SmpInfo, 32, {
+            processor_id: 0,
+            lapic_id: 4,
+            reserved: 8,
+            goto_address: 16,
+            extra_argument: 24,
+        });
+        assert_layout!(SmbiosResponse, 24, {
+            revision: 0,
+            entry_32: 8,
+            entry_64: 16,
+        });
+        assert_layout!(RsdpResponse, 16, {
+            revision: 0,
+            address: 8,
+        });
     }
 }
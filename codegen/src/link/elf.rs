@@ -0,0 +1,2735 @@
+//! The ELF linker, which lays segments out in an image and resolves the
+//! references between them, and the linked image, with its writers.
+
+use super::{Label, LabelDefinition, ReferenceFormat, Segment, Visibility};
+use crate::{
+    dwarf::{self, Type},
+    elf64::{
+        common::{Addr, Half, Uchar, Word, Xword},
+        dynamic::{
+            Dyn, DF_1_PIE, DF_TEXTREL, DT_FLAGS, DT_FLAGS_1, DT_NULL, DT_RELA, DT_RELACOUNT,
+            DT_RELAENT, DT_RELASZ, DYN_SIZE,
+        },
+        file_header::{
+            FileHeader, EI_ABIVERSION, EI_OSABI, ELFOSABI_STANDALONE, EM_X86_64, ET_DYN, ET_EXEC,
+            FILE_HEADER_SIZE,
+        },
+        program::{
+            Phdr, PF_R, PF_W, PF_X, PROGRAM_HEADER_SIZE, PT_DYNAMIC, PT_GNU_RELRO, PT_LOAD, PT_PHDR,
+        },
+        reloc::{r_info, Rela, RELA_SIZE, R_X86_64_RELATIVE},
+        section_header::{
+            SectionHeader, SHF_ALLOC, SHF_EXECINSTR, SHF_WRITE, SHT_NOBITS, SHT_PROGBITS,
+            SHT_STRTAB, SHT_SYMTAB,
+        },
+        string_table::StringTableBuilder,
+        symbol::{Symbol, STB_GLOBAL, STB_LOCAL, STT_FUNC, STT_NOTYPE, STT_OBJECT, SYMBOL_SIZE},
+    },
+    math::align_up,
+    multiboot2,
+};
+use bytemuck::Zeroable;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    hash::Hash,
+    io::{self, Read, Seek, SeekFrom, Write},
+    panic::Location,
+    path::Path,
+    sync::Arc,
+};
+
+pub struct ElfLinker {
+    file_type: Half,
+    machine: Half,
+    os_abi: Uchar,
+    abi_version: Uchar,
+    flags: Word,
+    base_address: Addr,
+    physical_base: Option<Addr>,
+    entry: Label,
+    page_size: Xword,
+    fill: u8,
+    position_independent: bool,
+    load_program_headers: bool,
+    allow_local_shadowing: bool,
+    segment_headers: Vec<Phdr>,
+    segments: Vec<Segment>,
+    options: Vec<SegmentOptions>,
+    assertions: Vec<LinkAssertion>,
+    debug_types: Vec<Type>,
+    multiboot2_header: Option<Segment>,
+}
+
+/// A condition on the final layout, checked in `finish()`.
+enum LinkAssertion {
+    Below(Label, Addr),
+    Aligned(Label, Xword),
+    SizeLe(Label, Label, Xword),
+}
+
+#[derive(Default)]
+struct SegmentOptions {
+    name: String,
+    fixed_address: Option<Addr>,
+    relro: bool,
+}
+
+/// Identifies a segment added to an [`ElfLinker`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SegmentId(usize);
+
+impl ElfLinker {
+    /// Create a linker for a standalone x86_64 executable.
+    pub fn new() -> Self {
+        Self {
+            file_type: ET_EXEC,
+            machine: EM_X86_64,
+            os_abi: ELFOSABI_STANDALONE,
+            abi_version: 0,
+            flags: 0,
+            base_address: 0xffffffff_80000000,
+            physical_base: None,
+            entry: Label("entry"),
+            page_size: 1 << 12,
+            fill: 0,
+            position_independent: false,
+            load_program_headers: false,
+            allow_local_shadowing: false,
+            segment_headers: Vec::new(),
+            segments: Vec::new(),
+            options: Vec::new(),
+            assertions: Vec::new(),
+            debug_types: Vec::new(),
+            multiboot2_header: None,
+        }
+    }
+
+    /// Set the object file type (`e_type`), e.g. `ET_EXEC` or `ET_DYN`.
+    pub fn set_file_type(&mut self, file_type: Half) {
+        self.file_type = file_type;
+    }
+
+    /// Set the target machine type (`e_machine`).
+    pub fn set_machine(&mut self, machine: Half) {
+        self.machine = machine;
+    }
+
+    /// Set the OS/ABI identification byte (`e_ident[EI_OSABI]`).
+    pub fn set_os_abi(&mut self, os_abi: Uchar) {
+        self.os_abi = os_abi;
+    }
+
+    /// Set the ABI version byte (`e_ident[EI_ABIVERSION]`).
+    pub fn set_abi_version(&mut self, abi_version: Uchar) {
+        self.abi_version = abi_version;
+    }
+
+    /// Set the processor-specific flags (`e_flags`).
+    pub fn set_flags(&mut self, flags: Word) {
+        self.flags = flags;
+    }
+
+    /// Set the virtual address at which the first segment is placed.
+    ///
+    /// Defaults to the start of the top 2GiB of the address space.
+    pub fn set_base_address(&mut self, base_address: Addr) {
+        self.base_address = base_address;
+    }
+
+    /// Set the physical address that the base address is loaded at
+    /// (`p_paddr`).
+    ///
+    /// By default, segments are loaded at physical addresses equal to their
+    /// virtual addresses. Segments placed at a fixed address are always
+    /// loaded at that physical address.
+    pub fn set_physical_base(&mut self, physical_base: Addr) {
+        self.physical_base = Some(physical_base);
+    }
+
+    /// Set the exported label that execution starts at (`e_entry`).
+    ///
+    /// Defaults to `entry`.
+    pub fn set_entry(&mut self, label: impl Into<Label>) {
+        self.entry = label.into();
+    }
+
+    fn physical_address(&self, vaddr: Addr) -> Addr {
+        physical_address(self.base_address, self.physical_base, vaddr)
+    }
+
+    /// Set the page size of the target.
+    ///
+    /// Each segment starts on a new page, so that it can be mapped with its
+    /// own permissions, and file offsets are kept congruent to virtual
+    /// addresses modulo the page size. Defaults to 4KiB.
+    pub fn set_page_size(&mut self, page_size: Xword) {
+        assert!(page_size.is_power_of_two());
+        self.page_size = page_size;
+    }
+
+    /// Set the byte used to fill the file between segments.
+    ///
+    /// Defaults to zero.
+    pub fn set_fill(&mut self, fill: u8) {
+        self.fill = fill;
+    }
+
+    /// Produce a position-independent executable (`ET_DYN`).
+    ///
+    /// The image is linked at base address zero (unless overridden afterwards
+    /// with [`set_base_address`](Self::set_base_address)), and every absolute
+    /// reference gets an `R_X86_64_RELATIVE` entry in a `PT_DYNAMIC` segment,
+    /// so that the loader is free to place the image anywhere. If any of
+    /// them is in a segment that isn't writable, the image is flagged with
+    /// `DF_TEXTREL`, so that the loader knows it has to patch that segment.
+    pub fn position_independent(&mut self) {
+        self.file_type = ET_DYN;
+        self.base_address = 0;
+        self.position_independent = true;
+    }
+
+    /// Map the file and program headers into memory with their own
+    /// `PT_LOAD` segment at the base address, and describe the program header
+    /// table with a `PT_PHDR` entry.
+    ///
+    /// Some loaders and analysis tools require the program header table to
+    /// be part of the memory image.
+    pub fn load_program_headers(&mut self) {
+        self.load_program_headers = true;
+    }
+
+    /// Embed a Multiboot2 header (see [`multiboot2::HeaderBuilder`]) in the
+    /// image.
+    ///
+    /// The header is placed in its own read-only segment before all other
+    /// segments, so that it lies within the first 32KiB of the file as the
+    /// specification requires. Bootloaders load segments at their physical
+    /// addresses, so a higher-half image also needs
+    /// [`set_physical_base`](Self::set_physical_base).
+    ///
+    /// [`multiboot2::HeaderBuilder`]: crate::multiboot2::HeaderBuilder
+    pub fn set_multiboot2_header(&mut self, header: Segment) {
+        self.multiboot2_header = Some(header);
+    }
+
+    /// Allow local labels to shadow labels exported by other segments.
+    ///
+    /// References from within the segment defining the local label always
+    /// resolve to the local label. By default, this situation is rejected in
+    /// `finish()`, since it is usually a mistake.
+    pub fn allow_local_shadowing(&mut self) {
+        self.allow_local_shadowing = true;
+    }
+
+    /// Add a segment to the image, loaded with the given flags and
+    /// alignment. Segments are laid out in the order they are added.
+    ///
+    /// Segment names must be unique, and are used to refer to the segment
+    /// after linking.
+    pub fn add_segment(
+        &mut self,
+        name: &str,
+        flags: Word,
+        align: Xword,
+        segment: Segment,
+    ) -> SegmentId {
+        assert!(
+            self.segment_id(name).is_none(),
+            "duplicate segment name {:?}",
+            name
+        );
+
+        let program_header = Phdr {
+            p_type: PT_LOAD,
+            p_flags: flags,
+            p_offset: 0, // Resolved in `finish()`
+            p_vaddr: 0,  // Resolved in `finish()`
+            p_paddr: 0,  //TODO
+            p_filesz: segment.data.len() as u64,
+            p_memsz: segment.len() as u64,
+            p_align: align,
+        };
+
+        self.segment_headers.push(program_header);
+        self.segments.push(segment);
+        self.options.push(SegmentOptions {
+            name: name.to_owned(),
+            ..Default::default()
+        });
+        SegmentId(self.segments.len() - 1)
+    }
+
+    /// Look up a segment by name.
+    pub fn segment_id(&self, name: &str) -> Option<SegmentId> {
+        self.options
+            .iter()
+            .position(|options| options.name == name)
+            .map(SegmentId)
+    }
+
+    /// Define exported labels at the start and end of a segment.
+    ///
+    /// Useful for code that needs to know the extent of a whole segment at
+    /// runtime, e.g. to map itself.
+    pub fn export_segment_bounds(
+        &mut self,
+        segment: SegmentId,
+        start: impl Into<Label>,
+        end: impl Into<Label>,
+    ) {
+        let segment = &mut self.segments[segment.0];
+        segment.define_label_at(0, start.into(), Visibility::Exported);
+        segment.define_label_at(segment.len(), end.into(), Visibility::Exported);
+    }
+
+    /// Place a segment at a fixed virtual address, instead of after the
+    /// previous segment.
+    ///
+    /// Segments following it are placed after it, unless it is placed below
+    /// the previous segment; this allows a low-memory segment (e.g. an AP
+    /// startup trampoline) to be placed without disturbing the layout of the
+    /// rest of the image. Overlapping segments are rejected in `finish()`.
+    pub fn set_segment_address(&mut self, segment: SegmentId, vaddr: Addr) {
+        let alignment = self.segments[segment.0].alignment as u64;
+        assert!(
+            vaddr.is_multiple_of(alignment),
+            "segment address {vaddr:#x} is not aligned to {alignment:#x}"
+        );
+        self.options[segment.0].fixed_address = Some(vaddr);
+    }
+
+    /// Mark a segment as read-only after relocation.
+    ///
+    /// The segment is still loaded with the flags it was added with (which
+    /// should include `PF_W`), so that it can be written during early boot,
+    /// but it is also covered by a `PT_GNU_RELRO` header telling the
+    /// program to remap it read-only once initialization is done.
+    pub fn set_relro(&mut self, segment: SegmentId) {
+        self.options[segment.0].relro = true;
+    }
+
+    /// Assert that an exported label is placed below `addr`.
+    pub fn assert_below(&mut self, label: impl Into<Label>, addr: Addr) {
+        self.assertions
+            .push(LinkAssertion::Below(label.into(), addr));
+    }
+
+    /// Assert that an exported label is aligned to `alignment` bytes.
+    pub fn assert_aligned(&mut self, label: impl Into<Label>, alignment: Xword) {
+        assert!(alignment.is_power_of_two());
+        self.assertions
+            .push(LinkAssertion::Aligned(label.into(), alignment));
+    }
+
+    /// Assert that the distance between two exported labels is at most
+    /// `size` bytes (and that `end` is not before `start`).
+    pub fn assert_size_le(&mut self, start: impl Into<Label>, end: impl Into<Label>, size: Xword) {
+        self.assertions
+            .push(LinkAssertion::SizeLe(start.into(), end.into(), size));
+    }
+
+    /// Describe `ty` to debuggers, even if no label has that type, e.g. for
+    /// structures that the bootloader or hardware places in memory.
+    pub fn add_debug_type(&mut self, ty: Type) {
+        self.debug_types.push(ty);
+    }
+
+    /// Check that every referenced label, and the entry point, is defined,
+    /// which [`finish`](Self::finish) panics on otherwise. For tools that
+    /// link user input, to report its mistakes as errors.
+    pub fn check_labels(&self) -> io::Result<()> {
+        let exported: BTreeSet<Label> = self
+            .segments
+            .iter()
+            .flat_map(|segment| &segment.labels)
+            .filter(|(_, definition)| definition.visibility == Visibility::Exported)
+            .map(|(&label, _)| label)
+            .collect();
+        let mut undefined = Vec::new();
+        if !exported.contains(&self.entry) {
+            undefined.push(format!(
+                "entry label `{}` is not defined or not exported",
+                self.entry.name()
+            ));
+        }
+        for (segment, options) in self.segments.iter().zip(&self.options) {
+            for label in segment.references.keys() {
+                if !segment.labels.contains_key(label) && !exported.contains(label) {
+                    undefined.push(format!(
+                        "undefined label `{}`, referenced from {}",
+                        label.name(),
+                        options.name
+                    ));
+                }
+            }
+        }
+        if undefined.is_empty() {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                undefined.join("\n"),
+            ))
+        }
+    }
+
+    /// Link all segments in memory, resolving references between them.
+    pub fn finish(mut self) -> Linked {
+        let layout = self.layout();
+
+        let placed = self
+            .segment_headers
+            .iter()
+            .zip(&mut self.segments)
+            .map(|(header, segment)| (header.p_vaddr, header.p_paddr, segment))
+            .collect();
+        let relocations = resolve_all(placed, &layout.exports, &self.segment_headers);
+        if self.position_independent {
+            let dynamic = self.segments.last_mut().unwrap();
+            write_relocations(dynamic, &relocations);
+        }
+
+        Linked {
+            file_header: layout.file_header,
+            program_headers: layout.program_headers,
+            fill: self.fill,
+            exports: layout.exports,
+            debug_types: self.debug_types,
+            segments: self
+                .segment_headers
+                .into_iter()
+                .zip(self.segments)
+                .zip(self.options)
+                .map(|((header, segment), options)| LinkedSegment {
+                    name: options.name,
+                    header,
+                    data: segment.data,
+                    labels: segment.labels,
+                    locations: segment.locations,
+                    comments: segment.comments,
+                    types: segment.types,
+                })
+                .collect(),
+        }
+    }
+
+    /// Link all segments and write the resulting file directly to `writer`.
+    ///
+    /// Unlike [`finish`](Self::finish), this never builds a copy of the whole
+    /// file in memory. Segment data is written (and then dropped) one segment
+    /// at a time, and the file and program headers are backpatched at the
+    /// start of the file afterwards.
+    pub fn finish_to<W: Write + Seek>(mut self, writer: &mut W) -> io::Result<()> {
+        let layout = self.layout();
+        let start = writer.stream_position()?;
+
+        // Headers are written last, but the padding following them must
+        // still be filled.
+        let mut position = layout.file_header.e_phoff
+            + layout.file_header.e_phnum as u64 * PROGRAM_HEADER_SIZE as u64;
+        writer.seek(SeekFrom::Start(start + position))?;
+
+        let segment_count = self.segments.len();
+        let mut relocations = Vec::new();
+        for (i, (header, mut segment)) in self.segment_headers.iter().zip(self.segments).enumerate()
+        {
+            if self.position_independent && i == segment_count - 1 {
+                write_relocations(&mut segment, &relocations);
+            } else {
+                resolve_references(
+                    header.p_vaddr,
+                    header.p_paddr,
+                    &mut segment,
+                    &layout.exports,
+                    &self.segment_headers,
+                    &mut relocations,
+                );
+            }
+            let padding = header.p_offset - position;
+            io::copy(&mut io::repeat(self.fill).take(padding), writer)?;
+            writer.write_all(&segment.data)?;
+            position = header.p_offset + segment.data.len() as u64;
+        }
+
+        writer.seek(SeekFrom::Start(start))?;
+        writer.write_all(bytemuck::bytes_of(&layout.file_header))?;
+        for header in &layout.program_headers {
+            writer.write_all(bytemuck::bytes_of(header))?;
+        }
+
+        writer.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+
+    /// Assign file offsets and virtual addresses to every segment, and
+    /// resolve all exported labels to their absolute virtual addresses.
+    fn layout(&mut self) -> Layout {
+        if let Some(mut header) = self.multiboot2_header.take() {
+            header.align(multiboot2::HEADER_ALIGN);
+            self.add_segment(MULTIBOOT2_SEGMENT, PF_R, self.page_size, header);
+            self.segment_headers.rotate_right(1);
+            self.segments.rotate_right(1);
+            self.options.rotate_right(1);
+        }
+        if self.position_independent {
+            self.add_dynamic_segment();
+        }
+
+        // PT_PHDR and the PT_LOAD covering the headers come before all other
+        // headers, and PT_DYNAMIC and PT_GNU_RELRO are added after all of the
+        // PT_LOAD headers.
+        let leading_header_count = 2 * self.load_program_headers as usize;
+        let relro_count = self.options.iter().filter(|options| options.relro).count();
+        let program_header_count = leading_header_count
+            + self.segment_headers.len()
+            + self.position_independent as usize
+            + relro_count;
+
+        let program_header_offset = FILE_HEADER_SIZE as u64;
+        let program_header_end =
+            program_header_offset + program_header_count as u64 * PROGRAM_HEADER_SIZE as u64;
+
+        let mut current_file_offset = program_header_end;
+        let mut current_vaddr = if self.load_program_headers {
+            // The headers are mapped at the base address, so the first
+            // segment follows them.
+            self.base_address + program_header_end
+        } else {
+            self.base_address
+        };
+
+        for ((header, segment), options) in self
+            .segment_headers
+            .iter_mut()
+            .zip(&self.segments)
+            .zip(&self.options)
+        {
+            // 1. Resolve file offsets and virtual addresses for this segment
+            let modulus = self.page_size.max(header.p_align);
+            current_file_offset = align_up(current_file_offset, segment.alignment as u64);
+
+            let vaddr = match options.fixed_address {
+                // The file offset is advanced to be congruent to the fixed
+                // address.
+                Some(vaddr) => {
+                    current_file_offset += vaddr.wrapping_sub(current_file_offset) % modulus;
+                    vaddr
+                }
+                // The file offset only needs to satisfy the segment's own
+                // alignment; the virtual address is then chosen on a new page
+                // (or the current one, if nothing has been placed on it yet),
+                // congruent to the file offset.
+                None => {
+                    align_up(align_up(current_vaddr, self.page_size), modulus)
+                        + current_file_offset % modulus
+                }
+            };
+
+            header.p_offset = current_file_offset;
+            header.p_vaddr = vaddr;
+            header.p_paddr = match options.fixed_address {
+                Some(_) => vaddr,
+                None => physical_address(self.base_address, self.physical_base, vaddr),
+            };
+
+            current_file_offset += segment.data.len() as u64;
+            if vaddr >= current_vaddr {
+                current_vaddr = vaddr + segment.len() as u64;
+            }
+        }
+
+        // 2. Resolve exported labels to their absolute virtual addresses.
+        let placed: Vec<_> = self
+            .options
+            .iter()
+            .zip(&self.segment_headers)
+            .zip(&self.segments)
+            .map(|((options, header), segment)| (options.name.as_str(), header.p_vaddr, segment))
+            .collect();
+        let exports = collect_exports(&placed, self.allow_local_shadowing);
+
+        let mut program_headers = Vec::with_capacity(program_header_count);
+        if self.load_program_headers {
+            program_headers.push(Phdr {
+                p_type: PT_PHDR,
+                p_flags: PF_R,
+                p_offset: program_header_offset,
+                p_vaddr: self.base_address + program_header_offset,
+                p_paddr: self.physical_address(self.base_address + program_header_offset),
+                p_filesz: program_header_end - program_header_offset,
+                p_memsz: program_header_end - program_header_offset,
+                p_align: 8,
+            });
+            program_headers.push(Phdr {
+                p_type: PT_LOAD,
+                p_flags: PF_R,
+                p_offset: 0,
+                p_vaddr: self.base_address,
+                p_paddr: self.physical_address(self.base_address),
+                p_filesz: program_header_end,
+                p_memsz: program_header_end,
+                p_align: self.page_size,
+            });
+        }
+        program_headers.extend(&self.segment_headers);
+        if self.position_independent {
+            let text_relocations = self.text_relocations();
+            let header = self.segment_headers.last().unwrap();
+            let dynamic = self.segments.last_mut().unwrap();
+            let table_offset = dynamic.labels[&Label(DYNAMIC_LABEL)].offset;
+            let rela_size = (table_offset - dynamic.labels[&Label(RELA_LABEL)].offset) as u64;
+
+            let mut table = vec![
+                (DT_RELA, header.p_vaddr),
+                (DT_RELASZ, rela_size),
+                (DT_RELAENT, RELA_SIZE as u64),
+                (DT_RELACOUNT, rela_size / RELA_SIZE as u64),
+                (DT_FLAGS_1, DF_1_PIE),
+            ];
+            if text_relocations {
+                table.push((DT_FLAGS, DF_TEXTREL));
+            }
+            table.push((DT_NULL, 0));
+            for (i, &(d_tag, d_val)) in table.iter().enumerate() {
+                let entry = Dyn { d_tag, d_val };
+                dynamic.data[table_offset + i * DYN_SIZE as usize..][..DYN_SIZE as usize]
+                    .copy_from_slice(bytemuck::bytes_of(&entry));
+            }
+
+            let table_size = (table.len() * DYN_SIZE as usize) as u64;
+            program_headers.push(Phdr {
+                p_type: PT_DYNAMIC,
+                p_flags: header.p_flags,
+                p_offset: header.p_offset + table_offset as u64,
+                p_vaddr: header.p_vaddr + table_offset as u64,
+                p_paddr: header.p_paddr + table_offset as u64,
+                p_filesz: table_size,
+                p_memsz: table_size,
+                p_align: 8,
+            });
+        }
+
+        for (header, options) in self.segment_headers.iter().zip(&self.options) {
+            if !options.relro {
+                continue;
+            }
+            // Round up to the end of the page, since the following segment
+            // always starts on a new page.
+            let size = align_up(header.p_vaddr + header.p_memsz, self.page_size) - header.p_vaddr;
+            program_headers.push(Phdr {
+                p_type: PT_GNU_RELRO,
+                p_flags: PF_R,
+                p_memsz: size,
+                p_align: 1,
+                ..*header
+            });
+        }
+
+        check_overlaps(&program_headers);
+        if self.options[0].name == MULTIBOOT2_SEGMENT {
+            let header = &self.segment_headers[0];
+            assert!(
+                header.p_offset + header.p_filesz <= multiboot2::SEARCH,
+                "multiboot2 header does not fit in the first {} bytes",
+                multiboot2::SEARCH
+            );
+        }
+        for assertion in &self.assertions {
+            check_assertion(assertion, &exports);
+        }
+
+        let mut file_header = FileHeader::new();
+        file_header.e_ident[EI_OSABI] = self.os_abi;
+        file_header.e_ident[EI_ABIVERSION] = self.abi_version;
+        file_header.e_type = self.file_type;
+        file_header.e_machine = self.machine;
+        file_header.e_flags = self.flags;
+        file_header.e_entry = *exports.get(&self.entry).unwrap_or_else(|| {
+            panic!(
+                "entry label {:?} is not defined or not exported",
+                self.entry
+            )
+        });
+        file_header.e_phnum = program_headers
+            .len()
+            .try_into()
+            .expect("segment table overflow");
+        file_header.e_phoff = program_header_offset;
+
+        Layout {
+            file_header,
+            program_headers,
+            exports,
+        }
+    }
+
+    /// Append a segment holding the relocation table and dynamic array for a
+    /// position-independent executable.
+    ///
+    /// Its contents are filled in after layout and reference resolution.
+    fn add_dynamic_segment(&mut self) {
+        let references = || {
+            self.segments
+                .iter()
+                .flat_map(|segment| segment.references.values().flatten())
+        };
+        assert!(
+            !references().any(|reference| matches!(
+                reference.format,
+                ReferenceFormat::Abs32
+                    | ReferenceFormat::Abs16
+                    | ReferenceFormat::GateOffset
+                    | ReferenceFormat::Phys64
+                    | ReferenceFormat::Phys32
+            )),
+            "absolute 16-bit, 32-bit, gate and physical references are not supported in \
+             position-independent executables"
+        );
+        let relocation_count: usize = references()
+            .filter(|reference| reference.format == ReferenceFormat::Abs64)
+            .count();
+
+        let dynamic_count = 6 + self.text_relocations() as usize;
+        let mut dynamic = Segment::with_capacity(
+            (relocation_count * RELA_SIZE as usize) + dynamic_count * DYN_SIZE as usize,
+        );
+        dynamic.align(8);
+        dynamic.label(RELA_LABEL);
+        dynamic.extend(std::iter::repeat_n(
+            0u8,
+            relocation_count * RELA_SIZE as usize,
+        ));
+        dynamic.label(DYNAMIC_LABEL);
+        dynamic.extend(std::iter::repeat_n(0u8, dynamic_count * DYN_SIZE as usize));
+
+        self.add_segment("dynamic", PF_R | PF_W, 1 << 12, dynamic);
+    }
+
+    /// Whether a relocation patches a segment that isn't writable, which
+    /// the loader must then make writable while it relocates the image.
+    fn text_relocations(&self) -> bool {
+        self.segment_headers
+            .iter()
+            .zip(&self.segments)
+            .filter(|(header, _)| header.p_flags & PF_W == 0)
+            .flat_map(|(_, segment)| segment.references.values().flatten())
+            .any(|reference| reference.format == ReferenceFormat::Abs64)
+    }
+}
+
+const MULTIBOOT2_SEGMENT: &str = "multiboot2";
+const RELA_LABEL: &str = "rela";
+const DYNAMIC_LABEL: &str = "dynamic";
+
+struct Layout {
+    file_header: FileHeader,
+    program_headers: Vec<Phdr>,
+    exports: BTreeMap<Label, u64>,
+}
+
+/// Resolve the exported labels of placed segments, given as
+/// `(name, vaddr, segment)`, to their absolute virtual addresses.
+///
+/// Panics on duplicate exported labels, and on local labels shadowing
+/// exported labels unless `allow_local_shadowing` is set.
+pub(crate) fn collect_exports(
+    segments: &[(&str, Addr, &Segment)],
+    allow_local_shadowing: bool,
+) -> BTreeMap<Label, Addr> {
+    let mut exports = BTreeMap::new();
+    // The segment index and offset of each exported label, for diagnostics.
+    let mut export_sources: BTreeMap<Label, (usize, usize)> = BTreeMap::new();
+
+    for (index, &(name, vaddr, segment)) in segments.iter().enumerate() {
+        for (&label, definition) in &segment.labels {
+            if definition.visibility != Visibility::Exported {
+                continue;
+            }
+            exports.insert(label, vaddr + definition.offset as u64);
+            if let Some((previous_index, previous_offset)) =
+                export_sources.insert(label, (index, definition.offset))
+            {
+                panic!(
+                    "duplicate label definition across segments: {:?} in {:?} at offset {:#x} \
+                     and in {:?} at offset {:#x}",
+                    label.name(),
+                    segments[previous_index].0,
+                    previous_offset,
+                    name,
+                    definition.offset,
+                );
+            }
+        }
+    }
+
+    if !allow_local_shadowing {
+        for &(name, _, segment) in segments {
+            for (label, definition) in &segment.labels {
+                if definition.visibility != Visibility::Local {
+                    continue;
+                }
+                if let Some(&(export_index, export_offset)) = export_sources.get(label) {
+                    panic!(
+                        "local label {:?} in {:?} at offset {:#x} shadows label exported by {:?} \
+                         at offset {:#x}",
+                        label.name(),
+                        name,
+                        definition.offset,
+                        segments[export_index].0,
+                        export_offset,
+                    );
+                }
+            }
+        }
+    }
+
+    exports
+}
+
+fn physical_address(base_address: Addr, physical_base: Option<Addr>, vaddr: Addr) -> Addr {
+    match physical_base {
+        Some(physical_base) => vaddr.wrapping_sub(base_address) + physical_base,
+        None => vaddr,
+    }
+}
+
+/// Ensure that no two loaded segments occupy the same virtual addresses.
+fn check_overlaps(program_headers: &[Phdr]) {
+    let mut loaded: Vec<&Phdr> = program_headers
+        .iter()
+        .filter(|header| header.p_type == PT_LOAD)
+        .collect();
+    loaded.sort_by_key(|header| header.p_vaddr);
+    for pair in loaded.windows(2) {
+        let end = pair[0].p_vaddr + pair[0].p_memsz;
+        assert!(
+            end <= pair[1].p_vaddr,
+            "segment at {:#x}..{:#x} overlaps segment at {:#x}",
+            pair[0].p_vaddr,
+            end,
+            pair[1].p_vaddr,
+        );
+    }
+}
+
+fn check_assertion(assertion: &LinkAssertion, exports: &BTreeMap<Label, Addr>) {
+    let address = |label: &Label| {
+        *exports
+            .get(label)
+            .unwrap_or_else(|| panic!("link assertion on undefined label {:?}", label.name()))
+    };
+    match assertion {
+        LinkAssertion::Below(label, limit) => {
+            let location = address(label);
+            assert!(
+                location < *limit,
+                "link assertion failed: {:?} at {:#x} is not below {:#x}",
+                label.name(),
+                location,
+                limit,
+            );
+        }
+        LinkAssertion::Aligned(label, alignment) => {
+            let location = address(label);
+            assert!(
+                location % alignment == 0,
+                "link assertion failed: {:?} at {:#x} is not aligned to {:#x}",
+                label.name(),
+                location,
+                alignment,
+            );
+        }
+        LinkAssertion::SizeLe(start, end, size) => {
+            let (start_location, end_location) = (address(start), address(end));
+            assert!(
+                start_location <= end_location && end_location - start_location <= *size,
+                "link assertion failed: {:?}..{:?} ({:#x}..{:#x}) is larger than {:#x} bytes",
+                start.name(),
+                end.name(),
+                start_location,
+                end_location,
+                size,
+            );
+        }
+    }
+}
+
+/// An absolute address that needs to be adjusted by the load base.
+pub(crate) struct Relocation {
+    /// Virtual address of the 64-bit field to adjust.
+    pub location: Addr,
+    /// Link-time value of the field.
+    pub value: Addr,
+}
+
+/// Patch every reference in `segment` with the address of its target label.
+///
+/// Labels defined in the same segment take precedence over labels exported
+/// by other segments. Absolute references are also recorded in
+/// `relocations`. The segment is loaded at the physical address `paddr`, and
+/// the other segments as given by `headers`, for physical references.
+pub(crate) fn resolve_references(
+    vaddr: Addr,
+    paddr: Addr,
+    segment: &mut Segment,
+    exports: &BTreeMap<Label, u64>,
+    headers: &[Phdr],
+    relocations: &mut Vec<Relocation>,
+) {
+    for (label, references) in &segment.references {
+        let label_location = match segment.labels.get(label) {
+            Some(definition) => vaddr + definition.offset as u64,
+            None => *exports
+                .get(label)
+                .unwrap_or_else(|| panic!("undefined label {:?}", label)),
+        };
+
+        for reference in references {
+            let format = reference.format;
+            let location = vaddr + reference.location as u64;
+            let target = match format {
+                ReferenceFormat::Phys64 => {
+                    let definition = segment.labels.get(label).unwrap_or_else(|| {
+                        panic!("physical reference to {label:?}, which is not in the same segment")
+                    });
+                    paddr + definition.offset as u64
+                }
+                ReferenceFormat::Phys32 => match segment.labels.get(label) {
+                    Some(definition) => paddr + definition.offset as u64,
+                    None => headers
+                        .iter()
+                        .filter(|header| header.p_type == PT_LOAD)
+                        .find(|header| {
+                            (header.p_vaddr..=header.p_vaddr + header.p_memsz)
+                                .contains(&label_location)
+                        })
+                        .map(|header| header.p_paddr + (label_location - header.p_vaddr))
+                        .unwrap_or_else(|| {
+                            panic!("physical reference to {label:?}, which is not loaded")
+                        }),
+                },
+                _ => label_location,
+            };
+            let value = format.resolve(location, target).unwrap_or_else(|| {
+                panic!("{format:?} reference to {label:?} at {target:#x} from {location:#x} is out of range")
+            });
+            format.patch(
+                &mut segment.data[reference.location..][..format.len()],
+                value,
+            );
+            if format == ReferenceFormat::Abs64 {
+                relocations.push(Relocation {
+                    location,
+                    value: label_location,
+                });
+            }
+        }
+    }
+}
+
+/// Resolve the references of each segment, given as `(vaddr, paddr,
+/// segment)`, like [`resolve_references`], and return the relocations of
+/// all of them in order. `headers` are the program headers of the segments.
+///
+/// Segments are resolved independently of each other, in parallel with the
+/// `parallel` feature.
+pub(crate) fn resolve_all(
+    segments: Vec<(Addr, Addr, &mut Segment)>,
+    exports: &BTreeMap<Label, Addr>,
+    headers: &[Phdr],
+) -> Vec<Relocation> {
+    let resolve = |(vaddr, paddr, segment): (Addr, Addr, &mut Segment)| {
+        let mut relocations = Vec::new();
+        resolve_references(vaddr, paddr, segment, exports, headers, &mut relocations);
+        relocations
+    };
+    #[cfg(feature = "parallel")]
+    {
+        use rayon::prelude::*;
+        segments.into_par_iter().flat_map_iter(resolve).collect()
+    }
+    #[cfg(not(feature = "parallel"))]
+    {
+        segments.into_iter().flat_map(resolve).collect()
+    }
+}
+
+/// Fill the relocation table of the dynamic segment.
+fn write_relocations(dynamic: &mut Segment, relocations: &[Relocation]) {
+    let table_offset = dynamic.labels[&Label(RELA_LABEL)].offset;
+    for (i, relocation) in relocations.iter().enumerate() {
+        let entry = Rela {
+            r_offset: relocation.location,
+            r_info: r_info(0, R_X86_64_RELATIVE),
+            r_addend: relocation.value as i64,
+        };
+        dynamic.data[table_offset + i * RELA_SIZE as usize..][..RELA_SIZE as usize]
+            .copy_from_slice(bytemuck::bytes_of(&entry));
+    }
+}
+
+pub struct Linked {
+    file_header: FileHeader,
+    program_headers: Vec<Phdr>,
+    fill: u8,
+    exports: BTreeMap<Label, Addr>,
+    debug_types: Vec<Type>,
+    segments: Vec<LinkedSegment>,
+}
+
+/// A segment of a linked image, with its references resolved.
+pub struct LinkedSegment {
+    name: String,
+    header: Phdr,
+    data: Vec<u8>,
+    labels: BTreeMap<Label, LabelDefinition>,
+    locations: Vec<(usize, &'static Location<'static>)>,
+    comments: Vec<(usize, String)>,
+    types: Vec<(Label, Type)>,
+}
+
+impl LinkedSegment {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The `PT_LOAD` program header of the segment.
+    pub fn header(&self) -> &Phdr {
+        &self.header
+    }
+
+    /// The contents of the segment in the file, without reserved space.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// The labels defined in the segment, at offsets from its start.
+    pub fn labels(&self) -> &BTreeMap<Label, LabelDefinition> {
+        &self.labels
+    }
+
+    /// The comments on the segment, at offsets from its start, in order.
+    pub fn comments(&self) -> &[(usize, String)] {
+        &self.comments
+    }
+}
+
+impl Linked {
+    fn segment(&self, name: &str) -> Option<&LinkedSegment> {
+        self.segments.iter().find(|segment| segment.name == name)
+    }
+
+    /// The file header and program headers, as they are written.
+    pub fn headers(&self) -> (&FileHeader, &[Phdr]) {
+        (&self.file_header, &self.program_headers)
+    }
+
+    /// The segments, in layout order.
+    pub fn segments(&self) -> &[LinkedSegment] {
+        &self.segments
+    }
+
+    /// The virtual address of the start of the named segment.
+    pub fn segment_vaddr(&self, name: &str) -> Option<Addr> {
+        self.segment(name).map(|segment| segment.header.p_vaddr)
+    }
+
+    /// The size in memory of the named segment.
+    pub fn segment_size(&self, name: &str) -> Option<Xword> {
+        self.segment(name).map(|segment| segment.header.p_memsz)
+    }
+
+    /// The virtual address of an exported label.
+    pub fn label_address(&self, label: &str) -> Option<Addr> {
+        self.exports.get(&Label(label)).copied()
+    }
+
+    /// The name of the segment that defines the given label.
+    ///
+    /// Exported labels are searched first, then the local labels of each
+    /// segment in layout order.
+    pub fn label_segment(&self, label: &str) -> Option<&str> {
+        let defines = |segment: &&LinkedSegment, visibility| {
+            segment
+                .labels
+                .get(&Label(label))
+                .is_some_and(|definition| definition.visibility == visibility)
+        };
+        self.segments
+            .iter()
+            .find(|segment| defines(segment, Visibility::Exported))
+            .or_else(|| {
+                self.segments
+                    .iter()
+                    .find(|segment| defines(segment, Visibility::Local))
+            })
+            .map(|segment| segment.name.as_str())
+    }
+
+    /// The virtual address of a label, exported or local, searched for like
+    /// [`label_segment`](Self::label_segment).
+    pub fn any_label_address(&self, label: &str) -> Option<Addr> {
+        let segment = self.segment(self.label_segment(label)?)?;
+        Some(segment.header.p_vaddr + segment.labels[&Label(label)].offset as u64)
+    }
+
+    /// The physical address of the entry point.
+    fn physical_entry(&self) -> Addr {
+        let entry = self.file_header.e_entry;
+        self.segments
+            .iter()
+            .map(|segment| &segment.header)
+            .find(|header| (header.p_vaddr..header.p_vaddr + header.p_memsz).contains(&entry))
+            .map(|header| header.p_paddr + (entry - header.p_vaddr))
+            .unwrap_or(entry)
+    }
+
+    /// Write the contents of each segment as Intel HEX records, at their
+    /// physical addresses, followed by the physical entry point.
+    ///
+    /// Fails if any segment or the entry point lies above 4GiB.
+    pub fn write_ihex<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let entry = self.check_32_bit("Intel HEX")?;
+        let mut upper = None;
+        for segment in &self.segments {
+            let start = segment.header.p_paddr;
+            let mut offset = 0;
+            while offset < segment.data.len() {
+                let address = (start + offset as u64) as u32;
+                if upper != Some(address >> 16) {
+                    upper = Some(address >> 16);
+                    ihex_record(writer, 0x04, 0, &((address >> 16) as u16).to_be_bytes())?;
+                }
+                // Data records may not cross a 64KiB boundary.
+                let len = (segment.data.len() - offset)
+                    .min(16)
+                    .min(0x10000 - (address & 0xffff) as usize);
+                ihex_record(writer, 0x00, address as u16, &segment.data[offset..][..len])?;
+                offset += len;
+            }
+        }
+
+        ihex_record(writer, 0x05, 0, &entry.to_be_bytes())?;
+        ihex_record(writer, 0x01, 0, &[])
+    }
+
+    /// Write the contents of each segment as Motorola S-records (S3), at
+    /// their physical addresses, followed by the physical entry point (S7).
+    ///
+    /// Fails if any segment or the entry point lies above 4GiB.
+    pub fn write_srec<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let entry = self.check_32_bit("S-record")?;
+        let mut count = 0u32;
+        for segment in &self.segments {
+            let start = segment.header.p_paddr;
+            for (i, chunk) in segment.data.chunks(16).enumerate() {
+                let address = start as u32 + 16 * i as u32;
+                srec_record(writer, 3, address, chunk)?;
+                count += 1;
+            }
+        }
+        if count <= 0xffff {
+            srec_record(writer, 5, count, &[])?;
+        } else {
+            srec_record(writer, 6, count, &[])?;
+        }
+        srec_record(writer, 7, entry, &[])
+    }
+
+    /// Write the contents of the segments as a flat binary, as they would be
+    /// laid out in physical memory starting from the lowest segment.
+    /// Reserved space is zeroed if another segment follows it, and other
+    /// gaps between segments are padded with the fill byte.
+    pub fn write_flat<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut segments: Vec<&LinkedSegment> = self.segments.iter().collect();
+        segments.sort_by_key(|segment| segment.header.p_paddr);
+        let mut position = match segments.first() {
+            Some(segment) => segment.header.p_paddr,
+            None => return Ok(()),
+        };
+        let mut zeroed_until = position;
+        for segment in segments {
+            let start = segment.header.p_paddr;
+            if start < zeroed_until {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("segment {:?} overlaps the previous segment", segment.name),
+                ));
+            }
+            io::copy(&mut io::repeat(0).take(zeroed_until - position), writer)?;
+            io::copy(
+                &mut io::repeat(self.fill).take(start - zeroed_until),
+                writer,
+            )?;
+            writer.write_all(&segment.data)?;
+            position = start + segment.header.p_filesz;
+            zeroed_until = start + segment.header.p_memsz;
+        }
+        Ok(())
+    }
+
+    /// The contents of each segment in memory, including reserved space, as
+    /// `(physical address, data)`.
+    pub fn memory_image(&self) -> Vec<(Addr, Vec<u8>)> {
+        self.segments
+            .iter()
+            .map(|segment| {
+                let mut data = segment.data.clone();
+                data.resize(segment.header.p_memsz as usize, 0);
+                (segment.header.p_paddr, data)
+            })
+            .collect()
+    }
+
+    /// Write the memory image as a sparse file, with each segment at the
+    /// file offset equal to its physical address. Gaps are left as holes.
+    pub fn write_memory_dump<W: Write + Seek>(&self, writer: &mut W) -> io::Result<()> {
+        let mut end = 0;
+        for (address, data) in self.memory_image() {
+            writer.seek(SeekFrom::Start(address))?;
+            writer.write_all(&data)?;
+            end = end.max(address + data.len() as u64);
+        }
+        // Extend the file to the end of the last segment.
+        writer.seek(SeekFrom::Start(end))?;
+        Ok(())
+    }
+
+    /// Write each segment's memory image to `<dir>/<segment>.bin`, and return
+    /// the QEMU arguments that preload them with the generic loader device
+    /// and start the first CPU at the physical entry point.
+    pub fn write_qemu_loader_blobs(&self, dir: &Path) -> io::Result<Vec<String>> {
+        let mut args = Vec::new();
+        for (segment, (address, data)) in self.segments.iter().zip(self.memory_image()) {
+            let path = dir.join(format!("{}.bin", segment.name));
+            fs::write(&path, data)?;
+            args.push("-device".to_owned());
+            args.push(format!(
+                "loader,file={},addr={:#x},force-raw=on",
+                path.display(),
+                address
+            ));
+        }
+        args.push("-device".to_owned());
+        args.push(format!(
+            "loader,addr={:#x},cpu-num=0",
+            self.physical_entry()
+        ));
+        Ok(args)
+    }
+
+    /// Every label as `(address, size, scope, segment, label)`, sorted by
+    /// address. The scope is `g` for exported labels and `l` for local
+    /// labels, and the size of a label extends to the next label in the same
+    /// segment, or the end of the segment.
+    pub(crate) fn symbols(&self) -> Vec<(Addr, usize, char, &str, Arc<str>)> {
+        let mut symbols = Vec::new();
+        for segment in &self.segments {
+            let mut offsets: Vec<usize> = segment
+                .labels
+                .values()
+                .map(|definition| definition.offset)
+                .collect();
+            offsets.push(segment.header.p_memsz as usize);
+            offsets.sort_unstable();
+            offsets.dedup();
+
+            for (label, definition) in &segment.labels {
+                let next = offsets[offsets.partition_point(|&o| o <= definition.offset)..]
+                    .first()
+                    .copied()
+                    .unwrap_or(definition.offset);
+                let scope = match definition.visibility {
+                    Visibility::Exported => 'g',
+                    Visibility::Local => 'l',
+                };
+                symbols.push((
+                    segment.header.p_vaddr + definition.offset as u64,
+                    next - definition.offset,
+                    scope,
+                    segment.name.as_str(),
+                    label.name(),
+                ));
+            }
+        }
+        symbols.sort();
+        symbols
+    }
+
+    /// Write a symbol file listing every label, sorted by address.
+    ///
+    /// Each line has the form `<address> <size> <scope> <segment> <label>`,
+    /// where the address (virtual) and size are in hexadecimal, and the
+    /// scope is `g` for exported labels and `l` for local labels. The size
+    /// of a label extends to the next label in the same segment, or the end
+    /// of the segment.
+    pub fn write_symbols<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        for (address, size, scope, segment, label) in self.symbols() {
+            writeln!(
+                writer,
+                "{:016x} {:08x} {} {} {}",
+                address, size, scope, segment, label
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Write a comment file listing every comment, sorted by address.
+    ///
+    /// Each line has the form `<address> <segment> <comment>`, where the
+    /// address (virtual) is in hexadecimal, like the symbol file.
+    pub fn write_comments<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        for (address, segment, text) in self.comments() {
+            writeln!(writer, "{:016x} {} {}", address, segment, text)?;
+        }
+        Ok(())
+    }
+
+    /// Every comment as `(address, segment, comment)`, sorted by address,
+    /// with the comments at the same address in the order they were made.
+    fn comments(&self) -> Vec<(Addr, &str, &str)> {
+        let mut comments: Vec<(Addr, &str, &str)> = self
+            .segments
+            .iter()
+            .flat_map(|segment| {
+                segment.comments.iter().map(|(offset, text)| {
+                    (
+                        segment.header.p_vaddr + *offset as u64,
+                        segment.name.as_str(),
+                        text.as_str(),
+                    )
+                })
+            })
+            .collect();
+        comments.sort_by_key(|&(address, _, _)| address);
+        comments
+    }
+
+    /// Write an ELF file that holds DWARF line number information for the
+    /// segments, mapping the address of each instruction to the source
+    /// location of the generator code that emitted it, and a symbol table of
+    /// every label.
+    ///
+    /// Like the symbol file, it is kept out of the boot image. It has no
+    /// segments, and a section without contents for each segment, as in the
+    /// output of `objcopy --only-keep-debug`, so it can be loaded into gdb
+    /// with `symbol-file`, or given to `addr2line -e`. Source paths are
+    /// relative to the directory of this crate.
+    pub fn write_debug_info<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.write_with_sections(writer, false)
+    }
+
+    /// Write the ELF file like [`write`](Self::write), followed by section
+    /// headers, a symbol table and the debugging information of
+    /// [`write_debug_info`](Self::write_debug_info), so that debuggers and
+    /// `objdump` can be pointed at the image itself.
+    ///
+    /// Each segment is described by a section of the same name, with a
+    /// `SHT_NOBITS` section named `<segment>.bss` for any reserved space at
+    /// its end. Labels in executable segments are `STT_FUNC` symbols, and
+    /// labels in other segments `STT_OBJECT` symbols, with the sizes of the
+    /// symbol file. Labels of size zero, such as the bounds of a segment,
+    /// have no type. Loaders ignore the sections, so the image still loads
+    /// the same way.
+    pub fn write_debuggable<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.validate()?;
+        self.write_with_sections(writer, true)
+    }
+
+    /// Write the section headers describing the segments, the symbol table
+    /// and the debugging information, after the image if `image`, or with
+    /// only the file header otherwise.
+    fn write_with_sections<W: Write>(&self, writer: &mut W, image: bool) -> io::Result<()> {
+        let units: Vec<dwarf::Unit> = self
+            .segments
+            .iter()
+            .filter(|segment| !segment.locations.is_empty())
+            .map(|segment| dwarf::Unit {
+                name: &segment.name,
+                low_pc: segment.header.p_vaddr,
+                high_pc: segment.header.p_vaddr + segment.header.p_filesz,
+                rows: segment
+                    .locations
+                    .iter()
+                    .map(|&(offset, location)| (segment.header.p_vaddr + offset as u64, location))
+                    .collect(),
+            })
+            .collect();
+        let typed: Vec<(Arc<str>, Addr, &Type)> = self
+            .segments
+            .iter()
+            .flat_map(|segment| {
+                segment.types.iter().map(|(label, ty)| {
+                    let definition = segment.labels.get(label).unwrap_or_else(|| {
+                        panic!(
+                            "label {} has a type, but is not defined in segment {}",
+                            label, segment.name
+                        )
+                    });
+                    let address = segment.header.p_vaddr + definition.offset as u64;
+                    (label.name(), address, ty)
+                })
+            })
+            .collect();
+        let variables: Vec<dwarf::Variable> = typed
+            .iter()
+            .map(|(name, address, ty)| dwarf::Variable {
+                name,
+                address: *address,
+                ty: (*ty).clone(),
+            })
+            .collect();
+        let debug = dwarf::debug_sections(
+            &units,
+            &variables,
+            &self.debug_types,
+            env!("CARGO_MANIFEST_DIR"),
+        );
+
+        // The section of the contents of each segment, and the offset at
+        // which its reserved space, in a section of its own, starts.
+        let mut names = StringTableBuilder::new();
+        let mut headers = vec![SectionHeader::zeroed()];
+        let mut segment_sections = Vec::new();
+        for segment in &self.segments {
+            let mut flags = SHF_ALLOC;
+            if segment.header.p_flags & PF_W != 0 {
+                flags |= SHF_WRITE;
+            }
+            if segment.header.p_flags & PF_X != 0 {
+                flags |= SHF_EXECINSTR;
+            }
+            let section = SectionHeader {
+                sh_name: names.push(segment.name.as_bytes()),
+                sh_type: SHT_NOBITS,
+                sh_flags: flags,
+                sh_addr: segment.header.p_vaddr,
+                sh_size: segment.header.p_memsz,
+                sh_addralign: 1,
+                ..SectionHeader::zeroed()
+            };
+            let (filesz, memsz) = (segment.header.p_filesz, segment.header.p_memsz);
+            if !image || filesz == 0 {
+                segment_sections.push((headers.len(), None));
+                headers.push(section);
+                continue;
+            }
+            headers.push(SectionHeader {
+                sh_type: SHT_PROGBITS,
+                sh_offset: segment.header.p_offset,
+                sh_size: filesz,
+                ..section
+            });
+            if memsz > filesz {
+                segment_sections.push((headers.len() - 1, Some(filesz as usize)));
+                headers.push(SectionHeader {
+                    sh_name: names.push(format!("{}.bss", segment.name).as_bytes()),
+                    sh_addr: segment.header.p_vaddr + filesz,
+                    sh_size: memsz - filesz,
+                    ..section
+                });
+            } else {
+                segment_sections.push((headers.len() - 1, None));
+            }
+        }
+
+        // Local symbols must come before global ones.
+        let mut strings = StringTableBuilder::new();
+        let mut symbols = vec![Symbol::zeroed()];
+        let mut globals = Vec::new();
+        for (address, size, scope, segment_name, label) in self.symbols() {
+            let index = self
+                .segments
+                .iter()
+                .position(|segment| segment.name == segment_name)
+                .unwrap();
+            let segment = &self.segments[index];
+            let offset = (address - segment.header.p_vaddr) as usize;
+            let (section, reserved) = segment_sections[index];
+            let section = match reserved {
+                Some(reserved) if offset >= reserved => section + 1,
+                _ => section,
+            };
+            let symbol_type = if size == 0 {
+                STT_NOTYPE
+            } else if segment.header.p_flags & PF_X != 0 {
+                STT_FUNC
+            } else {
+                STT_OBJECT
+            };
+            let binding = match scope {
+                'l' => STB_LOCAL,
+                _ => STB_GLOBAL,
+            };
+            let symbol = Symbol {
+                st_name: strings.push(label.as_bytes()),
+                st_info: binding | symbol_type,
+                st_other: 0,
+                st_shndx: section as Half,
+                st_value: address,
+                st_size: size as Xword,
+            };
+            match binding {
+                STB_LOCAL => symbols.push(symbol),
+                _ => globals.push(symbol),
+            }
+        }
+        let first_global = symbols.len();
+        symbols.extend(globals);
+
+        let symtab = headers.len();
+        let mut sections = vec![
+            (
+                SectionHeader {
+                    sh_name: names.push(b".symtab"),
+                    sh_type: SHT_SYMTAB,
+                    sh_link: (symtab + 1) as Word,
+                    sh_info: first_global as Word,
+                    sh_addralign: 8,
+                    sh_entsize: SYMBOL_SIZE as Xword,
+                    ..SectionHeader::zeroed()
+                },
+                bytemuck::cast_slice(&symbols).to_vec(),
+            ),
+            (
+                SectionHeader {
+                    sh_name: names.push(b".strtab"),
+                    sh_type: SHT_STRTAB,
+                    sh_addralign: 1,
+                    ..SectionHeader::zeroed()
+                },
+                strings.finish(),
+            ),
+        ];
+        for (name, data) in [
+            (&b".debug_abbrev"[..], debug.abbrev),
+            (b".debug_info", debug.info),
+            (b".debug_line", debug.line),
+        ] {
+            let header = SectionHeader {
+                sh_name: names.push(name),
+                sh_type: SHT_PROGBITS,
+                sh_addralign: 1,
+                ..SectionHeader::zeroed()
+            };
+            sections.push((header, data));
+        }
+        let shstrndx = headers.len() + sections.len();
+        let shstrtab = SectionHeader {
+            sh_name: names.push(b".shstrtab"),
+            sh_type: SHT_STRTAB,
+            sh_addralign: 1,
+            ..SectionHeader::zeroed()
+        };
+        sections.push((shstrtab, names.finish()));
+
+        let mut offset = match image {
+            true => self.image_size(),
+            false => FILE_HEADER_SIZE as u64,
+        };
+        for (header, data) in &mut sections {
+            offset = align_up(offset, header.sh_addralign);
+            header.sh_offset = offset;
+            header.sh_size = data.len() as u64;
+            offset += data.len() as u64;
+        }
+        let section_headers_offset = align_up(offset, 8);
+        headers.extend(sections.iter().map(|(header, _)| *header));
+
+        let mut file_header = FileHeader {
+            e_shoff: section_headers_offset,
+            e_shnum: headers.len() as Half,
+            e_shstrndx: shstrndx as Half,
+            ..self.file_header
+        };
+        let mut position = if image {
+            self.write_image(writer, &file_header)?
+        } else {
+            file_header.e_phoff = 0;
+            file_header.e_phnum = 0;
+            writer.write_all(bytemuck::bytes_of(&file_header))?;
+            FILE_HEADER_SIZE as u64
+        };
+        for (header, data) in &sections {
+            writer.write_all(&vec![0; (header.sh_offset - position) as usize])?;
+            writer.write_all(data)?;
+            position = header.sh_offset + data.len() as u64;
+        }
+        writer.write_all(&vec![0; (section_headers_offset - position) as usize])?;
+        for header in &headers {
+            writer.write_all(bytemuck::bytes_of(header))?;
+        }
+        Ok(())
+    }
+
+    /// Write a human-readable report of the file header, the program headers,
+    /// the segments and the address of every label, like `readelf -lhs`,
+    /// with the comments among the labels.
+    pub fn write_report<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let header = &self.file_header;
+        let type_ = match header.e_type {
+            ET_EXEC => "EXEC (executable)".to_owned(),
+            ET_DYN => "DYN (position-independent executable)".to_owned(),
+            other => format!("{:#x}", other),
+        };
+        writeln!(writer, "File header:")?;
+        writeln!(writer, "  Type:            {}", type_)?;
+        writeln!(writer, "  Entry point:     {:#x}", header.e_entry)?;
+        writeln!(
+            writer,
+            "  Program headers: {} of {} bytes, at offset {:#x}",
+            header.e_phnum, header.e_phentsize, header.e_phoff
+        )?;
+
+        writeln!(writer)?;
+        writeln!(writer, "Program headers:")?;
+        writeln!(
+            writer,
+            "  {:<12} {:<18} {:<18} {:<18} {:<10} {:<10} Flg Align",
+            "Type", "Offset", "VirtAddr", "PhysAddr", "FileSiz", "MemSiz"
+        )?;
+        for phdr in &self.program_headers {
+            let type_ = match phdr.p_type {
+                PT_LOAD => "LOAD".to_owned(),
+                PT_DYNAMIC => "DYNAMIC".to_owned(),
+                PT_PHDR => "PHDR".to_owned(),
+                PT_GNU_RELRO => "GNU_RELRO".to_owned(),
+                other => format!("{:#x}", other),
+            };
+            let flags: String = [(PF_R, 'R'), (PF_W, 'W'), (PF_X, 'E')]
+                .iter()
+                .map(|&(flag, c)| if phdr.p_flags & flag != 0 { c } else { ' ' })
+                .collect();
+            writeln!(
+                writer,
+                "  {:<12} {:#018x} {:#018x} {:#018x} {:#010x} {:#010x} {} {:#x}",
+                type_,
+                phdr.p_offset,
+                phdr.p_vaddr,
+                phdr.p_paddr,
+                phdr.p_filesz,
+                phdr.p_memsz,
+                flags,
+                phdr.p_align
+            )?;
+        }
+
+        writeln!(writer)?;
+        writeln!(writer, "Segments:")?;
+        for segment in &self.segments {
+            writeln!(
+                writer,
+                "  {:<18} {:#018x} {:#010x}",
+                segment.name, segment.header.p_vaddr, segment.header.p_memsz
+            )?;
+        }
+
+        // Comments follow the labels at their address.
+        writeln!(writer)?;
+        writeln!(writer, "Labels:")?;
+        let mut lines: Vec<(Addr, bool, String)> = self
+            .symbols()
+            .into_iter()
+            .map(|(address, size, scope, segment, label)| {
+                let line = format!(
+                    "  {:#018x} {:#010x} {} {:<18} {}",
+                    address, size, scope, segment, label
+                );
+                (address, false, line)
+            })
+            .collect();
+        lines.extend(
+            self.comments().into_iter().map(|(address, _, text)| {
+                (address, true, format!("  {:#018x} ; {}", address, text))
+            }),
+        );
+        lines.sort_by_key(|&(address, comment, _)| (address, comment));
+        for (_, _, line) in lines {
+            writeln!(writer, "{}", line)?;
+        }
+        Ok(())
+    }
+
+    /// Ensure that all segments lie in the low 4GiB of physical memory, for
+    /// formats with 32-bit addresses, and return the physical entry point.
+    fn check_32_bit(&self, format: &str) -> io::Result<u32> {
+        for segment in &self.segments {
+            let start = segment.header.p_paddr;
+            if start + segment.data.len() as u64 > 1 << 32 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "segment {:?} at {:#x} is not addressable in {}",
+                        segment.name, start, format
+                    ),
+                ));
+            }
+        }
+        u32::try_from(self.physical_entry()).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("entry point is not addressable in {}", format),
+            )
+        })
+    }
+
+    /// Check the invariants of the file and program headers that loaders
+    /// rely on:
+    ///
+    /// - The program header table is where the file header says it is, and
+    ///   does not overlap the contents of any segment.
+    /// - The file offset and virtual address of each loaded segment are
+    ///   congruent modulo its alignment.
+    /// - Loaded segments overlap neither in the file nor in memory.
+    /// - The entry point is inside an executable segment.
+    ///
+    /// These hold for any output of the linker, so a failure is a bug in the
+    /// linker rather than in its input.
+    pub fn validate(&self) -> io::Result<()> {
+        let invalid = |message: String| Err(io::Error::new(io::ErrorKind::InvalidData, message));
+        let header = &self.file_header;
+
+        if header.e_phentsize != PROGRAM_HEADER_SIZE
+            || header.e_phnum as usize != self.program_headers.len()
+        {
+            return invalid(format!(
+                "file header has {} program headers of {} bytes, expected {} of {}",
+                header.e_phnum,
+                header.e_phentsize,
+                self.program_headers.len(),
+                PROGRAM_HEADER_SIZE
+            ));
+        }
+        let table_end = header.e_phoff + header.e_phnum as u64 * PROGRAM_HEADER_SIZE as u64;
+        if header.e_phoff < FILE_HEADER_SIZE as u64 {
+            return invalid(format!(
+                "program headers at {:#x} overlap the file header",
+                header.e_phoff
+            ));
+        }
+        for segment in &self.segments {
+            if segment.header.p_offset < table_end && !segment.data.is_empty() {
+                return invalid(format!(
+                    "segment {:?} at {:#x} overlaps the program headers",
+                    segment.name, segment.header.p_offset
+                ));
+            }
+        }
+        for phdr in &self.program_headers {
+            if phdr.p_type == PT_PHDR
+                && (phdr.p_offset, phdr.p_filesz) != (header.e_phoff, table_end - header.e_phoff)
+            {
+                return invalid("PT_PHDR does not match the file header".to_owned());
+            }
+        }
+
+        let loads: Vec<&Phdr> = self
+            .program_headers
+            .iter()
+            .filter(|phdr| phdr.p_type == PT_LOAD)
+            .collect();
+        for phdr in &loads {
+            if phdr.p_align > 1
+                && (!phdr.p_align.is_power_of_two()
+                    || phdr.p_offset % phdr.p_align != phdr.p_vaddr % phdr.p_align)
+            {
+                return invalid(format!(
+                    "segment at offset {:#x} and address {:#x} is not congruent modulo {:#x}",
+                    phdr.p_offset, phdr.p_vaddr, phdr.p_align
+                ));
+            }
+            if phdr.p_filesz > phdr.p_memsz {
+                return invalid(format!(
+                    "segment at {:#x} is larger in the file than in memory",
+                    phdr.p_vaddr
+                ));
+            }
+        }
+        for (i, a) in loads.iter().enumerate() {
+            for b in &loads[i + 1..] {
+                let overlaps = |a_start: u64, a_size: u64, b_start: u64, b_size: u64| {
+                    a_size != 0
+                        && b_size != 0
+                        && a_start < b_start + b_size
+                        && b_start < a_start + a_size
+                };
+                if overlaps(a.p_offset, a.p_filesz, b.p_offset, b.p_filesz) {
+                    return invalid(format!(
+                        "segments at offsets {:#x} and {:#x} overlap in the file",
+                        a.p_offset, b.p_offset
+                    ));
+                }
+                if overlaps(a.p_vaddr, a.p_memsz, b.p_vaddr, b.p_memsz) {
+                    return invalid(format!(
+                        "segments at {:#x} and {:#x} overlap in memory",
+                        a.p_vaddr, b.p_vaddr
+                    ));
+                }
+            }
+        }
+
+        let entry = header.e_entry;
+        if !loads.iter().any(|phdr| {
+            phdr.p_flags & PF_X != 0 && (phdr.p_vaddr..phdr.p_vaddr + phdr.p_memsz).contains(&entry)
+        }) {
+            return invalid(format!(
+                "entry point {:#x} is not in an executable segment",
+                entry
+            ));
+        }
+        Ok(())
+    }
+
+    /// Write the ELF file, after checking it with `validate()`.
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.validate()?;
+        self.write_image(writer, &self.file_header)?;
+        Ok(())
+    }
+
+    /// The ELF file written by [`write`](Self::write), in a buffer that is
+    /// allocated once at its final size.
+    pub fn to_vec(&self) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::with_capacity(self.image_size() as usize);
+        self.write(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// The size of the file written by [`write`](Self::write).
+    fn image_size(&self) -> u64 {
+        self.segments.last().map_or(
+            FILE_HEADER_SIZE as u64
+                + self.program_headers.len() as u64 * PROGRAM_HEADER_SIZE as u64,
+            |segment| segment.header.p_offset + segment.data.len() as u64,
+        )
+    }
+
+    /// Write `file_header`, the program headers and the segments, and return
+    /// the number of bytes written.
+    fn write_image<W: Write>(&self, writer: &mut W, file_header: &FileHeader) -> io::Result<u64> {
+        writer.write_all(bytemuck::bytes_of(file_header))?;
+        let mut position = FILE_HEADER_SIZE as u64;
+        for header in &self.program_headers {
+            writer.write_all(bytemuck::bytes_of(header))?;
+            position += PROGRAM_HEADER_SIZE as u64;
+        }
+
+        for segment in &self.segments {
+            let padding = segment.header.p_offset - position;
+            io::copy(&mut io::repeat(self.fill).take(padding), writer)?;
+            writer.write_all(&segment.data)?;
+            position = segment.header.p_offset + segment.data.len() as u64;
+        }
+        Ok(position)
+    }
+}
+
+/// Write a single S-record. Data records (S3) and start address records (S7)
+/// have 32-bit addresses, and count records (S5, S6) 16- or 24-bit counts.
+fn srec_record<W: Write>(writer: &mut W, type_: u8, address: u32, data: &[u8]) -> io::Result<()> {
+    let address_len = match type_ {
+        5 => 2,
+        6 => 3,
+        _ => 4,
+    };
+    let mut record = vec![(address_len + data.len() + 1) as u8];
+    record.extend(&address.to_be_bytes()[4 - address_len..]);
+    record.extend(data);
+    let checksum = !record.iter().fold(0u8, |a, b| a.wrapping_add(*b));
+    record.push(checksum);
+
+    write!(writer, "S{}", type_)?;
+    for byte in record {
+        write!(writer, "{:02X}", byte)?;
+    }
+    writeln!(writer)
+}
+
+/// Write a single Intel HEX record.
+fn ihex_record<W: Write>(writer: &mut W, type_: u8, address: u16, data: &[u8]) -> io::Result<()> {
+    let mut record = vec![data.len() as u8];
+    record.extend(address.to_be_bytes());
+    record.push(type_);
+    record.extend(data);
+    let checksum = record
+        .iter()
+        .fold(0u8, |a, b| a.wrapping_add(*b))
+        .wrapping_neg();
+    record.push(checksum);
+
+    write!(writer, ":")?;
+    for byte in record {
+        write!(writer, "{:02X}", byte)?;
+    }
+    writeln!(writer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elf64::program::{PF_R, PF_W, PF_X};
+    use std::io::Cursor;
+
+    fn linker() -> ElfLinker {
+        let mut data = Segment::new();
+        data.label("pointer");
+        data.append_reference("entry", ReferenceFormat::Abs64);
+        data.label("loop");
+        data.append(&[0u8; 9]);
+
+        let mut code = Segment::new();
+        code.set_fill(0xcc);
+        code.export_label("entry");
+        code.label("loop");
+        code.extend([0xe9]);
+        code.append_reference("loop", ReferenceFormat::Rel32);
+        code.pad_to_alignment(16);
+
+        let mut linker = ElfLinker::new();
+        linker.add_segment("data", PF_R, 1 << 12, data);
+        linker.add_segment("code", PF_R | PF_X, 1 << 12, code);
+        linker
+    }
+
+    #[test]
+    fn to_vec_is_allocated_once() {
+        let linked = linker().finish();
+        let mut written = Vec::new();
+        linked.write(&mut written).unwrap();
+
+        let bytes = linked.to_vec().unwrap();
+        assert_eq!(bytes, written);
+        assert_eq!(bytes.capacity(), bytes.len());
+    }
+
+    #[test]
+    fn streaming_matches_in_memory() {
+        let linker = || {
+            let mut linker = linker();
+            linker.set_fill(0xcc);
+            linker
+        };
+
+        let mut in_memory = Vec::new();
+        linker().finish().write(&mut in_memory).unwrap();
+
+        let mut streamed = Cursor::new(Vec::new());
+        linker().finish_to(&mut streamed).unwrap();
+
+        assert_eq!(in_memory, streamed.into_inner());
+        // Padding between the data and code segments, and at the end of the
+        // code segment.
+        let code_offset = in_memory.len() - 16;
+        assert_eq!(in_memory[code_offset - 1], 0xcc);
+        assert_eq!(in_memory[in_memory.len() - 1], 0xcc);
+    }
+
+    #[test]
+    fn entry_label_is_configurable() {
+        let mut linker = linker();
+        let code = linker.segment_id("code").unwrap();
+        linker.export_segment_bounds(code, "code_start", "code_end");
+        linker.set_entry("code_end");
+        let linked = linker.finish();
+
+        let (header, _) = linked.headers();
+        assert_eq!(Some(header.e_entry), linked.label_address("code_end"));
+        assert_ne!(Some(header.e_entry), linked.label_address("entry"));
+    }
+
+    #[test]
+    fn undefined_labels_are_errors() {
+        assert!(linker().check_labels().is_ok());
+
+        // Local labels of other segments are not visible.
+        let mut linker = linker();
+        let mut more = Segment::new();
+        more.append_reference("nowhere", ReferenceFormat::Rel32);
+        more.append_reference("loop", ReferenceFormat::Rel32);
+        linker.add_segment("more", PF_R, 1 << 12, more);
+        linker.set_entry("start");
+        assert_eq!(
+            linker.check_labels().unwrap_err().to_string(),
+            "entry label `start` is not defined or not exported\n\
+             undefined label `loop`, referenced from more\n\
+             undefined label `nowhere`, referenced from more"
+        );
+    }
+
+    #[test]
+    fn intel_hex() {
+        let mut linker = linker();
+        linker.set_physical_base(0xf000);
+        let linked = linker.finish();
+
+        let mut ihex = Vec::new();
+        linked.write_ihex(&mut ihex).unwrap();
+        let ihex = String::from_utf8(ihex).unwrap();
+        let lines: Vec<&str> = ihex.lines().collect();
+
+        for line in &lines {
+            let bytes: Vec<u8> = (1..line.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&line[i..i + 2], 16).unwrap())
+                .collect();
+            assert_eq!(bytes.iter().fold(0u8, |a, b| a.wrapping_add(*b)), 0);
+        }
+        // The data segment is at the start of the physical base page, and
+        // the code segment is on the next page, across a 64KiB boundary.
+        assert_eq!(lines[0], ":020000040000FA");
+        assert_eq!(&lines[1][..9], ":10F0B000");
+        assert_eq!(lines[3], ":020000040001F9");
+        assert_eq!(lines[lines.len() - 2], ":04000005000100D026");
+        assert_eq!(lines[lines.len() - 1], ":00000001FF");
+    }
+
+    #[test]
+    fn motorola_srec() {
+        let mut linker = linker();
+        linker.set_physical_base(0x100000);
+        let linked = linker.finish();
+
+        let mut srec = Vec::new();
+        linked.write_srec(&mut srec).unwrap();
+        let srec = String::from_utf8(srec).unwrap();
+        let lines: Vec<&str> = srec.lines().collect();
+
+        for line in &lines {
+            let bytes: Vec<u8> = (2..line.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&line[i..i + 2], 16).unwrap())
+                .collect();
+            assert_eq!(bytes[0] as usize, bytes.len() - 1);
+            assert_eq!(bytes.iter().fold(0u8, |a, b| a.wrapping_add(*b)), 0xff);
+        }
+        assert_eq!(&lines[0][..12], "S315001000B0");
+        assert_eq!(lines[lines.len() - 2], "S5030003F9");
+        assert_eq!(lines[lines.len() - 1], "S705001010D00A");
+    }
+
+    #[test]
+    fn flat_binary() {
+        let mut linker = linker();
+        linker.set_fill(0xcc);
+        let linked = linker.finish();
+
+        let mut flat = Vec::new();
+        linked.write_flat(&mut flat).unwrap();
+        // Data segment at 0xb0, padding up to the code segment at 0x10d0.
+        assert_eq!(flat.len(), 0x1020 + 0x10);
+        assert_eq!(flat[0x11], 0xcc);
+        assert_eq!(flat[0x1020..][..5], [0xe9, 0xfb, 0xff, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn reserved_space() {
+        let mut bss = Segment::new();
+        bss.export_label("stack_bottom");
+        bss.reserve(0x2000);
+        bss.export_label("stack_top");
+
+        let mut linker = linker();
+        linker.set_physical_base(0x100000);
+        let bss = linker.add_segment("bss", PF_R | PF_W, 1 << 12, bss);
+        linker.export_segment_bounds(bss, "bss_start", "bss_end");
+        let linked = linker.finish();
+
+        let header = &linked.segment("bss").unwrap().header;
+        assert_eq!(header.p_filesz, 0);
+        assert_eq!(header.p_memsz, 0x2000);
+        assert_eq!(
+            linked.label_address("stack_top"),
+            linked.label_address("bss_end")
+        );
+
+        let mut dump = Cursor::new(Vec::new());
+        linked.write_memory_dump(&mut dump).unwrap();
+        let dump = dump.into_inner();
+        let (address, data) = linked.memory_image().pop().unwrap();
+        assert_eq!(data, [0; 0x2000]);
+        assert_eq!(dump.len() as u64, address + 0x2000);
+        // The data segment, with the pointer to the entry point.
+        assert_eq!(dump[0x1000e8..][..4], [0x00, 0x11, 0x00, 0x80]);
+    }
+
+    #[test]
+    fn symbol_file() {
+        let linked = linker().finish();
+
+        let mut symbols = Vec::new();
+        linked.write_symbols(&mut symbols).unwrap();
+        assert_eq!(
+            String::from_utf8(symbols).unwrap(),
+            "ffffffff800000b0 00000008 l data pointer\n\
+             ffffffff800000b8 00000009 l data loop\n\
+             ffffffff800010d0 00000010 g code entry\n\
+             ffffffff800010d0 00000010 l code loop\n"
+        );
+    }
+
+    #[test]
+    fn debug_info() {
+        let mut code = Segment::new();
+        code.export_label("entry");
+        code.set_location(Location::caller());
+        code.extend([0x90, 0x90]);
+        code.set_location(Location::caller());
+        code.extend([0xf4]);
+        let mut linker = ElfLinker::new();
+        linker.add_segment("data", PF_R, 1 << 12, Segment::new());
+        linker.add_segment("code", PF_R | PF_X, 1 << 12, code);
+        let linked = linker.finish();
+
+        let mut file = Vec::new();
+        linked.write_debug_info(&mut file).unwrap();
+        let header: FileHeader = bytemuck::pod_read_unaligned(&file[..FILE_HEADER_SIZE as usize]);
+        assert_eq!(header.e_phnum, 0);
+        assert_eq!(header.e_entry, linked.file_header.e_entry);
+        let sections = read_sections(&file);
+        let section_names: Vec<&str> = sections.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(
+            section_names,
+            [
+                "",
+                "data",
+                "code",
+                ".symtab",
+                ".strtab",
+                ".debug_abbrev",
+                ".debug_info",
+                ".debug_line",
+                ".shstrtab"
+            ]
+        );
+
+        // Segments are described by sections without contents.
+        let code = &sections[2].1;
+        assert_eq!(code.sh_type, SHT_NOBITS);
+        assert_eq!(code.sh_addr, linked.segment_vaddr("code").unwrap());
+        assert_eq!(code.sh_flags, SHF_ALLOC | SHF_EXECINSTR);
+        // Only the code has line numbers, from this file.
+        let line = &sections[7].1;
+        let line = &file[line.sh_offset as usize..][..line.sh_size as usize];
+        assert!(line
+            .windows(file!().len())
+            .any(|window| window == file!().as_bytes()));
+    }
+
+    /// The names and headers of the sections of an ELF file.
+    fn read_sections(file: &[u8]) -> Vec<(String, SectionHeader)> {
+        let header: FileHeader = bytemuck::pod_read_unaligned(&file[..FILE_HEADER_SIZE as usize]);
+        let sections: Vec<SectionHeader> = (0..header.e_shnum as usize)
+            .map(|i| {
+                let offset = header.e_shoff as usize + i * size_of::<SectionHeader>();
+                bytemuck::pod_read_unaligned(&file[offset..][..size_of::<SectionHeader>()])
+            })
+            .collect();
+        let names = &sections[header.e_shstrndx as usize];
+        let names = &file[names.sh_offset as usize..][..names.sh_size as usize];
+        sections
+            .into_iter()
+            .map(|section| (c_string(&names[section.sh_name as usize..]), section))
+            .collect()
+    }
+
+    fn c_string(bytes: &[u8]) -> String {
+        let end = bytes.iter().position(|&b| b == 0).unwrap();
+        String::from_utf8(bytes[..end].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn debuggable() {
+        let mut data = Segment::new();
+        data.export_label("table");
+        data.extend([1, 2, 3, 4]);
+        data.label("buffer");
+        data.reserve(12);
+        let mut code = Segment::new();
+        code.export_label("entry");
+        code.extend([0x90, 0xf4]);
+        code.label("helper");
+        code.set_location(Location::caller());
+        code.extend([0xc3]);
+        code.label("code_end");
+        let mut linker = ElfLinker::new();
+        linker.add_segment("data", PF_R | PF_W, 1 << 12, data);
+        linker.add_segment("code", PF_R | PF_X, 1 << 12, code);
+        let linked = linker.finish();
+
+        let mut image = Vec::new();
+        linked.write(&mut image).unwrap();
+        let mut file = Vec::new();
+        linked.write_debuggable(&mut file).unwrap();
+        // Only the section header fields of the file header change.
+        let header: FileHeader = bytemuck::pod_read_unaligned(&file[..FILE_HEADER_SIZE as usize]);
+        assert_eq!(
+            bytemuck::bytes_of(&FileHeader {
+                e_shoff: 0,
+                e_shnum: 0,
+                e_shstrndx: 0,
+                ..header
+            }),
+            &image[..FILE_HEADER_SIZE as usize]
+        );
+        assert_eq!(
+            file[FILE_HEADER_SIZE as usize..image.len()],
+            image[FILE_HEADER_SIZE as usize..]
+        );
+
+        let sections = read_sections(&file);
+        let section_names: Vec<&str> = sections.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(
+            section_names,
+            [
+                "",
+                "data",
+                "data.bss",
+                "code",
+                ".symtab",
+                ".strtab",
+                ".debug_abbrev",
+                ".debug_info",
+                ".debug_line",
+                ".shstrtab"
+            ]
+        );
+        let (data, bss, code) = (&sections[1].1, &sections[2].1, &sections[3].1);
+        assert_eq!((data.sh_type, data.sh_size), (SHT_PROGBITS, 4));
+        assert_eq!((bss.sh_type, bss.sh_size), (SHT_NOBITS, 12));
+        assert_eq!(bss.sh_addr, data.sh_addr + 4);
+        let code_offset = code.sh_offset as usize;
+        assert_eq!(
+            file[code_offset..][..code.sh_size as usize],
+            [0x90, 0xf4, 0xc3]
+        );
+
+        let symtab = &sections[4].1;
+        let strtab = &sections[5].1;
+        assert_eq!(symtab.sh_link, 5);
+        let symbols: Vec<(String, Uchar, Half, Xword)> = file[symtab.sh_offset as usize..]
+            [..symtab.sh_size as usize]
+            .chunks(SYMBOL_SIZE as usize)
+            .map(|bytes| {
+                let symbol: Symbol = bytemuck::pod_read_unaligned(bytes);
+                let name = &file[strtab.sh_offset as usize + symbol.st_name as usize..];
+                (
+                    c_string(name),
+                    symbol.st_info,
+                    symbol.st_shndx,
+                    symbol.st_size,
+                )
+            })
+            .collect();
+        // Local symbols come first.
+        assert_eq!(symtab.sh_info, 4);
+        assert_eq!(
+            symbols,
+            [
+                ("".to_owned(), 0, 0, 0),
+                ("buffer".to_owned(), STB_LOCAL | STT_OBJECT, 2, 12),
+                ("helper".to_owned(), STB_LOCAL | STT_FUNC, 3, 1),
+                ("code_end".to_owned(), STB_LOCAL | STT_NOTYPE, 3, 0),
+                ("table".to_owned(), STB_GLOBAL | STT_OBJECT, 1, 4),
+                ("entry".to_owned(), STB_GLOBAL | STT_FUNC, 3, 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn inspection() {
+        let linked = linker().finish();
+
+        let (file_header, program_headers) = linked.headers();
+        assert_eq!(file_header.e_entry, 0xffffffff800010d0);
+        assert_eq!(file_header.e_phnum as usize, program_headers.len());
+        let segments = linked.segments();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[1].name(), "code");
+        assert_eq!(segments[1].header().p_vaddr, 0xffffffff800010d0);
+        assert_eq!(segments[1].data()[0], 0xe9);
+        assert_eq!(segments[1].labels()[&Label("entry")].offset, 0);
+
+        let mut report = Vec::new();
+        linked.write_report(&mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[2], "  Entry point:     0xffffffff800010d0");
+        assert!(lines.contains(
+            &"  LOAD         0x00000000000000d0 0xffffffff800010d0 0xffffffff800010d0 \
+              0x00000010 0x00000010 R E 0x1000"
+        ));
+        assert!(lines.contains(&"  code               0xffffffff800010d0 0x00000010"));
+        assert!(lines.contains(&"  0xffffffff800010d0 0x00000010 g code               entry"));
+    }
+
+    #[test]
+    fn comments() {
+        let mut function = Segment::new();
+        function.comment("prologue");
+        function.extend([0x55]);
+        function.comment("body");
+        function.comment("second line");
+        function.extend([0xc3]);
+        let mut code = Segment::new();
+        code.export_label("entry");
+        code.comment("call");
+        code.extend([0xe8, 0, 0, 0, 0]);
+        code.label("function");
+        code.embed(function);
+        let mut linker = ElfLinker::new();
+        linker.add_segment("code", PF_R | PF_X, 1 << 12, code);
+        let linked = linker.finish();
+        let entry = linked.label_address("entry").unwrap();
+
+        assert_eq!(
+            linked.segments()[0].comments(),
+            [
+                (0, "call".to_owned()),
+                (5, "prologue".to_owned()),
+                (6, "body".to_owned()),
+                (6, "second line".to_owned())
+            ]
+        );
+        let mut comments = Vec::new();
+        linked.write_comments(&mut comments).unwrap();
+        assert_eq!(
+            String::from_utf8(comments).unwrap(),
+            format!(
+                "{:016x} code call\n{:016x} code prologue\n\
+                 {:016x} code body\n{:016x} code second line\n",
+                entry,
+                entry + 5,
+                entry + 6,
+                entry + 6
+            )
+        );
+
+        let mut report = Vec::new();
+        linked.write_report(&mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        let labels = &report[report.find("Labels:").unwrap()..];
+        let lines: Vec<&str> = labels.lines().skip(1).collect();
+        assert_eq!(
+            lines,
+            [
+                format!("  {:#018x} 0x00000005 g code               entry", entry),
+                format!("  {:#018x} ; call", entry),
+                format!(
+                    "  {:#018x} 0x00000002 l code               function",
+                    entry + 5
+                ),
+                format!("  {:#018x} ; prologue", entry + 5),
+                format!("  {:#018x} ; body", entry + 6),
+                format!("  {:#018x} ; second line", entry + 6),
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "not one line")]
+    fn multi_line_comment() {
+        Segment::new().comment("first\nsecond");
+    }
+
+    #[test]
+    fn position_independent_relocations() {
+        let mut linker = linker();
+        linker.position_independent();
+        let linked = linker.finish();
+
+        assert_eq!(linked.file_header.e_type, ET_DYN);
+        let dynamic = linked
+            .program_headers
+            .iter()
+            .find(|header| header.p_type == PT_DYNAMIC)
+            .unwrap();
+        let rela = &linked.segments.last().unwrap().data;
+        let entry: Rela = bytemuck::pod_read_unaligned(&rela[..RELA_SIZE as usize]);
+
+        // One Abs64 reference to `entry`, from the first word of the data
+        // segment.
+        assert_eq!(dynamic.p_offset % 8, 0);
+        assert_eq!(entry.r_type(), R_X86_64_RELATIVE);
+        assert_eq!(entry.r_offset, linked.program_headers[0].p_vaddr);
+        assert_eq!(entry.r_addend as u64, linked.file_header.e_entry);
+    }
+
+    /// The entries of the dynamic array of a position-independent image.
+    fn dynamic_entries(linked: &Linked) -> Vec<(i64, u64)> {
+        let dynamic = linked
+            .program_headers
+            .iter()
+            .find(|header| header.p_type == PT_DYNAMIC)
+            .unwrap();
+        let segment = linked.segments.last().unwrap();
+        let offset = (dynamic.p_vaddr - segment.header.p_vaddr) as usize;
+        segment.data[offset..][..dynamic.p_filesz as usize]
+            .chunks(DYN_SIZE as usize)
+            .map(|entry| {
+                let entry: Dyn = bytemuck::pod_read_unaligned(entry);
+                (entry.d_tag, entry.d_val)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn text_relocations_are_flagged() {
+        // The pointer in the read-only data segment is relocated.
+        let mut linker = linker();
+        linker.position_independent();
+        let entries = dynamic_entries(&linker.finish());
+        assert!(entries.contains(&(DT_FLAGS, DF_TEXTREL)));
+        assert_eq!(entries.last(), Some(&(DT_NULL, 0)));
+
+        let mut data = Segment::new();
+        data.append_reference("entry", ReferenceFormat::Abs64);
+        let mut code = Segment::new();
+        code.export_label("entry");
+        code.extend([0xc3]);
+        let mut linker = ElfLinker::new();
+        linker.add_segment("data", PF_R | PF_W, 1 << 12, data);
+        linker.add_segment("code", PF_R | PF_X, 1 << 12, code);
+        linker.position_independent();
+        let entries = dynamic_entries(&linker.finish());
+        assert!(entries.iter().all(|&(d_tag, _)| d_tag != DT_FLAGS));
+        assert_eq!(entries.len(), 6);
+    }
+
+    #[test]
+    fn relocations_are_deterministic() {
+        let link = || {
+            let mut linker = linker();
+            let mut pointers = Segment::new();
+            for label in ["a", "b", "c", "d", "e", "f", "g", "h"] {
+                pointers.label(label);
+                pointers.append_reference(label, ReferenceFormat::Abs64);
+            }
+            linker.add_segment("pointers", PF_R | PF_W, 1 << 12, pointers);
+            linker.position_independent();
+
+            let mut bytes = Vec::new();
+            linker.finish().write(&mut bytes).unwrap();
+            bytes
+        };
+        assert_eq!(link(), link());
+    }
+
+    #[test]
+    fn program_headers_are_loaded() {
+        let mut linker = linker();
+        linker.load_program_headers();
+        let linked = linker.finish();
+
+        let phdr = &linked.program_headers[0];
+        let load = &linked.program_headers[1];
+        assert_eq!(phdr.p_type, PT_PHDR);
+        assert_eq!(load.p_type, PT_LOAD);
+        assert_eq!(load.p_offset, 0);
+        assert!(load.p_vaddr <= phdr.p_vaddr);
+        assert!(phdr.p_vaddr + phdr.p_memsz <= load.p_vaddr + load.p_memsz);
+        assert_eq!(phdr.p_memsz, 4 * PROGRAM_HEADER_SIZE as u64);
+
+        for header in &linked.program_headers {
+            assert_eq!(header.p_offset % 4096, header.p_vaddr % 4096);
+        }
+    }
+
+    #[test]
+    fn validate() {
+        let mut with_headers = linker();
+        with_headers.load_program_headers();
+        assert!(with_headers.finish().validate().is_ok());
+
+        let invalid = |corrupt: fn(&mut Linked)| {
+            let mut linked = linker().finish();
+            corrupt(&mut linked);
+            assert!(linked.write(&mut Vec::new()).is_err());
+            linked.validate().unwrap_err().to_string()
+        };
+        assert!(invalid(|linked| linked.file_header.e_phnum += 1).contains("program headers"));
+        assert!(invalid(|linked| linked.program_headers[1].p_offset += 1).contains("congruent"));
+        assert!(invalid(|linked| {
+            linked.program_headers[1].p_align = 1;
+            linked.program_headers[1].p_vaddr = linked.program_headers[0].p_vaddr;
+        })
+        .contains("overlap in memory"));
+        assert!(invalid(|linked| linked.program_headers[1].p_flags = PF_R).contains("entry point"));
+    }
+
+    #[test]
+    fn segments_start_on_new_pages() {
+        let mut linker = linker();
+        let mut big = Segment::new();
+        big.align(16);
+        big.append(&[0u8; 3]);
+        linker.add_segment("big", PF_R, 1 << 21, big);
+        let linked = linker.finish();
+
+        let mut previous_end = 0;
+        for header in &linked.program_headers {
+            assert_eq!(
+                header.p_offset % header.p_align,
+                header.p_vaddr % header.p_align
+            );
+            assert!(header.p_vaddr / 4096 > previous_end / 4096);
+            previous_end = header.p_vaddr + header.p_memsz;
+        }
+        assert_eq!(linked.program_headers[2].p_offset % 16, 0);
+    }
+
+    #[test]
+    fn gate_offset_reference() {
+        let mut segment = Segment::new();
+        segment.reference("handler", ReferenceFormat::GateOffset);
+        segment.append(&[0xaau8; 16]);
+        let exports = BTreeMap::from([(Label("handler"), 0xffff_ffff_8012_3456)]);
+        let mut relocations = Vec::new();
+        resolve_references(
+            0x1000,
+            0x1000,
+            &mut segment,
+            &exports,
+            &[],
+            &mut relocations,
+        );
+
+        assert_eq!(
+            segment.bytes(),
+            [
+                0x56, 0x34, 0xaa, 0xaa, 0xaa, 0xaa, 0x12, 0x80, 0xff, 0xff, 0xff, 0xff, 0xaa, 0xaa,
+                0xaa, 0xaa
+            ]
+        );
+        assert!(relocations.is_empty());
+    }
+
+    #[test]
+    fn physical_reference() {
+        let mut segment = Segment::new();
+        segment.reference("table", ReferenceFormat::Phys64);
+        segment.append(&0x3u64);
+        segment.label("table");
+        segment.append(&0u64);
+        let mut relocations = Vec::new();
+        resolve_references(
+            0xffff_8000_0000_1000,
+            0x20_0000,
+            &mut segment,
+            &BTreeMap::new(),
+            &[],
+            &mut relocations,
+        );
+
+        assert_eq!(segment.bytes()[..8], 0x20_000bu64.to_le_bytes());
+        assert!(relocations.is_empty());
+    }
+
+    #[test]
+    fn fixed_segment_address() {
+        let mut linker = linker();
+        let mut trampoline = Segment::new();
+        trampoline.append(&[0x90u8; 8]);
+        let id = linker.add_segment("trampoline", PF_R | PF_X, 1 << 12, trampoline);
+        linker.set_segment_address(id, 0x8000);
+        let mut after = Segment::new();
+        after.append(&0u64);
+        linker.add_segment("after", PF_R, 1 << 12, after);
+        let linked = linker.finish();
+
+        let headers = &linked.program_headers;
+        assert_eq!(headers[2].p_vaddr, 0x8000);
+        assert_eq!(headers[2].p_offset % 4096, 0);
+        // The following segment continues after the higher-half segments.
+        assert!(headers[3].p_vaddr > headers[1].p_vaddr);
+    }
+
+    #[test]
+    #[should_panic(expected = "overlaps")]
+    fn overlapping_fixed_segments() {
+        let mut linker = linker();
+        let mut first = Segment::new();
+        first.append(&[0u8; 32]);
+        let first = linker.add_segment("first", PF_R, 1 << 12, first);
+        linker.set_segment_address(first, 0x8000);
+        let mut second = Segment::new();
+        second.append(&[0u8; 32]);
+        let second = linker.add_segment("second", PF_R, 1 << 12, second);
+        linker.set_segment_address(second, 0x8010);
+        linker.finish();
+    }
+
+    #[test]
+    fn relro_segment() {
+        let mut linker = linker();
+        let mut table = Segment::new();
+        table.append(&[0u8; 24]);
+        let id = linker.add_segment("table", PF_R | PF_W, 1 << 12, table);
+        linker.set_relro(id);
+        let linked = linker.finish();
+
+        let load = &linked.program_headers[2];
+        let relro = linked.program_headers.last().unwrap();
+        assert_eq!(relro.p_type, PT_GNU_RELRO);
+        assert_eq!(relro.p_vaddr, load.p_vaddr);
+        assert_eq!(relro.p_offset, load.p_offset);
+        assert_eq!((relro.p_vaddr + relro.p_memsz) % 4096, 0);
+    }
+
+    #[test]
+    fn embedded_segment() {
+        let mut font = Segment::new();
+        font.align(16);
+        font.export_label("font");
+        font.append(&[0xffu8; 16]);
+        font.label("font_table");
+        font.append_reference("font", ReferenceFormat::Abs64);
+
+        let mut rodata = Segment::new();
+        rodata.append(&[1u8; 3]);
+        assert_eq!(rodata.embed(font), 16);
+        assert_eq!(rodata.alignment, 16);
+        assert_eq!(rodata.labels[&Label("font")].offset, 16);
+        assert_eq!(rodata.labels[&Label("font_table")].offset, 32);
+        assert_eq!(
+            rodata.labels[&Label("font")].visibility,
+            Visibility::Exported
+        );
+        assert_eq!(rodata.references[&Label("font")][0].location, 32);
+    }
+
+    #[test]
+    fn segment_queries() {
+        let mut linker = linker();
+        let data = linker.segment_id("data").unwrap();
+        linker.export_segment_bounds(data, "data_start", "data_end");
+        let linked = linker.finish();
+
+        let start = linked.segment_vaddr("data").unwrap();
+        let size = linked.segment_size("data").unwrap();
+        assert_eq!(linked.label_address("data_start"), Some(start));
+        assert_eq!(linked.label_address("data_end"), Some(start + size));
+        assert_eq!(linked.label_segment("entry"), Some("code"));
+        assert_eq!(linked.label_segment("pointer"), Some("data"));
+        assert_eq!(linked.label_segment("loop"), Some("data"));
+        assert_eq!(linked.label_segment("missing"), None);
+        assert_eq!(linked.any_label_address("loop"), Some(start + 8));
+    }
+
+    #[test]
+    #[should_panic(expected = "\"entry\" in \"code\" at offset 0x0 and in \"more\" at offset 0x4")]
+    fn duplicate_export() {
+        let mut linker = linker();
+        let mut more = Segment::new();
+        more.append(&0u32);
+        more.export_label("entry");
+        linker.add_segment("more", PF_R, 1 << 12, more);
+        linker.finish();
+    }
+
+    #[test]
+    #[should_panic(expected = "shadows label exported by \"code\"")]
+    fn local_shadowing_rejected() {
+        let mut linker = linker();
+        let mut more = Segment::new();
+        more.label("entry");
+        linker.add_segment("more", PF_R, 1 << 12, more);
+        linker.finish();
+    }
+
+    #[test]
+    fn local_shadowing_allowed() {
+        let mut linker = linker();
+        let mut more = Segment::new();
+        more.label("entry");
+        linker.add_segment("more", PF_R, 1 << 12, more);
+        linker.allow_local_shadowing();
+        linker.finish();
+    }
+
+    #[test]
+    fn link_assertions_pass() {
+        let mut linker = linker();
+        let code = linker.segment_id("code").unwrap();
+        linker.export_segment_bounds(code, "code_start", "code_end");
+        linker.assert_below("entry", 0xffffffff_c0000000);
+        linker.assert_aligned("entry", 16);
+        linker.assert_size_le("code_start", "code_end", 16);
+        linker.finish();
+    }
+
+    #[test]
+    #[should_panic(expected = "\"entry\" at 0xffffffff800010d0 is not below 0x100000")]
+    fn link_assertion_below() {
+        let mut linker = linker();
+        linker.assert_below("entry", 0x100000);
+        linker.finish();
+    }
+
+    #[test]
+    #[should_panic(expected = "is larger than 0xf bytes")]
+    fn link_assertion_size() {
+        let mut linker = linker();
+        let code = linker.segment_id("code").unwrap();
+        linker.export_segment_bounds(code, "code_start", "code_end");
+        linker.assert_size_le("code_start", "code_end", 15);
+        linker.finish();
+    }
+
+    #[test]
+    fn multiboot2_header_first() {
+        let mut linker = linker();
+        linker.set_physical_base(0x200000);
+        let code = linker.segment_id("code").unwrap();
+        linker.set_segment_address(code, 0x100000);
+        let mut header = multiboot2::HeaderBuilder::new(multiboot2::ARCHITECTURE_I386);
+        header.entry_address(0, "entry");
+        linker.set_multiboot2_header(header.finish());
+        let linked = linker.finish();
+
+        let segment = &linked.segments[0];
+        assert_eq!(segment.name, "multiboot2");
+        assert_eq!(segment.header.p_offset % 8, 0);
+        assert!(segment.header.p_offset < multiboot2::SEARCH);
+        assert_eq!(
+            segment.header.p_paddr,
+            0x200000 + segment.header.p_vaddr % 4096
+        );
+        assert_eq!(segment.data[..4], multiboot2::HEADER_MAGIC.to_le_bytes());
+        // Entry address tag.
+        assert_eq!(segment.data[24..28], 0x100000u32.to_le_bytes());
+    }
+
+    #[test]
+    fn multiboot2_entry_is_physical() {
+        let mut linker = linker();
+        linker.set_base_address(0xffff_ffff_8000_0000);
+        linker.set_physical_base(0x200000);
+        let mut header = multiboot2::HeaderBuilder::new(multiboot2::ARCHITECTURE_I386);
+        header.entry_address(0, "entry");
+        linker.set_multiboot2_header(header.finish());
+        let linked = linker.finish();
+
+        let code = linked.segment("code").unwrap();
+        let entry = linked.label_address("entry").unwrap();
+        let physical_entry = code.header.p_paddr + (entry - code.header.p_vaddr);
+        assert_ne!(physical_entry, entry);
+        assert_eq!(physical_entry, linked.physical_entry());
+        let segment = &linked.segments[0];
+        assert_eq!(
+            segment.data[24..28],
+            u32::try_from(physical_entry).unwrap().to_le_bytes()
+        );
+    }
+
+    #[test]
+    fn local_labels_are_per_segment() {
+        let mut bytes = Vec::new();
+        linker().finish().write(&mut bytes).unwrap();
+
+        // The JMP in the code segment must resolve to its own `loop` label
+        // (jumping to itself), not to the one in the data segment.
+        let jmp = bytes.iter().rposition(|&b| b == 0xe9).unwrap();
+        assert_eq!(bytes[jmp + 1..][..4], (-5i32).to_le_bytes());
+    }
+}
@@ -0,0 +1,863 @@
+//! Labels, references between them, and the segments of code and data that
+//! define and patch them, which the linker places in an image.
+
+use crate::{dwarf::Type, elf64::common::Addr, x86::descriptor::IdtGate};
+use bytemuck::Pod;
+use std::{
+    cell::RefCell,
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap},
+    fmt,
+    panic::Location,
+    sync::{Arc, LazyLock, RwLock},
+};
+
+// The linker, which places segments in an image and resolves the
+// references between them.
+#[cfg(feature = "image")]
+mod elf;
+
+#[cfg(feature = "image")]
+pub use self::elf::*;
+
+/// A label, interned so that it is cheap to copy, compare and hash, and
+/// doesn't borrow its name: `Label(&format!("isr_{}", n))` is as good as
+/// `Label("halt")`.
+///
+/// Labels are ordered by name, so that tables keyed by labels, and the
+/// images built from them, don't depend on the order of interning.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Label {
+    id: u32,
+}
+
+/// The label named `name`.
+#[allow(non_snake_case)]
+pub fn Label(name: &str) -> Label {
+    Label::new(name)
+}
+
+impl From<&str> for Label {
+    fn from(name: &str) -> Self {
+        Label::new(name)
+    }
+}
+
+impl From<&String> for Label {
+    fn from(name: &String) -> Self {
+        Label::new(name)
+    }
+}
+
+/// The names of labels, indexed by ID, and the IDs of names.
+#[derive(Default)]
+struct Names {
+    names: Vec<Arc<str>>,
+    ids: HashMap<Arc<str>, Label>,
+}
+
+/// The names of all labels. Labels are made by instruction operands, which
+/// are `Copy` values built before any assembler sees them, as well as by
+/// assemblers and linkers, and a label can be held by any of them, so there
+/// is one table for the process rather than one per assembler.
+///
+/// The table owns each distinct name once. Names made by
+/// [`fresh_label`](crate::asm::AssemblerCore::fresh_label) are numbered per
+/// assembler, so they repeat across assemblers, and the table only grows
+/// with the labels of the largest one rather than with every call.
+static LABELS: LazyLock<RwLock<Names>> = LazyLock::new(Default::default);
+
+thread_local! {
+    /// The labels that this thread has seen, so that interning a name again,
+    /// as most labels are, and looking up names to order labels take no
+    /// lock, and segments built in parallel don't wait on each other. The
+    /// names are a prefix of those of the process.
+    static SEEN: RefCell<Names> = RefCell::default();
+}
+
+impl Label {
+    pub fn new(name: &str) -> Self {
+        SEEN.with_borrow_mut(|seen| {
+            if let Some(&label) = seen.ids.get(name) {
+                return label;
+            }
+            let label = Self::intern(name);
+            seen.sync(label);
+            let name = seen.names[label.index()].clone();
+            seen.ids.insert(name, label);
+            label
+        })
+    }
+
+    /// Look up or add `name` in the table of the process.
+    fn intern(name: &str) -> Self {
+        if let Some(&label) = LABELS.read().unwrap().ids.get(name) {
+            return label;
+        }
+        let mut labels = LABELS.write().unwrap();
+        if let Some(&label) = labels.ids.get(name) {
+            return label;
+        }
+        let id = u32::try_from(labels.names.len()).expect("too many labels");
+        let label = Self { id };
+        let name: Arc<str> = name.into();
+        labels.names.push(name.clone());
+        labels.ids.insert(name, label);
+        label
+    }
+
+    fn index(self) -> usize {
+        self.id as usize
+    }
+
+    /// Call `f` with the names of labels, which include those of `labels`.
+    fn with_names<R>(labels: &[Label], f: impl FnOnce(&[Arc<str>]) -> R) -> R {
+        SEEN.with_borrow_mut(|seen| {
+            for &label in labels {
+                seen.sync(label);
+            }
+            f(&seen.names)
+        })
+    }
+
+    pub fn name(&self) -> Arc<str> {
+        Self::with_names(&[*self], |names| names[self.index()].clone())
+    }
+
+    /// The memory operand at this label.
+    pub fn ptr(self) -> Ptr {
+        Ptr { label: self }
+    }
+}
+
+impl Names {
+    /// Copy the names of the process up to that of `label`, if this thread
+    /// hasn't seen them yet.
+    fn sync(&mut self, label: Label) {
+        if label.index() >= self.names.len() {
+            let labels = LABELS.read().unwrap();
+            self.names
+                .extend_from_slice(&labels.names[self.names.len()..]);
+        }
+    }
+}
+
+impl PartialOrd for Label {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Label {
+    fn cmp(&self, other: &Self) -> Ordering {
+        if self.id == other.id {
+            Ordering::Equal
+        } else {
+            Self::with_names(&[*self, *other], |names| {
+                names[self.index()].cmp(&names[other.index()])
+            })
+        }
+    }
+}
+
+impl fmt::Debug for Label {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Label({:?})", self.name())
+    }
+}
+
+impl fmt::Display for Label {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name())
+    }
+}
+
+/// A memory operand at a label.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Ptr {
+    label: Label,
+}
+
+/// The memory operand at the label named `name`.
+#[allow(non_snake_case)]
+pub fn Ptr(name: &str) -> Ptr {
+    Label::new(name).ptr()
+}
+
+impl Ptr {
+    pub fn label(&self) -> Label {
+        self.label
+    }
+}
+
+impl fmt::Debug for Ptr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Ptr({:?})", self.label.name())
+    }
+}
+
+pub struct Reference {
+    pub location: usize,
+    pub format: ReferenceFormat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReferenceFormat {
+    /// A signed 32-bit relative offset from the end of the reference.
+    /// Used in near-JMP and branching instructions.
+    Rel32,
+
+    /// A signed 16-bit relative offset from the end of the reference.
+    /// Used in near-JMP and branching instructions of real-mode code.
+    Rel16,
+
+    /// An absolute 64-bit address.
+    Abs64,
+
+    /// An absolute 32-bit address. The target must lie in the low 4GiB.
+    ///
+    /// Not supported in position-independent executables.
+    Abs32,
+
+    /// An absolute 16-bit address, as used by real-mode code. The target
+    /// must lie in the low 64KiB.
+    ///
+    /// Not supported in position-independent executables.
+    Abs16,
+
+    /// An absolute 64-bit address split across the offset fields of a
+    /// 16-byte IDT gate descriptor: bits 15..0 at byte 0, bits 31..16 at
+    /// byte 6 and bits 63..32 at byte 8. The other bytes of the gate are
+    /// left unchanged, so the reference is made to a gate that has already
+    /// been appended.
+    ///
+    /// Not supported in position-independent executables.
+    GateOffset,
+
+    /// The physical address of a label in the same segment, ORed into the
+    /// 64-bit value already at the location, so that it can hold flags in
+    /// the low bits, as in a page table entry.
+    ///
+    /// Not supported in position-independent executables.
+    Phys64,
+
+    /// The physical address of a label, as an absolute 32-bit address, e.g.
+    /// for a bootloader that jumps to it with paging disabled. The target
+    /// must lie in the low 4GiB of physical memory.
+    ///
+    /// Not supported in position-independent executables.
+    Phys32,
+}
+
+impl ReferenceFormat {
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Rel32 => 4,
+            Self::Rel16 => 2,
+            Self::Abs64 => 8,
+            Self::Abs32 => 4,
+            Self::Abs16 => 2,
+            Self::GateOffset => 16,
+            Self::Phys64 => 8,
+            Self::Phys32 => 4,
+        }
+    }
+
+    /// The value to store for a reference at virtual address `location` to
+    /// the address `target`, or `None` if the target is out of range.
+    ///
+    /// `location` is the address of the first byte of the reference.
+    /// `target` is a virtual address, except for `Phys64` and `Phys32`,
+    /// where it is a physical address.
+    pub fn resolve(&self, location: Addr, target: Addr) -> Option<u64> {
+        match self {
+            //FIXME This assumes that the rel32 operand is at the end of the
+            // instruction.
+            Self::Rel32 => {
+                let relative_to = location.checked_add(4)?;
+                let offset = i32::try_from(target as i128 - relative_to as i128).ok()?;
+                Some(offset as u32 as u64)
+            }
+            Self::Rel16 => {
+                let relative_to = location.checked_add(2)?;
+                let offset = i16::try_from(target as i128 - relative_to as i128).ok()?;
+                Some(offset as u16 as u64)
+            }
+            Self::Abs32 | Self::Phys32 => u32::try_from(target).ok().map(u64::from),
+            Self::Abs16 => u16::try_from(target).ok().map(u64::from),
+            Self::Abs64 | Self::GateOffset | Self::Phys64 => Some(target),
+        }
+    }
+
+    /// Store a value returned by [`resolve`](Self::resolve) into `field`,
+    /// which holds the `len()` bytes of the reference.
+    pub fn patch(&self, field: &mut [u8], value: u64) {
+        assert_eq!(field.len(), self.len());
+        match self {
+            Self::Rel32 | Self::Abs32 | Self::Phys32 => {
+                field.copy_from_slice(&(value as u32).to_le_bytes())
+            }
+            Self::Rel16 | Self::Abs16 => field.copy_from_slice(&(value as u16).to_le_bytes()),
+            Self::Abs64 => field.copy_from_slice(&value.to_le_bytes()),
+            Self::GateOffset => {
+                field[IdtGate::OFFSET_LOW..][..2].copy_from_slice(&(value as u16).to_le_bytes());
+                field[IdtGate::OFFSET_MID..][..2]
+                    .copy_from_slice(&((value >> 16) as u16).to_le_bytes());
+                field[IdtGate::OFFSET_HIGH..][..4]
+                    .copy_from_slice(&((value >> 32) as u32).to_le_bytes());
+            }
+            Self::Phys64 => {
+                let flags = u64::from_le_bytes(field.try_into().unwrap());
+                field.copy_from_slice(&(flags | value).to_le_bytes());
+            }
+        }
+    }
+}
+
+/// Controls which references a label can be resolved from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Visibility {
+    /// Only visible to references within the same segment.
+    Local,
+
+    /// Visible to references from any segment. Exported labels share a
+    /// single namespace across all segments.
+    Exported,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LabelDefinition {
+    pub offset: usize,
+    pub visibility: Visibility,
+}
+
+pub struct Segment {
+    pub(crate) alignment: usize,
+    pub(crate) fill: u8,
+    pub(crate) data: Vec<u8>,
+    /// Zero-initialized bytes following the data, which take up memory but
+    /// no space in the file.
+    pub(crate) reserved: usize,
+    // Ordered, so that relocation tables and diagnostics don't depend on
+    // hashing.
+    pub(crate) labels: BTreeMap<Label, LabelDefinition>,
+    pub(crate) references: BTreeMap<Label, Vec<Reference>>,
+    /// The source location of the generator code that emitted the data from
+    /// each offset, in order of offset, for debugging information.
+    pub(crate) locations: Vec<(usize, &'static Location<'static>)>,
+    /// Comments on the data from each offset, in order of offset, for
+    /// listings and reports.
+    pub(crate) comments: Vec<(usize, String)>,
+    /// The types of the data at labels, for debugging information.
+    pub(crate) types: Vec<(Label, Type)>,
+}
+
+impl Segment {
+    pub fn new() -> Self {
+        Self {
+            alignment: 1,
+            fill: 0,
+            data: Vec::new(),
+            reserved: 0,
+            labels: BTreeMap::new(),
+            references: BTreeMap::new(),
+            locations: Vec::new(),
+            comments: Vec::new(),
+            types: Vec::new(),
+        }
+    }
+
+    /// An empty segment with room for `capacity` bytes of data, e.g. for a
+    /// large blob of known size, so that appending it doesn't reallocate.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            data: Vec::with_capacity(capacity),
+            ..Self::new()
+        }
+    }
+
+    /// Make room for at least `additional` more bytes of data.
+    pub fn reserve_capacity(&mut self, additional: usize) {
+        self.data.reserve(additional);
+    }
+
+    pub fn align(&mut self, alignment: usize) {
+        assert!(alignment.is_power_of_two());
+        self.alignment = self.alignment.max(alignment);
+    }
+
+    /// Set the byte used to pad the segment in
+    /// [`pad_to_alignment`](Self::pad_to_alignment).
+    ///
+    /// Defaults to zero. Code segments may prefer `0xcc` (INT3), so that
+    /// execution running into padding faults immediately.
+    pub fn set_fill(&mut self, fill: u8) {
+        self.fill = fill;
+    }
+
+    /// Pad the segment with its fill byte, so that the next byte appended is
+    /// aligned to `alignment` (assuming the start of the segment is aligned
+    /// at least as strictly).
+    pub fn pad_to_alignment(&mut self, alignment: usize) {
+        self.align(alignment);
+        let padding = self.len().next_multiple_of(alignment) - self.len();
+        if self.reserved > 0 {
+            self.reserved += padding;
+        } else {
+            self.data.extend(std::iter::repeat_n(self.fill, padding));
+        }
+    }
+
+    /// Reserve zero-initialized space at the end of the segment, like
+    /// `.bss`. It occupies memory but not space in the file.
+    ///
+    /// No more data can be appended after reserving space.
+    pub fn reserve(&mut self, size: usize) {
+        self.reserved += size;
+    }
+
+    /// Define a label at the current position, visible only within this
+    /// segment.
+    pub fn label(&mut self, label: impl Into<Label>) {
+        self.offset_label(0, label);
+    }
+
+    pub fn offset_label(&mut self, offset: usize, label: impl Into<Label>) {
+        self.define_label(offset, label, Visibility::Local);
+    }
+
+    /// Define a label at the current position, visible to references from
+    /// all segments.
+    pub fn export_label(&mut self, label: impl Into<Label>) {
+        self.export_offset_label(0, label);
+    }
+
+    pub fn export_offset_label(&mut self, offset: usize, label: impl Into<Label>) {
+        self.define_label(offset, label, Visibility::Exported);
+    }
+
+    fn define_label(&mut self, offset: usize, label: impl Into<Label>, visibility: Visibility) {
+        self.define_label_at(self.len() + offset, label.into(), visibility);
+    }
+
+    fn define_label_at(&mut self, offset: usize, label: Label, visibility: Visibility) {
+        if let Some(previous) = self
+            .labels
+            .insert(label, LabelDefinition { offset, visibility })
+        {
+            panic!(
+                "duplicate label {:?} at offset {:#x} (previously defined at offset {:#x})",
+                label, offset, previous.offset
+            );
+        }
+    }
+
+    /// The current size of the segment in bytes, including reserved space.
+    pub fn len(&self) -> usize {
+        self.data.len() + self.reserved
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The contents of the segment, with references not yet resolved.
+    ///
+    /// Does not include reserved space.
+    pub fn bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn append<T: Pod>(&mut self, val: &T) {
+        // TODO build our own "POD" abstraction; bytemuck isn't useful if
+        // we're compiling for a machine with different endianness
+        self.extend(bytemuck::bytes_of(val).iter().copied());
+    }
+
+    pub fn append_reference(&mut self, label: impl Into<Label>, format: ReferenceFormat) {
+        self.reference(label, format);
+        self.extend(std::iter::repeat(0u8).take(format.len()));
+    }
+
+    pub fn extend(&mut self, bytes: impl IntoIterator<Item = u8>) {
+        assert!(
+            self.reserved == 0,
+            "cannot append data after reserved space"
+        );
+        self.data.extend(bytes);
+    }
+
+    /// The data, for appending to it in place, e.g. to encode instructions
+    /// without copying them. Like [`extend`](Self::extend), it can't be
+    /// appended to after reserved space.
+    pub(crate) fn data_mut(&mut self) -> &mut Vec<u8> {
+        assert!(
+            self.reserved == 0,
+            "cannot append data after reserved space"
+        );
+        &mut self.data
+    }
+
+    /// Append the contents of another segment, aligned according to its
+    /// alignment requirement.
+    ///
+    /// The labels and references of the embedded segment are merged into
+    /// this one, keeping their visibility, with their offsets rebased. Its
+    /// reserved space is kept as well, so nothing more can be appended after
+    /// embedding a segment with reserved space.
+    ///
+    /// Returns the offset of the embedded segment within this one.
+    pub fn embed(&mut self, other: Segment) -> usize {
+        self.pad_to_alignment(other.alignment);
+        let base = self.len();
+        self.extend(other.data);
+        self.reserved = other.reserved;
+
+        for (label, definition) in other.labels {
+            self.define_label_at(base + definition.offset, label, definition.visibility);
+        }
+        for (label, references) in other.references {
+            self.references
+                .entry(label)
+                .or_default()
+                .extend(references.into_iter().map(|reference| Reference {
+                    location: base + reference.location,
+                    ..reference
+                }));
+        }
+        self.locations.extend(
+            other
+                .locations
+                .into_iter()
+                .map(|(offset, location)| (base + offset, location)),
+        );
+        self.comments.extend(
+            other
+                .comments
+                .into_iter()
+                .map(|(offset, text)| (base + offset, text)),
+        );
+        self.types.extend(other.types);
+        base
+    }
+
+    /// Rename the local labels of the segment, and the references to them,
+    /// to `scope:label`, so that segments assembled from different sources
+    /// can be [embedded](Self::embed) into one without their local labels
+    /// clashing.
+    pub fn scope_local_labels(&mut self, scope: &str) {
+        let renamed: HashMap<Label, Label> = self
+            .labels
+            .iter()
+            .filter(|(_, definition)| definition.visibility == Visibility::Local)
+            .map(|(&label, _)| (label, Label(&format!("{}:{}", scope, label.name()))))
+            .collect();
+        let rename = |label: Label| renamed.get(&label).copied().unwrap_or(label);
+        self.labels = std::mem::take(&mut self.labels)
+            .into_iter()
+            .map(|(label, definition)| (rename(label), definition))
+            .collect();
+        self.references = std::mem::take(&mut self.references)
+            .into_iter()
+            .map(|(label, references)| (rename(label), references))
+            .collect();
+        for (label, _) in &mut self.types {
+            *label = rename(*label);
+        }
+    }
+
+    /// Attach a comment to the data appended next, e.g. to explain a
+    /// sequence of instructions in listings of the linked image. A comment
+    /// is a single line of text.
+    pub fn comment(&mut self, text: &str) {
+        assert!(!text.contains('\n'), "comment {:?} is not one line", text);
+        self.comments.push((self.len(), text.to_owned()));
+    }
+
+    /// Describe the data at `label`, which must be defined in this segment,
+    /// as a value of type `ty` to debuggers, e.g. `<[IdtGate; 256]>::
+    /// debug_type()`.
+    pub fn label_type(&mut self, label: impl Into<Label>, ty: Type) {
+        self.types.push((label.into(), ty));
+    }
+
+    /// Attribute the data appended from here on to `location`, until the
+    /// next location is set.
+    pub(crate) fn set_location(&mut self, location: &'static Location<'static>) {
+        let offset = self.len();
+        match self.locations.last_mut() {
+            Some(last) if last.1 == location => {}
+            // Nothing was appended since the last location was set.
+            Some(last) if last.0 == offset => last.1 = location,
+            _ => self.locations.push((offset, location)),
+        }
+    }
+
+    pub fn reference(&mut self, label: impl Into<Label>, format: ReferenceFormat) {
+        self.offset_reference(0, label, format);
+    }
+
+    pub fn offset_reference(
+        &mut self,
+        offset: usize,
+        label: impl Into<Label>,
+        format: ReferenceFormat,
+    ) {
+        self.label_reference(offset, label.into(), format);
+    }
+
+    /// Like [`offset_reference`](Self::offset_reference), with an interned
+    /// label, e.g. from an instruction operand.
+    pub(crate) fn label_reference(&mut self, offset: usize, label: Label, format: ReferenceFormat) {
+        assert!(self.reserved == 0, "cannot reference from reserved space");
+        self.references
+            .entry(label)
+            .or_insert(Vec::new())
+            .push(Reference {
+                location: self.data.len() + offset,
+                format,
+            });
+    }
+}
+
+/// Build independent segments, e.g. one per module of the generator, and
+/// return them in order. With the `parallel` feature, each is built on its
+/// own thread.
+pub fn build_segments<F>(builders: Vec<F>) -> Vec<Segment>
+where
+    F: FnOnce() -> Segment + Send,
+{
+    #[cfg(feature = "parallel")]
+    {
+        use rayon::prelude::*;
+        builders.into_par_iter().map(|build| build()).collect()
+    }
+    #[cfg(not(feature = "parallel"))]
+    {
+        builders.into_iter().map(|build| build()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn labels_are_interned() {
+        let name = format!("isr_{}", 3);
+        assert_eq!(Label(&name), Label("isr_3"));
+        assert_ne!(Label("isr_3"), Label("isr_4"));
+        assert_eq!(&*Label(&name).name(), "isr_3");
+        assert_eq!(Ptr(&name).label(), Label("isr_3"));
+        // Ordered by name, not by order of interning.
+        assert!(Label("interned_z") > Label("interned_a"));
+    }
+
+    #[test]
+    fn labels_are_shared_between_threads() {
+        let here = Label("shared_between_threads");
+        let there = std::thread::spawn(|| {
+            let label = Label(&format!("shared_{}_threads", "between"));
+            (label, Label("only_there"))
+        })
+        .join()
+        .unwrap();
+        assert_eq!(there.0, here);
+        assert_eq!(&*there.0.name(), "shared_between_threads");
+        assert_eq!(Label("only_there"), there.1);
+        // Labels made by other threads are ordered by name here too.
+        assert!(there.1 < here);
+    }
+
+    #[test]
+    fn scoped_local_labels_do_not_clash() {
+        let file = |scope| {
+            let mut segment = Segment::new();
+            segment.label("loop");
+            segment.append_reference("loop", ReferenceFormat::Abs64);
+            segment.append_reference("shared", ReferenceFormat::Abs64);
+            segment.scope_local_labels(scope);
+            segment
+        };
+        let mut segment = file("a.s");
+        segment.export_label("shared");
+        assert_eq!(segment.embed(file("b.s")), 16);
+
+        let offsets = |label| {
+            let references = &segment.references[&Label(label)];
+            references.iter().map(|r| r.location).collect::<Vec<_>>()
+        };
+        assert_eq!(segment.labels[&Label("a.s:loop")].offset, 0);
+        assert_eq!(segment.labels[&Label("b.s:loop")].offset, 16);
+        assert_eq!(offsets("a.s:loop"), [0]);
+        assert_eq!(offsets("b.s:loop"), [16]);
+        // References to labels defined elsewhere keep their names.
+        assert_eq!(offsets("shared"), [8, 24]);
+        assert!(!segment.labels.contains_key(&Label("loop")));
+    }
+
+    #[test]
+    fn build_segments_keeps_order() {
+        let builders: Vec<_> = (0..16u8)
+            .map(|i| {
+                move || {
+                    let mut segment = Segment::new();
+                    segment.append(&i);
+                    segment
+                }
+            })
+            .collect();
+        let bytes: Vec<u8> = build_segments(builders)
+            .iter()
+            .map(|segment| segment.bytes()[0])
+            .collect();
+        assert_eq!(bytes, (0..16).collect::<Vec<u8>>());
+    }
+
+    /// Addresses near the ends of the ranges of the reference formats, and
+    /// of the address space, as well as arbitrary ones.
+    fn address() -> impl Strategy<Value = Addr> {
+        prop_oneof![
+            any::<Addr>(),
+            0..0x100u64,
+            0xff00..0x1_0100u64,
+            0xffff_ff00..0x1_0000_0100u64,
+            Addr::MAX - 0xff..=Addr::MAX,
+        ]
+    }
+
+    /// Distances from the end of a Rel32 reference to its target, near the
+    /// ends of its range, as well as arbitrary ones.
+    fn distance() -> impl Strategy<Value = i64> {
+        prop_oneof![
+            any::<i32>().prop_map(i64::from),
+            i32::MIN as i64 - 0x100..i32::MIN as i64 + 0x100,
+            i32::MAX as i64 - 0x100..i32::MAX as i64 + 0x100,
+            any::<i64>(),
+        ]
+    }
+
+    fn resolve_and_patch(
+        format: ReferenceFormat,
+        field: &mut [u8],
+        location: Addr,
+        target: Addr,
+    ) -> bool {
+        match format.resolve(location, target) {
+            Some(value) => {
+                format.patch(field, value);
+                true
+            }
+            None => false,
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn rel32_reaches_target(location in address(), distance in distance()) {
+            let end = location.checked_add(4);
+            prop_assume!(end.is_some());
+            let end = end.unwrap();
+            let target = u64::try_from(end as i128 + distance as i128);
+            prop_assume!(target.is_ok());
+            let target = target.unwrap();
+
+            let mut field = [0; 4];
+            let in_range = i32::try_from(distance).is_ok();
+            prop_assert_eq!(
+                resolve_and_patch(ReferenceFormat::Rel32, &mut field, location, target),
+                in_range
+            );
+            if in_range {
+                // The displacement is sign-extended and added to the address
+                // of the next instruction.
+                let displacement = i32::from_le_bytes(field) as i64 as u64;
+                prop_assert_eq!(end.wrapping_add(displacement), target);
+            }
+        }
+
+        #[test]
+        fn rel16_reaches_target(location in address(), distance in -0x10000i64..0x10000) {
+            let end = location.checked_add(2);
+            prop_assume!(end.is_some());
+            let end = end.unwrap();
+            let target = u64::try_from(end as i128 + distance as i128);
+            prop_assume!(target.is_ok());
+            let target = target.unwrap();
+
+            let mut field = [0; 2];
+            let in_range = i16::try_from(distance).is_ok();
+            prop_assert_eq!(
+                resolve_and_patch(ReferenceFormat::Rel16, &mut field, location, target),
+                in_range
+            );
+            if in_range {
+                let displacement = i16::from_le_bytes(field) as i64 as u64;
+                prop_assert_eq!(end.wrapping_add(displacement), target);
+            }
+        }
+
+        #[test]
+        fn absolute_reaches_target(location in address(), target in address()) {
+            let mut field = [0; 8];
+            prop_assert!(resolve_and_patch(ReferenceFormat::Abs64, &mut field, location, target));
+            prop_assert_eq!(u64::from_le_bytes(field), target);
+
+            // 32-bit and 16-bit addresses are zero-extended.
+            let mut field = [0; 4];
+            let in_range = target <= u32::MAX as u64;
+            prop_assert_eq!(
+                resolve_and_patch(ReferenceFormat::Abs32, &mut field, location, target),
+                in_range
+            );
+            if in_range {
+                prop_assert_eq!(u32::from_le_bytes(field) as u64, target);
+            }
+            let mut field = [0; 4];
+            prop_assert_eq!(
+                resolve_and_patch(ReferenceFormat::Phys32, &mut field, location, target),
+                in_range
+            );
+            if in_range {
+                prop_assert_eq!(u32::from_le_bytes(field) as u64, target);
+            }
+
+            let mut field = [0; 2];
+            let in_range = target <= u16::MAX as u64;
+            prop_assert_eq!(
+                resolve_and_patch(ReferenceFormat::Abs16, &mut field, location, target),
+                in_range
+            );
+            if in_range {
+                prop_assert_eq!(u16::from_le_bytes(field) as u64, target);
+            }
+        }
+
+        #[test]
+        fn gate_offset_reaches_target(gate in any::<[u8; 16]>(), target in address()) {
+            let mut field = gate;
+            prop_assert!(resolve_and_patch(ReferenceFormat::GateOffset, &mut field, 0, target));
+
+            let patched: &IdtGate = bytemuck::from_bytes(&field);
+            let offset = patched.offset_low as u64
+                | (patched.offset_mid as u64) << 16
+                | (patched.offset_high as u64) << 32;
+            prop_assert_eq!(offset, target);
+            // The rest of the gate is unchanged.
+            let original: &IdtGate = bytemuck::from_bytes(&gate);
+            prop_assert_eq!(
+                (patched.selector, patched.ist, patched.attributes, patched.reserved),
+                (original.selector, original.ist, original.attributes, original.reserved)
+            );
+        }
+
+        #[test]
+        fn phys64_keeps_flags(flags in 0..0x1000u64, target in address()) {
+            let target = target & !0xfff;
+            let mut field = flags.to_le_bytes();
+            prop_assert!(resolve_and_patch(ReferenceFormat::Phys64, &mut field, 0, target));
+            prop_assert_eq!(u64::from_le_bytes(field), target | flags);
+        }
+    }
+}
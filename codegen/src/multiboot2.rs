@@ -0,0 +1,288 @@
+//! Multiboot2 header and boot information structures.
+//!
+//! See the [Multiboot2 specification](https://www.gnu.org/software/grub/manual/multiboot2/multiboot.html).
+
+use bytemuck::{Pod, Zeroable};
+
+use crate::link::{ReferenceFormat, Segment};
+
+/// Magic value identifying the Multiboot2 header.
+pub const HEADER_MAGIC: u32 = 0xe85250d6;
+/// Value passed in EAX by a Multiboot2-compliant bootloader.
+pub const BOOTLOADER_MAGIC: u32 = 0x36d76289;
+
+/// The header must be contained within this many bytes from the start of
+/// the image.
+pub const SEARCH: u64 = 32768;
+/// Required alignment of the header and of each tag.
+pub const HEADER_ALIGN: usize = 8;
+
+/// 32-bit (protected mode) i386.
+pub const ARCHITECTURE_I386: u32 = 0;
+/// 32-bit MIPS.
+pub const ARCHITECTURE_MIPS32: u32 = 4;
+
+pub const HEADER_TAG_END: u16 = 0;
+pub const HEADER_TAG_INFORMATION_REQUEST: u16 = 1;
+pub const HEADER_TAG_ADDRESS: u16 = 2;
+pub const HEADER_TAG_ENTRY_ADDRESS: u16 = 3;
+pub const HEADER_TAG_CONSOLE_FLAGS: u16 = 4;
+pub const HEADER_TAG_FRAMEBUFFER: u16 = 5;
+pub const HEADER_TAG_MODULE_ALIGN: u16 = 6;
+pub const HEADER_TAG_EFI_BS: u16 = 7;
+pub const HEADER_TAG_ENTRY_ADDRESS_EFI32: u16 = 8;
+pub const HEADER_TAG_ENTRY_ADDRESS_EFI64: u16 = 9;
+pub const HEADER_TAG_RELOCATABLE: u16 = 10;
+
+/// The bootloader may ignore the tag if it doesn't support it.
+pub const HEADER_TAG_OPTIONAL: u16 = 1;
+
+pub const TAG_TYPE_END: u32 = 0;
+pub const TAG_TYPE_CMDLINE: u32 = 1;
+pub const TAG_TYPE_BOOT_LOADER_NAME: u32 = 2;
+pub const TAG_TYPE_MODULE: u32 = 3;
+pub const TAG_TYPE_BASIC_MEMINFO: u32 = 4;
+pub const TAG_TYPE_BOOTDEV: u32 = 5;
+pub const TAG_TYPE_MMAP: u32 = 6;
+pub const TAG_TYPE_VBE: u32 = 7;
+pub const TAG_TYPE_FRAMEBUFFER: u32 = 8;
+pub const TAG_TYPE_ELF_SECTIONS: u32 = 9;
+pub const TAG_TYPE_APM: u32 = 10;
+pub const TAG_TYPE_EFI32: u32 = 11;
+pub const TAG_TYPE_EFI64: u32 = 12;
+pub const TAG_TYPE_SMBIOS: u32 = 13;
+pub const TAG_TYPE_ACPI_OLD: u32 = 14;
+pub const TAG_TYPE_ACPI_NEW: u32 = 15;
+pub const TAG_TYPE_NETWORK: u32 = 16;
+pub const TAG_TYPE_EFI_MMAP: u32 = 17;
+pub const TAG_TYPE_EFI_BS: u32 = 18;
+pub const TAG_TYPE_EFI32_IH: u32 = 19;
+pub const TAG_TYPE_EFI64_IH: u32 = 20;
+pub const TAG_TYPE_LOAD_BASE_ADDR: u32 = 21;
+
+pub const MEMORY_AVAILABLE: u32 = 1;
+pub const MEMORY_RESERVED: u32 = 2;
+pub const MEMORY_ACPI_RECLAIMABLE: u32 = 3;
+pub const MEMORY_NVS: u32 = 4;
+pub const MEMORY_BADRAM: u32 = 5;
+
+pub const FRAMEBUFFER_TYPE_INDEXED: u8 = 0;
+pub const FRAMEBUFFER_TYPE_RGB: u8 = 1;
+pub const FRAMEBUFFER_TYPE_EGA_TEXT: u8 = 2;
+
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct Header {
+    pub magic: u32,
+    pub architecture: u32,
+    pub header_length: u32,
+    pub checksum: u32,
+}
+
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct HeaderTag {
+    pub type_: u16,
+    pub flags: u16,
+    pub size: u32,
+}
+
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct FramebufferHeaderTag {
+    pub tag: HeaderTag,
+    pub width: u32,
+    pub height: u32,
+    pub depth: u32,
+}
+
+/// Fixed part of the boot information structure. Tags follow, each aligned
+/// to 8 bytes.
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct BootInformation {
+    pub total_size: u32,
+    pub reserved: u32,
+}
+
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct Tag {
+    pub type_: u32,
+    pub size: u32,
+}
+
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct BasicMemoryInfoTag {
+    pub tag: Tag,
+    /// Kilobytes of lower memory, starting at address 0.
+    pub mem_lower: u32,
+    /// Kilobytes of upper memory, starting at address 1MiB.
+    pub mem_upper: u32,
+}
+
+/// Memory map tag. Entries of `entry_size` bytes follow.
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct MemoryMapTag {
+    pub tag: Tag,
+    pub entry_size: u32,
+    pub entry_version: u32,
+}
+
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct MemoryMapEntry {
+    pub base_addr: u64,
+    pub length: u64,
+    pub type_: u32,
+    pub reserved: u32,
+}
+
+/// Module tag. A null-terminated command line follows.
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct ModuleTag {
+    pub tag: Tag,
+    pub mod_start: u32,
+    pub mod_end: u32,
+}
+
+/// Framebuffer tag. Color information specific to `framebuffer_type`
+/// follows.
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct FramebufferTag {
+    pub tag: Tag,
+    pub framebuffer_addr: u64,
+    pub framebuffer_pitch: u32,
+    pub framebuffer_width: u32,
+    pub framebuffer_height: u32,
+    pub framebuffer_bpp: u8,
+    pub framebuffer_type: u8,
+    pub reserved: u16,
+}
+
+/// ACPI RSDP tag (either version). A copy of the RSDP follows.
+pub type AcpiTag = Tag;
+
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct LoadBaseAddressTag {
+    pub tag: Tag,
+    pub load_base_addr: u32,
+}
+
+/// Convenience builder for a Multiboot2 header segment.
+pub struct HeaderBuilder {
+    architecture: u32,
+    tags: Segment,
+}
+
+impl HeaderBuilder {
+    pub fn new(architecture: u32) -> Self {
+        let mut tags = Segment::new();
+        tags.align(HEADER_ALIGN);
+        Self { architecture, tags }
+    }
+
+    fn tag(&mut self, type_: u16, flags: u16, size: usize) {
+        self.tags.pad_to_alignment(HEADER_ALIGN);
+        self.tags.append(&HeaderTag {
+            type_,
+            flags,
+            size: size.try_into().unwrap(),
+        });
+    }
+
+    /// Request the given boot information tag types from the bootloader.
+    pub fn information_request(&mut self, flags: u16, types: &[u32]) {
+        self.tag(
+            HEADER_TAG_INFORMATION_REQUEST,
+            flags,
+            size_of::<HeaderTag>() + size_of_val(types),
+        );
+        for type_ in types {
+            self.tags.append(type_);
+        }
+    }
+
+    /// Override the ELF entry point with the physical address of `label`.
+    ///
+    /// The label must be loaded in the low 4GiB.
+    pub fn entry_address(&mut self, flags: u16, label: &str) {
+        self.tag(HEADER_TAG_ENTRY_ADDRESS, flags, size_of::<HeaderTag>() + 4);
+        self.tags.append_reference(label, ReferenceFormat::Phys32);
+    }
+
+    /// Request a graphical framebuffer mode. Zero fields mean no preference.
+    pub fn framebuffer(&mut self, flags: u16, width: u32, height: u32, depth: u32) {
+        self.tags.pad_to_alignment(HEADER_ALIGN);
+        self.tags.append(&FramebufferHeaderTag {
+            tag: HeaderTag {
+                type_: HEADER_TAG_FRAMEBUFFER,
+                flags,
+                size: size_of::<FramebufferHeaderTag>() as u32,
+            },
+            width,
+            height,
+            depth,
+        });
+    }
+
+    /// Request that modules be page-aligned.
+    pub fn module_align(&mut self, flags: u16) {
+        self.tag(HEADER_TAG_MODULE_ALIGN, flags, size_of::<HeaderTag>());
+    }
+
+    /// Terminate the tag list and produce the header segment.
+    pub fn finish(mut self) -> Segment {
+        self.tag(HEADER_TAG_END, 0, size_of::<HeaderTag>());
+
+        let header_length = (size_of::<Header>() + self.tags.len()) as u32;
+        let mut header = Segment::new();
+        header.append(&Header {
+            magic: HEADER_MAGIC,
+            architecture: self.architecture,
+            header_length,
+            checksum: 0u32
+                .wrapping_sub(HEADER_MAGIC)
+                .wrapping_sub(self.architecture)
+                .wrapping_sub(header_length),
+        });
+        header.embed(self.tags);
+        header
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn boot_information_layout() {
+        assert_eq!(size_of::<MemoryMapEntry>(), 24);
+        assert_eq!(size_of::<FramebufferTag>(), 32);
+        assert_eq!(std::mem::offset_of!(FramebufferTag, framebuffer_bpp), 28);
+    }
+
+    #[test]
+    fn header_checksum() {
+        let mut builder = HeaderBuilder::new(ARCHITECTURE_I386);
+        builder.information_request(0, &[TAG_TYPE_MMAP, TAG_TYPE_FRAMEBUFFER]);
+        builder.module_align(HEADER_TAG_OPTIONAL);
+        let header = builder.finish();
+
+        let words: Vec<u32> = header
+            .bytes()
+            .chunks(4)
+            .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()))
+            .collect();
+        assert_eq!(words[0], HEADER_MAGIC);
+        assert_eq!(words[2] as usize, header.len());
+        assert_eq!(words[..4].iter().fold(0u32, |a, b| a.wrapping_add(*b)), 0);
+        // Header (16) + request (8 + 8) + module align (8) + end (8)
+        assert_eq!(header.len(), 48);
+    }
+}
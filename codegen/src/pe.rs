@@ -0,0 +1,976 @@
+//! PE32+ image format, as loaded by UEFI firmware.
+//!
+//! See the [PE Format specification](https://learn.microsoft.com/en-us/windows/win32/debug/pe-format).
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, Write},
+};
+
+use bytemuck::{Pod, Zeroable};
+
+use crate::{
+    link::{collect_exports, resolve_all, Label, ReferenceFormat, Segment, Visibility},
+    math::align_up,
+};
+
+/// "MZ"
+pub const DOS_MAGIC: u16 = 0x5a4d;
+/// "PE\0\0"
+pub const PE_SIGNATURE: &[u8; 4] = b"PE\0\0";
+
+/// Real-mode program that prints a message and exits when the image is run
+/// under DOS.
+pub const DOS_STUB: &[u8; 64] = b"\x0e\x1f\xba\x0e\x00\xb4\x09\xcd\x21\xb8\x01\x4c\xcd\x21\
+This program cannot be run in DOS mode.\r\r\n$\0\0\0\0\0\0\0";
+
+pub const IMAGE_FILE_MACHINE_I386: u16 = 0x14c;
+pub const IMAGE_FILE_MACHINE_AMD64: u16 = 0x8664;
+pub const IMAGE_FILE_MACHINE_ARM64: u16 = 0xaa64;
+
+pub const IMAGE_FILE_RELOCS_STRIPPED: u16 = 0x0001;
+pub const IMAGE_FILE_EXECUTABLE_IMAGE: u16 = 0x0002;
+pub const IMAGE_FILE_LARGE_ADDRESS_AWARE: u16 = 0x0020;
+pub const IMAGE_FILE_DEBUG_STRIPPED: u16 = 0x0200;
+pub const IMAGE_FILE_DLL: u16 = 0x2000;
+
+/// PE32+ optional header magic.
+pub const PE32_PLUS_MAGIC: u16 = 0x20b;
+
+pub const IMAGE_SUBSYSTEM_EFI_APPLICATION: u16 = 10;
+pub const IMAGE_SUBSYSTEM_EFI_BOOT_SERVICE_DRIVER: u16 = 11;
+pub const IMAGE_SUBSYSTEM_EFI_RUNTIME_DRIVER: u16 = 12;
+pub const IMAGE_SUBSYSTEM_EFI_ROM: u16 = 13;
+
+pub const IMAGE_DLLCHARACTERISTICS_HIGH_ENTROPY_VA: u16 = 0x0020;
+pub const IMAGE_DLLCHARACTERISTICS_DYNAMIC_BASE: u16 = 0x0040;
+pub const IMAGE_DLLCHARACTERISTICS_NX_COMPAT: u16 = 0x0100;
+
+pub const IMAGE_NUMBEROF_DIRECTORY_ENTRIES: usize = 16;
+pub const IMAGE_DIRECTORY_ENTRY_EXPORT: usize = 0;
+pub const IMAGE_DIRECTORY_ENTRY_IMPORT: usize = 1;
+pub const IMAGE_DIRECTORY_ENTRY_BASERELOC: usize = 5;
+pub const IMAGE_DIRECTORY_ENTRY_DEBUG: usize = 6;
+
+pub const IMAGE_SCN_CNT_CODE: u32 = 0x00000020;
+pub const IMAGE_SCN_CNT_INITIALIZED_DATA: u32 = 0x00000040;
+pub const IMAGE_SCN_CNT_UNINITIALIZED_DATA: u32 = 0x00000080;
+pub const IMAGE_SCN_MEM_DISCARDABLE: u32 = 0x02000000;
+pub const IMAGE_SCN_MEM_EXECUTE: u32 = 0x20000000;
+pub const IMAGE_SCN_MEM_READ: u32 = 0x40000000;
+pub const IMAGE_SCN_MEM_WRITE: u32 = 0x80000000;
+
+/// Log2 of the section alignment plus one, in bits 20-23 of the section
+/// characteristics. Only used in object files.
+pub const IMAGE_SCN_ALIGN_SHIFT: u32 = 20;
+
+/// Padding entry in a base relocation block.
+pub const IMAGE_REL_BASED_ABSOLUTE: u16 = 0;
+/// Apply the full 64-bit delta to the field.
+pub const IMAGE_REL_BASED_DIR64: u16 = 10;
+
+/// 64-bit virtual address of the target.
+pub const IMAGE_REL_AMD64_ADDR64: u16 = 0x0001;
+/// 32-bit virtual address of the target.
+pub const IMAGE_REL_AMD64_ADDR32: u16 = 0x0002;
+/// 32-bit offset of the target from the end of the field.
+pub const IMAGE_REL_AMD64_REL32: u16 = 0x0004;
+
+pub const IMAGE_SYM_UNDEFINED: i16 = 0;
+pub const IMAGE_SYM_CLASS_EXTERNAL: u8 = 2;
+pub const IMAGE_SYM_CLASS_STATIC: u8 = 3;
+/// Symbol type of a function.
+pub const IMAGE_SYM_DTYPE_FUNCTION: u16 = 0x20;
+
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct DosHeader {
+    pub e_magic: u16,
+    pub e_cblp: u16,
+    pub e_cp: u16,
+    pub e_crlc: u16,
+    pub e_cparhdr: u16,
+    pub e_minalloc: u16,
+    pub e_maxalloc: u16,
+    pub e_ss: u16,
+    pub e_sp: u16,
+    pub e_csum: u16,
+    pub e_ip: u16,
+    pub e_cs: u16,
+    pub e_lfarlc: u16,
+    pub e_ovno: u16,
+    pub e_res: [u16; 4],
+    pub e_oemid: u16,
+    pub e_oeminfo: u16,
+    pub e_res2: [u16; 10],
+    /// File offset of the PE signature.
+    pub e_lfanew: u32,
+}
+
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct CoffHeader {
+    pub machine: u16,
+    pub number_of_sections: u16,
+    pub time_date_stamp: u32,
+    pub pointer_to_symbol_table: u32,
+    pub number_of_symbols: u32,
+    pub size_of_optional_header: u16,
+    pub characteristics: u16,
+}
+
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct DataDirectory {
+    pub virtual_address: u32,
+    pub size: u32,
+}
+
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct OptionalHeader64 {
+    pub magic: u16,
+    pub major_linker_version: u8,
+    pub minor_linker_version: u8,
+    pub size_of_code: u32,
+    pub size_of_initialized_data: u32,
+    pub size_of_uninitialized_data: u32,
+    pub address_of_entry_point: u32,
+    pub base_of_code: u32,
+    pub image_base: u64,
+    pub section_alignment: u32,
+    pub file_alignment: u32,
+    pub major_operating_system_version: u16,
+    pub minor_operating_system_version: u16,
+    pub major_image_version: u16,
+    pub minor_image_version: u16,
+    pub major_subsystem_version: u16,
+    pub minor_subsystem_version: u16,
+    pub win32_version_value: u32,
+    pub size_of_image: u32,
+    pub size_of_headers: u32,
+    pub check_sum: u32,
+    pub subsystem: u16,
+    pub dll_characteristics: u16,
+    pub size_of_stack_reserve: u64,
+    pub size_of_stack_commit: u64,
+    pub size_of_heap_reserve: u64,
+    pub size_of_heap_commit: u64,
+    pub loader_flags: u32,
+    pub number_of_rva_and_sizes: u32,
+    pub data_directories: [DataDirectory; IMAGE_NUMBEROF_DIRECTORY_ENTRIES],
+}
+
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct SectionHeader {
+    pub name: [u8; 8],
+    pub virtual_size: u32,
+    pub virtual_address: u32,
+    pub size_of_raw_data: u32,
+    pub pointer_to_raw_data: u32,
+    pub pointer_to_relocations: u32,
+    pub pointer_to_linenumbers: u32,
+    pub number_of_relocations: u16,
+    pub number_of_linenumbers: u16,
+    pub characteristics: u32,
+}
+
+/// Header of a base relocation block, followed by 16-bit entries for one
+/// 4KiB page.
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct BaseRelocationBlock {
+    pub page_rva: u32,
+    pub block_size: u32,
+}
+
+/// A relocation in an object file section.
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C, packed)]
+pub struct CoffRelocation {
+    pub virtual_address: u32,
+    pub symbol_table_index: u32,
+    pub type_: u16,
+}
+
+/// An entry in an object file's symbol table.
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C, packed)]
+pub struct CoffSymbol {
+    /// The name, or if longer than 8 bytes, four zero bytes followed by its
+    /// offset in the string table.
+    pub name: [u8; 8],
+    pub value: u32,
+    /// One-based section index, or `IMAGE_SYM_UNDEFINED`.
+    pub section_number: i16,
+    pub type_: u16,
+    pub storage_class: u8,
+    pub number_of_aux_symbols: u8,
+}
+
+const DOS_HEADER_SIZE: usize = size_of::<DosHeader>();
+const NT_HEADERS_OFFSET: usize = DOS_HEADER_SIZE + DOS_STUB.len();
+const RELOC_SECTION: &str = ".reloc";
+
+struct Section {
+    name: String,
+    characteristics: u32,
+    segment: Segment,
+}
+
+/// Links segments into a PE32+ image, such as a UEFI application.
+///
+/// Absolute 64-bit references are recorded as base relocations, so the
+/// image can be loaded at any address.
+pub struct PeLinker {
+    machine: u16,
+    subsystem: u16,
+    image_base: u64,
+    section_alignment: u32,
+    file_alignment: u32,
+    sections: Vec<Section>,
+}
+
+impl Default for PeLinker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PeLinker {
+    /// Create a linker for an x86_64 UEFI application.
+    pub fn new() -> Self {
+        Self {
+            machine: IMAGE_FILE_MACHINE_AMD64,
+            subsystem: IMAGE_SUBSYSTEM_EFI_APPLICATION,
+            image_base: 0x1_4000_0000,
+            section_alignment: 1 << 12,
+            file_alignment: 1 << 9,
+            sections: Vec::new(),
+        }
+    }
+
+    pub fn set_machine(&mut self, machine: u16) {
+        self.machine = machine;
+    }
+
+    pub fn set_subsystem(&mut self, subsystem: u16) {
+        self.subsystem = subsystem;
+    }
+
+    /// Set the preferred load address of the image.
+    pub fn set_image_base(&mut self, image_base: u64) {
+        assert!(
+            image_base.is_multiple_of(1 << 16),
+            "image base must be 64KiB-aligned"
+        );
+        self.image_base = image_base;
+    }
+
+    /// Add a section to the image. Sections are laid out in the order they
+    /// are added.
+    ///
+    /// `name` is at most 8 bytes long, and `characteristics` is a
+    /// combination of the `IMAGE_SCN_*` flags.
+    pub fn add_section(&mut self, name: &str, characteristics: u32, segment: Segment) {
+        assert!(name.len() <= 8, "section name {:?} is too long", name);
+        assert!(
+            name != RELOC_SECTION,
+            "section name {:?} is reserved",
+            RELOC_SECTION
+        );
+        assert!(
+            self.sections.iter().all(|section| section.name != name),
+            "duplicate section name {:?}",
+            name
+        );
+        assert!(
+            segment.alignment <= self.section_alignment as usize,
+            "alignment of section {:?} exceeds the section alignment",
+            name
+        );
+        self.sections.push(Section {
+            name: name.to_owned(),
+            characteristics,
+            segment,
+        });
+    }
+
+    /// Link the image and write it to `writer`.
+    pub fn finish_to<W: Write>(self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.finish())
+    }
+
+    /// Link the image.
+    pub fn finish(mut self) -> Vec<u8> {
+        let section_alignment = self.section_alignment as u64;
+        let file_alignment = self.file_alignment as u64;
+
+        // Reserve space for a .reloc section header; it is dropped below if
+        // there turn out to be no relocations.
+        let headers_size = NT_HEADERS_OFFSET
+            + PE_SIGNATURE.len()
+            + size_of::<CoffHeader>()
+            + size_of::<OptionalHeader64>()
+            + (self.sections.len() + 1) * size_of::<SectionHeader>();
+        let headers_size = align_up(headers_size as u64, file_alignment);
+
+        // 1. Assign each section its RVA.
+        let mut current_rva = align_up(headers_size, section_alignment);
+        let mut rvas = Vec::with_capacity(self.sections.len());
+        for section in &self.sections {
+            rvas.push(current_rva);
+            current_rva = align_up(
+                current_rva + section.segment.len() as u64,
+                section_alignment,
+            );
+        }
+
+        // 2. Resolve references, collecting the locations of absolute
+        // addresses that need to be rebased when the image is moved.
+        let placed: Vec<_> = self
+            .sections
+            .iter()
+            .zip(&rvas)
+            .map(|(section, &rva)| {
+                (
+                    section.name.as_str(),
+                    self.image_base + rva,
+                    &section.segment,
+                )
+            })
+            .collect();
+        let exports = collect_exports(&placed, false);
+        let entry = exports
+            .get(&Label("entry"))
+            .copied()
+            .expect("entry label is not defined or not exported");
+
+        for section in &self.sections {
+            for (label, references) in &section.segment.references {
+                assert!(
+                    references.iter().all(|reference| !matches!(
+                        reference.format,
+                        ReferenceFormat::Abs32
+                            | ReferenceFormat::Abs16
+                            | ReferenceFormat::GateOffset
+                            | ReferenceFormat::Phys64
+                            | ReferenceFormat::Phys32
+                    )),
+                    "absolute 16-bit, 32-bit, gate or physical reference to {:?} cannot be \
+                     relocated in a PE image",
+                    label.name()
+                );
+            }
+        }
+        let placed = self
+            .sections
+            .iter_mut()
+            .zip(&rvas)
+            .map(|(section, &rva)| {
+                let address = self.image_base + rva;
+                (address, address, &mut section.segment)
+            })
+            .collect();
+        let relocations = resolve_all(placed, &exports, &[]);
+
+        // 3. Group relocations into one block per page.
+        let mut pages: BTreeMap<u32, Vec<u16>> = BTreeMap::new();
+        for relocation in &relocations {
+            let rva = u32::try_from(relocation.location - self.image_base).unwrap();
+            pages
+                .entry(rva & !0xfff)
+                .or_default()
+                .push(IMAGE_REL_BASED_DIR64 << 12 | (rva & 0xfff) as u16);
+        }
+        if !pages.is_empty() {
+            let mut reloc = Segment::new();
+            reloc.align(4);
+            for (page_rva, mut entries) in pages {
+                entries.sort_unstable();
+                if entries.len() % 2 != 0 {
+                    entries.push(IMAGE_REL_BASED_ABSOLUTE << 12);
+                }
+                reloc.append(&BaseRelocationBlock {
+                    page_rva,
+                    block_size: (size_of::<BaseRelocationBlock>() + entries.len() * 2) as u32,
+                });
+                for entry in entries {
+                    reloc.append(&entry);
+                }
+            }
+            rvas.push(current_rva);
+            current_rva = align_up(current_rva + reloc.len() as u64, section_alignment);
+            self.sections.push(Section {
+                name: RELOC_SECTION.to_owned(),
+                characteristics: IMAGE_SCN_CNT_INITIALIZED_DATA
+                    | IMAGE_SCN_MEM_READ
+                    | IMAGE_SCN_MEM_DISCARDABLE,
+                segment: reloc,
+            });
+        }
+
+        // 4. Build the section table.
+        let mut section_headers = Vec::with_capacity(self.sections.len());
+        let mut current_file_offset = headers_size;
+        let mut optional_header = OptionalHeader64::zeroed();
+        for (section, &rva) in self.sections.iter().zip(&rvas) {
+            let virtual_size = section.segment.len() as u64;
+            let raw_size = align_up(section.segment.bytes().len() as u64, file_alignment);
+            let mut name = [0; 8];
+            name[..section.name.len()].copy_from_slice(section.name.as_bytes());
+            section_headers.push(SectionHeader {
+                name,
+                virtual_size: virtual_size as u32,
+                virtual_address: rva as u32,
+                size_of_raw_data: raw_size as u32,
+                pointer_to_raw_data: if raw_size == 0 {
+                    0
+                } else {
+                    current_file_offset as u32
+                },
+                pointer_to_relocations: 0,
+                pointer_to_linenumbers: 0,
+                number_of_relocations: 0,
+                number_of_linenumbers: 0,
+                characteristics: section.characteristics,
+            });
+            current_file_offset += raw_size;
+
+            if section.characteristics & IMAGE_SCN_CNT_CODE != 0 {
+                if optional_header.size_of_code == 0 {
+                    optional_header.base_of_code = rva as u32;
+                }
+                optional_header.size_of_code += raw_size as u32;
+            }
+            if section.characteristics & IMAGE_SCN_CNT_INITIALIZED_DATA != 0 {
+                optional_header.size_of_initialized_data += raw_size as u32;
+            }
+        }
+
+        // 5. Fill in the headers.
+        let mut characteristics = IMAGE_FILE_EXECUTABLE_IMAGE | IMAGE_FILE_LARGE_ADDRESS_AWARE;
+        let mut dll_characteristics =
+            IMAGE_DLLCHARACTERISTICS_NX_COMPAT | IMAGE_DLLCHARACTERISTICS_HIGH_ENTROPY_VA;
+        match section_headers.last() {
+            Some(reloc) if &reloc.name[..RELOC_SECTION.len()] == RELOC_SECTION.as_bytes() => {
+                dll_characteristics |= IMAGE_DLLCHARACTERISTICS_DYNAMIC_BASE;
+                optional_header.data_directories[IMAGE_DIRECTORY_ENTRY_BASERELOC] = DataDirectory {
+                    virtual_address: reloc.virtual_address,
+                    size: reloc.virtual_size,
+                };
+            }
+            _ => characteristics |= IMAGE_FILE_RELOCS_STRIPPED,
+        }
+
+        let dos_header = DosHeader {
+            e_magic: DOS_MAGIC,
+            e_cblp: 0x90,
+            e_cp: 3,
+            e_cparhdr: (DOS_HEADER_SIZE / 16) as u16,
+            e_maxalloc: 0xffff,
+            e_sp: 0xb8,
+            e_lfarlc: DOS_HEADER_SIZE as u16,
+            e_lfanew: NT_HEADERS_OFFSET as u32,
+            ..Zeroable::zeroed()
+        };
+        let coff_header = CoffHeader {
+            machine: self.machine,
+            number_of_sections: section_headers.len().try_into().unwrap(),
+            time_date_stamp: 0,
+            pointer_to_symbol_table: 0,
+            number_of_symbols: 0,
+            size_of_optional_header: size_of::<OptionalHeader64>() as u16,
+            characteristics,
+        };
+        let optional_header = OptionalHeader64 {
+            magic: PE32_PLUS_MAGIC,
+            address_of_entry_point: u32::try_from(entry - self.image_base).unwrap(),
+            image_base: self.image_base,
+            section_alignment: self.section_alignment,
+            file_alignment: self.file_alignment,
+            size_of_image: u32::try_from(current_rva).unwrap(),
+            size_of_headers: headers_size as u32,
+            subsystem: self.subsystem,
+            dll_characteristics,
+            size_of_stack_reserve: 1 << 20,
+            size_of_stack_commit: 1 << 12,
+            size_of_heap_reserve: 1 << 20,
+            size_of_heap_commit: 1 << 12,
+            number_of_rva_and_sizes: IMAGE_NUMBEROF_DIRECTORY_ENTRIES as u32,
+            ..optional_header
+        };
+
+        // 6. Write the image.
+        let mut image = Vec::with_capacity(current_file_offset as usize);
+        image.extend_from_slice(bytemuck::bytes_of(&dos_header));
+        image.extend_from_slice(DOS_STUB);
+        image.extend_from_slice(PE_SIGNATURE);
+        image.extend_from_slice(bytemuck::bytes_of(&coff_header));
+        image.extend_from_slice(bytemuck::bytes_of(&optional_header));
+        image.extend_from_slice(bytemuck::cast_slice(&section_headers));
+        image.resize(headers_size as usize, 0);
+        for (section, header) in self.sections.iter().zip(&section_headers) {
+            // Sections without file data, like a `.bss` of reserved space,
+            // have no raw data pointer to pad up to.
+            if header.size_of_raw_data == 0 {
+                continue;
+            }
+            image.extend_from_slice(section.segment.bytes());
+            image.resize(
+                (header.pointer_to_raw_data + header.size_of_raw_data) as usize,
+                section.segment.fill,
+            );
+        }
+        image
+    }
+}
+
+/// Writes segments as sections of a COFF object file, for linking with
+/// `link.exe` or `lld-link`.
+///
+/// Exported labels become external symbols, and local labels become static
+/// symbols. Labels that are referenced but not defined become undefined
+/// external symbols, to be resolved by the linker.
+pub struct CoffObjectBuilder {
+    machine: u16,
+    sections: Vec<Section>,
+}
+
+impl Default for CoffObjectBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CoffObjectBuilder {
+    /// Create a builder for an x86_64 object file.
+    pub fn new() -> Self {
+        Self {
+            machine: IMAGE_FILE_MACHINE_AMD64,
+            sections: Vec::new(),
+        }
+    }
+
+    /// Add a section to the object file.
+    ///
+    /// `characteristics` is a combination of the `IMAGE_SCN_*` flags; the
+    /// alignment flags are derived from the segment.
+    pub fn add_section(&mut self, name: &str, characteristics: u32, segment: Segment) {
+        assert!(
+            self.sections.iter().all(|section| section.name != name),
+            "duplicate section name {:?}",
+            name
+        );
+        assert!(
+            segment.alignment <= 8192,
+            "alignment of section {:?} is too large",
+            name
+        );
+        self.sections.push(Section {
+            name: name.to_owned(),
+            characteristics,
+            segment,
+        });
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        // Check for duplicate exports; addresses are assigned by the linker.
+        let placed: Vec<_> = self
+            .sections
+            .iter()
+            .map(|section| (section.name.as_str(), 0, &section.segment))
+            .collect();
+        collect_exports(&placed, true);
+
+        // The string table starts with its size.
+        let mut strings = vec![0; 4];
+
+        // 1. Symbols for every defined label, ordered by section and offset,
+        // then for undefined labels, ordered by name.
+        let mut symbols = Vec::new();
+        let mut local_symbols = HashMap::new();
+        let mut external_symbols = HashMap::new();
+        for (index, section) in self.sections.iter().enumerate() {
+            let mut labels: Vec<_> = section.segment.labels.iter().collect();
+            labels.sort_by_key(|(label, definition)| (definition.offset, label.name()));
+            for (&label, definition) in labels {
+                let storage_class = match definition.visibility {
+                    Visibility::Exported => {
+                        external_symbols.insert(label, symbols.len() as u32);
+                        IMAGE_SYM_CLASS_EXTERNAL
+                    }
+                    Visibility::Local => {
+                        local_symbols.insert((index, label), symbols.len() as u32);
+                        IMAGE_SYM_CLASS_STATIC
+                    }
+                };
+                symbols.push(CoffSymbol {
+                    name: symbol_name(&mut strings, &label.name()),
+                    value: definition.offset as u32,
+                    section_number: index as i16 + 1,
+                    type_: match section.characteristics & IMAGE_SCN_CNT_CODE {
+                        0 => 0,
+                        _ => IMAGE_SYM_DTYPE_FUNCTION,
+                    },
+                    storage_class,
+                    number_of_aux_symbols: 0,
+                });
+            }
+        }
+        let mut undefined: Vec<Label> = self
+            .sections
+            .iter()
+            .enumerate()
+            .flat_map(|(index, section)| {
+                section
+                    .segment
+                    .references
+                    .keys()
+                    .map(move |label| (index, label))
+            })
+            .filter(|(index, label)| {
+                !local_symbols.contains_key(&(*index, **label))
+                    && !external_symbols.contains_key(*label)
+            })
+            .map(|(_, &label)| label)
+            .collect();
+        undefined.sort_by_key(|label| label.name());
+        undefined.dedup();
+        for label in undefined {
+            external_symbols.insert(label, symbols.len() as u32);
+            symbols.push(CoffSymbol {
+                name: symbol_name(&mut strings, &label.name()),
+                value: 0,
+                section_number: IMAGE_SYM_UNDEFINED,
+                type_: 0,
+                storage_class: IMAGE_SYM_CLASS_EXTERNAL,
+                number_of_aux_symbols: 0,
+            });
+        }
+
+        // 2. Relocations for every reference, ordered by location.
+        let relocations: Vec<Vec<CoffRelocation>> = self
+            .sections
+            .iter()
+            .enumerate()
+            .map(|(index, section)| {
+                let mut relocations: Vec<CoffRelocation> = section
+                    .segment
+                    .references
+                    .iter()
+                    .flat_map(|(label, references)| {
+                        let symbol = local_symbols
+                            .get(&(index, *label))
+                            .or_else(|| external_symbols.get(label))
+                            .copied()
+                            .unwrap();
+                        references.iter().map(move |reference| CoffRelocation {
+                            virtual_address: reference.location as u32,
+                            symbol_table_index: symbol,
+                            type_: match reference.format {
+                                ReferenceFormat::Rel32 => IMAGE_REL_AMD64_REL32,
+                                ReferenceFormat::Abs64 => IMAGE_REL_AMD64_ADDR64,
+                                ReferenceFormat::Abs32 => IMAGE_REL_AMD64_ADDR32,
+                                ReferenceFormat::Abs16 => panic!(
+                                    "absolute 16-bit reference to {:?} cannot be relocated in a \
+                                     COFF object",
+                                    label.name()
+                                ),
+                                ReferenceFormat::Rel16 => panic!(
+                                    "relative 16-bit reference to {:?} cannot be relocated in a \
+                                     COFF object",
+                                    label.name()
+                                ),
+                                ReferenceFormat::GateOffset => panic!(
+                                    "gate reference to {:?} cannot be relocated in a COFF object",
+                                    label.name()
+                                ),
+                                ReferenceFormat::Phys64 | ReferenceFormat::Phys32 => panic!(
+                                    "physical reference to {:?} cannot be relocated in a COFF \
+                                     object",
+                                    label.name()
+                                ),
+                            },
+                        })
+                    })
+                    .collect();
+                relocations.sort_by_key(|relocation| relocation.virtual_address);
+                assert!(
+                    relocations.len() < 0xffff,
+                    "too many relocations in section {:?}",
+                    section.name
+                );
+                relocations
+            })
+            .collect();
+
+        // 3. Section table, followed by each section's data and relocations.
+        let mut current_offset =
+            size_of::<CoffHeader>() + self.sections.len() * size_of::<SectionHeader>();
+        let mut section_headers = Vec::with_capacity(self.sections.len());
+        for (section, relocations) in self.sections.iter().zip(&relocations) {
+            let size = section.segment.len();
+            let relocations_offset = current_offset + size;
+            let name = section_name(&mut strings, &section.name);
+            let alignment = section.segment.alignment.trailing_zeros() + 1;
+            section_headers.push(SectionHeader {
+                name,
+                virtual_size: 0,
+                virtual_address: 0,
+                size_of_raw_data: size as u32,
+                pointer_to_raw_data: if size == 0 { 0 } else { current_offset as u32 },
+                pointer_to_relocations: if relocations.is_empty() {
+                    0
+                } else {
+                    relocations_offset as u32
+                },
+                pointer_to_linenumbers: 0,
+                number_of_relocations: relocations.len() as u16,
+                number_of_linenumbers: 0,
+                characteristics: section.characteristics | alignment << IMAGE_SCN_ALIGN_SHIFT,
+            });
+            current_offset = relocations_offset + relocations.len() * size_of::<CoffRelocation>();
+        }
+        let strings_len = strings.len() as u32;
+        strings[..4].copy_from_slice(&strings_len.to_le_bytes());
+
+        let coff_header = CoffHeader {
+            machine: self.machine,
+            number_of_sections: self.sections.len().try_into().unwrap(),
+            time_date_stamp: 0,
+            pointer_to_symbol_table: current_offset as u32,
+            number_of_symbols: symbols.len() as u32,
+            size_of_optional_header: 0,
+            characteristics: 0,
+        };
+
+        let mut object = Vec::new();
+        object.extend_from_slice(bytemuck::bytes_of(&coff_header));
+        object.extend_from_slice(bytemuck::cast_slice(&section_headers));
+        for (section, relocations) in self.sections.iter().zip(&relocations) {
+            object.extend_from_slice(section.segment.bytes());
+            // Objects have no notion of partially initialized sections.
+            object.resize(object.len() + section.segment.reserved, 0);
+            object.extend_from_slice(bytemuck::cast_slice(relocations));
+        }
+        object.extend_from_slice(bytemuck::cast_slice(&symbols));
+        object.extend_from_slice(&strings);
+        object
+    }
+}
+
+/// Encode a symbol name, moving it to the string table if it is longer than
+/// 8 bytes.
+fn symbol_name(strings: &mut Vec<u8>, name: &str) -> [u8; 8] {
+    let mut field = [0; 8];
+    if name.len() <= 8 {
+        field[..name.len()].copy_from_slice(name.as_bytes());
+    } else {
+        field[4..].copy_from_slice(&(strings.len() as u32).to_le_bytes());
+        strings.extend(name.as_bytes());
+        strings.push(0);
+    }
+    field
+}
+
+/// Encode a section name, moving it to the string table and referring to it
+/// as `/offset` if it is longer than 8 bytes.
+fn section_name(strings: &mut Vec<u8>, name: &str) -> [u8; 8] {
+    let mut field = [0; 8];
+    if name.len() <= 8 {
+        field[..name.len()].copy_from_slice(name.as_bytes());
+    } else {
+        let reference = format!("/{}", strings.len());
+        field[..reference.len()].copy_from_slice(reference.as_bytes());
+        strings.extend(name.as_bytes());
+        strings.push(0);
+    }
+    field
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_sizes() {
+        assert_eq!(size_of::<DosHeader>(), 64);
+        assert_eq!(size_of::<CoffHeader>(), 20);
+        assert_eq!(size_of::<OptionalHeader64>(), 240);
+        assert_eq!(size_of::<SectionHeader>(), 40);
+    }
+
+    #[test]
+    fn base_relocations() {
+        let mut text = Segment::new();
+        text.export_label("entry");
+        text.extend([0xe9]);
+        text.append_reference("entry", ReferenceFormat::Rel32);
+
+        let mut data = Segment::new();
+        data.append(&[0u8; 8]);
+        data.label("pointer");
+        data.append_reference("entry", ReferenceFormat::Abs64);
+
+        let mut linker = PeLinker::new();
+        linker.add_section(
+            ".text",
+            IMAGE_SCN_CNT_CODE | IMAGE_SCN_MEM_READ | IMAGE_SCN_MEM_EXECUTE,
+            text,
+        );
+        linker.add_section(
+            ".data",
+            IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ | IMAGE_SCN_MEM_WRITE,
+            data,
+        );
+        let image = linker.finish();
+
+        let coff_offset = NT_HEADERS_OFFSET + PE_SIGNATURE.len();
+        let coff: CoffHeader =
+            bytemuck::pod_read_unaligned(&image[coff_offset..][..size_of::<CoffHeader>()]);
+        assert_eq!(coff.number_of_sections, 3);
+        let optional_offset = coff_offset + size_of::<CoffHeader>();
+        let optional: OptionalHeader64 = bytemuck::pod_read_unaligned(
+            &image[optional_offset..][..size_of::<OptionalHeader64>()],
+        );
+        assert_eq!(optional.address_of_entry_point, 0x1000);
+        assert_eq!(optional.size_of_image, 0x4000);
+
+        let sections: Vec<SectionHeader> = image[optional_offset + size_of::<OptionalHeader64>()..]
+            .chunks_exact(size_of::<SectionHeader>())
+            .take(3)
+            .map(bytemuck::pod_read_unaligned)
+            .collect();
+        let text = &image[sections[0].pointer_to_raw_data as usize..];
+        assert_eq!(text[..5], [0xe9, 0xfb, 0xff, 0xff, 0xff]);
+        let data = &image[sections[1].pointer_to_raw_data as usize..];
+        assert_eq!(data[8..16], 0x1_4000_1000_u64.to_le_bytes());
+
+        let directory = optional.data_directories[IMAGE_DIRECTORY_ENTRY_BASERELOC];
+        assert_eq!(directory.virtual_address, sections[2].virtual_address);
+        let reloc = &image[sections[2].pointer_to_raw_data as usize..][..directory.size as usize];
+        let words: Vec<u16> = reloc
+            .chunks(2)
+            .map(|chunk| u16::from_le_bytes(chunk.try_into().unwrap()))
+            .collect();
+        // Page 0x2000, 12-byte block, DIR64 at offset 8, padding.
+        assert_eq!(words, [0x2000, 0, 12, 0, 0xa008, 0]);
+    }
+
+    #[test]
+    fn reserved_only_section() {
+        let mut text = Segment::new();
+        text.export_label("entry");
+        text.extend([0xc3]);
+
+        let mut bss = Segment::new();
+        bss.reserve(0x100);
+
+        let mut data = Segment::new();
+        data.extend([0xaa; 4]);
+
+        let mut linker = PeLinker::new();
+        linker.add_section(
+            ".text",
+            IMAGE_SCN_CNT_CODE | IMAGE_SCN_MEM_READ | IMAGE_SCN_MEM_EXECUTE,
+            text,
+        );
+        linker.add_section(
+            ".bss",
+            IMAGE_SCN_CNT_UNINITIALIZED_DATA | IMAGE_SCN_MEM_READ | IMAGE_SCN_MEM_WRITE,
+            bss,
+        );
+        linker.add_section(
+            ".data",
+            IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ | IMAGE_SCN_MEM_WRITE,
+            data,
+        );
+        let image = linker.finish();
+
+        let coff_offset = NT_HEADERS_OFFSET + PE_SIGNATURE.len();
+        let optional_offset = coff_offset + size_of::<CoffHeader>();
+        let sections: Vec<SectionHeader> = image[optional_offset + size_of::<OptionalHeader64>()..]
+            .chunks_exact(size_of::<SectionHeader>())
+            .take(3)
+            .map(bytemuck::pod_read_unaligned)
+            .collect();
+        assert_eq!(image[..2], DOS_MAGIC.to_le_bytes());
+        assert_eq!(sections[1].virtual_size, 0x100);
+        assert_eq!(sections[1].size_of_raw_data, 0);
+        assert_eq!(sections[1].pointer_to_raw_data, 0);
+        let end = sections[2].pointer_to_raw_data + sections[2].size_of_raw_data;
+        assert_eq!(image.len(), end as usize);
+        assert_eq!(image[sections[0].pointer_to_raw_data as usize], 0xc3);
+        let data = &image[sections[2].pointer_to_raw_data as usize..];
+        assert_eq!(data[..4], [0xaa; 4]);
+    }
+
+    #[test]
+    fn object_file() {
+        let mut text = Segment::new();
+        text.export_label("start");
+        text.extend([0xe8]);
+        text.append_reference("external_function", ReferenceFormat::Rel32);
+        text.label("loop");
+        text.extend([0xe9]);
+        text.append_reference("loop", ReferenceFormat::Rel32);
+
+        let mut data = Segment::new();
+        data.align(8);
+        data.export_label("function_table");
+        data.append_reference("start", ReferenceFormat::Abs64);
+
+        let mut builder = CoffObjectBuilder::new();
+        builder.add_section(
+            ".text",
+            IMAGE_SCN_CNT_CODE | IMAGE_SCN_MEM_READ | IMAGE_SCN_MEM_EXECUTE,
+            text,
+        );
+        builder.add_section(
+            ".data",
+            IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ | IMAGE_SCN_MEM_WRITE,
+            data,
+        );
+        let object = builder.finish();
+
+        let coff: CoffHeader = bytemuck::pod_read_unaligned(&object[..size_of::<CoffHeader>()]);
+        assert_eq!(coff.number_of_symbols, 4);
+        let symbols: Vec<CoffSymbol> = object[coff.pointer_to_symbol_table as usize..]
+            .chunks_exact(size_of::<CoffSymbol>())
+            .take(4)
+            .map(bytemuck::pod_read_unaligned)
+            .collect();
+        let strings =
+            &object[coff.pointer_to_symbol_table as usize + 4 * size_of::<CoffSymbol>()..];
+        let name = |symbol: &CoffSymbol| match symbol.name[..4] {
+            [0, 0, 0, 0] => {
+                let offset = u32::from_le_bytes(symbol.name[4..].try_into().unwrap()) as usize;
+                let end = strings[offset..].iter().position(|&b| b == 0).unwrap();
+                String::from_utf8(strings[offset..][..end].to_vec()).unwrap()
+            }
+            _ => String::from_utf8(symbol.name.to_vec())
+                .unwrap()
+                .trim_end_matches('\0')
+                .to_owned(),
+        };
+        let names: Vec<String> = symbols.iter().map(name).collect();
+        assert_eq!(
+            names,
+            ["start", "loop", "function_table", "external_function"]
+        );
+        assert_eq!({ symbols[3].section_number }, IMAGE_SYM_UNDEFINED);
+
+        let text: SectionHeader = bytemuck::pod_read_unaligned(
+            &object[size_of::<CoffHeader>()..][..size_of::<SectionHeader>()],
+        );
+        assert_eq!(text.number_of_relocations, 2);
+        let relocation: CoffRelocation = bytemuck::pod_read_unaligned(
+            &object[text.pointer_to_relocations as usize..][..size_of::<CoffRelocation>()],
+        );
+        assert_eq!({ relocation.virtual_address }, 1);
+        assert_eq!({ relocation.symbol_table_index }, 3);
+        assert_eq!({ relocation.type_ }, IMAGE_REL_AMD64_REL32);
+    }
+}
@@ -0,0 +1,207 @@
+//! Resolution of addresses, e.g. from a crash log, to the labels of a linked
+//! image, as `label+offset`.
+//!
+//! The map of labels is either taken from a [`Linked`] image, or read from
+//! the symbol file that [`Linked::write_symbols`] writes next to it, which
+//! is sorted by address and small enough to keep with every build. It is a
+//! lightweight alternative to the DWARF information of
+//! [`Linked::write_debug_info`], which needs a debugger to read.
+
+use crate::{elf64::common::Addr, link::Linked};
+use std::{
+    fmt::{self, Display, Formatter},
+    io,
+};
+
+/// A label of a linked image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    /// The virtual address of the label.
+    pub address: Addr,
+    /// The size of the label, up to the next label in the same segment, or
+    /// the end of the segment.
+    pub size: u64,
+    pub exported: bool,
+    pub segment: String,
+    pub name: String,
+}
+
+/// An address, resolved to the label that contains it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Resolved<'a> {
+    pub symbol: &'a Symbol,
+    pub offset: u64,
+}
+
+impl Display for Resolved<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.offset {
+            0 => write!(f, "{}", self.symbol.name),
+            offset => write!(f, "{}+{:#x}", self.symbol.name, offset),
+        }
+    }
+}
+
+pub struct Symbolizer {
+    /// Sorted by address, with exported labels before local ones at the same
+    /// address.
+    symbols: Vec<Symbol>,
+}
+
+impl Symbolizer {
+    /// The labels of a linked image.
+    pub fn from_linked(linked: &Linked) -> Self {
+        let symbols = linked
+            .symbols()
+            .into_iter()
+            .map(|(address, size, scope, segment, name)| Symbol {
+                address,
+                size: size as u64,
+                exported: scope == 'g',
+                segment: segment.to_owned(),
+                name: name.to_string(),
+            })
+            .collect();
+        Self::new(symbols)
+    }
+
+    /// Parse a symbol file, as written by [`Linked::write_symbols`].
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let invalid = |line: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("malformed symbol {:?}", line),
+            )
+        };
+        let mut symbols = Vec::new();
+        for line in text.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let &[address, size, scope, segment, name] = fields.as_slice() else {
+                return Err(invalid(line));
+            };
+            symbols.push(Symbol {
+                address: u64::from_str_radix(address, 16).map_err(|_| invalid(line))?,
+                size: u64::from_str_radix(size, 16).map_err(|_| invalid(line))?,
+                exported: match scope {
+                    "g" => true,
+                    "l" => false,
+                    _ => return Err(invalid(line)),
+                },
+                segment: segment.to_owned(),
+                name: name.to_owned(),
+            });
+        }
+        Ok(Self::new(symbols))
+    }
+
+    fn new(mut symbols: Vec<Symbol>) -> Self {
+        symbols.sort_by_key(|symbol| (symbol.address, !symbol.exported));
+        Self { symbols }
+    }
+
+    /// Every label, sorted by address.
+    pub fn symbols(&self) -> &[Symbol] {
+        &self.symbols
+    }
+
+    /// The label that contains `address`, preferring exported labels where
+    /// several start at the same address.
+    ///
+    /// An address at the start of a label of size zero, such as the end of
+    /// a segment, resolves to that label.
+    pub fn resolve(&self, address: Addr) -> Option<Resolved<'_>> {
+        let end = self
+            .symbols
+            .partition_point(|symbol| symbol.address <= address);
+        let start = self.symbols[..end].last().map(|last| {
+            self.symbols
+                .partition_point(|symbol| symbol.address < last.address)
+        })?;
+        let candidates = &self.symbols[start..end];
+        let offset = address - candidates[0].address;
+        candidates
+            .iter()
+            .find(|symbol| offset < symbol.size)
+            .or_else(|| candidates.first().filter(|_| offset == 0))
+            .map(|symbol| Resolved { symbol, offset })
+    }
+
+    /// Annotate each hexadecimal number of the form `0x...` in `text` that
+    /// resolves to a label, e.g. `panic at 0xffffffff80004321` becomes
+    /// `panic at 0xffffffff80004321 <print+0x1d>`.
+    pub fn annotate(&self, text: &str) -> String {
+        let mut out = String::new();
+        let mut rest = text;
+        while let Some(start) = rest.find("0x") {
+            let digits = rest[start + 2..]
+                .find(|c: char| !c.is_ascii_hexdigit())
+                .unwrap_or(rest.len() - start - 2);
+            let end = start + 2 + digits;
+            out.push_str(&rest[..end]);
+            if let Some(resolved) = u64::from_str_radix(&rest[start + 2..end], 16)
+                .ok()
+                .and_then(|address| self.resolve(address))
+            {
+                out.push_str(&format!(" <{}>", resolved));
+            }
+            rest = &rest[end..];
+        }
+        out.push_str(rest);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        elf64::program::{PF_R, PF_X},
+        link::{ElfLinker, Segment},
+    };
+
+    #[test]
+    fn resolve() {
+        let mut code = Segment::new();
+        code.label("start");
+        code.export_label("entry");
+        code.extend([0x90; 4]);
+        code.label("helper");
+        code.extend([0x90; 4]);
+        code.label("end");
+        let mut linker = ElfLinker::new();
+        linker.add_segment("code", PF_R | PF_X, 1 << 12, code);
+        let linked = linker.finish();
+        let entry = linked.label_address("entry").unwrap();
+
+        let mut file = Vec::new();
+        linked.write_symbols(&mut file).unwrap();
+        let parsed = Symbolizer::parse(std::str::from_utf8(&file).unwrap()).unwrap();
+        let symbolizer = Symbolizer::from_linked(&linked);
+        assert_eq!(parsed.symbols(), symbolizer.symbols());
+
+        let resolve = |address| symbolizer.resolve(address).map(|r| r.to_string());
+        assert_eq!(resolve(entry).as_deref(), Some("entry"));
+        assert_eq!(resolve(entry + 3).as_deref(), Some("entry+0x3"));
+        assert_eq!(resolve(entry + 4).as_deref(), Some("helper"));
+        assert_eq!(resolve(entry + 8).as_deref(), Some("end"));
+        // Addresses outside of every label.
+        assert_eq!(resolve(entry - 1), None);
+        assert_eq!(resolve(entry + 9), None);
+
+        assert_eq!(
+            symbolizer.annotate(&format!("panic at {:#x}: 0x\n  0x{:x}\n", entry + 5, entry)),
+            format!(
+                "panic at {:#x} <helper+0x1>: 0x\n  0x{:x} <entry>\n",
+                entry + 5,
+                entry
+            )
+        );
+    }
+
+    #[test]
+    fn malformed() {
+        assert!(Symbolizer::parse("ffffffff80000000 00000004 g code\n").is_err());
+        assert!(Symbolizer::parse("ffffffff80000000 00000004 x code entry\n").is_err());
+        assert!(Symbolizer::parse("").unwrap().resolve(0).is_none());
+    }
+}
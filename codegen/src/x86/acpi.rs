@@ -0,0 +1,172 @@
+//! A generator for a routine that finds ACPI tables.
+//!
+//! The generated routine `{name}_find_table(signature)`, called with the
+//! System V ABI, looks up the table with a 4-byte signature, given as a
+//! little-endian `u32` like [`MADT`], and returns a pointer to it, or null
+//! if there is none. It validates the RSDP from Limine's RSDP response and
+//! the XSDT, and returns null if either is missing or invalid. The tables
+//! themselves are not validated.
+//!
+//! ACPI tables refer to each other by physical address, so the routine also
+//! needs Limine's HHDM response to access them. It takes the address in the
+//! RSDP response to be virtual, as it is up to base revision 2.
+
+use super::{
+    address::{disp8, Index, Indirect},
+    control::ControlFlow,
+    function::{Arg, Function},
+    instruction::*,
+    register::{R32::*, R64::*, R8::*},
+    Assembler, Emitter,
+};
+use crate::{
+    limine::{self, RequestHandle},
+    link::Label,
+};
+
+/// Signature of the multiple APIC description table.
+pub const MADT: u32 = u32::from_le_bytes(*b"APIC");
+/// Signature of the HPET description table.
+pub const HPET: u32 = u32::from_le_bytes(*b"HPET");
+/// Signature of the fixed ACPI description table.
+pub const FADT: u32 = u32::from_le_bytes(*b"FACP");
+/// Signature of the PCI Express memory-mapped configuration table.
+pub const MCFG: u32 = u32::from_le_bytes(*b"MCFG");
+
+const RSDP_SIGNATURE: u64 = u64::from_le_bytes(*b"RSD PTR ");
+const XSDT_SIGNATURE: u32 = u32::from_le_bytes(*b"XSDT");
+
+/// Size of the ACPI 1.0 part of the RSDP, covered by its first checksum.
+const RSDP_V1_SIZE: u64 = 20;
+/// Offsets in the RSDP.
+const RSDP_REVISION: i8 = 15;
+const RSDP_LENGTH: i8 = 20;
+const RSDP_XSDT_ADDRESS: i8 = 24;
+/// The first revision with an XSDT.
+const RSDP_XSDT_REVISION: i8 = 2;
+
+/// Offset of the length in the header common to all tables.
+const TABLE_LENGTH: i8 = 4;
+/// Size of the header common to all tables, which the XSDT's entries
+/// follow.
+const TABLE_HEADER_SIZE: i8 = 36;
+
+pub struct Acpi<'a> {
+    name: &'a str,
+    rsdp: RequestHandle,
+    hhdm: RequestHandle,
+}
+
+impl<'a> Acpi<'a> {
+    /// A routine using the responses to the RSDP request `rsdp` and the
+    /// HHDM request `hhdm`.
+    pub fn new(name: &'a str, rsdp: RequestHandle, hhdm: RequestHandle) -> Self {
+        Self { name, rsdp, hhdm }
+    }
+
+    /// A label derived from the name.
+    fn label(&self, suffix: &str) -> Label {
+        Label(&format!("{}_{}", self.name, suffix))
+    }
+
+    pub fn emit(&self, asm: &mut Assembler) {
+        let checksum = self.label("checksum");
+        emit_checksum(asm, checksum);
+
+        let mut f = Function::new(self.label("find_table"))
+            .export()
+            .params(1)
+            .returns()
+            .begin(asm);
+        let not_found = f.fresh_label("find_table_not_found");
+        let end = f.fresh_label("find_table_end");
+        // R15 holds the signature, RBX the RSDP, R12 the HHDM offset and R13
+        // the XSDT.
+        f.push(MOV(R15D, EDI));
+
+        f.push(MOV(RAX, self.hhdm.ptr()));
+        f.push(TEST(RAX, RAX));
+        f.push(JZ(not_found));
+        f.push(MOV(R12, Index(RAX, limine::HHDM_OFFSET_DISPLACEMENT)));
+
+        f.push(MOV(RAX, self.rsdp.ptr()));
+        f.push(TEST(RAX, RAX));
+        f.push(JZ(not_found));
+        f.push(MOV(
+            RBX,
+            Index(RAX, disp8(limine::RSDP_RESPONSE_ADDRESS_OFFSET)),
+        ));
+        f.push(MOV(RAX, Indirect(RBX)));
+        f.push(MOV(RDX, RSDP_SIGNATURE));
+        f.push(CMP(RAX, RDX));
+        f.push(JNZ(not_found));
+        f.call_fn(checksum, &[Arg::Reg(RBX), Arg::Imm(RSDP_V1_SIZE)]);
+        f.push(TEST(AL, AL));
+        f.push(JNZ(not_found));
+        f.push(MOVZX(RAX, Index(RBX, RSDP_REVISION)));
+        f.push(CMP(RAX, RSDP_XSDT_REVISION));
+        f.push(JCC(Condition::Below, not_found));
+        f.push(MOV(ESI, Index(RBX, RSDP_LENGTH)));
+        f.call_fn(checksum, &[Arg::Reg(RBX), Arg::Reg(RSI)]);
+        f.push(TEST(AL, AL));
+        f.push(JNZ(not_found));
+
+        f.push(MOV(R13, Index(RBX, RSDP_XSDT_ADDRESS)));
+        f.push(ADD(R13, R12));
+        f.push(MOV(EAX, Index(R13, 0)));
+        f.push(MOV(RDX, XSDT_SIGNATURE as u64));
+        f.push(CMP(RAX, RDX));
+        f.push(JNZ(not_found));
+        f.push(MOV(ESI, Index(R13, TABLE_LENGTH)));
+        f.call_fn(checksum, &[Arg::Reg(R13), Arg::Reg(RSI)]);
+        f.push(TEST(AL, AL));
+        f.push(JNZ(not_found));
+
+        // Walk the entries, from RDI up to RSI, for a table whose signature
+        // matches.
+        f.push(MOV(ESI, Index(R13, TABLE_LENGTH)));
+        f.push(ADD(RSI, R13));
+        f.push(LEA(RDI, Index(R13, TABLE_HEADER_SIZE)));
+        f.while_(
+            |f| {
+                f.push(CMP(RDI, RSI));
+                Condition::Below
+            },
+            |f, _| {
+                f.push(MOV(RAX, Indirect(RDI)));
+                f.push(ADD(RAX, R12));
+                f.push(MOV(EDX, Index(RAX, 0)));
+                f.push(CMP(RDX, R15));
+                f.push(JZ(end));
+                f.push(ADD(RDI, 8i8));
+            },
+        );
+        f.label(not_found);
+        f.push(XOR(RAX, RAX));
+        f.label(end);
+        f.ret();
+        f.finish();
+    }
+}
+
+/// Emit `checksum(pointer, length)`, which returns the sum of the bytes in
+/// AL. ACPI structures are valid if their bytes sum to zero.
+fn emit_checksum(asm: &mut Assembler, name: Label) {
+    let mut f = Function::new(name).params(2).returns().begin(asm);
+    let (pointer, length) = (f.param(0), f.param(1));
+    f.push(XOR(RAX, RAX));
+    f.push(XOR(RCX, RCX));
+    f.while_(
+        |f| {
+            f.push(CMP(RCX, length));
+            Condition::Below
+        },
+        |f, _| {
+            f.push(MOVZX(RDX, Index(RCX, pointer)));
+            f.push(ADD(RAX, RDX));
+            f.push(INC(RCX));
+        },
+    );
+    f.ret();
+    f.finish();
+}
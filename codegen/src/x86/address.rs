@@ -1,6 +1,14 @@
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Index<I, B>(pub I, pub B);
 
+/// Convert a structure field offset to an 8-bit displacement for `Index`
+/// addressing. Panics if it does not fit; in a `const` item, that is a
+/// compile-time error.
+pub const fn disp8(offset: usize) -> i8 {
+    assert!(offset <= i8::MAX as usize, "offset does not fit in disp8");
+    offset as i8
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Times1;
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -10,8 +18,58 @@ pub struct Times4;
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Times8;
 
+/// The scale factor of a [`ScaledIndex`].
+pub trait Scale {
+    /// The scale field of the SIB byte.
+    fn bits(&self) -> u8;
+}
+
+impl Scale for Times1 {
+    fn bits(&self) -> u8 {
+        0b00
+    }
+}
+
+impl Scale for Times2 {
+    fn bits(&self) -> u8 {
+        0b01
+    }
+}
+
+impl Scale for Times4 {
+    fn bits(&self) -> u8 {
+        0b10
+    }
+}
+
+impl Scale for Times8 {
+    fn bits(&self) -> u8 {
+        0b11
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct ScaledIndex<S, I, B>(pub S, pub I, pub B);
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Indirect<R>(pub R);
+
+/// An offset from the base of the GS segment, like the offset of a field of
+/// the per-CPU data that `GS_BASE` points to. Encoded as a 32-bit absolute
+/// displacement with a GS segment override.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GsIndex(pub usize);
+
+/// Split a linear address below 1MiB into a real-mode segment and an offset
+/// below 16, e.g. for the `CS:IP` that an AP starts at. Panics if the
+/// address is out of range; in a `const` item, that is a compile-time
+/// error.
+pub const fn segment_offset(linear: u32) -> (u16, u16) {
+    assert!(linear < 1 << 20, "address is not reachable in real mode");
+    ((linear >> 4) as u16, (linear & 0xf) as u16)
+}
+
+/// The linear address of a real-mode segment and offset.
+pub const fn linear_address(segment: u16, offset: u16) -> u32 {
+    ((segment as u32) << 4) + offset as u32
+}
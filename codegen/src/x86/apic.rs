@@ -0,0 +1,155 @@
+//! A generator for local APIC setup and end-of-interrupt routines.
+//!
+//! The local APIC's registers are memory-mapped, at a physical address
+//! given by the [`APIC_BASE`](msr::APIC_BASE) MSR, usually `0xfee00000`.
+//! The virtual address that the kernel has mapped them at is only known at
+//! run time, so it is passed to the generated `init` routine and kept in a
+//! global for the other routines.
+//!
+//! The generated routines, called with the System V ABI, are:
+//!
+//! - `{name}_init(base)`: enable the APIC, with its registers mapped at
+//!   `base`, and start the timer if one is configured
+//! - `{name}_eoi()`: signal the end of the interrupt being handled. It only
+//!   clobbers RAX, and follows the [`Kernel`] convention, so that it can be
+//!   called cheaply from interrupt handlers
+
+use super::{
+    address::Index, convention::Kernel, function::Function, global::Global, instruction::*, msr,
+    register::R64::*, Assembler,
+};
+use crate::link::{Label, Segment};
+
+/// Task priority register.
+const TPR: i32 = 0x80;
+/// End of interrupt register.
+const EOI: i32 = 0xb0;
+/// Spurious interrupt vector register.
+const SVR: i32 = 0xf0;
+/// Software enable bit of the SVR.
+const SVR_ENABLE: u32 = 1 << 8;
+/// Timer entry of the local vector table.
+const LVT_TIMER: i32 = 0x320;
+/// Periodic mode bit of the LVT timer entry.
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+/// Timer initial count register.
+const TIMER_INITIAL_COUNT: i32 = 0x380;
+/// Timer divide configuration register.
+const TIMER_DIVIDE: i32 = 0x3e0;
+
+/// Configuration of the APIC timer.
+#[derive(Debug, Clone, Copy)]
+pub struct Timer {
+    pub vector: u8,
+    /// Divisor of the bus clock, a power of two from 1 to 128.
+    pub divisor: u32,
+    /// Number of divided ticks until the interrupt.
+    pub initial_count: u32,
+    /// Whether the count restarts after each interrupt, instead of firing
+    /// just once.
+    pub periodic: bool,
+}
+
+impl Timer {
+    /// The value of the divide configuration register: bits 0, 1 and 3
+    /// encode log2(divisor) - 1, modulo 8.
+    fn divide_configuration(&self) -> u32 {
+        assert!(
+            self.divisor.is_power_of_two() && self.divisor <= 128,
+            "unsupported APIC timer divisor {}",
+            self.divisor
+        );
+        let code = (self.divisor.trailing_zeros() + 7) % 8;
+        (code & 0b11) | (code & 0b100) << 1
+    }
+}
+
+pub struct LocalApic<'a> {
+    name: &'a str,
+    spurious_vector: u8,
+    msr_enable: bool,
+    timer: Option<Timer>,
+}
+
+impl<'a> LocalApic<'a> {
+    /// Spurious interrupts are delivered to `spurious_vector`, whose handler
+    /// must return without signalling the end of the interrupt.
+    pub fn new(name: &'a str, spurious_vector: u8) -> Self {
+        Self {
+            name,
+            spurious_vector,
+            msr_enable: false,
+            timer: None,
+        }
+    }
+
+    /// Also set the global enable bit of the `APIC_BASE` MSR, in case
+    /// the firmware left the APIC disabled. The software enable bit in the
+    /// spurious interrupt vector register is always set.
+    pub fn msr_enable(mut self) -> Self {
+        self.msr_enable = true;
+        self
+    }
+
+    pub fn timer(mut self, timer: Timer) -> Self {
+        self.timer = Some(timer);
+        self
+    }
+
+    /// A label derived from the name.
+    fn label(&self, suffix: &str) -> Label {
+        Label(&format!("{}_{}", self.name, suffix))
+    }
+
+    /// Emit the exported routines into `asm`, and the global holding the
+    /// register base into `data`.
+    pub fn emit(&self, asm: &mut Assembler, data: &mut Segment) {
+        let base = Global::<u64>::reserve(data, self.label("base"));
+
+        let mut f = Function::new(self.label("init"))
+            .export()
+            .params(1)
+            .begin(asm);
+        let registers = f.param(0);
+        base.store(&mut f, registers);
+        if self.msr_enable {
+            msr::read(&mut f, msr::APIC_BASE, RAX);
+            f.push(OR(RAX, msr::APIC_BASE_ENABLE as i32));
+            msr::write(&mut f, msr::APIC_BASE, RAX);
+        }
+        // Accept all interrupt priorities.
+        f.push(MOV(Index(registers, TPR), 0u32));
+        f.push(MOV(
+            Index(registers, SVR),
+            SVR_ENABLE | self.spurious_vector as u32,
+        ));
+        if let Some(timer) = self.timer {
+            f.push(MOV(
+                Index(registers, TIMER_DIVIDE),
+                timer.divide_configuration(),
+            ));
+            let mode = if timer.periodic {
+                LVT_TIMER_PERIODIC
+            } else {
+                0
+            };
+            f.push(MOV(Index(registers, LVT_TIMER), mode | timer.vector as u32));
+            // Writing the initial count starts the timer.
+            f.push(MOV(
+                Index(registers, TIMER_INITIAL_COUNT),
+                timer.initial_count,
+            ));
+        }
+        f.ret();
+        f.finish();
+
+        let mut f = Function::new(self.label("eoi"))
+            .export()
+            .convention(&Kernel)
+            .begin(asm);
+        base.load(&mut f, RAX);
+        f.push(MOV(Index(RAX, EOI), 0u32));
+        f.ret();
+        f.finish();
+    }
+}
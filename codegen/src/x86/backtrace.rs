@@ -0,0 +1,191 @@
+//! A generator for a routine that prints a backtrace, by walking the chain of
+//! frame pointers.
+//!
+//! Every [`Function`] that isn't naked keeps RBP pointing at its frame, which
+//! holds the caller's RBP and the return address:
+//!
+//! ```text
+//! rbp + 8     return address
+//! rbp         caller's rbp
+//! ```
+//!
+//! so the return addresses of all active calls can be found by following the
+//! saved RBPs up the stack. The chain ends at a zero RBP, which Limine sets
+//! at the kernel entry point. Naked functions and code outside of functions
+//! don't push frames, so they appear as the function that called them, or
+//! not at all.
+//!
+//! The generated routine, `print_backtrace()`, is called with the System V
+//! ABI, and prints the return address of each frame above its caller with
+//! the kernel's `print` and `tohex` routines:
+//!
+//! ```text
+//! backtrace:
+//!   0xffffffff80004321
+//!   0xffffffff800042a7
+//! ```
+
+use super::{
+    address::Indirect,
+    control::ControlFlow,
+    function::{Arg, Function},
+    instruction::*,
+    register::R64::*,
+    Assembler,
+};
+
+pub const PRINT_BACKTRACE: &str = "print_backtrace";
+
+/// The most frames printed, in case the chain is corrupted in a way that
+/// still moves up the stack.
+pub const MAX_FRAMES: u64 = 32;
+
+pub struct Backtrace<'a> {
+    print: &'a str,
+    tohex: &'a str,
+}
+
+impl<'a> Backtrace<'a> {
+    /// A backtrace printed with `print(string)` and
+    /// `tohex(value) -> string`.
+    pub fn new(print: &'a str, tohex: &'a str) -> Self {
+        Self { print, tohex }
+    }
+
+    pub fn emit(&self, asm: &mut Assembler) {
+        let header = asm.const_bytes(b"backtrace:\n\0");
+        let prefix = asm.const_bytes(b"  0x\0");
+        let newline = asm.const_bytes(b"\n\0");
+
+        let mut f = Function::new(PRINT_BACKTRACE).export().begin(asm);
+        f.call_fn(self.print, &[Arg::Label(header.label())]);
+        // RBX walks the frames, starting with the caller's, and R12 counts
+        // the frames left to print.
+        f.push(MOV(RBX, Indirect(RBP)));
+        f.push(MOV(R12, MAX_FRAMES));
+        f.loop_(|f, labels| {
+            f.push(TEST(RBX, RBX));
+            f.push(JZ(labels.break_));
+            f.call_fn(self.print, &[Arg::Label(prefix.label())]);
+            f.call_fn(self.tohex, &[Arg::Index(RBX, 8)]);
+            f.call_fn(self.print, &[Arg::Reg(RAX)]);
+            f.call_fn(self.print, &[Arg::Label(newline.label())]);
+            // Frames of callers are at higher addresses, so a link that
+            // doesn't move up the stack would loop.
+            f.push(MOV(RAX, Indirect(RBX)));
+            f.push(CMP(RAX, RBX));
+            f.push(JCC(Condition::BelowOrEqual, labels.break_));
+            f.push(MOV(RBX, RAX));
+            f.push(DEC(R12));
+            f.push(JNZ(labels.continue_));
+        });
+        f.ret();
+        f.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        elf64::program::{PF_R, PF_W, PF_X},
+        link::{ElfLinker, Linked, Ptr, ReferenceFormat, Segment},
+        x86::emulator::Machine,
+    };
+
+    /// Link `code` with `print_backtrace()`, and call `caller`. Returns the
+    /// address of each string printed, except that `tohex` returns its
+    /// argument, so that return addresses are "printed" as themselves.
+    fn run(code: impl FnOnce(&mut Assembler), caller: &str) -> (Linked, Vec<u64>) {
+        let mut asm = Assembler::new();
+        asm.export_label("entry");
+        asm.push(HLT);
+        let mut f = Function::new("print").params(1).begin(&mut asm);
+        f.push(MOV(RAX, Ptr("log_end")));
+        f.push(MOV(Indirect(RAX), RDI));
+        f.push(ADD(RAX, 8i8));
+        f.push(MOV(Ptr("log_end"), RAX));
+        f.finish();
+        let mut f = Function::new("tohex").params(1).returns().begin(&mut asm);
+        f.push(MOV(RAX, RDI));
+        f.finish();
+        Backtrace::new("print", "tohex").emit(&mut asm);
+        code(&mut asm);
+
+        let mut data = Segment::new();
+        data.align(8);
+        data.export_label("log_end");
+        data.append_reference("log", ReferenceFormat::Abs64);
+        data.export_label("log");
+        data.reserve(8 * 64);
+        let mut linker = ElfLinker::new();
+        linker.add_segment("rodata", PF_R, 1 << 12, asm.constant_pool());
+        linker.add_segment("data", PF_R | PF_W, 1 << 12, data);
+        linker.add_segment("code", PF_R | PF_X, 1 << 12, asm.finish());
+        let linked = linker.finish();
+
+        let mut machine = Machine::load(&linked);
+        machine.call(linked.label_address(caller).unwrap(), &[]);
+        let log = machine
+            .read(linked.label_address("log").unwrap(), 8 * 64)
+            .chunks(8)
+            .map(|word| u64::from_le_bytes(word.try_into().unwrap()))
+            .take_while(|&word| word != 0)
+            .collect();
+        (linked, log)
+    }
+
+    #[test]
+    fn walks_the_chain() {
+        // outer() calls inner(), which calls print_backtrace().
+        let (linked, log) = run(
+            |asm| {
+                for (name, callee) in [("outer", "inner"), ("inner", PRINT_BACKTRACE)] {
+                    let mut f = Function::new(name).export().begin(asm);
+                    f.call_fn(callee, &[]);
+                    f.label(&format!("{}_return", name));
+                    f.ret();
+                    f.finish();
+                }
+            },
+            "outer",
+        );
+
+        let string = |address| {
+            let rodata = linked.segment_vaddr("rodata").unwrap();
+            let data = &linked.segments()[0].data()[(address - rodata) as usize..];
+            String::from_utf8(data[..data.iter().position(|&b| b == 0).unwrap()].to_vec()).unwrap()
+        };
+        assert_eq!(string(log[0]), "backtrace:\n");
+        // Each frame is printed as its prefix, return address and newline,
+        // from the frame of inner() up to the frame of outer(), whose
+        // caller's RBP is zero. The return address of outer() is the host's.
+        let frames: Vec<&[u64]> = log[1..].chunks(3).collect();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(string(frames[0][0]), "  0x");
+        assert_eq!(
+            frames[0][1],
+            linked.any_label_address("outer_return").unwrap()
+        );
+        assert_eq!(string(frames[0][2]), "\n");
+    }
+
+    #[test]
+    fn stops_at_a_corrupted_link() {
+        // A caller whose saved RBP points at its own frame.
+        let (_, log) = run(
+            |asm| {
+                let mut f = Function::new("cycle").export().begin(asm);
+                f.push(MOV(Indirect(RBP), RBP));
+                f.call_fn(PRINT_BACKTRACE, &[]);
+                f.label("cycle_return");
+                f.ret();
+                f.finish();
+            },
+            "cycle",
+        );
+        // The walk stops after the first frame, rather than printing it
+        // until the frame limit.
+        assert_eq!(log.len(), 1 + 3);
+    }
+}
@@ -0,0 +1,157 @@
+//! Structured control flow, which generates the labels and jumps for
+//! conditionals and loops.
+//!
+//! Conditions are tested on the flags, as set by code emitted just before the
+//! combinator (for [`if_`](ControlFlow::if_)) or by the condition closure
+//! (for [`while_`](ControlFlow::while_)).
+
+use super::{
+    address::{ScaledIndex, Times8},
+    instruction::{Condition, CMP, JCC, JMP, LEA, MOV, SUB},
+    register::R64::{self, *},
+    Emitter,
+};
+use crate::link::{Label, ReferenceFormat};
+
+/// Minimum number of arms for [`switch`](ControlFlow::switch) to use a jump
+/// table.
+const MIN_TABLE_ARMS: usize = 4;
+
+/// The labels of a loop, for jumping out of it or to its next iteration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Loop {
+    /// Jump here to leave the loop.
+    pub break_: Label,
+    /// Jump here to start the next iteration, re-testing the condition of a
+    /// `while_` loop.
+    pub continue_: Label,
+}
+
+pub trait ControlFlow: Emitter + Sized {
+    /// Emit `then` to run if `condition` holds, otherwise `else_`.
+    fn if_(
+        &mut self,
+        condition: Condition,
+        then: impl FnOnce(&mut Self),
+        else_: impl FnOnce(&mut Self),
+    ) {
+        let else_label = self.fresh_label("else");
+        let end_label = self.fresh_label("end_if");
+        self.push(JCC(condition.negate(), else_label));
+        then(self);
+        self.push(JMP(end_label));
+        self.label(else_label);
+        else_(self);
+        self.label(end_label);
+    }
+
+    /// Emit `then` to run if `condition` holds.
+    fn if_then(&mut self, condition: Condition, then: impl FnOnce(&mut Self)) {
+        let end_label = self.fresh_label("end_if");
+        self.push(JCC(condition.negate(), end_label));
+        then(self);
+        self.label(end_label);
+    }
+
+    /// Emit a loop that runs `body` for as long as the condition emitted by
+    /// `condition` holds, testing it before each iteration.
+    fn while_(
+        &mut self,
+        condition: impl FnOnce(&mut Self) -> Condition,
+        body: impl FnOnce(&mut Self, Loop),
+    ) {
+        let labels = Loop {
+            continue_: self.fresh_label("while"),
+            break_: self.fresh_label("end_while"),
+        };
+        self.label(labels.continue_);
+        let condition = condition(self);
+        self.push(JCC(condition.negate(), labels.break_));
+        body(self, labels);
+        self.push(JMP(labels.continue_));
+        self.label(labels.break_);
+    }
+
+    /// Emit a loop that runs `body` until it jumps to `break_`.
+    fn loop_(&mut self, body: impl FnOnce(&mut Self, Loop)) {
+        let labels = Loop {
+            continue_: self.fresh_label("loop"),
+            break_: self.fresh_label("end_loop"),
+        };
+        self.label(labels.continue_);
+        body(self, labels);
+        self.push(JMP(labels.continue_));
+        self.label(labels.break_);
+    }
+
+    /// Jump to the label of the arm whose value equals `value`, or to
+    /// `default` if there is none.
+    ///
+    /// Dense arms are dispatched through a table of addresses, placed in the
+    /// instruction stream after the indirect jump, and sparse ones through a
+    /// chain of comparisons. Clobbers R10, R11 and the flags.
+    fn switch(&mut self, value: R64, arms: &[(u64, &str)], default: &str) {
+        assert!(
+            value != R10 && value != R11,
+            "{:?} is clobbered by switch",
+            value
+        );
+        let mut sorted = arms.to_vec();
+        sorted.sort_by_key(|&(case, _)| case);
+        for pair in sorted.windows(2) {
+            assert!(pair[0].0 != pair[1].0, "duplicate switch arm {}", pair[0].0);
+        }
+
+        let Some((&(min, _), &(max, _))) = sorted.first().zip(sorted.last()) else {
+            self.push(JMP(Label(default)));
+            return;
+        };
+        // At least half of the table entries must be arms.
+        let span = (max - min).saturating_add(1);
+        if sorted.len() < MIN_TABLE_ARMS || span > 2 * sorted.len() as u64 {
+            for &(case, label) in &sorted {
+                match i32::try_from(case as i64) {
+                    Ok(case) => self.push(CMP(value, case)),
+                    Err(_) => {
+                        self.push(MOV(R11, case));
+                        self.push(CMP(value, R11));
+                    }
+                }
+                self.push(JCC(Condition::Zero, Label(label)));
+            }
+            self.push(JMP(Label(default)));
+            return;
+        }
+
+        let table = self.fresh_label("switch_table");
+        self.push(MOV(R11, value));
+        match i32::try_from(min) {
+            Ok(0) => {}
+            Ok(min) => self.push(SUB(R11, min)),
+            Err(_) => {
+                self.push(MOV(R10, min));
+                self.push(SUB(R11, R10));
+            }
+        }
+        // Unsigned, so that values below the minimum wrap around and are
+        // also out of range.
+        self.push(CMP(R11, i32::try_from(span - 1).unwrap()));
+        self.push(JCC(Condition::Above, Label(default)));
+        self.push(LEA(R10, table.ptr()));
+        self.push(MOV(R10, ScaledIndex(Times8, R11, R10)));
+        self.push(JMP(R10));
+
+        self.pad_to_alignment(8);
+        self.label(table);
+        let mut arms = sorted.iter().peekable();
+        for case in min..=max {
+            let label = match arms.next_if(|&&(arm, _)| arm == case) {
+                Some(&(_, label)) => label,
+                None => default,
+            };
+            self.append_reference(label, ReferenceFormat::Abs64);
+        }
+    }
+}
+
+impl<E: Emitter> ControlFlow for E {}
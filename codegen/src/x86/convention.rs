@@ -0,0 +1,130 @@
+//! Calling conventions, describing how integer arguments and results are
+//! passed and which registers a call may clobber.
+//!
+//! A [`Function`](super::function::Function) follows one convention, and
+//! calls are emitted for the convention of the callee with
+//! [`Assembler::call_with`](super::Assembler::call_with). Conventions are
+//! passed around as `&'static dyn CallingConvention`, e.g. `&SysV`.
+
+use super::{
+    function::{CALLEE_SAVED, CALLER_SAVED, PARAMETER_REGISTERS, RETURN_REGISTER},
+    register::R64::{self, *},
+};
+
+pub trait CallingConvention {
+    /// A name for messages, which also identifies the convention.
+    fn name(&self) -> &'static str;
+
+    /// Integer parameter registers, in argument order. Further arguments
+    /// are passed on the stack.
+    fn parameters(&self) -> &'static [R64];
+
+    fn return_register(&self) -> R64;
+
+    /// Registers that a call may clobber. Must include R11, which is used as
+    /// scratch when passing arguments.
+    fn caller_saved(&self) -> &'static [R64];
+
+    /// Registers that a call preserves, apart from RSP.
+    fn callee_saved(&self) -> &'static [R64];
+
+    /// Required alignment of RSP at a `CALL`, in bytes.
+    fn stack_alignment(&self) -> usize {
+        16
+    }
+
+    /// Bytes that the caller reserves above the stack arguments, for the
+    /// callee to spill its register arguments into.
+    fn shadow_space(&self) -> usize {
+        0
+    }
+}
+
+impl PartialEq for dyn CallingConvention {
+    fn eq(&self, other: &Self) -> bool {
+        self.name() == other.name()
+    }
+}
+
+/// The System V AMD64 convention, used by the Limine protocol and most
+/// generated code.
+pub struct SysV;
+
+impl CallingConvention for SysV {
+    fn name(&self) -> &'static str {
+        "sysv"
+    }
+
+    fn parameters(&self) -> &'static [R64] {
+        &PARAMETER_REGISTERS
+    }
+
+    fn return_register(&self) -> R64 {
+        RETURN_REGISTER
+    }
+
+    fn caller_saved(&self) -> &'static [R64] {
+        &CALLER_SAVED
+    }
+
+    fn callee_saved(&self) -> &'static [R64] {
+        &CALLEE_SAVED
+    }
+}
+
+/// The Microsoft x64 convention, used by UEFI.
+pub struct Microsoft;
+
+impl CallingConvention for Microsoft {
+    fn name(&self) -> &'static str {
+        "microsoft"
+    }
+
+    fn parameters(&self) -> &'static [R64] {
+        &[RCX, RDX, R8, R9]
+    }
+
+    fn return_register(&self) -> R64 {
+        RAX
+    }
+
+    fn caller_saved(&self) -> &'static [R64] {
+        &[RAX, RCX, RDX, R8, R9, R10, R11]
+    }
+
+    fn callee_saved(&self) -> &'static [R64] {
+        &[RBX, RBP, RDI, RSI, R12, R13, R14, R15]
+    }
+
+    fn shadow_space(&self) -> usize {
+        32
+    }
+}
+
+/// A convention for hot internal helpers and code called from interrupt
+/// handlers. Parameters are passed as with [`SysV`], but only RAX, R10 and
+/// R11 are clobbered, so callers rarely need to save anything. The callee
+/// saves the registers it actually uses instead.
+pub struct Kernel;
+
+impl CallingConvention for Kernel {
+    fn name(&self) -> &'static str {
+        "kernel"
+    }
+
+    fn parameters(&self) -> &'static [R64] {
+        &PARAMETER_REGISTERS
+    }
+
+    fn return_register(&self) -> R64 {
+        RAX
+    }
+
+    fn caller_saved(&self) -> &'static [R64] {
+        &[RAX, R10, R11]
+    }
+
+    fn callee_saved(&self) -> &'static [R64] {
+        &[RBX, RBP, RCX, RDX, RSI, RDI, R8, R9, R12, R13, R14, R15]
+    }
+}
@@ -0,0 +1,157 @@
+//! Probes for processor features reported by `CPUID`.
+//!
+//! Each [`Feature`] is a bit in one of the output registers of a `CPUID`
+//! leaf. [`require`] emits a query for a feature that branches away if it is
+//! absent, including when the processor does not implement the leaf at all.
+
+use super::{
+    instruction::*,
+    register::{R64, R64::*},
+    Emitter,
+};
+use crate::link::Label;
+
+/// Features that can be probed with [`require`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Feature {
+    Fpu,
+    /// `RDTSC`.
+    Tsc,
+    /// `RDMSR` and `WRMSR`.
+    Msr,
+    Pae,
+    /// An on-chip local APIC.
+    Apic,
+    /// Global pages.
+    Pge,
+    /// Page attribute table.
+    Pat,
+    Fxsr,
+    Sse,
+    Sse2,
+    Sse3,
+    Ssse3,
+    Sse41,
+    Sse42,
+    X2Apic,
+    Popcnt,
+    /// One-shot APIC timer deadlines written to an MSR.
+    TscDeadline,
+    Xsave,
+    Avx,
+    Rdrand,
+    /// Running under a hypervisor.
+    Hypervisor,
+    /// `RDFSBASE`, `WRFSBASE`, `RDGSBASE` and `WRGSBASE`.
+    Fsgsbase,
+    /// Supervisor mode execution prevention.
+    Smep,
+    /// Enhanced `REP MOVSB` and `REP STOSB`.
+    Erms,
+    Invpcid,
+    /// Supervisor mode access prevention.
+    Smap,
+    /// User mode instruction prevention.
+    Umip,
+    /// 5-level paging.
+    La57,
+    /// `SYSCALL` and `SYSRET`.
+    Syscall,
+    /// The no-execute page bit.
+    Nx,
+    /// 1GiB pages.
+    Page1G,
+    Rdtscp,
+    LongMode,
+}
+
+/// The output register of `CPUID` holding a feature bit. Feature bits are
+/// never in EAX.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Output {
+    Ebx,
+    Ecx,
+    Edx,
+}
+
+impl Output {
+    fn register(&self) -> R64 {
+        match self {
+            Self::Ebx => RBX,
+            Self::Ecx => RCX,
+            Self::Edx => RDX,
+        }
+    }
+}
+
+impl Feature {
+    /// The leaf and subleaf reporting the feature, and its output register
+    /// and bit.
+    fn location(&self) -> (u32, u32, Output, u32) {
+        use Output::*;
+        match self {
+            Self::Fpu => (0x1, 0, Edx, 0),
+            Self::Tsc => (0x1, 0, Edx, 4),
+            Self::Msr => (0x1, 0, Edx, 5),
+            Self::Pae => (0x1, 0, Edx, 6),
+            Self::Apic => (0x1, 0, Edx, 9),
+            Self::Pge => (0x1, 0, Edx, 13),
+            Self::Pat => (0x1, 0, Edx, 16),
+            Self::Fxsr => (0x1, 0, Edx, 24),
+            Self::Sse => (0x1, 0, Edx, 25),
+            Self::Sse2 => (0x1, 0, Edx, 26),
+            Self::Sse3 => (0x1, 0, Ecx, 0),
+            Self::Ssse3 => (0x1, 0, Ecx, 9),
+            Self::Sse41 => (0x1, 0, Ecx, 19),
+            Self::Sse42 => (0x1, 0, Ecx, 20),
+            Self::X2Apic => (0x1, 0, Ecx, 21),
+            Self::Popcnt => (0x1, 0, Ecx, 23),
+            Self::TscDeadline => (0x1, 0, Ecx, 24),
+            Self::Xsave => (0x1, 0, Ecx, 26),
+            Self::Avx => (0x1, 0, Ecx, 28),
+            Self::Rdrand => (0x1, 0, Ecx, 30),
+            Self::Hypervisor => (0x1, 0, Ecx, 31),
+            Self::Fsgsbase => (0x7, 0, Ebx, 0),
+            Self::Smep => (0x7, 0, Ebx, 7),
+            Self::Erms => (0x7, 0, Ebx, 9),
+            Self::Invpcid => (0x7, 0, Ebx, 10),
+            Self::Smap => (0x7, 0, Ebx, 20),
+            Self::Umip => (0x7, 0, Ecx, 2),
+            Self::La57 => (0x7, 0, Ecx, 16),
+            Self::Syscall => (0x8000_0001, 0, Edx, 11),
+            Self::Nx => (0x8000_0001, 0, Edx, 20),
+            Self::Page1G => (0x8000_0001, 0, Edx, 26),
+            Self::Rdtscp => (0x8000_0001, 0, Edx, 27),
+            Self::LongMode => (0x8000_0001, 0, Edx, 29),
+        }
+    }
+}
+
+/// Jump to `absent` unless the processor has `feature`, and fall through
+/// otherwise. Clobbers RAX, RCX and RDX.
+///
+/// RBX, which `CPUID` also writes, is saved on the stack around it, so the
+/// stack must have room for one more value. The flags are tested after it is
+/// restored, as `POP` leaves them unchanged.
+pub fn require(asm: &mut impl Emitter, feature: Feature, absent: &str) {
+    let (leaf, subleaf, output, bit) = feature.location();
+
+    // Leaf 0 or 0x80000000 of each range reports the highest leaf in it.
+    // Querying a higher leaf returns unrelated data instead of failing.
+    asm.push(MOV(RAX, (leaf & 0x8000_0000) as u64));
+    asm.push(PUSH(RBX));
+    asm.push(CPUID);
+    asm.push(POP(RBX));
+    asm.push(MOV(RDX, leaf as u64));
+    asm.push(CMP(RAX, RDX));
+    asm.push(JCC(Condition::Below, Label(absent)));
+
+    asm.push(MOV(RAX, leaf as u64));
+    asm.push(MOV(RCX, subleaf as u64));
+    asm.push(PUSH(RBX));
+    asm.push(CPUID);
+    // Bit 31 is sign-extended, but the upper halves of the outputs are zero.
+    asm.push(TEST(output.register(), (1u32 << bit) as i32));
+    asm.push(POP(RBX));
+    asm.push(JZ(Label(absent)));
+}
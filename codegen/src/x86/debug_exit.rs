@@ -0,0 +1,108 @@
+//! Exit statuses through QEMU's `isa-debug-exit` device, for kernels run as
+//! tests to report their result to the host.
+//!
+//! The guest writes a status byte to the device's I/O port, and QEMU exits
+//! at once with the exit code `status << 1 | 1`. QEMU must be run with
+//! [`QEMU_DEVICE`], e.g. `-device isa-debug-exit,iobase=0xf4,iosize=0x01`.
+
+use super::{
+    instruction::{HLT, JMP, MOV, OUT},
+    register::R8::AL,
+    Emitter,
+};
+
+/// The I/O port of the device.
+pub const PORT: u8 = 0xf4;
+
+/// The `-device` argument for QEMU.
+pub const QEMU_DEVICE: &str = "isa-debug-exit,iobase=0xf4,iosize=0x01";
+
+/// The status of a test kernel that passed. It is not zero, so that a pass
+/// can be told apart from QEMU exiting normally, with exit code 0.
+pub const EXIT_SUCCESS: u8 = 0x10;
+
+/// The status of a test kernel that failed, e.g. on a failed assertion.
+pub const EXIT_FAILURE: u8 = 0x11;
+
+/// Emit code that exits QEMU with `status`, which must fit in the 7 bits
+/// of an exit code, or halts if the device is not present.
+///
+/// Clobbers AL.
+pub fn emit_exit(asm: &mut impl Emitter, status: u8) {
+    assert!(
+        status < 0x80,
+        "debug exit status {:#x} does not fit in an exit code",
+        status
+    );
+    let halt = asm.fresh_label("debug_exit_halt");
+    asm.push(MOV(AL, status));
+    asm.push(OUT(PORT, AL));
+    asm.label(halt);
+    asm.push(HLT);
+    asm.push(JMP(halt));
+}
+
+/// The outcome of a test kernel, from the exit code of QEMU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// The kernel exited with [`EXIT_SUCCESS`].
+    Passed,
+    /// The kernel exited with another status.
+    Failed(u8),
+    /// QEMU exited without the kernel writing to the device, e.g. on a
+    /// triple fault with `-no-reboot`, with this exit code, or was killed.
+    NoExit(Option<i32>),
+}
+
+impl Outcome {
+    pub fn from_exit_code(code: Option<i32>) -> Self {
+        match code {
+            Some(code) if code & 1 == 1 && (0..0x100).contains(&code) => match (code >> 1) as u8 {
+                EXIT_SUCCESS => Self::Passed,
+                status => Self::Failed(status),
+            },
+            _ => Self::NoExit(code),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::x86::{decode::decode, Assembler};
+
+    #[test]
+    fn exit_sequence() {
+        let mut asm = Assembler::new();
+        emit_exit(&mut asm, EXIT_FAILURE);
+        let code = asm.finish().bytes().to_vec();
+        let mut listing = Vec::new();
+        let mut position = 0;
+        while position < code.len() {
+            let decoded = decode(&code[position..]).unwrap();
+            listing.push(decoded.to_string());
+            position += decoded.length;
+        }
+        // The jump back to the HLT is not resolved until linking.
+        assert_eq!(listing[..3], ["mov al, 0x11", "out 0xf4, al", "hlt"]);
+        assert!(listing[3].starts_with("jmp"));
+    }
+
+    #[test]
+    fn outcomes() {
+        assert_eq!(Outcome::from_exit_code(Some(0x21)), Outcome::Passed);
+        assert_eq!(
+            Outcome::from_exit_code(Some(0x23)),
+            Outcome::Failed(EXIT_FAILURE)
+        );
+        assert_eq!(Outcome::from_exit_code(Some(0x01)), Outcome::Failed(0));
+        assert_eq!(Outcome::from_exit_code(Some(0)), Outcome::NoExit(Some(0)));
+        assert_eq!(Outcome::from_exit_code(None), Outcome::NoExit(None));
+    }
+
+    #[test]
+    #[should_panic(expected = "does not fit")]
+    fn status_too_large() {
+        emit_exit(&mut Assembler::new(), 0x80);
+    }
+}
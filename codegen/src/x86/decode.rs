@@ -0,0 +1,1142 @@
+//! A decoder for the instructions that the [encoder](super::instruction)
+//! emits, for checking encodings and for listings of generated code.
+//!
+//! [`decode`] decodes the instruction at the start of a slice of code, and
+//! the result displays in Intel syntax. Only the opcodes that the encoder
+//! uses are supported, in all of their operand sizes, and anything else
+//! decodes to `None`, as do the byte registers that need a REX prefix.
+
+use super::{
+    instruction::{Condition, Immediate},
+    register::{Xmm, R16, R32, R64, R8},
+};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mnemonic {
+    Add,
+    And,
+    Bsf,
+    Call,
+    Cld,
+    Cmp,
+    Cmpsb,
+    Cmpxchg,
+    Cpuid,
+    Dec,
+    Div,
+    Hlt,
+    Imul,
+    In,
+    Inc,
+    Int3,
+    Iretq,
+    Jcc(Condition),
+    Jmp,
+    Lea,
+    Lidt,
+    Mov,
+    Movdqa,
+    Movdqu,
+    Movq,
+    Movsb,
+    Movzx,
+    Neg,
+    Nop,
+    Or,
+    Out,
+    Pause,
+    Pcmpeqb,
+    Pmovmskb,
+    Pop,
+    Punpcklqdq,
+    Push,
+    Pxor,
+    Rdmsr,
+    Ret,
+    Scasb,
+    Shl,
+    Shr,
+    Sti,
+    Stosb,
+    Stosw,
+    Sub,
+    Swapgs,
+    Syscall,
+    Sysretq,
+    Test,
+    Wrmsr,
+    Xchg,
+    Xor,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Prefix {
+    Lock,
+    Rep,
+    Repe,
+    Repne,
+}
+
+/// Size of a memory operand.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Size {
+    Byte,
+    Word,
+    Dword,
+    Qword,
+    Xmmword,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Base {
+    Register(R64),
+    /// The address of the next instruction.
+    Rip,
+    /// No base, for an absolute displacement.
+    Absolute,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Displacement {
+    X8(i8),
+    X32(i32),
+}
+
+impl Displacement {
+    pub fn value(&self) -> i32 {
+        match *self {
+            Self::X8(value) => value as i32,
+            Self::X32(value) => value,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Memory {
+    /// Size of the operand, or `None` if it is not accessed as a value, as
+    /// with `LEA`.
+    pub size: Option<Size>,
+    /// Whether the address is relative to the GS base.
+    pub gs: bool,
+    pub base: Base,
+    /// The index register and its scale factor.
+    pub index: Option<(R64, u8)>,
+    pub displacement: Option<Displacement>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operand {
+    R8(R8),
+    R16(R16),
+    R32(R32),
+    R64(R64),
+    Xmm(Xmm),
+    Immediate(Immediate),
+    /// A branch target, relative to the end of the instruction.
+    Relative(i32),
+    Memory(Memory),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Decoded {
+    pub prefix: Option<Prefix>,
+    pub mnemonic: Mnemonic,
+    pub operands: Vec<Operand>,
+    /// Length of the encoding, in bytes.
+    pub length: usize,
+}
+
+/// Decode the instruction at the start of `code`, or return `None` if it is
+/// not supported or is cut off.
+pub fn decode(code: &[u8]) -> Option<Decoded> {
+    let mut decoder = Decoder {
+        code,
+        position: 0,
+        operand_size_override: false,
+        operand_size_used: false,
+        lock: false,
+        repeat: None,
+        gs: false,
+        rex: None,
+    };
+    decoder.prefixes()?;
+    let (mnemonic, operands) = decoder.instruction()?;
+
+    let prefix = if decoder.lock {
+        let lockable = matches!(
+            mnemonic,
+            Mnemonic::Add
+                | Mnemonic::And
+                | Mnemonic::Cmpxchg
+                | Mnemonic::Dec
+                | Mnemonic::Inc
+                | Mnemonic::Neg
+                | Mnemonic::Or
+                | Mnemonic::Sub
+                | Mnemonic::Xchg
+                | Mnemonic::Xor
+        );
+        if !lockable || !matches!(operands.first(), Some(Operand::Memory(_))) {
+            return None;
+        }
+        Some(Prefix::Lock)
+    } else {
+        match (decoder.repeat.take(), mnemonic) {
+            (None, _) => None,
+            (Some(0xf3), Mnemonic::Movsb | Mnemonic::Stosb | Mnemonic::Stosw) => Some(Prefix::Rep),
+            (Some(0xf3), Mnemonic::Cmpsb | Mnemonic::Scasb) => Some(Prefix::Repe),
+            (Some(0xf2), Mnemonic::Cmpsb | Mnemonic::Scasb) => Some(Prefix::Repne),
+            (Some(_), _) => return None,
+        }
+    };
+    // Prefixes that have no effect are not accepted, since they would not be
+    // re-encoded.
+    if decoder.operand_size_override && !decoder.operand_size_used {
+        return None;
+    }
+    if decoder.gs && !operands.iter().any(|o| matches!(o, Operand::Memory(_))) {
+        return None;
+    }
+
+    Some(Decoded {
+        prefix,
+        mnemonic,
+        operands,
+        length: decoder.position,
+    })
+}
+
+/// The operand of a ModRM r/m field.
+enum Rm {
+    Register(u8),
+    Memory(Memory),
+}
+
+struct Decoder<'b> {
+    code: &'b [u8],
+    position: usize,
+    operand_size_override: bool,
+    /// Whether the operand-size override prefix has been taken into
+    /// account, either for the operand size or as a mandatory prefix.
+    operand_size_used: bool,
+    lock: bool,
+    /// A REP prefix, F2 or F3, that has not been taken into account yet.
+    repeat: Option<u8>,
+    gs: bool,
+    rex: Option<u8>,
+}
+
+impl<'b> Decoder<'b> {
+    fn byte(&mut self) -> Option<u8> {
+        let byte = *self.code.get(self.position)?;
+        self.position += 1;
+        Some(byte)
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.code.get(self.position).copied()
+    }
+
+    fn array<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let bytes = self.code.get(self.position..self.position + N)?;
+        self.position += N;
+        bytes.try_into().ok()
+    }
+
+    fn prefixes(&mut self) -> Option<()> {
+        loop {
+            match self.peek()? {
+                0x66 => self.operand_size_override = true,
+                0xf0 => self.lock = true,
+                0xf2 | 0xf3 => self.repeat = Some(self.peek()?),
+                0x65 => self.gs = true,
+                0x40..=0x4f => {
+                    self.rex = Some(self.byte()?);
+                    return Some(());
+                }
+                _ => return Some(()),
+            }
+            self.position += 1;
+        }
+    }
+
+    fn rex_bit(&self, bit: u8) -> u8 {
+        self.rex.map_or(0, |rex| rex >> bit & 1)
+    }
+
+    fn rex_w(&self) -> bool {
+        self.rex_bit(3) != 0
+    }
+
+    /// The operand size of instructions with a 16, 32 or 64-bit operand.
+    fn size(&mut self) -> Size {
+        self.operand_size_used = true;
+        if self.rex_w() {
+            Size::Qword
+        } else if self.operand_size_override {
+            Size::Word
+        } else {
+            Size::Dword
+        }
+    }
+
+    /// Take the mandatory prefix of an SSE instruction, which must be the
+    /// only one.
+    fn mandatory_prefix(&mut self) -> Option<u8> {
+        match (self.operand_size_override, self.repeat.take()) {
+            (true, None) => {
+                self.operand_size_used = true;
+                Some(0x66)
+            }
+            (false, Some(prefix)) => Some(prefix),
+            _ => None,
+        }
+    }
+
+    fn immediate(&mut self, size: Size) -> Option<Operand> {
+        Some(Operand::Immediate(match size {
+            Size::Byte => Immediate::X8(self.array()?),
+            Size::Word => Immediate::X16(self.array()?),
+            // 64-bit operations take a sign-extended 32-bit immediate.
+            Size::Dword | Size::Qword => Immediate::X32(self.array()?),
+            Size::Xmmword => return None,
+        }))
+    }
+
+    fn relative(&mut self) -> Option<Operand> {
+        Some(Operand::Relative(i32::from_le_bytes(self.array()?)))
+    }
+
+    /// The rel8 operand of a short branch.
+    fn relative8(&mut self) -> Option<Operand> {
+        let [offset] = self.array()?;
+        Some(Operand::Relative(i32::from(offset as i8)))
+    }
+
+    fn register(&self, code: u8, size: Size) -> Option<Operand> {
+        Some(match size {
+            // With a REX prefix, codes 4 to 7 are SPL, BPL, SIL and DIL.
+            Size::Byte if self.rex.is_some() && (4..8).contains(&code) => return None,
+            Size::Byte => Operand::R8(R8::from_code(code)),
+            Size::Word => Operand::R16(R16::from_code(code)),
+            Size::Dword => Operand::R32(R32::from_code(code)),
+            Size::Qword => Operand::R64(R64::from_code(code)),
+            Size::Xmmword => Operand::Xmm(Xmm::from_code(code)),
+        })
+    }
+
+    /// Decode a ModRM byte and what follows it, returning the reg field and
+    /// the r/m operand.
+    fn modrm(&mut self) -> Option<(u8, Rm)> {
+        let modrm = self.byte()?;
+        let mod_ = modrm >> 6;
+        let reg = (modrm >> 3 & 0b111) | self.rex_bit(2) << 3;
+        let rm = modrm & 0b111;
+        if mod_ == 0b11 {
+            return Some((reg, Rm::Register(rm | self.rex_bit(0) << 3)));
+        }
+
+        let (base, index) = if rm == 0b100 {
+            let sib = self.byte()?;
+            let index = (sib >> 3 & 0b111) | self.rex_bit(1) << 3;
+            let index = (index != 0b100).then(|| (R64::from_code(index), 1 << (sib >> 6)));
+            // With mod = 00, a base of 101 means no base register.
+            let base = if mod_ == 0b00 && sib & 0b111 == 0b101 {
+                Base::Absolute
+            } else {
+                Base::Register(R64::from_code(sib & 0b111 | self.rex_bit(0) << 3))
+            };
+            (base, index)
+        } else if mod_ == 0b00 && rm == 0b101 {
+            (Base::Rip, None)
+        } else {
+            (
+                Base::Register(R64::from_code(rm | self.rex_bit(0) << 3)),
+                None,
+            )
+        };
+        let displacement = match (mod_, base) {
+            (0b01, _) => Some(Displacement::X8(i8::from_le_bytes(self.array()?))),
+            (0b10, _) | (_, Base::Absolute | Base::Rip) => {
+                Some(Displacement::X32(i32::from_le_bytes(self.array()?)))
+            }
+            _ => None,
+        };
+
+        Some((
+            reg,
+            Rm::Memory(Memory {
+                size: None,
+                gs: self.gs,
+                base,
+                index,
+                displacement,
+            }),
+        ))
+    }
+
+    /// The r/m operand, as a register or memory operand of `size`.
+    fn rm(&self, rm: Rm, size: Size) -> Option<Operand> {
+        match rm {
+            Rm::Register(code) => self.register(code, size),
+            Rm::Memory(memory) => Some(Operand::Memory(Memory {
+                size: Some(size),
+                ..memory
+            })),
+        }
+    }
+
+    /// Decode a ModRM byte for an instruction with a register and an r/m
+    /// operand of the same size.
+    fn reg_rm(&mut self, size: Size) -> Option<(Operand, Operand)> {
+        let (reg, rm) = self.modrm()?;
+        Some((self.register(reg, size)?, self.rm(rm, size)?))
+    }
+
+    fn instruction(&mut self) -> Option<(Mnemonic, Vec<Operand>)> {
+        let opcode = self.byte()?;
+        Some(match opcode {
+            // ALU operations between a register and an r/m operand.
+            0x00..=0x3f if opcode & 0b111 < 0b100 => {
+                let mnemonic = alu(opcode >> 3)?;
+                let size = if opcode & 1 == 0 {
+                    Size::Byte
+                } else {
+                    self.size()
+                };
+                let (reg, rm) = self.reg_rm(size)?;
+                if opcode & 0b10 == 0 {
+                    (mnemonic, vec![rm, reg])
+                } else {
+                    (mnemonic, vec![reg, rm])
+                }
+            }
+            0x50..=0x57 => (
+                Mnemonic::Push,
+                vec![Operand::R64(R64::from_code(
+                    opcode & 0b111 | self.rex_bit(0) << 3,
+                ))],
+            ),
+            0x58..=0x5f => (
+                Mnemonic::Pop,
+                vec![Operand::R64(R64::from_code(
+                    opcode & 0b111 | self.rex_bit(0) << 3,
+                ))],
+            ),
+            0x6a => (Mnemonic::Push, vec![self.immediate(Size::Byte)?]),
+            0x70..=0x7f => (
+                Mnemonic::Jcc(condition(opcode & 0xf)),
+                vec![self.relative8()?],
+            ),
+            0x80 | 0x81 | 0x83 => {
+                let size = if opcode == 0x80 {
+                    Size::Byte
+                } else {
+                    self.size()
+                };
+                let (reg, rm) = self.modrm()?;
+                let rm = self.rm(rm, size)?;
+                let immediate = if opcode == 0x83 { Size::Byte } else { size };
+                (alu(reg & 0b111)?, vec![rm, self.immediate(immediate)?])
+            }
+            0x84 | 0x85 | 0x86 | 0x88 | 0x89 => {
+                let size = if opcode & 1 == 0 {
+                    Size::Byte
+                } else {
+                    self.size()
+                };
+                let (reg, rm) = self.reg_rm(size)?;
+                let mnemonic = match opcode {
+                    0x84 | 0x85 => Mnemonic::Test,
+                    0x86 => Mnemonic::Xchg,
+                    _ => Mnemonic::Mov,
+                };
+                (mnemonic, vec![rm, reg])
+            }
+            0x8a | 0x8b => {
+                let size = if opcode == 0x8a {
+                    Size::Byte
+                } else {
+                    self.size()
+                };
+                let (reg, rm) = self.reg_rm(size)?;
+                (Mnemonic::Mov, vec![reg, rm])
+            }
+            0x8d => {
+                let size = self.size();
+                let (reg, rm) = self.modrm()?;
+                let Rm::Memory(memory) = rm else {
+                    return None;
+                };
+                (
+                    Mnemonic::Lea,
+                    vec![self.register(reg, size)?, Operand::Memory(memory)],
+                )
+            }
+            0x90 if self.rex_bit(0) == 0 => {
+                if self.repeat == Some(0xf3) {
+                    self.repeat = None;
+                    (Mnemonic::Pause, vec![])
+                } else {
+                    (Mnemonic::Nop, vec![])
+                }
+            }
+            0xa4 => (Mnemonic::Movsb, vec![]),
+            0xa6 => (Mnemonic::Cmpsb, vec![]),
+            0xaa => (Mnemonic::Stosb, vec![]),
+            0xab if self.size() == Size::Word => (Mnemonic::Stosw, vec![]),
+            0xae => (Mnemonic::Scasb, vec![]),
+            0xb0..=0xb7 => {
+                let register = self.register(opcode & 0b111 | self.rex_bit(0) << 3, Size::Byte)?;
+                (Mnemonic::Mov, vec![register, self.immediate(Size::Byte)?])
+            }
+            0xb8..=0xbf => {
+                let size = self.size();
+                let register = self.register(opcode & 0b111 | self.rex_bit(0) << 3, size)?;
+                let immediate = if size == Size::Qword {
+                    Operand::Immediate(Immediate::X64(self.array()?))
+                } else {
+                    self.immediate(size)?
+                };
+                (Mnemonic::Mov, vec![register, immediate])
+            }
+            0xc1 | 0xd3 => {
+                let size = self.size();
+                let (reg, rm) = self.modrm()?;
+                let mnemonic = match reg & 0b111 {
+                    4 => Mnemonic::Shl,
+                    5 => Mnemonic::Shr,
+                    _ => return None,
+                };
+                let amount = if opcode == 0xc1 {
+                    self.immediate(Size::Byte)?
+                } else {
+                    Operand::R8(R8::CL)
+                };
+                (mnemonic, vec![self.rm(rm, size)?, amount])
+            }
+            0xc3 => (Mnemonic::Ret, vec![]),
+            0xc6 | 0xc7 => {
+                let size = if opcode == 0xc6 {
+                    Size::Byte
+                } else {
+                    self.size()
+                };
+                let (reg, rm) = self.modrm()?;
+                if reg & 0b111 != 0 {
+                    return None;
+                }
+                (
+                    Mnemonic::Mov,
+                    vec![self.rm(rm, size)?, self.immediate(size)?],
+                )
+            }
+            0xcc => (Mnemonic::Int3, vec![]),
+            0xcf if self.rex_w() => (Mnemonic::Iretq, vec![]),
+            0xe6 => (
+                Mnemonic::Out,
+                vec![self.immediate(Size::Byte)?, Operand::R8(R8::AL)],
+            ),
+            0xe8 => (Mnemonic::Call, vec![self.relative()?]),
+            0xe9 => (Mnemonic::Jmp, vec![self.relative()?]),
+            0xeb => (Mnemonic::Jmp, vec![self.relative8()?]),
+            0xec => (
+                Mnemonic::In,
+                vec![Operand::R8(R8::AL), Operand::R16(R16::DX)],
+            ),
+            0xee => (
+                Mnemonic::Out,
+                vec![Operand::R16(R16::DX), Operand::R8(R8::AL)],
+            ),
+            0xf4 => (Mnemonic::Hlt, vec![]),
+            0xf6 | 0xf7 => {
+                let size = if opcode == 0xf6 {
+                    Size::Byte
+                } else {
+                    self.size()
+                };
+                let (reg, rm) = self.modrm()?;
+                let rm = self.rm(rm, size)?;
+                match reg & 0b111 {
+                    0 => (Mnemonic::Test, vec![rm, self.immediate(size)?]),
+                    3 => (Mnemonic::Neg, vec![rm]),
+                    6 => (Mnemonic::Div, vec![rm]),
+                    _ => return None,
+                }
+            }
+            0xfb => (Mnemonic::Sti, vec![]),
+            0xfc => (Mnemonic::Cld, vec![]),
+            0xff => {
+                let (reg, rm) = self.modrm()?;
+                let size = self.size();
+                match reg & 0b111 {
+                    0 => (Mnemonic::Inc, vec![self.rm(rm, size)?]),
+                    1 => (Mnemonic::Dec, vec![self.rm(rm, size)?]),
+                    // Near branches always take a 64-bit operand.
+                    2 => (Mnemonic::Call, vec![self.rm(rm, Size::Qword)?]),
+                    4 => (Mnemonic::Jmp, vec![self.rm(rm, Size::Qword)?]),
+                    _ => return None,
+                }
+            }
+            0x0f => self.two_byte()?,
+            _ => return None,
+        })
+    }
+
+    /// Decode an instruction with a 0F opcode escape.
+    fn two_byte(&mut self) -> Option<(Mnemonic, Vec<Operand>)> {
+        let opcode = self.byte()?;
+        Some(match opcode {
+            0x01 if self.peek()? == 0xf8 => {
+                self.position += 1;
+                (Mnemonic::Swapgs, vec![])
+            }
+            0x01 => {
+                let (reg, rm) = self.modrm()?;
+                match (reg & 0b111, rm) {
+                    (3, Rm::Memory(memory)) => (Mnemonic::Lidt, vec![Operand::Memory(memory)]),
+                    _ => return None,
+                }
+            }
+            0x05 => (Mnemonic::Syscall, vec![]),
+            0x07 if self.rex_w() => (Mnemonic::Sysretq, vec![]),
+            0x30 => (Mnemonic::Wrmsr, vec![]),
+            0x32 => (Mnemonic::Rdmsr, vec![]),
+            0x80..=0x8f => (
+                Mnemonic::Jcc(condition(opcode & 0xf)),
+                vec![self.relative()?],
+            ),
+            0xa2 => (Mnemonic::Cpuid, vec![]),
+            0xaf | 0xbc => {
+                let size = self.size();
+                let (reg, rm) = self.reg_rm(size)?;
+                let mnemonic = if opcode == 0xaf {
+                    Mnemonic::Imul
+                } else {
+                    Mnemonic::Bsf
+                };
+                (mnemonic, vec![reg, rm])
+            }
+            0xb0 => {
+                let (reg, rm) = self.reg_rm(Size::Byte)?;
+                (Mnemonic::Cmpxchg, vec![rm, reg])
+            }
+            0xb6 => {
+                let size = self.size();
+                let (reg, rm) = self.modrm()?;
+                (
+                    Mnemonic::Movzx,
+                    vec![self.register(reg, size)?, self.rm(rm, Size::Byte)?],
+                )
+            }
+            0x6c | 0x6e | 0x6f | 0x74 | 0x7f | 0xd7 | 0xef => self.sse(opcode)?,
+            _ => return None,
+        })
+    }
+
+    /// Decode an SSE instruction with a 0F opcode escape.
+    fn sse(&mut self, opcode: u8) -> Option<(Mnemonic, Vec<Operand>)> {
+        let prefix = self.mandatory_prefix()?;
+        let (reg, rm) = self.modrm()?;
+        let xmm = Operand::Xmm(Xmm::from_code(reg));
+        Some(match (prefix, opcode) {
+            (0xf3, 0x6f) => (Mnemonic::Movdqu, vec![xmm, self.rm(rm, Size::Xmmword)?]),
+            (0xf3, 0x7f) => (Mnemonic::Movdqu, vec![self.rm(rm, Size::Xmmword)?, xmm]),
+            (0x66, 0x6f) => (Mnemonic::Movdqa, vec![xmm, self.rm(rm, Size::Xmmword)?]),
+            (0x66, 0x6e) if self.rex_w() => (Mnemonic::Movq, vec![xmm, self.rm(rm, Size::Qword)?]),
+            (0x66, 0x6c) => (Mnemonic::Punpcklqdq, vec![xmm, self.rm(rm, Size::Xmmword)?]),
+            (0x66, 0xef) => (Mnemonic::Pxor, vec![xmm, self.rm(rm, Size::Xmmword)?]),
+            (0x66, 0x74) => (Mnemonic::Pcmpeqb, vec![xmm, self.rm(rm, Size::Xmmword)?]),
+            (0x66, 0xd7) => match rm {
+                Rm::Register(code) => (
+                    Mnemonic::Pmovmskb,
+                    vec![
+                        Operand::R32(R32::from_code(reg)),
+                        Operand::Xmm(Xmm::from_code(code)),
+                    ],
+                ),
+                Rm::Memory(_) => return None,
+            },
+            _ => return None,
+        })
+    }
+}
+
+/// The ALU operation selected by bits 5:3 of its opcode, or by the reg field
+/// of the immediate forms.
+fn alu(operation: u8) -> Option<Mnemonic> {
+    Some(match operation {
+        0 => Mnemonic::Add,
+        1 => Mnemonic::Or,
+        4 => Mnemonic::And,
+        5 => Mnemonic::Sub,
+        6 => Mnemonic::Xor,
+        7 => Mnemonic::Cmp,
+        _ => return None,
+    })
+}
+
+fn condition(code: u8) -> Condition {
+    [
+        Condition::Overflow,
+        Condition::NotOverflow,
+        Condition::Below,
+        Condition::AboveOrEqual,
+        Condition::Zero,
+        Condition::NotZero,
+        Condition::BelowOrEqual,
+        Condition::Above,
+        Condition::Sign,
+        Condition::NotSign,
+        Condition::Parity,
+        Condition::NotParity,
+        Condition::Less,
+        Condition::GreaterOrEqual,
+        Condition::LessOrEqual,
+        Condition::Greater,
+    ][code as usize]
+}
+
+/// The name of a register, mnemonic or size in Intel syntax, which is its
+/// variant name in lowercase.
+fn name(value: impl fmt::Debug) -> String {
+    format!("{:?}", value).to_lowercase()
+}
+
+impl fmt::Display for Mnemonic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Jcc(condition) => {
+                let suffix = [
+                    "o", "no", "b", "ae", "z", "nz", "be", "a", "s", "ns", "p", "np", "l", "ge",
+                    "le", "g",
+                ][condition.code() as usize];
+                write!(f, "j{}", suffix)
+            }
+            _ => write!(f, "{}", name(self)),
+        }
+    }
+}
+
+impl fmt::Display for Memory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(size) = self.size {
+            write!(f, "{} ptr ", name(size))?;
+        }
+        if self.gs {
+            write!(f, "gs:")?;
+        }
+        let mut terms = Vec::new();
+        match self.base {
+            Base::Register(base) => terms.push(name(base)),
+            Base::Rip => terms.push("rip".to_string()),
+            Base::Absolute => {}
+        }
+        if let Some((index, scale)) = self.index {
+            let index = name(index);
+            terms.push(match scale {
+                1 => index,
+                _ => format!("{}*{}", scale, index),
+            });
+        }
+        let mut address = terms.join(" + ");
+        let displacement = self.displacement.map_or(0, |d| d.value());
+        if address.is_empty() {
+            address = format!("{:#x}", displacement);
+        } else if displacement < 0 {
+            address += &format!(" - {:#x}", displacement.unsigned_abs());
+        } else if displacement > 0 {
+            address += &format!(" + {:#x}", displacement);
+        }
+        write!(f, "[{}]", address)
+    }
+}
+
+impl fmt::Display for Decoded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.prefix {
+            Some(Prefix::Lock) => write!(f, "lock ")?,
+            Some(Prefix::Rep) => write!(f, "rep ")?,
+            Some(Prefix::Repe) => write!(f, "repe ")?,
+            Some(Prefix::Repne) => write!(f, "repne ")?,
+            None => {}
+        }
+        write!(f, "{}", self.mnemonic)?;
+        for (i, operand) in self.operands.iter().enumerate() {
+            write!(f, "{}", if i == 0 { " " } else { ", " })?;
+            match operand {
+                Operand::R8(register) => write!(f, "{}", name(register))?,
+                Operand::R16(register) => write!(f, "{}", name(register))?,
+                Operand::R32(register) => write!(f, "{}", name(register))?,
+                Operand::R64(register) => write!(f, "{}", name(register))?,
+                Operand::Xmm(register) => write!(f, "{}", name(register))?,
+                Operand::Immediate(immediate) => {
+                    let mut value = [0; 8];
+                    value[..immediate.bytes().len()].copy_from_slice(immediate.bytes());
+                    write!(f, "{:#x}", u64::from_le_bytes(value))?
+                }
+                // Relative to the start of the instruction, like `$` in NASM.
+                Operand::Relative(offset) => {
+                    write!(f, "${:+#x}", self.length as i64 + *offset as i64)?
+                }
+                Operand::Memory(memory) => write!(f, "{}", memory)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        link::{Label, Ptr},
+        x86::{
+            address::{GsIndex, Index, Indirect, ScaledIndex, Times2, Times4, Times8},
+            instruction::{
+                tests::{bytes, cases},
+                *,
+            },
+        },
+    };
+    use Operand as O;
+
+    /// The addressing forms of the encoder.
+    #[derive(Debug)]
+    enum Address {
+        Ptr,
+        Gs(GsIndex),
+        Indirect(Indirect<R64>),
+        Disp8(Index<R64, i8>),
+        Disp32(Index<R64, i32>),
+        Indexed(Index<R64, R64>),
+        Scaled(u8, R64, R64),
+    }
+
+    fn address(memory: &Memory) -> Address {
+        // RBP and R13 take a zero displacement when used without one.
+        let implicit = |base: R64, displacement| {
+            displacement == Some(Displacement::X8(0)) && matches!(base, R64::RBP | R64::R13)
+        };
+        match (memory.gs, memory.base, memory.index, memory.displacement) {
+            (true, Base::Absolute, None, Some(Displacement::X32(d))) => {
+                Address::Gs(GsIndex(d as usize))
+            }
+            (false, Base::Rip, None, Some(Displacement::X32(0))) => Address::Ptr,
+            (false, Base::Register(base), None, d) if d.is_none() || implicit(base, d) => {
+                Address::Indirect(Indirect(base))
+            }
+            (false, Base::Register(base), None, Some(Displacement::X8(d))) => {
+                Address::Disp8(Index(base, d))
+            }
+            (false, Base::Register(base), None, Some(Displacement::X32(d))) => {
+                Address::Disp32(Index(base, d))
+            }
+            (false, Base::Register(base), Some((index, 1)), d)
+                if d.is_none() || implicit(base, d) =>
+            {
+                Address::Indexed(Index(index, base))
+            }
+            (false, Base::Register(base), Some((index, scale)), d)
+                if d.is_none() || implicit(base, d) =>
+            {
+                Address::Scaled(scale, index, base)
+            }
+            _ => panic!("no addressing form for {:?}", memory),
+        }
+    }
+
+    fn x8(immediate: &Immediate) -> u8 {
+        immediate.bytes()[0]
+    }
+
+    fn x16(immediate: &Immediate) -> i16 {
+        i16::from_le_bytes(immediate.bytes().try_into().unwrap())
+    }
+
+    fn x32(immediate: &Immediate) -> u32 {
+        u32::from_le_bytes(immediate.bytes().try_into().unwrap())
+    }
+
+    fn x64(immediate: &Immediate) -> u64 {
+        u64::from_le_bytes(immediate.bytes().try_into().unwrap())
+    }
+
+    /// Encode a decoded instruction again, with the encoder's instruction
+    /// forms. Label references are encoded as zero, like before linking.
+    fn reencode(decoded: &Decoded) -> Vec<u8> {
+        use Mnemonic as M;
+        let target = || Label("target");
+        let ptr = || Ptr("target");
+        let unsupported = || -> Vec<u8> { panic!("no instruction form for {}", decoded) };
+        let operands = decoded.operands.as_slice();
+        match (decoded.prefix, decoded.mnemonic, operands) {
+            (None, M::Hlt, []) => bytes(HLT),
+            (None, M::Jmp, [O::Relative(0)]) => bytes(JMP(target())),
+            (None, M::Jmp, [O::R64(r)]) => bytes(JMP(*r)),
+            (None, M::Jcc(c), [O::Relative(0)]) => bytes(JCC(c, target())),
+            (None, M::Call, [O::Relative(0)]) => bytes(CALL(target())),
+            (None, M::Call, [O::R64(r)]) => bytes(CALL(*r)),
+            (None, M::Ret, []) => bytes(RET),
+            (None, M::Iretq, []) => bytes(IRET),
+            (None, M::Swapgs, []) => bytes(SWAPGS),
+            (None, M::Syscall, []) => bytes(SYSCALL),
+            (None, M::Sysretq, []) => bytes(SYSRET),
+            (None, M::Lidt, [O::Memory(m)]) => match address(m) {
+                Address::Ptr => bytes(LIDT(ptr())),
+                Address::Indirect(a) => bytes(LIDT(a)),
+                _ => unsupported(),
+            },
+            (None, M::Sti, []) => bytes(STI),
+            (None, M::Cld, []) => bytes(CLD),
+            (None, M::Rdmsr, []) => bytes(RDMSR),
+            (None, M::Wrmsr, []) => bytes(WRMSR),
+            (None, M::Cpuid, []) => bytes(CPUID),
+            (None, M::Pause, []) => bytes(PAUSE),
+            (None, M::Nop, []) => bytes(NOP),
+            (None, M::Int3, []) => bytes(INT3),
+            (None, M::Push, [O::R64(r)]) => bytes(PUSH(*r)),
+            (None, M::Push, [O::Immediate(i)]) => bytes(PUSH(x8(i) as i8)),
+            (None, M::Pop, [O::R64(r)]) => bytes(POP(*r)),
+            (None, M::Mov, [O::R64(r), O::Immediate(i @ Immediate::X64(_))]) => {
+                bytes(MOV(*r, x64(i)))
+            }
+            (None, M::Mov, [O::R8(r), O::Immediate(i)]) => bytes(MOV(*r, x8(i))),
+            (None, M::Mov, [O::R64(r), O::R64(s)]) => bytes(MOV(*r, *s)),
+            (None, M::Mov, [O::R32(r), O::R32(s)]) => bytes(MOV(*r, *s)),
+            (None, M::Mov, [O::R64(r), O::Memory(m)]) => match address(m) {
+                Address::Ptr => bytes(MOV(*r, ptr())),
+                Address::Gs(a) => bytes(MOV(*r, a)),
+                Address::Indirect(a) => bytes(MOV(*r, a)),
+                Address::Disp8(a) => bytes(MOV(*r, a)),
+                Address::Disp32(a) => bytes(MOV(*r, a)),
+                Address::Indexed(a) => bytes(MOV(*r, a)),
+                Address::Scaled(2, i, b) => bytes(MOV(*r, ScaledIndex(Times2, i, b))),
+                Address::Scaled(4, i, b) => bytes(MOV(*r, ScaledIndex(Times4, i, b))),
+                Address::Scaled(8, i, b) => bytes(MOV(*r, ScaledIndex(Times8, i, b))),
+                _ => unsupported(),
+            },
+            (None, M::Mov, [O::R32(r), O::Memory(m)]) => match address(m) {
+                Address::Ptr => bytes(MOV(*r, ptr())),
+                Address::Gs(a) => bytes(MOV(*r, a)),
+                Address::Disp8(a) => bytes(MOV(*r, a)),
+                _ => unsupported(),
+            },
+            (None, M::Mov, [O::R16(r), O::Memory(m)]) => match address(m) {
+                Address::Ptr => bytes(MOV(*r, ptr())),
+                Address::Gs(a) => bytes(MOV(*r, a)),
+                _ => unsupported(),
+            },
+            (None, M::Mov, [O::R8(r), O::Memory(m)]) => match address(m) {
+                Address::Ptr => bytes(MOV(*r, ptr())),
+                Address::Gs(a) => bytes(MOV(*r, a)),
+                Address::Indexed(a) => bytes(MOV(*r, a)),
+                _ => unsupported(),
+            },
+            (None, M::Mov, [O::Memory(m), O::R64(r)]) => match address(m) {
+                Address::Ptr => bytes(MOV(ptr(), *r)),
+                Address::Gs(a) => bytes(MOV(a, *r)),
+                Address::Indirect(a) => bytes(MOV(a, *r)),
+                Address::Disp8(a) => bytes(MOV(a, *r)),
+                Address::Disp32(a) => bytes(MOV(a, *r)),
+                _ => unsupported(),
+            },
+            (None, M::Mov, [O::Memory(m), O::R32(r)]) => match address(m) {
+                Address::Ptr => bytes(MOV(ptr(), *r)),
+                Address::Gs(a) => bytes(MOV(a, *r)),
+                Address::Disp8(a) => bytes(MOV(a, *r)),
+                _ => unsupported(),
+            },
+            (None, M::Mov, [O::Memory(m), O::R16(r)]) => match address(m) {
+                Address::Ptr => bytes(MOV(ptr(), *r)),
+                Address::Gs(a) => bytes(MOV(a, *r)),
+                Address::Disp8(a) => bytes(MOV(a, *r)),
+                _ => unsupported(),
+            },
+            (None, M::Mov, [O::Memory(m), O::R8(r)]) => match address(m) {
+                Address::Ptr => bytes(MOV(ptr(), *r)),
+                Address::Gs(a) => bytes(MOV(a, *r)),
+                Address::Indirect(a) => bytes(MOV(a, *r)),
+                Address::Indexed(a) => bytes(MOV(a, *r)),
+                _ => unsupported(),
+            },
+            (None, M::Mov, [O::Memory(m), O::Immediate(i @ Immediate::X8(_))]) => {
+                match address(m) {
+                    Address::Indirect(a) => bytes(MOV(a, x8(i))),
+                    _ => unsupported(),
+                }
+            }
+            (None, M::Mov, [O::Memory(m), O::Immediate(i @ Immediate::X32(_))]) => {
+                match address(m) {
+                    Address::Disp32(a) => bytes(MOV(a, x32(i))),
+                    _ => unsupported(),
+                }
+            }
+            (None, M::Xchg, [O::Memory(m), O::R8(r)]) => match address(m) {
+                Address::Ptr => bytes(XCHG(ptr(), *r)),
+                _ => unsupported(),
+            },
+            (Some(Prefix::Lock), M::Xchg, [O::Memory(m), O::R8(r)]) => match address(m) {
+                Address::Ptr => bytes(LOCK(XCHG(ptr(), *r))),
+                _ => unsupported(),
+            },
+            (None, M::Cmpxchg, [O::Memory(m), O::R8(r)]) => match address(m) {
+                Address::Ptr => bytes(CMPXCHG(ptr(), *r)),
+                _ => unsupported(),
+            },
+            (Some(Prefix::Lock), M::Cmpxchg, [O::Memory(m), O::R8(r)]) => match address(m) {
+                Address::Ptr => bytes(LOCK(CMPXCHG(ptr(), *r))),
+                _ => unsupported(),
+            },
+            (None, M::Movzx, [O::R64(r), O::Memory(m)]) => match address(m) {
+                Address::Disp8(a) => bytes(MOVZX(*r, a)),
+                Address::Indexed(a) => bytes(MOVZX(*r, a)),
+                _ => unsupported(),
+            },
+            (None, M::Lea, [O::R64(r), O::Memory(m)]) => match address(m) {
+                Address::Ptr => bytes(LEA(*r, ptr())),
+                Address::Disp8(a) => bytes(LEA(*r, a)),
+                _ => unsupported(),
+            },
+            (None, M::Add, [O::R64(r), O::Immediate(i @ Immediate::X8(_))]) => {
+                bytes(ADD(*r, x8(i) as i8))
+            }
+            (None, M::Add, [O::R64(r), O::Immediate(i)]) => bytes(ADD(*r, x32(i) as i32)),
+            (None, M::Add, [O::R64(r), O::R64(s)]) => bytes(ADD(*r, *s)),
+            (None, M::Sub, [O::R64(r), O::Immediate(i @ Immediate::X8(_))]) => {
+                bytes(SUB(*r, x8(i) as i8))
+            }
+            (None, M::Sub, [O::R64(r), O::Immediate(i)]) => bytes(SUB(*r, x32(i) as i32)),
+            (None, M::Sub, [O::R64(r), O::R64(s)]) => bytes(SUB(*r, *s)),
+            (None, M::Cmp, [O::Memory(m), O::Immediate(i)]) => match address(m) {
+                Address::Indexed(a) => bytes(CMP(a, x8(i))),
+                _ => unsupported(),
+            },
+            (None, M::Cmp, [O::R64(r), O::R64(s)]) => bytes(CMP(*r, *s)),
+            (None, M::Cmp, [O::R64(r), O::Immediate(i @ Immediate::X8(_))]) => {
+                bytes(CMP(*r, x8(i) as i8))
+            }
+            (None, M::Cmp, [O::R64(r), O::Immediate(i)]) => bytes(CMP(*r, x32(i) as i32)),
+            (None, M::Test, [O::R64(r), O::R64(s)]) => bytes(TEST(*r, *s)),
+            (None, M::Test, [O::R8(r), O::R8(s)]) => bytes(TEST(*r, *s)),
+            (None, M::Test, [O::R64(r), O::Immediate(i)]) => bytes(TEST(*r, x32(i) as i32)),
+            (None, M::Test, [O::Memory(m), O::Immediate(i)]) => match address(m) {
+                Address::Disp8(a) => bytes(TEST(a, x8(i))),
+                _ => unsupported(),
+            },
+            (None, M::Or, [O::Memory(m), O::Immediate(i @ Immediate::X16(_))]) => {
+                match address(m) {
+                    Address::Disp8(a) => bytes(OR(a, x16(i))),
+                    _ => unsupported(),
+                }
+            }
+            (None, M::Or, [O::Memory(m), O::Immediate(i)]) => match address(m) {
+                Address::Disp8(a) => bytes(OR(a, x8(i))),
+                _ => unsupported(),
+            },
+            (None, M::Or, [O::R64(r), O::Immediate(i)]) => bytes(OR(*r, x32(i) as i32)),
+            (None, M::Or, [O::R64(r), O::R64(s)]) => bytes(OR(*r, *s)),
+            (None, M::And, [O::R64(r), O::Immediate(i)]) => bytes(AND(*r, x8(i) as i8)),
+            (None, M::And, [O::R64(r), O::R64(s)]) => bytes(AND(*r, *s)),
+            (None, M::Xor, [O::R64(r), O::R64(s)]) => bytes(XOR(*r, *s)),
+            (None, M::Xor, [O::R64(r), O::Immediate(i)]) => bytes(XOR(*r, x32(i) as i32)),
+            (None, M::Shl, [O::R64(r), O::Immediate(i)]) => bytes(SHL(*r, x8(i) as i8)),
+            (None, M::Shr, [O::R64(r), O::Immediate(i)]) => bytes(SHR(*r, x8(i) as i8)),
+            (None, M::Shr, [O::R64(r), O::R8(s)]) => bytes(SHR(*r, *s)),
+            (None, M::Inc, [O::R64(r)]) => bytes(INC(*r)),
+            (None, M::Dec, [O::R64(r)]) => bytes(DEC(*r)),
+            (None, M::Neg, [O::R64(r)]) => bytes(NEG(*r)),
+            (None, M::Div, [O::R64(r)]) => bytes(DIV(*r)),
+            (None, M::In, [O::R8(r), O::R16(s)]) => bytes(IN(*r, *s)),
+            (None, M::Out, [O::R16(r), O::R8(s)]) => bytes(OUT(*r, *s)),
+            (None, M::Out, [O::Immediate(i), O::R8(s)]) => bytes(OUT(x8(i), *s)),
+            (None, M::Imul, [O::R64(r), O::R64(s)]) => bytes(IMUL(*r, *s)),
+            (None, M::Bsf, [O::R64(r), O::R64(s)]) => bytes(BSF(*r, *s)),
+            (None, M::Movsb, []) => bytes(MOVSB),
+            (None, M::Stosb, []) => bytes(STOSB),
+            (None, M::Stosw, []) => bytes(STOSW),
+            (None, M::Cmpsb, []) => bytes(CMPSB),
+            (None, M::Scasb, []) => bytes(SCASB),
+            (Some(Prefix::Rep), M::Movsb, []) => bytes(REP(MOVSB)),
+            (Some(Prefix::Rep), M::Stosb, []) => bytes(REP(STOSB)),
+            (Some(Prefix::Rep), M::Stosw, []) => bytes(REP(STOSW)),
+            (Some(Prefix::Repe), M::Cmpsb, []) => bytes(REPE(CMPSB)),
+            (Some(Prefix::Repne), M::Scasb, []) => bytes(REPNE(SCASB)),
+            (None, M::Movdqu, [O::Xmm(x), O::Memory(m)]) => match address(m) {
+                Address::Indexed(a) => bytes(MOVDQU(*x, a)),
+                _ => unsupported(),
+            },
+            (None, M::Movdqu, [O::Memory(m), O::Xmm(x)]) => match address(m) {
+                Address::Indexed(a) => bytes(MOVDQU(a, *x)),
+                _ => unsupported(),
+            },
+            (None, M::Movdqa, [O::Xmm(x), O::Memory(m)]) => match address(m) {
+                Address::Indirect(a) => bytes(MOVDQA(*x, a)),
+                _ => unsupported(),
+            },
+            (None, M::Movq, [O::Xmm(x), O::R64(r)]) => bytes(MOVQ(*x, *r)),
+            (None, M::Punpcklqdq, [O::Xmm(x), O::Xmm(y)]) => bytes(PUNPCKLQDQ(*x, *y)),
+            (None, M::Pxor, [O::Xmm(x), O::Xmm(y)]) => bytes(PXOR(*x, *y)),
+            (None, M::Pcmpeqb, [O::Xmm(x), O::Xmm(y)]) => bytes(PCMPEQB(*x, *y)),
+            (None, M::Pmovmskb, [O::R32(r), O::Xmm(x)]) => bytes(PMOVMSKB(*r, *x)),
+            _ => unsupported(),
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        for (encoding, syntax) in cases() {
+            let decoded = decode(&encoding)
+                .unwrap_or_else(|| panic!("{}: {:02x?} did not decode", syntax, encoding));
+            assert_eq!(decoded.length, encoding.len(), "{}: {}", syntax, decoded);
+            assert_eq!(reencode(&decoded), encoding, "{}: {}", syntax, decoded);
+        }
+    }
+
+    #[test]
+    fn display() {
+        let display = |encoding: Vec<u8>| decode(&encoding).unwrap().to_string();
+        assert_eq!(
+            display(bytes(MOV(R64::R14, Index(R64::R13, -128i8)))),
+            "mov r14, qword ptr [r13 - 0x80]"
+        );
+        assert_eq!(
+            display(bytes(MOV(
+                R64::RAX,
+                ScaledIndex(Times8, R64::R12, R64::RBX)
+            ))),
+            "mov rax, qword ptr [rbx + 8*r12]"
+        );
+        assert_eq!(
+            display(bytes(MOV(GsIndex(0x10), R16::R9W))),
+            "mov word ptr gs:[0x10], r9w"
+        );
+        assert_eq!(
+            display(bytes(LOCK(CMPXCHG(Ptr("target"), R8::CL)))),
+            "lock cmpxchg byte ptr [rip], cl"
+        );
+        assert_eq!(display(bytes(REP(STOSW))), "rep stosw");
+        assert_eq!(
+            display(bytes(JCC(Condition::Below, Label("target")))),
+            "jb $+0x6"
+        );
+        assert_eq!(display(bytes(ADD(R64::RSP, -8i8))), "add rsp, 0xf8");
+        // Short branches, as produced by branch relaxation.
+        assert_eq!(display(vec![0xeb, 0xfe]), "jmp $+0x0");
+        assert_eq!(display(vec![0x75, 0x10]), "jnz $+0x12");
+    }
+
+    #[test]
+    fn unsupported() {
+        // Cut off.
+        assert_eq!(decode(&[0x48, 0x8b]), None);
+        assert_eq!(decode(&[0xe9, 0, 0]), None);
+        // An unsupported opcode, and a byte register that needs REX.
+        assert_eq!(decode(&[0x0f, 0x0b]), None);
+        assert_eq!(decode(&[0x40, 0x88, 0xf0]), None);
+        // A prefix that the instruction does not use.
+        assert_eq!(decode(&[0xf3, 0xc3]), None);
+        assert_eq!(decode(&[0xf0, 0x48, 0x89, 0xc8]), None);
+    }
+}
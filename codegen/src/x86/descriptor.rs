@@ -0,0 +1,239 @@
+//! Descriptor table entries, and a builder for the interrupt descriptor
+//! table.
+
+use bytemuck::Zeroable;
+
+use crate::{
+    dwarf::DebugType,
+    layout::layout,
+    link::{Label, ReferenceFormat, Segment},
+};
+
+/// Gate type of an interrupt gate, which clears IF on entry.
+pub const GATE_INTERRUPT: u8 = 0x0e;
+/// Gate type of a trap gate, which leaves IF unchanged.
+pub const GATE_TRAP: u8 = 0x0f;
+/// Present bit of a gate or segment descriptor's attributes.
+pub const DESCRIPTOR_PRESENT: u8 = 0x80;
+/// Shift of the descriptor privilege level within the attributes.
+pub const DPL_SHIFT: u8 = 5;
+
+layout! {
+    /// A 64-bit mode IDT entry.
+    pub struct IdtGate {
+        /// Offset 15..0 of the handler.
+        pub offset_low: u16 => OFFSET_LOW,
+        /// Code segment selector of the handler.
+        pub selector: u16 => SELECTOR,
+        /// Interrupt stack table index, or 0 to not switch stacks.
+        pub ist: u8 => IST,
+        /// Gate type, DPL and present bit.
+        pub attributes: u8 => ATTRIBUTES,
+        /// Offset 31..16 of the handler.
+        pub offset_mid: u16 => OFFSET_MID,
+        /// Offset 63..32 of the handler.
+        pub offset_high: u32 => OFFSET_HIGH,
+        pub reserved: u32 => RESERVED,
+    }
+}
+
+layout! {
+    /// A GDT code or data segment descriptor. In 64-bit mode, the base and
+    /// limit are ignored for code and data segments.
+    pub struct SegmentDescriptor {
+        pub limit_low: u16 => LIMIT_LOW,
+        pub base_low: u16 => BASE_LOW,
+        pub base_mid: u8 => BASE_MID,
+        /// Segment type, S, DPL and present bit.
+        pub access: u8 => ACCESS,
+        /// Limit 19..16 in the low nibble, flags (G, D/B, L, AVL) in the
+        /// high nibble.
+        pub flags_limit_high: u8 => FLAGS_LIMIT_HIGH,
+        pub base_high: u8 => BASE_HIGH,
+    }
+}
+
+const _: () = assert!(IdtGate::SIZE == 16);
+const _: () = assert!(SegmentDescriptor::SIZE == 8);
+
+/// Number of vectors, and so of gates in a full IDT.
+pub const IDT_ENTRIES: usize = 256;
+
+/// An IDT gate for a handler.
+#[derive(Debug, Clone, Copy)]
+pub struct Gate {
+    pub handler: Label,
+    /// Code segment selector of the handler.
+    pub selector: u16,
+    /// Interrupt stack table index, or 0 to not switch stacks.
+    pub ist: u8,
+    /// [`GATE_INTERRUPT`] or [`GATE_TRAP`].
+    pub gate_type: u8,
+    /// Highest privilege level allowed to raise the interrupt with `INT`.
+    pub dpl: u8,
+}
+
+impl Gate {
+    /// An interrupt gate for `handler`, which can only be raised by the
+    /// kernel.
+    pub fn interrupt(handler: impl Into<Label>, selector: u16) -> Self {
+        Self {
+            handler: handler.into(),
+            selector,
+            ist: 0,
+            gate_type: GATE_INTERRUPT,
+            dpl: 0,
+        }
+    }
+
+    /// A trap gate for `handler`, which can only be raised by the kernel.
+    pub fn trap(handler: impl Into<Label>, selector: u16) -> Self {
+        Self {
+            gate_type: GATE_TRAP,
+            ..Self::interrupt(handler, selector)
+        }
+    }
+
+    pub fn ist(self, ist: u8) -> Self {
+        Self { ist, ..self }
+    }
+
+    pub fn dpl(self, dpl: u8) -> Self {
+        Self { dpl, ..self }
+    }
+}
+
+/// Builds an IDT of [`IDT_ENTRIES`] gates at build time.
+///
+/// Handler addresses are split across the offset fields of their gates by
+/// the linker, with [`ReferenceFormat::GateOffset`], so the table needs no
+/// patching at run time and can be read-only. Vectors without a handler are
+/// not present.
+pub struct IdtBuilder {
+    gates: Vec<Option<Gate>>,
+}
+
+impl Default for IdtBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IdtBuilder {
+    pub fn new() -> Self {
+        Self {
+            gates: vec![None; IDT_ENTRIES],
+        }
+    }
+
+    pub fn set(&mut self, vector: u8, gate: Gate) {
+        assert!(gate.ist <= 7, "IST index {} out of range", gate.ist);
+        assert!(gate.dpl <= 3, "DPL {} out of range", gate.dpl);
+        assert!(
+            matches!(gate.gate_type, GATE_INTERRUPT | GATE_TRAP),
+            "unsupported gate type {:#x}",
+            gate.gate_type
+        );
+        let slot = &mut self.gates[vector as usize];
+        assert!(slot.is_none(), "vector {} already has a handler", vector);
+        *slot = Some(gate);
+    }
+
+    /// Emit the table into `segment` at the exported label `idt`, followed
+    /// by the operand of `LIDT` at the exported label `idtr`.
+    pub fn emit(&self, segment: &mut Segment, idt: impl Into<Label>, idtr: impl Into<Label>) {
+        let idt = idt.into();
+        segment.pad_to_alignment(IdtGate::SIZE);
+        segment.export_label(idt);
+        segment.label_type(idt, <[IdtGate; IDT_ENTRIES]>::debug_type());
+        for gate in &self.gates {
+            match gate {
+                Some(gate) => {
+                    segment.reference(gate.handler, ReferenceFormat::GateOffset);
+                    segment.append(&IdtGate {
+                        selector: gate.selector,
+                        ist: gate.ist,
+                        attributes: DESCRIPTOR_PRESENT | gate.dpl << DPL_SHIFT | gate.gate_type,
+                        ..IdtGate::zeroed()
+                    });
+                }
+                None => segment.append(&IdtGate::zeroed()),
+            }
+        }
+
+        segment.pad_to_alignment(8);
+        segment.export_label(idtr);
+        segment.append(&((IDT_ENTRIES * IdtGate::SIZE - 1) as u16)); // Limit
+        segment.append_reference(idt, ReferenceFormat::Abs64);
+    }
+}
+
+// Linking the table needs the linker.
+#[cfg(all(test, feature = "image"))]
+mod tests {
+    use super::*;
+    use crate::{
+        elf64::program::{PF_R, PF_X},
+        link::ElfLinker,
+    };
+
+    #[test]
+    fn linked_gates() {
+        let mut idt = IdtBuilder::new();
+        idt.set(3, Gate::trap("breakpoint", 0x08).ist(2).dpl(3));
+        idt.set(14, Gate::interrupt("page_fault", 0x28));
+        let mut tables = Segment::new();
+        idt.emit(&mut tables, "idt", "idtr");
+
+        let mut code = Segment::new();
+        code.export_label("breakpoint");
+        code.extend([0x48, 0xcf]);
+        code.pad_to_alignment(0x1000);
+        code.export_label("page_fault");
+        code.extend([0x48, 0xcf]);
+
+        let mut linker = ElfLinker::new();
+        linker.set_base_address(0xffff_ffff_8000_0000);
+        linker.add_segment("code", PF_R | PF_X, 1 << 12, code);
+        linker.add_segment("tables", PF_R, 1 << 12, tables);
+        linker.set_entry("breakpoint");
+        let linked = linker.finish();
+        let tables = &linked.segments()[1];
+        let data = tables.data();
+        let gate = |vector: usize| -> IdtGate {
+            bytemuck::pod_read_unaligned(&data[vector * IdtGate::SIZE..][..IdtGate::SIZE])
+        };
+
+        let handler = linked.label_address("breakpoint").unwrap();
+        let breakpoint = gate(3);
+        assert_eq!(breakpoint.offset_low, handler as u16);
+        assert_eq!(breakpoint.offset_mid, (handler >> 16) as u16);
+        assert_eq!(breakpoint.offset_high, (handler >> 32) as u32);
+        assert_eq!(breakpoint.selector, 0x08);
+        assert_eq!(breakpoint.ist, 2);
+        assert_eq!(
+            breakpoint.attributes,
+            DESCRIPTOR_PRESENT | 3 << DPL_SHIFT | GATE_TRAP
+        );
+
+        let handler = linked.label_address("page_fault").unwrap();
+        assert_eq!(handler & 0xffff, 0x1000);
+        let page_fault = gate(14);
+        assert_eq!(page_fault.offset_low, 0x1000);
+        assert_eq!(page_fault.offset_mid, (handler >> 16) as u16);
+        assert_eq!(page_fault.offset_high, 0xffff_ffff);
+        assert_eq!(page_fault.selector, 0x28);
+        assert_eq!(page_fault.ist, 0);
+        assert_eq!(page_fault.attributes, DESCRIPTOR_PRESENT | GATE_INTERRUPT);
+
+        // Vectors without a handler are not present.
+        assert_eq!(bytemuck::bytes_of(&gate(4)), [0; IdtGate::SIZE]);
+
+        let idt = linked.label_address("idt").unwrap();
+        assert_eq!(idt, tables.header().p_vaddr);
+        let idtr = (linked.label_address("idtr").unwrap() - idt) as usize;
+        assert_eq!(idtr % 8, 0);
+        assert_eq!(data[idtr..][..2], 4095u16.to_le_bytes());
+        assert_eq!(data[idtr + 2..][..8], idt.to_le_bytes());
+    }
+}
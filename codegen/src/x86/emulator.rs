@@ -0,0 +1,973 @@
+//! An interpreter for the general-purpose subset of the instructions that
+//! the [encoder](super::instruction) emits, for testing generated routines
+//! on the host.
+//!
+//! Instructions are decoded with the [decoder](super::decode), and act on
+//! the general-purpose registers, the arithmetic flags and mapped regions of
+//! memory. System, port I/O and SSE instructions are not supported, and
+//! neither is anything that would fault on hardware, like accesses to
+//! unmapped memory: these panic, naming the instruction.
+
+use super::{
+    decode::{decode, Base, Decoded, Memory, Mnemonic, Operand, Prefix, Size},
+    instruction::Condition,
+    register::{Register, R64},
+};
+use crate::{elf64::common::Addr, link::Linked};
+
+/// The return address pushed by [`Machine::call`], which is never mapped.
+const RETURN_ADDRESS: Addr = 0xdead_0000_0000;
+
+/// Where [`Machine::load`] maps the stack, and its size.
+const STACK_BASE: Addr = 0x7fff_0000_0000;
+const STACK_SIZE: usize = 1 << 16;
+
+/// How many instructions a call may execute before it is taken to be stuck.
+const STEP_LIMIT: usize = 10_000_000;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Flags {
+    pub carry: bool,
+    pub parity: bool,
+    pub zero: bool,
+    pub sign: bool,
+    pub overflow: bool,
+    pub direction: bool,
+}
+
+impl Flags {
+    fn condition(&self, condition: Condition) -> bool {
+        match condition {
+            Condition::Overflow => self.overflow,
+            Condition::NotOverflow => !self.overflow,
+            Condition::Below => self.carry,
+            Condition::AboveOrEqual => !self.carry,
+            Condition::Zero => self.zero,
+            Condition::NotZero => !self.zero,
+            Condition::BelowOrEqual => self.carry || self.zero,
+            Condition::Above => !self.carry && !self.zero,
+            Condition::Sign => self.sign,
+            Condition::NotSign => !self.sign,
+            Condition::Parity => self.parity,
+            Condition::NotParity => !self.parity,
+            Condition::Less => self.sign != self.overflow,
+            Condition::GreaterOrEqual => self.sign == self.overflow,
+            Condition::LessOrEqual => self.zero || self.sign != self.overflow,
+            Condition::Greater => !self.zero && self.sign == self.overflow,
+        }
+    }
+
+    /// Set the zero, sign and parity flags from a result of `bytes` bytes.
+    fn set_result(&mut self, result: u64, bytes: usize) {
+        self.zero = result & mask(bytes) == 0;
+        self.sign = result & sign_bit(bytes) != 0;
+        self.parity = (result as u8).count_ones().is_multiple_of(2);
+    }
+}
+
+/// A contiguous region of mapped memory.
+struct Region {
+    start: Addr,
+    data: Vec<u8>,
+}
+
+pub struct Machine {
+    registers: [u64; 16],
+    pub rip: Addr,
+    pub flags: Flags,
+    /// The base address of GS-relative memory operands.
+    pub gs_base: Addr,
+    regions: Vec<Region>,
+}
+
+impl Machine {
+    /// A machine with no memory mapped, and all registers zero.
+    fn new() -> Self {
+        Self {
+            registers: [0; 16],
+            rip: 0,
+            flags: Flags::default(),
+            gs_base: 0,
+            regions: Vec::new(),
+        }
+    }
+
+    /// A machine with every segment of a linked image mapped at its virtual
+    /// address, and RSP at the top of a separately mapped stack.
+    pub fn load(linked: &Linked) -> Self {
+        let mut machine = Self::new();
+        for segment in linked.segments() {
+            let mut data = segment.data().to_vec();
+            data.resize(segment.header().p_memsz as usize, 0);
+            machine.map(segment.header().p_vaddr, data);
+        }
+        machine.map(STACK_BASE, vec![0; STACK_SIZE]);
+        machine.set_register(R64::RSP, STACK_BASE + STACK_SIZE as u64);
+        machine
+    }
+
+    /// Map `data` at `address`. Panics if it overlaps mapped memory.
+    pub fn map(&mut self, address: Addr, data: Vec<u8>) {
+        let end = address + data.len() as u64;
+        assert!(
+            self.regions.iter().all(|region| {
+                end <= region.start || region.start + region.data.len() as u64 <= address
+            }),
+            "mapping at {:#x}..{:#x} overlaps mapped memory",
+            address,
+            end
+        );
+        self.regions.push(Region {
+            start: address,
+            data,
+        });
+    }
+
+    pub fn register(&self, register: R64) -> u64 {
+        self.registers[index(register)]
+    }
+
+    pub fn set_register(&mut self, register: R64, value: u64) {
+        self.registers[index(register)] = value;
+    }
+
+    /// The `len` bytes of memory at `address`.
+    pub fn read(&self, address: Addr, len: usize) -> &[u8] {
+        let (region, offset) = self.region(address, len);
+        &self.regions[region].data[offset..][..len]
+    }
+
+    pub fn write(&mut self, address: Addr, bytes: &[u8]) {
+        let (region, offset) = self.region(address, bytes.len());
+        self.regions[region].data[offset..][..bytes.len()].copy_from_slice(bytes);
+    }
+
+    /// The null-terminated string at `address`, without its terminator.
+    pub fn read_c_string(&self, address: Addr) -> &[u8] {
+        let (region, offset) = self.region(address, 1);
+        let data = &self.regions[region].data[offset..];
+        let len = data
+            .iter()
+            .position(|&byte| byte == 0)
+            .unwrap_or_else(|| panic!("string at {:#x} is not terminated", address));
+        &data[..len]
+    }
+
+    /// Call the function at `address` with up to six integer arguments, as
+    /// in the System V calling convention, and return RAX once it returns.
+    pub fn call(&mut self, address: Addr, args: &[u64]) -> u64 {
+        const ARGS: [R64; 6] = [R64::RDI, R64::RSI, R64::RDX, R64::RCX, R64::R8, R64::R9];
+        assert!(args.len() <= ARGS.len(), "too many arguments");
+        for (&register, &arg) in ARGS.iter().zip(args) {
+            self.set_register(register, arg);
+        }
+        self.push(RETURN_ADDRESS);
+        self.rip = address;
+        self.run_until(RETURN_ADDRESS);
+        self.register(R64::RAX)
+    }
+
+    /// Step until RIP reaches `address`.
+    pub fn run_until(&mut self, address: Addr) {
+        for _ in 0..STEP_LIMIT {
+            if self.rip == address {
+                return;
+            }
+            self.step();
+        }
+        panic!("no return after {} instructions", STEP_LIMIT);
+    }
+
+    /// Execute one instruction.
+    pub fn step(&mut self) {
+        let (region, offset) = self.region(self.rip, 1);
+        let decoded = decode(&self.regions[region].data[offset..])
+            .unwrap_or_else(|| panic!("can not decode the instruction at {:#x}", self.rip));
+        let address = self.rip;
+        self.rip += decoded.length as u64;
+        self.execute(&decoded)
+            .unwrap_or_else(|| panic!("`{}` at {:#x} is not supported", decoded, address));
+    }
+
+    /// Execute a decoded instruction, with RIP already past it, or return
+    /// `None` if it is not supported.
+    fn execute(&mut self, decoded: &Decoded) -> Option<()> {
+        use Mnemonic as M;
+
+        let operands = decoded.operands.as_slice();
+        match (decoded.mnemonic, operands) {
+            (M::Nop | M::Pause, []) => {}
+            (M::Cld, []) => self.flags.direction = false,
+
+            (M::Mov, [dest, src]) => {
+                let value = self.read_operand(src, size(dest)?)?;
+                self.write_operand(dest, value)?;
+            }
+            (M::Movzx, [dest, src]) => {
+                let value = self.read_operand(src, size(src)?)?;
+                self.write_operand(dest, value)?;
+            }
+            (M::Lea, [dest, Operand::Memory(memory)]) => {
+                let address = self.address(memory);
+                self.write_operand(dest, address)?;
+            }
+            (M::Xchg, [a, b]) => {
+                let bytes = size(a)?;
+                let (x, y) = (self.read_operand(a, bytes)?, self.read_operand(b, bytes)?);
+                self.write_operand(a, y)?;
+                self.write_operand(b, x)?;
+            }
+
+            (M::Add | M::Sub | M::Cmp | M::And | M::Or | M::Xor | M::Test, [dest, src]) => {
+                let bytes = size(dest)?;
+                let (a, b) = (
+                    self.read_operand(dest, bytes)?,
+                    self.read_operand(src, bytes)?,
+                );
+                let result = match decoded.mnemonic {
+                    M::Add => self.add(a, b, bytes),
+                    M::Sub | M::Cmp => self.sub(a, b, bytes),
+                    M::And | M::Test => self.logic(a & b, bytes),
+                    M::Or => self.logic(a | b, bytes),
+                    _ => self.logic(a ^ b, bytes),
+                };
+                if !matches!(decoded.mnemonic, M::Cmp | M::Test) {
+                    self.write_operand(dest, result)?;
+                }
+            }
+            (M::Inc | M::Dec | M::Neg, [dest]) => {
+                let bytes = size(dest)?;
+                let a = self.read_operand(dest, bytes)?;
+                let carry = self.flags.carry;
+                let result = match decoded.mnemonic {
+                    M::Inc => self.add(a, 1, bytes),
+                    M::Dec => self.sub(a, 1, bytes),
+                    _ => self.sub(0, a, bytes),
+                };
+                // INC and DEC leave the carry flag unchanged.
+                if decoded.mnemonic != M::Neg {
+                    self.flags.carry = carry;
+                }
+                self.write_operand(dest, result)?;
+            }
+            (M::Shl | M::Shr, [dest, count]) => {
+                let bytes = size(dest)?;
+                let a = self.read_operand(dest, bytes)?;
+                let bits = bytes as u32 * 8;
+                let count = self.read_operand(count, 1)? as u32 & if bytes == 8 { 63 } else { 31 };
+                // A count of zero leaves the flags unchanged.
+                if count != 0 {
+                    let result = if decoded.mnemonic == M::Shl {
+                        let result = a.checked_shl(count).unwrap_or(0) & mask(bytes);
+                        self.flags.carry = count <= bits && a >> (bits - count) & 1 != 0;
+                        self.flags.overflow = (result & sign_bit(bytes) != 0) != self.flags.carry;
+                        result
+                    } else {
+                        self.flags.carry = a.checked_shr(count - 1).unwrap_or(0) & 1 != 0;
+                        self.flags.overflow = a & sign_bit(bytes) != 0;
+                        a.checked_shr(count).unwrap_or(0)
+                    };
+                    self.flags.set_result(result, bytes);
+                    self.write_operand(dest, result)?;
+                }
+            }
+            (M::Imul, [dest, src]) => {
+                let bytes = size(dest)?;
+                let a = sign_extend(self.read_operand(dest, bytes)?, bytes) as i128;
+                let b = sign_extend(self.read_operand(src, bytes)?, bytes) as i128;
+                let product = a * b;
+                let result = product as u64 & mask(bytes);
+                let overflow = sign_extend(result, bytes) as i128 != product;
+                self.flags.carry = overflow;
+                self.flags.overflow = overflow;
+                self.write_operand(dest, result)?;
+            }
+            (M::Div, [src]) => {
+                let bytes = size(src)?;
+                let divisor = self.read_operand(src, bytes)? as u128;
+                assert!(divisor != 0, "divide by zero");
+                let (high, low) = match bytes {
+                    // The dividend is AX, and AH takes the remainder.
+                    1 => (
+                        self.register(R64::RAX) >> 8 & 0xff,
+                        self.register(R64::RAX) & 0xff,
+                    ),
+                    _ => (
+                        self.register(R64::RDX) & mask(bytes),
+                        self.register(R64::RAX) & mask(bytes),
+                    ),
+                };
+                let dividend = (high as u128) << (bytes * 8) | low as u128;
+                let quotient = dividend / divisor;
+                let remainder = dividend % divisor;
+                assert!(quotient <= mask(bytes) as u128, "divide overflow");
+                match bytes {
+                    1 => {
+                        let rax = self.register(R64::RAX) & !0xffff;
+                        self.set_register(
+                            R64::RAX,
+                            rax | (remainder as u64) << 8 | quotient as u64,
+                        );
+                    }
+                    _ => {
+                        self.write_gpr(R64::RAX, quotient as u64, bytes, 0);
+                        self.write_gpr(R64::RDX, remainder as u64, bytes, 0);
+                    }
+                }
+            }
+            (M::Bsf, [dest, src]) => {
+                let bytes = size(dest)?;
+                let value = self.read_operand(src, bytes)?;
+                self.flags.zero = value == 0;
+                if value != 0 {
+                    self.write_operand(dest, value.trailing_zeros() as u64)?;
+                }
+            }
+            (M::Cmpxchg, [dest, src]) => {
+                let bytes = size(dest)?;
+                let current = self.read_operand(dest, bytes)?;
+                let expected = self.register(R64::RAX) & mask(bytes);
+                self.sub(expected, current, bytes);
+                if self.flags.zero {
+                    let value = self.read_operand(src, bytes)?;
+                    self.write_operand(dest, value)?;
+                } else {
+                    self.write_gpr(R64::RAX, current, bytes, 0);
+                }
+            }
+
+            (M::Push, [src]) => {
+                let value = self.read_operand(src, 8)?;
+                self.push(value);
+            }
+            (M::Pop, [dest]) => {
+                let value = self.pop();
+                self.write_operand(dest, value)?;
+            }
+            (M::Jmp, [target]) => self.rip = self.branch_target(target)?,
+            (M::Jcc(condition), [target]) => {
+                if self.flags.condition(condition) {
+                    self.rip = self.branch_target(target)?;
+                }
+            }
+            (M::Call, [target]) => {
+                let target = self.branch_target(target)?;
+                self.push(self.rip);
+                self.rip = target;
+            }
+            (M::Ret, []) => self.rip = self.pop(),
+
+            (M::Movsb | M::Stosb | M::Stosw | M::Cmpsb | M::Scasb, []) => {
+                self.string(decoded.mnemonic, decoded.prefix)
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+
+    /// Execute a string instruction, repeated as given by its prefix.
+    fn string(&mut self, mnemonic: Mnemonic, prefix: Option<Prefix>) {
+        let bytes = if mnemonic == Mnemonic::Stosw { 2 } else { 1 };
+        let step = if self.flags.direction {
+            (bytes as u64).wrapping_neg()
+        } else {
+            bytes as u64
+        };
+        loop {
+            if prefix.is_some() {
+                if self.register(R64::RCX) == 0 {
+                    break;
+                }
+                self.set_register(R64::RCX, self.register(R64::RCX) - 1);
+            }
+
+            let rsi = self.register(R64::RSI);
+            let rdi = self.register(R64::RDI);
+            match mnemonic {
+                Mnemonic::Movsb => {
+                    let byte = self.read(rsi, 1)[0];
+                    self.write(rdi, &[byte]);
+                }
+                Mnemonic::Stosb | Mnemonic::Stosw => {
+                    let value = self.register(R64::RAX).to_le_bytes();
+                    self.write(rdi, &value[..bytes]);
+                }
+                Mnemonic::Cmpsb => {
+                    let (a, b) = (self.read(rsi, 1)[0], self.read(rdi, 1)[0]);
+                    self.sub(a as u64, b as u64, 1);
+                }
+                _ => {
+                    let b = self.read(rdi, 1)[0];
+                    self.sub(self.register(R64::RAX) & 0xff, b as u64, 1);
+                }
+            }
+            if matches!(mnemonic, Mnemonic::Movsb | Mnemonic::Cmpsb) {
+                self.set_register(R64::RSI, rsi.wrapping_add(step));
+            }
+            self.set_register(R64::RDI, rdi.wrapping_add(step));
+
+            match prefix {
+                None => break,
+                Some(Prefix::Repe) if !self.flags.zero => break,
+                Some(Prefix::Repne) if self.flags.zero => break,
+                _ => {}
+            }
+        }
+    }
+
+    fn add(&mut self, a: u64, b: u64, bytes: usize) -> u64 {
+        let result = a.wrapping_add(b) & mask(bytes);
+        self.flags.carry = (a as u128 + b as u128) > mask(bytes) as u128;
+        self.flags.overflow = (a ^ result) & (b ^ result) & sign_bit(bytes) != 0;
+        self.flags.set_result(result, bytes);
+        result
+    }
+
+    fn sub(&mut self, a: u64, b: u64, bytes: usize) -> u64 {
+        let result = a.wrapping_sub(b) & mask(bytes);
+        self.flags.carry = a < b;
+        self.flags.overflow = (a ^ b) & (a ^ result) & sign_bit(bytes) != 0;
+        self.flags.set_result(result, bytes);
+        result
+    }
+
+    fn logic(&mut self, result: u64, bytes: usize) -> u64 {
+        self.flags.carry = false;
+        self.flags.overflow = false;
+        self.flags.set_result(result, bytes);
+        result
+    }
+
+    fn push(&mut self, value: u64) {
+        let rsp = self.register(R64::RSP) - 8;
+        self.set_register(R64::RSP, rsp);
+        self.write(rsp, &value.to_le_bytes());
+    }
+
+    fn pop(&mut self) -> u64 {
+        let rsp = self.register(R64::RSP);
+        let value = u64::from_le_bytes(self.read(rsp, 8).try_into().unwrap());
+        self.set_register(R64::RSP, rsp + 8);
+        value
+    }
+
+    fn branch_target(&self, target: &Operand) -> Option<Addr> {
+        match target {
+            Operand::Relative(offset) => Some(self.rip.wrapping_add(*offset as i64 as u64)),
+            _ => self.read_operand(target, 8),
+        }
+    }
+
+    /// The effective address of a memory operand.
+    fn address(&self, memory: &Memory) -> Addr {
+        let base = match memory.base {
+            Base::Register(register) => self.register(register),
+            Base::Rip => self.rip,
+            Base::Absolute => 0,
+        };
+        let index = memory.index.map_or(0, |(register, scale)| {
+            self.register(register).wrapping_mul(scale as u64)
+        });
+        let displacement = memory.displacement.map_or(0, |d| d.value() as i64 as u64);
+        let segment = if memory.gs { self.gs_base } else { 0 };
+        segment
+            .wrapping_add(base)
+            .wrapping_add(index)
+            .wrapping_add(displacement)
+    }
+
+    /// Read an operand, with immediates sign-extended to `bytes` bytes.
+    fn read_operand(&self, operand: &Operand, bytes: usize) -> Option<u64> {
+        Some(match *operand {
+            Operand::R8(register) => {
+                let shift = if register.high_byte() { 8 } else { 0 };
+                self.register(register.gpr()?) >> shift & 0xff
+            }
+            Operand::R16(register) => self.register(register.gpr()?) & 0xffff,
+            Operand::R32(register) => self.register(register.gpr()?) & 0xffff_ffff,
+            Operand::R64(register) => self.register(register),
+            Operand::Immediate(immediate) => {
+                let mut value = [0; 8];
+                let len = immediate.bytes().len();
+                value[..len].copy_from_slice(immediate.bytes());
+                sign_extend(u64::from_le_bytes(value), len) & mask(bytes)
+            }
+            Operand::Memory(memory) => {
+                let len = memory_size(&memory)?;
+                let mut value = [0; 8];
+                value[..len].copy_from_slice(self.read(self.address(&memory), len));
+                u64::from_le_bytes(value)
+            }
+            Operand::Xmm(_) | Operand::Relative(_) => return None,
+        })
+    }
+
+    fn write_operand(&mut self, operand: &Operand, value: u64) -> Option<()> {
+        match *operand {
+            Operand::R8(register) => {
+                let shift = if register.high_byte() { 8 } else { 0 };
+                self.write_gpr(register.gpr()?, value, 1, shift);
+            }
+            Operand::R16(register) => self.write_gpr(register.gpr()?, value, 2, 0),
+            // 32-bit results are zero-extended to 64 bits.
+            Operand::R32(register) => self.set_register(register.gpr()?, value & 0xffff_ffff),
+            Operand::R64(register) => self.set_register(register, value),
+            Operand::Memory(memory) => {
+                let len = memory_size(&memory)?;
+                let address = self.address(&memory);
+                self.write(address, &value.to_le_bytes()[..len]);
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+
+    /// Write the low `bytes` bytes of a register, at `shift` bits, keeping
+    /// the rest of it.
+    fn write_gpr(&mut self, register: R64, value: u64, bytes: usize, shift: u32) {
+        if bytes == 4 {
+            self.set_register(register, value & 0xffff_ffff);
+            return;
+        }
+        let mask = mask(bytes) << shift;
+        let old = self.register(register);
+        self.set_register(register, old & !mask | (value << shift) & mask);
+    }
+
+    /// The region that holds `len` bytes at `address`, and the offset into
+    /// it.
+    fn region(&self, address: Addr, len: usize) -> (usize, usize) {
+        self.regions
+            .iter()
+            .position(|region| {
+                address >= region.start
+                    && address - region.start + len as u64 <= region.data.len() as u64
+            })
+            .map(|index| (index, (address - self.regions[index].start) as usize))
+            .unwrap_or_else(|| panic!("access to unmapped memory at {:#x}", address))
+    }
+}
+
+fn index(register: R64) -> usize {
+    (register.in_rm() | register.rex_b() << 3) as usize
+}
+
+/// The size of an operand in bytes, or `None` for immediates and branch
+/// targets, which take the size of the other operand.
+fn size(operand: &Operand) -> Option<usize> {
+    match operand {
+        Operand::R8(_) => Some(1),
+        Operand::R16(_) => Some(2),
+        Operand::R32(_) => Some(4),
+        Operand::R64(_) => Some(8),
+        Operand::Memory(memory) => memory_size(memory),
+        _ => None,
+    }
+}
+
+fn memory_size(memory: &Memory) -> Option<usize> {
+    match memory.size? {
+        Size::Byte => Some(1),
+        Size::Word => Some(2),
+        Size::Dword => Some(4),
+        Size::Qword => Some(8),
+        Size::Xmmword => None,
+    }
+}
+
+fn mask(bytes: usize) -> u64 {
+    u64::MAX >> (64 - bytes * 8)
+}
+
+fn sign_bit(bytes: usize) -> u64 {
+    1 << (bytes * 8 - 1)
+}
+
+fn sign_extend(value: u64, bytes: usize) -> u64 {
+    let shift = 64 - bytes * 8;
+    ((value << shift) as i64 >> shift) as u64
+}
+
+// The tests run the routines of the generator.
+#[cfg(all(test, feature = "kernel"))]
+mod tests {
+    use super::*;
+    use crate::{
+        elf64::program::{PF_R, PF_W, PF_X},
+        ir::{self, BinOp, Type},
+        link::{ElfLinker, Label, Segment},
+        x86::{
+            control::ControlFlow,
+            format::{Formatter, Sink},
+            instruction::*,
+            intrinsics::{Intrinsic, Intrinsics, Variant},
+            ioapic::{IoApic, Redirection},
+            lower,
+            register::{
+                R32::{EAX, ECX},
+                R64::*,
+                R8::AH,
+            },
+            spinlock::Spinlock,
+            Assembler,
+        },
+    };
+
+    /// Link `code`, after an entry point that halts, with a writable
+    /// `buffer` of 256 bytes, and load it.
+    fn load(code: impl FnOnce(&mut Assembler)) -> (Linked, Machine) {
+        load_with_data(|asm, _| code(asm))
+    }
+
+    /// Like [`load`], but `code` may also add to the writable data, after
+    /// the buffer.
+    fn load_with_data(code: impl FnOnce(&mut Assembler, &mut Segment)) -> (Linked, Machine) {
+        let mut asm = Assembler::new();
+        asm.export_label("entry");
+        asm.push(HLT);
+        let mut data = Segment::new();
+        data.align(8);
+        data.export_label("buffer");
+        data.reserve(256);
+        code(&mut asm, &mut data);
+
+        let mut linker = ElfLinker::new();
+        linker.add_segment("rodata", PF_R, 1 << 12, asm.constant_pool());
+        linker.add_segment("data", PF_R | PF_W, 1 << 12, data);
+        linker.add_segment("code", PF_R | PF_X, 1 << 12, asm.finish());
+        let linked = linker.finish();
+        let machine = Machine::load(&linked);
+        (linked, machine)
+    }
+
+    #[test]
+    fn intrinsics() {
+        let (linked, mut machine) = load(|asm| {
+            let mut intrinsics = Intrinsics::new(Variant::Rep);
+            for intrinsic in [
+                Intrinsic::Memcpy,
+                Intrinsic::Memset,
+                Intrinsic::Memcmp,
+                Intrinsic::Strlen,
+            ] {
+                intrinsics.require(intrinsic);
+            }
+            intrinsics.emit(asm);
+        });
+        let call = |machine: &mut Machine, label, args: &[u64]| {
+            machine.call(linked.label_address(label).unwrap(), args)
+        };
+        let buffer = linked.label_address("buffer").unwrap();
+
+        machine.write(buffer, b"hello, world\0");
+        assert_eq!(call(&mut machine, "strlen", &[buffer]), 12);
+        assert_eq!(call(&mut machine, "strlen", &[buffer + 12]), 0);
+
+        assert_eq!(
+            call(&mut machine, "memset", &[buffer + 32, b'x' as u64, 4]),
+            buffer + 32
+        );
+        assert_eq!(machine.read(buffer + 32, 5), b"xxxx\0");
+        assert_eq!(
+            call(&mut machine, "memcpy", &[buffer + 64, buffer, 5]),
+            buffer + 64
+        );
+        assert_eq!(machine.read(buffer + 64, 6), b"hello\0");
+
+        assert_eq!(call(&mut machine, "memcmp", &[buffer, buffer + 64, 5]), 0);
+        assert_eq!(call(&mut machine, "memcmp", &[buffer, buffer + 64, 0]), 0);
+        machine.write(buffer + 66, b"m");
+        assert_eq!(
+            call(&mut machine, "memcmp", &[buffer, buffer + 64, 5]) as i64,
+            b'l' as i64 - b'm' as i64
+        );
+    }
+
+    #[test]
+    fn lowered_loop() {
+        // The sum of the integers below a parameter.
+        let mut f = ir::Function::new("sum", &[Type::I64], Some(Type::I64));
+        f.export();
+        let n = f.param(0);
+        let zero = f.const_(Type::I64, 0);
+        let top = f.block("sum_top", &[Type::I64, Type::I64]);
+        let body = f.block("sum_body", &[]);
+        let done = f.block("sum_done", &[]);
+        f.jump(top, &[n, zero]);
+
+        f.switch_to(top);
+        let (i, total) = (f.block_param(top, 0), f.block_param(top, 1));
+        f.branch(i, body, done);
+
+        f.switch_to(body);
+        let one = f.const_(Type::I64, 1);
+        let i = f.binary(BinOp::Sub, i, one);
+        let total = f.binary(BinOp::Add, total, i);
+        f.jump(top, &[i, total]);
+
+        f.switch_to(done);
+        f.ret(Some(total));
+
+        let (linked, mut machine) = load(|asm| lower::emit(&f, asm));
+        let sum = linked.label_address("sum").unwrap();
+        for n in [0, 1, 10, 1000] {
+            assert_eq!(machine.call(sum, &[n]), n * n.saturating_sub(1) / 2);
+        }
+    }
+
+    #[test]
+    fn flags_and_sizes() {
+        let (linked, mut machine) = load(|asm| {
+            asm.export_label("test");
+            // Writes to 32-bit registers clear the upper half, and writes to
+            // byte registers keep the rest.
+            asm.push(MOV(RAX, u64::MAX));
+            asm.push(MOV(RCX, 0x1234_5678u64));
+            asm.push(MOV(EAX, ECX));
+            asm.push(MOV(AH, 0xabu8));
+            asm.push(MOV(RDX, RAX));
+            // i64::MAX + 1 overflows as a signed value, but not unsigned,
+            // and the result is the least signed value.
+            asm.push(MOV(RCX, i64::MAX as u64));
+            asm.push(ADD(RCX, 1i8));
+            asm.push(JCC(Condition::NotOverflow, Label("test_fail")));
+            asm.push(JCC(Condition::Below, Label("test_fail")));
+            asm.push(CMP(RCX, 1i8));
+            asm.push(JCC(Condition::Below, Label("test_fail")));
+            asm.push(JCC(Condition::GreaterOrEqual, Label("test_fail")));
+            asm.push(MOV(RAX, RDX));
+            asm.push(RET);
+            asm.label("test_fail");
+            asm.push(XOR(RAX, RAX));
+            asm.push(RET);
+        });
+        let test = linked.label_address("test").unwrap();
+        assert_eq!(machine.call(test, &[]), 0x1234_ab78);
+    }
+
+    /// Export a label for each arm of a switch, returning the arm's value,
+    /// and one for the default, returning 0xdefa.
+    fn switch_arms(asm: &mut Assembler, arms: &[(u64, &str)], default: &str) {
+        for &(value, label) in arms {
+            asm.export_label(label);
+            asm.push(MOV(RAX, value));
+            asm.push(RET);
+        }
+        asm.export_label(default);
+        asm.push(MOV(RAX, 0xdefau64));
+        asm.push(RET);
+    }
+
+    #[test]
+    fn dense_switch() {
+        let arms = [
+            (10, "ten"),
+            (11, "eleven"),
+            (13, "thirteen"),
+            (14, "fourteen"),
+        ];
+        let (linked, mut machine) = load(|asm| {
+            asm.export_label("dispatch");
+            asm.switch(RDI, &arms, "default");
+            asm.export_label("dispatch_end");
+            switch_arms(asm, &arms, "default");
+        });
+        let address = |label| linked.label_address(label).unwrap();
+
+        // The table ends the dispatch code, with an entry for each value
+        // from the least arm to the greatest.
+        let table: Vec<u64> = machine
+            .read(address("dispatch_end") - 40, 40)
+            .chunks(8)
+            .map(|entry| u64::from_le_bytes(entry.try_into().unwrap()))
+            .collect();
+        assert_eq!(
+            table,
+            ["ten", "eleven", "default", "thirteen", "fourteen"].map(address)
+        );
+
+        let dispatch = address("dispatch");
+        for (value, expected) in [(10, 10), (11, 11), (12, 0xdefa), (13, 13), (14, 14)] {
+            assert_eq!(machine.call(dispatch, &[value]), expected, "{value}");
+        }
+        // Values below the least arm wrap around to above the greatest.
+        for value in [0, 9, 15, 1 << 40, u64::MAX] {
+            assert_eq!(machine.call(dispatch, &[value]), 0xdefa, "{value}");
+        }
+    }
+
+    #[test]
+    fn sparse_switch() {
+        // Values outside the range of an i32 immediate are compared through
+        // a register, and all-ones through a sign-extended immediate.
+        let arms = [
+            (1, "one"),
+            (1 << 40, "big"),
+            (0x8000_0000, "above_i32"),
+            (u64::MAX, "max"),
+        ];
+        let (linked, mut machine) = load(|asm| {
+            asm.export_label("dispatch");
+            asm.switch(RDI, &arms, "default");
+            switch_arms(asm, &arms, "default");
+        });
+        let dispatch = linked.label_address("dispatch").unwrap();
+        for (value, _) in arms {
+            assert_eq!(machine.call(dispatch, &[value]), value, "{value}");
+        }
+        for value in [0, 2, 0x7fff_ffff, 0xffff_ffff, u64::MAX - 1] {
+            assert_eq!(machine.call(dispatch, &[value]), 0xdefa, "{value}");
+        }
+    }
+
+    #[test]
+    fn spinlock() {
+        let (linked, mut machine) = load(|asm| Spinlock::new("lock", "buffer").emit(asm));
+        let address = |label| linked.label_address(label).unwrap();
+        let lock = address("buffer");
+
+        assert_eq!(machine.call(address("lock_try_acquire"), &[]), 1);
+        assert_eq!(machine.read(lock, 1), [1]);
+        assert_eq!(machine.call(address("lock_try_acquire"), &[]), 0);
+        assert_eq!(machine.read(lock, 1), [1]);
+        machine.call(address("lock_release"), &[]);
+        assert_eq!(machine.read(lock, 1), [0]);
+
+        // Acquiring a free lock takes it without spinning.
+        machine.call(address("lock_acquire"), &[]);
+        assert_eq!(machine.read(lock, 1), [1]);
+        assert_eq!(machine.call(address("lock_try_acquire"), &[]), 0);
+        machine.call(address("lock_release"), &[]);
+        assert_eq!(machine.call(address("lock_try_acquire"), &[]), 1);
+    }
+
+    #[test]
+    fn ioapic_init() {
+        const REGISTERS: Addr = 0xfec0_0000;
+        let ioapic = IoApic::new("ioapic", 0)
+            .redirect(Redirection::new(2, 0x30, 1))
+            .redirect(Redirection {
+                masked: true,
+                level_triggered: true,
+                active_low: true,
+                ..Redirection::new(9, 0x39, 0)
+            });
+        let (linked, mut machine) = load_with_data(|asm, data| ioapic.emit(asm, data));
+        machine.map(REGISTERS, vec![0; 0x20]);
+
+        // Step through the routine, recording the register selected by each
+        // write to the window. The window is reset to a value that is never
+        // written before each instruction, to notice every write.
+        let mut writes = Vec::new();
+        machine.set_register(R64::RDI, REGISTERS);
+        machine.push(RETURN_ADDRESS);
+        machine.rip = linked.label_address("ioapic_init").unwrap();
+        while machine.rip != RETURN_ADDRESS {
+            machine.write(REGISTERS + 0x10, &u32::MAX.to_le_bytes());
+            machine.step();
+            let window = machine.read(REGISTERS + 0x10, 4).try_into().unwrap();
+            if u32::from_le_bytes(window) != u32::MAX {
+                let select = machine.read(REGISTERS, 4).try_into().unwrap();
+                writes.push((u32::from_le_bytes(select), u32::from_le_bytes(window)));
+            }
+        }
+
+        // The high half of each entry is written before the low half.
+        assert_eq!(
+            writes,
+            [
+                (0x15, 1 << 24),
+                (0x14, 0x30),
+                (0x23, 0),
+                (0x22, 0x39 | 1 << 13 | 1 << 15 | 1 << 16),
+            ]
+        );
+        let base = machine.read(linked.label_address("ioapic_base").unwrap(), 8);
+        assert_eq!(base, REGISTERS.to_le_bytes());
+    }
+
+    /// Call `printf` with `format` and `args`, returning the bytes of each
+    /// call to its sink, `write`, which returns as soon as it is called.
+    fn printf(linked: &Linked, machine: &mut Machine, format: &str, args: &[u64]) -> Vec<Vec<u8>> {
+        let format_address = linked.label_address("buffer").unwrap();
+        machine.write(format_address, format.as_bytes());
+        machine.write(format_address + format.len() as u64, &[0]);
+        let registers = [RDI, RSI, RDX, RCX, R8, R9];
+        for (&register, &arg) in registers.iter().zip([format_address].iter().chain(args)) {
+            machine.set_register(register, arg);
+        }
+
+        let write = linked.label_address("write").unwrap();
+        let mut writes = Vec::new();
+        machine.push(RETURN_ADDRESS);
+        machine.rip = linked.label_address("printf").unwrap();
+        while machine.rip != RETURN_ADDRESS {
+            if machine.rip == write {
+                let (buffer, length) = (machine.register(RDI), machine.register(RSI));
+                writes.push(machine.read(buffer, length as usize).to_vec());
+            }
+            machine.step();
+        }
+        writes
+    }
+
+    #[test]
+    fn printf_directives() {
+        let (linked, mut machine) = load_with_data(|asm, data| {
+            asm.export_label("write");
+            asm.push(RET);
+            Formatter::new("printf", Sink::Function("write")).emit(asm, data);
+        });
+        let mut printf = |format, args: &[u64]| printf(&linked, &mut machine, format, args);
+
+        assert_eq!(
+            printf("%d %d %d %d", &[0, 42, -7i64 as u64, i64::MIN as u64]),
+            [b"0 42 -7 -9223372036854775808"]
+        );
+        assert_eq!(
+            printf("%x %x %x", &[0, 0xdead_beef, u64::MAX]),
+            [b"0 deadbeef ffffffffffffffff"]
+        );
+        assert_eq!(
+            printf("%c%c, 100%% %q", &[b'o' as u64, b'k' as u64]),
+            [b"ok, 100% q"]
+        );
+        // Without output, the sink is not called.
+        assert!(printf("", &[]).is_empty());
+    }
+
+    #[test]
+    fn printf_strings_and_flushes() {
+        let (linked, mut machine) = load_with_data(|asm, data| {
+            asm.export_label("write");
+            asm.push(RET);
+            Formatter::new("printf", Sink::Function("write")).emit(asm, data);
+        });
+        // After the format strings, in the second half of the buffer.
+        let strings = linked.label_address("buffer").unwrap() + 128;
+        machine.write(strings, b"world\0\0");
+        let mut printf = |format, args: &[u64]| printf(&linked, &mut machine, format, args);
+
+        assert_eq!(
+            printf("hello, %s!%s", &[strings, strings + 6]),
+            [b"hello, world!"]
+        );
+
+        // A full buffer is written as soon as it fills up, and not again
+        // on return if nothing follows.
+        let full = "x".repeat(64);
+        assert_eq!(printf(&full, &[]), [full.as_bytes()]);
+        // The buffer is empty again after it was written.
+        assert_eq!(printf("%s%c", &[strings, b'!' as u64]), [b"world!"]);
+        // A string that doesn't fit is split across writes.
+        let writes = printf(&format!("{}%s", "y".repeat(60)), &[strings]);
+        assert_eq!(writes, [format!("{}worl", "y".repeat(60)).as_bytes(), b"d"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "is not supported")]
+    fn unsupported() {
+        let (linked, mut machine) = load(|_| {});
+        machine.call(linked.label_address("entry").unwrap(), &[]);
+    }
+}
@@ -0,0 +1,282 @@
+//! A generator for `printf`-style formatting routines.
+//!
+//! The generated routine is called as `name(format, args...)` with the
+//! System V ABI, taking up to five arguments after the NUL-terminated format
+//! string. It supports these directives, each consuming one argument:
+//!
+//! - `%s`: a NUL-terminated string
+//! - `%x`: an unsigned integer, in hexadecimal
+//! - `%d`: a signed integer, in decimal
+//! - `%c`: a character
+//!
+//! `%%` writes a literal `%`, and `%` followed by any other character writes
+//! just that character.
+//! Output is collected in a small static buffer and written to the [`Sink`]
+//! whenever the buffer fills up, and once more before returning. The buffer
+//! makes the routine non-reentrant, so it must not be used from interrupt
+//! handlers that can interrupt it.
+
+use super::{
+    address::{disp8, Index, Indirect},
+    control::ControlFlow,
+    function::{Arg, Function, FunctionBuilder},
+    global::Global,
+    instruction::*,
+    register::{R16::*, R64, R64::*, R8::*},
+    Assembler, Emitter,
+};
+use crate::{
+    limine::{self, RequestHandle},
+    link::{Label, Segment},
+};
+
+/// Size of the output buffer, in bytes.
+const BUFFER_SIZE: i8 = 64;
+/// Size of the buffer that numbers are converted into. Enough for the
+/// decimal digits of any 64-bit value.
+const DIGITS_SIZE: i8 = 24;
+/// Number of arguments that may follow the format string.
+const MAX_ARGS: usize = 5;
+
+/// Where formatted output is written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sink<'a> {
+    /// The first terminal of a Limine terminal response. Output is dropped if
+    /// there is none.
+    Terminal(RequestHandle),
+    /// A 16550 UART at the given I/O port base, such as COM1 at `0x3f8`.
+    Serial(u16),
+    /// A function called as `write(buffer, length)`.
+    Function(&'a str),
+}
+
+pub struct Formatter<'a> {
+    name: &'a str,
+    sink: Sink<'a>,
+}
+
+impl<'a> Formatter<'a> {
+    pub fn new(name: &'a str, sink: Sink<'a>) -> Self {
+        Self { name, sink }
+    }
+
+    /// A label derived from the routine's name.
+    fn label(&self, suffix: &str) -> Label {
+        Label(&format!("{}_{}", self.name, suffix))
+    }
+
+    /// Emit the exported routine into `asm`, and its buffers into `data`.
+    pub fn emit(&self, asm: &mut Assembler, data: &mut Segment) {
+        let buffer =
+            Global::<[u8; BUFFER_SIZE as usize]>::reserve(data, self.label("buffer")).ptr();
+        let digits =
+            Global::<[u8; DIGITS_SIZE as usize]>::reserve(data, self.label("digits")).ptr();
+        let flush = self.label("flush");
+
+        self.emit_flush(asm, flush);
+
+        // RBX: format string cursor
+        // R12: next argument
+        // R13: number of bytes in the buffer
+        // R14: string or digit cursor
+        // R15: buffer
+        let mut f = Function::new(self.name)
+            .export()
+            .params(1 + MAX_ARGS)
+            .slot("args", 8 * MAX_ARGS, 8)
+            .begin(asm);
+
+        // Spill the arguments so that they can be walked in order.
+        let Index(base, args) = f.slot("args");
+        for index in 0..MAX_ARGS {
+            let slot = Index(base, args + 8 * index as i8);
+            f.push(MOV(slot, f.param(1 + index)));
+        }
+        f.push(MOV(RBX, f.param(0)));
+        f.push(LEA(R12, f.slot("args")));
+        f.push(XOR(R13, R13));
+        f.push(LEA(R15, buffer));
+        let lut = f.inline_data(b"0123456789abcdef", 1);
+
+        f.loop_(|f, labels| {
+            f.push(MOVZX(RAX, Index(RBX, 0)));
+            f.push(INC(RBX));
+            f.push(TEST(RAX, RAX));
+            f.push(JZ(labels.break_));
+            f.push(CMP(RAX, b'%' as i8));
+            f.if_then(Condition::NotZero, |f| {
+                put(f, flush);
+                f.push(JMP(labels.continue_));
+            });
+
+            let string = f.fresh_label("format_string");
+            let hex = f.fresh_label("format_hex");
+            let decimal = f.fresh_label("format_decimal");
+            let character = f.fresh_label("format_char");
+            let convert = f.fresh_label("format_convert");
+
+            f.push(MOVZX(RAX, Index(RBX, 0)));
+            f.push(TEST(RAX, RAX));
+            f.push(JZ(labels.break_));
+            f.push(INC(RBX));
+            for (directive, target) in [
+                (b's', string),
+                (b'x', hex),
+                (b'd', decimal),
+                (b'c', character),
+            ] {
+                f.push(CMP(RAX, directive as i8));
+                f.push(JZ(target));
+            }
+            put(f, flush);
+            f.push(JMP(labels.continue_));
+
+            f.label(string);
+            next_arg(f, R14);
+            f.loop_(|f, string_labels| {
+                f.push(MOVZX(RAX, Index(R14, 0)));
+                f.push(TEST(RAX, RAX));
+                f.push(JZ(string_labels.break_));
+                put(f, flush);
+                f.push(INC(R14));
+            });
+            f.push(JMP(labels.continue_));
+
+            f.label(character);
+            next_arg(f, RAX);
+            put(f, flush);
+            f.push(JMP(labels.continue_));
+
+            f.label(decimal);
+            next_arg(f, R14);
+            f.push(TEST(R14, R14));
+            f.if_then(Condition::Sign, |f| {
+                f.push(MOV(RAX, b'-' as u64));
+                put(f, flush);
+                f.push(NEG(R14));
+            });
+            f.push(MOV(RAX, R14));
+            f.push(MOV(RCX, 10));
+            f.push(JMP(convert));
+
+            f.label(hex);
+            next_arg(f, RAX);
+            f.push(MOV(RCX, 16));
+
+            // Convert RAX to digits in base RCX, from the end of the digit
+            // buffer backwards.
+            f.label(convert);
+            f.push(LEA(RDI, digits));
+            f.push(ADD(RDI, DIGITS_SIZE));
+            f.push(LEA(R8, lut));
+            f.loop_(|f, digit_labels| {
+                f.push(XOR(RDX, RDX));
+                f.push(DIV(RCX));
+                f.push(MOVZX(RDX, Index(RDX, R8)));
+                f.push(DEC(RDI));
+                f.push(MOV(Indirect(RDI), DL));
+                f.push(TEST(RAX, RAX));
+                f.push(JZ(digit_labels.break_));
+            });
+            f.push(MOV(R14, RDI));
+            f.while_(
+                |f| {
+                    f.push(LEA(RAX, digits));
+                    f.push(ADD(RAX, DIGITS_SIZE));
+                    f.push(CMP(R14, RAX));
+                    Condition::Below
+                },
+                |f, _| {
+                    f.push(MOVZX(RAX, Index(R14, 0)));
+                    put(f, flush);
+                    f.push(INC(R14));
+                },
+            );
+        });
+
+        f.push(TEST(R13, R13));
+        f.if_then(Condition::NotZero, |f| {
+            f.call_fn(flush, &[Arg::Reg(R15), Arg::Reg(R13)]);
+        });
+        f.finish();
+    }
+
+    /// Emit `flush(buffer, length)`, which writes to the sink.
+    fn emit_flush(&self, asm: &mut Assembler, flush: Label) {
+        let mut f = Function::new(flush).params(2).begin(asm);
+        let (buffer, length) = (f.param(0), f.param(1));
+        match self.sink {
+            Sink::Terminal(terminal) => {
+                let end = f.fresh_label("flush_end");
+                f.push(MOV(RDX, length));
+                f.push(MOV(RSI, buffer));
+                f.push(MOV(RAX, terminal.ptr()));
+                f.push(TEST(RAX, RAX));
+                f.push(JZ(end));
+                f.push(MOV(
+                    RDI,
+                    Index(RAX, disp8(limine::TERMINAL_RESPONSE_TERMINAL_COUNT_OFFSET)),
+                ));
+                f.push(TEST(RDI, RDI));
+                f.push(JZ(end));
+                f.push(MOV(
+                    RDI,
+                    Index(RAX, disp8(limine::TERMINAL_RESPONSE_TERMINALS_OFFSET)),
+                ));
+                f.push(MOV(RDI, Indirect(RDI)));
+                f.push(MOV(
+                    RAX,
+                    Index(RAX, disp8(limine::TERMINAL_RESPONSE_WRITE_OFFSET)),
+                ));
+                f.push(CALL(RAX));
+                f.label(end);
+            }
+            Sink::Serial(port) => {
+                // Line status register, and its transmit holding register
+                // empty bit.
+                let line_status = port + 5;
+                let transmit_empty = 0x20;
+                f.while_(
+                    |f| {
+                        f.push(TEST(length, length));
+                        Condition::NotZero
+                    },
+                    |f, _| {
+                        f.push(MOV(RDX, line_status as u64));
+                        f.loop_(|f, labels| {
+                            f.push(IN(AL, DX));
+                            f.push(AND(RAX, transmit_empty));
+                            f.push(JNZ(labels.break_));
+                        });
+                        f.push(MOV(RDX, port as u64));
+                        f.push(MOVZX(RAX, Index(buffer, 0)));
+                        f.push(OUT(DX, AL));
+                        f.push(INC(buffer));
+                        f.push(DEC(length));
+                    },
+                );
+            }
+            Sink::Function(write) => {
+                f.call_fn(write, &[Arg::Reg(buffer), Arg::Reg(length)]);
+            }
+        }
+        f.finish();
+    }
+}
+
+/// Load the next argument into `dst`.
+fn next_arg(f: &mut FunctionBuilder, dst: R64) {
+    f.push(MOV(dst, Indirect(R12)));
+    f.push(ADD(R12, 8i8));
+}
+
+/// Append the byte in AL to the buffer, flushing it if it is full.
+fn put(f: &mut FunctionBuilder<'_>, flush: Label) {
+    f.push(MOV(Index(R13, R15), AL));
+    f.push(INC(R13));
+    f.push(CMP(R13, BUFFER_SIZE));
+    f.if_then(Condition::Zero, |f| {
+        f.call_fn(flush, &[Arg::Reg(R15), Arg::Reg(R13)]);
+        f.push(XOR(R13, R13));
+    });
+}
@@ -0,0 +1,230 @@
+//! Stack frame layout for a [`Function`](super::function::Function).
+//!
+//! The prologue pushes RBP, reserves space for the frame's slots, then pushes
+//! the saved callee-saved registers, padded so that RSP is 16-byte aligned in
+//! the body:
+//!
+//! ```text
+//! rbp + 8     return address
+//! rbp         caller's rbp
+//! rbp - 8     slots, in order of allocation
+//!             padding
+//!             saved registers
+//! rsp         values pushed by the body
+//! ```
+//!
+//! Slots don't move when the set of saved registers changes, so it can be
+//! decided after the body has been emitted.
+//!
+//! Slots are addressed by name, relative to RBP or RSP. Values pushed and
+//! popped by the body are tracked, so that RSP-relative operands stay
+//! correct and calls can re-align the stack.
+
+use super::{
+    address::Index,
+    register::R64::{self, *},
+};
+
+struct Slot<'a> {
+    /// `None` for slots allocated by [`Frame::locals`].
+    name: Option<&'a str>,
+    /// Distance below RBP of the slot's lowest byte.
+    depth: usize,
+}
+
+pub struct Frame {
+    saved: usize,
+    slots: Vec<(Option<String>, usize, usize)>,
+    pushed: usize,
+}
+
+impl Default for Frame {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Frame {
+    pub fn new() -> Self {
+        Self {
+            saved: 0,
+            slots: Vec::new(),
+            pushed: 0,
+        }
+    }
+
+    /// Set the number of saved registers below the slots.
+    pub(super) fn set_saved(&mut self, count: usize) {
+        self.saved = count;
+    }
+
+    /// Allocate a slot of `size` bytes, aligned to `align` bytes (at most
+    /// 16).
+    pub fn slot(&mut self, name: &str, size: usize, align: usize) {
+        assert!(
+            self.slots
+                .iter()
+                .all(|(other, _, _)| other.as_deref() != Some(name)),
+            "duplicate slot {}",
+            name
+        );
+        self.add(Some(name.to_owned()), size, align);
+    }
+
+    /// Allocate `count` unnamed 8-byte slots, accessed by index with
+    /// [`local`](Self::local).
+    pub fn locals(&mut self, count: usize) {
+        for _ in 0..count {
+            self.add(None, 8, 8);
+        }
+    }
+
+    fn add(&mut self, name: Option<String>, size: usize, align: usize) {
+        assert!(
+            align.is_power_of_two() && align <= 16,
+            "unsupported slot alignment {}",
+            align
+        );
+        self.slots.push((name, size, align));
+    }
+
+    /// Lay out the slots below RBP. RBP is 16-byte aligned, so a slot is
+    /// aligned if its depth is.
+    fn layout(&self) -> impl Iterator<Item = Slot<'_>> {
+        let mut depth = 0;
+        self.slots.iter().map(move |(name, size, align)| {
+            depth = (depth + size).next_multiple_of(*align);
+            Slot {
+                name: name.as_deref(),
+                depth,
+            }
+        })
+    }
+
+    /// Distance below RBP of the bottom of the slots.
+    fn depth(&self) -> usize {
+        self.layout().last().map_or(0, |slot| slot.depth)
+    }
+
+    /// Bytes subtracted from RSP before saving registers, padded so that RSP
+    /// is 16-byte aligned in the body.
+    pub fn size(&self) -> usize {
+        (self.depth() + 8 * self.saved).next_multiple_of(16) - 8 * self.saved
+    }
+
+    fn find(&self, name: &str) -> Slot<'_> {
+        self.layout()
+            .find(|slot| slot.name == Some(name))
+            .unwrap_or_else(|| panic!("no slot named {}", name))
+    }
+
+    fn rbp_operand(depth: usize) -> Index<R64, i8> {
+        let offset = -(depth as isize);
+        assert!(offset >= i8::MIN as isize, "slot too deep for disp8");
+        Index(RBP, offset as i8)
+    }
+
+    fn rsp_operand(&self, depth: usize) -> Index<R64, i8> {
+        let offset = self.size() + 8 * self.saved + self.pushed - depth;
+        assert!(
+            offset <= i8::MAX as usize,
+            "slot too far from RSP for disp8"
+        );
+        Index(RSP, offset as i8)
+    }
+
+    /// The named slot, relative to RBP.
+    pub fn rbp(&self, name: &str) -> Index<R64, i8> {
+        Self::rbp_operand(self.find(name).depth)
+    }
+
+    /// The named slot, relative to the current RSP.
+    pub fn rsp(&self, name: &str) -> Index<R64, i8> {
+        self.rsp_operand(self.find(name).depth)
+    }
+
+    /// Unnamed slot `index`, relative to RBP.
+    pub fn local(&self, index: usize) -> Index<R64, i8> {
+        let slot = self
+            .layout()
+            .filter(|slot| slot.name.is_none())
+            .nth(index)
+            .unwrap_or_else(|| panic!("no local {}", index));
+        Self::rbp_operand(slot.depth)
+    }
+
+    /// Record `bytes` pushed onto the stack by the body.
+    pub fn push(&mut self, bytes: usize) {
+        self.pushed += bytes;
+    }
+
+    /// Record `bytes` popped from the stack by the body.
+    pub fn pop(&mut self, bytes: usize) {
+        assert!(bytes <= self.pushed, "popped more than was pushed");
+        self.pushed -= bytes;
+    }
+
+    /// Whether no slots have been allocated.
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    /// Bytes currently pushed by the body.
+    pub fn pushed(&self) -> usize {
+        self.pushed
+    }
+
+    /// Whether RSP is currently 16-byte aligned.
+    pub fn is_aligned(&self) -> bool {
+        self.pushed.is_multiple_of(16)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A frame with slots of various sizes and alignments, and a local.
+    fn frame() -> Frame {
+        let mut frame = Frame::new();
+        frame.slot("byte", 1, 1);
+        frame.slot("word", 8, 8);
+        frame.slot("vector", 16, 16);
+        frame.locals(1);
+        frame
+    }
+
+    #[test]
+    fn slot_offsets() {
+        let frame = frame();
+        // Each slot is placed below the previous one, rounded down to its
+        // alignment.
+        assert_eq!(frame.rbp("byte"), Index(RBP, -1));
+        assert_eq!(frame.rbp("word"), Index(RBP, -16));
+        assert_eq!(frame.rbp("vector"), Index(RBP, -32));
+        assert_eq!(frame.local(0), Index(RBP, -40));
+    }
+
+    #[test]
+    fn alignment() {
+        let mut frame = frame();
+        for (saved, size) in [(0, 48), (1, 40), (2, 48), (3, 40)] {
+            frame.set_saved(saved);
+            assert_eq!(frame.size(), size, "{saved} saved registers");
+            assert_eq!((frame.size() + 8 * saved) % 16, 0);
+        }
+        assert_eq!(Frame::new().size(), 0);
+
+        // RSP-relative operands account for the saved registers and the
+        // values pushed by the body.
+        frame.set_saved(1);
+        assert_eq!(frame.rsp("byte"), Index(RSP, 47));
+        assert_eq!(frame.rsp("vector"), Index(RSP, 16));
+        assert!(frame.is_aligned());
+        frame.push(8);
+        assert_eq!(frame.rsp("vector"), Index(RSP, 24));
+        assert!(!frame.is_aligned());
+        frame.pop(8);
+        assert_eq!(frame.rsp("vector"), Index(RSP, 16));
+    }
+}
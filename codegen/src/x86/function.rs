@@ -0,0 +1,945 @@
+//! Functions following the System V AMD64 calling convention.
+//!
+//! Integer arguments are passed in [`PARAMETER_REGISTERS`] and the result is
+//! returned in [`RETURN_REGISTER`]. A function may clobber the
+//! [`CALLER_SAVED`] registers, but must preserve the [`CALLEE_SAVED`] ones.
+//! The stack is 16-byte aligned at every `CALL`.
+//!
+//! [`Assembler::call_fn`] emits calls to such functions, and
+//! [`Function`] emits their prologue and epilogue. Other conventions are
+//! supported through [`CallingConvention`].
+//!
+//! Unless it is naked, a function keeps the caller's RBP below its return
+//! address and points RBP at it, so that the frames form a chain that
+//! [`backtrace`](super::backtrace) can walk. The body must not change RBP.
+
+use super::{
+    address::Index,
+    convention::{CallingConvention, SysV},
+    frame::Frame,
+    instruction::{Instruction, ADD, CALL, JMP, LEA, MOV, POP, PUSH, RET, SUB},
+    register::R64::{self, *},
+    Assembler, Emitter,
+};
+use crate::link::{Label, Ptr, ReferenceFormat};
+
+/// Integer parameter registers, in argument order.
+pub const PARAMETER_REGISTERS: [R64; 6] = [RDI, RSI, RDX, RCX, R8, R9];
+pub const RETURN_REGISTER: R64 = RAX;
+pub const CALLER_SAVED: [R64; 9] = [RAX, RCX, RDX, RSI, RDI, R8, R9, R10, R11];
+/// Callee-saved registers, apart from RSP.
+pub const CALLEE_SAVED: [R64; 6] = [RBX, RBP, R12, R13, R14, R15];
+
+/// Scratch register used by [`Assembler::call_fn`] to marshal arguments.
+/// It is caller-saved and not used for parameters in every
+/// [`CallingConvention`].
+const SCRATCH: R64 = R11;
+
+/// An integer argument to [`Assembler::call_fn`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Arg {
+    /// The value of a register.
+    Reg(R64),
+    Imm(u64),
+    /// The address of a label.
+    Label(Label),
+    /// The 64-bit value stored at a label.
+    Load(Label),
+    /// The 64-bit value stored at a displacement from a register.
+    Index(R64, i8),
+}
+
+impl Arg {
+    /// The register read by this argument, if any.
+    fn source(&self) -> Option<R64> {
+        match *self {
+            Self::Reg(register) | Self::Index(register, _) => Some(register),
+            _ => None,
+        }
+    }
+
+    fn load_into(&self, asm: &mut Assembler, dst: R64) {
+        match *self {
+            Self::Reg(src) => {
+                if src != dst {
+                    asm.push(MOV(dst, src));
+                }
+            }
+            Self::Imm(value) => asm.push(MOV(dst, value)),
+            Self::Label(label) => asm.push(LEA(dst, label.ptr())),
+            Self::Load(label) => asm.push(MOV(dst, label.ptr())),
+            Self::Index(base, displacement) => asm.push(MOV(dst, Index(base, displacement))),
+        }
+    }
+
+    fn with_source(self, from: R64, to: R64) -> Self {
+        match self {
+            Self::Reg(register) if register == from => Self::Reg(to),
+            Self::Index(register, displacement) if register == from => {
+                Self::Index(to, displacement)
+            }
+            other => other,
+        }
+    }
+}
+
+impl Assembler {
+    /// Call a function, passing `args` according to the calling convention.
+    ///
+    /// RSP must be 16-byte aligned, as it is in a [`FunctionBuilder`] body.
+    /// All [`CALLER_SAVED`] registers are clobbered, including R11, which is
+    /// used as scratch and cannot be passed as an argument.
+    pub fn call_fn(&mut self, target: impl Into<Label>, args: &[Arg]) {
+        self.call_fn_preserving(target, args, &[]);
+    }
+
+    /// Like [`call_fn`](Self::call_fn), but preserves the values of the
+    /// caller-saved registers in `live` across the call by saving them on the
+    /// stack. Callee-saved registers in `live` are ignored.
+    ///
+    /// The registers are restored after the call, so if `live` includes the
+    /// return register, the callee's result is overwritten.
+    pub fn call_fn_preserving(&mut self, target: impl Into<Label>, args: &[Arg], live: &[R64]) {
+        self.call_with(&SysV, target, args, live);
+    }
+
+    /// Like [`call_fn_preserving`](Self::call_fn_preserving), for a function
+    /// following another calling convention.
+    pub fn call_with(
+        &mut self,
+        convention: &dyn CallingConvention,
+        target: impl Into<Label>,
+        args: &[Arg],
+        live: &[R64],
+    ) {
+        self.call(convention, target, args, live, false);
+    }
+
+    /// Emit a call, first padding the stack by 8 bytes if `misaligned`.
+    fn call(
+        &mut self,
+        convention: &dyn CallingConvention,
+        target: impl Into<Label>,
+        args: &[Arg],
+        live: &[R64],
+        misaligned: bool,
+    ) {
+        assert!(
+            args.iter().all(|arg| arg.source() != Some(SCRATCH)),
+            "{:?} cannot be passed as an argument",
+            SCRATCH
+        );
+        let saved: Vec<R64> = convention
+            .caller_saved()
+            .iter()
+            .copied()
+            .filter(|register| live.contains(register))
+            .collect();
+        let parameters = convention.parameters();
+        let (register_args, stack_args) = args.split_at(args.len().min(parameters.len()));
+        let shadow_space = convention.shadow_space();
+        // Everything pushed from here to the CALL, in 8-byte words.
+        let words = saved.len() + stack_args.len() + shadow_space / 8 + misaligned as usize;
+        let alignment = convention.stack_alignment() / 8;
+        let padding = (alignment - words % alignment) % alignment;
+
+        for &register in &saved {
+            self.push(PUSH(register));
+        }
+        if padding > 0 {
+            self.push(SUB(RSP, 8 * padding as i32));
+        }
+        // Stack arguments are pushed last to first, so that the first is at
+        // the lowest address.
+        for arg in stack_args.iter().rev() {
+            match *arg {
+                Arg::Reg(register) => self.push(PUSH(register)),
+                _ => {
+                    arg.load_into(self, SCRATCH);
+                    self.push(PUSH(SCRATCH));
+                }
+            }
+        }
+        if shadow_space > 0 {
+            self.push(SUB(RSP, shadow_space as i32));
+        }
+        self.move_parallel(
+            parameters
+                .iter()
+                .copied()
+                .zip(register_args.iter().copied())
+                .collect(),
+        );
+
+        self.push(CALL(target.into()));
+
+        let stack_size = 8 * (stack_args.len() + padding) + shadow_space;
+        if stack_size > 0 {
+            self.push(ADD(RSP, stack_size as i32));
+        }
+        for &register in saved.iter().rev() {
+            self.push(POP(register));
+        }
+    }
+
+    /// Load each argument into its register, ordering the moves so that no
+    /// register is overwritten before it is read.
+    pub(super) fn move_parallel(&mut self, mut pending: Vec<(R64, Arg)>) {
+        while !pending.is_empty() {
+            // A move can be emitted once no other pending move reads its
+            // destination.
+            let ready = (0..pending.len()).find(|&i| {
+                let dst = pending[i].0;
+                pending
+                    .iter()
+                    .enumerate()
+                    .all(|(j, (_, arg))| j == i || arg.source() != Some(dst))
+            });
+            match ready {
+                Some(i) => {
+                    let (dst, arg) = pending.remove(i);
+                    arg.load_into(self, dst);
+                }
+                None => {
+                    // Every destination is still needed, so the moves form a
+                    // cycle. Break it by copying one destination to scratch.
+                    let dst = pending[0].0;
+                    self.push(MOV(SCRATCH, dst));
+                    for (_, arg) in &mut pending {
+                        *arg = arg.with_source(dst, SCRATCH);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The signature and frame layout of a function.
+pub struct Function {
+    name: Label,
+    exported: bool,
+    params: usize,
+    returns: bool,
+    naked: bool,
+    convention: &'static dyn CallingConvention,
+    saved: Vec<R64>,
+    frame: Frame,
+}
+
+impl Function {
+    pub fn new(name: impl Into<Label>) -> Self {
+        Self {
+            name: name.into(),
+            exported: false,
+            params: 0,
+            returns: false,
+            naked: false,
+            convention: &SysV,
+            saved: Vec::new(),
+            frame: Frame::new(),
+        }
+    }
+
+    /// Export the function's label to other segments.
+    pub fn export(mut self) -> Self {
+        self.exported = true;
+        self
+    }
+
+    /// Declare the number of integer parameters, up to the number of
+    /// parameter registers.
+    pub fn params(mut self, count: usize) -> Self {
+        self.params = count;
+        self
+    }
+
+    /// Declare that the function returns a value, in [`RETURN_REGISTER`]
+    /// for the default convention.
+    pub fn returns(mut self) -> Self {
+        self.returns = true;
+        self
+    }
+
+    /// Emit no prologue or epilogue, for stubs like trampolines that manage
+    /// the stack themselves. [`FunctionBuilder::ret`] emits a plain `RET`,
+    /// and the body must not fall off the end.
+    ///
+    /// A naked function has no frame, so it can't have slots or saved
+    /// registers. Calls assume that it was itself entered by a `CALL`.
+    pub fn naked(mut self) -> Self {
+        self.naked = true;
+        self
+    }
+
+    /// Follow `convention` instead of the System V convention.
+    pub fn convention(mut self, convention: &'static dyn CallingConvention) -> Self {
+        self.convention = convention;
+        self
+    }
+
+    /// Callee-saved registers to save in the prologue and restore in the
+    /// epilogue.
+    ///
+    /// Callee-saved registers that the body names as operands are saved
+    /// automatically, so this is only needed for registers that it uses
+    /// implicitly, or to fix the set in advance for
+    /// [`FunctionBuilder::slot_rsp`].
+    pub fn saves(mut self, registers: &[R64]) -> Self {
+        self.saved = registers.to_vec();
+        self.frame.set_saved(self.saved.len());
+        self
+    }
+
+    /// Reserve 8-byte stack slots for locals, accessed through
+    /// [`FunctionBuilder::local`].
+    pub fn locals(mut self, count: usize) -> Self {
+        self.frame.locals(count);
+        self
+    }
+
+    /// Reserve a named stack slot, accessed through
+    /// [`FunctionBuilder::slot`].
+    pub fn slot(mut self, name: &str, size: usize, align: usize) -> Self {
+        self.frame.slot(name, size, align);
+        self
+    }
+
+    /// Start emitting the function's body. The prologue is emitted by
+    /// [`FunctionBuilder::finish`], once the registers used by the body are
+    /// known.
+    pub fn begin(self, asm: &mut Assembler) -> FunctionBuilder<'_> {
+        assert!(
+            self.params <= self.convention.parameters().len(),
+            "stack parameters are not supported"
+        );
+        for register in &self.saved {
+            assert!(
+                self.convention.callee_saved().contains(register) && *register != RBP,
+                "{:?} is not a callee-saved register usable by the body",
+                register
+            );
+        }
+        assert!(
+            !self.naked || (self.saved.is_empty() && self.frame.size() == 0),
+            "naked function {} cannot have a frame",
+            self.name
+        );
+        FunctionBuilder {
+            function: self,
+            asm,
+            body: Assembler::new(),
+            epilogue: None,
+            returning: false,
+            rsp_relative: false,
+            inline_data: Vec::new(),
+            tail_call: None,
+        }
+    }
+}
+
+/// Emits the body of a [`Function`], between its prologue and epilogue.
+///
+/// The body is buffered, and the whole function is emitted by
+/// [`finish`](Self::finish).
+pub struct FunctionBuilder<'a> {
+    function: Function,
+    asm: &'a mut Assembler,
+    body: Assembler,
+    /// Label of the epilogue, if [`ret`](Self::ret) has been used before
+    /// the end of the body.
+    epilogue: Option<Label>,
+    /// Whether [`ret`](Self::ret) was the last thing emitted, so that
+    /// anything emitted after it must first jump to the epilogue.
+    returning: bool,
+    /// Whether a slot has been addressed relative to RSP, which depends on
+    /// the number of saved registers.
+    rsp_relative: bool,
+    /// Data to place after the function's code, with its label and
+    /// alignment.
+    inline_data: Vec<(Label, Vec<u8>, usize)>,
+    /// The convention and target of a call whose arguments have been
+    /// loaded, but whose `CALL` has not been emitted, in case it becomes a
+    /// tail call.
+    tail_call: Option<(&'static dyn CallingConvention, Label)>,
+}
+
+impl FunctionBuilder<'_> {
+    pub fn name(&self) -> Label {
+        self.function.name
+    }
+
+    /// The register holding parameter `index`.
+    pub fn param(&self, index: usize) -> R64 {
+        assert!(
+            index < self.function.params,
+            "{} has {} parameters",
+            self.function.name,
+            self.function.params
+        );
+        self.function.convention.parameters()[index]
+    }
+
+    /// The register to place the result in before [`ret`](Self::ret).
+    pub fn result(&self) -> R64 {
+        assert!(
+            self.function.returns,
+            "{} does not return a value",
+            self.function.name
+        );
+        self.function.convention.return_register()
+    }
+
+    /// The stack slot for local `index`.
+    pub fn local(&self, index: usize) -> Index<R64, i8> {
+        self.function.frame.local(index)
+    }
+
+    /// The named stack slot, relative to RBP.
+    pub fn slot(&self, name: &str) -> Index<R64, i8> {
+        self.function.frame.rbp(name)
+    }
+
+    /// The named stack slot, relative to RSP, accounting for values pushed
+    /// with [`push_value`](Self::push_value).
+    ///
+    /// The offset depends on the number of saved registers, so every
+    /// callee-saved register used by the body must be declared with
+    /// [`Function::saves`].
+    pub fn slot_rsp(&mut self, name: &str) -> Index<R64, i8> {
+        self.rsp_relative = true;
+        self.function.frame.rsp(name)
+    }
+
+    /// See [`Assembler::const_u64`].
+    pub fn const_u64(&mut self, value: u64) -> Ptr {
+        self.asm.const_u64(value)
+    }
+
+    /// See [`Assembler::const_bytes`].
+    pub fn const_bytes(&mut self, bytes: &[u8]) -> Ptr {
+        self.asm.const_bytes(bytes)
+    }
+
+    /// A pointer to a copy of `bytes`, aligned to `align` bytes, placed in
+    /// the code stream after the end of the function.
+    ///
+    /// Unlike [`const_bytes`](Self::const_bytes), the data is not shared,
+    /// but it is close to the code that uses it and needs no data segment.
+    /// Each block has its own label, which ends where the next label
+    /// begins, so that listings can tell it apart from code.
+    pub fn inline_data(&mut self, bytes: &[u8], align: usize) -> Ptr {
+        let label = self
+            .asm
+            .fresh_label(&format!("{}.data", self.function.name));
+        self.inline_data.push((label, bytes.to_vec(), align));
+        label.ptr()
+    }
+
+    /// Push a register, keeping track of the stack depth.
+    pub fn push_value(&mut self, register: R64) {
+        self.push(PUSH(register));
+        self.function.frame.push(8);
+    }
+
+    /// Pop a register pushed by [`push_value`](Self::push_value).
+    pub fn pop_value(&mut self, register: R64) {
+        self.push(POP(register));
+        self.function.frame.pop(8);
+    }
+
+    /// Emit a pending call, and the jump to the epilogue for a preceding
+    /// [`ret`](Self::ret), if the body continues after them.
+    fn continue_body(&mut self) {
+        self.emit_pending_call();
+        if self.returning {
+            self.returning = false;
+            let epilogue = match self.epilogue {
+                Some(epilogue) => epilogue,
+                None => *self.epilogue.insert(self.asm.fresh_label("epilogue")),
+            };
+            self.body.push(JMP(epilogue));
+        }
+    }
+
+    /// Emit the `CALL` of a pending call that did not become a tail call,
+    /// padding the stack as any other call would be.
+    fn emit_pending_call(&mut self) {
+        if let Some((convention, target)) = self.tail_call.take() {
+            // A naked function starts with the return address on the stack.
+            let misaligned = self.function.frame.is_aligned() == self.function.naked;
+            self.body.call(convention, target, &[], &[], misaligned);
+        }
+    }
+
+    pub fn label(&mut self, label: impl Into<Label>) {
+        self.continue_body();
+        self.body.label(label);
+    }
+
+    #[track_caller]
+    pub fn push<I>(&mut self, instruction: I)
+    where
+        I: Instruction,
+    {
+        self.continue_body();
+        self.body.push(instruction);
+    }
+
+    /// See [`Assembler::comment`].
+    pub fn comment(&mut self, text: &str) {
+        self.continue_body();
+        self.body.comment(text);
+    }
+
+    /// Like [`Assembler::call_fn`], but re-aligns the stack if values have
+    /// been pushed with [`push_value`](Self::push_value).
+    pub fn call_fn(&mut self, target: impl Into<Label>, args: &[Arg]) {
+        self.call_fn_preserving(target, args, &[]);
+    }
+
+    pub fn call_fn_preserving(&mut self, target: impl Into<Label>, args: &[Arg], live: &[R64]) {
+        self.call_with(&SysV, target, args, live);
+    }
+
+    /// Like [`Assembler::call_with`], but re-aligns the stack if values have
+    /// been pushed with [`push_value`](Self::push_value).
+    ///
+    /// If nothing but [`ret`](Self::ret) or [`finish`](Self::finish) follows
+    /// the call, the `CALL` and `RET` are replaced with a `JMP` after the
+    /// epilogue. This is only done if the callee follows the same
+    /// convention, the arguments are all in registers, nothing has been
+    /// pushed, and the function has no slots that an argument could point
+    /// to.
+    pub fn call_with(
+        &mut self,
+        convention: &'static dyn CallingConvention,
+        target: impl Into<Label>,
+        args: &[Arg],
+        live: &[R64],
+    ) {
+        self.continue_body();
+        let frame = &self.function.frame;
+        if *convention == *self.function.convention
+            && args.len() <= convention.parameters().len()
+            && live
+                .iter()
+                .all(|register| !convention.caller_saved().contains(register))
+            && frame.pushed() == 0
+            && frame.is_empty()
+        {
+            self.body.move_parallel(
+                convention
+                    .parameters()
+                    .iter()
+                    .copied()
+                    .zip(args.iter().copied())
+                    .collect(),
+            );
+            self.tail_call = Some((convention, target.into()));
+            return;
+        }
+        // A naked function starts with the return address on the stack.
+        let misaligned = self.function.frame.is_aligned() == self.function.naked;
+        self.body.call(convention, target, args, live, misaligned);
+    }
+
+    pub(super) fn move_parallel(&mut self, pending: Vec<(R64, Arg)>) {
+        self.continue_body();
+        self.body.move_parallel(pending);
+    }
+
+    /// Return from the function. May be used more than once.
+    pub fn ret(&mut self) {
+        if self.function.naked {
+            match self.tail_call.take() {
+                Some((_, target)) => self.body.push(JMP(target)),
+                None => self.body.push(RET),
+            }
+        } else if !self.returning {
+            if self.tail_call.is_none() {
+                self.continue_body();
+            }
+            self.returning = true;
+        }
+    }
+
+    /// Emit the function: its prologue, the body, and the epilogue, saving
+    /// and restoring the callee-saved registers used by the body. A naked
+    /// function is emitted as just its body.
+    pub fn finish(mut self) {
+        // Earlier returns jump to the epilogue, so it can't end in a tail
+        // call, and naked functions must not fall off the end.
+        if self.function.naked || self.epilogue.is_some() {
+            self.emit_pending_call();
+        }
+        let Self {
+            mut function,
+            asm,
+            body,
+            epilogue,
+            rsp_relative,
+            inline_data,
+            tail_call,
+            ..
+        } = self;
+
+        if function.exported {
+            asm.export_label(function.name);
+        } else {
+            asm.label(function.name);
+        }
+        if function.naked {
+            asm.embed(body);
+            emit_inline_data(asm, inline_data);
+            return;
+        }
+
+        let mut saved = function.saved.clone();
+        let callee_saved = function.convention.callee_saved();
+        for &register in body.used_registers() {
+            if callee_saved.contains(&register) && register != RBP && !saved.contains(&register) {
+                assert!(
+                    !rsp_relative,
+                    "{} uses {:?}, which must be declared as saved to address slots relative to RSP",
+                    function.name,
+                    register
+                );
+                saved.push(register);
+            }
+        }
+        function.frame.set_saved(saved.len());
+
+        asm.push(PUSH(RBP));
+        asm.push(MOV(RBP, RSP));
+        let frame_size = function.frame.size();
+        if frame_size > 0 {
+            asm.push(SUB(RSP, frame_size as i32));
+        }
+        for &register in &saved {
+            asm.push(PUSH(register));
+        }
+
+        asm.embed(body);
+
+        if let Some(epilogue) = epilogue {
+            asm.label(epilogue);
+        }
+        for &register in saved.iter().rev() {
+            asm.push(POP(register));
+        }
+        if frame_size > 0 {
+            asm.push(ADD(RSP, frame_size as i32));
+        }
+        asm.push(POP(RBP));
+        match tail_call {
+            Some((_, target)) => asm.push(JMP(target)),
+            None => asm.push(RET),
+        }
+        emit_inline_data(asm, inline_data);
+    }
+}
+
+/// Emit the data of [`FunctionBuilder::inline_data`], following a function's
+/// final `RET` or `JMP`.
+fn emit_inline_data(asm: &mut Assembler, inline_data: Vec<(Label, Vec<u8>, usize)>) {
+    for (label, bytes, align) in inline_data {
+        asm.pad_to_alignment(align);
+        asm.label(label);
+        asm.append(&bytes);
+    }
+}
+
+impl Emitter for FunctionBuilder<'_> {
+    #[track_caller]
+    fn push<I>(&mut self, instruction: I)
+    where
+        I: Instruction,
+    {
+        FunctionBuilder::push(self, instruction);
+    }
+
+    fn label(&mut self, label: impl Into<Label>) {
+        FunctionBuilder::label(self, label);
+    }
+
+    fn fresh_label(&mut self, prefix: &str) -> Label {
+        self.asm.fresh_label(prefix)
+    }
+
+    fn pad_to_alignment(&mut self, alignment: usize) {
+        self.continue_body();
+        self.body.pad_to_alignment(alignment);
+    }
+
+    fn append_reference(&mut self, label: impl Into<Label>, format: ReferenceFormat) {
+        self.continue_body();
+        self.body.append_reference(label, format);
+    }
+
+    fn comment(&mut self, text: &str) {
+        FunctionBuilder::comment(self, text);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::x86::instruction::NOP;
+
+    /// The code of `function`, with the body emitted by `body`.
+    fn emit(function: Function, body: impl FnOnce(&mut FunctionBuilder)) -> Vec<u8> {
+        let mut asm = Assembler::new();
+        let mut f = function.begin(&mut asm);
+        body(&mut f);
+        f.finish();
+        asm.finish().bytes().to_vec()
+    }
+
+    /// The code emitted by `build`, to compare against.
+    fn expect(build: impl FnOnce(&mut Assembler)) -> Vec<u8> {
+        let mut asm = Assembler::new();
+        build(&mut asm);
+        asm.finish().bytes().to_vec()
+    }
+
+    #[test]
+    fn call_breaks_register_cycles() {
+        let code = expect(|asm| asm.call_fn("g", &[Arg::Reg(RSI), Arg::Reg(RDI)]));
+        assert_eq!(
+            code,
+            expect(|asm| {
+                asm.push(MOV(R11, RDI));
+                asm.push(MOV(RDI, RSI));
+                asm.push(MOV(RSI, R11));
+                asm.push(CALL(Label("g")));
+            })
+        );
+    }
+
+    #[test]
+    fn call_with_stack_args() {
+        // The seventh argument is pushed, padded to keep the stack aligned.
+        let mut args: Vec<Arg> = (1..=6).map(Arg::Imm).collect();
+        args.push(Arg::Imm(7));
+        let code = expect(|asm| asm.call_fn("g", &args));
+        assert_eq!(
+            code,
+            expect(|asm| {
+                asm.push(SUB(RSP, 8));
+                asm.push(MOV(R11, 7u64));
+                asm.push(PUSH(R11));
+                for (register, value) in PARAMETER_REGISTERS.into_iter().zip(1u64..) {
+                    asm.push(MOV(register, value));
+                }
+                asm.push(CALL(Label("g")));
+                asm.push(ADD(RSP, 16));
+            })
+        );
+
+        // Registers are pushed directly, last to first.
+        args.push(Arg::Reg(RBX));
+        let code = expect(|asm| asm.call_fn("g", &args));
+        assert_eq!(
+            code,
+            expect(|asm| {
+                asm.push(PUSH(RBX));
+                asm.push(MOV(R11, 7u64));
+                asm.push(PUSH(R11));
+                for (register, value) in PARAMETER_REGISTERS.into_iter().zip(1u64..) {
+                    asm.push(MOV(register, value));
+                }
+                asm.push(CALL(Label("g")));
+                asm.push(ADD(RSP, 16));
+            })
+        );
+    }
+
+    #[test]
+    fn call_preserving_live_registers() {
+        // Only caller-saved registers are saved, padded to keep the stack
+        // aligned.
+        let code = expect(|asm| asm.call_fn_preserving("g", &[], &[RBX, RCX]));
+        assert_eq!(
+            code,
+            expect(|asm| {
+                asm.push(PUSH(RCX));
+                asm.push(SUB(RSP, 8));
+                asm.push(CALL(Label("g")));
+                asm.push(ADD(RSP, 8));
+                asm.push(POP(RCX));
+            })
+        );
+
+        let code = expect(|asm| asm.call_fn_preserving("g", &[Arg::Reg(RCX)], &[RDX, RCX]));
+        assert_eq!(
+            code,
+            expect(|asm| {
+                asm.push(PUSH(RCX));
+                asm.push(PUSH(RDX));
+                asm.push(MOV(RDI, RCX));
+                asm.push(CALL(Label("g")));
+                asm.push(POP(RDX));
+                asm.push(POP(RCX));
+            })
+        );
+    }
+
+    #[test]
+    fn prologue_and_epilogue() {
+        // Saved registers, locals, and the bytes subtracted from RSP so that
+        // it is 16-byte aligned in the body.
+        let cases: [(&[R64], usize, i32); 5] = [
+            (&[], 0, 0),
+            (&[RBX], 0, 8),
+            (&[RBX, R12], 0, 0),
+            (&[RBX], 1, 8),
+            (&[RBX, R12], 1, 16),
+        ];
+        for (saved, locals, frame_size) in cases {
+            assert_eq!((8 * saved.len() as i32 + frame_size) % 16, 0);
+            let code = emit(Function::new("f").saves(saved).locals(locals), |f| {
+                f.push(NOP)
+            });
+            assert_eq!(
+                code,
+                expect(|asm| {
+                    asm.push(PUSH(RBP));
+                    asm.push(MOV(RBP, RSP));
+                    if frame_size > 0 {
+                        asm.push(SUB(RSP, frame_size));
+                    }
+                    for &register in saved {
+                        asm.push(PUSH(register));
+                    }
+                    asm.push(NOP);
+                    for &register in saved.iter().rev() {
+                        asm.push(POP(register));
+                    }
+                    if frame_size > 0 {
+                        asm.push(ADD(RSP, frame_size));
+                    }
+                    asm.push(POP(RBP));
+                    asm.push(RET);
+                }),
+                "saving {saved:?} with {locals} locals"
+            );
+        }
+    }
+
+    #[test]
+    fn saves_registers_used_by_body() {
+        let code = emit(Function::new("f"), |f| f.push(MOV(R12, RAX)));
+        assert_eq!(
+            code,
+            expect(|asm| {
+                asm.push(PUSH(RBP));
+                asm.push(MOV(RBP, RSP));
+                asm.push(SUB(RSP, 8));
+                asm.push(PUSH(R12));
+                asm.push(MOV(R12, RAX));
+                asm.push(POP(R12));
+                asm.push(ADD(RSP, 8));
+                asm.push(POP(RBP));
+                asm.push(RET);
+            })
+        );
+    }
+
+    #[test]
+    fn tail_call() {
+        let code = emit(Function::new("f").params(2), |f| {
+            let (a, b) = (f.param(0), f.param(1));
+            f.call_fn("g", &[Arg::Reg(b), Arg::Reg(a)]);
+            f.ret();
+        });
+        assert_eq!(
+            code,
+            expect(|asm| {
+                asm.push(PUSH(RBP));
+                asm.push(MOV(RBP, RSP));
+                asm.push(MOV(R11, RDI));
+                asm.push(MOV(RDI, RSI));
+                asm.push(MOV(RSI, R11));
+                asm.push(POP(RBP));
+                asm.push(JMP(Label("g")));
+            })
+        );
+    }
+
+    #[test]
+    fn call_followed_by_body() {
+        let code = emit(Function::new("f"), |f| {
+            f.call_fn("g", &[]);
+            f.push(NOP);
+            f.ret();
+        });
+        assert_eq!(
+            code,
+            expect(|asm| {
+                asm.push(PUSH(RBP));
+                asm.push(MOV(RBP, RSP));
+                asm.push(CALL(Label("g")));
+                asm.push(NOP);
+                asm.push(POP(RBP));
+                asm.push(RET);
+            })
+        );
+    }
+
+    #[test]
+    fn call_before_existing_epilogue() {
+        let code = emit(Function::new("f"), |f| {
+            f.ret();
+            f.label("more");
+            f.call_fn("g", &[]);
+            f.ret();
+        });
+        assert_eq!(
+            code,
+            expect(|asm| {
+                asm.push(PUSH(RBP));
+                asm.push(MOV(RBP, RSP));
+                asm.push(JMP(Label("epilogue")));
+                asm.label("more");
+                asm.push(CALL(Label("g")));
+                asm.label("epilogue");
+                asm.push(POP(RBP));
+                asm.push(RET);
+            })
+        );
+    }
+
+    #[test]
+    fn naked_calls_are_aligned() {
+        // Entered with the return address on the stack, so the call is
+        // padded, unless it becomes a tail call.
+        let code = emit(Function::new("f").naked(), |f| {
+            f.call_fn("g", &[]);
+            f.push(NOP);
+            f.call_fn("h", &[]);
+            f.ret();
+        });
+        assert_eq!(
+            code,
+            expect(|asm| {
+                asm.push(SUB(RSP, 8));
+                asm.push(CALL(Label("g")));
+                asm.push(ADD(RSP, 8));
+                asm.push(NOP);
+                asm.push(JMP(Label("h")));
+            })
+        );
+
+        // A call at the end of the body, e.g. to a function that doesn't
+        // return, is still a call.
+        let code = emit(Function::new("f").naked(), |f| f.call_fn("g", &[]));
+        assert_eq!(
+            code,
+            expect(|asm| {
+                asm.push(SUB(RSP, 8));
+                asm.push(CALL(Label("g")));
+                asm.push(ADD(RSP, 8));
+            })
+        );
+    }
+}
@@ -0,0 +1,126 @@
+//! Typed global variables.
+//!
+//! A [`Global`] is a label for a value of type `T` in a data segment. It
+//! records the type of the value, so that globals holding integers can be
+//! loaded and stored with the `MOV` form of the right size, instead of
+//! repeating it at every use of a raw label.
+
+use std::{
+    marker::PhantomData,
+    mem::{align_of, size_of},
+};
+
+use bytemuck::Pod;
+
+use super::{
+    instruction::MOV,
+    register::{R16, R32, R64, R8},
+    Emitter,
+};
+use crate::link::{Label, Ptr, Segment};
+
+pub struct Global<T> {
+    label: Label,
+    _type: PhantomData<T>,
+}
+
+// Not derived, as that would require `T: Copy`.
+impl<T> Clone for Global<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Global<T> {}
+
+impl<T: Pod> Global<T> {
+    /// Allocate a global in `segment`, initialized to `value`.
+    pub fn new(segment: &mut Segment, label: impl Into<Label>, value: &T) -> Self {
+        let label = label.into();
+        segment.pad_to_alignment(align_of::<T>());
+        segment.export_label(label);
+        segment.append(value);
+        Self::at(label)
+    }
+}
+
+impl<T> Global<T> {
+    /// Allocate a zero-initialized global in the reserved space of
+    /// `segment`, like `.bss`. See [`Segment::reserve`].
+    pub fn reserve(segment: &mut Segment, label: impl Into<Label>) -> Self {
+        let label = label.into();
+        segment.pad_to_alignment(align_of::<T>());
+        segment.export_label(label);
+        segment.reserve(size_of::<T>());
+        Self::at(label)
+    }
+
+    /// A global defined elsewhere, at `label`.
+    pub fn at(label: impl Into<Label>) -> Self {
+        Self {
+            label: label.into(),
+            _type: PhantomData,
+        }
+    }
+
+    pub fn label(&self) -> Label {
+        self.label
+    }
+
+    /// The global as a RIP-relative memory operand, e.g. for `LEA`.
+    pub fn ptr(&self) -> Ptr {
+        self.label.ptr()
+    }
+}
+
+impl<T: Scalar> Global<T> {
+    /// Load the value into `dst`.
+    pub fn load(&self, asm: &mut impl Emitter, dst: T::Register) {
+        T::load(asm, dst, self.ptr());
+    }
+
+    /// Store the value in `src`.
+    pub fn store(&self, asm: &mut impl Emitter, src: T::Register) {
+        T::store(asm, self.ptr(), src);
+    }
+}
+
+/// Integer types that fit in a register, and can be loaded and stored with a
+/// single `MOV`.
+pub trait Scalar: Pod {
+    /// The register of the same size.
+    type Register: Copy;
+
+    fn load(asm: &mut impl Emitter, dst: Self::Register, src: Ptr);
+
+    fn store(asm: &mut impl Emitter, dst: Ptr, src: Self::Register);
+}
+
+macro_rules! scalar {
+    ($($ty:ty => $register:ty),* $(,)?) => {
+        $(
+            impl Scalar for $ty {
+                type Register = $register;
+
+                fn load<'a>(asm: &mut impl Emitter, dst: $register, src: Ptr) {
+                    asm.push(MOV(dst, src));
+                }
+
+                fn store<'a>(asm: &mut impl Emitter, dst: Ptr, src: $register) {
+                    asm.push(MOV(dst, src));
+                }
+            }
+        )*
+    };
+}
+
+scalar! {
+    u8 => R8,
+    i8 => R8,
+    u16 => R16,
+    i16 => R16,
+    u32 => R32,
+    i32 => R32,
+    u64 => R64,
+    i64 => R64,
+}
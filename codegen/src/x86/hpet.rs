@@ -0,0 +1,147 @@
+//! A generator for basic HPET setup.
+//!
+//! The HPET's registers are memory-mapped, at the physical address given
+//! by the ACPI HPET table. As with the [local APIC](super::apic), the
+//! virtual address that the kernel has mapped them at is passed to the
+//! generated `init` routine and kept in a global for the other routines.
+//!
+//! The generated routines, called with the System V ABI, are:
+//!
+//! - `{name}_init(base)`: reset the main counter, start the periodic timer
+//!   if one is configured, and enable the HPET, with its registers mapped at
+//!   `base`
+//! - `{name}_counter()`: return the main counter, which counts up in periods
+//!   of the HPET's clock, reported in femtoseconds by its capabilities
+
+use super::{
+    address::Index, function::Function, global::Global, instruction::*, register::R64::*, Assembler,
+};
+use crate::link::{Label, Segment};
+
+/// General capabilities and ID register. The clock period, in
+/// femtoseconds, is in bits 63:32.
+const CAPABILITIES: i8 = 0x00;
+/// General configuration register.
+const CONFIGURATION: i32 = 0x10;
+/// Enable bit of the general configuration, which starts the main counter
+/// and allows timer interrupts.
+const ENABLE: u32 = 1 << 0;
+/// Main counter value register.
+const MAIN_COUNTER: i32 = 0xf0;
+
+/// Configuration register of timer 0. Each timer's registers follow the
+/// previous one's at [`TIMER_STRIDE`].
+const TIMER_CONFIGURATION: i32 = 0x100;
+/// Comparator register of timer 0.
+const TIMER_COMPARATOR: i32 = 0x108;
+const TIMER_STRIDE: i32 = 0x20;
+/// Timer configuration bits: interrupt enable, periodic mode, allow setting
+/// the period with the next comparator write, and the shift of the I/O APIC
+/// input that the interrupt is routed to.
+const TIMER_INTERRUPT_ENABLE: u32 = 1 << 2;
+const TIMER_PERIODIC: u32 = 1 << 3;
+const TIMER_VALUE_SET: u32 = 1 << 6;
+const TIMER_ROUTE_SHIFT: u32 = 9;
+
+const FEMTOSECONDS_PER_NANOSECOND: u64 = 1_000_000;
+
+/// Configuration of a periodic HPET timer.
+#[derive(Debug, Clone, Copy)]
+pub struct Timer {
+    /// Index of the timer, which must support periodic mode. Timer 0 is
+    /// usually the only one that does.
+    pub index: u8,
+    /// I/O APIC input that the interrupt is routed to. It must be one of
+    /// those allowed by the timer's capabilities.
+    pub route: u8,
+    /// Time between interrupts, in nanoseconds.
+    pub period_ns: u64,
+}
+
+pub struct Hpet<'a> {
+    name: &'a str,
+    timer: Option<Timer>,
+}
+
+impl<'a> Hpet<'a> {
+    pub fn new(name: &'a str) -> Self {
+        Self { name, timer: None }
+    }
+
+    pub fn timer(mut self, timer: Timer) -> Self {
+        assert!(
+            timer.index < 32,
+            "HPET timer {} does not exist",
+            timer.index
+        );
+        assert!(
+            timer.route < 32,
+            "HPET interrupt route {} is out of range",
+            timer.route
+        );
+        assert!(
+            timer.period_ns > 0
+                && timer
+                    .period_ns
+                    .checked_mul(FEMTOSECONDS_PER_NANOSECOND)
+                    .is_some(),
+            "HPET timer period {}ns is out of range",
+            timer.period_ns
+        );
+        self.timer = Some(timer);
+        self
+    }
+
+    /// A label derived from the name.
+    fn label(&self, suffix: &str) -> Label {
+        Label(&format!("{}_{}", self.name, suffix))
+    }
+
+    /// Emit the exported routines into `asm`, and the global holding the
+    /// register base into `data`.
+    pub fn emit(&self, asm: &mut Assembler, data: &mut Segment) {
+        let base = Global::<u64>::reserve(data, self.label("base"));
+
+        let mut f = Function::new(self.label("init"))
+            .export()
+            .params(1)
+            .begin(asm);
+        let registers = f.param(0);
+        base.store(&mut f, registers);
+        // Halt the main counter while it is reset and the timer is set up.
+        f.push(MOV(Index(registers, CONFIGURATION), 0u32));
+        f.push(MOV(Index(registers, MAIN_COUNTER), 0u32));
+        f.push(MOV(Index(registers, MAIN_COUNTER + 4), 0u32));
+        if let Some(timer) = self.timer {
+            let offset = TIMER_STRIDE * timer.index as i32;
+            f.push(MOV(
+                Index(registers, TIMER_CONFIGURATION + offset),
+                TIMER_INTERRUPT_ENABLE
+                    | TIMER_PERIODIC
+                    | TIMER_VALUE_SET
+                    | (timer.route as u32) << TIMER_ROUTE_SHIFT,
+            ));
+            // The period in clock ticks, from the clock period in
+            // femtoseconds. With the value set bit, the comparator write
+            // sets both the first deadline and the period.
+            f.push(MOV(RCX, Index(registers, CAPABILITIES)));
+            f.push(SHR(RCX, 32i8));
+            f.push(MOV(RAX, timer.period_ns * FEMTOSECONDS_PER_NANOSECOND));
+            f.push(XOR(RDX, RDX));
+            f.push(DIV(RCX));
+            f.push(MOV(Index(registers, TIMER_COMPARATOR + offset), RAX));
+        }
+        f.push(MOV(Index(registers, CONFIGURATION), ENABLE));
+        f.ret();
+        f.finish();
+
+        let mut f = Function::new(self.label("counter"))
+            .export()
+            .returns()
+            .begin(asm);
+        base.load(&mut f, RCX);
+        f.push(MOV(RAX, Index(RCX, MAIN_COUNTER)));
+        f.ret();
+        f.finish();
+    }
+}
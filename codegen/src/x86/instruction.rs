@@ -1,26 +1,124 @@
 use super::{
-    address::{Index, Indirect},
-    register::{Register, R16, R32, R64, R8},
+    address::{GsIndex, Index, Indirect, Scale, ScaledIndex},
+    register::{Egpr, Register, Sreg, Xmm, R16, R32, R64, R8},
 };
-use crate::link::{Label, Ptr, Reference, ReferenceFormat};
+use crate::{
+    asm::Encoding,
+    link::{Label, Ptr, Reference, ReferenceFormat, Segment},
+};
+use std::fmt;
+
+/// The most bytes an x86 instruction may have.
+pub const MAX_INSTRUCTION_LENGTH: usize = 15;
+
+/// A list of at most `N` items, stored inline, so that encoding an
+/// instruction doesn't allocate.
+#[derive(Clone, Copy)]
+struct InlineVec<T, const N: usize> {
+    items: [T; N],
+    len: usize,
+}
+
+impl<T: Copy, const N: usize> InlineVec<T, N> {
+    /// An empty list, with unused slots holding `fill`.
+    fn new(fill: T) -> Self {
+        Self {
+            items: [fill; N],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, item: T) {
+        assert!(self.len < N, "more than {} operands of a kind", N);
+        self.items[self.len] = item;
+        self.len += 1;
+    }
+
+    fn extend(&mut self, items: impl IntoIterator<Item = T>) {
+        for item in items {
+            self.push(item);
+        }
+    }
+
+    fn as_slice(&self) -> &[T] {
+        &self.items[..self.len]
+    }
+}
 
-pub struct InstructionBuilder<'a> {
-    prefixes: Vec<u8>,
+/// The operating mode that code is encoded for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Mode {
+    /// 64-bit mode.
+    #[default]
+    Long,
+    /// 32-bit protected mode: the default operand and address sizes are 32
+    /// bits, and there are no REX prefixes, so R8-R15 and 64-bit operands
+    /// are not available.
+    ///
+    /// Memory operands are written with the 64-bit names of their base and
+    /// index registers, which select the 32-bit registers, and instructions
+    /// whose operand size defaults to 64 bits in long mode, like `PUSH` and
+    /// `JMP r/m`, operate on 32 bits. A [`Ptr`] is an absolute address
+    /// rather than a RIP-relative one.
+    Protected,
+    /// 16-bit real mode: the default operand and address sizes are 16 bits.
+    /// Besides the restrictions of [protected mode](Self::Protected), 32-bit
+    /// operands need an operand-size override, which is added, and 16-bit
+    /// operands don't, so it is dropped.
+    ///
+    /// Memory operands with base and index registers use 32-bit addressing,
+    /// with an address-size override. A [`Ptr`] is a 16-bit absolute
+    /// offset, and branches to labels have 16-bit relative offsets.
+    Real,
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Long => "long",
+            Self::Protected => "protected",
+            Self::Real => "real",
+        })
+    }
+}
+
+pub struct InstructionBuilder {
+    /// Legacy prefixes, at most one from each of the four groups.
+    prefixes: InlineVec<u8, 4>,
+    operand_size_override: bool,
     rex: u8,
+    /// The bits of a REX2 prefix that extend register codes to 5 bits, for
+    /// the registers of [`Egpr`]. Any of them makes the REX prefix a REX2
+    /// prefix.
+    rex2: u8,
     opcode_size: u8,
     opcode: [u8; 3],
     modrm: Option<u8>,
     sib: Option<u8>,
     displacement: Option<Immediate>,
     immediate: Option<Immediate>,
-    reference: Option<(Label<'a>, ReferenceFormat)>,
+    reference: Option<(Label, ReferenceFormat)>,
+    /// Whether the memory operand is RIP-relative, which is an absolute
+    /// address outside of long mode.
+    rip_relative: bool,
+    /// General-purpose registers named by the operands: at most the ModRM
+    /// reg field, and a base and an index.
+    registers: InlineVec<R64, 3>,
+    /// Whether an operand is a high byte register, which rules out a REX
+    /// prefix.
+    high_byte: bool,
+    /// Whether the operands are 32 bits wide: a 32-bit general-purpose
+    /// register, or a memory operand marked with [`dword`](Self::dword).
+    dword: bool,
 }
 
-impl<'a> InstructionBuilder<'a> {
+impl InstructionBuilder {
     pub fn new() -> Self {
         Self {
-            prefixes: Vec::new(),
+            prefixes: InlineVec::new(0),
+            operand_size_override: false,
             rex: 0x40,
+            rex2: 0,
             opcode_size: 0,
             opcode: [0; 3],
             modrm: None,
@@ -28,11 +126,42 @@ impl<'a> InstructionBuilder<'a> {
             displacement: None,
             immediate: None,
             reference: None,
+            rip_relative: false,
+            registers: InlineVec::new(R64::RAX),
+            high_byte: false,
+            dword: false,
+        }
+    }
+
+    /// The general-purpose registers named by the operands. Implicit operands
+    /// are not included.
+    pub fn registers(&self) -> &[R64] {
+        self.registers.as_slice()
+    }
+
+    /// Add the operand-size override prefix. It is emitted after the other
+    /// legacy prefixes, as assemblers do.
+    pub fn operand_size_override(self) -> Self {
+        Self {
+            operand_size_override: true,
+            ..self
+        }
+    }
+
+    /// Mark the operands as 32 bits wide, for encodings with no 32-bit
+    /// register to tell, like `MOV dword [rbx], imm32`, so that the operand
+    /// size can be overridden in real mode.
+    pub fn dword(self) -> Self {
+        Self {
+            dword: true,
+            ..self
         }
     }
 
-    pub fn operand_size_override(mut self) -> Self {
-        self.prefixes.push(0x66);
+    /// Add a legacy prefix, such as a REP prefix or the mandatory prefix of
+    /// an SSE instruction.
+    pub fn prefix(mut self, prefix: u8) -> Self {
+        self.prefixes.push(prefix);
         self
     }
 
@@ -51,9 +180,13 @@ impl<'a> InstructionBuilder<'a> {
         }
     }
 
-    pub fn op_reg<R: Register>(self, reg: R) -> Self {
+    pub fn op_reg<R: Register>(mut self, reg: R) -> Self {
+        self.registers.extend(reg.gpr());
         Self {
             rex: self.rex | reg.rex_b(),
+            rex2: self.rex2 | reg.rex2_b(),
+            high_byte: self.high_byte || reg.high_byte(),
+            dword: self.dword || reg.dword(),
             opcode: [
                 self.opcode[0],
                 self.opcode[1],
@@ -70,9 +203,13 @@ impl<'a> InstructionBuilder<'a> {
         }
     }
 
-    pub fn reg<R: Register>(self, reg: R) -> Self {
+    pub fn reg<R: Register>(mut self, reg: R) -> Self {
+        self.registers.extend(reg.gpr());
         Self {
             rex: self.rex | reg.rex_r(),
+            rex2: self.rex2 | reg.rex2_r(),
+            high_byte: self.high_byte || reg.high_byte(),
+            dword: self.dword || reg.dword(),
             modrm: Some(self.modrm.unwrap_or(0x00) | reg.in_reg()),
             ..self
         }
@@ -85,9 +222,13 @@ impl<'a> InstructionBuilder<'a> {
         }
     }
 
-    pub fn rm_reg<R: Register>(self, reg: R) -> Self {
+    pub fn rm_reg<R: Register>(mut self, reg: R) -> Self {
+        self.registers.extend(reg.gpr());
         Self {
             rex: self.rex | reg.rex_b(),
+            rex2: self.rex2 | reg.rex2_b(),
+            high_byte: self.high_byte || reg.high_byte(),
+            dword: self.dword || reg.dword(),
             modrm: Some(self.modrm.unwrap_or(0x00) | reg.in_rm()),
             ..self
         }
@@ -100,7 +241,10 @@ impl<'a> InstructionBuilder<'a> {
         }
     }
 
-    pub fn index(self, reg: R64) -> Self {
+    pub fn index(mut self, reg: R64) -> Self {
+        // An index of 100 means no index.
+        assert!(reg != R64::RSP, "RSP can not be used as an index register");
+        self.registers.extend(reg.gpr());
         Self {
             rex: self.rex | reg.rex_x(),
             sib: Some(self.sib.unwrap_or(0x00) | reg.in_reg()),
@@ -108,7 +252,8 @@ impl<'a> InstructionBuilder<'a> {
         }
     }
 
-    pub fn base(self, reg: R64) -> Self {
+    pub fn base(mut self, reg: R64) -> Self {
+        self.registers.extend(reg.gpr());
         Self {
             rex: self.rex | reg.rex_b(),
             sib: Some(self.sib.unwrap_or(0x00) | reg.in_base()),
@@ -116,6 +261,13 @@ impl<'a> InstructionBuilder<'a> {
         }
     }
 
+    pub fn base_const(self, x: u8) -> Self {
+        Self {
+            sib: Some(self.sib.unwrap_or(0x00) | x),
+            ..self
+        }
+    }
+
     pub fn displacement<I: Into<Immediate>>(self, displacement: I) -> Self {
         Self {
             displacement: Some(displacement.into()),
@@ -134,65 +286,279 @@ impl<'a> InstructionBuilder<'a> {
         self.mod_(0b11).rm_reg(reg)
     }
 
+    pub fn rm_xmm(self, reg: Xmm) -> Self {
+        self.mod_(0b11).rm_reg(reg)
+    }
+
+    /// Set the SIB index field to "none", for a base register without an
+    /// index.
+    pub fn no_index(self) -> Self {
+        Self {
+            sib: Some(self.sib.unwrap_or(0x00) | (0b100 << 3)),
+            ..self
+        }
+    }
+
     pub fn indirect(self, indirect: Indirect<R64>) -> Self {
-        self.mod_(0b00).rm_reg(indirect.0)
+        match indirect.0.in_rm() {
+            // With mod = 00, r/m = 101 means RIP-relative, so RBP and R13
+            // need an explicit zero displacement.
+            0b101 => self.indexed_displacement(Index(indirect.0, 0)),
+            // r/m = 100 means a SIB byte follows, so RSP and R12 need one.
+            0b100 => self.mod_(0b00).rm_const(0b100).no_index().base(indirect.0),
+            _ => self.mod_(0b00).rm_reg(indirect.0),
+        }
     }
 
     pub fn indexed_indirect(self, index: Index<R64, R64>) -> Self {
-        self.mod_(0b00).rm_const(0b100).index(index.0).base(index.1)
+        // With mod = 00, a base of 101 means no base register, so RBP and
+        // R13 need an explicit zero displacement.
+        let builder = if index.1.in_rm() == 0b101 {
+            self.mod_(0b01).displacement(0i8)
+        } else {
+            self.mod_(0b00)
+        };
+        builder.rm_const(0b100).index(index.0).base(index.1)
+    }
+
+    pub fn scaled_indexed_indirect<S: Scale>(self, index: ScaledIndex<S, R64, R64>) -> Self {
+        let builder = self.indexed_indirect(Index(index.1, index.2));
+        Self {
+            sib: Some(builder.sib.unwrap_or(0x00) | index.0.bits() << 6),
+            ..builder
+        }
     }
 
     pub fn indexed_displacement(self, index: Index<R64, i8>) -> Self {
-        self.mod_(0b01).rm_reg(index.0).displacement(index.1)
-    }
-
-    pub fn reference(self, label: Label<'a>, format: ReferenceFormat) -> Self {
+        let builder = self.mod_(0b01);
+        // r/m = 100 means a SIB byte follows, so RSP and R12 need one.
+        let builder = if index.0.in_rm() == 0b100 {
+            builder.rm_const(0b100).no_index().base(index.0)
+        } else {
+            builder.rm_reg(index.0)
+        };
+        builder.displacement(index.1)
+    }
+
+    pub fn indexed_displacement32(self, index: Index<R64, i32>) -> Self {
+        let builder = self.mod_(0b10);
+        // r/m = 100 means a SIB byte follows, so RSP and R12 need one.
+        let builder = if index.0.in_rm() == 0b100 {
+            builder.rm_const(0b100).no_index().base(index.0)
+        } else {
+            builder.rm_reg(index.0)
+        };
+        builder.displacement(index.1)
+    }
+
+    pub fn gs_index(self, index: GsIndex) -> Self {
+        let displacement = i32::try_from(index.0).expect("GS offset does not fit in disp32");
+        // With mod = 00, r/m = 100 and a SIB base of 101 with no index, the
+        // address is just the 32-bit displacement, which the segment
+        // override makes relative to GS_BASE.
+        self.prefix(0x65)
+            .mod_(0b00)
+            .rm_const(0b100)
+            .no_index()
+            .base_const(0b101)
+            .displacement(displacement)
+    }
+
+    pub fn reference(self, label: Label, format: ReferenceFormat) -> Self {
         Self {
             reference: Some((label, format)),
             ..self
         }
     }
 
-    pub fn rel32(self, label: Label<'a>) -> Self {
+    pub fn rel32(self, label: Label) -> Self {
         self.displacement(0i32)
             .reference(label, ReferenceFormat::Rel32)
     }
 
-    pub fn rip_relative(self, ptr: Ptr<'a>) -> Self {
-        self.mod_(0b00)
-            .rm_const(0b101)
-            .immediate(0u32)
-            .reference(Label(ptr.0), ReferenceFormat::Rel32)
+    pub fn rip_relative(self, ptr: Ptr) -> Self {
+        Self {
+            rip_relative: true,
+            ..self
+                .mod_(0b00)
+                .rm_const(0b101)
+                .immediate(0u32)
+                .reference(ptr.label(), ReferenceFormat::Rel32)
+        }
     }
 
-    pub fn serialize<'b>(&'b self) -> impl IntoIterator<Item = u8> + 'b {
-        self.prefixes
-            .iter()
-            .copied()
-            .chain(if self.rex & 0x0f != 0 {
-                Some(self.rex)
-            } else {
-                None
-            })
-            .chain(
-                self.opcode[(self.opcode.len() - self.opcode_size as usize)..]
-                    .iter()
-                    .copied(),
-            )
-            .chain(self.modrm)
-            .chain(self.sib)
-            .chain(self.displacement.iter().flat_map(Immediate::bytes).copied())
-            .chain(self.immediate.iter().flat_map(Immediate::bytes).copied())
+    /// Adapt the encoding to `mode`, panicking if the instruction can not be
+    /// encoded in it.
+    pub fn for_mode(self, mode: Mode) -> Self {
+        if mode == Mode::Long {
+            return self;
+        }
+        assert!(
+            self.rex2 == 0,
+            "R16-R31 can not be encoded in {} mode",
+            mode
+        );
+        assert!(
+            self.rex & 0x08 == 0,
+            "64-bit operands can not be encoded in {} mode",
+            mode
+        );
+        assert!(
+            self.rex & 0x07 == 0,
+            "R8-R15 can not be encoded in {} mode",
+            mode
+        );
+        let builder = match self.reference {
+            // With mod = 00, r/m = 101 is a 32-bit absolute address outside
+            // of long mode.
+            Some((label, ReferenceFormat::Rel32)) if self.rip_relative => Self {
+                reference: Some((label, ReferenceFormat::Abs32)),
+                rip_relative: false,
+                ..self
+            },
+            _ => self,
+        };
+        match mode {
+            Mode::Long | Mode::Protected => builder,
+            Mode::Real => builder.for_real_mode(),
+        }
+    }
+
+    /// Adapt a protected-mode encoding to real mode.
+    fn for_real_mode(mut self) -> Self {
+        let memory = self.modrm.is_some_and(|modrm| modrm >> 6 != 0b11);
+        match self.reference {
+            // With 16-bit addressing, mod = 00 and r/m = 110 is a 16-bit
+            // absolute address.
+            Some((label, ReferenceFormat::Abs32)) if memory && self.sib.is_none() => {
+                self.modrm = self.modrm.map(|modrm| modrm & !0b111 | 0b110);
+                self.immediate = Some(0u16.into());
+                self.reference = Some((label, ReferenceFormat::Abs16));
+            }
+            // The operand of a near branch.
+            Some((label, ReferenceFormat::Rel32)) => {
+                self.displacement = Some(0u16.into());
+                self.reference = Some((label, ReferenceFormat::Rel16));
+            }
+            _ if memory => self.prefixes.push(0x67),
+            _ => {}
+        }
+        // The operand size is known from the registers, or recorded by the
+        // encoding with `dword`. A 32-bit immediate without either means
+        // that the encoding didn't record it.
+        assert!(
+            self.dword || !matches!(self.immediate, Some(Immediate::X32(_))),
+            "the operand size of a 32-bit immediate is not known in real mode"
+        );
+        self.operand_size_override = self.dword;
+        self
+    }
+
+    /// Append the encoding of the instruction to `out`.
+    pub fn serialize(&self, out: &mut Vec<u8>) {
+        let rex = self.rex & 0x0f != 0 || self.rex2 != 0;
+        // With a REX prefix, the codes of AH, CH, DH and BH select SPL, BPL,
+        // SIL and DIL instead.
+        assert!(
+            !(rex && self.high_byte),
+            "AH, CH, DH and BH can not be encoded in an instruction with a REX prefix"
+        );
+        let len = self.len();
+        assert!(
+            len <= MAX_INSTRUCTION_LENGTH,
+            "{}-byte instruction is longer than {} bytes",
+            len,
+            MAX_INSTRUCTION_LENGTH
+        );
+        out.reserve(len);
+        out.extend_from_slice(self.prefixes.as_slice());
+        if self.operand_size_override {
+            out.push(0x66);
+        }
+        let opcode = self.opcode_bytes();
+        if self.rex2 != 0 {
+            let (map, opcode) = self.rex2_opcode();
+            out.extend([0xd5, map << 7 | self.rex2 | self.rex & 0x0f]);
+            out.extend_from_slice(opcode);
+        } else {
+            if rex {
+                out.push(self.rex);
+            }
+            out.extend_from_slice(opcode);
+        }
+        out.extend(self.modrm);
+        out.extend(self.sib);
+        for field in [self.displacement, self.immediate].iter().flatten() {
+            out.extend_from_slice(field.bytes());
+        }
     }
 
-    pub fn references(&self) -> impl IntoIterator<Item = (Label<'a>, Reference)> {
+    /// Whether the instruction needs a REX2 prefix, for an APX register.
+    pub fn rex2(&self) -> bool {
+        self.rex2 != 0
+    }
+
+    fn opcode_bytes(&self) -> &[u8] {
+        &self.opcode[(self.opcode.len() - self.opcode_size as usize)..]
+    }
+
+    /// The opcode map selected by the REX2 prefix, and the opcode without
+    /// the escape byte that it replaces. Panics if the opcode has no REX2
+    /// form.
+    fn rex2_opcode(&self) -> (u8, &[u8]) {
+        let (map, opcode) = match self.opcode_bytes() {
+            [0x0f, 0x38 | 0x3a, ..] => panic!(
+                "{:02x?} is in opcode map 2 or 3, which can not be encoded with a REX2 prefix",
+                self.opcode_bytes()
+            ),
+            [0x0f, opcode @ ..] => (1, opcode),
+            opcode => (0, opcode),
+        };
+        // These rows hold the REX prefixes, and the branches, string moves
+        // and system instructions that have no REX2 form.
+        assert!(
+            !matches!(
+                (map, opcode[0] >> 4),
+                (0, 0x4 | 0x7 | 0xa | 0xe) | (1, 0x3 | 0x8)
+            ),
+            "{:02x?} can not be encoded with a REX2 prefix",
+            self.opcode_bytes()
+        );
+        (map, opcode)
+    }
+
+    /// The length of the serialized instruction, in bytes.
+    pub fn len(&self) -> usize {
+        let rex = if self.rex2 != 0 {
+            // The REX2 prefix replaces the 0F escape byte.
+            2 - (self.opcode_bytes().first() == Some(&0x0f)) as usize
+        } else {
+            (self.rex & 0x0f != 0) as usize
+        };
+        self.prefixes.len
+            + self.operand_size_override as usize
+            + rex
+            + self.opcode_size as usize
+            + self.modrm.is_some() as usize
+            + self.sib.is_some() as usize
+            + self.displacement.map_or(0, |d| d.bytes().len())
+            + self.immediate.map_or(0, |i| i.bytes().len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn references(&self) -> impl IntoIterator<Item = (Label, Reference)> {
         // FIXME: This assumes that the reference is at the end of the instruction.
-        let size = self.serialize().into_iter().count();
+        let size = self.len();
         self.reference.into_iter().map(move |(label, format)| {
             (
                 label,
                 Reference {
-                    location: size - format.len(),
+                    location: size.checked_sub(format.len()).unwrap_or_else(|| {
+                        panic!("{format:?} reference to {label:?} in a {size}-byte instruction")
+                    }),
                     format,
                 },
             )
@@ -200,6 +566,21 @@ impl<'a> InstructionBuilder<'a> {
     }
 }
 
+impl Encoding for InstructionBuilder {
+    fn len(&self) -> usize {
+        InstructionBuilder::len(self)
+    }
+
+    fn references(&self) -> impl IntoIterator<Item = (Label, Reference)> {
+        InstructionBuilder::references(self)
+    }
+
+    fn serialize(&self, out: &mut Vec<u8>) {
+        InstructionBuilder::serialize(self, out);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Immediate {
     X8([u8; 1]),
     X16([u8; 2]),
@@ -208,7 +589,7 @@ pub enum Immediate {
 }
 
 impl Immediate {
-    fn bytes(&self) -> &[u8] {
+    pub fn bytes(&self) -> &[u8] {
         match self {
             Self::X8(arr) => arr.as_slice(),
             Self::X16(arr) => arr.as_slice(),
@@ -274,14 +655,20 @@ impl Opcode for [u8; 3] {
     }
 }
 
-pub trait Instruction<'a> {
-    fn encode(&self) -> InstructionBuilder<'a>;
+pub trait Instruction {
+    fn encode(&self) -> InstructionBuilder;
+
+    /// Append the encoding of the instruction to `segment`, like
+    /// [`Encoding::encode_into`].
+    fn encode_into(&self, segment: &mut Segment) {
+        self.encode().encode_into(segment);
+    }
 }
 
 pub struct HLT;
 
-impl<'a> Instruction<'a> for HLT {
-    fn encode(&self) -> InstructionBuilder<'a> {
+impl Instruction for HLT {
+    fn encode(&self) -> InstructionBuilder {
         // F4 | HLT
         InstructionBuilder::new().opcode(0xf4)
     }
@@ -289,33 +676,138 @@ impl<'a> Instruction<'a> for HLT {
 
 pub struct JMP<Target>(pub Target);
 
-impl<'a> Instruction<'a> for JMP<Label<'a>> {
-    fn encode(&self) -> InstructionBuilder<'a> {
+impl Instruction for JMP<Label> {
+    fn encode(&self) -> InstructionBuilder {
         // E9 cd | JMP rel32
         InstructionBuilder::new().opcode(0xe9).rel32(self.0)
     }
 }
 
+impl Instruction for JMP<R64> {
+    fn encode(&self) -> InstructionBuilder {
+        // FF /4 | JMP r/m64
+        InstructionBuilder::new()
+            .opcode(0xff)
+            .reg_const(4)
+            .rm_literal(self.0)
+    }
+}
+
 pub struct JZ<Target>(pub Target);
 
-impl<'a> Instruction<'a> for JZ<Label<'a>> {
-    fn encode(&self) -> InstructionBuilder<'a> {
+impl Instruction for JZ<Label> {
+    fn encode(&self) -> InstructionBuilder {
         // 0F 84 cd | JZ rel32
         InstructionBuilder::new().opcode([0x0f, 0x84]).rel32(self.0)
     }
 }
 
+pub struct JNZ<Target>(pub Target);
+
+impl Instruction for JNZ<Label> {
+    fn encode(&self) -> InstructionBuilder {
+        // 0F 85 cd | JNZ rel32
+        InstructionBuilder::new().opcode([0x0f, 0x85]).rel32(self.0)
+    }
+}
+
+/// A condition on the flags, as tested by conditional jumps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    Overflow,
+    NotOverflow,
+    /// Unsigned less than (carry set).
+    Below,
+    /// Unsigned greater than or equal (carry clear).
+    AboveOrEqual,
+    Zero,
+    NotZero,
+    /// Unsigned less than or equal.
+    BelowOrEqual,
+    /// Unsigned greater than.
+    Above,
+    Sign,
+    NotSign,
+    Parity,
+    NotParity,
+    /// Signed less than.
+    Less,
+    /// Signed greater than or equal.
+    GreaterOrEqual,
+    /// Signed less than or equal.
+    LessOrEqual,
+    /// Signed greater than.
+    Greater,
+}
+
+impl Condition {
+    /// The condition code, as encoded in the low nibble of Jcc opcodes.
+    pub fn code(&self) -> u8 {
+        match self {
+            Self::Overflow => 0x0,
+            Self::NotOverflow => 0x1,
+            Self::Below => 0x2,
+            Self::AboveOrEqual => 0x3,
+            Self::Zero => 0x4,
+            Self::NotZero => 0x5,
+            Self::BelowOrEqual => 0x6,
+            Self::Above => 0x7,
+            Self::Sign => 0x8,
+            Self::NotSign => 0x9,
+            Self::Parity => 0xa,
+            Self::NotParity => 0xb,
+            Self::Less => 0xc,
+            Self::GreaterOrEqual => 0xd,
+            Self::LessOrEqual => 0xe,
+            Self::Greater => 0xf,
+        }
+    }
+
+    /// The opposite condition, which holds exactly when this one does not.
+    pub fn negate(&self) -> Self {
+        match self {
+            Self::Overflow => Self::NotOverflow,
+            Self::NotOverflow => Self::Overflow,
+            Self::Below => Self::AboveOrEqual,
+            Self::AboveOrEqual => Self::Below,
+            Self::Zero => Self::NotZero,
+            Self::NotZero => Self::Zero,
+            Self::BelowOrEqual => Self::Above,
+            Self::Above => Self::BelowOrEqual,
+            Self::Sign => Self::NotSign,
+            Self::NotSign => Self::Sign,
+            Self::Parity => Self::NotParity,
+            Self::NotParity => Self::Parity,
+            Self::Less => Self::GreaterOrEqual,
+            Self::GreaterOrEqual => Self::Less,
+            Self::LessOrEqual => Self::Greater,
+            Self::Greater => Self::LessOrEqual,
+        }
+    }
+}
+
+pub struct JCC<Target>(pub Condition, pub Target);
+
+impl Instruction for JCC<Label> {
+    fn encode(&self) -> InstructionBuilder {
+        // 0F 80+cc cd | Jcc rel32
+        InstructionBuilder::new()
+            .opcode([0x0f, 0x80 | self.0.code()])
+            .rel32(self.1)
+    }
+}
+
 pub struct CALL<Target>(pub Target);
 
-impl<'a> Instruction<'a> for CALL<Label<'a>> {
-    fn encode(&self) -> InstructionBuilder<'a> {
+impl Instruction for CALL<Label> {
+    fn encode(&self) -> InstructionBuilder {
         // E8 cd | CALL rel32
         InstructionBuilder::new().opcode(0xe8).rel32(self.0)
     }
 }
 
-impl<'a> Instruction<'a> for CALL<R64> {
-    fn encode(&self) -> InstructionBuilder<'a> {
+impl Instruction for CALL<R64> {
+    fn encode(&self) -> InstructionBuilder {
         // FF /2 | CALL r/m64
         InstructionBuilder::new()
             .opcode(0xff)
@@ -326,8 +818,8 @@ impl<'a> Instruction<'a> for CALL<R64> {
 
 pub struct RET;
 
-impl<'a> Instruction<'a> for RET {
-    fn encode(&self) -> InstructionBuilder<'a> {
+impl Instruction for RET {
+    fn encode(&self) -> InstructionBuilder {
         // C3 | RET
         InstructionBuilder::new().opcode(0xc3)
     }
@@ -335,17 +827,47 @@ impl<'a> Instruction<'a> for RET {
 
 pub struct IRET;
 
-impl<'a> Instruction<'a> for IRET {
-    fn encode(&self) -> InstructionBuilder<'a> {
+impl Instruction for IRET {
+    fn encode(&self) -> InstructionBuilder {
         // REX.W + CF | IRETQ
         InstructionBuilder::new().rex_w().opcode(0xcf)
     }
 }
 
+pub struct SWAPGS;
+
+impl Instruction for SWAPGS {
+    fn encode(&self) -> InstructionBuilder {
+        // 0F 01 F8 | SWAPGS
+        InstructionBuilder::new().opcode([0x0f, 0x01, 0xf8])
+    }
+}
+
+/// Call the operating system, at the entry point in the `LSTAR` MSR.
+pub struct SYSCALL;
+
+impl Instruction for SYSCALL {
+    fn encode(&self) -> InstructionBuilder {
+        // 0F 05 | SYSCALL
+        InstructionBuilder::new().opcode([0x0f, 0x05])
+    }
+}
+
+/// Return from `SYSCALL` to 64-bit user mode, at the address in RCX and
+/// with the flags in R11.
+pub struct SYSRET;
+
+impl Instruction for SYSRET {
+    fn encode(&self) -> InstructionBuilder {
+        // REX.W + 0F 07 | SYSRETQ
+        InstructionBuilder::new().rex_w().opcode([0x0f, 0x07])
+    }
+}
+
 pub struct LIDT<Src>(pub Src);
 
-impl<'a> Instruction<'a> for LIDT<Indirect<R64>> {
-    fn encode(&self) -> InstructionBuilder<'a> {
+impl Instruction for LIDT<Indirect<R64>> {
+    fn encode(&self) -> InstructionBuilder {
         // 0F 01 /3 | LIDT m16&64
         InstructionBuilder::new()
             .opcode([0x0f, 0x01])
@@ -354,8 +876,8 @@ impl<'a> Instruction<'a> for LIDT<Indirect<R64>> {
     }
 }
 
-impl<'a> Instruction<'a> for LIDT<Ptr<'a>> {
-    fn encode(&self) -> InstructionBuilder<'a> {
+impl Instruction for LIDT<Ptr> {
+    fn encode(&self) -> InstructionBuilder {
         // 0F 01 /3 | LIDT m16&64
         InstructionBuilder::new()
             .opcode([0x0f, 0x01])
@@ -366,17 +888,65 @@ impl<'a> Instruction<'a> for LIDT<Ptr<'a>> {
 
 pub struct STI;
 
-impl<'a> Instruction<'a> for STI {
-    fn encode(&self) -> InstructionBuilder<'a> {
+impl Instruction for STI {
+    fn encode(&self) -> InstructionBuilder {
         // FB | STI
         InstructionBuilder::new().opcode(0xfb)
     }
 }
 
+pub struct CLD;
+
+impl Instruction for CLD {
+    fn encode(&self) -> InstructionBuilder {
+        // FC | CLD
+        InstructionBuilder::new().opcode(0xfc)
+    }
+}
+
+pub struct RDMSR;
+
+impl Instruction for RDMSR {
+    fn encode(&self) -> InstructionBuilder {
+        // 0F 32 | RDMSR
+        InstructionBuilder::new().opcode([0x0f, 0x32])
+    }
+}
+
+pub struct WRMSR;
+
+impl Instruction for WRMSR {
+    fn encode(&self) -> InstructionBuilder {
+        // 0F 30 | WRMSR
+        InstructionBuilder::new().opcode([0x0f, 0x30])
+    }
+}
+
+/// Query processor information for the leaf in EAX and subleaf in ECX,
+/// returned in EAX, EBX, ECX and EDX.
+pub struct CPUID;
+
+impl Instruction for CPUID {
+    fn encode(&self) -> InstructionBuilder {
+        // 0F A2 | CPUID
+        InstructionBuilder::new().opcode([0x0f, 0xa2])
+    }
+}
+
+/// Hint that the processor is in a spin-wait loop.
+pub struct PAUSE;
+
+impl Instruction for PAUSE {
+    fn encode(&self) -> InstructionBuilder {
+        // F3 90 | PAUSE
+        InstructionBuilder::new().prefix(0xf3).opcode(0x90)
+    }
+}
+
 pub struct NOP;
 
-impl<'a> Instruction<'a> for NOP {
-    fn encode(&self) -> InstructionBuilder<'a> {
+impl Instruction for NOP {
+    fn encode(&self) -> InstructionBuilder {
         // NP 90 | NOP
         InstructionBuilder::new().opcode(0x90)
     }
@@ -384,8 +954,8 @@ impl<'a> Instruction<'a> for NOP {
 
 pub struct INT3;
 
-impl<'a> Instruction<'a> for INT3 {
-    fn encode(&self) -> InstructionBuilder<'a> {
+impl Instruction for INT3 {
+    fn encode(&self) -> InstructionBuilder {
         // CC | INT3
         InstructionBuilder::new().opcode(0xcc)
     }
@@ -393,219 +963,775 @@ impl<'a> Instruction<'a> for INT3 {
 
 pub struct PUSH<Src>(pub Src);
 
-impl<'a> Instruction<'a> for PUSH<R64> {
-    fn encode(&self) -> InstructionBuilder<'a> {
+impl Instruction for PUSH<R64> {
+    fn encode(&self) -> InstructionBuilder {
         // 50+rd | PUSH r64
         InstructionBuilder::new().opcode(0x50).op_reg(self.0)
     }
 }
 
+impl Instruction for PUSH<Egpr> {
+    fn encode(&self) -> InstructionBuilder {
+        // REX2 + 50+rd | PUSH r64
+        InstructionBuilder::new().opcode(0x50).op_reg(self.0)
+    }
+}
+
+impl Instruction for PUSH<i8> {
+    fn encode(&self) -> InstructionBuilder {
+        // 6A ib | PUSH imm8
+        InstructionBuilder::new().opcode(0x6a).immediate(self.0)
+    }
+}
+
 pub struct POP<Dst>(pub Dst);
 
-impl<'a> Instruction<'a> for POP<R64> {
-    fn encode(&self) -> InstructionBuilder<'a> {
+impl Instruction for POP<R64> {
+    fn encode(&self) -> InstructionBuilder {
         // 58+ rd | POP r64
         InstructionBuilder::new().opcode(0x58).op_reg(self.0)
     }
 }
 
+impl Instruction for POP<Egpr> {
+    fn encode(&self) -> InstructionBuilder {
+        // REX2 + 58+ rd | POP r64
+        InstructionBuilder::new().opcode(0x58).op_reg(self.0)
+    }
+}
+
 pub struct MOV<Dst, Src>(pub Dst, pub Src);
 
-impl<'a> Instruction<'a> for MOV<R64, u64> {
-    fn encode(&self) -> InstructionBuilder<'a> {
-        // REX.W + B8+ rd io | MOV r64, imm64
+impl Instruction for MOV<Sreg, R16> {
+    fn encode(&self) -> InstructionBuilder {
+        // 8E /r | MOV Sreg, r/m16
         InstructionBuilder::new()
-            .rex_w()
-            .opcode(0xb8)
-            .op_reg(self.0)
-            .immediate(self.1)
+            .opcode(0x8e)
+            .reg_const(self.0.code())
+            .mod_(0b11)
+            .rm_reg(self.1)
     }
 }
 
-impl<'a> Instruction<'a> for MOV<R64, Ptr<'a>> {
-    fn encode(&self) -> InstructionBuilder<'a> {
-        // REX.W + 8B /r | MOV r64,r/m64
+impl Instruction for MOV<R16, Sreg> {
+    fn encode(&self) -> InstructionBuilder {
+        // 8C /r | MOV r/m16, Sreg
         InstructionBuilder::new()
-            .rex_w()
-            .opcode(0x8b)
-            .reg(self.0)
-            .rip_relative(self.1)
+            .opcode(0x8c)
+            .reg_const(self.1.code())
+            .mod_(0b11)
+            .rm_reg(self.0)
     }
 }
 
-impl<'a> Instruction<'a> for MOV<R64, R64> {
-    fn encode(&self) -> InstructionBuilder<'a> {
-        // REX.W + 8B /r | MOV r64,r/m64
+impl Instruction for MOV<R64, u64> {
+    fn encode(&self) -> InstructionBuilder {
+        // REX.W + B8+ rd io | MOV r64, imm64
         InstructionBuilder::new()
             .rex_w()
-            .opcode(0x8b)
-            .reg(self.0)
-            .rm_literal(self.1)
+            .opcode(0xb8)
+            .op_reg(self.0)
+            .immediate(self.1)
+    }
+}
+
+impl Instruction for MOV<R8, u8> {
+    fn encode(&self) -> InstructionBuilder {
+        // B0+ rb ib | MOV r8, imm8
+        InstructionBuilder::new()
+            .opcode(0xb0)
+            .op_reg(self.0)
+            .immediate(self.1)
     }
 }
 
-impl<'a> Instruction<'a> for MOV<R64, Indirect<R64>> {
-    fn encode(&self) -> InstructionBuilder<'a> {
+impl Instruction for MOV<R64, Ptr> {
+    fn encode(&self) -> InstructionBuilder {
         // REX.W + 8B /r | MOV r64,r/m64
         InstructionBuilder::new()
             .rex_w()
             .opcode(0x8b)
             .reg(self.0)
-            .indirect(self.1)
+            .rip_relative(self.1)
     }
 }
 
-impl<'a> Instruction<'a> for MOV<R64, Index<R64, i8>> {
-    fn encode(&self) -> InstructionBuilder<'a> {
-        // REX.W + 8B /r | MOV r64,r/m64
+impl Instruction for MOV<R32, Ptr> {
+    fn encode(&self) -> InstructionBuilder {
+        // 8B /r | MOV r32,r/m32
         InstructionBuilder::new()
-            .rex_w()
             .opcode(0x8b)
             .reg(self.0)
-            .indexed_displacement(self.1)
+            .rip_relative(self.1)
     }
 }
 
-impl<'a> Instruction<'a> for MOV<R64, Index<R64, R64>> {
-    fn encode(&self) -> InstructionBuilder<'a> {
-        // REX.W + 8B /r | MOV r64,r/m64
+impl Instruction for MOV<R16, Ptr> {
+    fn encode(&self) -> InstructionBuilder {
+        // 8B /r | MOV r16,r/m16
         InstructionBuilder::new()
-            .rex_w()
+            .operand_size_override()
             .opcode(0x8b)
             .reg(self.0)
-            .indexed_indirect(self.1)
+            .rip_relative(self.1)
     }
 }
 
-impl<'a> Instruction<'a> for MOV<R8, Index<R64, R64>> {
-    fn encode(&self) -> InstructionBuilder<'a> {
+impl Instruction for MOV<R8, Ptr> {
+    fn encode(&self) -> InstructionBuilder {
         // 8A /r | MOV r8,r/m8
-        // FIXME In 64-bit mode, r/m8 can not be encoded to access the
-        // following byte registers if a REX prefix is used: AH, BH, CH, DH.
         InstructionBuilder::new()
             .opcode(0x8a)
             .reg(self.0)
-            .indexed_indirect(self.1)
+            .rip_relative(self.1)
     }
 }
 
-impl<'a> Instruction<'a> for MOV<Indirect<R64>, R64> {
-    fn encode(&self) -> InstructionBuilder<'a> {
+impl Instruction for MOV<Ptr, R64> {
+    fn encode(&self) -> InstructionBuilder {
         // REX.W + 89 /r | MOV r/m64,r64
         InstructionBuilder::new()
             .rex_w()
             .opcode(0x89)
-            .indirect(self.0)
             .reg(self.1)
+            .rip_relative(self.0)
     }
 }
 
-impl<'a> Instruction<'a> for MOV<Indirect<R64>, R8> {
-    fn encode(&self) -> InstructionBuilder<'a> {
-        // 88 /r | MOV r/m8,r8
-        // FIXME In 64-bit mode, r/m8 can not be encoded to access the
-        // following byte registers if a REX prefix is used: AH, BH, CH, DH.
+impl Instruction for MOV<Ptr, R32> {
+    fn encode(&self) -> InstructionBuilder {
+        // 89 /r | MOV r/m32,r32
         InstructionBuilder::new()
-            .opcode(0x88)
-            .indirect(self.0)
+            .opcode(0x89)
             .reg(self.1)
+            .rip_relative(self.0)
     }
 }
 
-impl<'a> Instruction<'a> for MOV<Indirect<R64>, u8> {
-    fn encode(&self) -> InstructionBuilder<'a> {
-        // C6 /0 ib | MOV r/m8, imm8
-        // FIXME In 64-bit mode, r/m8 can not be encoded to access the
-        // following byte registers if a REX prefix is used: AH, BH, CH, DH.
-        InstructionBuilder::new()
-            .rex_w()
-            .opcode(0xc6)
-            .reg_const(0)
-            .indirect(self.0)
-            .immediate(self.1)
-    }
-}
-
-impl<'a> Instruction<'a> for MOV<Index<R64, i8>, R16> {
-    fn encode(&self) -> InstructionBuilder<'a> {
+impl Instruction for MOV<Ptr, R16> {
+    fn encode(&self) -> InstructionBuilder {
         // 89 /r | MOV r/m16,r16
         InstructionBuilder::new()
             .operand_size_override()
             .opcode(0x89)
             .reg(self.1)
-            .indexed_displacement(self.0)
+            .rip_relative(self.0)
     }
 }
 
-impl<'a> Instruction<'a> for MOV<Index<R64, i8>, R32> {
-    fn encode(&self) -> InstructionBuilder<'a> {
-        // 89 /r | MOV r/m32,r32
+impl Instruction for MOV<Ptr, R8> {
+    fn encode(&self) -> InstructionBuilder {
+        // 88 /r | MOV r/m8,r8
         InstructionBuilder::new()
-            .opcode(0x89)
+            .opcode(0x88)
             .reg(self.1)
-            .indexed_displacement(self.0)
+            .rip_relative(self.0)
     }
 }
 
-pub struct LEA<Dst, Src>(pub Dst, pub Src);
-
-impl<'a> Instruction<'a> for LEA<R64, Ptr<'a>> {
-    fn encode(&self) -> InstructionBuilder<'a> {
-        // REX.W + 8D /r | LEA r64, m
+impl Instruction for MOV<R64, GsIndex> {
+    fn encode(&self) -> InstructionBuilder {
+        // REX.W + 8B /r | MOV r64,r/m64
         InstructionBuilder::new()
             .rex_w()
-            .opcode(0x8d)
+            .opcode(0x8b)
             .reg(self.0)
-            .rip_relative(self.1)
+            .gs_index(self.1)
     }
 }
 
-pub struct SUB<Dst, Src>(pub Dst, pub Src);
-
-impl<'a> Instruction<'a> for SUB<R64, i8> {
-    fn encode(&self) -> InstructionBuilder<'a> {
-        // 80 /5 ib | SUB r/m8, imm8
-        // FIXME In 64-bit mode, r/m8 can not be encoded to access the
-        // following byte registers if a REX prefix is used: AH, BH, CH, DH.
+impl Instruction for MOV<R32, GsIndex> {
+    fn encode(&self) -> InstructionBuilder {
+        // 8B /r | MOV r32,r/m32
         InstructionBuilder::new()
-            .opcode(0x80)
-            .reg_const(5)
-            .rm_literal(self.0)
-            .immediate(self.1)
+            .opcode(0x8b)
+            .reg(self.0)
+            .gs_index(self.1)
     }
 }
 
-pub struct CMP<A, B>(pub A, pub B);
-
-impl<'a> Instruction<'a> for CMP<Index<R64, R64>, u8> {
-    fn encode(&self) -> InstructionBuilder<'a> {
-        // 80 /7 ib | CMP r/m8, imm8
+impl Instruction for MOV<R16, GsIndex> {
+    fn encode(&self) -> InstructionBuilder {
+        // 8B /r | MOV r16,r/m16
         InstructionBuilder::new()
-            .opcode(0x80)
-            .reg_const(7)
-            .indexed_indirect(self.0)
-            .immediate(self.1)
+            .operand_size_override()
+            .opcode(0x8b)
+            .reg(self.0)
+            .gs_index(self.1)
     }
 }
 
-pub struct TEST<A, B>(pub A, pub B);
+impl Instruction for MOV<R8, GsIndex> {
+    fn encode(&self) -> InstructionBuilder {
+        // 8A /r | MOV r8,r/m8
+        InstructionBuilder::new()
+            .opcode(0x8a)
+            .reg(self.0)
+            .gs_index(self.1)
+    }
+}
 
-impl<'a> Instruction<'a> for TEST<R64, R64> {
-    fn encode(&self) -> InstructionBuilder<'a> {
-        // REX.W + 85 /r | TEST r/m64, r64
+impl Instruction for MOV<GsIndex, R64> {
+    fn encode(&self) -> InstructionBuilder {
+        // REX.W + 89 /r | MOV r/m64,r64
         InstructionBuilder::new()
             .rex_w()
-            .opcode(0x85)
-            .rm_literal(self.0)
+            .opcode(0x89)
             .reg(self.1)
+            .gs_index(self.0)
     }
 }
 
-pub struct OR<Dst, Src>(pub Dst, pub Src);
-
-impl<'a> Instruction<'a> for OR<Index<R64, i8>, i16> {
-    fn encode(&self) -> InstructionBuilder<'a> {
-        // 81 /1 iw | OR r/m16, imm16
+impl Instruction for MOV<GsIndex, R32> {
+    fn encode(&self) -> InstructionBuilder {
+        // 89 /r | MOV r/m32,r32
+        InstructionBuilder::new()
+            .opcode(0x89)
+            .reg(self.1)
+            .gs_index(self.0)
+    }
+}
+
+impl Instruction for MOV<GsIndex, R16> {
+    fn encode(&self) -> InstructionBuilder {
+        // 89 /r | MOV r/m16,r16
+        InstructionBuilder::new()
+            .operand_size_override()
+            .opcode(0x89)
+            .reg(self.1)
+            .gs_index(self.0)
+    }
+}
+
+impl Instruction for MOV<GsIndex, R8> {
+    fn encode(&self) -> InstructionBuilder {
+        // 88 /r | MOV r/m8,r8
+        InstructionBuilder::new()
+            .opcode(0x88)
+            .reg(self.1)
+            .gs_index(self.0)
+    }
+}
+
+impl Instruction for MOV<R64, R64> {
+    fn encode(&self) -> InstructionBuilder {
+        // REX.W + 8B /r | MOV r64,r/m64
+        InstructionBuilder::new()
+            .rex_w()
+            .opcode(0x8b)
+            .reg(self.0)
+            .rm_literal(self.1)
+    }
+}
+
+impl Instruction for MOV<R64, Indirect<R64>> {
+    fn encode(&self) -> InstructionBuilder {
+        // REX.W + 8B /r | MOV r64,r/m64
+        InstructionBuilder::new()
+            .rex_w()
+            .opcode(0x8b)
+            .reg(self.0)
+            .indirect(self.1)
+    }
+}
+
+impl Instruction for MOV<R32, R32> {
+    fn encode(&self) -> InstructionBuilder {
+        // 8B /r | MOV r32,r/m32
+        InstructionBuilder::new()
+            .opcode(0x8b)
+            .reg(self.0)
+            .mod_(0b11)
+            .rm_reg(self.1)
+    }
+}
+
+impl Instruction for MOV<R32, Index<R64, i8>> {
+    fn encode(&self) -> InstructionBuilder {
+        // 8B /r | MOV r32,r/m32
+        InstructionBuilder::new()
+            .opcode(0x8b)
+            .reg(self.0)
+            .indexed_displacement(self.1)
+    }
+}
+
+impl Instruction for MOV<R64, Index<R64, i8>> {
+    fn encode(&self) -> InstructionBuilder {
+        // REX.W + 8B /r | MOV r64,r/m64
+        InstructionBuilder::new()
+            .rex_w()
+            .opcode(0x8b)
+            .reg(self.0)
+            .indexed_displacement(self.1)
+    }
+}
+
+impl Instruction for MOV<R64, Index<R64, i32>> {
+    fn encode(&self) -> InstructionBuilder {
+        // REX.W + 8B /r | MOV r64,r/m64
+        InstructionBuilder::new()
+            .rex_w()
+            .opcode(0x8b)
+            .reg(self.0)
+            .indexed_displacement32(self.1)
+    }
+}
+
+impl Instruction for MOV<R64, Index<R64, R64>> {
+    fn encode(&self) -> InstructionBuilder {
+        // REX.W + 8B /r | MOV r64,r/m64
+        InstructionBuilder::new()
+            .rex_w()
+            .opcode(0x8b)
+            .reg(self.0)
+            .indexed_indirect(self.1)
+    }
+}
+
+impl<S: Scale + Copy> Instruction for MOV<R64, ScaledIndex<S, R64, R64>> {
+    fn encode(&self) -> InstructionBuilder {
+        // REX.W + 8B /r | MOV r64,r/m64
+        InstructionBuilder::new()
+            .rex_w()
+            .opcode(0x8b)
+            .reg(self.0)
+            .scaled_indexed_indirect(self.1)
+    }
+}
+
+impl Instruction for MOV<R8, Index<R64, R64>> {
+    fn encode(&self) -> InstructionBuilder {
+        // 8A /r | MOV r8,r/m8
+        InstructionBuilder::new()
+            .opcode(0x8a)
+            .reg(self.0)
+            .indexed_indirect(self.1)
+    }
+}
+
+impl Instruction for MOV<Indirect<R64>, R64> {
+    fn encode(&self) -> InstructionBuilder {
+        // REX.W + 89 /r | MOV r/m64,r64
+        InstructionBuilder::new()
+            .rex_w()
+            .opcode(0x89)
+            .indirect(self.0)
+            .reg(self.1)
+    }
+}
+
+impl Instruction for MOV<Indirect<R64>, R8> {
+    fn encode(&self) -> InstructionBuilder {
+        // 88 /r | MOV r/m8,r8
+        InstructionBuilder::new()
+            .opcode(0x88)
+            .indirect(self.0)
+            .reg(self.1)
+    }
+}
+
+impl Instruction for MOV<Indirect<R64>, u8> {
+    fn encode(&self) -> InstructionBuilder {
+        // C6 /0 ib | MOV r/m8, imm8
+        InstructionBuilder::new()
+            .opcode(0xc6)
+            .reg_const(0)
+            .indirect(self.0)
+            .immediate(self.1)
+    }
+}
+
+impl Instruction for MOV<Index<R64, i32>, u32> {
+    fn encode(&self) -> InstructionBuilder {
+        // C7 /0 id | MOV r/m32, imm32
+        InstructionBuilder::new()
+            .opcode(0xc7)
+            .reg_const(0)
+            .indexed_displacement32(self.0)
+            .immediate(self.1)
+            .dword()
+    }
+}
+
+impl Instruction for MOV<Index<R64, i32>, R64> {
+    fn encode(&self) -> InstructionBuilder {
+        // REX.W + 89 /r | MOV r/m64,r64
+        InstructionBuilder::new()
+            .rex_w()
+            .opcode(0x89)
+            .indexed_displacement32(self.0)
+            .reg(self.1)
+    }
+}
+
+impl Instruction for MOV<Egpr, Egpr> {
+    fn encode(&self) -> InstructionBuilder {
+        // REX2.W + 8B /r | MOV r64,r/m64
+        InstructionBuilder::new()
+            .rex_w()
+            .opcode(0x8b)
+            .reg(self.0)
+            .mod_(0b11)
+            .rm_reg(self.1)
+    }
+}
+
+impl Instruction for MOV<Egpr, R64> {
+    fn encode(&self) -> InstructionBuilder {
+        // REX2.W + 8B /r | MOV r64,r/m64
+        InstructionBuilder::new()
+            .rex_w()
+            .opcode(0x8b)
+            .reg(self.0)
+            .rm_literal(self.1)
+    }
+}
+
+impl Instruction for MOV<R64, Egpr> {
+    fn encode(&self) -> InstructionBuilder {
+        // REX2.W + 8B /r | MOV r64,r/m64
+        InstructionBuilder::new()
+            .rex_w()
+            .opcode(0x8b)
+            .reg(self.0)
+            .mod_(0b11)
+            .rm_reg(self.1)
+    }
+}
+
+impl Instruction for MOV<Egpr, Index<R64, i8>> {
+    fn encode(&self) -> InstructionBuilder {
+        // REX2.W + 8B /r | MOV r64,r/m64
+        InstructionBuilder::new()
+            .rex_w()
+            .opcode(0x8b)
+            .reg(self.0)
+            .indexed_displacement(self.1)
+    }
+}
+
+impl Instruction for MOV<Index<R64, i8>, Egpr> {
+    fn encode(&self) -> InstructionBuilder {
+        // REX2.W + 89 /r | MOV r/m64,r64
+        InstructionBuilder::new()
+            .rex_w()
+            .opcode(0x89)
+            .reg(self.1)
+            .indexed_displacement(self.0)
+    }
+}
+
+impl Instruction for MOV<Index<R64, i8>, R64> {
+    fn encode(&self) -> InstructionBuilder {
+        // REX.W + 89 /r | MOV r/m64,r64
+        InstructionBuilder::new()
+            .rex_w()
+            .opcode(0x89)
+            .reg(self.1)
+            .indexed_displacement(self.0)
+    }
+}
+
+impl Instruction for MOV<Index<R64, i8>, R16> {
+    fn encode(&self) -> InstructionBuilder {
+        // 89 /r | MOV r/m16,r16
+        InstructionBuilder::new()
+            .operand_size_override()
+            .opcode(0x89)
+            .reg(self.1)
+            .indexed_displacement(self.0)
+    }
+}
+
+impl Instruction for MOV<Index<R64, i8>, R32> {
+    fn encode(&self) -> InstructionBuilder {
+        // 89 /r | MOV r/m32,r32
+        InstructionBuilder::new()
+            .opcode(0x89)
+            .reg(self.1)
+            .indexed_displacement(self.0)
+    }
+}
+
+impl Instruction for MOV<Index<R64, R64>, R8> {
+    fn encode(&self) -> InstructionBuilder {
+        // 88 /r | MOV r/m8,r8
+        InstructionBuilder::new()
+            .opcode(0x88)
+            .reg(self.1)
+            .indexed_indirect(self.0)
+    }
+}
+
+/// Exchange the operands. With a memory operand, the exchange is atomic
+/// even without a `LOCK` prefix.
+pub struct XCHG<A, B>(pub A, pub B);
+
+impl Instruction for XCHG<Ptr, R8> {
+    fn encode(&self) -> InstructionBuilder {
+        // 86 /r | XCHG r/m8, r8
+        InstructionBuilder::new()
+            .opcode(0x86)
+            .reg(self.1)
+            .rip_relative(self.0)
+    }
+}
+
+/// Compare `AL`, `AX`, `EAX` or `RAX` with the destination. If they are
+/// equal, store the source in the destination, and otherwise load the
+/// destination into the accumulator. `ZF` is set if they were equal.
+pub struct CMPXCHG<Dst, Src>(pub Dst, pub Src);
+
+impl Instruction for CMPXCHG<Ptr, R8> {
+    fn encode(&self) -> InstructionBuilder {
+        // 0F B0 /r | CMPXCHG r/m8, r8
+        InstructionBuilder::new()
+            .opcode([0x0f, 0xb0])
+            .reg(self.1)
+            .rip_relative(self.0)
+    }
+}
+
+/// Perform a read-modify-write of a memory operand atomically.
+pub struct LOCK<I>(pub I);
+
+impl Instruction for LOCK<XCHG<Ptr, R8>> {
+    fn encode(&self) -> InstructionBuilder {
+        // F0 86 /r | LOCK XCHG r/m8, r8
+        self.0.encode().prefix(0xf0)
+    }
+}
+
+impl Instruction for LOCK<CMPXCHG<Ptr, R8>> {
+    fn encode(&self) -> InstructionBuilder {
+        // F0 0F B0 /r | LOCK CMPXCHG r/m8, r8
+        self.0.encode().prefix(0xf0)
+    }
+}
+
+pub struct MOVZX<Dst, Src>(pub Dst, pub Src);
+
+impl Instruction for MOVZX<R64, Index<R64, R64>> {
+    fn encode(&self) -> InstructionBuilder {
+        // REX.W + 0F B6 /r | MOVZX r64, r/m8
+        InstructionBuilder::new()
+            .rex_w()
+            .opcode([0x0f, 0xb6])
+            .reg(self.0)
+            .indexed_indirect(self.1)
+    }
+}
+
+impl Instruction for MOVZX<R64, Index<R64, i8>> {
+    fn encode(&self) -> InstructionBuilder {
+        // REX.W + 0F B6 /r | MOVZX r64, r/m8
+        InstructionBuilder::new()
+            .rex_w()
+            .opcode([0x0f, 0xb6])
+            .reg(self.0)
+            .indexed_displacement(self.1)
+    }
+}
+
+pub struct LEA<Dst, Src>(pub Dst, pub Src);
+
+impl Instruction for LEA<R64, Ptr> {
+    fn encode(&self) -> InstructionBuilder {
+        // REX.W + 8D /r | LEA r64, m
+        InstructionBuilder::new()
+            .rex_w()
+            .opcode(0x8d)
+            .reg(self.0)
+            .rip_relative(self.1)
+    }
+}
+
+impl Instruction for LEA<R64, Index<R64, i8>> {
+    fn encode(&self) -> InstructionBuilder {
+        // REX.W + 8D /r | LEA r64, m
+        InstructionBuilder::new()
+            .rex_w()
+            .opcode(0x8d)
+            .reg(self.0)
+            .indexed_displacement(self.1)
+    }
+}
+
+pub struct ADD<Dst, Src>(pub Dst, pub Src);
+
+impl Instruction for ADD<R64, i8> {
+    fn encode(&self) -> InstructionBuilder {
+        // REX.W + 83 /0 ib | ADD r/m64, imm8
+        InstructionBuilder::new()
+            .rex_w()
+            .opcode(0x83)
+            .reg_const(0)
+            .rm_literal(self.0)
+            .immediate(self.1)
+    }
+}
+
+impl Instruction for ADD<R64, i32> {
+    fn encode(&self) -> InstructionBuilder {
+        // REX.W + 81 /0 id | ADD r/m64, imm32
+        InstructionBuilder::new()
+            .rex_w()
+            .opcode(0x81)
+            .reg_const(0)
+            .rm_literal(self.0)
+            .immediate(self.1)
+    }
+}
+
+impl Instruction for ADD<R64, R64> {
+    fn encode(&self) -> InstructionBuilder {
+        // REX.W + 01 /r | ADD r/m64, r64
+        InstructionBuilder::new()
+            .rex_w()
+            .opcode(0x01)
+            .rm_literal(self.0)
+            .reg(self.1)
+    }
+}
+
+pub struct SUB<Dst, Src>(pub Dst, pub Src);
+
+impl Instruction for SUB<R64, i8> {
+    fn encode(&self) -> InstructionBuilder {
+        // REX.W + 83 /5 ib | SUB r/m64, imm8
+        InstructionBuilder::new()
+            .rex_w()
+            .opcode(0x83)
+            .reg_const(5)
+            .rm_literal(self.0)
+            .immediate(self.1)
+    }
+}
+
+impl Instruction for SUB<R64, i32> {
+    fn encode(&self) -> InstructionBuilder {
+        // REX.W + 81 /5 id | SUB r/m64, imm32
+        InstructionBuilder::new()
+            .rex_w()
+            .opcode(0x81)
+            .reg_const(5)
+            .rm_literal(self.0)
+            .immediate(self.1)
+    }
+}
+
+impl Instruction for SUB<R64, R64> {
+    fn encode(&self) -> InstructionBuilder {
+        // REX.W + 29 /r | SUB r/m64, r64
+        InstructionBuilder::new()
+            .rex_w()
+            .opcode(0x29)
+            .rm_literal(self.0)
+            .reg(self.1)
+    }
+}
+
+pub struct CMP<A, B>(pub A, pub B);
+
+impl Instruction for CMP<Index<R64, R64>, u8> {
+    fn encode(&self) -> InstructionBuilder {
+        // 80 /7 ib | CMP r/m8, imm8
+        InstructionBuilder::new()
+            .opcode(0x80)
+            .reg_const(7)
+            .indexed_indirect(self.0)
+            .immediate(self.1)
+    }
+}
+
+impl Instruction for CMP<R64, R64> {
+    fn encode(&self) -> InstructionBuilder {
+        // REX.W + 39 /r | CMP r/m64, r64
+        InstructionBuilder::new()
+            .rex_w()
+            .opcode(0x39)
+            .rm_literal(self.0)
+            .reg(self.1)
+    }
+}
+
+impl Instruction for CMP<R64, i8> {
+    fn encode(&self) -> InstructionBuilder {
+        // REX.W + 83 /7 ib | CMP r/m64, imm8
+        InstructionBuilder::new()
+            .rex_w()
+            .opcode(0x83)
+            .reg_const(7)
+            .rm_literal(self.0)
+            .immediate(self.1)
+    }
+}
+
+impl Instruction for CMP<R64, i32> {
+    fn encode(&self) -> InstructionBuilder {
+        // REX.W + 81 /7 id | CMP r/m64, imm32
+        InstructionBuilder::new()
+            .rex_w()
+            .opcode(0x81)
+            .reg_const(7)
+            .rm_literal(self.0)
+            .immediate(self.1)
+    }
+}
+
+pub struct TEST<A, B>(pub A, pub B);
+
+impl Instruction for TEST<R64, R64> {
+    fn encode(&self) -> InstructionBuilder {
+        // REX.W + 85 /r | TEST r/m64, r64
+        InstructionBuilder::new()
+            .rex_w()
+            .opcode(0x85)
+            .rm_literal(self.0)
+            .reg(self.1)
+    }
+}
+
+impl Instruction for TEST<R8, R8> {
+    fn encode(&self) -> InstructionBuilder {
+        // 84 /r | TEST r/m8, r8
+        InstructionBuilder::new()
+            .opcode(0x84)
+            .mod_(0b11)
+            .rm_reg(self.0)
+            .reg(self.1)
+    }
+}
+
+impl Instruction for TEST<R64, i32> {
+    fn encode(&self) -> InstructionBuilder {
+        // REX.W + F7 /0 id | TEST r/m64, imm32
+        InstructionBuilder::new()
+            .rex_w()
+            .opcode(0xf7)
+            .reg_const(0)
+            .rm_literal(self.0)
+            .immediate(self.1)
+    }
+}
+
+impl Instruction for TEST<Index<R64, i8>, u8> {
+    fn encode(&self) -> InstructionBuilder {
+        // F6 /0 ib | TEST r/m8, imm8
+        InstructionBuilder::new()
+            .opcode(0xf6)
+            .reg_const(0)
+            .indexed_displacement(self.0)
+            .immediate(self.1)
+    }
+}
+
+pub struct OR<Dst, Src>(pub Dst, pub Src);
+
+impl Instruction for OR<Index<R64, i8>, i16> {
+    fn encode(&self) -> InstructionBuilder {
+        // 81 /1 iw | OR r/m16, imm16
         InstructionBuilder::new()
             .operand_size_override()
             .opcode(0x81)
@@ -615,37 +1741,108 @@ impl<'a> Instruction<'a> for OR<Index<R64, i8>, i16> {
     }
 }
 
-pub struct AND<Dst, Src>(pub Dst, pub Src);
+impl Instruction for OR<Index<R64, i8>, u8> {
+    fn encode(&self) -> InstructionBuilder {
+        // 80 /1 ib | OR r/m8, imm8
+        InstructionBuilder::new()
+            .opcode(0x80)
+            .reg_const(1)
+            .indexed_displacement(self.0)
+            .immediate(self.1)
+    }
+}
+
+impl Instruction for OR<R64, i32> {
+    fn encode(&self) -> InstructionBuilder {
+        // REX.W + 81 /1 id | OR r/m64, imm32
+        InstructionBuilder::new()
+            .rex_w()
+            .opcode(0x81)
+            .reg_const(1)
+            .rm_literal(self.0)
+            .immediate(self.1)
+    }
+}
+
+impl Instruction for OR<R64, R64> {
+    fn encode(&self) -> InstructionBuilder {
+        // REX.W + 09 /r | OR r/m64, r64
+        InstructionBuilder::new()
+            .rex_w()
+            .opcode(0x09)
+            .rm_literal(self.0)
+            .reg(self.1)
+    }
+}
+
+pub struct AND<Dst, Src>(pub Dst, pub Src);
+
+impl Instruction for AND<R64, i8> {
+    fn encode(&self) -> InstructionBuilder {
+        // REX.W + 83 /4 ib | AND r/m64, imm8
+        InstructionBuilder::new()
+            .rex_w()
+            .opcode(0x83)
+            .reg_const(4)
+            .rm_literal(self.0)
+            .immediate(self.1)
+    }
+}
+
+impl Instruction for AND<R64, R64> {
+    fn encode(&self) -> InstructionBuilder {
+        // REX.W + 21 /r | AND r/m64, r64
+        InstructionBuilder::new()
+            .rex_w()
+            .opcode(0x21)
+            .rm_literal(self.0)
+            .reg(self.1)
+    }
+}
+
+pub struct XOR<Dst, Src>(pub Dst, pub Src);
 
-impl<'a> Instruction<'a> for AND<R64, i8> {
-    fn encode(&self) -> InstructionBuilder<'a> {
-        // REX.W + 83 /4 ib | AND r/m64, imm8
+impl Instruction for XOR<R64, R64> {
+    fn encode(&self) -> InstructionBuilder {
+        // REX.W + 31 /r | XOR r/m64, r64
         InstructionBuilder::new()
             .rex_w()
-            .opcode(0x83)
-            .reg_const(4)
+            .opcode(0x31)
+            .rm_literal(self.0)
+            .reg(self.1)
+    }
+}
+
+impl Instruction for XOR<R64, i32> {
+    fn encode(&self) -> InstructionBuilder {
+        // REX.W + 81 /6 id | XOR r/m64, imm32
+        InstructionBuilder::new()
+            .rex_w()
+            .opcode(0x81)
+            .reg_const(6)
             .rm_literal(self.0)
             .immediate(self.1)
     }
 }
 
-pub struct XOR<Dst, Src>(pub Dst, pub Src);
+pub struct SHL<Dst, Amt>(pub Dst, pub Amt);
 
-impl<'a> Instruction<'a> for XOR<R64, R64> {
-    fn encode(&self) -> InstructionBuilder<'a> {
-        // REX.W + 33 /r | XOR r64, r/m64
+impl Instruction for SHL<R64, i8> {
+    fn encode(&self) -> InstructionBuilder {
+        // REX.W + C1 /4 ib | SHL r/m64, imm8
         InstructionBuilder::new()
             .rex_w()
-            .opcode(0x33)
-            .reg(self.0)
-            .rm_literal(self.1)
+            .opcode(0xc1)
+            .reg_const(4)
+            .rm_literal(self.0)
+            .immediate(self.1)
     }
 }
 
 pub struct SHR<Dst, Amt>(pub Dst, pub Amt);
 
-impl<'a> Instruction<'a> for SHR<R64, i8> {
-    fn encode(&self) -> InstructionBuilder<'a> {
+impl Instruction for SHR<R64, i8> {
+    fn encode(&self) -> InstructionBuilder {
         // REX.W + C1 /5 ib | SHR r/m64, imm8
         InstructionBuilder::new()
             .rex_w()
@@ -656,8 +1853,8 @@ impl<'a> Instruction<'a> for SHR<R64, i8> {
     }
 }
 
-impl<'a> Instruction<'a> for SHR<R64, R8> {
-    fn encode(&self) -> InstructionBuilder<'a> {
+impl Instruction for SHR<R64, R8> {
+    fn encode(&self) -> InstructionBuilder {
         // REX.W + D3 /5 | SHR r/m64, CL
         assert!(self.1 == R8::CL, "shift amount must be in CL register");
         InstructionBuilder::new()
@@ -670,8 +1867,8 @@ impl<'a> Instruction<'a> for SHR<R64, R8> {
 
 pub struct INC<Dst>(pub Dst);
 
-impl<'a> Instruction<'a> for INC<R64> {
-    fn encode(&self) -> InstructionBuilder<'a> {
+impl Instruction for INC<R64> {
+    fn encode(&self) -> InstructionBuilder {
         // REX.W + FF /0 | INC r/m64
         InstructionBuilder::new()
             .rex_w()
@@ -680,3 +1877,1108 @@ impl<'a> Instruction<'a> for INC<R64> {
             .rm_literal(self.0)
     }
 }
+
+pub struct DEC<Dst>(pub Dst);
+
+impl Instruction for DEC<R64> {
+    fn encode(&self) -> InstructionBuilder {
+        // REX.W + FF /1 | DEC r/m64
+        InstructionBuilder::new()
+            .rex_w()
+            .opcode(0xff)
+            .reg_const(1)
+            .rm_literal(self.0)
+    }
+}
+
+pub struct NEG<Dst>(pub Dst);
+
+impl Instruction for NEG<R64> {
+    fn encode(&self) -> InstructionBuilder {
+        // REX.W + F7 /3 | NEG r/m64
+        InstructionBuilder::new()
+            .rex_w()
+            .opcode(0xf7)
+            .reg_const(3)
+            .rm_literal(self.0)
+    }
+}
+
+/// Unsigned divide RDX:RAX, with the quotient in RAX and the remainder in
+/// RDX.
+pub struct DIV<Src>(pub Src);
+
+impl Instruction for DIV<R64> {
+    fn encode(&self) -> InstructionBuilder {
+        // REX.W + F7 /6 | DIV r/m64
+        InstructionBuilder::new()
+            .rex_w()
+            .opcode(0xf7)
+            .reg_const(6)
+            .rm_literal(self.0)
+    }
+}
+
+pub struct IN<Dst, Port>(pub Dst, pub Port);
+
+impl Instruction for IN<R8, R16> {
+    fn encode(&self) -> InstructionBuilder {
+        // EC | IN AL, DX
+        assert!(self.0 == R8::AL, "destination must be AL");
+        assert!(self.1 == R16::DX, "port must be in DX");
+        InstructionBuilder::new().opcode(0xec)
+    }
+}
+
+pub struct OUT<Port, Src>(pub Port, pub Src);
+
+impl Instruction for OUT<R16, R8> {
+    fn encode(&self) -> InstructionBuilder {
+        // EE | OUT DX, AL
+        assert!(self.0 == R16::DX, "port must be in DX");
+        assert!(self.1 == R8::AL, "source must be AL");
+        InstructionBuilder::new().opcode(0xee)
+    }
+}
+
+impl Instruction for OUT<u8, R8> {
+    fn encode(&self) -> InstructionBuilder {
+        // E6 ib | OUT imm8, AL
+        assert!(self.1 == R8::AL, "source must be AL");
+        InstructionBuilder::new().opcode(0xe6).immediate(self.0)
+    }
+}
+
+pub struct IMUL<Dst, Src>(pub Dst, pub Src);
+
+impl Instruction for IMUL<R64, R64> {
+    fn encode(&self) -> InstructionBuilder {
+        // REX.W + 0F AF /r | IMUL r64, r/m64
+        InstructionBuilder::new()
+            .rex_w()
+            .opcode([0x0f, 0xaf])
+            .reg(self.0)
+            .rm_literal(self.1)
+    }
+}
+
+pub struct BSF<Dst, Src>(pub Dst, pub Src);
+
+impl Instruction for BSF<R64, R64> {
+    fn encode(&self) -> InstructionBuilder {
+        // REX.W + 0F BC /r | BSF r64, r/m64
+        InstructionBuilder::new()
+            .rex_w()
+            .opcode([0x0f, 0xbc])
+            .reg(self.0)
+            .rm_literal(self.1)
+    }
+}
+
+/// Copy a byte from `[RSI]` to `[RDI]`, then step both.
+pub struct MOVSB;
+
+impl Instruction for MOVSB {
+    fn encode(&self) -> InstructionBuilder {
+        // A4 | MOVSB
+        InstructionBuilder::new().opcode(0xa4)
+    }
+}
+
+/// Store `AL` to `[RDI]`, then step it.
+pub struct STOSB;
+
+impl Instruction for STOSB {
+    fn encode(&self) -> InstructionBuilder {
+        // AA | STOSB
+        InstructionBuilder::new().opcode(0xaa)
+    }
+}
+
+/// Store `AX` to `[RDI]`, then step it.
+pub struct STOSW;
+
+impl Instruction for STOSW {
+    fn encode(&self) -> InstructionBuilder {
+        // AB | STOSW
+        InstructionBuilder::new()
+            .operand_size_override()
+            .opcode(0xab)
+    }
+}
+
+/// Compare the byte at `[RSI]` with `[RDI]`, then step both.
+pub struct CMPSB;
+
+impl Instruction for CMPSB {
+    fn encode(&self) -> InstructionBuilder {
+        // A6 | CMPSB
+        InstructionBuilder::new().opcode(0xa6)
+    }
+}
+
+/// Compare `AL` with the byte at `[RDI]`, then step it.
+pub struct SCASB;
+
+impl Instruction for SCASB {
+    fn encode(&self) -> InstructionBuilder {
+        // AE | SCASB
+        InstructionBuilder::new().opcode(0xae)
+    }
+}
+
+/// Repeat a string instruction `RCX` times.
+pub struct REP<I>(pub I);
+
+impl Instruction for REP<MOVSB> {
+    fn encode(&self) -> InstructionBuilder {
+        // F3 A4 | REP MOVSB
+        self.0.encode().prefix(0xf3)
+    }
+}
+
+impl Instruction for REP<STOSB> {
+    fn encode(&self) -> InstructionBuilder {
+        // F3 AA | REP STOSB
+        self.0.encode().prefix(0xf3)
+    }
+}
+
+impl Instruction for REP<STOSW> {
+    fn encode(&self) -> InstructionBuilder {
+        // F3 AB | REP STOSW
+        self.0.encode().prefix(0xf3)
+    }
+}
+
+/// Repeat a string comparison `RCX` times, or until the operands differ.
+pub struct REPE<I>(pub I);
+
+impl Instruction for REPE<CMPSB> {
+    fn encode(&self) -> InstructionBuilder {
+        // F3 A6 | REPE CMPSB
+        self.0.encode().prefix(0xf3)
+    }
+}
+
+/// Repeat a string comparison `RCX` times, or until the operands are equal.
+pub struct REPNE<I>(pub I);
+
+impl Instruction for REPNE<SCASB> {
+    fn encode(&self) -> InstructionBuilder {
+        // F2 AE | REPNE SCASB
+        self.0.encode().prefix(0xf2)
+    }
+}
+
+pub struct MOVDQU<Dst, Src>(pub Dst, pub Src);
+
+impl Instruction for MOVDQU<Xmm, Index<R64, R64>> {
+    fn encode(&self) -> InstructionBuilder {
+        // F3 0F 6F /r | MOVDQU xmm1, xmm2/m128
+        InstructionBuilder::new()
+            .prefix(0xf3)
+            .opcode([0x0f, 0x6f])
+            .reg(self.0)
+            .indexed_indirect(self.1)
+    }
+}
+
+impl Instruction for MOVDQU<Index<R64, R64>, Xmm> {
+    fn encode(&self) -> InstructionBuilder {
+        // F3 0F 7F /r | MOVDQU xmm2/m128, xmm1
+        InstructionBuilder::new()
+            .prefix(0xf3)
+            .opcode([0x0f, 0x7f])
+            .reg(self.1)
+            .indexed_indirect(self.0)
+    }
+}
+
+pub struct MOVDQA<Dst, Src>(pub Dst, pub Src);
+
+impl Instruction for MOVDQA<Xmm, Indirect<R64>> {
+    fn encode(&self) -> InstructionBuilder {
+        // 66 0F 6F /r | MOVDQA xmm1, xmm2/m128
+        InstructionBuilder::new()
+            .operand_size_override()
+            .opcode([0x0f, 0x6f])
+            .reg(self.0)
+            .indirect(self.1)
+    }
+}
+
+pub struct MOVQ<Dst, Src>(pub Dst, pub Src);
+
+impl Instruction for MOVQ<Xmm, R64> {
+    fn encode(&self) -> InstructionBuilder {
+        // 66 REX.W 0F 6E /r | MOVQ xmm, r/m64
+        InstructionBuilder::new()
+            .operand_size_override()
+            .rex_w()
+            .opcode([0x0f, 0x6e])
+            .reg(self.0)
+            .rm_literal(self.1)
+    }
+}
+
+pub struct PUNPCKLQDQ<Dst, Src>(pub Dst, pub Src);
+
+impl Instruction for PUNPCKLQDQ<Xmm, Xmm> {
+    fn encode(&self) -> InstructionBuilder {
+        // 66 0F 6C /r | PUNPCKLQDQ xmm1, xmm2/m128
+        InstructionBuilder::new()
+            .operand_size_override()
+            .opcode([0x0f, 0x6c])
+            .reg(self.0)
+            .rm_xmm(self.1)
+    }
+}
+
+pub struct PXOR<Dst, Src>(pub Dst, pub Src);
+
+impl Instruction for PXOR<Xmm, Xmm> {
+    fn encode(&self) -> InstructionBuilder {
+        // 66 0F EF /r | PXOR xmm1, xmm2/m128
+        InstructionBuilder::new()
+            .operand_size_override()
+            .opcode([0x0f, 0xef])
+            .reg(self.0)
+            .rm_xmm(self.1)
+    }
+}
+
+pub struct PCMPEQB<Dst, Src>(pub Dst, pub Src);
+
+impl Instruction for PCMPEQB<Xmm, Xmm> {
+    fn encode(&self) -> InstructionBuilder {
+        // 66 0F 74 /r | PCMPEQB xmm1, xmm2/m128
+        InstructionBuilder::new()
+            .operand_size_override()
+            .opcode([0x0f, 0x74])
+            .reg(self.0)
+            .rm_xmm(self.1)
+    }
+}
+
+pub struct PMOVMSKB<Dst, Src>(pub Dst, pub Src);
+
+impl Instruction for PMOVMSKB<R32, Xmm> {
+    fn encode(&self) -> InstructionBuilder {
+        // 66 0F D7 /r | PMOVMSKB reg, xmm
+        InstructionBuilder::new()
+            .operand_size_override()
+            .opcode([0x0f, 0xd7])
+            .reg(self.0)
+            .rm_xmm(self.1)
+    }
+}
+
+/// Golden tests of the encodings against an external assembler, `llvm-mc`,
+/// or the program named by `LLVM_MC`. The tests are skipped if it is not
+/// installed.
+///
+/// Each case pairs an instruction with its Intel syntax, in the dialect of
+/// `llvm-mc`. Where there is more than one encoding, the syntax selects the
+/// one that the encoder uses: `.s` for the alternate direction of
+/// register-to-register `MOV`, and `{disp32}` for 32-bit displacements and
+/// branch offsets. Label references, like `target`, are assembled as
+/// fixups, and compared as zeros.
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use crate::x86::{
+        address::{ScaledIndex, Times8},
+        decode::decode,
+        register::{Sreg::*, Xmm::*, R16::*, R32::*, R64::*, R8 as Byte, R8::*},
+    };
+    use std::{
+        io::Write,
+        process::{Command, Stdio},
+    };
+
+    /// The encoding of `instruction`, checking that its computed length is
+    /// the length of the serialized bytes.
+    pub(in crate::x86) fn bytes(instruction: impl Instruction) -> Vec<u8> {
+        let encoded = instruction.encode();
+        let mut bytes = Vec::new();
+        encoded.serialize(&mut bytes);
+        assert_eq!(encoded.len(), bytes.len(), "length of {:02x?}", bytes);
+        bytes
+    }
+
+    /// Assemble the lines, returning the encoding of each, or `None` if the
+    /// assembler is not installed.
+    fn assemble(lines: &[&str]) -> Option<Vec<Vec<u8>>> {
+        let program = std::env::var("LLVM_MC").unwrap_or_else(|_| "llvm-mc".into());
+        let mut child = Command::new(program)
+            .args([
+                "--triple=x86_64",
+                "--x86-asm-syntax=intel",
+                "--show-encoding",
+            ])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .ok()?;
+        let mut stdin = child.stdin.take().unwrap();
+        for line in lines {
+            writeln!(stdin, "{}", line).unwrap();
+        }
+        drop(stdin);
+        let output = child.wait_with_output().unwrap();
+        assert!(
+            output.status.success(),
+            "assembler failed:\n{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+
+        let encodings: Vec<Vec<u8>> = String::from_utf8(output.stdout)
+            .unwrap()
+            .lines()
+            .filter_map(|line| line.split_once("encoding: [").map(|(_, rest)| rest))
+            .map(|rest| {
+                let (bytes, _) = rest.split_once(']').unwrap();
+                bytes
+                    .split(',')
+                    .map(|byte| match byte.strip_prefix("0x") {
+                        Some(hex) => u8::from_str_radix(hex, 16).unwrap(),
+                        // A byte of a fixup.
+                        None => 0,
+                    })
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            encodings.len(),
+            lines.len(),
+            "expected one encoding per line"
+        );
+        Some(encodings)
+    }
+
+    /// An encoding of every instruction form, with its syntax. Other tests
+    /// share these cases.
+    pub(in crate::x86) fn cases() -> Vec<(Vec<u8>, &'static str)> {
+        let target = || Label("target");
+        let ptr = || Ptr("target");
+        vec![
+            (bytes(HLT), "hlt"),
+            (bytes(JMP(target())), "{disp32} jmp target"),
+            (bytes(JMP(R12)), "jmp r12"),
+            (bytes(JZ(target())), "{disp32} jz target"),
+            (bytes(JNZ(target())), "{disp32} jnz target"),
+            (bytes(JCC(Condition::Below, target())), "{disp32} jb target"),
+            (
+                bytes(JCC(Condition::Greater, target())),
+                "{disp32} jg target",
+            ),
+            (bytes(CALL(target())), "call target"),
+            (bytes(CALL(R11)), "call r11"),
+            (bytes(RET), "ret"),
+            (bytes(IRET), "iretq"),
+            (bytes(SWAPGS), "swapgs"),
+            (bytes(SYSCALL), "syscall"),
+            (bytes(SYSRET), "sysretq"),
+            (bytes(LIDT(Indirect(RAX))), "lidt [rax]"),
+            (bytes(LIDT(Indirect(R12))), "lidt [r12]"),
+            (bytes(LIDT(ptr())), "lidt [rip + target]"),
+            (bytes(STI), "sti"),
+            (bytes(CLD), "cld"),
+            (bytes(RDMSR), "rdmsr"),
+            (bytes(WRMSR), "wrmsr"),
+            (bytes(CPUID), "cpuid"),
+            (bytes(PAUSE), "pause"),
+            (bytes(NOP), "nop"),
+            (bytes(INT3), "int3"),
+            (bytes(PUSH(RBX)), "push rbx"),
+            (bytes(PUSH(R15)), "push r15"),
+            (bytes(PUSH(-1i8)), "push -1"),
+            (bytes(POP(R12)), "pop r12"),
+            (
+                bytes(MOV(RAX, 0x1122_3344_5566_7788u64)),
+                "movabs rax, 0x1122334455667788",
+            ),
+            (bytes(MOV(R10, 1u64)), "movabs r10, 1"),
+            (bytes(MOV(AL, 0x12u8)), "mov al, 0x12"),
+            (bytes(MOV(R9B, 0xffu8)), "mov r9b, 0xff"),
+            (bytes(MOV(RAX, ptr())), "mov rax, qword ptr [rip + target]"),
+            (bytes(MOV(R13, ptr())), "mov r13, qword ptr [rip + target]"),
+            (bytes(MOV(ECX, ptr())), "mov ecx, dword ptr [rip + target]"),
+            (bytes(MOV(R9D, ptr())), "mov r9d, dword ptr [rip + target]"),
+            (bytes(MOV(DX, ptr())), "mov dx, word ptr [rip + target]"),
+            (bytes(MOV(BL, ptr())), "mov bl, byte ptr [rip + target]"),
+            (bytes(MOV(R10B, ptr())), "mov r10b, byte ptr [rip + target]"),
+            (bytes(MOV(ptr(), R14)), "mov qword ptr [rip + target], r14"),
+            (bytes(MOV(ptr(), ESI)), "mov dword ptr [rip + target], esi"),
+            (bytes(MOV(ptr(), R11W)), "mov word ptr [rip + target], r11w"),
+            (bytes(MOV(ptr(), CL)), "mov byte ptr [rip + target], cl"),
+            (
+                bytes(MOV(RSP, GsIndex(0x10))),
+                "mov rsp, qword ptr gs:[0x10]",
+            ),
+            (
+                bytes(MOV(R15, GsIndex(0x10))),
+                "mov r15, qword ptr gs:[0x10]",
+            ),
+            (
+                bytes(MOV(R8D, GsIndex(0x20))),
+                "mov r8d, dword ptr gs:[0x20]",
+            ),
+            (bytes(MOV(BX, GsIndex(8))), "mov bx, word ptr gs:[8]"),
+            (bytes(MOV(CL, GsIndex(0))), "mov cl, byte ptr gs:[0]"),
+            (
+                bytes(MOV(GsIndex(0x7fff_ffff), R15)),
+                "mov qword ptr gs:[0x7fffffff], r15",
+            ),
+            (bytes(MOV(GsIndex(4), EDI)), "mov dword ptr gs:[4], edi"),
+            (bytes(MOV(GsIndex(2), R9W)), "mov word ptr gs:[2], r9w"),
+            (bytes(MOV(GsIndex(1), DL)), "mov byte ptr gs:[1], dl"),
+            (bytes(MOV(RAX, RBX)), "mov.s rax, rbx"),
+            (bytes(MOV(R9, RSP)), "mov.s r9, rsp"),
+            (bytes(MOV(RDX, R14)), "mov.s rdx, r14"),
+            (bytes(MOV(RAX, Indirect(RBX))), "mov rax, qword ptr [rbx]"),
+            (bytes(MOV(RAX, Indirect(RSP))), "mov rax, qword ptr [rsp]"),
+            (bytes(MOV(RAX, Indirect(RBP))), "mov rax, qword ptr [rbp]"),
+            (bytes(MOV(R11, Indirect(R12))), "mov r11, qword ptr [r12]"),
+            (bytes(MOV(R11, Indirect(R13))), "mov r11, qword ptr [r13]"),
+            (bytes(MOV(EAX, ECX)), "mov.s eax, ecx"),
+            (bytes(MOV(R8D, R15D)), "mov.s r8d, r15d"),
+            (
+                bytes(MOV(EAX, Index(RBX, 4i8))),
+                "mov eax, dword ptr [rbx + 4]",
+            ),
+            (
+                bytes(MOV(R15D, Index(R12, -8i8))),
+                "mov r15d, dword ptr [r12 - 8]",
+            ),
+            (bytes(MOV(RAX, Index(RBP, 0i8))), "mov rax, qword ptr [rbp]"),
+            (
+                bytes(MOV(RCX, Index(RSP, 8i8))),
+                "mov rcx, qword ptr [rsp + 8]",
+            ),
+            (
+                bytes(MOV(R14, Index(R13, -128i8))),
+                "mov r14, qword ptr [r13 - 128]",
+            ),
+            (
+                bytes(MOV(RAX, Index(RBX, 8i32))),
+                "{disp32} mov rax, qword ptr [rbx + 8]",
+            ),
+            (
+                bytes(MOV(R10, Index(RSP, 0x100i32))),
+                "mov r10, qword ptr [rsp + 0x100]",
+            ),
+            (
+                bytes(MOV(RDX, Index(R12, -0x1000i32))),
+                "mov rdx, qword ptr [r12 - 0x1000]",
+            ),
+            (
+                bytes(MOV(RAX, Index(RCX, RDX))),
+                "mov rax, qword ptr [rdx + rcx]",
+            ),
+            (
+                bytes(MOV(R9, Index(R14, RBP))),
+                "mov r9, qword ptr [rbp + r14]",
+            ),
+            (
+                bytes(MOV(RAX, Index(R14, R13))),
+                "mov rax, qword ptr [r13 + r14]",
+            ),
+            (
+                bytes(MOV(RAX, ScaledIndex(Times8, R12, RBX))),
+                "mov rax, qword ptr [rbx + 8*r12]",
+            ),
+            (
+                bytes(MOV(CL, Index(RSI, RDI))),
+                "mov cl, byte ptr [rdi + rsi]",
+            ),
+            (
+                bytes(MOV(R10B, Index(R11, R12))),
+                "mov r10b, byte ptr [r12 + r11]",
+            ),
+            (bytes(MOV(Indirect(RDI), RAX)), "mov qword ptr [rdi], rax"),
+            (bytes(MOV(Indirect(R12), R9)), "mov qword ptr [r12], r9"),
+            (bytes(MOV(Indirect(RDI), DL)), "mov byte ptr [rdi], dl"),
+            (bytes(MOV(Indirect(R13), R8B)), "mov byte ptr [r13], r8b"),
+            (
+                bytes(MOV(Indirect(RBX), 0x12u8)),
+                "mov byte ptr [rbx], 0x12",
+            ),
+            (
+                bytes(MOV(Indirect(R12), 0x80u8)),
+                "mov byte ptr [r12], 0x80",
+            ),
+            (
+                bytes(MOV(Index(RBX, 0x10i32), 0xdead_beefu32)),
+                "{disp32} mov dword ptr [rbx + 0x10], 0xdeadbeef",
+            ),
+            (
+                bytes(MOV(Index(R12, 0x1000i32), RAX)),
+                "mov qword ptr [r12 + 0x1000], rax",
+            ),
+            (
+                bytes(MOV(Index(RBP, 8i8), R11)),
+                "mov qword ptr [rbp + 8], r11",
+            ),
+            (
+                bytes(MOV(Index(RSP, -2i8), CX)),
+                "mov word ptr [rsp - 2], cx",
+            ),
+            (
+                bytes(MOV(Index(R13, 4i8), EDX)),
+                "mov dword ptr [r13 + 4], edx",
+            ),
+            (
+                bytes(MOV(Index(RAX, R9), BL)),
+                "mov byte ptr [r9 + rax], bl",
+            ),
+            (bytes(XCHG(ptr(), CL)), "xchg byte ptr [rip + target], cl"),
+            (
+                bytes(LOCK(XCHG(ptr(), R11B))),
+                "lock xchg byte ptr [rip + target], r11b",
+            ),
+            (
+                bytes(CMPXCHG(ptr(), DL)),
+                "cmpxchg byte ptr [rip + target], dl",
+            ),
+            (
+                bytes(LOCK(CMPXCHG(ptr(), CL))),
+                "lock cmpxchg byte ptr [rip + target], cl",
+            ),
+            (
+                bytes(MOVZX(RAX, Index(RCX, RDX))),
+                "movzx rax, byte ptr [rdx + rcx]",
+            ),
+            (
+                bytes(MOVZX(R10, Index(RBX, 15i8))),
+                "movzx r10, byte ptr [rbx + 15]",
+            ),
+            (bytes(LEA(RAX, ptr())), "lea rax, [rip + target]"),
+            (bytes(LEA(RDI, Index(R13, 36i8))), "lea rdi, [r13 + 36]"),
+            (bytes(ADD(RSP, 8i8)), "add rsp, 8"),
+            (bytes(ADD(R12, 0x1000i32)), "add r12, 0x1000"),
+            (bytes(ADD(RAX, R12)), "add rax, r12"),
+            (bytes(SUB(RSP, 8i8)), "sub rsp, 8"),
+            (bytes(SUB(R11, 0x100i32)), "sub r11, 0x100"),
+            (bytes(SUB(R11, RDX)), "sub r11, rdx"),
+            (
+                bytes(CMP(Index(RCX, RDX), 0u8)),
+                "cmp byte ptr [rdx + rcx], 0",
+            ),
+            (bytes(CMP(RDI, RSI)), "cmp rdi, rsi"),
+            (bytes(CMP(R15, RDX)), "cmp r15, rdx"),
+            (bytes(CMP(RAX, 2i8)), "cmp rax, 2"),
+            (bytes(CMP(RCX, 0x1000i32)), "cmp rcx, 0x1000"),
+            (bytes(TEST(RAX, RAX)), "test rax, rax"),
+            (bytes(TEST(R9, RCX)), "test r9, rcx"),
+            (bytes(TEST(AL, AL)), "test al, al"),
+            (bytes(TEST(R8B, CL)), "test r8b, cl"),
+            (bytes(TEST(RCX, 0x100i32)), "test rcx, 0x100"),
+            (
+                bytes(TEST(Index(RBX, 4i8), 0x80u8)),
+                "test byte ptr [rbx + 4], 0x80",
+            ),
+            (
+                bytes(OR(Index(RBX, 4i8), 0x1234i16)),
+                "or word ptr [rbx + 4], 0x1234",
+            ),
+            (
+                bytes(OR(Index(RDI, 1i8), 0x80u8)),
+                "or byte ptr [rdi + 1], 0x80",
+            ),
+            (bytes(OR(RCX, 0x100i32)), "or rcx, 0x100"),
+            (bytes(OR(RAX, R15)), "or rax, r15"),
+            (bytes(AND(RSP, -16i8)), "and rsp, -16"),
+            (bytes(AND(RCX, RDX)), "and rcx, rdx"),
+            (bytes(XOR(RAX, RAX)), "xor rax, rax"),
+            (bytes(XOR(R9, R10)), "xor r9, r10"),
+            (bytes(XOR(RCX, 0x100i32)), "xor rcx, 0x100"),
+            (bytes(SHL(RAX, 4i8)), "shl rax, 4"),
+            (bytes(SHR(RCX, 32i8)), "shr rcx, 32"),
+            (bytes(SHR(R13, CL)), "shr r13, cl"),
+            (bytes(INC(RCX)), "inc rcx"),
+            (bytes(DEC(R15)), "dec r15"),
+            (bytes(NEG(RAX)), "neg rax"),
+            (bytes(DIV(R9)), "div r9"),
+            (bytes(IN(AL, DX)), "in al, dx"),
+            (bytes(OUT(DX, AL)), "out dx, al"),
+            (bytes(OUT(0x43u8, AL)), "out 0x43, al"),
+            (bytes(IMUL(RAX, R12)), "imul rax, r12"),
+            (bytes(BSF(RCX, RDX)), "bsf rcx, rdx"),
+            (bytes(MOVSB), "movsb"),
+            (bytes(STOSB), "stosb"),
+            (bytes(STOSW), "stosw"),
+            (bytes(CMPSB), "cmpsb"),
+            (bytes(SCASB), "scasb"),
+            (bytes(REP(MOVSB)), "rep movsb"),
+            (bytes(REP(STOSB)), "rep stosb"),
+            (bytes(REP(STOSW)), "rep stosw"),
+            (bytes(REPE(CMPSB)), "repe cmpsb"),
+            (bytes(REPNE(SCASB)), "repne scasb"),
+            (
+                bytes(MOVDQU(XMM0, Index(RCX, RDX))),
+                "movdqu xmm0, xmmword ptr [rdx + rcx]",
+            ),
+            (
+                bytes(MOVDQU(XMM9, Index(R12, R13))),
+                "movdqu xmm9, xmmword ptr [r13 + r12]",
+            ),
+            (
+                bytes(MOVDQU(Index(RAX, RDI), XMM15)),
+                "movdqu xmmword ptr [rdi + rax], xmm15",
+            ),
+            (
+                bytes(MOVDQA(XMM1, Indirect(RSP))),
+                "movdqa xmm1, xmmword ptr [rsp]",
+            ),
+            (bytes(MOVQ(XMM1, R9)), "movq xmm1, r9"),
+            (bytes(MOVQ(XMM12, RAX)), "movq xmm12, rax"),
+            (bytes(PUNPCKLQDQ(XMM0, XMM8)), "punpcklqdq xmm0, xmm8"),
+            (bytes(PXOR(XMM10, XMM2)), "pxor xmm10, xmm2"),
+            (bytes(PCMPEQB(XMM3, XMM3)), "pcmpeqb xmm3, xmm3"),
+            (bytes(PMOVMSKB(EAX, XMM9)), "pmovmskb eax, xmm9"),
+            (bytes(PMOVMSKB(R10D, XMM1)), "pmovmskb r10d, xmm1"),
+        ]
+    }
+
+    #[test]
+    fn computed_lengths() {
+        // Each case is encoded by `bytes`, which checks the computed length
+        // against the serialized one, whether or not the assembler is
+        // installed.
+        for (bytes, line) in cases() {
+            assert!(!bytes.is_empty(), "{line}");
+        }
+    }
+
+    #[test]
+    fn golden_encodings() {
+        let cases = cases();
+        let lines: Vec<&str> = cases.iter().map(|(_, line)| *line).collect();
+        let Some(expected) = assemble(&lines) else {
+            eprintln!("llvm-mc is not installed, skipping golden encoding tests");
+            return;
+        };
+        let mismatches: Vec<String> = cases
+            .iter()
+            .zip(&expected)
+            .filter(|((actual, _), expected)| actual != *expected)
+            .map(|((actual, line), expected)| {
+                format!("{}: got {:02x?}, expected {:02x?}", line, actual, expected)
+            })
+            .collect();
+        assert!(
+            mismatches.is_empty(),
+            "encodings differ from the assembler's:\n{}",
+            mismatches.join("\n")
+        );
+    }
+
+    /// The 4-bit code of a register, split between the ModRM or SIB byte
+    /// and the REX prefix.
+    fn code(register: impl Register) -> u8 {
+        register.in_rm() | register.rex_b() << 3
+    }
+
+    fn name(register: impl std::fmt::Debug) -> String {
+        format!("{:?}", register).to_lowercase()
+    }
+
+    /// The length of a REX prefix, which is needed for REX.W or any register
+    /// code above 7.
+    fn rex_len(w: bool, codes: &[u8]) -> usize {
+        (w || codes.iter().any(|&code| code > 7)) as usize
+    }
+
+    /// The length of a memory operand's ModRM byte, SIB byte and
+    /// displacement, where `displacement` is the length of an explicit
+    /// displacement.
+    fn memory_len(base: R64, index: bool, displacement: usize) -> usize {
+        // A base of RSP or R12 needs a SIB byte, and a base of RBP or R13 needs
+        // a displacement.
+        let sib = index || code(base) & 7 == 0b100;
+        let displacement = match displacement {
+            0 if code(base) & 7 == 0b101 => 1,
+            _ => displacement,
+        };
+        1 + sib as usize + displacement
+    }
+
+    fn address(base: R64, index: Option<(R64, u8)>, displacement: i32) -> String {
+        let mut address = name(base);
+        match index {
+            Some((index, 1)) => address += &format!(" + {}", name(index)),
+            Some((index, scale)) => address += &format!(" + {}*{}", scale, name(index)),
+            None => {}
+        }
+        match displacement {
+            0 => {}
+            d if d < 0 => address += &format!(" - {:#x}", d.unsigned_abs()),
+            d => address += &format!(" + {:#x}", d),
+        }
+        format!("[{}]", address)
+    }
+
+    const GPRS: [R64; 16] = [
+        RAX, RCX, RDX, RBX, RSP, RBP, RSI, RDI, R8, R9, R10, R11, R12, R13, R14, R15,
+    ];
+
+    #[derive(Default)]
+    struct Matrix {
+        failures: Vec<String>,
+    }
+
+    impl Matrix {
+        /// Check that an encoding has the expected length, and decodes to the
+        /// expected text, using all of its bytes.
+        fn check(&mut self, instruction: impl Instruction, len: usize, text: String) {
+            let encoding = bytes(instruction);
+            let decoded = decode(&encoding);
+            let decoded_text = decoded.as_ref().map(|decoded| decoded.to_string());
+            let decoded_len = decoded.as_ref().map(|decoded| decoded.length);
+            if encoding.len() != len
+                || decoded_len != Some(len)
+                || decoded_text.as_deref() != Some(&text)
+            {
+                self.failures.push(format!(
+                    "{}: {:02x?} ({} bytes) decodes as {:?}, expected {} bytes",
+                    text,
+                    encoding,
+                    encoding.len(),
+                    decoded_text,
+                    len
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn operand_matrix() {
+        let mut m = Matrix::default();
+        let r32 = |r: R64| R32::from_code(code(r));
+        let r8 = |r: R64| Byte::from_code(code(r));
+        let indexes = GPRS.iter().copied().filter(|&r| r != RSP);
+
+        for a in GPRS {
+            let (ca, na) = (code(a), name(a));
+
+            // A single register in the opcode or ModRM r/m field.
+            let n = rex_len(false, &[ca]);
+            m.check(PUSH(a), n + 1, format!("push {}", na));
+            m.check(POP(a), n + 1, format!("pop {}", na));
+            m.check(JMP(a), n + 2, format!("jmp {}", na));
+            m.check(CALL(a), n + 2, format!("call {}", na));
+            m.check(INC(a), 3, format!("inc {}", na));
+            m.check(DEC(a), 3, format!("dec {}", na));
+            m.check(NEG(a), 3, format!("neg {}", na));
+            m.check(DIV(a), 3, format!("div {}", na));
+
+            // Boundary immediates.
+            for value in [0, u64::MAX, 1 << 63] {
+                m.check(MOV(a, value), 10, format!("mov {}, {:#x}", na, value));
+            }
+            for value in [0, -1, i8::MIN, i8::MAX] {
+                let text = |op| format!("{} {}, {:#x}", op, na, value as u8);
+                m.check(ADD(a, value), 4, text("add"));
+                m.check(SUB(a, value), 4, text("sub"));
+                m.check(CMP(a, value), 4, text("cmp"));
+                m.check(AND(a, value), 4, text("and"));
+            }
+            for value in [0, -1, i32::MIN, i32::MAX] {
+                let text = |op| format!("{} {}, {:#x}", op, na, value as u32);
+                m.check(ADD(a, value), 7, text("add"));
+                m.check(SUB(a, value), 7, text("sub"));
+                m.check(CMP(a, value), 7, text("cmp"));
+                m.check(TEST(a, value), 7, text("test"));
+                m.check(OR(a, value), 7, text("or"));
+                m.check(XOR(a, value), 7, text("xor"));
+            }
+            for value in [1i8, 63] {
+                m.check(SHL(a, value), 4, format!("shl {}, {:#x}", na, value));
+                m.check(SHR(a, value), 4, format!("shr {}, {:#x}", na, value));
+            }
+
+            // Byte registers, including the high bytes, which only exist
+            // without a REX prefix.
+            for code_8 in 0..16 {
+                let b = Byte::from_code(code_8);
+                m.check(
+                    MOV(b, 0xffu8),
+                    rex_len(false, &[code_8]) + 2,
+                    format!("mov {}, 0xff", name(b)),
+                );
+                if !b.high_byte() || ca < 8 {
+                    m.check(
+                        MOV(Indirect(a), b),
+                        rex_len(false, &[code_8, ca]) + 1 + memory_len(a, false, 0),
+                        format!("mov byte ptr {}, {}", address(a, None, 0), name(b)),
+                    );
+                }
+            }
+
+            for b in GPRS {
+                let (cb, nb) = (code(b), name(b));
+
+                // Two registers, in the ModRM reg and r/m fields.
+                let text = |op| format!("{} {}, {}", op, na, nb);
+                m.check(MOV(a, b), 3, text("mov"));
+                m.check(ADD(a, b), 3, text("add"));
+                m.check(SUB(a, b), 3, text("sub"));
+                m.check(CMP(a, b), 3, text("cmp"));
+                m.check(TEST(a, b), 3, text("test"));
+                m.check(OR(a, b), 3, text("or"));
+                m.check(AND(a, b), 3, text("and"));
+                m.check(XOR(a, b), 3, text("xor"));
+                m.check(IMUL(a, b), 4, text("imul"));
+                m.check(BSF(a, b), 4, text("bsf"));
+                m.check(
+                    MOV(r32(a), r32(b)),
+                    rex_len(false, &[ca, cb]) + 2,
+                    format!("mov {}, {}", name(r32(a)), name(r32(b))),
+                );
+                if !(r8(a).high_byte() || r8(b).high_byte()) || (ca < 8 && cb < 8) {
+                    m.check(
+                        TEST(r8(a), r8(b)),
+                        rex_len(false, &[ca, cb]) + 2,
+                        format!("test {}, {}", name(r8(a)), name(r8(b))),
+                    );
+                }
+
+                // A register and a memory operand with a base register.
+                let mem =
+                    |size, displacement| format!("{} ptr {}", size, address(b, None, displacement));
+                m.check(
+                    MOV(a, Indirect(b)),
+                    1 + 1 + memory_len(b, false, 0),
+                    format!("mov {}, {}", na, mem("qword", 0)),
+                );
+                m.check(
+                    MOV(Indirect(b), a),
+                    1 + 1 + memory_len(b, false, 0),
+                    format!("mov {}, {}", mem("qword", 0), na),
+                );
+                for d in [0, -1, i8::MIN, i8::MAX] {
+                    let len = 1 + 1 + memory_len(b, false, 1);
+                    m.check(
+                        MOV(a, Index(b, d)),
+                        len,
+                        format!("mov {}, {}", na, mem("qword", d as i32)),
+                    );
+                    m.check(
+                        MOV(r32(a), Index(b, d)),
+                        rex_len(false, &[ca, cb]) + len - 1,
+                        format!("mov {}, {}", name(r32(a)), mem("dword", d as i32)),
+                    );
+                    m.check(
+                        LEA(a, Index(b, d)),
+                        len,
+                        format!("lea {}, {}", na, address(b, None, d as i32)),
+                    );
+                }
+                for d in [0, -1, i32::MIN, i32::MAX] {
+                    m.check(
+                        MOV(a, Index(b, d)),
+                        1 + 1 + memory_len(b, false, 4),
+                        format!("mov {}, {}", na, mem("qword", d)),
+                    );
+                }
+
+                // A register and a memory operand with a base and an index.
+                for index in indexes.clone() {
+                    let ci = code(index);
+                    let mem = |size, scale| {
+                        format!("{} ptr {}", size, address(b, Some((index, scale)), 0))
+                    };
+                    let len = memory_len(b, true, 0);
+                    m.check(
+                        MOV(a, Index(index, b)),
+                        2 + len,
+                        format!("mov {}, {}", na, mem("qword", 1)),
+                    );
+                    m.check(
+                        MOV(a, ScaledIndex(Times8, index, b)),
+                        2 + len,
+                        format!("mov {}, {}", na, mem("qword", 8)),
+                    );
+                    m.check(
+                        MOVZX(a, Index(index, b)),
+                        3 + len,
+                        format!("movzx {}, {}", na, mem("byte", 1)),
+                    );
+                    m.check(
+                        MOVDQU(Xmm::from_code(ca), Index(index, b)),
+                        1 + rex_len(false, &[ca, ci, cb]) + 2 + len,
+                        format!("movdqu {}, {}", name(Xmm::from_code(ca)), mem("xmmword", 1)),
+                    );
+                    for code_8 in 0..16 {
+                        let r = Byte::from_code(code_8);
+                        if r.high_byte() && (ci > 7 || cb > 7) {
+                            continue;
+                        }
+                        m.check(
+                            MOV(r, Index(index, b)),
+                            rex_len(false, &[code_8, ci, cb]) + 1 + len,
+                            format!("mov {}, {}", name(r), mem("byte", 1)),
+                        );
+                    }
+                }
+            }
+        }
+
+        assert!(
+            m.failures.is_empty(),
+            "{} encodings are wrong, including:\n{}",
+            m.failures.len(),
+            m.failures[..m.failures.len().min(20)].join("\n")
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "RSP can not be used as an index register")]
+    fn rsp_index() {
+        bytes(MOV(RAX, Index(RSP, RBX)));
+    }
+
+    #[test]
+    #[should_panic(expected = "AH, CH, DH and BH can not be encoded")]
+    fn high_byte_with_rex() {
+        bytes(MOV(AH, Index(RAX, R8)));
+    }
+
+    /// The encoding of `instruction` in `mode`, with the location and format
+    /// of its reference, if any.
+    fn in_mode(
+        mode: Mode,
+        instruction: impl Instruction,
+    ) -> (Vec<u8>, Option<(usize, ReferenceFormat)>) {
+        let encoded = instruction.encode().for_mode(mode);
+        let mut bytes = Vec::new();
+        encoded.serialize(&mut bytes);
+        let reference = encoded
+            .references()
+            .into_iter()
+            .next()
+            .map(|(_, reference)| (reference.location, reference.format));
+        (bytes, reference)
+    }
+
+    #[test]
+    fn protected_mode() {
+        assert_eq!(
+            in_mode(Mode::Protected, MOV(EAX, EBX)),
+            (vec![0x8b, 0xc3], None)
+        );
+        assert_eq!(in_mode(Mode::Protected, PUSH(RBP)), (vec![0x55], None));
+        assert_eq!(
+            in_mode(Mode::Protected, MOV(ECX, Index(RSP, 8i8))),
+            (vec![0x8b, 0x4c, 0x24, 0x08], None)
+        );
+        // A pointer is an absolute address.
+        assert_eq!(
+            in_mode(Mode::Protected, MOV(EAX, Ptr("x"))),
+            (
+                vec![0x8b, 0x05, 0, 0, 0, 0],
+                Some((2, ReferenceFormat::Abs32))
+            )
+        );
+        assert_eq!(
+            in_mode(Mode::Protected, JMP(Label("x"))),
+            (vec![0xe9, 0, 0, 0, 0], Some((1, ReferenceFormat::Rel32)))
+        );
+    }
+
+    #[test]
+    fn real_mode() {
+        assert_eq!(
+            in_mode(Mode::Real, MOV(EAX, EBX)),
+            (vec![0x66, 0x8b, 0xc3], None)
+        );
+        assert_eq!(in_mode(Mode::Real, PUSH(RBP)), (vec![0x55], None));
+        assert_eq!(in_mode(Mode::Real, MOV(DS, AX)), (vec![0x8e, 0xd8], None));
+        // Base registers need 32-bit addressing.
+        assert_eq!(
+            in_mode(Mode::Real, MOV(ECX, Index(RSP, 8i8))),
+            (vec![0x67, 0x66, 0x8b, 0x4c, 0x24, 0x08], None)
+        );
+        // A pointer is a 16-bit absolute offset.
+        assert_eq!(
+            in_mode(Mode::Real, MOV(Ptr("x"), AX)),
+            (vec![0x89, 0x06, 0, 0], Some((2, ReferenceFormat::Abs16)))
+        );
+        assert_eq!(
+            in_mode(Mode::Real, JMP(Label("x"))),
+            (vec![0xe9, 0, 0], Some((1, ReferenceFormat::Rel16)))
+        );
+        // The only operand of a dword store is in memory.
+        assert_eq!(
+            in_mode(Mode::Real, MOV(Index(RBX, 4i32), 0xdeadbeefu32)),
+            (
+                vec![0x67, 0x66, 0xc7, 0x83, 4, 0, 0, 0, 0xef, 0xbe, 0xad, 0xde],
+                None
+            )
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "operand size of a 32-bit immediate is not known")]
+    fn real_mode_unknown_operand_size() {
+        // 68 id | PUSH imm32, without the operand size recorded.
+        InstructionBuilder::new()
+            .opcode(0x68)
+            .immediate(0u32)
+            .for_mode(Mode::Real);
+    }
+
+    #[test]
+    fn rex2() {
+        assert_eq!(bytes(MOV(Egpr::R16, RAX)), [0xd5, 0x48, 0x8b, 0xc0]);
+        assert_eq!(bytes(MOV(RAX, Egpr::R31)), [0xd5, 0x19, 0x8b, 0xc7]);
+        assert_eq!(
+            bytes(MOV(Egpr::R24, Index(RBP, -8i8))),
+            [0xd5, 0x4c, 0x8b, 0x45, 0xf8]
+        );
+        assert_eq!(bytes(PUSH(Egpr::R17)), [0xd5, 0x10, 0x51]);
+        // The REX2 prefix replaces the 0F escape byte of map 1.
+        let imul = InstructionBuilder::new()
+            .rex_w()
+            .opcode([0x0f, 0xaf])
+            .reg(Egpr::R16)
+            .rm_literal(RAX);
+        let mut out = Vec::new();
+        imul.serialize(&mut out);
+        assert_eq!(out, [0xd5, 0xc8, 0xaf, 0xc0]);
+        assert_eq!(imul.len(), out.len());
+    }
+
+    #[test]
+    #[should_panic(expected = "can not be encoded with a REX2 prefix")]
+    fn rex2_legacy_only() {
+        // 0F 38 F0 /r | MOVBE r64, m64
+        InstructionBuilder::new()
+            .rex_w()
+            .opcode([0x0f, 0x38, 0xf0])
+            .reg(Egpr::R16)
+            .indirect(Indirect(RAX))
+            .serialize(&mut Vec::new());
+    }
+
+    #[test]
+    #[should_panic(expected = "R16-R31 can not be encoded in protected mode")]
+    fn rex2_protected_mode() {
+        PUSH(Egpr::R16).encode().for_mode(Mode::Protected);
+    }
+
+    #[test]
+    #[should_panic(expected = "64-bit operands can not be encoded in protected mode")]
+    fn protected_mode_rex_w() {
+        MOV(RAX, RBX).encode().for_mode(Mode::Protected);
+    }
+
+    #[test]
+    #[should_panic(expected = "R8-R15 can not be encoded in protected mode")]
+    fn protected_mode_extended_register() {
+        MOV(EAX, R8D).encode().for_mode(Mode::Protected);
+    }
+}
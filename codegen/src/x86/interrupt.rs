@@ -0,0 +1,344 @@
+//! Entry and exit code for interrupt and exception handlers.
+//!
+//! On an interrupt, the CPU aligns RSP to 16 bytes and pushes the
+//! interrupted SS, RSP, RFLAGS, CS and RIP, followed by an error code for
+//! some exceptions. An [`InterruptHandler`] wraps a body so that it can be
+//! written like any other code: it pushes a zero error code if the CPU did
+//! not, saves the caller-saved registers of a [`CallingConvention`], aligns
+//! the stack for calls and clears the direction flag. The body may call
+//! functions following that convention, which preserve the remaining
+//! registers, but must save any callee-saved registers that it uses
+//! directly.
+//!
+//! Handlers that only call functions following the
+//! [`Kernel`](super::convention::Kernel) convention save just three
+//! registers, instead of the nine of the default [`SysV`] convention.
+//!
+//! A handler can also be shared by all 32 exception vectors, with
+//! [`InterruptHandler::emit_exceptions`]. A small stub for each vector pushes
+//! the error code if needed and the vector number, and jumps to the handler.
+//!
+//! After the body, everything is restored in reverse and the handler returns
+//! with `IRETQ`. The stack at the start of the body is:
+//!
+//! ```text
+//!             ss
+//!             rsp
+//!             rflags
+//!             cs
+//!             rip
+//!             error code
+//!             vector, for exception stubs
+//!             saved registers
+//! rsp         padding, if needed
+//! ```
+
+use super::{
+    address::Index,
+    convention::{CallingConvention, SysV},
+    descriptor::{Gate, IdtBuilder},
+    instruction::{ADD, CLD, IRET, JMP, JZ, POP, PUSH, SUB, SWAPGS, TEST},
+    register::R64::{self, *},
+    Assembler,
+};
+use crate::link::Label;
+
+/// Number of vectors reserved for exceptions.
+pub const EXCEPTION_VECTORS: u8 = 32;
+
+/// Requested privilege level bits of a segment selector.
+const RPL_MASK: u8 = 0b11;
+
+/// Whether the CPU pushes an error code for exception `vector`.
+pub fn pushes_error_code(vector: u8) -> bool {
+    // #DF, #TS, #NP, #SS, #GP, #PF, #AC, #CP, #VC, #SX
+    matches!(vector, 8 | 10..=14 | 17 | 21 | 29 | 30)
+}
+
+pub struct InterruptHandler<'a> {
+    name: &'a str,
+    error_code: bool,
+    swapgs: bool,
+    convention: &'static dyn CallingConvention,
+    /// Whether the handler is entered from exception stubs, which push the
+    /// error code and the vector number.
+    stubs: bool,
+}
+
+impl<'a> InterruptHandler<'a> {
+    pub fn new(name: &'a str) -> Self {
+        Self {
+            name,
+            error_code: false,
+            swapgs: false,
+            convention: &SysV,
+            stubs: false,
+        }
+    }
+
+    /// Declare that the CPU pushes an error code for this handler's vector.
+    /// See [`pushes_error_code`].
+    pub fn error_code(mut self) -> Self {
+        self.error_code = true;
+        self
+    }
+
+    /// Swap GS with `SWAPGS` on entry and exit, when interrupting user mode.
+    pub fn swapgs(mut self) -> Self {
+        self.swapgs = true;
+        self
+    }
+
+    /// Save only the registers clobbered by calls following `convention`,
+    /// instead of the System V convention.
+    pub fn convention(mut self, convention: &'static dyn CallingConvention) -> Self {
+        self.convention = convention;
+        self
+    }
+
+    /// Emit the handler, with `body` between its entry and exit code.
+    pub fn emit(self, asm: &mut Assembler, body: impl FnOnce(&mut Assembler, &InterruptFrame)) {
+        // The CPU's frame, the error code, the vector and the saved
+        // registers.
+        let saved = self.convention.caller_saved();
+        let vector = if self.stubs { 1 } else { 0 };
+        let pushed = 5 + 1 + vector + saved.len();
+        let frame = InterruptFrame {
+            saved,
+            vector: self.stubs,
+            padding: if pushed % 2 == 1 { 8 } else { 0 },
+        };
+
+        // Exported, to be referenced from the IDT in another segment.
+        asm.export_label(self.name);
+        if !self.error_code && !self.stubs {
+            asm.push(PUSH(0i8));
+        }
+        if self.swapgs {
+            swapgs_from_user(asm, 8 * vector);
+        }
+        for &register in saved {
+            asm.push(PUSH(register));
+        }
+        if frame.padding > 0 {
+            asm.push(SUB(RSP, frame.padding as i32));
+        }
+        // The System V ABI requires the direction flag to be clear.
+        asm.push(CLD);
+
+        body(asm, &frame);
+
+        if frame.padding > 0 {
+            asm.push(ADD(RSP, frame.padding as i8));
+        }
+        for &register in saved.iter().rev() {
+            asm.push(POP(register));
+        }
+        if self.swapgs {
+            swapgs_from_user(asm, 8 * vector);
+        }
+        // Discard the error code and vector.
+        asm.push(ADD(RSP, (8 + 8 * vector) as i8));
+        asm.push(IRET);
+    }
+
+    /// Emit the handler as the common body of an entry stub for each of the
+    /// exception vectors, and register the stubs in `idt` as interrupt gates
+    /// with the code segment `selector`.
+    ///
+    /// Each stub pushes a zero error code if the CPU does not push one, and
+    /// then the vector number, which the body can read with
+    /// [`InterruptFrame::vector`].
+    pub fn emit_exceptions(
+        mut self,
+        asm: &mut Assembler,
+        idt: &mut IdtBuilder,
+        selector: u16,
+        body: impl FnOnce(&mut Assembler, &InterruptFrame),
+    ) {
+        assert!(
+            !self.error_code,
+            "exception stubs push the error code of every vector"
+        );
+        for vector in 0..EXCEPTION_VECTORS {
+            let stub = Label(&format!("{}.{}", self.name, vector));
+            // Exported, to be referenced from the IDT in another segment.
+            asm.export_label(stub);
+            if !pushes_error_code(vector) {
+                asm.push(PUSH(0i8));
+            }
+            asm.push(PUSH(vector as i8));
+            asm.push(JMP(Label(self.name)));
+            idt.set(vector, Gate::interrupt(stub, selector));
+        }
+
+        self.stubs = true;
+        self.emit(asm, body);
+    }
+}
+
+/// Emit `SWAPGS` if the interrupted code was in user mode, with `offset`
+/// bytes above the error code on top of the stack.
+fn swapgs_from_user(asm: &mut Assembler, offset: usize) {
+    let kernel = asm.fresh_label("interrupt_from_kernel");
+    asm.push(TEST(Index(RSP, 16 + offset as i8), RPL_MASK));
+    asm.push(JZ(kernel));
+    asm.push(SWAPGS);
+    asm.label(kernel);
+}
+
+/// The stack of an [`InterruptHandler`], relative to RSP at the start of its
+/// body.
+pub struct InterruptFrame {
+    saved: &'static [R64],
+    /// Whether the vector number was pushed, by an exception stub.
+    vector: bool,
+    padding: usize,
+}
+
+impl InterruptFrame {
+    fn operand(offset: usize) -> Index<R64, i8> {
+        Index(RSP, offset as i8)
+    }
+
+    fn error_code_offset(&self) -> usize {
+        let vector = if self.vector { 8 } else { 0 };
+        self.padding + 8 * self.saved.len() + vector
+    }
+
+    /// The value that `register` had when the handler was entered. Only the
+    /// caller-saved registers of the handler's convention are saved.
+    pub fn saved(&self, register: R64) -> Index<R64, i8> {
+        let index = self
+            .saved
+            .iter()
+            .position(|&saved| saved == register)
+            .unwrap_or_else(|| panic!("{:?} is not saved by interrupt handlers", register));
+        Self::operand(self.padding + 8 * (self.saved.len() - 1 - index))
+    }
+
+    /// The vector number pushed by an exception stub. Only available in
+    /// handlers emitted with [`InterruptHandler::emit_exceptions`].
+    pub fn vector(&self) -> Index<R64, i8> {
+        assert!(
+            self.vector,
+            "the vector number is only pushed by exception stubs"
+        );
+        Self::operand(self.error_code_offset() - 8)
+    }
+
+    /// The error code pushed by the CPU, or zero.
+    pub fn error_code(&self) -> Index<R64, i8> {
+        Self::operand(self.error_code_offset())
+    }
+
+    /// The interrupted instruction pointer.
+    pub fn rip(&self) -> Index<R64, i8> {
+        Self::operand(self.error_code_offset() + 8)
+    }
+
+    pub fn cs(&self) -> Index<R64, i8> {
+        Self::operand(self.error_code_offset() + 16)
+    }
+
+    pub fn rflags(&self) -> Index<R64, i8> {
+        Self::operand(self.error_code_offset() + 24)
+    }
+
+    /// The interrupted stack pointer.
+    pub fn rsp(&self) -> Index<R64, i8> {
+        Self::operand(self.error_code_offset() + 32)
+    }
+
+    pub fn ss(&self) -> Index<R64, i8> {
+        Self::operand(self.error_code_offset() + 40)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::x86::convention::Kernel;
+
+    /// The frame of a handler following `convention`, and whether it is
+    /// entered from exception stubs.
+    fn frame(convention: &'static dyn CallingConvention, stubs: bool) -> InterruptFrame {
+        let mut frame = None;
+        let handler = InterruptHandler::new("handler").convention(convention);
+        let mut asm = Assembler::new();
+        let body = |_: &mut Assembler, f: &InterruptFrame| {
+            frame = Some(InterruptFrame {
+                saved: f.saved,
+                vector: f.vector,
+                padding: f.padding,
+            })
+        };
+        if stubs {
+            handler.emit_exceptions(&mut asm, &mut IdtBuilder::new(), 0x08, body);
+        } else {
+            handler.emit(&mut asm, body);
+        }
+        frame.unwrap()
+    }
+
+    #[test]
+    fn frame_offsets() {
+        // Padded so that the CPU's 16-byte aligned frame, the error code,
+        // the vector and the saved registers end 16-byte aligned.
+        let f = frame(&SysV, false);
+        assert_eq!(f.saved(RAX), Index(RSP, 72));
+        assert_eq!(f.saved(R11), Index(RSP, 8));
+        assert_eq!(f.error_code(), Index(RSP, 80));
+        assert_eq!(f.rip(), Index(RSP, 88));
+        assert_eq!(f.ss(), Index(RSP, 120));
+
+        let f = frame(&SysV, true);
+        assert_eq!(f.saved(RAX), Index(RSP, 64));
+        assert_eq!(f.vector(), Index(RSP, 72));
+        assert_eq!(f.error_code(), Index(RSP, 80));
+        assert_eq!(f.rip(), Index(RSP, 88));
+
+        let f = frame(&Kernel, false);
+        assert_eq!(f.saved(RAX), Index(RSP, 24));
+        assert_eq!(f.saved(R11), Index(RSP, 8));
+        assert_eq!(f.error_code(), Index(RSP, 32));
+        assert_eq!(f.rip(), Index(RSP, 40));
+
+        let f = frame(&Kernel, true);
+        assert_eq!(f.saved(RAX), Index(RSP, 16));
+        assert_eq!(f.saved(R11), Index(RSP, 0));
+        assert_eq!(f.vector(), Index(RSP, 24));
+        assert_eq!(f.error_code(), Index(RSP, 32));
+        assert_eq!(f.rip(), Index(RSP, 40));
+    }
+
+    #[test]
+    fn exception_stubs() {
+        let mut asm = Assembler::new();
+        InterruptHandler::new("handler")
+            .convention(&Kernel)
+            .emit_exceptions(&mut asm, &mut IdtBuilder::new(), 0x08, |_, _| {});
+
+        let mut expected = Assembler::new();
+        for vector in 0..EXCEPTION_VECTORS {
+            expected.export_label(&format!("handler.{}", vector));
+            if !matches!(vector, 8 | 10..=14 | 17 | 21 | 29 | 30) {
+                expected.push(PUSH(0i8));
+            }
+            expected.push(PUSH(vector as i8));
+            expected.push(JMP(Label("handler")));
+        }
+        expected.export_label("handler");
+        for register in [RAX, R10, R11] {
+            expected.push(PUSH(register));
+        }
+        expected.push(CLD);
+        for register in [R11, R10, RAX] {
+            expected.push(POP(register));
+        }
+        // Both the error code and the vector are discarded.
+        expected.push(ADD(RSP, 16i8));
+        expected.push(IRET);
+        assert_eq!(asm.finish().bytes(), expected.finish().bytes());
+    }
+}
@@ -0,0 +1,270 @@
+//! A library of memory and string routines, emitted on demand.
+//!
+//! Code that needs one of the routines [`require`](Intrinsics::require)s it
+//! and calls the returned label with the System V ABI; each required routine
+//! is then emitted once by [`emit`](Intrinsics::emit). The routines follow
+//! the C standard library signatures:
+//!
+//! - `memcpy(dst, src, n) -> dst`
+//! - `memset(dst, byte, n) -> dst`
+//! - `memcmp(a, b, n) -> difference of the first differing bytes, or 0`
+//! - `strlen(string) -> length`
+
+use super::{
+    address::{Index, Indirect},
+    control::ControlFlow,
+    instruction::*,
+    register::{Xmm::*, R32::*, R64::*, R8::*},
+    Assembler,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Intrinsic {
+    Memcpy,
+    Memset,
+    Memcmp,
+    Strlen,
+}
+
+impl Intrinsic {
+    /// The exported label of the routine.
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::Memcpy => "memcpy",
+            Self::Memset => "memset",
+            Self::Memcmp => "memcmp",
+            Self::Strlen => "strlen",
+        }
+    }
+}
+
+/// How the routines are implemented.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Variant {
+    /// Compact REP-prefixed string instructions, which are fast on CPUs with
+    /// enhanced REP MOVSB/STOSB and need no SSE state.
+    Rep,
+    /// 16 bytes at a time with SSE2. The kernel must have enabled SSE.
+    Sse2,
+}
+
+pub struct Intrinsics {
+    variant: Variant,
+    required: Vec<Intrinsic>,
+}
+
+impl Intrinsics {
+    pub fn new(variant: Variant) -> Self {
+        Self {
+            variant,
+            required: Vec::new(),
+        }
+    }
+
+    /// Request that `intrinsic` is emitted, returning the label to call.
+    pub fn require(&mut self, intrinsic: Intrinsic) -> &'static str {
+        if !self.required.contains(&intrinsic) {
+            self.required.push(intrinsic);
+        }
+        intrinsic.label()
+    }
+
+    /// Emit and export each required routine.
+    pub fn emit(&self, asm: &mut Assembler) {
+        for &intrinsic in &self.required {
+            asm.export_label(intrinsic.label());
+            match (intrinsic, self.variant) {
+                (Intrinsic::Memcpy, Variant::Rep) => memcpy_rep(asm),
+                (Intrinsic::Memset, Variant::Rep) => memset_rep(asm),
+                (Intrinsic::Memcmp, Variant::Rep) => memcmp_rep(asm),
+                (Intrinsic::Strlen, Variant::Rep) => strlen_rep(asm),
+                (Intrinsic::Memcpy, Variant::Sse2) => memcpy_sse2(asm),
+                (Intrinsic::Memset, Variant::Sse2) => memset_sse2(asm),
+                (Intrinsic::Memcmp, Variant::Sse2) => memcmp_sse2(asm),
+                (Intrinsic::Strlen, Variant::Sse2) => strlen_sse2(asm),
+            }
+        }
+    }
+}
+
+fn memcpy_rep(asm: &mut Assembler) {
+    asm.push(MOV(RAX, RDI));
+    asm.push(MOV(RCX, RDX));
+    asm.push(REP(MOVSB));
+    asm.push(RET);
+}
+
+fn memset_rep(asm: &mut Assembler) {
+    asm.push(MOV(R9, RDI));
+    asm.push(MOV(RAX, RSI));
+    asm.push(MOV(RCX, RDX));
+    asm.push(REP(STOSB));
+    asm.push(MOV(RAX, R9));
+    asm.push(RET);
+}
+
+fn memcmp_rep(asm: &mut Assembler) {
+    let done = asm.fresh_label("memcmp_done");
+    asm.push(MOV(RCX, RDX));
+    // Also sets ZF, for when the count is zero.
+    asm.push(XOR(RAX, RAX));
+    asm.push(REPE(CMPSB));
+    asm.push(JZ(done));
+    // Both pointers have stepped past the differing bytes.
+    asm.push(MOVZX(RAX, Index(RDI, -1)));
+    asm.push(MOVZX(RCX, Index(RSI, -1)));
+    asm.push(SUB(RAX, RCX));
+    asm.label(done);
+    asm.push(RET);
+}
+
+fn strlen_rep(asm: &mut Assembler) {
+    asm.push(MOV(RDX, RDI));
+    asm.push(XOR(RAX, RAX));
+    asm.push(MOV(RCX, u64::MAX));
+    asm.push(REPNE(SCASB));
+    // RDI has stepped past the terminator.
+    asm.push(MOV(RAX, RDI));
+    asm.push(SUB(RAX, RDX));
+    asm.push(DEC(RAX));
+    asm.push(RET);
+}
+
+/// Emit a loop over `RCX` from its current value up to the multiple of 16
+/// below the count in RDX, in steps of 16, followed by a loop over the
+/// remaining bytes.
+fn blocks_then_bytes(
+    asm: &mut Assembler,
+    block: impl FnOnce(&mut Assembler),
+    byte: impl FnOnce(&mut Assembler),
+) {
+    asm.push(MOV(R8, RDX));
+    asm.push(AND(R8, -16));
+    asm.while_(
+        |asm| {
+            asm.push(CMP(RCX, R8));
+            Condition::Below
+        },
+        |asm, _| {
+            block(asm);
+            asm.push(ADD(RCX, 16i8));
+        },
+    );
+    asm.while_(
+        |asm| {
+            asm.push(CMP(RCX, RDX));
+            Condition::Below
+        },
+        |asm, _| {
+            byte(asm);
+            asm.push(INC(RCX));
+        },
+    );
+}
+
+fn memcpy_sse2(asm: &mut Assembler) {
+    asm.push(XOR(RCX, RCX));
+    blocks_then_bytes(
+        asm,
+        |asm| {
+            asm.push(MOVDQU(XMM0, Index(RCX, RSI)));
+            asm.push(MOVDQU(Index(RCX, RDI), XMM0));
+        },
+        |asm| {
+            asm.push(MOV(R9B, Index(RCX, RSI)));
+            asm.push(MOV(Index(RCX, RDI), R9B));
+        },
+    );
+    asm.push(MOV(RAX, RDI));
+    asm.push(RET);
+}
+
+fn memset_sse2(asm: &mut Assembler) {
+    // Broadcast the low byte of RSI to all bytes of R9 and XMM0.
+    asm.push(SHL(RSI, 56));
+    asm.push(SHR(RSI, 56));
+    asm.push(MOV(R9, 0x0101_0101_0101_0101));
+    asm.push(IMUL(R9, RSI));
+    asm.push(MOVQ(XMM0, R9));
+    asm.push(PUNPCKLQDQ(XMM0, XMM0));
+
+    asm.push(XOR(RCX, RCX));
+    blocks_then_bytes(
+        asm,
+        |asm| asm.push(MOVDQU(Index(RCX, RDI), XMM0)),
+        |asm| asm.push(MOV(Index(RCX, RDI), R9B)),
+    );
+    asm.push(MOV(RAX, RDI));
+    asm.push(RET);
+}
+
+fn memcmp_sse2(asm: &mut Assembler) {
+    let differ = asm.fresh_label("memcmp_differ");
+    let done = asm.fresh_label("memcmp_done");
+
+    asm.push(XOR(RCX, RCX));
+    blocks_then_bytes(
+        asm,
+        |asm| {
+            asm.push(MOVDQU(XMM0, Index(RCX, RDI)));
+            asm.push(MOVDQU(XMM1, Index(RCX, RSI)));
+            asm.push(PCMPEQB(XMM0, XMM1));
+            asm.push(PMOVMSKB(EAX, XMM0));
+            // Set bits now mark the differing bytes.
+            asm.push(XOR(RAX, 0xffff));
+            asm.if_then(Condition::NotZero, |asm| {
+                asm.push(BSF(RAX, RAX));
+                asm.push(ADD(RCX, RAX));
+                asm.push(JMP(differ));
+            });
+        },
+        |asm| {
+            asm.push(MOVZX(RAX, Index(RCX, RDI)));
+            asm.push(MOVZX(R9, Index(RCX, RSI)));
+            asm.push(SUB(RAX, R9));
+            asm.push(JNZ(done));
+        },
+    );
+    asm.push(XOR(RAX, RAX));
+    asm.push(RET);
+
+    // RCX is the index of the first differing byte.
+    asm.label(differ);
+    asm.push(MOVZX(RAX, Index(RCX, RDI)));
+    asm.push(MOVZX(R9, Index(RCX, RSI)));
+    asm.push(SUB(RAX, R9));
+    asm.label(done);
+    asm.push(RET);
+}
+
+fn strlen_sse2(asm: &mut Assembler) {
+    // Aligned loads never cross into an unmapped page, so start at the
+    // 16-byte block containing the string and ignore the bytes before it.
+    asm.push(MOV(RAX, RDI));
+    asm.push(MOV(RCX, RDI));
+    asm.push(AND(RCX, 15));
+    asm.push(SUB(RAX, RCX));
+    asm.push(PXOR(XMM0, XMM0));
+    asm.push(MOVDQA(XMM1, Indirect(RAX)));
+    asm.push(PCMPEQB(XMM1, XMM0));
+    asm.push(PMOVMSKB(EDX, XMM1));
+    asm.push(SHR(RDX, CL));
+    asm.push(TEST(RDX, RDX));
+    asm.if_then(Condition::NotZero, |asm| {
+        asm.push(BSF(RAX, RDX));
+        asm.push(RET);
+    });
+
+    asm.loop_(|asm, labels| {
+        asm.push(ADD(RAX, 16i8));
+        asm.push(MOVDQA(XMM1, Indirect(RAX)));
+        asm.push(PCMPEQB(XMM1, XMM0));
+        asm.push(PMOVMSKB(EDX, XMM1));
+        asm.push(TEST(RDX, RDX));
+        asm.push(JNZ(labels.break_));
+    });
+    asm.push(BSF(RDX, RDX));
+    asm.push(ADD(RAX, RDX));
+    asm.push(SUB(RAX, RDI));
+    asm.push(RET);
+}
@@ -0,0 +1,158 @@
+//! A generator for I/O APIC redirection table setup.
+//!
+//! An I/O APIC's registers are accessed indirectly, by writing the index of
+//! a register to the memory-mapped `IOREGSEL` and then accessing its value
+//! through `IOWIN`. Each of its inputs has a 64-bit redirection entry, which
+//! selects the vector and destination of the interrupts from the global
+//! system interrupt (GSI) wired to it.
+//!
+//! The generated routine `{name}_init(base)`, called with the System V ABI,
+//! programs the redirection entries declared with
+//! [`redirect`](IoApic::redirect), with the registers mapped at `base`,
+//! which it also stores in the global `{name}_base`.
+
+use super::{
+    address::Index, function::Function, global::Global, instruction::*, register::R64, Assembler,
+    Emitter,
+};
+use crate::link::{Label, Segment};
+
+/// Register select, holding the index of the register accessed through
+/// [`IOWIN`].
+const IOREGSEL: i32 = 0x00;
+/// Register window.
+const IOWIN: i32 = 0x10;
+/// Index of the low half of the redirection entry of input 0. Each entry
+/// takes two registers.
+const REDIRECTION_TABLE: u32 = 0x10;
+/// Number of inputs of the largest I/O APICs.
+const MAX_INPUTS: u32 = 240;
+
+/// Low half of a redirection entry: the interrupt input pin is active low.
+const ACTIVE_LOW: u32 = 1 << 13;
+/// Low half of a redirection entry: the input is level-triggered.
+const LEVEL_TRIGGERED: u32 = 1 << 15;
+/// Low half of a redirection entry: interrupts from the input are masked.
+const MASKED: u32 = 1 << 16;
+/// Shift of the destination APIC ID in the high half of a redirection entry.
+const DESTINATION_SHIFT: u32 = 24;
+
+/// A redirection entry, with fixed delivery to a physical destination.
+#[derive(Debug, Clone, Copy)]
+pub struct Redirection {
+    pub gsi: u32,
+    pub vector: u8,
+    /// Local APIC ID of the CPU that the interrupts are delivered to.
+    pub destination: u8,
+    pub masked: bool,
+    /// Whether the input is level-triggered instead of edge-triggered, as
+    /// may be given by an interrupt source override in the MADT.
+    pub level_triggered: bool,
+    /// Whether the input is active low instead of active high, as may be
+    /// given by an interrupt source override in the MADT.
+    pub active_low: bool,
+}
+
+impl Redirection {
+    /// An unmasked, edge-triggered and active high entry, the defaults for
+    /// ISA IRQs.
+    pub fn new(gsi: u32, vector: u8, destination: u8) -> Self {
+        Self {
+            gsi,
+            vector,
+            destination,
+            masked: false,
+            level_triggered: false,
+            active_low: false,
+        }
+    }
+
+    fn low(&self) -> u32 {
+        let mut low = self.vector as u32;
+        if self.active_low {
+            low |= ACTIVE_LOW;
+        }
+        if self.level_triggered {
+            low |= LEVEL_TRIGGERED;
+        }
+        if self.masked {
+            low |= MASKED;
+        }
+        low
+    }
+
+    fn high(&self) -> u32 {
+        (self.destination as u32) << DESTINATION_SHIFT
+    }
+}
+
+pub struct IoApic<'a> {
+    name: &'a str,
+    gsi_base: u32,
+    redirections: Vec<Redirection>,
+}
+
+impl<'a> IoApic<'a> {
+    /// An I/O APIC whose first input is wired to the GSI `gsi_base`, as
+    /// given by its entry in the MADT.
+    pub fn new(name: &'a str, gsi_base: u32) -> Self {
+        Self {
+            name,
+            gsi_base,
+            redirections: Vec::new(),
+        }
+    }
+
+    /// Program the redirection entry of a GSI handled by this I/O APIC.
+    pub fn redirect(mut self, redirection: Redirection) -> Self {
+        assert!(
+            redirection
+                .gsi
+                .checked_sub(self.gsi_base)
+                .is_some_and(|input| input < MAX_INPUTS),
+            "GSI {} is not handled by I/O APIC {}",
+            redirection.gsi,
+            self.name
+        );
+        assert!(
+            !self.redirections.iter().any(|r| r.gsi == redirection.gsi),
+            "GSI {} is redirected more than once",
+            redirection.gsi
+        );
+        self.redirections.push(redirection);
+        self
+    }
+
+    /// A label derived from the name.
+    fn label(&self, suffix: &str) -> Label {
+        Label(&format!("{}_{}", self.name, suffix))
+    }
+
+    /// Emit the exported routine into `asm`, and the global holding the
+    /// register base into `data`.
+    pub fn emit(&self, asm: &mut Assembler, data: &mut Segment) {
+        let base = Global::<u64>::reserve(data, self.label("base"));
+
+        let mut f = Function::new(self.label("init"))
+            .export()
+            .params(1)
+            .begin(asm);
+        let registers = f.param(0);
+        base.store(&mut f, registers);
+        for redirection in &self.redirections {
+            let index = REDIRECTION_TABLE + 2 * (redirection.gsi - self.gsi_base);
+            // The high half is written first, so that the entry is never
+            // unmasked with a stale destination.
+            write(&mut f, registers, index + 1, redirection.high());
+            write(&mut f, registers, index, redirection.low());
+        }
+        f.ret();
+        f.finish();
+    }
+}
+
+/// Write `value` to the register `index` of the I/O APIC at `registers`.
+fn write(f: &mut impl Emitter, registers: R64, index: u32, value: u32) {
+    f.push(MOV(Index(registers, IOREGSEL), index));
+    f.push(MOV(Index(registers, IOWIN), value));
+}
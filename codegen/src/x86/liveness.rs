@@ -0,0 +1,115 @@
+//! Liveness analysis of [`VInst`]s.
+//!
+//! A register is live at a point if its current value may be read later.
+//! Liveness is computed per instruction by iterating the usual backward
+//! dataflow equations over the control flow graph until they settle:
+//!
+//! ```text
+//! live_out(i) = union of live_in(s) over the successors s of i
+//! live_in(i)  = uses(i) + (live_out(i) - def(i))
+//! ```
+//!
+//! It is used to compute live intervals for register allocation, and to
+//! remove instructions whose results are never read.
+
+use std::collections::{BTreeSet, HashMap};
+
+use super::regalloc::{VInst, VReg};
+
+pub struct Liveness {
+    live_in: Vec<BTreeSet<VReg>>,
+    live_out: Vec<BTreeSet<VReg>>,
+}
+
+impl Liveness {
+    pub fn compute(insts: &[VInst]) -> Self {
+        let labels: HashMap<&str, usize> = insts
+            .iter()
+            .enumerate()
+            .filter_map(|(i, inst)| match inst {
+                VInst::Label(label) => Some((*label, i)),
+                _ => None,
+            })
+            .collect();
+        let successors: Vec<Vec<usize>> = insts
+            .iter()
+            .enumerate()
+            .map(|(i, inst)| {
+                let mut successors = Vec::new();
+                if let Some(target) = inst.jump_target() {
+                    successors.push(
+                        *labels
+                            .get(target)
+                            .unwrap_or_else(|| panic!("jump to undefined label {}", target)),
+                    );
+                }
+                let falls_through = !matches!(inst, VInst::Jmp(_) | VInst::Ret(_));
+                if falls_through && i + 1 < insts.len() {
+                    successors.push(i + 1);
+                }
+                successors
+            })
+            .collect();
+
+        let mut live_in = vec![BTreeSet::new(); insts.len()];
+        let mut live_out = vec![BTreeSet::new(); insts.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for i in (0..insts.len()).rev() {
+                let out: BTreeSet<VReg> = successors[i]
+                    .iter()
+                    .flat_map(|&successor| live_in[successor].iter().copied())
+                    .collect();
+                let mut in_: BTreeSet<VReg> = out.clone();
+                if let Some(def) = insts[i].def() {
+                    in_.remove(&def);
+                }
+                in_.extend(insts[i].uses());
+                if in_ != live_in[i] || out != live_out[i] {
+                    live_in[i] = in_;
+                    live_out[i] = out;
+                    changed = true;
+                }
+            }
+        }
+
+        Self { live_in, live_out }
+    }
+
+    /// Registers live before instruction `index`.
+    pub fn live_in(&self, index: usize) -> &BTreeSet<VReg> {
+        &self.live_in[index]
+    }
+
+    /// Registers live after instruction `index`.
+    pub fn live_out(&self, index: usize) -> &BTreeSet<VReg> {
+        &self.live_out[index]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::x86::{function::Function, regalloc::VirtualFunction};
+
+    #[test]
+    fn loops_keep_values_live() {
+        let mut v = VirtualFunction::new(Function::new("f").returns());
+        let (count, value) = (v.reg(), v.reg());
+        v.push(VInst::MovImm(count, 10));
+        v.push(VInst::MovImm(value, 5));
+        v.push(VInst::Label("loop"));
+        v.push(VInst::Dec(count));
+        v.push(VInst::JumpIfNotZero(count, "loop"));
+        v.push(VInst::Ret(Some(value)));
+
+        let liveness = Liveness::compute(v.insts());
+        // The counter is read again after the back edge, and the value is
+        // live through the whole loop.
+        assert_eq!(liveness.live_out(4), &BTreeSet::from([count, value]));
+        assert_eq!(liveness.live_in(2), &BTreeSet::from([count, value]));
+        assert_eq!(liveness.live_in(5), &BTreeSet::from([value]));
+        assert_eq!(liveness.live_in(0), &BTreeSet::new());
+    }
+}
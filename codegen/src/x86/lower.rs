@@ -0,0 +1,141 @@
+//! Lowering of the [IR](crate::ir) to x86 instructions.
+//!
+//! Each IR value becomes a virtual register, and each operation one or two
+//! [`VInst`]s; the result is then register-allocated and emitted by
+//! [`VirtualFunction::emit`].
+
+use crate::ir::{self, BinOp, Op, Terminator, Value};
+
+use super::{
+    function::Function,
+    regalloc::{VInst, VReg, VirtualFunction},
+    Assembler,
+};
+
+/// Lower an IR function and emit it into `asm`.
+pub fn emit<'a>(function: &ir::Function<'a>, asm: &mut Assembler) {
+    lower(function).emit(asm);
+}
+
+/// Lower an IR function to virtual-register code.
+pub fn lower<'a>(function: &ir::Function<'a>) -> VirtualFunction<'a> {
+    function.verify();
+    let mut signature = Function::new(function.name()).params(function.param_count());
+    if function.is_exported() {
+        signature = signature.export();
+    }
+    if function.returns().is_some() {
+        signature = signature.returns();
+    }
+
+    let mut v = VirtualFunction::new(signature);
+    let regs: Vec<VReg> = (0..function.value_count()).map(|_| v.reg()).collect();
+    let reg = |value: Value| regs[value.index()];
+
+    for index in 0..function.param_count() {
+        v.push(VInst::Param(reg(function.param(index)), index));
+    }
+
+    // Constants are tracked so that they can be folded into immediates.
+    let mut constants = vec![None; function.value_count()];
+
+    let blocks = function.blocks();
+    for (index, block) in blocks.iter().enumerate() {
+        // The entry block's label is defined by the function prologue.
+        if index > 0 {
+            v.push(VInst::Label(block.label));
+        }
+        for inst in &block.insts {
+            let result = inst.result.map(reg);
+            match inst.op {
+                Op::Const(value) => {
+                    constants[inst.result.unwrap().index()] = Some(value);
+                    v.push(VInst::MovImm(result.unwrap(), value));
+                }
+                Op::Address(label) => v.push(VInst::Lea(result.unwrap(), label)),
+                Op::Binary(op, lhs, rhs) => {
+                    let dst = result.unwrap();
+                    v.push(VInst::Mov(dst, reg(lhs)));
+                    let constant = constants[rhs.index()];
+                    v.push(match (op, constant) {
+                        (BinOp::Shl, Some(amount)) => VInst::ShlImm(dst, amount as i8),
+                        (BinOp::Shr, Some(amount)) => VInst::ShrImm(dst, amount as i8),
+                        (BinOp::Shl | BinOp::Shr, None) => {
+                            panic!("shift amount must be a constant")
+                        }
+                        (BinOp::Add, Some(value)) if i32::try_from(value as i64).is_ok() => {
+                            VInst::AddImm(dst, value as i64 as i32)
+                        }
+                        (BinOp::Sub, Some(value)) if i32::try_from(-(value as i64)).is_ok() => {
+                            VInst::AddImm(dst, -(value as i64) as i32)
+                        }
+                        (BinOp::And, Some(value)) if i8::try_from(value as i64).is_ok() => {
+                            VInst::AndImm(dst, value as i64 as i8)
+                        }
+                        (BinOp::Add, _) => VInst::Add(dst, reg(rhs)),
+                        (BinOp::Sub, _) => VInst::Sub(dst, reg(rhs)),
+                        (BinOp::And, _) => VInst::And(dst, reg(rhs)),
+                        (BinOp::Or, _) => VInst::Or(dst, reg(rhs)),
+                        (BinOp::Xor, _) => VInst::Xor(dst, reg(rhs)),
+                    });
+                }
+                Op::Load(ptr, offset) => v.push(VInst::Load(result.unwrap(), reg(ptr), offset)),
+                Op::LoadByte(ptr, index) => {
+                    v.push(VInst::LoadByte(result.unwrap(), reg(ptr), reg(index)))
+                }
+                Op::Store(ptr, offset, value) => v.push(VInst::Store(reg(ptr), offset, reg(value))),
+                Op::Call(target, ref args) => {
+                    v.push(VInst::Call(
+                        target,
+                        args.iter().copied().map(reg).collect(),
+                        result,
+                    ));
+                }
+            }
+        }
+
+        let next = blocks.get(index + 1).map(|block| block.label);
+        match block.terminator.as_ref().unwrap() {
+            Terminator::Jump(target, args) => {
+                let params = &function.block_data(*target).params;
+                // Block arguments are copied in parallel, so go through
+                // temporaries if an argument is also one of the parameters.
+                let overlapping = args.iter().any(|arg| params.contains(arg));
+                let sources: Vec<VReg> = if overlapping {
+                    args.iter()
+                        .map(|&arg| {
+                            let temporary = v.reg();
+                            v.push(VInst::Mov(temporary, reg(arg)));
+                            temporary
+                        })
+                        .collect()
+                } else {
+                    args.iter().copied().map(reg).collect()
+                };
+                for (&param, source) in params.iter().zip(sources) {
+                    if reg(param) != source {
+                        v.push(VInst::Mov(reg(param), source));
+                    }
+                }
+                let label = function.block_data(*target).label;
+                if next != Some(label) {
+                    v.push(VInst::Jmp(label));
+                }
+            }
+            Terminator::Branch(condition, nonzero, zero) => {
+                let nonzero = function.block_data(*nonzero).label;
+                let zero = function.block_data(*zero).label;
+                if next == Some(nonzero) {
+                    v.push(VInst::JumpIfZero(reg(*condition), zero));
+                } else {
+                    v.push(VInst::JumpIfNotZero(reg(*condition), nonzero));
+                    if next != Some(zero) {
+                        v.push(VInst::Jmp(zero));
+                    }
+                }
+            }
+            Terminator::Return(value) => v.push(VInst::Ret(value.map(reg))),
+        }
+    }
+    v
+}
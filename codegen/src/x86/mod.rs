@@ -1,38 +1,369 @@
+#[cfg(feature = "kernel")]
+pub mod acpi;
 pub mod address;
+#[cfg(feature = "kernel")]
+pub mod apic;
+#[cfg(feature = "kernel")]
+pub mod backtrace;
+#[cfg(feature = "kernel")]
+pub mod control;
+#[cfg(feature = "kernel")]
+pub mod convention;
+#[cfg(feature = "kernel")]
+pub mod cpuid;
+#[cfg(feature = "kernel")]
+pub mod debug_exit;
+pub mod decode;
+pub mod descriptor;
+#[cfg(all(test, feature = "differential"))]
+mod differential;
+#[cfg(feature = "image")]
+pub mod emulator;
+#[cfg(feature = "kernel")]
+pub mod format;
+#[cfg(feature = "kernel")]
+pub mod frame;
+#[cfg(feature = "kernel")]
+pub mod function;
+#[cfg(feature = "kernel")]
+pub mod global;
+#[cfg(feature = "kernel")]
+pub mod hpet;
 pub mod instruction;
+#[cfg(feature = "kernel")]
+pub mod interrupt;
+#[cfg(feature = "kernel")]
+pub mod intrinsics;
+#[cfg(feature = "kernel")]
+pub mod ioapic;
+#[cfg(feature = "kernel")]
+pub mod liveness;
+#[cfg(feature = "kernel")]
+pub mod lower;
+#[cfg(feature = "kernel")]
+pub mod msr;
+#[cfg(feature = "kernel")]
+pub mod paging;
+#[cfg(feature = "kernel")]
+pub mod panic;
+pub mod parse;
+#[cfg(feature = "kernel")]
+pub mod percpu;
+#[cfg(feature = "kernel")]
+pub mod pic;
+#[cfg(feature = "kernel")]
+pub mod pit;
+#[cfg(feature = "kernel")]
+pub mod regalloc;
 pub mod register;
+#[cfg(feature = "kernel")]
+pub mod spinlock;
+#[cfg(feature = "kernel")]
+pub mod syscall;
+#[cfg(feature = "kernel")]
+pub mod vga;
 
-use self::instruction::Instruction;
-use crate::link::Segment;
+use self::{
+    instruction::{Instruction, InstructionBuilder, Mode, MAX_INSTRUCTION_LENGTH},
+    register::R64,
+};
+use crate::{
+    asm::{Arch, AssemblerCore},
+    link::{Label, Ptr, ReferenceFormat, Segment},
+};
+use std::ops::RangeInclusive;
 
-pub struct Assembler<'a> {
-    segment: Segment<'a>,
+pub use alpha_codegen_macros::asm_block;
+
+/// The x86-64 instruction set, for the [assembler core](AssemblerCore).
+pub struct X86;
+
+impl Arch for X86 {
+    type Encoding = InstructionBuilder;
+
+    const NOP: u8 = 0x90;
+    const MAX_INSTRUCTION_LENGTH: usize = MAX_INSTRUCTION_LENGTH;
+    const INSTRUCTION_ALIGNMENT: usize = 1;
+    const SHORT_BRANCH_LEN: usize = 2;
+    const SHORT_BRANCH_RANGE: RangeInclusive<i64> = i8::MIN as i64..=i8::MAX as i64;
+
+    /// `JMP rel32` and `Jcc rel32`, or their `rel16` forms in real mode,
+    /// have 2-byte `rel8` forms.
+    fn short_branch(encoding: &[u8], format: ReferenceFormat) -> Option<u8> {
+        match (encoding, format) {
+            ([0xe9, _, _, _, _], ReferenceFormat::Rel32)
+            | ([0xe9, _, _], ReferenceFormat::Rel16) => Some(0xeb),
+            ([0x0f, opcode @ 0x80..=0x8f, _, _, _, _], ReferenceFormat::Rel32)
+            | ([0x0f, opcode @ 0x80..=0x8f, _, _], ReferenceFormat::Rel16) => {
+                Some(0x70 | (opcode & 0xf))
+            }
+            _ => None,
+        }
+    }
+
+    fn encode_short_branch(opcode: u8, displacement: i64, out: &mut Vec<u8>) {
+        out.extend([opcode, displacement as i8 as u8]);
+    }
 }
 
-impl<'a> Assembler<'a> {
+/// Something that instructions and labels can be emitted into.
+pub trait Emitter {
+    fn push<I>(&mut self, instruction: I)
+    where
+        I: Instruction;
+
+    fn label(&mut self, label: impl Into<Label>);
+
+    /// Generate a unique local label, for generated code that has no
+    /// meaningful name for it.
+    fn fresh_label(&mut self, prefix: &str) -> Label;
+
+    fn pad_to_alignment(&mut self, alignment: usize);
+
+    /// Append the address of `label` as data, e.g. for a jump table.
+    fn append_reference(&mut self, label: impl Into<Label>, format: ReferenceFormat);
+
+    /// Attach a one-line comment to the next instruction, for listings of
+    /// the linked image.
+    fn comment(&mut self, text: &str);
+}
+
+pub struct Assembler {
+    core: AssemblerCore<X86>,
+    /// General-purpose registers named by the instructions pushed so far.
+    used: Vec<R64>,
+    mode: Mode,
+    apx: bool,
+}
+
+impl Assembler {
     pub fn new() -> Self {
         Self {
-            segment: Segment::new(),
+            core: AssemblerCore::new(),
+            used: Vec::new(),
+            mode: Mode::Long,
+            apx: false,
         }
     }
 
-    pub fn label(&mut self, label: &'a str) {
-        self.segment.label(label);
+    /// Encode the instructions pushed from now on for `mode`, e.g. for the
+    /// real-mode and protected-mode stages of a startup trampoline.
+    /// Instructions that can not be encoded in it panic.
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+    }
+
+    /// Allow instructions with the APX registers of
+    /// [`Egpr`](register::Egpr), which are encoded with a REX2 prefix and
+    /// fault on processors without APX. They panic otherwise.
+    pub fn set_apx(&mut self, apx: bool) {
+        self.apx = apx;
+    }
+
+    /// Shorten near jumps and conditional jumps to labels in the same
+    /// segment to their 2-byte forms in [`finish`](Self::finish), wherever
+    /// the target is in range.
+    ///
+    /// Code must not depend on the size of the code between its labels,
+    /// other than through alignment padding.
+    pub fn set_relax_branches(&mut self, relax: bool) {
+        self.core.set_relax_branches(relax);
+    }
+
+    /// Generate a unique local label, of the form `prefix.N`.
+    pub fn fresh_label(&mut self, prefix: &str) -> Label {
+        self.core.fresh_label(prefix)
+    }
+
+    pub fn label(&mut self, label: impl Into<Label>) {
+        self.core.label(label);
+    }
+
+    /// A pointer to a 64-bit constant in the
+    /// [constant pool](Self::constant_pool), e.g. for `MOV r64, [rip+x]`
+    /// in place of a 10-byte `MOV r64, imm64`.
+    pub fn const_u64(&mut self, value: u64) -> Ptr {
+        self.core.const_u64(value)
+    }
+
+    /// A pointer to a copy of `bytes` in the
+    /// [constant pool](Self::constant_pool).
+    pub fn const_bytes(&mut self, bytes: &[u8]) -> Ptr {
+        self.core.const_bytes(bytes)
+    }
+
+    /// Take the constants requested so far, as a segment of exported labels
+    /// to be embedded in read-only data.
+    pub fn constant_pool(&mut self) -> Segment {
+        self.core.constant_pool()
+    }
+
+    pub fn export_label(&mut self, label: impl Into<Label>) {
+        self.core.export_label(label);
+    }
+
+    /// Set the byte used to pad code in
+    /// [`pad_to_alignment`](Self::pad_to_alignment), e.g. `0xcc` (INT3) or
+    /// `0x90` (NOP).
+    pub fn set_fill(&mut self, fill: u8) {
+        self.core.set_fill(fill);
+    }
+
+    pub fn pad_to_alignment(&mut self, alignment: usize) {
+        self.core.pad_to_alignment(alignment);
+    }
+
+    pub fn append_reference(&mut self, label: impl Into<Label>, format: ReferenceFormat) {
+        self.core.append_reference(label, format);
+    }
+
+    /// See [`Segment::comment`].
+    pub fn comment(&mut self, text: &str) {
+        self.core.comment(text);
     }
 
+    /// Append data to the code stream. It must not be reachable by
+    /// execution, e.g. by following an unconditional jump.
+    pub fn append(&mut self, bytes: &[u8]) {
+        self.core.append(bytes);
+    }
+
+    /// Make room for `count` more instructions of any length, so that a
+    /// large generated routine is encoded without reallocating.
+    pub fn reserve_instructions(&mut self, count: usize) {
+        self.core.reserve_instructions(count);
+    }
+
+    /// Append an instruction. It is attributed to the source location of
+    /// the caller in debugging information.
+    #[track_caller]
     pub fn push<I>(&mut self, instruction: I)
     where
-        I: Instruction<'a>,
+        I: Instruction,
     {
-        let encoded = instruction.encode();
-        for (label, reference) in encoded.references() {
-            self.segment
-                .offset_reference(reference.location, label.0, reference.format);
+        let encoded = instruction.encode().for_mode(self.mode);
+        assert!(
+            self.apx || !encoded.rex2(),
+            "R16-R31 can not be encoded without APX enabled"
+        );
+        for &register in encoded.registers() {
+            if !self.used.contains(&register) {
+                self.used.push(register);
+            }
         }
-        self.segment.extend(encoded.serialize());
+        self.core.push(&encoded);
     }
 
-    pub fn finish(self) -> Segment<'a> {
-        self.segment
+    /// The general-purpose registers named as operands by the instructions
+    /// pushed so far, in order of first use. Registers used implicitly, like
+    /// RCX by `REP`, are not included.
+    pub fn used_registers(&self) -> &[R64] {
+        &self.used
+    }
+
+    /// Append the code of another assembler, which execution falls through
+    /// into. If it requires alignment, the padding is NOPs.
+    pub fn embed(&mut self, other: Assembler) {
+        for register in other.used {
+            if !self.used.contains(&register) {
+                self.used.push(register);
+            }
+        }
+        self.core.embed(other.core);
+    }
+
+    pub fn finish(self) -> Segment {
+        self.core.finish()
+    }
+}
+
+impl Emitter for Assembler {
+    #[track_caller]
+    fn push<I>(&mut self, instruction: I)
+    where
+        I: Instruction,
+    {
+        Assembler::push(self, instruction);
+    }
+
+    fn label(&mut self, label: impl Into<Label>) {
+        Assembler::label(self, label);
+    }
+
+    fn fresh_label(&mut self, prefix: &str) -> Label {
+        Assembler::fresh_label(self, prefix)
+    }
+
+    fn pad_to_alignment(&mut self, alignment: usize) {
+        Assembler::pad_to_alignment(self, alignment);
+    }
+
+    fn append_reference(&mut self, label: impl Into<Label>, format: ReferenceFormat) {
+        Assembler::append_reference(self, label, format);
+    }
+
+    fn comment(&mut self, text: &str) {
+        Assembler::comment(self, text);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        address::{GsIndex, Index, Indirect, ScaledIndex, Times4},
+        instruction::*,
+        register::{R64::*, R8::*},
+        *,
+    };
+    use crate::link::Label;
+
+    /// Emit through the trait, as generic code would.
+    fn epilogue<E: Emitter>(asm: &mut E, done: &'static str) {
+        asm_block!(asm;
+            {done}: pop rbp;
+            ret;
+        );
+    }
+
+    #[test]
+    fn asm_block_matches_builder() {
+        let offset = 16;
+        let mut expected = Assembler::new();
+        expected.push(PUSH(RBP));
+        expected.push(MOV(RAX, Index(RBP, -8i8)));
+        expected.push(MOV(Index(RDI, offset + 8), RAX));
+        expected.push(MOV(RDX, ScaledIndex(Times4, RSI, RDI)));
+        expected.push(MOV(AL, Index(RSI, RDI)));
+        expected.push(MOV(RCX, GsIndex(8)));
+        expected.push(MOV(Indirect(RDI), 0x20u8));
+        expected.label("loop");
+        expected.push(SUB(RCX, 1i8));
+        expected.push(JCC(Condition::Above, Label("loop")));
+        expected.push(JNZ(Label("loop")));
+        expected.push(REP(STOSB));
+        expected.push(LEA(RSI, Ptr("message")));
+        expected.label("done");
+        expected.push(POP(RBP));
+        expected.push(RET);
+
+        let mut asm = Assembler::new();
+        asm_block!(asm;
+            push rbp;
+            mov rax, [rbp - 8i8];
+            mov [rdi + {offset + 8}], rax;
+            mov rdx, [rdi + rsi * 4];
+            mov al, [rdi + rsi];
+            mov rcx, [gs:8];
+            mov [rdi], 0x20u8;
+            "loop":
+            sub rcx, 1i8;
+            ja "loop";
+            jne "loop";
+            rep stosb;
+            lea rsi, ["message"];
+        );
+        epilogue(&mut asm, "done");
+
+        assert_eq!(asm.finish().bytes(), expected.finish().bytes());
     }
 }
@@ -0,0 +1,73 @@
+//! Model-specific registers, and helpers to read and write them.
+//!
+//! `RDMSR` and `WRMSR` take the MSR in ECX and its value split across
+//! EDX:EAX. [`read`] and [`write`] emit that setup around them, so that MSRs
+//! can be handled as 64-bit values in a single register.
+
+use super::{
+    instruction::*,
+    register::{R64, R64::*},
+    Emitter,
+};
+
+/// Time stamp counter.
+pub const TSC: u32 = 0x10;
+/// Local APIC base address and enable bits.
+pub const APIC_BASE: u32 = 0x1b;
+/// Page attribute table.
+pub const PAT: u32 = 0x277;
+/// Extended feature enable register.
+pub const EFER: u32 = 0xc000_0080;
+/// Segment selectors loaded by `SYSCALL` and `SYSRET`.
+pub const STAR: u32 = 0xc000_0081;
+/// Target of `SYSCALL` in 64-bit mode.
+pub const LSTAR: u32 = 0xc000_0082;
+/// Target of `SYSCALL` in compatibility mode.
+pub const CSTAR: u32 = 0xc000_0083;
+/// RFLAGS bits cleared by `SYSCALL`.
+pub const FMASK: u32 = 0xc000_0084;
+pub const FS_BASE: u32 = 0xc000_0100;
+pub const GS_BASE: u32 = 0xc000_0101;
+/// Swapped with [`GS_BASE`] by `SWAPGS`.
+pub const KERNEL_GS_BASE: u32 = 0xc000_0102;
+/// Value returned in ECX by `RDTSCP`.
+pub const TSC_AUX: u32 = 0xc000_0103;
+
+/// Enables `SYSCALL` and `SYSRET`.
+pub const EFER_SCE: u64 = 1 << 0;
+/// Enables long mode.
+pub const EFER_LME: u64 = 1 << 8;
+/// Set by the processor while long mode is active.
+pub const EFER_LMA: u64 = 1 << 10;
+/// Enables the no-execute bit in page table entries.
+pub const EFER_NXE: u64 = 1 << 11;
+
+/// Set on the bootstrap processor.
+pub const APIC_BASE_BSP: u64 = 1 << 8;
+/// Enables x2APIC mode.
+pub const APIC_BASE_X2APIC: u64 = 1 << 10;
+/// Global enable of the local APIC.
+pub const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+/// Read `msr` into `dst`, clobbering RAX, RCX and RDX.
+pub fn read(asm: &mut impl Emitter, msr: u32, dst: R64) {
+    asm.push(MOV(RCX, msr as u64));
+    asm.push(RDMSR);
+    asm.push(SHL(RDX, 32i8));
+    asm.push(OR(RAX, RDX));
+    if dst != RAX {
+        asm.push(MOV(dst, RAX));
+    }
+}
+
+/// Write the value in `src` to `msr`, clobbering RAX, RCX and RDX. `src` may
+/// be any of them.
+pub fn write(asm: &mut impl Emitter, msr: u32, src: R64) {
+    if src != RAX {
+        asm.push(MOV(RAX, src));
+    }
+    asm.push(MOV(RDX, RAX));
+    asm.push(SHR(RDX, 32i8));
+    asm.push(MOV(RCX, msr as u64));
+    asm.push(WRMSR);
+}
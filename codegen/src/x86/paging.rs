@@ -0,0 +1,278 @@
+//! A builder for 4-level page tables, emitted at build time.
+//!
+//! Mappings are declared as ranges of virtual and physical addresses, and
+//! the PML4, PDPTs, PDs and PTs that they need are emitted into a segment,
+//! each aligned to a page. Entries pointing to lower-level tables hold the
+//! physical addresses of those tables, which are filled in by the linker
+//! with [`ReferenceFormat::Phys64`], so the segment can be loaded anywhere
+//! in physical memory and the tables used as-is by loading `CR3` with the
+//! physical address of the PML4.
+
+use crate::link::{Label, ReferenceFormat, Segment};
+
+pub const PAGE_PRESENT: u64 = 1 << 0;
+pub const PAGE_WRITABLE: u64 = 1 << 1;
+/// Accessible from user mode.
+pub const PAGE_USER: u64 = 1 << 2;
+pub const PAGE_WRITE_THROUGH: u64 = 1 << 3;
+pub const PAGE_CACHE_DISABLE: u64 = 1 << 4;
+/// Maps a 2MiB or 1GiB page in a PD or PDPT, instead of pointing to a table.
+pub const PAGE_HUGE: u64 = 1 << 7;
+/// Not flushed from the TLB when `CR3` is written, if `CR4.PGE` is set.
+pub const PAGE_GLOBAL: u64 = 1 << 8;
+/// Instruction fetches are not allowed, if `EFER.NXE` is set.
+pub const PAGE_NO_EXECUTE: u64 = 1 << 63;
+
+/// Flags of a leaf entry that can be given to
+/// [`map`](PageTableBuilder::map).
+const LEAF_FLAGS: u64 = PAGE_WRITABLE
+    | PAGE_USER
+    | PAGE_WRITE_THROUGH
+    | PAGE_CACHE_DISABLE
+    | PAGE_GLOBAL
+    | PAGE_NO_EXECUTE;
+
+/// Flags of a leaf entry that must also be allowed by the entries of the
+/// tables above it.
+const INHERITED_FLAGS: u64 = PAGE_WRITABLE | PAGE_USER;
+
+const ENTRIES: usize = 512;
+const TABLE_SIZE: usize = 8 * ENTRIES;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageSize {
+    /// A 4KiB page, mapped by a PT.
+    Size4K,
+    /// A 2MiB page, mapped by a PD.
+    Size2M,
+    /// A 1GiB page, mapped by a PDPT.
+    Size1G,
+}
+
+impl PageSize {
+    pub fn bytes(&self) -> u64 {
+        1 << self.shift()
+    }
+
+    /// The bit position of the virtual address bits that index the table
+    /// holding the leaf entry.
+    fn shift(&self) -> u32 {
+        match self {
+            Self::Size4K => 12,
+            Self::Size2M => 21,
+            Self::Size1G => 30,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Entry {
+    Empty,
+    /// A lower-level table, by index, and the flags of the entry.
+    Table(usize, u64),
+    /// The physical address and flags of a page.
+    Page(u64),
+}
+
+pub struct PageTableBuilder {
+    /// The PML4 first, then the lower-level tables in order of creation.
+    tables: Vec<Vec<Entry>>,
+}
+
+impl Default for PageTableBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PageTableBuilder {
+    pub fn new() -> Self {
+        Self {
+            tables: vec![vec![Entry::Empty; ENTRIES]],
+        }
+    }
+
+    /// Map `size` bytes of virtual memory at `virt` to physical memory at
+    /// `phys`, with pages of `page_size`. The addresses and size must be
+    /// multiples of the page size.
+    ///
+    /// `flags` are the `PAGE_*` flags of each page, apart from
+    /// [`PAGE_PRESENT`] and [`PAGE_HUGE`], which are set as needed.
+    /// Mappings may not overlap, unless they map the same pages identically.
+    pub fn map(&mut self, virt: u64, phys: u64, size: u64, flags: u64, page_size: PageSize) {
+        let bytes = page_size.bytes();
+        assert!(
+            virt.is_multiple_of(bytes) && phys.is_multiple_of(bytes) && size.is_multiple_of(bytes),
+            "mapping of {:#x} bytes from {:#x} to {:#x} is not aligned to {:?} pages",
+            size,
+            virt,
+            phys,
+            page_size
+        );
+        assert!(
+            flags & !LEAF_FLAGS == 0,
+            "unsupported page flags {:#x}",
+            flags & !LEAF_FLAGS
+        );
+        let leaf = match page_size {
+            PageSize::Size4K => PAGE_PRESENT | flags,
+            _ => PAGE_PRESENT | PAGE_HUGE | flags,
+        };
+        for offset in (0..size).step_by(bytes as usize) {
+            self.map_page(virt + offset, (phys + offset) | leaf, page_size);
+        }
+    }
+
+    fn map_page(&mut self, virt: u64, leaf: u64, page_size: PageSize) {
+        let mut table = 0;
+        // PML4, PDPT and PD indices, down to the table holding the page.
+        for shift in [39, 30, 21]
+            .into_iter()
+            .filter(|&shift| shift > page_size.shift())
+        {
+            let index = index(virt, shift);
+            table = match self.tables[table][index] {
+                Entry::Empty => {
+                    let next = self.tables.len();
+                    self.tables.push(vec![Entry::Empty; ENTRIES]);
+                    self.tables[table][index] =
+                        Entry::Table(next, PAGE_PRESENT | (leaf & INHERITED_FLAGS));
+                    next
+                }
+                Entry::Table(next, flags) => {
+                    self.tables[table][index] =
+                        Entry::Table(next, flags | (leaf & INHERITED_FLAGS));
+                    next
+                }
+                Entry::Page(_) => panic!("{:#x} is already mapped by a larger page", virt),
+            };
+        }
+        let entry = &mut self.tables[table][index(virt, page_size.shift())];
+        match *entry {
+            Entry::Empty => *entry = Entry::Page(leaf),
+            Entry::Page(existing) if existing == leaf => {}
+            Entry::Table(..) => panic!("{:#x} is already mapped by smaller pages", virt),
+            Entry::Page(_) => panic!("{:#x} is already mapped differently", virt),
+        }
+    }
+
+    /// Emit the tables into `segment`, with the PML4 first at the exported
+    /// label `pml4`.
+    pub fn emit(&self, segment: &mut Segment, pml4: impl Into<Label>) {
+        let pml4 = pml4.into();
+        let labels: Vec<Label> = (0..self.tables.len())
+            .map(|index| match index {
+                0 => pml4,
+                _ => Label(&format!("{}.{}", pml4, index)),
+            })
+            .collect();
+
+        segment.pad_to_alignment(TABLE_SIZE);
+        for (index, table) in self.tables.iter().enumerate() {
+            match index {
+                0 => segment.export_label(pml4),
+                _ => segment.label(labels[index]),
+            }
+            for entry in table {
+                match *entry {
+                    Entry::Empty => segment.append(&0u64),
+                    Entry::Table(index, flags) => {
+                        segment.reference(labels[index], ReferenceFormat::Phys64);
+                        segment.append(&flags);
+                    }
+                    Entry::Page(page) => segment.append(&page),
+                }
+            }
+        }
+    }
+}
+
+/// The index into the table at `shift` for the virtual address `virt`.
+fn index(virt: u64, shift: u32) -> usize {
+    (virt >> shift) as usize % ENTRIES
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        elf64::program::{PF_R, PF_W},
+        link::ElfLinker,
+    };
+
+    /// The entry at `index` of the table at `table` in `data`.
+    fn entry(data: &[u8], table: usize, index: usize) -> u64 {
+        let offset = table * TABLE_SIZE + index * 8;
+        u64::from_le_bytes(data[offset..][..8].try_into().unwrap())
+    }
+
+    #[test]
+    fn mappings_share_tables() {
+        let mut builder = PageTableBuilder::new();
+        builder.map(0x1000, 0x5000, 0x1000, PAGE_WRITABLE, PageSize::Size4K);
+        builder.map(0x20_0000, 0x40_0000, 0x20_0000, PAGE_USER, PageSize::Size2M);
+        // Mapping the same page again is allowed.
+        builder.map(0x1000, 0x5000, 0x1000, PAGE_WRITABLE, PageSize::Size4K);
+
+        // One PML4, PDPT and PD for both, and a PT for the 4KiB page.
+        assert_eq!(builder.tables.len(), 4);
+        let shared = PAGE_PRESENT | PAGE_WRITABLE | PAGE_USER;
+        assert!(matches!(builder.tables[0][0], Entry::Table(1, flags) if flags == shared));
+        assert!(matches!(builder.tables[1][0], Entry::Table(2, flags) if flags == shared));
+        assert!(matches!(
+            builder.tables[2][0],
+            Entry::Table(3, flags) if flags == PAGE_PRESENT | PAGE_WRITABLE
+        ));
+
+        let mut segment = Segment::new();
+        builder.emit(&mut segment, "pml4");
+        let mut linker = ElfLinker::new();
+        linker.set_base_address(0xffff_8000_0000_0000);
+        linker.set_physical_base(0x10_0000);
+        linker.add_segment("tables", PF_R | PF_W, 1 << 12, segment);
+        // The image needs an entry point, though it is never run.
+        linker.set_entry("pml4");
+        let linked = linker.finish();
+        let tables = &linked.segments()[0];
+        let phys = tables.header().p_paddr;
+        let data = tables.data();
+
+        assert_eq!(linked.label_address("pml4"), Some(tables.header().p_vaddr));
+        assert_eq!(entry(data, 0, 0), (phys + 0x1000) | shared);
+        assert_eq!(entry(data, 1, 0), (phys + 0x2000) | shared);
+        assert_eq!(
+            entry(data, 2, 0),
+            (phys + 0x3000) | PAGE_PRESENT | PAGE_WRITABLE
+        );
+        assert_eq!(
+            entry(data, 2, 1),
+            0x40_0000 | PAGE_PRESENT | PAGE_HUGE | PAGE_USER
+        );
+        assert_eq!(entry(data, 3, 1), 0x5000 | PAGE_PRESENT | PAGE_WRITABLE);
+        assert_eq!(entry(data, 3, 0), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "0x1000 is already mapped differently")]
+    fn overlapping_mappings() {
+        let mut builder = PageTableBuilder::new();
+        builder.map(0x1000, 0x5000, 0x1000, 0, PageSize::Size4K);
+        builder.map(0x1000, 0x6000, 0x1000, 0, PageSize::Size4K);
+    }
+
+    #[test]
+    #[should_panic(expected = "0x1000 is already mapped by a larger page")]
+    fn inside_larger_page() {
+        let mut builder = PageTableBuilder::new();
+        builder.map(0, 0, 0x20_0000, 0, PageSize::Size2M);
+        builder.map(0x1000, 0x5000, 0x1000, 0, PageSize::Size4K);
+    }
+
+    #[test]
+    #[should_panic(expected = "0x0 is already mapped by smaller pages")]
+    fn over_smaller_pages() {
+        let mut builder = PageTableBuilder::new();
+        builder.map(0x1000, 0x5000, 0x1000, 0, PageSize::Size4K);
+        builder.map(0, 0, 0x20_0000, 0, PageSize::Size2M);
+    }
+}
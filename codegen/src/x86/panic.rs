@@ -0,0 +1,119 @@
+//! Runtime failure helpers, which report a message and halt.
+//!
+//! [`Panic::emit`] emits these exported routines, called with the System V
+//! ABI:
+//!
+//! - `panic(message)`: fail with a NUL-terminated message
+//! - `assert_eq(a, b)`: fail unless `a == b`
+//! - `assert_nonzero(value)`: fail if `value` is zero
+//!
+//! A failure prints `panic at 0x<address>: <message>` using the kernel's
+//! `print` and `tohex` routines, where the address is the return address of
+//! the call to the helper, followed by a backtrace if one was requested with
+//! [`Panic::backtrace`], and then jumps to the kernel's halt loop. The
+//! helpers never return on failure, so the call sites need no failure path
+//! of their own.
+
+use super::{
+    address::Indirect,
+    function::{Arg, Function},
+    instruction::*,
+    register::R64::*,
+    Assembler, Emitter,
+};
+use crate::link::{Label, Ptr};
+
+pub const PANIC: &str = "panic";
+pub const ASSERT_EQ: &str = "assert_eq";
+pub const ASSERT_NONZERO: &str = "assert_nonzero";
+
+/// Internal entry point taking the message and the failing address.
+const PANIC_AT: &str = "panic_at";
+
+pub struct Panic<'a> {
+    print: &'a str,
+    tohex: &'a str,
+    halt: &'a str,
+    backtrace: Option<&'a str>,
+}
+
+impl<'a> Panic<'a> {
+    /// Helpers that report failures with `print(string)` and
+    /// `tohex(value) -> string`, then jump to `halt`.
+    pub fn new(print: &'a str, tohex: &'a str, halt: &'a str) -> Self {
+        Self {
+            print,
+            tohex,
+            halt,
+            backtrace: None,
+        }
+    }
+
+    /// Print a backtrace after the report, with a routine emitted by
+    /// [`Backtrace`](super::backtrace::Backtrace). It starts at the failing
+    /// call.
+    pub fn backtrace(mut self, print_backtrace: &'a str) -> Self {
+        self.backtrace = Some(print_backtrace);
+        self
+    }
+
+    pub fn emit(&self, asm: &mut Assembler) {
+        self.emit_panic_at(asm);
+
+        // The helpers are naked, so the return address is on top of the
+        // stack, and jumping to `panic_at` leaves RSP as a call would.
+        let mut f = Function::new(PANIC).export().naked().begin(asm);
+        f.push(MOV(RSI, Indirect(RSP)));
+        f.push(JMP(Label(PANIC_AT)));
+        f.finish();
+
+        let mut f = Function::new(ASSERT_EQ).export().naked().begin(asm);
+        let fail = f.fresh_label("assert_eq_fail");
+        f.push(CMP(RDI, RSI));
+        f.push(JNZ(fail));
+        f.ret();
+        f.label(fail);
+        let message = f.const_bytes(b"assertion failed: values are not equal\0");
+        fail_with(&mut f, message);
+        f.finish();
+
+        let mut f = Function::new(ASSERT_NONZERO).export().naked().begin(asm);
+        let fail = f.fresh_label("assert_nonzero_fail");
+        f.push(TEST(RDI, RDI));
+        f.push(JZ(fail));
+        f.ret();
+        f.label(fail);
+        let message = f.const_bytes(b"assertion failed: value is zero\0");
+        fail_with(&mut f, message);
+        f.finish();
+    }
+
+    /// Emit `panic_at(message, address)`, which prints the report and halts.
+    fn emit_panic_at(&self, asm: &mut Assembler) {
+        let mut f = Function::new(PANIC_AT).params(2).begin(asm);
+        let prefix = f.const_bytes(b"panic at 0x\0");
+        let separator = f.const_bytes(b": \0");
+        let newline = f.const_bytes(b"\n\0");
+
+        f.push(MOV(RBX, f.param(0)));
+        f.push(MOV(R12, f.param(1)));
+        f.call_fn(self.print, &[Arg::Label(prefix.label())]);
+        f.call_fn(self.tohex, &[Arg::Reg(R12)]);
+        f.call_fn(self.print, &[Arg::Reg(RAX)]);
+        f.call_fn(self.print, &[Arg::Label(separator.label())]);
+        f.call_fn(self.print, &[Arg::Reg(RBX)]);
+        f.call_fn(self.print, &[Arg::Label(newline.label())]);
+        if let Some(print_backtrace) = self.backtrace {
+            f.call_fn(print_backtrace, &[]);
+        }
+        f.push(JMP(Label(self.halt)));
+        f.finish();
+    }
+}
+
+/// Jump to `panic_at` with `message`, from the entry of a naked helper.
+fn fail_with(f: &mut impl Emitter, message: Ptr) {
+    f.push(LEA(RDI, message));
+    f.push(MOV(RSI, Indirect(RSP)));
+    f.push(JMP(Label(PANIC_AT)));
+}
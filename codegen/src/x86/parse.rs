@@ -0,0 +1,1016 @@
+//! A frontend for Intel-syntax assembly text, for code that is easier to
+//! maintain as a `.s` file than as builder calls. Each section of the text
+//! is assembled by an [`Assembler`] into a [`Segment`], to be linked
+//! alongside the generated ones.
+//!
+//! There is one statement per line, after any number of `name:` labels, and
+//! `;` starts a comment:
+//!
+//! - `global name, ...` exports labels, wherever they are defined.
+//! - `section name` switches to the named section, which is created on
+//!   first use. Text before the first `section` is in `.text`.
+//! - `align n` pads to a multiple of `n` bytes.
+//! - `db`, `dw`, `dd` and `dq` append a list of numbers of their size.
+//!   `db` also takes strings, in double quotes, and the others take labels,
+//!   for their absolute addresses.
+//! - Anything else is an instruction, like `mov rax, qword ptr [rbp-8]`,
+//!   optionally after a `rep`, `repe`, `repne` or `lock` prefix. Memory
+//!   operands are `[label]`, `[base]`, `[base+disp]`, `[base+index*scale]`
+//!   or `[gs:offset]`, and branch targets are labels.
+//!
+//! Only the forms of instructions that the [encoder](super::instruction)
+//! implements are accepted, and anything else is an error that names the
+//! line.
+
+use super::{
+    address::{GsIndex, Index, Indirect, ScaledIndex, Times1, Times2, Times4, Times8},
+    instruction::*,
+    register::{Sreg, Xmm, R16, R32, R64, R8},
+    Assembler,
+};
+use crate::link::{Label, Ptr, ReferenceFormat, Segment};
+use std::{collections::HashSet, fmt::Debug, io};
+
+/// Assemble `text`, returning the segment of each of its sections, in order
+/// of first use.
+pub fn parse(text: &str) -> io::Result<Vec<(String, Segment)>> {
+    let lines: Vec<&str> = text.lines().map(strip_comment).collect();
+    let error = |line: usize, message: String| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("line {}: {}", line + 1, message),
+        )
+    };
+
+    let mut globals = HashSet::new();
+    for line in &lines {
+        if let Some(("global" | ".global" | ".globl", names)) = split_word(line) {
+            globals.extend(names.split(',').map(str::trim));
+        }
+    }
+
+    // Each section, with the labels defined in it, which must be unique
+    // within the section.
+    let mut sections: Vec<(String, Assembler, HashSet<&str>)> = Vec::new();
+    let mut current = None;
+    for (number, line) in lines.iter().enumerate() {
+        let mut rest = *line;
+        let mut labels = Vec::new();
+        while let Some((label, tail)) = split_label(rest) {
+            labels.push(label);
+            rest = tail;
+        }
+        let statement = split_word(rest);
+        if let Some(("section" | ".section", name)) = statement {
+            if name.is_empty() {
+                return Err(error(number, "section has no name".into()));
+            }
+            current = Some(
+                match sections.iter().position(|(section, ..)| section == name) {
+                    Some(index) => index,
+                    None => {
+                        sections.push((name.to_owned(), Assembler::new(), HashSet::new()));
+                        sections.len() - 1
+                    }
+                },
+            );
+        }
+        if labels.is_empty() && statement.is_none() {
+            continue;
+        }
+        let index = *current.get_or_insert_with(|| {
+            sections.push((".text".to_owned(), Assembler::new(), HashSet::new()));
+            sections.len() - 1
+        });
+        let (_, asm, defined) = &mut sections[index];
+        for label in labels {
+            if !defined.insert(label) {
+                return Err(error(
+                    number,
+                    format!("label `{}` is already defined", label),
+                ));
+            }
+            if globals.contains(label) {
+                asm.export_label(label);
+            } else {
+                asm.label(label);
+            }
+        }
+        if let Some((word, operands)) = statement {
+            statement_into(asm, &word.to_ascii_lowercase(), operands)
+                .map_err(|message| error(number, message))?;
+        }
+    }
+    Ok(sections
+        .into_iter()
+        .map(|(name, asm, _)| (name, asm.finish()))
+        .collect())
+}
+
+/// The line up to a `;` that is not in a string or character literal.
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), _) if c == q => quote = None,
+            (None, ';') => return line[..i].trim(),
+            _ => {}
+        }
+    }
+    line.trim()
+}
+
+/// The first word of a non-empty statement, and the rest.
+fn split_word(statement: &str) -> Option<(&str, &str)> {
+    let statement = statement.trim();
+    if statement.is_empty() {
+        return None;
+    }
+    Some(match statement.split_once(char::is_whitespace) {
+        Some((word, rest)) => (word, rest.trim()),
+        None => (statement, ""),
+    })
+}
+
+/// A `name:` label at the start of a line, and the rest.
+fn split_label(line: &str) -> Option<(&str, &str)> {
+    let (label, rest) = line.split_once(':')?;
+    is_identifier(label).then(|| (label, rest.trim()))
+}
+
+fn is_identifier(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || matches!(c, '_' | '.'))
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '$'))
+}
+
+/// Split operands at the commas outside of brackets and literals.
+fn split_operands(operands: &str) -> Vec<&str> {
+    if operands.is_empty() {
+        return Vec::new();
+    }
+    let mut parts = Vec::new();
+    let (mut start, mut depth, mut quote) = (0, 0, None);
+    for (i, c) in operands.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), _) if c == q => quote = None,
+            (None, '[') => depth += 1,
+            (None, ']') => depth -= 1,
+            (None, ',') if depth == 0 => {
+                parts.push(operands[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(operands[start..].trim());
+    parts
+}
+
+/// A decimal, `0x` hexadecimal or `0b` binary number, optionally negative,
+/// or a character in single quotes.
+fn number(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits.trim_start()),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16).ok()? as i64
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        u64::from_str_radix(binary, 2).ok()? as i64
+    } else if let Some(character) = digits.strip_prefix('\'').and_then(|c| c.strip_suffix('\'')) {
+        let mut chars = character.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) if c.is_ascii() => c as i64,
+            _ => return None,
+        }
+    } else {
+        digits.parse().ok()?
+    };
+    Some(if negative {
+        value.wrapping_neg()
+    } else {
+        value
+    })
+}
+
+/// The bytes of a string in double quotes, with `\n`, `\t`, `\0`, `\\`
+/// and `\"` escapes.
+fn string(text: &str) -> Option<Vec<u8>> {
+    let text = text.strip_prefix('"')?.strip_suffix('"')?;
+    let mut bytes = Vec::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '\\' => match chars.next()? {
+                'n' => '\n',
+                't' => '\t',
+                '0' => '\0',
+                c @ ('\\' | '"') => c,
+                _ => return None,
+            },
+            c => c,
+        };
+        let mut buffer = [0; 4];
+        bytes.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+    }
+    Some(bytes)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Size {
+    Byte,
+    Word,
+    Dword,
+    Qword,
+    Xmmword,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Address {
+    Ptr(Ptr),
+    /// A base register and a displacement.
+    Base(R64, i64),
+    /// An index register, a scale and a base register.
+    Index(R64, u8, R64),
+    Gs(usize),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Memory {
+    size: Option<Size>,
+    address: Address,
+}
+
+impl Memory {
+    fn ptr(&self) -> Option<Ptr> {
+        match self.address {
+            Address::Ptr(ptr) => Some(ptr),
+            _ => None,
+        }
+    }
+
+    fn indirect(&self) -> Option<Indirect<R64>> {
+        match self.address {
+            Address::Base(base, 0) => Some(Indirect(base)),
+            _ => None,
+        }
+    }
+
+    fn disp8(&self) -> Option<Index<R64, i8>> {
+        match self.address {
+            Address::Base(base, displacement) => Some(Index(base, displacement.try_into().ok()?)),
+            _ => None,
+        }
+    }
+
+    fn disp32(&self) -> Option<Index<R64, i32>> {
+        match self.address {
+            Address::Base(base, displacement) => Some(Index(base, displacement.try_into().ok()?)),
+            _ => None,
+        }
+    }
+
+    fn index(&self) -> Option<Index<R64, R64>> {
+        match self.address {
+            Address::Index(index, 1, base) => Some(Index(index, base)),
+            _ => None,
+        }
+    }
+
+    fn gs(&self) -> Option<GsIndex> {
+        match self.address {
+            Address::Gs(offset) => Some(GsIndex(offset)),
+            _ => None,
+        }
+    }
+
+    /// Whether the size is given as `size`, which memory operands with an
+    /// immediate need.
+    fn sized(&self, size: Size) -> bool {
+        self.size == Some(size)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Operand {
+    R64(R64),
+    R32(R32),
+    R16(R16),
+    R8(R8),
+    Xmm(Xmm),
+    Sreg(Sreg),
+    Imm(i64),
+    Label(Label),
+    Memory(Memory),
+}
+
+/// The register named `name`, in any case.
+fn register(name: &str) -> Option<Operand> {
+    fn find<R: Debug>(name: &str, registers: impl IntoIterator<Item = R>) -> Option<R> {
+        registers
+            .into_iter()
+            .find(|register| format!("{:?}", register).eq_ignore_ascii_case(name))
+    }
+    None.or_else(|| find(name, (0..16).map(R64::from_code)).map(Operand::R64))
+        .or_else(|| find(name, (0..16).map(R32::from_code)).map(Operand::R32))
+        .or_else(|| find(name, (0..16).map(R16::from_code)).map(Operand::R16))
+        .or_else(|| find(name, (0..16).map(R8::from_code)).map(Operand::R8))
+        .or_else(|| find(name, (0..16).map(Xmm::from_code)).map(Operand::Xmm))
+        .or_else(|| {
+            use Sreg::*;
+            find(name, [ES, CS, SS, DS, FS, GS]).map(Operand::Sreg)
+        })
+}
+
+fn operand(text: &str) -> Result<Operand, String> {
+    if let Some(register) = register(text) {
+        return Ok(register);
+    }
+    if let Some(value) = number(text) {
+        return Ok(Operand::Imm(value));
+    }
+    if is_identifier(text) {
+        return Ok(Operand::Label(Label(text)));
+    }
+    memory(text).map(Operand::Memory)
+}
+
+fn memory(text: &str) -> Result<Memory, String> {
+    let invalid = || format!("invalid operand `{}`", text);
+    let (size, address) = match text.split_once("ptr") {
+        Some((size, address)) => {
+            let size = match size.trim().to_ascii_lowercase().as_str() {
+                "byte" => Size::Byte,
+                "word" => Size::Word,
+                "dword" => Size::Dword,
+                "qword" => Size::Qword,
+                "xmmword" => Size::Xmmword,
+                _ => return Err(invalid()),
+            };
+            (Some(size), address.trim())
+        }
+        None => (None, text),
+    };
+    let inner = address
+        .strip_prefix('[')
+        .and_then(|address| address.strip_suffix(']'))
+        .ok_or_else(invalid)?
+        .trim();
+    if let Some(offset) = inner.strip_prefix("gs:") {
+        let offset = number(offset.trim())
+            .and_then(|offset| usize::try_from(offset).ok())
+            .ok_or_else(invalid)?;
+        return Ok(Memory {
+            size,
+            address: Address::Gs(offset),
+        });
+    }
+    if is_identifier(inner) && register(inner).is_none() {
+        return Ok(Memory {
+            size,
+            address: Address::Ptr(Ptr(inner)),
+        });
+    }
+
+    // Terms of a sum of registers, scaled registers and numbers.
+    let mut registers = Vec::new();
+    let mut displacement = 0i64;
+    let mut rest = inner;
+    let mut negative = false;
+    loop {
+        let end = rest.find(['+', '-']).unwrap_or(rest.len());
+        let term = rest[..end].trim();
+        let scaled = term
+            .split_once('*')
+            .map(|(register, scale)| (register.trim(), scale.trim()));
+        match (register(term), scaled, number(term)) {
+            (Some(Operand::R64(register)), _, _) if !negative => registers.push((register, 1)),
+            (_, Some((name, scale)), _) if !negative => match (register(name), number(scale)) {
+                (Some(Operand::R64(register)), Some(scale @ (1 | 2 | 4 | 8))) => {
+                    registers.push((register, scale as u8))
+                }
+                _ => return Err(invalid()),
+            },
+            (None, None, Some(value)) => {
+                displacement = if negative {
+                    displacement.checked_sub(value)
+                } else {
+                    displacement.checked_add(value)
+                }
+                .ok_or_else(invalid)?;
+            }
+            _ => return Err(invalid()),
+        }
+        if end == rest.len() {
+            break;
+        }
+        negative = rest[end..].starts_with('-');
+        rest = &rest[end + 1..];
+    }
+    let address = match registers.as_slice() {
+        &[(base, 1)] => Address::Base(base, displacement),
+        &[(base, 1), (index, scale)] | &[(index, scale), (base, 1)] if displacement == 0 => {
+            Address::Index(index, scale, base)
+        }
+        _ => return Err(invalid()),
+    };
+    // RSP can't be encoded as an index, but can swap with an unscaled base.
+    let address = match address {
+        Address::Index(R64::RSP, 1, base) if base != R64::RSP => Address::Index(base, 1, R64::RSP),
+        Address::Index(R64::RSP, ..) => {
+            return Err(format!(
+                "RSP can not be used as an index register in `{}`",
+                text
+            ))
+        }
+        address => address,
+    };
+    Ok(Memory { size, address })
+}
+
+/// Assemble a directive or an instruction.
+fn statement_into(asm: &mut Assembler, word: &str, operands: &str) -> Result<(), String> {
+    let parts = split_operands(operands);
+    match word {
+        "global" | ".global" | ".globl" | "section" | ".section" => Ok(()),
+        "align" | ".align" => match parts.as_slice() {
+            [alignment] => match number(alignment) {
+                Some(alignment @ 1..) if (alignment as u64).is_power_of_two() => {
+                    asm.pad_to_alignment(alignment as usize);
+                    Ok(())
+                }
+                _ => Err(format!("invalid alignment `{}`", alignment)),
+            },
+            _ => Err("align takes one operand".into()),
+        },
+        "db" => {
+            for part in parts {
+                let bytes = match (string(part), number(part)) {
+                    (Some(bytes), _) => bytes,
+                    (None, Some(value @ -0x80..=0xff)) => vec![value as u8],
+                    _ => return Err(format!("invalid byte `{}`", part)),
+                };
+                asm.append(&bytes);
+            }
+            Ok(())
+        }
+        "dw" => data(asm, &parts, 2, ReferenceFormat::Abs16),
+        "dd" => data(asm, &parts, 4, ReferenceFormat::Abs32),
+        "dq" => data(asm, &parts, 8, ReferenceFormat::Abs64),
+        "rep" | "repe" | "repz" | "repne" | "repnz" | "lock" => {
+            let (inner, operands) =
+                split_word(operands).ok_or_else(|| format!("{} needs an instruction", word))?;
+            let operands = split_operands(operands)
+                .into_iter()
+                .map(operand)
+                .collect::<Result<Vec<_>, _>>()?;
+            prefixed(asm, word, &inner.to_ascii_lowercase(), &operands)
+        }
+        _ => {
+            let operands = parts
+                .into_iter()
+                .map(operand)
+                .collect::<Result<Vec<_>, _>>()?;
+            instruction(asm, word, &operands)
+        }
+    }
+}
+
+/// Append numbers or the addresses of labels of `size` bytes.
+fn data(
+    asm: &mut Assembler,
+    parts: &[&str],
+    size: usize,
+    format: ReferenceFormat,
+) -> Result<(), String> {
+    for &part in parts {
+        if let Some(value) = number(part) {
+            let bits = 8 * size as u32;
+            if size < 8 && !(-(1 << (bits - 1))..1 << bits).contains(&value) {
+                return Err(format!("{} does not fit in {} bytes", part, size));
+            }
+            asm.append(&value.to_le_bytes()[..size]);
+        } else if is_identifier(part) {
+            asm.append_reference(part, format);
+        } else {
+            return Err(format!("invalid data `{}`", part));
+        }
+    }
+    Ok(())
+}
+
+fn condition(mnemonic: &str) -> Option<Condition> {
+    use Condition::*;
+    Some(match mnemonic.strip_prefix('j')? {
+        "o" => Overflow,
+        "no" => NotOverflow,
+        "b" | "c" | "nae" => Below,
+        "ae" | "nb" | "nc" => AboveOrEqual,
+        "e" | "z" => Zero,
+        "ne" | "nz" => NotZero,
+        "be" | "na" => BelowOrEqual,
+        "a" | "nbe" => Above,
+        "s" => Sign,
+        "ns" => NotSign,
+        "p" | "pe" => Parity,
+        "np" | "po" => NotParity,
+        "l" | "nge" => Less,
+        "ge" | "nl" => GreaterOrEqual,
+        "le" | "ng" => LessOrEqual,
+        "g" | "nle" => Greater,
+        _ => return None,
+    })
+}
+
+fn byte(value: i64) -> Option<u8> {
+    (-0x80..=0xff).contains(&value).then_some(value as u8)
+}
+
+fn prefixed(
+    asm: &mut Assembler,
+    prefix: &str,
+    mnemonic: &str,
+    operands: &[Operand],
+) -> Result<(), String> {
+    use Operand::*;
+    match (prefix, mnemonic, operands) {
+        ("rep", "movsb", []) => asm.push(REP(MOVSB)),
+        ("rep", "stosb", []) => asm.push(REP(STOSB)),
+        ("rep", "stosw", []) => asm.push(REP(STOSW)),
+        ("repe" | "repz", "cmpsb", []) => asm.push(REPE(CMPSB)),
+        ("repne" | "repnz", "scasb", []) => asm.push(REPNE(SCASB)),
+        ("lock", "xchg", [Memory(m), R8(r)]) if m.ptr().is_some() => {
+            asm.push(LOCK(XCHG(m.ptr().unwrap(), *r)))
+        }
+        ("lock", "cmpxchg", [Memory(m), R8(r)]) if m.ptr().is_some() => {
+            asm.push(LOCK(CMPXCHG(m.ptr().unwrap(), *r)))
+        }
+        _ => return Err(format!("unsupported instruction `{} {}`", prefix, mnemonic)),
+    }
+    Ok(())
+}
+
+/// Push the instruction of the first form that matches the operands.
+fn instruction(asm: &mut Assembler, mnemonic: &str, operands: &[Operand]) -> Result<(), String> {
+    use Operand::*;
+    let unsupported = || {
+        Err(format!(
+            "unsupported operands for `{}`: {:?}",
+            mnemonic, operands
+        ))
+    };
+    match (mnemonic, operands) {
+        ("hlt", []) => asm.push(HLT),
+        ("ret", []) => asm.push(RET),
+        ("iretq", []) => asm.push(IRET),
+        ("swapgs", []) => asm.push(SWAPGS),
+        ("syscall", []) => asm.push(SYSCALL),
+        ("sysretq", []) => asm.push(SYSRET),
+        ("sti", []) => asm.push(STI),
+        ("cld", []) => asm.push(CLD),
+        ("rdmsr", []) => asm.push(RDMSR),
+        ("wrmsr", []) => asm.push(WRMSR),
+        ("cpuid", []) => asm.push(CPUID),
+        ("pause", []) => asm.push(PAUSE),
+        ("nop", []) => asm.push(NOP),
+        ("int3", []) => asm.push(INT3),
+        ("movsb", []) => asm.push(MOVSB),
+        ("stosb", []) => asm.push(STOSB),
+        ("stosw", []) => asm.push(STOSW),
+        ("cmpsb", []) => asm.push(CMPSB),
+        ("scasb", []) => asm.push(SCASB),
+
+        ("jmp", [Label(label)]) => asm.push(JMP(*label)),
+        ("jmp", [R64(r)]) => asm.push(JMP(*r)),
+        ("call", [Label(label)]) => asm.push(CALL(*label)),
+        ("call", [R64(r)]) => asm.push(CALL(*r)),
+        (_, [Label(label)]) if condition(mnemonic).is_some() => {
+            asm.push(JCC(condition(mnemonic).unwrap(), *label))
+        }
+
+        ("lidt", [Memory(m)]) => {
+            if let Some(address) = m.indirect() {
+                asm.push(LIDT(address))
+            } else if let Some(ptr) = m.ptr() {
+                asm.push(LIDT(ptr))
+            } else {
+                return unsupported();
+            }
+        }
+        ("push", [R64(r)]) => asm.push(PUSH(*r)),
+        ("push", [Imm(value)]) => match i8::try_from(*value) {
+            Ok(value) => asm.push(PUSH(value)),
+            Err(_) => return unsupported(),
+        },
+        ("pop", [R64(r)]) => asm.push(POP(*r)),
+
+        ("mov", [Sreg(s), R16(r)]) => asm.push(MOV(*s, *r)),
+        ("mov", [R16(r), Sreg(s)]) => asm.push(MOV(*r, *s)),
+        ("mov", [R64(r), Imm(value)]) => asm.push(MOV(*r, *value as u64)),
+        ("mov", [R8(r), Imm(value)]) => match byte(*value) {
+            Some(value) => asm.push(MOV(*r, value)),
+            None => return unsupported(),
+        },
+        ("mov", [R64(a), R64(b)]) => asm.push(MOV(*a, *b)),
+        ("mov", [R32(a), R32(b)]) => asm.push(MOV(*a, *b)),
+        ("mov", [R64(r), Memory(m)]) => {
+            if let Some(ptr) = m.ptr() {
+                asm.push(MOV(*r, ptr))
+            } else if let Some(gs) = m.gs() {
+                asm.push(MOV(*r, gs))
+            } else if let Some(address) = m.indirect() {
+                asm.push(MOV(*r, address))
+            } else if let Some(address) = m.disp8() {
+                asm.push(MOV(*r, address))
+            } else if let Some(address) = m.disp32() {
+                asm.push(MOV(*r, address))
+            } else if let Some(address) = m.index() {
+                asm.push(MOV(*r, address))
+            } else {
+                match m.address {
+                    Address::Index(index, 2, base) => {
+                        asm.push(MOV(*r, ScaledIndex(Times2, index, base)))
+                    }
+                    Address::Index(index, 4, base) => {
+                        asm.push(MOV(*r, ScaledIndex(Times4, index, base)))
+                    }
+                    Address::Index(index, 8, base) => {
+                        asm.push(MOV(*r, ScaledIndex(Times8, index, base)))
+                    }
+                    Address::Index(index, _, base) => {
+                        asm.push(MOV(*r, ScaledIndex(Times1, index, base)))
+                    }
+                    _ => return unsupported(),
+                }
+            }
+        }
+        ("mov", [R32(r), Memory(m)]) => {
+            if let Some(ptr) = m.ptr() {
+                asm.push(MOV(*r, ptr))
+            } else if let Some(gs) = m.gs() {
+                asm.push(MOV(*r, gs))
+            } else if let Some(address) = m.disp8() {
+                asm.push(MOV(*r, address))
+            } else {
+                return unsupported();
+            }
+        }
+        ("mov", [R16(r), Memory(m)]) => {
+            if let Some(ptr) = m.ptr() {
+                asm.push(MOV(*r, ptr))
+            } else if let Some(gs) = m.gs() {
+                asm.push(MOV(*r, gs))
+            } else {
+                return unsupported();
+            }
+        }
+        ("mov", [R8(r), Memory(m)]) => {
+            if let Some(ptr) = m.ptr() {
+                asm.push(MOV(*r, ptr))
+            } else if let Some(gs) = m.gs() {
+                asm.push(MOV(*r, gs))
+            } else if let Some(address) = m.index() {
+                asm.push(MOV(*r, address))
+            } else {
+                return unsupported();
+            }
+        }
+        ("mov", [Memory(m), R64(r)]) => {
+            if let Some(ptr) = m.ptr() {
+                asm.push(MOV(ptr, *r))
+            } else if let Some(gs) = m.gs() {
+                asm.push(MOV(gs, *r))
+            } else if let Some(address) = m.indirect() {
+                asm.push(MOV(address, *r))
+            } else if let Some(address) = m.disp8() {
+                asm.push(MOV(address, *r))
+            } else if let Some(address) = m.disp32() {
+                asm.push(MOV(address, *r))
+            } else {
+                return unsupported();
+            }
+        }
+        ("mov", [Memory(m), R32(r)]) => {
+            if let Some(ptr) = m.ptr() {
+                asm.push(MOV(ptr, *r))
+            } else if let Some(gs) = m.gs() {
+                asm.push(MOV(gs, *r))
+            } else if let Some(address) = m.disp8() {
+                asm.push(MOV(address, *r))
+            } else {
+                return unsupported();
+            }
+        }
+        ("mov", [Memory(m), R16(r)]) => {
+            if let Some(ptr) = m.ptr() {
+                asm.push(MOV(ptr, *r))
+            } else if let Some(gs) = m.gs() {
+                asm.push(MOV(gs, *r))
+            } else if let Some(address) = m.disp8() {
+                asm.push(MOV(address, *r))
+            } else {
+                return unsupported();
+            }
+        }
+        ("mov", [Memory(m), R8(r)]) => {
+            if let Some(ptr) = m.ptr() {
+                asm.push(MOV(ptr, *r))
+            } else if let Some(gs) = m.gs() {
+                asm.push(MOV(gs, *r))
+            } else if let Some(address) = m.indirect() {
+                asm.push(MOV(address, *r))
+            } else if let Some(address) = m.index() {
+                asm.push(MOV(address, *r))
+            } else {
+                return unsupported();
+            }
+        }
+        ("mov", [Memory(m), Imm(value)]) => {
+            match (
+                m.indirect(),
+                m.disp32(),
+                byte(*value),
+                u32::try_from(*value),
+            ) {
+                (Some(address), _, Some(value), _) if m.sized(Size::Byte) => {
+                    asm.push(MOV(address, value))
+                }
+                (_, Some(address), _, Ok(value)) if m.sized(Size::Dword) => {
+                    asm.push(MOV(address, value))
+                }
+                _ => return unsupported(),
+            }
+        }
+
+        ("xchg", [Memory(m), R8(r)]) if m.ptr().is_some() => asm.push(XCHG(m.ptr().unwrap(), *r)),
+        ("cmpxchg", [Memory(m), R8(r)]) if m.ptr().is_some() => {
+            asm.push(CMPXCHG(m.ptr().unwrap(), *r))
+        }
+        ("movzx", [R64(r), Memory(m)]) => {
+            if let Some(address) = m.index() {
+                asm.push(MOVZX(*r, address))
+            } else if let Some(address) = m.disp8() {
+                asm.push(MOVZX(*r, address))
+            } else {
+                return unsupported();
+            }
+        }
+        ("lea", [R64(r), Memory(m)]) => {
+            if let Some(ptr) = m.ptr() {
+                asm.push(LEA(*r, ptr))
+            } else if let Some(address) = m.disp8() {
+                asm.push(LEA(*r, address))
+            } else {
+                return unsupported();
+            }
+        }
+
+        ("add", [R64(a), R64(b)]) => asm.push(ADD(*a, *b)),
+        ("add", [R64(r), Imm(value)]) => match (i8::try_from(*value), i32::try_from(*value)) {
+            (Ok(value), _) => asm.push(ADD(*r, value)),
+            (_, Ok(value)) => asm.push(ADD(*r, value)),
+            _ => return unsupported(),
+        },
+        ("sub", [R64(a), R64(b)]) => asm.push(SUB(*a, *b)),
+        ("sub", [R64(r), Imm(value)]) => match (i8::try_from(*value), i32::try_from(*value)) {
+            (Ok(value), _) => asm.push(SUB(*r, value)),
+            (_, Ok(value)) => asm.push(SUB(*r, value)),
+            _ => return unsupported(),
+        },
+        ("cmp", [R64(a), R64(b)]) => asm.push(CMP(*a, *b)),
+        ("cmp", [R64(r), Imm(value)]) => match (i8::try_from(*value), i32::try_from(*value)) {
+            (Ok(value), _) => asm.push(CMP(*r, value)),
+            (_, Ok(value)) => asm.push(CMP(*r, value)),
+            _ => return unsupported(),
+        },
+        ("cmp", [Memory(m), Imm(value)]) => match (m.index(), byte(*value)) {
+            (Some(address), Some(value)) if m.sized(Size::Byte) => asm.push(CMP(address, value)),
+            _ => return unsupported(),
+        },
+        ("test", [R64(a), R64(b)]) => asm.push(TEST(*a, *b)),
+        ("test", [R8(a), R8(b)]) => asm.push(TEST(*a, *b)),
+        ("test", [R64(r), Imm(value)]) => match i32::try_from(*value) {
+            Ok(value) => asm.push(TEST(*r, value)),
+            Err(_) => return unsupported(),
+        },
+        ("test", [Memory(m), Imm(value)]) => match (m.disp8(), byte(*value)) {
+            (Some(address), Some(value)) if m.sized(Size::Byte) => asm.push(TEST(address, value)),
+            _ => return unsupported(),
+        },
+        ("or", [R64(a), R64(b)]) => asm.push(OR(*a, *b)),
+        ("or", [R64(r), Imm(value)]) => match i32::try_from(*value) {
+            Ok(value) => asm.push(OR(*r, value)),
+            Err(_) => return unsupported(),
+        },
+        ("or", [Memory(m), Imm(value)]) => match (m.disp8(), byte(*value), i16::try_from(*value)) {
+            (Some(address), Some(value), _) if m.sized(Size::Byte) => asm.push(OR(address, value)),
+            (Some(address), _, Ok(value)) if m.sized(Size::Word) => asm.push(OR(address, value)),
+            _ => return unsupported(),
+        },
+        ("and", [R64(a), R64(b)]) => asm.push(AND(*a, *b)),
+        ("and", [R64(r), Imm(value)]) => match i8::try_from(*value) {
+            Ok(value) => asm.push(AND(*r, value)),
+            Err(_) => return unsupported(),
+        },
+        ("xor", [R64(a), R64(b)]) => asm.push(XOR(*a, *b)),
+        ("xor", [R64(r), Imm(value)]) => match i32::try_from(*value) {
+            Ok(value) => asm.push(XOR(*r, value)),
+            Err(_) => return unsupported(),
+        },
+        ("shl", [R64(r), Imm(value)]) => match i8::try_from(*value) {
+            Ok(value) => asm.push(SHL(*r, value)),
+            Err(_) => return unsupported(),
+        },
+        ("shr", [R64(r), Imm(value)]) => match i8::try_from(*value) {
+            Ok(value) => asm.push(SHR(*r, value)),
+            Err(_) => return unsupported(),
+        },
+        ("shr", [R64(r), R8(super::register::R8::CL)]) => {
+            asm.push(SHR(*r, super::register::R8::CL))
+        }
+        ("inc", [R64(r)]) => asm.push(INC(*r)),
+        ("dec", [R64(r)]) => asm.push(DEC(*r)),
+        ("neg", [R64(r)]) => asm.push(NEG(*r)),
+        ("div", [R64(r)]) => asm.push(DIV(*r)),
+        ("imul", [R64(a), R64(b)]) => asm.push(IMUL(*a, *b)),
+        ("bsf", [R64(a), R64(b)]) => asm.push(BSF(*a, *b)),
+        ("in", [R8(a), R16(b)]) => asm.push(IN(*a, *b)),
+        ("out", [R16(a), R8(b)]) => asm.push(OUT(*a, *b)),
+        ("out", [Imm(port), R8(r)]) => match u8::try_from(*port) {
+            Ok(port) => asm.push(OUT(port, *r)),
+            Err(_) => return unsupported(),
+        },
+
+        ("movdqu", [Xmm(x), Memory(m)]) if m.index().is_some() => {
+            asm.push(MOVDQU(*x, m.index().unwrap()))
+        }
+        ("movdqu", [Memory(m), Xmm(x)]) if m.index().is_some() => {
+            asm.push(MOVDQU(m.index().unwrap(), *x))
+        }
+        ("movdqa", [Xmm(x), Memory(m)]) if m.indirect().is_some() => {
+            asm.push(MOVDQA(*x, m.indirect().unwrap()))
+        }
+        ("movq", [Xmm(x), R64(r)]) => asm.push(MOVQ(*x, *r)),
+        ("punpcklqdq", [Xmm(a), Xmm(b)]) => asm.push(PUNPCKLQDQ(*a, *b)),
+        ("pxor", [Xmm(a), Xmm(b)]) => asm.push(PXOR(*a, *b)),
+        ("pcmpeqb", [Xmm(a), Xmm(b)]) => asm.push(PCMPEQB(*a, *b)),
+        ("pmovmskb", [R32(r), Xmm(x)]) => asm.push(PMOVMSKB(*r, *x)),
+
+        _ => return unsupported(),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{link::Visibility, x86::register::R64::*};
+
+    #[test]
+    fn matches_builder() {
+        let text = "
+            global entry
+            entry:
+                push rbp            ; a comment
+                mov rbp, rsp
+                mov rax, qword ptr [rbp-8]
+            .loop: dec rcx
+                jnz .loop
+                mov rdx, [rdi+rsi*8]
+                lea rsi, [message]
+                rep movsb
+                mov byte ptr [rdi], 0x20
+                call entry
+                ret
+        ";
+        let sections = parse(text).unwrap();
+        assert_eq!(sections.len(), 1);
+        let (name, segment) = &sections[0];
+        assert_eq!(name, ".text");
+
+        let mut asm = Assembler::new();
+        asm.export_label("entry");
+        asm.push(PUSH(RBP));
+        asm.push(MOV(RBP, RSP));
+        asm.push(MOV(RAX, Index(RBP, -8i8)));
+        asm.label(".loop");
+        asm.push(DEC(RCX));
+        asm.push(JCC(Condition::NotZero, Label(".loop")));
+        asm.push(MOV(RDX, ScaledIndex(Times8, RSI, RDI)));
+        asm.push(LEA(RSI, Ptr("message")));
+        asm.push(REP(MOVSB));
+        asm.push(MOV(Indirect(RDI), 0x20u8));
+        asm.push(CALL(Label("entry")));
+        asm.push(RET);
+        let expected = asm.finish();
+
+        assert_eq!(segment.bytes(), expected.bytes());
+        assert!(matches!(
+            segment.labels[&Label("entry")].visibility,
+            Visibility::Exported
+        ));
+        assert_eq!(segment.labels[&Label(".loop")].offset, 8);
+    }
+
+    #[test]
+    fn data_and_sections() {
+        let text = r#"
+            section .text
+            start: hlt
+            section .rodata
+            align 8
+            table: dq start, -1
+            message: db "hi;", 10, 0
+            dw 0x1234
+            section .text
+            ret
+        "#;
+        let sections = parse(text).unwrap();
+        let names: Vec<_> = sections.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, [".text", ".rodata"]);
+        assert_eq!(sections[0].1.bytes(), [0xf4, 0xc3]);
+
+        let rodata = &sections[1].1;
+        let mut expected = vec![0; 8];
+        expected.extend([0xff; 8]);
+        expected.extend(b"hi;\n\0");
+        expected.extend([0x34, 0x12]);
+        assert_eq!(rodata.bytes(), expected);
+        assert_eq!(rodata.labels[&Label("message")].offset, 16);
+        assert_eq!(
+            rodata.references[&Label("start")][0].format,
+            ReferenceFormat::Abs64
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "image")]
+    fn links_with_builder_segments() {
+        use crate::{
+            elf64::program::{PF_R, PF_X},
+            link::ElfLinker,
+        };
+
+        let mut asm = Assembler::new();
+        asm.export_label("helper");
+        asm.push(RET);
+
+        let mut linker = ElfLinker::new();
+        linker.add_segment("builder", PF_R | PF_X, 1 << 12, asm.finish());
+        let text = "global entry\nentry: call helper\nhlt";
+        for (name, segment) in parse(text).unwrap() {
+            linker.add_segment(&name, PF_R | PF_X, 1 << 12, segment);
+        }
+        let linked = linker.finish();
+
+        let start = linked.label_address("entry").unwrap();
+        let helper = linked.label_address("helper").unwrap();
+        let code = linked.segments()[1].data();
+        assert_eq!(code[0], 0xe8);
+        let displacement = i32::from_le_bytes(code[1..5].try_into().unwrap());
+        assert_eq!(
+            start.wrapping_add(5).wrapping_add(displacement as u64),
+            helper
+        );
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        let error = |text| parse(text).err().unwrap().to_string();
+        assert_eq!(
+            error("nop\nmov al, [rax+rbx+8]"),
+            "line 2: invalid operand `[rax+rbx+8]`"
+        );
+        assert_eq!(
+            error("\n\nfrobnicate rax"),
+            "line 3: unsupported operands for `frobnicate`: [R64(RAX)]"
+        );
+        assert_eq!(error("db 256"), "line 1: invalid byte `256`");
+        assert_eq!(
+            error("mov [rax], 1"),
+            "line 1: unsupported operands for `mov`: [Memory(Memory { size: None, \
+             address: Base(RAX, 0) }), Imm(1)]"
+        );
+        assert_eq!(error("nop\nalign 3"), "line 2: invalid alignment `3`");
+        assert_eq!(
+            error("a:\nnop\na: nop"),
+            "line 3: label `a` is already defined"
+        );
+        assert_eq!(
+            error("mov al, [rsp*2+rax]"),
+            "line 1: RSP can not be used as an index register in `[rsp*2+rax]`"
+        );
+    }
+
+    #[test]
+    fn rsp_index_is_swapped_with_base() {
+        let (_, segment) = &parse("mov al, [rax+rsp]").unwrap()[0];
+        let mut asm = Assembler::new();
+        asm.push(MOV(super::R8::AL, Index(RAX, RSP)));
+        assert_eq!(segment.bytes(), asm.finish().bytes());
+    }
+}
@@ -0,0 +1,105 @@
+//! Per-CPU data, accessed through the GS segment.
+//!
+//! The per-CPU data is a structure declared with `layout!`, with one
+//! instance per CPU reserved in a data segment. Each CPU points its
+//! `GS_BASE` MSR at its own instance by calling the generated setup routine,
+//! after which the fields of the current CPU's instance are accessed with
+//! [`GsIndex`] operands, e.g. `MOV(RAX, GsIndex(CpuData::CURRENT_TASK))`.
+//!
+//! Code entered from user mode must execute `SWAPGS` before using these
+//! operands, as done by
+//! [`InterruptHandler::swapgs`](super::interrupt::InterruptHandler::swapgs).
+
+use std::{
+    marker::PhantomData,
+    mem::{align_of, size_of},
+};
+
+use bytemuck::Pod;
+
+use super::{
+    address::GsIndex, function::Function, instruction::*, msr, register::R64::*, Assembler,
+};
+use crate::{
+    dwarf::{DebugType, Type},
+    link::{Ptr, Segment},
+};
+
+/// Instances are aligned to cache lines, so that CPUs writing to their own
+/// data do not contend for lines holding another CPU's.
+const CACHE_LINE: usize = 64;
+
+pub struct PerCpu<'a, T> {
+    label: &'a str,
+    cpus: usize,
+    _type: PhantomData<T>,
+}
+
+impl<'a, T: Pod + DebugType> PerCpu<'a, T> {
+    /// Allocate zero-initialized instances for `cpus` CPUs in the reserved
+    /// space of `segment`, like `.bss`, starting at the exported `label`,
+    /// which debuggers see as an array of `cpus` instances.
+    pub fn reserve(segment: &mut Segment, label: &'a str, cpus: usize) -> Self {
+        assert!(cpus > 0, "per-CPU data {} has no instances", label);
+        segment.pad_to_alignment(Self::alignment());
+        segment.export_label(label);
+        segment.label_type(
+            label,
+            Type::Array {
+                element: Box::new(T::debug_type()),
+                count: cpus,
+                stride: Self::stride(),
+            },
+        );
+        segment.reserve(cpus * Self::stride());
+        Self {
+            label,
+            cpus,
+            _type: PhantomData,
+        }
+    }
+
+    fn alignment() -> usize {
+        align_of::<T>().max(CACHE_LINE)
+    }
+
+    /// The distance between consecutive instances, in bytes.
+    pub fn stride() -> usize {
+        size_of::<T>().next_multiple_of(Self::alignment())
+    }
+
+    pub fn label(&self) -> &'a str {
+        self.label
+    }
+
+    pub fn cpus(&self) -> usize {
+        self.cpus
+    }
+
+    /// The operand of a field of the current CPU's instance, given its
+    /// offset, e.g. from the constants defined by `layout!`.
+    pub fn field(&self, offset: usize) -> GsIndex {
+        assert!(
+            offset < size_of::<T>(),
+            "offset {:#x} is outside of per-CPU data {}",
+            offset,
+            self.label
+        );
+        GsIndex(offset)
+    }
+
+    /// Emit the exported routine `name(cpu)`, called with the System V ABI,
+    /// which points `GS_BASE` at the instance of the CPU numbered `cpu`.
+    /// The number is not checked, and must be less than
+    /// [`cpus`](Self::cpus).
+    pub fn emit_setup(&self, asm: &mut Assembler, name: &'a str) {
+        let mut f = Function::new(name).export().params(1).begin(asm);
+        f.push(MOV(RAX, Self::stride() as u64));
+        f.push(IMUL(RAX, f.param(0)));
+        f.push(LEA(RDX, Ptr(self.label)));
+        f.push(ADD(RAX, RDX));
+        msr::write(&mut f, msr::GS_BASE, RAX);
+        f.ret();
+        f.finish();
+    }
+}
@@ -0,0 +1,90 @@
+//! A generator for the initialization of the legacy 8259 PICs.
+//!
+//! At reset, the master PIC delivers IRQs 0-7 to vectors 8-15, which
+//! collide with CPU exceptions, so the PICs must be remapped before
+//! interrupts are enabled, even if they will not be used. The generated
+//! routine `{name}_init()`, called with the System V ABI, sends the usual
+//! initialization sequence to both PICs, with the slave cascaded on IRQ 2,
+//! and then masks the IRQs that are not wanted.
+
+use super::{function::Function, instruction::*, register::R8::*, Assembler, Emitter};
+use crate::link::Label;
+
+const MASTER_COMMAND: u8 = 0x20;
+const MASTER_DATA: u8 = 0x21;
+const SLAVE_COMMAND: u8 = 0xa0;
+const SLAVE_DATA: u8 = 0xa1;
+/// An unused port, written to give the PICs time to settle between
+/// commands.
+const WAIT_PORT: u8 = 0x80;
+
+/// ICW1: start initialization, and expect an ICW4.
+const ICW1_INIT: u8 = 0x11;
+/// ICW3 of the master: a slave is attached to IRQ 2.
+const ICW3_MASTER: u8 = 1 << 2;
+/// ICW3 of the slave: its cascade identity.
+const ICW3_SLAVE: u8 = 2;
+/// ICW4: 8086 mode.
+const ICW4_8086: u8 = 0x01;
+
+pub struct Pic<'a> {
+    name: &'a str,
+    master_offset: u8,
+    slave_offset: u8,
+    mask: u16,
+}
+
+impl<'a> Pic<'a> {
+    /// Deliver IRQs 0-7 to the vectors from `master_offset`, and IRQs 8-15
+    /// to the vectors from `slave_offset`. Both must be multiples of 8.
+    ///
+    /// All IRQs are masked unless [`mask`](Self::mask) is given.
+    pub fn new(name: &'a str, master_offset: u8, slave_offset: u8) -> Self {
+        for offset in [master_offset, slave_offset] {
+            assert!(
+                offset.is_multiple_of(8),
+                "PIC vector offset {:#x} is not a multiple of 8",
+                offset
+            );
+        }
+        Self {
+            name,
+            master_offset,
+            slave_offset,
+            mask: 0xffff,
+        }
+    }
+
+    /// Mask the IRQs whose bits are set, with IRQ 0 in bit 0. IRQ 2 must be
+    /// unmasked for any of IRQs 8-15 to be delivered.
+    pub fn mask(mut self, mask: u16) -> Self {
+        self.mask = mask;
+        self
+    }
+
+    pub fn emit(&self, asm: &mut Assembler) {
+        let name = Label(&format!("{}_init", self.name));
+        let mut f = Function::new(name).export().begin(asm);
+        write(&mut f, MASTER_COMMAND, ICW1_INIT);
+        write(&mut f, SLAVE_COMMAND, ICW1_INIT);
+        // ICW2 to ICW4, and then OCW1, the mask.
+        for (master, slave) in [
+            (self.master_offset, self.slave_offset),
+            (ICW3_MASTER, ICW3_SLAVE),
+            (ICW4_8086, ICW4_8086),
+            (self.mask as u8, (self.mask >> 8) as u8),
+        ] {
+            write(&mut f, MASTER_DATA, master);
+            write(&mut f, SLAVE_DATA, slave);
+        }
+        f.ret();
+        f.finish();
+    }
+}
+
+/// Write `value` to `port`, and wait for the PIC to process it.
+fn write(f: &mut impl Emitter, port: u8, value: u8) {
+    f.push(MOV(AL, value));
+    f.push(OUT(port, AL));
+    f.push(OUT(WAIT_PORT, AL));
+}
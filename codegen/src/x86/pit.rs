@@ -0,0 +1,57 @@
+//! A generator for the initialization of the legacy 8253/8254 PIT.
+//!
+//! The generated routine `{name}_init()`, called with the System V ABI,
+//! programs channel 0 as a rate generator, which raises IRQ 0 periodically
+//! at the configured frequency.
+
+use super::{function::Function, instruction::*, register::R8::*, Assembler};
+use crate::link::Label;
+
+/// Frequency of the PIT's input clock, in Hz.
+pub const PIT_FREQUENCY: u32 = 1_193_182;
+
+const CHANNEL_0_DATA: u8 = 0x40;
+const MODE_COMMAND: u8 = 0x43;
+
+/// Mode/command byte: channel 0, access mode low byte then high byte,
+/// operating mode 2 (rate generator), binary counting.
+const CHANNEL_0_RATE_GENERATOR: u8 = 0x34;
+
+pub struct Pit<'a> {
+    name: &'a str,
+    divisor: u16,
+}
+
+impl<'a> Pit<'a> {
+    /// Raise IRQ 0 at `frequency` Hz, as closely as the divisor of
+    /// [`PIT_FREQUENCY`] allows. The frequency must be between 19 Hz and
+    /// `PIT_FREQUENCY`.
+    pub fn new(name: &'a str, frequency: u32) -> Self {
+        let divisor = (PIT_FREQUENCY + frequency / 2)
+            .checked_div(frequency)
+            .unwrap_or(0);
+        assert!(
+            (1..=0x10000).contains(&divisor),
+            "PIT frequency {} Hz is out of range",
+            frequency
+        );
+        Self {
+            name,
+            // A divisor of 0x10000 is written as 0.
+            divisor: divisor as u16,
+        }
+    }
+
+    pub fn emit(&self, asm: &mut Assembler) {
+        let name = Label(&format!("{}_init", self.name));
+        let mut f = Function::new(name).export().begin(asm);
+        f.push(MOV(AL, CHANNEL_0_RATE_GENERATOR));
+        f.push(OUT(MODE_COMMAND, AL));
+        f.push(MOV(AL, self.divisor as u8));
+        f.push(OUT(CHANNEL_0_DATA, AL));
+        f.push(MOV(AL, (self.divisor >> 8) as u8));
+        f.push(OUT(CHANNEL_0_DATA, AL));
+        f.ret();
+        f.finish();
+    }
+}
@@ -0,0 +1,723 @@
+//! Code written against virtual registers, with physical registers assigned
+//! by linear-scan allocation.
+//!
+//! A [`VirtualFunction`] is a list of [`VInst`]s operating on [`VReg`]s.
+//! When emitted, each virtual register is assigned a physical register for
+//! its whole live interval, or spilled to a stack slot if none is free.
+//! Spilled values are loaded into scratch registers around each use.
+//! Instructions whose results are never read are removed first, using the
+//! same [liveness](super::liveness) analysis as the intervals.
+
+use std::collections::HashMap;
+
+use super::{
+    address::Index,
+    function::{Arg, Function, FunctionBuilder, RETURN_REGISTER},
+    instruction::{ADD, AND, DEC, JMP, JNZ, JZ, LEA, MOV, MOVZX, OR, SHL, SHR, SUB, TEST, XOR},
+    liveness::Liveness,
+    register::R64::{self, *},
+    Assembler,
+};
+use crate::link::{Label, Ptr};
+
+/// Registers available for allocation that are clobbered by calls, in order
+/// of preference.
+const ALLOCATABLE_CALLER_SAVED: [R64; 7] = [RAX, RCX, RDX, RSI, RDI, R8, R9];
+/// Registers available for allocation that are preserved across calls. Using
+/// them costs a save and restore in the prologue and epilogue.
+const ALLOCATABLE_CALLEE_SAVED: [R64; 5] = [RBX, R12, R13, R14, R15];
+/// Registers used to hold spilled values around an instruction. An
+/// instruction has at most two register operands.
+const SCRATCH: [R64; 2] = [R10, R11];
+
+/// A virtual register, holding a 64-bit value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct VReg(u32);
+
+/// An instruction operating on virtual registers.
+///
+/// Two-operand arithmetic follows x86: the first operand is both read and
+/// written.
+#[derive(Debug, Clone, PartialEq)]
+pub enum VInst<'a> {
+    Label(&'a str),
+    /// Define a register from an integer parameter. Must come before all
+    /// other instructions.
+    Param(VReg, usize),
+    MovImm(VReg, u64),
+    Mov(VReg, VReg),
+    /// Load the address of a label.
+    Lea(VReg, &'a str),
+    /// Load 64 bits from `[base + displacement]`.
+    Load(VReg, VReg, i8),
+    /// Load a zero-extended byte from `[base + index]`.
+    LoadByte(VReg, VReg, VReg),
+    /// Store 64 bits to `[base + displacement]`.
+    Store(VReg, i8, VReg),
+    Add(VReg, VReg),
+    Sub(VReg, VReg),
+    And(VReg, VReg),
+    Or(VReg, VReg),
+    Xor(VReg, VReg),
+    AddImm(VReg, i32),
+    AndImm(VReg, i8),
+    ShlImm(VReg, i8),
+    ShrImm(VReg, i8),
+    Dec(VReg),
+    Jmp(&'a str),
+    JumpIfZero(VReg, &'a str),
+    JumpIfNotZero(VReg, &'a str),
+    /// Call a System V function with the given arguments, optionally
+    /// defining a register from its result.
+    Call(&'a str, Vec<VReg>, Option<VReg>),
+    /// Return, optionally with a value.
+    Ret(Option<VReg>),
+}
+
+impl<'a> VInst<'a> {
+    /// Registers read by the instruction.
+    pub fn uses(&self) -> Vec<VReg> {
+        match self {
+            Self::Label(_)
+            | Self::Param(..)
+            | Self::MovImm(..)
+            | Self::Lea(..)
+            | Self::Jmp(_)
+            | Self::Ret(None) => vec![],
+            Self::Mov(_, src) | Self::Load(_, src, _) => vec![*src],
+            Self::LoadByte(_, base, index) => vec![*base, *index],
+            Self::Store(base, _, src) => vec![*base, *src],
+            Self::Add(dst, src)
+            | Self::Sub(dst, src)
+            | Self::And(dst, src)
+            | Self::Or(dst, src)
+            | Self::Xor(dst, src) => vec![*dst, *src],
+            Self::AddImm(dst, _)
+            | Self::AndImm(dst, _)
+            | Self::ShlImm(dst, _)
+            | Self::ShrImm(dst, _)
+            | Self::Dec(dst) => vec![*dst],
+            Self::JumpIfZero(reg, _) | Self::JumpIfNotZero(reg, _) | Self::Ret(Some(reg)) => {
+                vec![*reg]
+            }
+            Self::Call(_, args, _) => args.clone(),
+        }
+    }
+
+    /// The register written by the instruction, if any.
+    pub fn def(&self) -> Option<VReg> {
+        match self {
+            Self::Param(dst, _)
+            | Self::MovImm(dst, _)
+            | Self::Mov(dst, _)
+            | Self::Lea(dst, _)
+            | Self::Load(dst, _, _)
+            | Self::LoadByte(dst, _, _)
+            | Self::Add(dst, _)
+            | Self::Sub(dst, _)
+            | Self::And(dst, _)
+            | Self::Or(dst, _)
+            | Self::Xor(dst, _)
+            | Self::AddImm(dst, _)
+            | Self::AndImm(dst, _)
+            | Self::ShlImm(dst, _)
+            | Self::ShrImm(dst, _)
+            | Self::Dec(dst) => Some(*dst),
+            Self::Call(_, _, result) => *result,
+            _ => None,
+        }
+    }
+
+    /// The label this instruction may jump to, if any.
+    pub(super) fn jump_target(&self) -> Option<&'a str> {
+        match self {
+            Self::Jmp(label) | Self::JumpIfZero(_, label) | Self::JumpIfNotZero(_, label) => {
+                Some(label)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Where a virtual register lives.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Location {
+    Register(R64),
+    /// Spilled to the given local slot of the function's frame.
+    Slot(usize),
+}
+
+/// The range of instruction indices over which a register is live.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Interval {
+    reg: VReg,
+    start: usize,
+    end: usize,
+}
+
+/// The result of register allocation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Allocation {
+    pub locations: HashMap<VReg, Location>,
+    /// Callee-saved registers that were assigned, which the function must
+    /// save.
+    pub callee_saved: Vec<R64>,
+    /// Number of local slots used for spills.
+    pub slots: usize,
+}
+
+/// A function body written against virtual registers.
+pub struct VirtualFunction<'a> {
+    function: Function,
+    insts: Vec<VInst<'a>>,
+    next_reg: u32,
+}
+
+impl<'a> VirtualFunction<'a> {
+    /// The function's saved registers and locals are determined by register
+    /// allocation, overriding any set on `function`.
+    pub fn new(function: Function) -> Self {
+        Self {
+            function,
+            insts: Vec::new(),
+            next_reg: 0,
+        }
+    }
+
+    /// Allocate a fresh virtual register.
+    pub fn reg(&mut self) -> VReg {
+        let reg = VReg(self.next_reg);
+        self.next_reg += 1;
+        reg
+    }
+
+    pub fn push(&mut self, inst: VInst<'a>) {
+        self.insts.push(inst);
+    }
+
+    pub fn insts(&self) -> &[VInst<'a>] {
+        &self.insts
+    }
+
+    /// Remove instructions that only define registers that are never read,
+    /// and the results of calls that are never read. Removing an instruction
+    /// can make the registers it reads dead too, so this repeats until
+    /// nothing changes.
+    pub fn eliminate_dead_code(&mut self) {
+        loop {
+            let liveness = Liveness::compute(&self.insts);
+            let mut changed = false;
+            let mut index = 0;
+            self.insts.retain_mut(|inst| {
+                let dead = inst
+                    .def()
+                    .is_some_and(|def| !liveness.live_out(index).contains(&def));
+                index += 1;
+                if !dead {
+                    return true;
+                }
+                changed = true;
+                match inst {
+                    VInst::Call(_, _, result) => {
+                        *result = None;
+                        true
+                    }
+                    _ => false,
+                }
+            });
+            if !changed {
+                break;
+            }
+        }
+    }
+
+    /// Compute the live interval of each register, in order of start. An
+    /// interval spans every instruction that the register is live into or
+    /// defined by, including any holes between them.
+    fn intervals(&self) -> Vec<Interval> {
+        let liveness = Liveness::compute(&self.insts);
+        let mut intervals: HashMap<VReg, Interval> = HashMap::new();
+        for (i, inst) in self.insts.iter().enumerate() {
+            for &reg in liveness.live_in(i).iter().chain(&inst.def()) {
+                intervals
+                    .entry(reg)
+                    .and_modify(|interval| {
+                        interval.start = interval.start.min(i);
+                        interval.end = i;
+                    })
+                    .or_insert(Interval {
+                        reg,
+                        start: i,
+                        end: i,
+                    });
+            }
+        }
+
+        let mut intervals: Vec<Interval> = intervals.into_values().collect();
+        intervals.sort_by_key(|interval| (interval.start, interval.reg));
+        intervals
+    }
+
+    /// Assign each register a location by linear scan.
+    pub fn allocate(&self) -> Allocation {
+        let calls: Vec<usize> = self
+            .insts
+            .iter()
+            .enumerate()
+            .filter(|(_, inst)| matches!(inst, VInst::Call(..)))
+            .map(|(i, _)| i)
+            .collect();
+
+        let allocatable: Vec<R64> = ALLOCATABLE_CALLER_SAVED
+            .into_iter()
+            .chain(ALLOCATABLE_CALLEE_SAVED)
+            .collect();
+        let mut locations = HashMap::new();
+        let mut callee_saved = Vec::new();
+        let mut slots = 0;
+        let mut active: Vec<(Interval, R64)> = Vec::new();
+
+        for interval in self.intervals() {
+            // Expire intervals that ended before this one starts.
+            active.retain(|(other, _)| other.end >= interval.start);
+
+            // Values live across a call must be in callee-saved registers.
+            let crosses_call = calls
+                .iter()
+                .any(|&call| interval.start < call && call < interval.end);
+            let candidates: &[R64] = if crosses_call {
+                &ALLOCATABLE_CALLEE_SAVED
+            } else {
+                &allocatable
+            };
+            let free = candidates
+                .iter()
+                .copied()
+                .find(|register| active.iter().all(|(_, used)| used != register));
+
+            let register = match free {
+                Some(register) => Some(register),
+                None => {
+                    // Spill whichever candidate interval ends last, which
+                    // frees a register for the longest time.
+                    let (victim, _) = active
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, (_, register))| candidates.contains(register))
+                        .max_by_key(|(_, (other, _))| other.end)
+                        .expect("no candidate registers");
+                    if active[victim].0.end > interval.end {
+                        let (spilled, register) = active.remove(victim);
+                        locations.insert(spilled.reg, Location::Slot(slots));
+                        slots += 1;
+                        Some(register)
+                    } else {
+                        None
+                    }
+                }
+            };
+
+            match register {
+                Some(register) => {
+                    if ALLOCATABLE_CALLEE_SAVED.contains(&register)
+                        && !callee_saved.contains(&register)
+                    {
+                        callee_saved.push(register);
+                    }
+                    locations.insert(interval.reg, Location::Register(register));
+                    active.push((interval, register));
+                }
+                None => {
+                    locations.insert(interval.reg, Location::Slot(slots));
+                    slots += 1;
+                }
+            }
+        }
+
+        Allocation {
+            locations,
+            callee_saved,
+            slots,
+        }
+    }
+
+    /// Eliminate dead code, allocate registers and emit the function.
+    pub fn emit(mut self, asm: &mut Assembler) {
+        self.eliminate_dead_code();
+        let allocation = self.allocate();
+        let params = self
+            .insts
+            .iter()
+            .take_while(|inst| matches!(inst, VInst::Param(..)))
+            .count();
+        assert!(
+            self.insts[params..]
+                .iter()
+                .all(|inst| !matches!(inst, VInst::Param(..))),
+            "parameters must be defined before all other instructions"
+        );
+
+        let mut f = self
+            .function
+            .saves(&allocation.callee_saved)
+            .locals(allocation.slots)
+            .begin(asm);
+        let mut lower = Lowering {
+            f: &mut f,
+            allocation: &allocation,
+        };
+
+        // Spilled parameters are stored before any parameter register can
+        // be overwritten by moving the rest.
+        let mut moves = Vec::new();
+        for inst in &self.insts[..params] {
+            let VInst::Param(dst, index) = *inst else {
+                unreachable!()
+            };
+            let src = lower.f.param(index);
+            match allocation.locations[&dst] {
+                Location::Register(register) => moves.push((register, Arg::Reg(src))),
+                Location::Slot(slot) => lower.f.push(MOV(lower.f.local(slot), src)),
+            }
+        }
+        lower.f.move_parallel(moves);
+
+        for inst in &self.insts[params..] {
+            lower.inst(inst);
+        }
+        f.finish();
+    }
+}
+
+struct Lowering<'a, 'b> {
+    f: &'b mut FunctionBuilder<'a>,
+    allocation: &'b Allocation,
+}
+
+impl Lowering<'_, '_> {
+    /// Get a register holding the value of `reg`, loading it into the given
+    /// scratch register if it is spilled.
+    fn read(&mut self, reg: VReg, scratch: usize) -> R64 {
+        match self.allocation.locations[&reg] {
+            Location::Register(register) => register,
+            Location::Slot(slot) => {
+                let local = self.f.local(slot);
+                self.f.push(MOV(SCRATCH[scratch], local));
+                SCRATCH[scratch]
+            }
+        }
+    }
+
+    /// Get a register to write the value of `reg` to, to be followed by
+    /// [`write_back`](Self::write_back).
+    fn target(&self, reg: VReg) -> R64 {
+        match self.allocation.locations[&reg] {
+            Location::Register(register) => register,
+            Location::Slot(_) => SCRATCH[0],
+        }
+    }
+
+    /// Store the value of a spilled `reg` from scratch to its slot.
+    fn write_back(&mut self, reg: VReg) {
+        if let Location::Slot(slot) = self.allocation.locations[&reg] {
+            let local = self.f.local(slot);
+            self.f.push(MOV(local, SCRATCH[0]));
+        }
+    }
+
+    fn arg(&self, reg: VReg) -> Arg {
+        match self.allocation.locations[&reg] {
+            Location::Register(register) => Arg::Reg(register),
+            Location::Slot(slot) => {
+                let Index(base, displacement) = self.f.local(slot);
+                Arg::Index(base, displacement)
+            }
+        }
+    }
+
+    /// Lower a two-operand instruction that reads and writes `dst`.
+    fn read_modify_write(&mut self, dst: VReg, emit: impl FnOnce(&mut Self, R64)) {
+        let register = self.read(dst, 0);
+        emit(self, register);
+        self.write_back(dst);
+    }
+
+    fn inst(&mut self, inst: &VInst<'_>) {
+        match *inst {
+            VInst::Label(label) => self.f.label(label),
+            VInst::Param(..) => unreachable!(),
+            VInst::MovImm(dst, value) => {
+                let register = self.target(dst);
+                if value == 0 {
+                    self.f.push(XOR(register, register));
+                } else {
+                    self.f.push(MOV(register, value));
+                }
+                self.write_back(dst);
+            }
+            VInst::Mov(dst, src) => {
+                let src = self.read(src, 1);
+                let register = self.target(dst);
+                if register != src {
+                    self.f.push(MOV(register, src));
+                }
+                self.write_back(dst);
+            }
+            VInst::Lea(dst, label) => {
+                let register = self.target(dst);
+                self.f.push(LEA(register, Ptr(label)));
+                self.write_back(dst);
+            }
+            VInst::Load(dst, base, displacement) => {
+                let base = self.read(base, 1);
+                let register = self.target(dst);
+                self.f.push(MOV(register, Index(base, displacement)));
+                self.write_back(dst);
+            }
+            VInst::LoadByte(dst, base, index) => {
+                let base = self.read(base, 0);
+                let index = self.read(index, 1);
+                let register = self.target(dst);
+                self.f.push(MOVZX(register, Index(index, base)));
+                self.write_back(dst);
+            }
+            VInst::Store(base, displacement, src) => {
+                let base = self.read(base, 0);
+                let src = self.read(src, 1);
+                self.f.push(MOV(Index(base, displacement), src));
+            }
+            VInst::Add(dst, src) => {
+                let src = self.read(src, 1);
+                self.read_modify_write(dst, |l, dst| l.f.push(ADD(dst, src)));
+            }
+            VInst::Sub(dst, src) => {
+                let src = self.read(src, 1);
+                self.read_modify_write(dst, |l, dst| l.f.push(SUB(dst, src)));
+            }
+            VInst::And(dst, src) => {
+                let src = self.read(src, 1);
+                self.read_modify_write(dst, |l, dst| l.f.push(AND(dst, src)));
+            }
+            VInst::Or(dst, src) => {
+                let src = self.read(src, 1);
+                self.read_modify_write(dst, |l, dst| l.f.push(OR(dst, src)));
+            }
+            VInst::Xor(dst, src) => {
+                let src = self.read(src, 1);
+                self.read_modify_write(dst, |l, dst| l.f.push(XOR(dst, src)));
+            }
+            VInst::AddImm(dst, value) => {
+                self.read_modify_write(dst, |l, dst| l.f.push(ADD(dst, value)));
+            }
+            VInst::AndImm(dst, value) => {
+                self.read_modify_write(dst, |l, dst| l.f.push(AND(dst, value)));
+            }
+            VInst::ShlImm(dst, amount) => {
+                self.read_modify_write(dst, |l, dst| l.f.push(SHL(dst, amount)));
+            }
+            VInst::ShrImm(dst, amount) => {
+                self.read_modify_write(dst, |l, dst| l.f.push(SHR(dst, amount)));
+            }
+            VInst::Dec(dst) => {
+                self.read_modify_write(dst, |l, dst| l.f.push(DEC(dst)));
+            }
+            VInst::Jmp(label) => self.f.push(JMP(Label(label))),
+            VInst::JumpIfZero(reg, label) => {
+                let register = self.read(reg, 0);
+                self.f.push(TEST(register, register));
+                self.f.push(JZ(Label(label)));
+            }
+            VInst::JumpIfNotZero(reg, label) => {
+                let register = self.read(reg, 0);
+                self.f.push(TEST(register, register));
+                self.f.push(JNZ(Label(label)));
+            }
+            VInst::Call(target, ref args, result) => {
+                let args: Vec<Arg> = args.iter().map(|&arg| self.arg(arg)).collect();
+                self.f.call_fn(target, &args);
+                if let Some(result) = result {
+                    let register = self.target(result);
+                    if register != RETURN_REGISTER {
+                        self.f.push(MOV(register, RETURN_REGISTER));
+                    }
+                    self.write_back(result);
+                }
+            }
+            VInst::Ret(value) => {
+                if let Some(value) = value {
+                    let register = self.read(value, 0);
+                    let result = self.f.result();
+                    if register != result {
+                        self.f.push(MOV(result, register));
+                    }
+                }
+                self.f.ret();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::x86::instruction::{CALL, POP, PUSH, RET};
+
+    #[test]
+    fn values_live_across_calls_are_callee_saved() {
+        let mut v = VirtualFunction::new(Function::new("f").params(1).returns());
+        let (param, result) = (v.reg(), v.reg());
+        v.push(VInst::Param(param, 0));
+        v.push(VInst::Call("g", vec![], Some(result)));
+        v.push(VInst::Add(param, result));
+        v.push(VInst::Ret(Some(param)));
+
+        let allocation = v.allocate();
+        assert_eq!(allocation.locations[&param], Location::Register(RBX));
+        // The result is defined by the call, so it doesn't cross it.
+        assert_eq!(allocation.locations[&result], Location::Register(RAX));
+        assert_eq!(allocation.callee_saved, [RBX]);
+        assert_eq!(allocation.slots, 0);
+    }
+
+    #[test]
+    fn spills_the_interval_ending_last() {
+        // 13 values live at once, one more than there are registers.
+        let mut v = VirtualFunction::new(Function::new("f").returns());
+        let regs: Vec<VReg> = (0..13).map(|_| v.reg()).collect();
+        for (i, &reg) in regs.iter().enumerate() {
+            v.push(VInst::MovImm(reg, i as u64));
+        }
+        for &reg in &regs[1..] {
+            v.push(VInst::Add(regs[0], reg));
+        }
+        v.push(VInst::Ret(Some(regs[0])));
+
+        // The first value is live the longest, so it gives up its register.
+        let allocation = v.allocate();
+        assert_eq!(allocation.locations[&regs[0]], Location::Slot(0));
+        assert_eq!(allocation.locations[&regs[12]], Location::Register(RAX));
+        assert_eq!(allocation.slots, 1);
+        assert_eq!(allocation.callee_saved, ALLOCATABLE_CALLEE_SAVED);
+
+        // The last value is live the longest, so it is spilled itself.
+        let mut v = VirtualFunction::new(Function::new("f").returns());
+        let regs: Vec<VReg> = (0..13).map(|_| v.reg()).collect();
+        for (i, &reg) in regs.iter().enumerate() {
+            v.push(VInst::MovImm(reg, i as u64));
+        }
+        for &reg in &regs[..12] {
+            v.push(VInst::Add(regs[12], reg));
+        }
+        v.push(VInst::Ret(Some(regs[12])));
+
+        let allocation = v.allocate();
+        assert_eq!(allocation.locations[&regs[12]], Location::Slot(0));
+        assert_eq!(allocation.locations[&regs[0]], Location::Register(RAX));
+        assert_eq!(allocation.slots, 1);
+    }
+
+    #[test]
+    fn spilled_values_go_through_scratch() {
+        // Seven values live across a call, with five callee-saved registers
+        // to hold them.
+        let mut v = VirtualFunction::new(Function::new("f").params(2).returns());
+        let (a, b) = (v.reg(), v.reg());
+        let c: Vec<VReg> = (0..5).map(|_| v.reg()).collect();
+        let d = v.reg();
+        v.push(VInst::Param(a, 0));
+        v.push(VInst::Param(b, 1));
+        for (i, &reg) in c.iter().enumerate() {
+            v.push(VInst::MovImm(reg, i as u64 + 1));
+        }
+        v.push(VInst::Call("g", vec![], None));
+        v.push(VInst::Add(b, c[1]));
+        v.push(VInst::Add(b, c[2]));
+        v.push(VInst::Add(b, c[3]));
+        v.push(VInst::Add(c[0], c[4]));
+        v.push(VInst::Add(c[0], b));
+        v.push(VInst::LoadByte(d, a, c[0]));
+        v.push(VInst::Add(d, a));
+        v.push(VInst::Ret(Some(d)));
+
+        let allocation = v.allocate();
+        assert_eq!(allocation.locations[&a], Location::Slot(0));
+        assert_eq!(allocation.locations[&c[0]], Location::Slot(1));
+
+        let mut asm = Assembler::new();
+        v.emit(&mut asm);
+        let mut expected = Assembler::new();
+        expected.label("f");
+        expected.push(PUSH(RBP));
+        expected.push(MOV(RBP, RSP));
+        expected.push(SUB(RSP, 24));
+        for register in [RBX, R12, R13, R14, R15] {
+            expected.push(PUSH(register));
+        }
+        // The spilled parameter is stored before the others are moved.
+        expected.push(MOV(Index(RBP, -8i8), RDI));
+        expected.push(MOV(R12, RSI));
+        expected.push(MOV(R10, 1u64));
+        expected.push(MOV(Index(RBP, -16i8), R10));
+        expected.push(MOV(R14, 2u64));
+        expected.push(MOV(R15, 3u64));
+        expected.push(MOV(RBX, 4u64));
+        expected.push(MOV(R13, 5u64));
+        expected.push(CALL(Label("g")));
+        expected.push(ADD(R12, R14));
+        expected.push(ADD(R12, R15));
+        expected.push(ADD(R12, RBX));
+        // A spilled destination is read, modified and written back.
+        for src in [R13, R12] {
+            expected.push(MOV(R10, Index(RBP, -16i8)));
+            expected.push(ADD(R10, src));
+            expected.push(MOV(Index(RBP, -16i8), R10));
+        }
+        // Spilled operands are loaded into separate scratch registers.
+        expected.push(MOV(R10, Index(RBP, -8i8)));
+        expected.push(MOV(R11, Index(RBP, -16i8)));
+        expected.push(MOVZX(RAX, Index(R11, R10)));
+        expected.push(MOV(R11, Index(RBP, -8i8)));
+        expected.push(ADD(RAX, R11));
+        for register in [R15, R14, R13, R12, RBX] {
+            expected.push(POP(register));
+        }
+        expected.push(ADD(RSP, 24));
+        expected.push(POP(RBP));
+        expected.push(RET);
+        assert_eq!(asm.finish().bytes(), expected.finish().bytes());
+    }
+
+    #[test]
+    fn eliminates_chains_of_dead_code() {
+        let mut v = VirtualFunction::new(Function::new("f").params(1).returns());
+        let (param, a, b, c) = (v.reg(), v.reg(), v.reg(), v.reg());
+        v.push(VInst::Param(param, 0));
+        v.push(VInst::MovImm(a, 1));
+        v.push(VInst::Mov(b, a));
+        v.push(VInst::AddImm(b, 1));
+        v.push(VInst::Mov(c, b));
+        v.push(VInst::Ret(Some(param)));
+
+        v.eliminate_dead_code();
+        assert_eq!(v.insts(), [VInst::Param(param, 0), VInst::Ret(Some(param))]);
+    }
+
+    #[test]
+    fn keeps_calls_with_unused_results() {
+        let mut v = VirtualFunction::new(Function::new("f"));
+        let (arg, result) = (v.reg(), v.reg());
+        v.push(VInst::MovImm(arg, 1));
+        v.push(VInst::Call("g", vec![arg], Some(result)));
+        v.push(VInst::Ret(None));
+
+        v.eliminate_dead_code();
+        assert_eq!(
+            v.insts(),
+            [
+                VInst::MovImm(arg, 1),
+                VInst::Call("g", vec![arg], None),
+                VInst::Ret(None),
+            ]
+        );
+    }
+}
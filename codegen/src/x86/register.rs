@@ -8,8 +8,58 @@ pub trait Register {
     fn rex_b(&self) -> u8;
     fn rex_x(&self) -> u8;
     fn rex_r(&self) -> u8;
+
+    /// The 64-bit general-purpose register that this register is part of, if
+    /// any.
+    fn gpr(&self) -> Option<R64>;
+
+    /// Whether this is one of AH, CH, DH and BH, which can only be encoded in
+    /// an instruction without a REX prefix.
+    fn high_byte(&self) -> bool {
+        false
+    }
+
+    /// Whether this is a 32-bit general-purpose register, whose operand size
+    /// needs an override prefix in real mode.
+    fn dword(&self) -> bool {
+        false
+    }
+
+    /// The bits of the REX2 payload that extend the register code to 5 bits,
+    /// as the ModRM r/m field or SIB base, index or ModRM reg field.
+    fn rex2_b(&self) -> u8 {
+        0
+    }
+
+    fn rex2_x(&self) -> u8 {
+        0
+    }
+
+    fn rex2_r(&self) -> u8 {
+        0
+    }
 }
 
+/// General-purpose registers, indexed by register code.
+const GPRS: [R64; 16] = [
+    R64::RAX,
+    R64::RCX,
+    R64::RDX,
+    R64::RBX,
+    R64::RSP,
+    R64::RBP,
+    R64::RSI,
+    R64::RDI,
+    R64::R8,
+    R64::R9,
+    R64::R10,
+    R64::R11,
+    R64::R12,
+    R64::R13,
+    R64::R14,
+    R64::R15,
+];
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum R8 {
     AL,
@@ -36,6 +86,29 @@ pub enum R8 {
 }
 
 impl R8 {
+    /// The register with a 4-bit register code, as encoded without a REX
+    /// prefix for codes 4 to 7.
+    pub fn from_code(code: u8) -> Self {
+        [
+            Self::AL,
+            Self::CL,
+            Self::DL,
+            Self::BL,
+            Self::AH,
+            Self::CH,
+            Self::DH,
+            Self::BH,
+            Self::R8B,
+            Self::R9B,
+            Self::R10B,
+            Self::R11B,
+            Self::R12B,
+            Self::R13B,
+            Self::R14B,
+            Self::R15B,
+        ][code as usize]
+    }
+
     fn code(&self) -> u8 {
         match self {
             Self::AL => 0x0,
@@ -99,6 +172,22 @@ impl Register for R8 {
     fn rex_r(&self) -> u8 {
         self.upper_bit() << 2
     }
+
+    fn high_byte(&self) -> bool {
+        matches!(self, Self::AH | Self::CH | Self::DH | Self::BH)
+    }
+
+    fn gpr(&self) -> Option<R64> {
+        // Without REX, codes 4 to 7 are the high bytes of the first four
+        // registers.
+        Some(match self {
+            Self::AH => R64::RAX,
+            Self::CH => R64::RCX,
+            Self::DH => R64::RDX,
+            Self::BH => R64::RBX,
+            _ => GPRS[self.code() as usize],
+        })
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -122,6 +211,28 @@ pub enum R16 {
 }
 
 impl R16 {
+    /// The register with a 4-bit register code.
+    pub fn from_code(code: u8) -> Self {
+        [
+            Self::AX,
+            Self::CX,
+            Self::DX,
+            Self::BX,
+            Self::SP,
+            Self::BP,
+            Self::SI,
+            Self::DI,
+            Self::R8W,
+            Self::R9W,
+            Self::R10W,
+            Self::R11W,
+            Self::R12W,
+            Self::R13W,
+            Self::R14W,
+            Self::R15W,
+        ][code as usize]
+    }
+
     fn code(&self) -> u8 {
         match self {
             Self::AX => 0x0,
@@ -185,6 +296,10 @@ impl Register for R16 {
     fn rex_r(&self) -> u8 {
         self.upper_bit() << 2
     }
+
+    fn gpr(&self) -> Option<R64> {
+        Some(GPRS[self.code() as usize])
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -208,6 +323,28 @@ pub enum R32 {
 }
 
 impl R32 {
+    /// The register with a 4-bit register code.
+    pub fn from_code(code: u8) -> Self {
+        [
+            Self::EAX,
+            Self::ECX,
+            Self::EDX,
+            Self::EBX,
+            Self::ESP,
+            Self::EBP,
+            Self::ESI,
+            Self::EDI,
+            Self::R8D,
+            Self::R9D,
+            Self::R10D,
+            Self::R11D,
+            Self::R12D,
+            Self::R13D,
+            Self::R14D,
+            Self::R15D,
+        ][code as usize]
+    }
+
     fn code(&self) -> u8 {
         match self {
             Self::EAX => 0x0,
@@ -271,6 +408,14 @@ impl Register for R32 {
     fn rex_r(&self) -> u8 {
         self.upper_bit() << 2
     }
+
+    fn gpr(&self) -> Option<R64> {
+        Some(GPRS[self.code() as usize])
+    }
+
+    fn dword(&self) -> bool {
+        true
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -294,6 +439,11 @@ pub enum R64 {
 }
 
 impl R64 {
+    /// The register with a 4-bit register code.
+    pub fn from_code(code: u8) -> Self {
+        GPRS[code as usize]
+    }
+
     fn code(&self) -> u8 {
         match self {
             Self::RAX => 0x0,
@@ -339,7 +489,8 @@ impl Register for R64 {
     }
 
     fn in_base(&self) -> u8 {
-        assert!(*self != Self::RBP, "RBP cannot be used as base");
+        // RBP and R13 are only usable as a base with a displacement, which
+        // the instruction builder adds.
         self.code_3bit() << 0
     }
 
@@ -359,4 +510,227 @@ impl Register for R64 {
     fn rex_r(&self) -> u8 {
         self.upper_bit() << 2
     }
+
+    fn gpr(&self) -> Option<R64> {
+        Some(GPRS[self.code() as usize])
+    }
+}
+
+/// The 64-bit general-purpose registers added by Intel APX, which are
+/// encoded with a REX2 prefix. Their names clash with the type of 16-bit
+/// registers, so they are best written qualified, like `Egpr::R16`.
+///
+/// They are not allocated by the register allocator, nor reported by
+/// [`Assembler::used_registers`](super::Assembler::used_registers).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Egpr {
+    R16 = 16,
+    R17,
+    R18,
+    R19,
+    R20,
+    R21,
+    R22,
+    R23,
+    R24,
+    R25,
+    R26,
+    R27,
+    R28,
+    R29,
+    R30,
+    R31,
+}
+
+impl Egpr {
+    fn code(&self) -> u8 {
+        *self as u8
+    }
+
+    fn code_3bit(&self) -> u8 {
+        self.code() & 0b111
+    }
+
+    fn upper_bit(&self) -> u8 {
+        (self.code() >> 3) & 1
+    }
+
+    fn top_bit(&self) -> u8 {
+        self.code() >> 4
+    }
+}
+
+impl Register for Egpr {
+    fn in_opcode(&self) -> u8 {
+        self.code_3bit()
+    }
+
+    fn in_rm(&self) -> u8 {
+        self.code_3bit()
+    }
+
+    fn in_reg(&self) -> u8 {
+        self.code_3bit() << 3
+    }
+
+    fn in_base(&self) -> u8 {
+        self.code_3bit()
+    }
+
+    fn in_index(&self) -> u8 {
+        self.code_3bit() << 3
+    }
+
+    fn rex_b(&self) -> u8 {
+        self.upper_bit()
+    }
+
+    fn rex_x(&self) -> u8 {
+        self.upper_bit() << 1
+    }
+
+    fn rex_r(&self) -> u8 {
+        self.upper_bit() << 2
+    }
+
+    fn gpr(&self) -> Option<R64> {
+        None
+    }
+
+    fn rex2_b(&self) -> u8 {
+        self.top_bit() << 4
+    }
+
+    fn rex2_x(&self) -> u8 {
+        self.top_bit() << 5
+    }
+
+    fn rex2_r(&self) -> u8 {
+        self.top_bit() << 6
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Xmm {
+    XMM0,
+    XMM1,
+    XMM2,
+    XMM3,
+    XMM4,
+    XMM5,
+    XMM6,
+    XMM7,
+    XMM8,
+    XMM9,
+    XMM10,
+    XMM11,
+    XMM12,
+    XMM13,
+    XMM14,
+    XMM15,
+}
+
+impl Xmm {
+    /// The register with a 4-bit register code.
+    pub fn from_code(code: u8) -> Self {
+        [
+            Self::XMM0,
+            Self::XMM1,
+            Self::XMM2,
+            Self::XMM3,
+            Self::XMM4,
+            Self::XMM5,
+            Self::XMM6,
+            Self::XMM7,
+            Self::XMM8,
+            Self::XMM9,
+            Self::XMM10,
+            Self::XMM11,
+            Self::XMM12,
+            Self::XMM13,
+            Self::XMM14,
+            Self::XMM15,
+        ][code as usize]
+    }
+
+    fn code(&self) -> u8 {
+        *self as u8
+    }
+
+    fn code_3bit(&self) -> u8 {
+        self.code() & 0b111
+    }
+
+    fn upper_bit(&self) -> u8 {
+        self.code() >> 3
+    }
+}
+
+impl Register for Xmm {
+    fn in_opcode(&self) -> u8 {
+        unreachable!("XMM registers are not encoded in the opcode")
+    }
+
+    fn in_rm(&self) -> u8 {
+        self.code_3bit() << 0
+    }
+
+    fn in_reg(&self) -> u8 {
+        self.code_3bit() << 3
+    }
+
+    fn in_base(&self) -> u8 {
+        unreachable!("XMM register cannot be used as a pointer")
+    }
+
+    fn in_index(&self) -> u8 {
+        unreachable!("XMM register cannot be used as a pointer")
+    }
+
+    fn rex_b(&self) -> u8 {
+        self.upper_bit() << 0
+    }
+
+    fn rex_x(&self) -> u8 {
+        self.upper_bit() << 1
+    }
+
+    fn rex_r(&self) -> u8 {
+        self.upper_bit() << 2
+    }
+
+    fn gpr(&self) -> Option<R64> {
+        None
+    }
+}
+
+/// Segment registers, in order of register code.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Sreg {
+    ES,
+    CS,
+    SS,
+    DS,
+    FS,
+    GS,
+}
+
+impl Sreg {
+    /// The code of the register in the ModRM reg field.
+    pub fn code(&self) -> u8 {
+        *self as u8
+    }
+
+    /// The prefix that overrides the segment of a memory operand with this
+    /// register.
+    pub fn override_prefix(&self) -> u8 {
+        match self {
+            Self::ES => 0x26,
+            Self::CS => 0x2e,
+            Self::SS => 0x36,
+            Self::DS => 0x3e,
+            Self::FS => 0x64,
+            Self::GS => 0x65,
+        }
+    }
 }
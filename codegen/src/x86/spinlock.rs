@@ -0,0 +1,86 @@
+//! A generator for spinlock routines.
+//!
+//! The lock is a byte at a label provided by the caller, which is zero when
+//! the lock is free and one when it is held, so it can be allocated with
+//! [`Global::<u8>::reserve`](super::global::Global::reserve). The generated
+//! routines, called with the System V ABI, are:
+//!
+//! - `{name}_acquire()`: spin until the lock is taken
+//! - `{name}_try_acquire()`: take the lock if it is free, and return 1 if it
+//!   was taken or 0 otherwise
+//! - `{name}_release()`: release the lock, which must be held
+//!
+//! Acquiring spins on plain loads while the lock is held, and only retries
+//! the atomic exchange once it has been seen free, so that waiting CPUs do
+//! not keep taking the cache line from the holder.
+
+use super::{
+    control::ControlFlow,
+    function::Function,
+    instruction::*,
+    register::{R64::*, R8::*},
+    Assembler, Emitter,
+};
+use crate::link::{Label, Ptr};
+
+pub struct Spinlock<'a> {
+    name: &'a str,
+    lock: &'a str,
+}
+
+impl<'a> Spinlock<'a> {
+    /// Routines for the lock byte at `lock`.
+    pub fn new(name: &'a str, lock: &'a str) -> Self {
+        Self { name, lock }
+    }
+
+    /// A label derived from the name.
+    fn label(&self, suffix: &str) -> Label {
+        Label(&format!("{}_{}", self.name, suffix))
+    }
+
+    pub fn emit(&self, asm: &mut Assembler) {
+        let lock = Ptr(self.lock);
+
+        let mut f = Function::new(self.label("acquire")).export().begin(asm);
+        f.loop_(|f, acquired| {
+            f.push(MOV(AL, 1u8));
+            f.push(XCHG(lock, AL));
+            f.push(TEST(AL, AL));
+            f.push(JZ(acquired.break_));
+            f.loop_(|f, free| {
+                f.push(PAUSE);
+                f.push(MOV(AL, lock));
+                f.push(TEST(AL, AL));
+                f.push(JZ(free.break_));
+            });
+        });
+        f.ret();
+        f.finish();
+
+        let mut f = Function::new(self.label("try_acquire"))
+            .export()
+            .returns()
+            .begin(asm);
+        let end = f.fresh_label("try_acquire_end");
+        // Store 1 if the lock holds 0, the value in AL.
+        f.push(MOV(AL, 0u8));
+        f.push(MOV(CL, 1u8));
+        f.push(LOCK(CMPXCHG(lock, CL)));
+        // MOV leaves the flags unchanged.
+        f.push(MOV(RAX, 0u64));
+        f.push(JNZ(end));
+        f.push(MOV(RAX, 1u64));
+        f.label(end);
+        f.ret();
+        f.finish();
+
+        // Stores are not reordered with earlier loads or stores, so a plain
+        // store releases the lock after the accesses of the critical section.
+        let mut f = Function::new(self.label("release")).export().begin(asm);
+        f.push(MOV(AL, 0u8));
+        f.push(MOV(lock, AL));
+        f.ret();
+        f.finish();
+    }
+}
@@ -0,0 +1,136 @@
+//! A generator for the `SYSCALL` entry point and its setup.
+//!
+//! `SYSCALL` jumps to the kernel with the user's stack and GS base still
+//! loaded, and with the return address in RCX and the flags in R11, which
+//! `SYSRET` restores. The generated entry stub swaps to the kernel's GS base
+//! with `SWAPGS`, switches to the kernel stack found in the
+//! [per-CPU data](super::percpu), and calls the handler with the System V
+//! ABI as `handler(number, a0, a1, a2, a3, a4)`, where the number is taken
+//! from RAX and the arguments from RDI, RSI, RDX, R10 and R8. The handler's
+//! result is returned to the user in RAX.
+//!
+//! The generated routines are:
+//!
+//! - `{name}_init()`, called with the System V ABI: enable `SYSCALL` in
+//!   `EFER`, and program `STAR`, `LSTAR` and `FMASK`
+//! - `{name}_entry`: the entry stub, whose address is written to `LSTAR`
+//!
+//! Interrupts are disabled by `FMASK` on entry, and stay disabled while the
+//! handler runs unless it enables them itself. The caller-saved registers
+//! other than RAX are cleared before returning, so that no kernel values
+//! leak to the user.
+//!
+//! On Intel processors, `SYSRET` faults in kernel mode, on the user's stack,
+//! if the return address is not canonical. That only happens for a
+//! `SYSCALL` at the very end of the lower half, so the last page below
+//! `0x0000_8000_0000_0000` must never be mapped executable for the user.
+
+use super::{
+    address::GsIndex, function::Function, instruction::*, msr, register::R64::*, Assembler,
+};
+use crate::link::Label;
+
+/// Flags cleared on entry: TF, IF, DF, IOPL, NT and AC. DF must be clear
+/// for the handler, as required by the System V ABI.
+const FMASK: u64 = 0x4_7700;
+
+pub struct Syscall<'a> {
+    name: &'a str,
+    handler: &'a str,
+    kernel_stack: GsIndex,
+    user_stack: GsIndex,
+    kernel_code: u16,
+    user_base: u16,
+}
+
+impl<'a> Syscall<'a> {
+    /// Call `handler` for system calls. `kernel_stack` is the per-CPU field
+    /// holding the top of the kernel stack to switch to, which must be
+    /// 16-byte aligned, and `user_stack` is a per-CPU field where the user's
+    /// stack pointer is briefly kept.
+    ///
+    /// The selectors are those of the kernel code segment, followed by the
+    /// kernel data segment, and of the first of the user segments, which
+    /// must be followed by the user data segment and then the 64-bit user
+    /// code segment. `SYSCALL` and `SYSRET` load the segments from these
+    /// without reading the GDT.
+    pub fn new(
+        name: &'a str,
+        handler: &'a str,
+        kernel_stack: GsIndex,
+        user_stack: GsIndex,
+        kernel_code: u16,
+        user_base: u16,
+    ) -> Self {
+        Self {
+            name,
+            handler,
+            kernel_stack,
+            user_stack,
+            kernel_code,
+            user_base,
+        }
+    }
+
+    /// A label derived from the name.
+    fn label(&self, suffix: &str) -> Label {
+        Label(&format!("{}_{}", self.name, suffix))
+    }
+
+    /// The value of `STAR`: the `SYSCALL` CS and SS base in bits 47:32, and
+    /// the `SYSRET` one in bits 63:48.
+    fn star(&self) -> u64 {
+        (self.kernel_code as u64) << 32 | (self.user_base as u64) << 48
+    }
+
+    pub fn emit(&self, asm: &mut Assembler) {
+        let entry = self.label("entry");
+
+        let mut f = Function::new(self.label("init")).export().begin(asm);
+        msr::read(&mut f, msr::EFER, RAX);
+        f.push(OR(RAX, msr::EFER_SCE as i32));
+        msr::write(&mut f, msr::EFER, RAX);
+        f.push(MOV(RAX, self.star()));
+        msr::write(&mut f, msr::STAR, RAX);
+        f.push(LEA(RAX, entry.ptr()));
+        msr::write(&mut f, msr::LSTAR, RAX);
+        f.push(MOV(RAX, FMASK));
+        msr::write(&mut f, msr::FMASK, RAX);
+        f.ret();
+        f.finish();
+
+        let mut f = Function::new(entry).export().naked().begin(asm);
+        f.push(SWAPGS);
+        f.push(MOV(self.user_stack, RSP));
+        f.push(MOV(RSP, self.kernel_stack));
+        // Keep the return state on the kernel stack rather than in the
+        // per-CPU data, in case the handler switches to another task.
+        f.push(PUSH(R11));
+        f.push(PUSH(RCX));
+        f.push(MOV(RCX, self.user_stack));
+        f.push(PUSH(RCX));
+        // Align the stack for the call, after the three pushes.
+        f.push(SUB(RSP, 8i32));
+
+        // Shift the arguments into place, each before it is overwritten.
+        f.push(MOV(R9, R8));
+        f.push(MOV(R8, R10));
+        f.push(MOV(RCX, RDX));
+        f.push(MOV(RDX, RSI));
+        f.push(MOV(RSI, RDI));
+        f.push(MOV(RDI, RAX));
+        f.push(CALL(Label(self.handler)));
+
+        f.push(ADD(RSP, 8i8));
+        f.push(POP(RDX));
+        f.push(POP(RCX));
+        f.push(POP(R11));
+        f.push(MOV(RSP, RDX));
+        for register in [RDX, RSI, RDI, R8, R9, R10] {
+            f.push(XOR(register, register));
+        }
+        f.push(SWAPGS);
+        f.push(SYSRET);
+        f.finish();
+    }
+}
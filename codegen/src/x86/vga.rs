@@ -0,0 +1,172 @@
+//! A generator for a VGA text-mode console.
+//!
+//! The console writes to the 80x25 text buffer at physical address
+//! `0xb8000`, through the higher-half direct map reported by Limine, so it
+//! does not depend on Limine's terminal and keeps working after the
+//! bootloader's memory has been reclaimed. It only works if the display is
+//! actually in text mode, such as under BIOS boot with a text-mode
+//! framebuffer, and is meant as a fallback output path.
+//!
+//! The generated routine `{name}_write(buffer, length)`, called with the
+//! System V ABI, writes `length` bytes to the console. It has the signature
+//! of [`Sink::Function`](super::format::Sink::Function), so it can back a
+//! formatter. Newlines move the cursor to the start of the next line, every
+//! other byte is written as a character, and the screen scrolls up by a line
+//! when the cursor moves past the end. Output is dropped if the HHDM request
+//! has no response. The cursor position is kept in a global, and the
+//! hardware cursor is moved to it after each write.
+
+use super::{
+    address::Index,
+    control::ControlFlow,
+    function::Function,
+    global::Global,
+    instruction::*,
+    register::{R16::*, R64::*, R8::*},
+    Assembler, Emitter,
+};
+use crate::{
+    limine::{self, RequestHandle},
+    link::{Label, Segment},
+};
+
+const COLUMNS: u64 = 80;
+const ROWS: u64 = 25;
+/// Physical address of the text buffer.
+const TEXT_BUFFER: i32 = 0xb8000;
+/// Size of a character cell: the character, then its attribute.
+const CELL_SIZE: u64 = 2;
+
+/// CRT controller index and data ports, used to move the hardware cursor.
+const CRTC_INDEX: u64 = 0x3d4;
+const CRTC_DATA: u64 = 0x3d5;
+/// CRT controller registers holding the high and low bytes of the cursor
+/// position.
+const CURSOR_HIGH: u8 = 0x0e;
+const CURSOR_LOW: u8 = 0x0f;
+
+pub struct VgaConsole<'a> {
+    name: &'a str,
+    hhdm: RequestHandle,
+    attribute: u8,
+}
+
+impl<'a> VgaConsole<'a> {
+    /// A console using the response to the HHDM request `hhdm` to find the
+    /// text buffer.
+    ///
+    /// Characters are light grey on black unless
+    /// [`attribute`](Self::attribute) is given.
+    pub fn new(name: &'a str, hhdm: RequestHandle) -> Self {
+        Self {
+            name,
+            hhdm,
+            attribute: 0x07,
+        }
+    }
+
+    /// The attribute byte of written characters, with the background color
+    /// in the high nibble and the foreground color in the low nibble.
+    pub fn attribute(mut self, attribute: u8) -> Self {
+        self.attribute = attribute;
+        self
+    }
+
+    /// A label derived from the name.
+    fn label(&self, suffix: &str) -> Label {
+        Label(&format!("{}_{}", self.name, suffix))
+    }
+
+    /// Emit the exported routine into `asm`, and the cursor position into
+    /// `data`.
+    pub fn emit(&self, asm: &mut Assembler, data: &mut Segment) {
+        let cursor = Global::<u64>::reserve(data, self.label("cursor"));
+        let attribute = (self.attribute as u64) << 8;
+
+        let mut f = Function::new(self.label("write"))
+            .export()
+            .params(2)
+            .begin(asm);
+        let end = f.fresh_label("vga_write_end");
+        // R8 walks the buffer up to R9, and R11 holds the cursor, as an
+        // index into the cells of the screen at R10.
+        f.push(MOV(R8, f.param(0)));
+        f.push(MOV(R9, f.param(0)));
+        f.push(ADD(R9, f.param(1)));
+        f.push(MOV(RAX, self.hhdm.ptr()));
+        f.push(TEST(RAX, RAX));
+        f.push(JZ(end));
+        f.push(MOV(R10, Index(RAX, limine::HHDM_OFFSET_DISPLACEMENT)));
+        f.push(ADD(R10, TEXT_BUFFER));
+        cursor.load(&mut f, R11);
+
+        f.while_(
+            |f| {
+                f.push(CMP(R8, R9));
+                Condition::Below
+            },
+            |f, _| {
+                f.push(MOVZX(RAX, Index(R8, 0)));
+                f.push(INC(R8));
+                f.push(CMP(RAX, b'\n' as i8));
+                f.if_(
+                    Condition::Zero,
+                    |f| {
+                        // Move to the start of the next line.
+                        f.push(MOV(RAX, R11));
+                        f.push(XOR(RDX, RDX));
+                        f.push(MOV(RCX, COLUMNS));
+                        f.push(DIV(RCX));
+                        f.push(SUB(R11, RDX));
+                        f.push(ADD(R11, COLUMNS as i32));
+                    },
+                    |f| {
+                        f.push(OR(RAX, attribute as i32));
+                        f.push(MOV(RDI, R11));
+                        f.push(ADD(RDI, R11));
+                        f.push(ADD(RDI, R10));
+                        f.push(STOSW);
+                        f.push(INC(R11));
+                    },
+                );
+                f.push(CMP(R11, (COLUMNS * ROWS) as i32));
+                f.if_then(Condition::AboveOrEqual, |f| {
+                    // Move all lines but the first up by one, and clear the
+                    // last, which RDI then points to.
+                    f.push(MOV(RDI, R10));
+                    f.push(MOV(RSI, R10));
+                    f.push(ADD(RSI, (COLUMNS * CELL_SIZE) as i32));
+                    f.push(MOV(RCX, COLUMNS * (ROWS - 1) * CELL_SIZE));
+                    f.push(REP(MOVSB));
+                    f.push(MOV(RAX, attribute | b' ' as u64));
+                    f.push(MOV(RCX, COLUMNS));
+                    f.push(REP(STOSW));
+                    f.push(SUB(R11, COLUMNS as i32));
+                });
+            },
+        );
+        cursor.store(&mut f, R11);
+
+        // The cursor position is below 2^16, so it can be written a byte at
+        // a time from RAX.
+        f.push(MOV(RAX, R11));
+        write_crtc(&mut f, CURSOR_LOW);
+        f.push(SHR(RAX, 8i8));
+        write_crtc(&mut f, CURSOR_HIGH);
+        f.label(end);
+        f.ret();
+        f.finish();
+    }
+}
+
+/// Write `AL` to the CRT controller register `index`, clobbering RCX and
+/// RDX.
+fn write_crtc(f: &mut impl Emitter, index: u8) {
+    f.push(MOV(RCX, RAX));
+    f.push(MOV(RDX, CRTC_INDEX));
+    f.push(MOV(AL, index));
+    f.push(OUT(DX, AL));
+    f.push(MOV(RDX, CRTC_DATA));
+    f.push(MOV(RAX, RCX));
+    f.push(OUT(DX, AL));
+}
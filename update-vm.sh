@@ -13,5 +13,5 @@ sudo mount "${LOOP}p1" /mnt
 MOUNT=/mnt
 
 cd codegen
-cargo run
+cargo run --example kernel
 sudo cp kernel.elf /mnt/
\ No newline at end of file